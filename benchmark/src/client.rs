@@ -354,6 +354,9 @@ impl Client {
         if thd_cnt != 0 {
             builder = builder.cq_count(thd_cnt);
         }
+        if !cfg.get_core_list().is_empty() {
+            builder = util::pin_cores(builder, cfg.get_core_list());
+        }
         let env = Arc::new(builder.build());
         if cfg.get_core_limit() > 0 {
             error!("client config core limit is set but ignored");
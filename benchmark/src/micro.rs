@@ -0,0 +1,344 @@
+// Copyright 2018 PingCAP, Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Micro benchmarks for the hot paths that the QPS worker can't isolate.
+//!
+//! They are timed by hand rather than by a harness like criterion. The buffer
+//! pool has to be installed before gRPC core is initialized and stays for the
+//! lifetime of the process, so the pooled and the `malloc` runs need separate
+//! processes that are chosen from the command line. Page faults and message
+//! rates are reported too, and they aren't time per iteration.
+
+extern crate clap;
+extern crate futures;
+extern crate grpcio as grpc;
+extern crate grpcio_proto as grpc_proto;
+extern crate libc;
+
+use std::sync::Arc;
+use std::time::Instant;
+
+use clap::{App, Arg};
+use futures::sync::oneshot;
+use futures::{stream, Future, Sink, Stream};
+use grpc::{
+    CallOption, ChannelBuilder, Client, EnvBuilder, Error, Marshaller, MetadataBuilder, Method,
+    MethodType, ServerBuilder, ServiceBuilder, WriteFlags,
+};
+use grpc_proto::testing::messages::{SimpleRequest, SimpleResponse};
+use grpc_proto::util;
+
+fn report(name: &str, iters: u32, start: Instant) {
+    let elapsed = start.elapsed();
+    let nanos = elapsed.as_secs() * 1_000_000_000 + u64::from(elapsed.subsec_nanos());
+    println!(
+        "{:<24} {:>12.1} ns/iter",
+        name,
+        nanos as f64 / f64::from(iters)
+    );
+}
+
+fn bench_codec(iters: u32, size: usize) {
+    let mut req = SimpleRequest::new();
+    req.set_response_size(size as i32);
+    req.set_payload(util::new_payload(size));
+
+    let mut buf = Vec::new();
+    let start = Instant::now();
+    for _ in 0..iters {
+        buf.clear();
+        grpc::pb_ser(&req, &mut buf);
+    }
+    report(&format!("codec/ser/{}", size), iters, start);
+
+    let start = Instant::now();
+    for _ in 0..iters {
+        let r: SimpleRequest = grpc::pb_de(&buf).unwrap();
+        assert_eq!(r.get_response_size(), size as i32);
+    }
+    report(&format!("codec/de/{}", size), iters, start);
+}
+
+// Measures building and copying the headers of an authenticated call, a
+// bearer token along with `entries` short headers.
+fn bench_metadata(iters: u32, entries: usize) {
+    let token = format!("Bearer {}", "t".repeat(1024));
+    let headers: Vec<_> = (0..entries)
+        .map(|i| (format!("x-header-{}", i), format!("value-{}", i)))
+        .collect();
+    let build = || {
+        let mut builder = MetadataBuilder::with_capacity(entries + 1);
+        builder.add_str("authorization", &token).unwrap();
+        for &(ref k, ref v) in &headers {
+            builder.add_str(k, v).unwrap();
+        }
+        builder.build()
+    };
+
+    let start = Instant::now();
+    for _ in 0..iters {
+        build();
+    }
+    report(&format!("metadata/build/{}", entries), iters, start);
+
+    let meta = build();
+    let start = Instant::now();
+    for _ in 0..iters {
+        let _ = meta.clone();
+    }
+    report(&format!("metadata/clone/{}", entries), iters, start);
+}
+
+// Measures the round trip of waking up a future spawned on a completion queue
+// from another thread.
+fn bench_cq_notify(iters: u32) {
+    let env = Arc::new(EnvBuilder::new().cq_count(1).build());
+    // The channel is never connected, it's only used to reach the queue.
+    let ch = ChannelBuilder::new(env).connect("127.0.0.1:1");
+    let client = Client::new(ch);
+
+    let start = Instant::now();
+    for _ in 0..iters {
+        let (req_tx, req_rx) = oneshot::channel::<()>();
+        let (resp_tx, resp_rx) = oneshot::channel();
+        client.spawn(req_rx.map(|_| resp_tx.send(()).unwrap()).map_err(|_| ()));
+        req_tx.send(()).unwrap();
+        resp_rx.wait().unwrap();
+    }
+    report("cq/notify", iters, start);
+}
+
+const METHOD_UNARY_CALL: Method<SimpleRequest, SimpleResponse> = Method {
+    ty: MethodType::Unary,
+    name: "/grpc.testing.BenchmarkService/UnaryCall",
+    req_mar: Marshaller {
+        ser: grpc::pb_ser,
+        de: grpc::pb_de,
+    },
+    resp_mar: Marshaller {
+        ser: grpc::pb_ser,
+        de: grpc::pb_de,
+    },
+};
+
+// Measures the latency of unary calls to a server in the same process, with
+// the poll threads left to the scheduler or pinned to cores.
+fn bench_unary(iters: u32, mode: &str, builder: EnvBuilder) {
+    let env = Arc::new(builder.build());
+    let service = ServiceBuilder::new()
+        .add_unary_handler(&METHOD_UNARY_CALL, |ctx, req: SimpleRequest, sink| {
+            let mut resp = SimpleResponse::new();
+            resp.set_payload(util::new_payload(req.get_response_size() as usize));
+            ctx.spawn(
+                sink.success(resp)
+                    .map_err(|e| panic!("failed to reply: {:?}", e)),
+            );
+        })
+        .build();
+    let mut server = ServerBuilder::new(env.clone())
+        .register_service(service)
+        .bind("127.0.0.1", 0)
+        .build()
+        .unwrap();
+    server.start();
+    let port = server.bind_addrs()[0].1;
+    let ch = ChannelBuilder::new(env).connect(&format!("127.0.0.1:{}", port));
+    let client = Client::new(ch);
+
+    let req = SimpleRequest::new();
+    // Warm up the connection.
+    client
+        .unary_call(&METHOD_UNARY_CALL, &req, CallOption::default())
+        .unwrap();
+    let start = Instant::now();
+    for _ in 0..iters {
+        client
+            .unary_call(&METHOD_UNARY_CALL, &req, CallOption::default())
+            .unwrap();
+    }
+    report(&format!("unary/{}", mode), iters, start);
+    server.shutdown().wait().unwrap();
+}
+
+const METHOD_STREAMING_FROM_SERVER: Method<SimpleRequest, SimpleResponse> = Method {
+    ty: MethodType::ServerStreaming,
+    name: "/grpc.testing.BenchmarkService/StreamingFromServer",
+    req_mar: Marshaller {
+        ser: grpc::pb_ser,
+        de: grpc::pb_de,
+    },
+    resp_mar: Marshaller {
+        ser: grpc::pb_ser,
+        de: grpc::pb_de,
+    },
+};
+
+// Measures the throughput of receiving small messages, which is bound by the
+// per message overhead of reading them out of gRPC core.
+fn bench_server_streaming(iters: u32, size: usize) {
+    let env = Arc::new(EnvBuilder::new().cq_count(2).build());
+    let service = ServiceBuilder::new()
+        .add_server_streaming_handler(
+            &METHOD_STREAMING_FROM_SERVER,
+            move |ctx, req: SimpleRequest, sink| {
+                let mut resp = SimpleResponse::new();
+                resp.set_payload(util::new_payload(req.get_response_size() as usize));
+                let resps = (0..iters).map(move |_| (resp.clone(), WriteFlags::default()));
+                ctx.spawn(
+                    sink.send_all(stream::iter_ok::<_, Error>(resps))
+                        .map(|_| ())
+                        .map_err(|e| panic!("failed to reply: {:?}", e)),
+                );
+            },
+        )
+        .build();
+    let mut server = ServerBuilder::new(env.clone())
+        .register_service(service)
+        .bind("127.0.0.1", 0)
+        .build()
+        .unwrap();
+    server.start();
+    let port = server.bind_addrs()[0].1;
+    let ch = ChannelBuilder::new(env).connect(&format!("127.0.0.1:{}", port));
+    let client = Client::new(ch);
+
+    let mut req = SimpleRequest::new();
+    req.set_response_size(size as i32);
+    let start = Instant::now();
+    let count = client
+        .server_streaming(&METHOD_STREAMING_FROM_SERVER, &req, CallOption::default())
+        .unwrap()
+        .fold(0, |n, _| Ok::<_, Error>(n + 1))
+        .wait()
+        .unwrap();
+    assert_eq!(count, iters);
+    let elapsed = start.elapsed();
+    let secs = elapsed.as_secs() as f64 + f64::from(elapsed.subsec_nanos()) / 1e9;
+    report(&format!("streaming/{}", size), iters, start);
+    println!("{:<24} {:>12.1} msg/s", "", f64::from(iters) / secs);
+    server.shutdown().wait().unwrap();
+}
+
+#[cfg(unix)]
+fn minor_faults() -> i64 {
+    unsafe {
+        let mut usage: libc::rusage = std::mem::zeroed();
+        libc::getrusage(libc::RUSAGE_SELF, &mut usage);
+        usage.ru_minflt as i64
+    }
+}
+
+// Measures the page faults of unary calls with large responses, whose
+// buffers are mapped and unmapped by `malloc` for every call unless the
+// buffer pool is used.
+#[cfg(unix)]
+fn bench_large_unary(iters: u32, size: usize, mode: &str) {
+    let env = Arc::new(EnvBuilder::new().cq_count(2).build());
+    let service = ServiceBuilder::new()
+        .add_unary_handler(&METHOD_UNARY_CALL, |ctx, req: SimpleRequest, sink| {
+            let mut resp = SimpleResponse::new();
+            resp.set_payload(util::new_payload(req.get_response_size() as usize));
+            ctx.spawn(
+                sink.success(resp)
+                    .map_err(|e| panic!("failed to reply: {:?}", e)),
+            );
+        })
+        .build();
+    let mut server = ServerBuilder::new(env.clone())
+        .register_service(service)
+        .bind("127.0.0.1", 0)
+        .build()
+        .unwrap();
+    server.start();
+    let port = server.bind_addrs()[0].1;
+    let ch = ChannelBuilder::new(env)
+        .max_receive_message_len(-1)
+        .connect(&format!("127.0.0.1:{}", port));
+    let client = Client::new(ch);
+
+    let mut req = SimpleRequest::new();
+    req.set_response_size(size as i32);
+    client
+        .unary_call(&METHOD_UNARY_CALL, &req, CallOption::default())
+        .unwrap();
+    let faults = minor_faults();
+    let start = Instant::now();
+    for _ in 0..iters {
+        client
+            .unary_call(&METHOD_UNARY_CALL, &req, CallOption::default())
+            .unwrap();
+    }
+    report(&format!("unary/{}/{}", size, mode), iters, start);
+    println!(
+        "{:<24} {:>12.1} faults/iter",
+        "",
+        (minor_faults() - faults) as f64 / f64::from(iters)
+    );
+    server.shutdown().wait().unwrap();
+}
+
+fn main() {
+    let matches = App::new("Benchmark Micro")
+        .about("Micro benchmarks of codec and completion queue")
+        .arg(
+            Arg::with_name("iters")
+                .long("iters")
+                .help("The iteration count of every benchmark.")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("buffer-pool")
+                .long("buffer-pool")
+                .help("Allocate the buffers of gRPC core from a pool of huge pages."),
+        )
+        .get_matches();
+    let pooled = matches.is_present("buffer-pool");
+    #[cfg(unix)]
+    {
+        if pooled {
+            let pool = grpc::alloc::BufferPool::new()
+                .huge_pages(true)
+                .preallocate(4 * 1024 * 1024, 8);
+            unsafe { grpc::alloc::use_buffer_pool(pool).unwrap() }
+        }
+    }
+    let iters: u32 = matches
+        .value_of("iters")
+        .unwrap_or("100000")
+        .parse()
+        .unwrap();
+
+    for &size in &[0, 1024, 64 * 1024] {
+        bench_codec(iters, size);
+    }
+    for &entries in &[4, 32] {
+        bench_metadata(iters, entries);
+    }
+    bench_cq_notify(iters);
+    for &size in &[16, 256, 4096] {
+        bench_server_streaming(iters, size);
+    }
+    #[cfg(unix)]
+    bench_large_unary(
+        (iters / 100).max(1),
+        1024 * 1024,
+        if pooled { "buffer-pool" } else { "malloc" },
+    );
+    bench_unary(iters, "default", EnvBuilder::new().cq_count(2));
+    #[cfg(target_os = "linux")]
+    bench_unary(
+        iters,
+        "thread-per-core",
+        EnvBuilder::new().thread_per_core(vec![0, 1]),
+    );
+}
@@ -38,6 +38,9 @@ impl Server {
         if thd_cnt != 0 {
             builder = builder.cq_count(thd_cnt);
         }
+        if !cfg.get_core_list().is_empty() {
+            builder = util::pin_cores(builder, cfg.get_core_list());
+        }
         let env = Arc::new(builder.build());
         if cfg.get_core_limit() > 0 {
             warn!("server config core limit is set but ignored");
@@ -14,6 +14,7 @@
 use std::f64;
 use std::time::{Duration, Instant};
 
+use grpc::EnvBuilder;
 use grpc_proto::testing::stats::HistogramData;
 use grpc_sys;
 
@@ -132,6 +133,18 @@ mod sys_util {
     }
 }
 
+/// Pin the poll threads to the cores listed by the config.
+#[cfg(target_os = "linux")]
+pub fn pin_cores(builder: EnvBuilder, cores: &[i32]) -> EnvBuilder {
+    builder.thread_per_core(cores.iter().map(|&c| c as usize).collect())
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn pin_cores(builder: EnvBuilder, _: &[i32]) -> EnvBuilder {
+    warn!("core list is set but ignored");
+    builder
+}
+
 #[inline]
 pub fn cpu_num_cores() -> usize {
     unsafe { grpc_sys::gpr_cpu_num_cores() as usize }
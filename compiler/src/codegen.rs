@@ -33,11 +33,16 @@
 // WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE SOFTWARE.
 
 use std::collections::HashMap;
+use std::io::{self, stdin, stdout, Read, Write};
+use std::process::{Command, Stdio};
+use std::{str, thread};
 
 use protobuf;
 use protobuf::compiler_plugin;
 use protobuf::descriptor::*;
 use protobuf::descriptorx::*;
+use protobuf::plugin::{CodeGeneratorRequest, CodeGeneratorResponse, CodeGeneratorResponse_File};
+use protobuf::Message;
 use protobuf_codegen::code_writer::CodeWriter;
 
 use super::util::{self, fq_grpc, to_snake_case, MethodType};
@@ -47,6 +52,7 @@ struct MethodGen<'a> {
     service_name: String,
     service_path: String,
     root_scope: &'a RootScope<'a>,
+    chunked: bool,
 }
 
 impl<'a> MethodGen<'a> {
@@ -55,12 +61,14 @@ impl<'a> MethodGen<'a> {
         service_name: String,
         service_path: String,
         root_scope: &'a RootScope<'a>,
+        chunked: bool,
     ) -> MethodGen<'a> {
         MethodGen {
             proto,
             service_name,
             service_path,
             root_scope,
+            chunked,
         }
     }
 
@@ -112,6 +120,46 @@ impl<'a> MethodGen<'a> {
         format!("\"{}/{}\"", self.service_path, &self.proto.get_name())
     }
 
+    // Whether the method has a chunked companion, see `gen_chunked`.
+    fn has_chunked(&self) -> bool {
+        match self.method_type().0 {
+            MethodType::Unary => self.chunked,
+            _ => false,
+        }
+    }
+
+    fn chunked_fq_name(&self) -> String {
+        format!(
+            "\"{}/{}Chunked\"",
+            self.service_path,
+            &self.proto.get_name()
+        )
+    }
+
+    fn const_chunked_method_name(&self) -> String {
+        format!("{}_CHUNKED", self.const_method_name())
+    }
+
+    fn variant_name(&self) -> String {
+        util::to_camel_case(self.proto.get_name())
+    }
+
+    fn deprecated(&self) -> bool {
+        self.proto.get_options().get_deprecated()
+    }
+
+    // Mark a client method of a deprecated method. `forwarding` methods call
+    // the `_opt` one, which is deprecated too.
+    fn write_deprecated(&self, w: &mut CodeWriter, forwarding: bool) {
+        if !self.deprecated() {
+            return;
+        }
+        w.write_line("#[deprecated]");
+        if forwarding {
+            w.write_line("#[allow(deprecated)]");
+        }
+    }
+
     fn const_method_name(&self) -> String {
         format!(
             "METHOD_{}_{}",
@@ -141,6 +189,25 @@ impl<'a> MethodGen<'a> {
             w.field_entry("req_mar", &pb_mar);
             w.field_entry("resp_mar", &pb_mar);
         });
+        if !self.has_chunked() {
+            return;
+        }
+
+        w.write_line("");
+        let head = format!(
+            "const {}: {}<{}, {}> = {} {{",
+            self.const_chunked_method_name(),
+            fq_grpc("Method"),
+            self.input(),
+            self.output(),
+            fq_grpc("Method")
+        );
+        w.block(&head, "};", |w| {
+            w.field_entry("ty", &fq_grpc("MethodType::Duplex"));
+            w.field_entry("name", &self.chunked_fq_name());
+            w.field_entry("req_mar", &pb_mar);
+            w.field_entry("resp_mar", &pb_mar);
+        });
     }
 
     // Method signatures
@@ -188,6 +255,29 @@ impl<'a> MethodGen<'a> {
         )
     }
 
+    fn chunked_opt(&self, method_name: &str) -> String {
+        format!(
+            "{}_chunked_opt(&self, req: &{}, chunk_size: usize, opt: {}) -> {}<{}>",
+            method_name,
+            self.input(),
+            fq_grpc("CallOption"),
+            fq_grpc("Result"),
+            self.output()
+        )
+    }
+
+    fn chunked_async_opt(&self, method_name: &str) -> String {
+        format!(
+            "{}_chunked_async_opt(&self, req: &{}, chunk_size: usize, opt: {}) -> {}<{}<{}>>",
+            method_name,
+            self.input(),
+            fq_grpc("CallOption"),
+            fq_grpc("Result"),
+            fq_grpc("chunk::ClientChunkedReceiver"),
+            self.output()
+        )
+    }
+
     fn client_streaming(&self, method_name: &str) -> String {
         format!(
             "{}(&self) -> {}<({}<{}>, {}<{}>)>",
@@ -266,6 +356,7 @@ impl<'a> MethodGen<'a> {
         match self.method_type().0 {
             // Unary
             MethodType::Unary => {
+                self.write_deprecated(w, false);
                 w.pub_fn(&self.unary_opt(&method_name), |w| {
                     w.write_line(&format!(
                         "self.client.unary_call(&{}, req, opt)",
@@ -274,6 +365,7 @@ impl<'a> MethodGen<'a> {
                 });
                 w.write_line("");
 
+                self.write_deprecated(w, true);
                 w.pub_fn(&self.unary(&method_name), |w| {
                     w.write_line(&format!(
                         "self.{}_opt(req, {})",
@@ -283,6 +375,7 @@ impl<'a> MethodGen<'a> {
                 });
                 w.write_line("");
 
+                self.write_deprecated(w, false);
                 w.pub_fn(&self.unary_async_opt(&method_name), |w| {
                     w.write_line(&format!(
                         "self.client.unary_call_async(&{}, req, opt)",
@@ -291,6 +384,7 @@ impl<'a> MethodGen<'a> {
                 });
                 w.write_line("");
 
+                self.write_deprecated(w, true);
                 w.pub_fn(&self.unary_async(&method_name), |w| {
                     w.write_line(&format!(
                         "self.{}_async_opt(req, {})",
@@ -298,10 +392,31 @@ impl<'a> MethodGen<'a> {
                         fq_grpc("CallOption::default()")
                     ));
                 });
+
+                if self.has_chunked() {
+                    w.write_line("");
+                    self.write_deprecated(w, false);
+                    w.pub_fn(&self.chunked_opt(&method_name), |w| {
+                        w.write_line(&format!(
+                            "self.client.chunked_unary_call(&{}, req, chunk_size, opt)",
+                            self.const_chunked_method_name()
+                        ));
+                    });
+                    w.write_line("");
+
+                    self.write_deprecated(w, false);
+                    w.pub_fn(&self.chunked_async_opt(&method_name), |w| {
+                        w.write_line(&format!(
+                            "self.client.chunked_unary_call_async(&{}, req, chunk_size, opt)",
+                            self.const_chunked_method_name()
+                        ));
+                    });
+                }
             }
 
             // Client streaming
             MethodType::ClientStreaming => {
+                self.write_deprecated(w, false);
                 w.pub_fn(&self.client_streaming_opt(&method_name), |w| {
                     w.write_line(&format!(
                         "self.client.client_streaming(&{}, opt)",
@@ -310,6 +425,7 @@ impl<'a> MethodGen<'a> {
                 });
                 w.write_line("");
 
+                self.write_deprecated(w, true);
                 w.pub_fn(&self.client_streaming(&method_name), |w| {
                     w.write_line(&format!(
                         "self.{}_opt({})",
@@ -321,6 +437,7 @@ impl<'a> MethodGen<'a> {
 
             // Server streaming
             MethodType::ServerStreaming => {
+                self.write_deprecated(w, false);
                 w.pub_fn(&self.server_streaming_opt(&method_name), |w| {
                     w.write_line(&format!(
                         "self.client.server_streaming(&{}, req, opt)",
@@ -329,6 +446,7 @@ impl<'a> MethodGen<'a> {
                 });
                 w.write_line("");
 
+                self.write_deprecated(w, true);
                 w.pub_fn(&self.server_streaming(&method_name), |w| {
                     w.write_line(&format!(
                         "self.{}_opt(req, {})",
@@ -340,6 +458,7 @@ impl<'a> MethodGen<'a> {
 
             // Duplex streaming
             MethodType::Duplex => {
+                self.write_deprecated(w, false);
                 w.pub_fn(&self.duplex_streaming_opt(&method_name), |w| {
                     w.write_line(&format!(
                         "self.client.duplex_streaming(&{}, opt)",
@@ -348,6 +467,7 @@ impl<'a> MethodGen<'a> {
                 });
                 w.write_line("");
 
+                self.write_deprecated(w, true);
                 w.pub_fn(&self.duplex_streaming(&method_name), |w| {
                     w.write_line(&format!(
                         "self.{}_opt({})",
@@ -359,24 +479,129 @@ impl<'a> MethodGen<'a> {
         };
     }
 
-    fn write_service(&self, w: &mut CodeWriter) {
+    fn req_name(&self) -> &'static str {
+        match self.method_type().0 {
+            MethodType::Unary | MethodType::ServerStreaming => "req",
+            MethodType::ClientStreaming | MethodType::Duplex => "stream",
+        }
+    }
+
+    fn service_sig(&self) -> String {
         let req_stream_type = format!("{}<{}>", fq_grpc("RequestStream"), self.input());
-        let (req, req_type, resp_type) = match self.method_type().0 {
-            MethodType::Unary => ("req", self.input(), "UnarySink"),
-            MethodType::ClientStreaming => ("stream", req_stream_type, "ClientStreamingSink"),
-            MethodType::ServerStreaming => ("req", self.input(), "ServerStreamingSink"),
-            MethodType::Duplex => ("stream", req_stream_type, "DuplexSink"),
+        let (req_type, resp_type) = match self.method_type().0 {
+            MethodType::Unary => (self.input(), "UnarySink"),
+            MethodType::ClientStreaming => (req_stream_type, "ClientStreamingSink"),
+            MethodType::ServerStreaming => (self.input(), "ServerStreamingSink"),
+            MethodType::Duplex => (req_stream_type, "DuplexSink"),
         };
-        let sig = format!(
+        format!(
             "{}(&self, ctx: {}, {}: {}, sink: {}<{}>)",
             self.name(),
             fq_grpc("RpcContext"),
-            req,
+            self.req_name(),
             req_type,
             fq_grpc(resp_type),
             self.output()
+        )
+    }
+
+    fn chunked_service_sig(&self, req_name: &str) -> String {
+        format!(
+            "{}_chunked(&self, ctx: {}, {}: {}<{}>, sink: {}<{}>)",
+            self.name(),
+            fq_grpc("RpcContext"),
+            req_name,
+            fq_grpc("chunk::ChunkedRequest"),
+            self.input(),
+            fq_grpc("chunk::ChunkedSink"),
+            self.output()
+        )
+    }
+
+    fn write_service(&self, w: &mut CodeWriter) {
+        w.fn_def(&self.service_sig());
+        if !self.has_chunked() {
+            return;
+        }
+
+        // Implementing the companion is optional.
+        w.def_fn(&self.chunked_service_sig("_req"), |w| {
+            w.write_line(&format!(
+                "let status = {}::new({}::Unimplemented, None);",
+                fq_grpc("RpcStatus"),
+                fq_grpc("RpcStatusCode")
+            ));
+            w.write_line("ctx.spawn(::futures::Future::map_err(sink.fail(status), |_| ()))");
+        });
+    }
+
+    fn const_descriptor_name(&self) -> String {
+        format!(
+            "{}_{}",
+            self.service_name().to_uppercase(),
+            self.name().to_uppercase()
+        )
+    }
+
+    fn write_descriptor(&self, w: &mut CodeWriter) {
+        let head = format!(
+            "pub const {}: MethodDescriptor = MethodDescriptor {{",
+            self.const_descriptor_name()
         );
-        w.fn_def(&sig);
+        w.block(&head, "};", |w| {
+            w.field_entry("name", &format!("\"{}\"", self.proto.get_name()));
+            w.field_entry("path", &self.fq_name());
+            w.field_entry(
+                "client_streaming",
+                &self.proto.get_client_streaming().to_string(),
+            );
+            w.field_entry(
+                "server_streaming",
+                &self.proto.get_server_streaming().to_string(),
+            );
+        });
+    }
+
+    fn write_transport_client(&self, w: &mut CodeWriter) {
+        if let MethodType::Unary = self.method_type().0 {
+            let sig = format!(
+                "{}(&self, req: &{}) -> Result<{}, T::Error>",
+                self.name(),
+                self.input(),
+                self.output()
+            );
+            self.write_deprecated(w, false);
+            w.pub_fn(&sig, |w| {
+                w.write_line(&format!(
+                    "self.transport.unary(&{}, req)",
+                    self.const_descriptor_name()
+                ));
+            });
+        } else {
+            w.comment(&format!(
+                "`{}` is a streaming method, which is only described by `{}`.",
+                self.proto.get_name(),
+                self.const_descriptor_name()
+            ));
+        }
+    }
+
+    fn write_service_forward(&self, w: &mut CodeWriter) {
+        w.def_fn(&self.service_sig(), |w| {
+            w.write_line(&format!(
+                "(**self).{}(ctx, {}, sink)",
+                self.name(),
+                self.req_name()
+            ));
+        });
+        if !self.has_chunked() {
+            return;
+        }
+
+        w.write_line("");
+        w.def_fn(&self.chunked_service_sig("req"), |w| {
+            w.write_line(&format!("(**self).{}_chunked(ctx, req, sink)", self.name()));
+        });
     }
 
     fn write_bind(&self, w: &mut CodeWriter) {
@@ -397,6 +622,34 @@ impl<'a> MethodGen<'a> {
                 w.write_line(&format!("instance.{}(ctx, req, resp)", self.name()));
             },
         );
+        if self.deprecated() {
+            w.write_line(&format!(
+                "builder = builder.deprecate_method(&{});",
+                self.const_method_name()
+            ));
+        }
+        if !self.has_chunked() {
+            return;
+        }
+
+        w.write_line("let instance = s.clone();");
+        w.block(
+            &format!(
+                "builder = builder.add_chunked_unary_handler(&{}, {}, move |ctx, req, resp| {{",
+                self.const_chunked_method_name(),
+                fq_grpc("chunk::DEFAULT_CHUNK_SIZE")
+            ),
+            "});",
+            |w| {
+                w.write_line(&format!("instance.{}_chunked(ctx, req, resp)", self.name()));
+            },
+        );
+        if self.deprecated() {
+            w.write_line(&format!(
+                "builder = builder.deprecate_method(&{});",
+                self.const_chunked_method_name()
+            ));
+        }
     }
 }
 
@@ -410,6 +663,7 @@ impl<'a> ServiceGen<'a> {
         proto: &'a ServiceDescriptorProto,
         file: &FileDescriptorProto,
         root_scope: &'a RootScope,
+        chunked: bool,
     ) -> ServiceGen<'a> {
         let service_path = if file.get_package().is_empty() {
             format!("/{}", proto.get_name())
@@ -425,6 +679,7 @@ impl<'a> ServiceGen<'a> {
                     util::to_camel_case(proto.get_name()),
                     service_path.clone(),
                     root_scope,
+                    chunked,
                 )
             })
             .collect();
@@ -440,6 +695,71 @@ impl<'a> ServiceGen<'a> {
         format!("{}Client", self.service_name())
     }
 
+    fn method_enum_name(&self) -> String {
+        format!("{}Method", self.service_name())
+    }
+
+    fn write_method_enum(&self, w: &mut CodeWriter) {
+        let name = self.method_enum_name();
+        w.derive(&["Clone", "Copy", "Debug", "PartialEq", "Eq", "Hash"]);
+        w.pub_enum(&name, |w| {
+            for method in &self.methods {
+                w.write_line(&format!("{},", method.variant_name()));
+            }
+        });
+
+        w.write_line("");
+
+        w.impl_self_block(&name, |w| {
+            let all: Vec<_> = self
+                .methods
+                .iter()
+                .map(|m| format!("{}::{}", name, m.variant_name()))
+                .collect();
+            w.write_line(&format!(
+                "pub const ALL: &'static [{}] = &[{}];",
+                name,
+                all.join(", ")
+            ));
+            w.write_line("");
+
+            w.pub_fn("path(&self) -> &'static str", |w| {
+                w.match_expr("*self", |w| {
+                    for method in &self.methods {
+                        w.case_expr(
+                            format!("{}::{}", name, method.variant_name()),
+                            method.fq_name(),
+                        );
+                    }
+                });
+            });
+            w.write_line("");
+
+            w.pub_fn(&format!("from_path(path: &str) -> Option<{}>", name), |w| {
+                w.match_expr("path", |w| {
+                    for method in &self.methods {
+                        w.case_expr(
+                            method.fq_name(),
+                            format!("Some({}::{})", name, method.variant_name()),
+                        );
+                    }
+                    w.case_expr("_", "None");
+                });
+            });
+            w.write_line("");
+
+            w.pub_fn(
+                &format!(
+                    "matches(&self, pattern: &{}) -> bool",
+                    fq_grpc("MethodPattern")
+                ),
+                |w| {
+                    w.write_line("pattern.matches(self.path())");
+                },
+            );
+        });
+    }
+
     fn write_client(&self, w: &mut CodeWriter) {
         w.pub_struct(&self.client_name(), |w| {
             w.field_decl("client", "::grpcio::Client");
@@ -478,6 +798,25 @@ impl<'a> ServiceGen<'a> {
 
         w.write_line("");
 
+        // So that one handler object can be shared by several services
+        // without being cloned.
+        w.impl_self_block(
+            format!(
+                "<T: {0} + ?Sized + Send + Sync> {0} for ::std::sync::Arc<T>",
+                self.service_name()
+            ),
+            |w| {
+                for (i, method) in self.methods.iter().enumerate() {
+                    if i != 0 {
+                        w.write_line("");
+                    }
+                    method.write_service_forward(w);
+                }
+            },
+        );
+
+        w.write_line("");
+
         let s = format!(
             "create_{}<S: {} + Send + Clone + 'static>(s: S) -> {}",
             to_snake_case(&self.service_name()),
@@ -504,18 +843,73 @@ impl<'a> ServiceGen<'a> {
         }
     }
 
+    fn write_transport(&self, w: &mut CodeWriter) {
+        for (i, method) in self.methods.iter().enumerate() {
+            if i != 0 {
+                w.write_line("");
+            }
+            method.write_descriptor(w);
+        }
+        w.write_line("");
+
+        let client = self.client_name();
+        w.pub_struct(&format!("{}<T>", client), |w| {
+            w.field_decl("transport", "T");
+        });
+        w.write_line("");
+
+        w.impl_self_block(&format!("<T: Transport> {}<T>", client), |w| {
+            w.pub_fn("new(transport: T) -> Self", |w| {
+                w.expr_block(&client, |w| {
+                    w.field_entry("transport", "transport");
+                });
+            });
+
+            for method in &self.methods {
+                w.write_line("");
+                method.write_transport_client(w);
+            }
+        });
+    }
+
     fn write(&self, w: &mut CodeWriter) {
         self.write_method_definitions(w);
         w.write_line("");
+        self.write_method_enum(w);
+        w.write_line("");
         self.write_client(w);
         w.write_line("");
         self.write_server(w);
     }
 }
 
+fn write_transport_prelude(w: &mut CodeWriter) {
+    w.comment("Generated for custom transports, grpcio is not required.");
+    w.write_line("");
+    w.derive(&["Clone", "Copy", "Debug", "PartialEq", "Eq"]);
+    w.pub_struct("MethodDescriptor", |w| {
+        w.pub_field_decl("name", "&'static str");
+        w.pub_field_decl("path", "&'static str");
+        w.pub_field_decl("client_streaming", "bool");
+        w.pub_field_decl("server_streaming", "bool");
+    });
+    w.write_line("");
+    w.comment("The way the generated clients make unary calls.");
+    w.pub_trait("Transport", |w| {
+        w.write_line("type Error;");
+        w.write_line("");
+        w.fn_def(
+            "unary<Req, Resp>(&self, method: &MethodDescriptor, req: &Req) -> \
+             Result<Resp, Self::Error> where Req: ::protobuf::Message, Resp: ::protobuf::Message",
+        );
+    });
+}
+
 fn gen_file(
     file: &FileDescriptorProto,
     root_scope: &RootScope,
+    transport: bool,
+    chunked: bool,
 ) -> Option<compiler_plugin::GenResult> {
     if file.get_service().is_empty() {
         return None;
@@ -527,10 +921,19 @@ fn gen_file(
     {
         let mut w = CodeWriter::new(&mut v);
         w.write_generated();
+        if transport {
+            w.write_line("");
+            write_transport_prelude(&mut w);
+        }
 
         for service in file.get_service() {
             w.write_line("");
-            ServiceGen::new(service, file, root_scope).write(&mut w);
+            let gen = ServiceGen::new(service, file, root_scope, chunked);
+            if transport {
+                gen.write_transport(&mut w);
+            } else {
+                gen.write(&mut w);
+            }
         }
     }
 
@@ -540,9 +943,52 @@ fn gen_file(
     })
 }
 
+/// Generate the gRPC code of `files_to_generate`.
+///
+/// The output only depends on the input: files are generated in the order of
+/// `files_to_generate`, and services and methods in the order they are
+/// declared.
 pub fn gen(
     file_descriptors: &[FileDescriptorProto],
     files_to_generate: &[String],
+) -> Vec<compiler_plugin::GenResult> {
+    gen_impl(file_descriptors, files_to_generate, false, false)
+}
+
+/// Same as `gen`, but every unary method also gets a chunked companion, see
+/// `grpcio::chunk`.
+///
+/// The companion of `Foo` is the duplex method `FooChunked`, described by
+/// `METHOD_<SERVICE>_FOO_CHUNKED`. Clients get `foo_chunked_opt` and
+/// `foo_chunked_async_opt`, and the service trait gets `foo_chunked`, which
+/// answers `Unimplemented` unless it's overridden. Responses are sent in
+/// chunks of `grpcio::chunk::DEFAULT_CHUNK_SIZE`. The companions are not
+/// variants of the method enum.
+pub fn gen_chunked(
+    file_descriptors: &[FileDescriptorProto],
+    files_to_generate: &[String],
+) -> Vec<compiler_plugin::GenResult> {
+    gen_impl(file_descriptors, files_to_generate, false, true)
+}
+
+/// Generate clients that make calls through a user supplied `Transport`
+/// instead of grpcio.
+///
+/// Besides the message types, the generated code only depends on protobuf,
+/// so it can be used where grpcio is not available, e.g. WASM. Every method
+/// gets a `MethodDescriptor`, but only unary methods get a client method.
+pub fn gen_transport(
+    file_descriptors: &[FileDescriptorProto],
+    files_to_generate: &[String],
+) -> Vec<compiler_plugin::GenResult> {
+    gen_impl(file_descriptors, files_to_generate, true, false)
+}
+
+fn gen_impl(
+    file_descriptors: &[FileDescriptorProto],
+    files_to_generate: &[String],
+    transport: bool,
+    chunked: bool,
 ) -> Vec<compiler_plugin::GenResult> {
     let files_map: HashMap<&str, &FileDescriptorProto> =
         file_descriptors.iter().map(|f| (f.get_name(), f)).collect();
@@ -558,12 +1004,222 @@ pub fn gen(
             continue;
         }
 
-        results.extend(gen_file(file, &root_scope).into_iter());
+        results.extend(gen_file(file, &root_scope, transport, chunked).into_iter());
     }
 
     results
 }
 
+// Generated files opt out of rustfmt by default.
+const RUSTFMT_SKIP: &str = "#![cfg_attr(rustfmt, rustfmt_skip)]\n";
+
+/// Same as `gen`, but the generated code is passed through `format`.
+///
+/// The attribute that makes rustfmt skip generated files is removed before
+/// formatting.
+pub fn gen_formatted<F>(
+    file_descriptors: &[FileDescriptorProto],
+    files_to_generate: &[String],
+    format: F,
+) -> io::Result<Vec<compiler_plugin::GenResult>>
+where
+    F: Fn(&str) -> io::Result<String>,
+{
+    format_results(gen(file_descriptors, files_to_generate), format)
+}
+
+fn format_results<F>(
+    mut results: Vec<compiler_plugin::GenResult>,
+    format: F,
+) -> io::Result<Vec<compiler_plugin::GenResult>>
+where
+    F: Fn(&str) -> io::Result<String>,
+{
+    for res in &mut results {
+        let code = str::from_utf8(&res.content)
+            .unwrap()
+            .replacen(RUSTFMT_SKIP, "", 1);
+        res.content = format(&code)?.into_bytes();
+    }
+    Ok(results)
+}
+
+/// Format `code` by the `rustfmt` found in `PATH`.
+pub fn rustfmt(code: &str) -> io::Result<String> {
+    let mut child = Command::new("rustfmt")
+        .args(&["--emit", "stdout", "--edition", "2015"])
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::inherit())
+        .spawn()?;
+    let mut stdin = child.stdin.take().unwrap();
+    let input = code.to_owned();
+    // Write in another thread, otherwise both sides may block on full pipes.
+    let writer = thread::spawn(move || stdin.write_all(input.as_bytes()));
+    let mut output = String::new();
+    child.stdout.take().unwrap().read_to_string(&mut output)?;
+    writer.join().unwrap()?;
+    let status = child.wait()?;
+    if !status.success() {
+        return Err(io::Error::new(
+            io::ErrorKind::Other,
+            format!("rustfmt exits with {}", status),
+        ));
+    }
+    Ok(output)
+}
+
+/// Entry of the protoc plugin.
+///
+/// Parameters are separated by commas, e.g. `--grpc_out=rustfmt,transport:.`:
+///
+/// - `rustfmt`: format the generated code by rustfmt.
+/// - `transport`: generate the code by `gen_transport` instead of `gen`.
+/// - `chunked`: generate the code by `gen_chunked` instead of `gen`, it's
+///   ignored with `transport`.
 pub fn protoc_gen_grpc_rust_main() {
-    compiler_plugin::plugin_main(gen);
+    let req: CodeGeneratorRequest = protobuf::parse_from_reader(&mut stdin()).unwrap();
+    let has_param = |name| req.get_parameter().split(',').any(|p| p.trim() == name);
+    let (format, transport) = (has_param("rustfmt"), has_param("transport"));
+    let chunked = !transport && has_param("chunked");
+    let (files, to_generate) = (req.get_proto_file(), req.get_file_to_generate());
+
+    let mut resp = CodeGeneratorResponse::new();
+    let results = gen_impl(files, to_generate, transport, chunked);
+    let results = if format {
+        format_results(results, rustfmt)
+    } else {
+        Ok(results)
+    };
+    match results {
+        Ok(results) => {
+            let files = results
+                .into_iter()
+                .map(|res| {
+                    let mut f = CodeGeneratorResponse_File::new();
+                    f.set_name(res.name);
+                    f.set_content(String::from_utf8(res.content).unwrap());
+                    f
+                })
+                .collect();
+            resp.set_file(files);
+        }
+        Err(e) => resp.set_error(format!("failed to format generated code: {}", e)),
+    }
+    resp.write_to_writer(&mut stdout()).unwrap();
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs::File;
+
+    use protobuf::descriptor::FileDescriptorSet;
+
+    use super::*;
+
+    fn load_example() -> (Vec<FileDescriptorProto>, Vec<String>) {
+        let mut f = File::open("../proto/example.desc").unwrap();
+        let mut set: FileDescriptorSet = protobuf::parse_from_reader(&mut f).unwrap();
+        let files = set.take_file().into_vec();
+        let names = files.iter().map(|f| f.get_name().to_owned()).collect();
+        (files, names)
+    }
+
+    #[test]
+    fn test_stable_output() {
+        let (files, names) = load_example();
+        let first = gen(&files, &names);
+        for _ in 0..3 {
+            let res = gen(&files, &names);
+            assert_eq!(res.len(), first.len());
+            for (a, b) in res.iter().zip(&first) {
+                assert_eq!(a.name, b.name);
+                assert_eq!(a.content, b.content);
+            }
+        }
+    }
+
+    #[test]
+    fn test_gen_formatted() {
+        let (files, names) = load_example();
+        let res = gen_formatted(&files, &names, |code| Ok(code.to_uppercase())).unwrap();
+        let raw = gen(&files, &names);
+        assert!(!raw.is_empty());
+        for (f, r) in res.iter().zip(&raw) {
+            let code = str::from_utf8(&f.content).unwrap();
+            let raw = str::from_utf8(&r.content).unwrap();
+            assert!(raw.contains(RUSTFMT_SKIP));
+            assert!(!code.contains(&RUSTFMT_SKIP.to_uppercase()));
+            assert_eq!(code, raw.replacen(RUSTFMT_SKIP, "", 1).to_uppercase());
+        }
+
+        let err = gen_formatted(&files, &names, |_| {
+            Err(io::Error::new(io::ErrorKind::Other, "boom"))
+        });
+        assert!(err.is_err());
+    }
+
+    #[test]
+    fn test_gen_transport() {
+        let (files, names) = load_example();
+        let res = gen_transport(&files, &names);
+        assert_eq!(res.len(), gen(&files, &names).len());
+        let code: String = res
+            .iter()
+            .map(|r| str::from_utf8(&r.content).unwrap())
+            .collect();
+        assert!(!code.contains("::grpcio"));
+        assert!(code.contains("pub trait Transport"));
+        assert!(code.contains("pub struct GreeterClient<T>"));
+        assert!(code.contains("pub const ROUTE_GUIDE_ROUTE_CHAT: MethodDescriptor"));
+        assert!(code.contains("pub fn get_feature(&self"));
+        assert!(!code.contains("pub fn route_chat(&self"));
+    }
+
+    #[test]
+    fn test_gen_chunked() {
+        let (files, names) = load_example();
+        let code = |res: Vec<compiler_plugin::GenResult>| -> String {
+            res.iter()
+                .map(|r| str::from_utf8(&r.content).unwrap().to_owned())
+                .collect()
+        };
+        assert!(!code(gen(&files, &names)).contains("Chunked"));
+
+        let code = code(gen_chunked(&files, &names));
+        assert!(code.contains("const METHOD_GREETER_SAY_HELLO_CHUNKED: "));
+        assert!(code.contains("name: \"/helloworld.Greeter/SayHelloChunked\","));
+        assert!(code.contains("pub fn say_hello_chunked_opt(&self"));
+        assert!(code.contains("pub fn say_hello_chunked_async_opt(&self"));
+        assert!(code.contains("fn say_hello_chunked(&self"));
+        assert!(code.contains(
+            "builder = builder.add_chunked_unary_handler(&METHOD_GREETER_SAY_HELLO_CHUNKED, "
+        ));
+        // Streaming methods don't have companions.
+        assert!(!code.contains("METHOD_ROUTE_GUIDE_ROUTE_CHAT_CHUNKED"));
+    }
+
+    #[test]
+    fn test_gen_deprecated() {
+        let (mut files, names) = load_example();
+        let code = |files: &[FileDescriptorProto]| -> String {
+            gen(files, &names)
+                .iter()
+                .map(|r| str::from_utf8(&r.content).unwrap().to_owned())
+                .collect()
+        };
+        assert!(!code(&files).contains("#[deprecated]"));
+
+        let file = files
+            .iter_mut()
+            .find(|f| f.get_service().iter().any(|s| s.get_name() == "Greeter"))
+            .unwrap();
+        file.mut_service()[0].mut_method()[0]
+            .mut_options()
+            .set_deprecated(true);
+        let code = code(&files);
+        assert!(code.contains("#[deprecated]\n    pub fn say_hello_opt("));
+        assert!(code.contains("#[deprecated]\n    #[allow(deprecated)]\n    pub fn say_hello("));
+        assert!(code.contains("builder = builder.deprecate_method(&METHOD_GREETER_SAY_HELLO);"));
+    }
 }
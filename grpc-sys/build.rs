@@ -23,16 +23,18 @@ use cc::Build;
 use cmake::Config;
 use pkg_config::Config as PkgConfig;
 
-const GRPC_VERSION: &'static str = "1.6.1";
+const GRPC_VERSION: &'static str = "1.7.2";
 
 fn link_grpc(cc: &mut Build, library: &str) {
     match PkgConfig::new()
         .atleast_version(GRPC_VERSION)
         .probe(library)
     {
-        Ok(lib) => for inc_path in lib.include_paths {
-            cc.include(inc_path);
-        },
+        Ok(lib) => {
+            for inc_path in lib.include_paths {
+                cc.include(inc_path);
+            }
+        }
         Err(e) => panic!("can't find library {} via pkg-config: {:?}", library, e),
     }
 }
@@ -44,7 +46,7 @@ fn prepare_grpc() {
         "grpc/third_party/cares/cares",
     ];
 
-    if cfg!(feature = "secure") {
+    if is_secure() {
         modules.push("grpc/third_party/boringssl");
     }
 
@@ -69,7 +71,7 @@ fn build_grpc(cc: &mut Build, library: &str) {
 
     let dst = {
         let mut config = Config::new("grpc");
-        if !cfg!(feature = "secure") {
+        if !is_secure() {
             // boringssl's configuration is still included, but targets
             // will never be built, hence specify a fake go to get rid of
             // the unnecessary dependency.
@@ -125,7 +127,7 @@ fn build_grpc(cc: &mut Build, library: &str) {
     println!("cargo:rustc-link-lib=static=gpr");
     println!("cargo:rustc-link-lib=static={}", library);
 
-    if cfg!(feature = "secure") {
+    if is_secure() {
         println!("cargo:rustc-link-lib=static=ssl");
         println!("cargo:rustc-link-lib=static=crypto");
     }
@@ -133,6 +135,12 @@ fn build_grpc(cc: &mut Build, library: &str) {
     cc.include("grpc/include");
 }
 
+/// Whether gRPC core is built with BoringSSL, which is needed by TLS on
+/// either side.
+fn is_secure() -> bool {
+    cfg!(feature = "tls-client") || cfg!(feature = "tls-server")
+}
+
 fn get_env(name: &str) -> Option<String> {
     println!("cargo:rerun-if-env-changed={}", name);
     match env::var(name) {
@@ -150,7 +158,13 @@ fn main() {
     println!("cargo:rerun-if-changed=grpc_wrap.c");
     println!("cargo:rerun-if-changed=grpc");
 
-    let library = if cfg!(feature = "secure") {
+    if cfg!(feature = "tls-client") {
+        cc.define("GRPC_SYS_TLS_CLIENT", None);
+    }
+    if cfg!(feature = "tls-server") {
+        cc.define("GRPC_SYS_TLS_SERVER", None);
+    }
+    let library = if is_secure() {
         cc.define("GRPC_SYS_SECURE", None);
         "grpc"
     } else {
@@ -55,12 +55,15 @@
 #include <grpc/support/log.h>
 #include <grpc/support/port_platform.h>
 #include <grpc/support/string_util.h>
+#include <grpc/support/sync.h>
 #include <grpc/support/thd.h>
+#include <grpc/support/useful.h>
 
 #ifdef GRPC_SYS_SECURE
 #include <grpc/grpc_security.h>
 #endif
 
+#include <stdbool.h>
 #include <string.h>
 
 #ifdef GPR_WINDOWS
@@ -194,6 +197,44 @@ GPR_EXPORT void GPR_CALLTYPE grpcwrap_metadata_array_add(
   array->count++;
 }
 
+/*
+ * Adds `count` entries in one call. `data` holds the keys and values back to
+ * back, whose lengths are given by `lengths` in pairs. The entries share a
+ * single copy of `data`.
+ */
+GPR_EXPORT void GPR_CALLTYPE grpcwrap_metadata_array_add_batch(
+    grpc_metadata_array* array, const char* data, size_t data_length,
+    const size_t* lengths, size_t count) {
+  GPR_ASSERT(array->count <= array->capacity);
+  size_t need = array->count + count;
+  size_t offset = 0;
+  size_t i;
+  grpc_slice all;
+  if (!count) {
+    return;
+  }
+  if (need > array->capacity) {
+    array->metadata =
+        gpr_realloc(array->metadata, need * sizeof(grpc_metadata));
+    memset(array->metadata + array->capacity, 0,
+           sizeof(grpc_metadata) * (need - array->capacity));
+    array->capacity = need;
+  }
+  all = grpc_slice_from_copied_buffer(data, data_length);
+  for (i = 0; i < count; i++) {
+    grpc_metadata* md = &array->metadata[array->count + i];
+    size_t key_length = lengths[2 * i];
+    size_t value_length = lengths[2 * i + 1];
+    GPR_ASSERT(offset + key_length + value_length <= data_length);
+    md->key = grpc_slice_sub(all, offset, offset + key_length);
+    offset += key_length;
+    md->value = grpc_slice_sub(all, offset, offset + value_length);
+    offset += value_length;
+  }
+  grpc_slice_unref(all);
+  array->count = need;
+}
+
 GPR_EXPORT const char* GPR_CALLTYPE grpcwrap_metadata_array_get_key(
     const grpc_metadata_array* array, size_t index, size_t* key_length) {
   GPR_ASSERT(index < array->count);
@@ -333,6 +374,56 @@ GPR_EXPORT void GPR_CALLTYPE grpcwrap_batch_context_recv_message_to_buffer(
   grpc_byte_buffer_reader_destroy(&reader);
 }
 
+/*
+ * Copies data from recv_message to a buffer in one pass, without walking
+ * the message twice as the length and the copy above do. Returns the length
+ * of the message, nothing is copied if the buffer is too small. Returns
+ * (size_t)-1 if there is no message.
+ */
+GPR_EXPORT size_t GPR_CALLTYPE grpcwrap_batch_context_recv_message_copy(
+    const grpcwrap_batch_context* ctx, char* buffer, size_t buffer_len) {
+  grpc_byte_buffer* bb = ctx->recv_message;
+  grpc_byte_buffer_reader reader;
+  grpc_slice slice;
+  size_t offset = 0;
+  size_t len;
+  size_t i;
+
+  if (!bb) {
+    return (size_t)-1;
+  }
+
+  /* Uncompressed messages are read from their slices directly. */
+  if (bb->type == GRPC_BB_RAW &&
+      bb->data.raw.compression == GRPC_COMPRESS_NONE) {
+    grpc_slice_buffer* slices = &bb->data.raw.slice_buffer;
+    len = slices->length;
+    if (len > buffer_len) {
+      return len;
+    }
+    for (i = 0; i < slices->count; i++) {
+      size_t slice_len = GRPC_SLICE_LENGTH(slices->slices[i]);
+      memcpy(buffer + offset, GRPC_SLICE_START_PTR(slices->slices[i]),
+             slice_len);
+      offset += slice_len;
+    }
+    return len;
+  }
+
+  GPR_ASSERT(grpc_byte_buffer_reader_init(&reader, bb));
+  len = grpc_byte_buffer_length(reader.buffer_out);
+  if (len <= buffer_len) {
+    while (grpc_byte_buffer_reader_next(&reader, &slice)) {
+      memcpy(buffer + offset, GRPC_SLICE_START_PTR(slice),
+             GRPC_SLICE_LENGTH(slice));
+      offset += GRPC_SLICE_LENGTH(slice);
+      grpc_slice_unref(slice);
+    }
+  }
+  grpc_byte_buffer_reader_destroy(&reader);
+  return len;
+}
+
 GPR_EXPORT grpc_status_code GPR_CALLTYPE
 grpcwrap_batch_context_recv_status_on_client_status(
     const grpcwrap_batch_context* ctx) {
@@ -452,15 +543,101 @@ GPR_EXPORT void GPR_CALLTYPE grpcwrap_channel_args_set_integer(
   args->args[index].value.integer = value;
 }
 
+/*
+ * Socket mutators are not part of the public API of gRPC core, following
+ * declarations mirror src/core/lib/iomgr/socket_mutator.h.
+ */
+struct grpc_socket_mutator;
+
+typedef struct {
+  bool (*mutate_fd)(int fd, struct grpc_socket_mutator* mutator);
+  int (*compare)(struct grpc_socket_mutator* a, struct grpc_socket_mutator* b);
+  void (*destroy)(struct grpc_socket_mutator* mutator);
+} grpcwrap_socket_mutator_vtable;
+
+void grpc_socket_mutator_init(struct grpc_socket_mutator* mutator,
+                              const grpcwrap_socket_mutator_vtable* vtable);
+grpc_arg grpc_socket_mutator_to_arg(struct grpc_socket_mutator* mutator);
+void grpc_socket_mutator_unref(struct grpc_socket_mutator* mutator);
+
+typedef int (*grpcwrap_mutate_fd_fn)(int fd, void* user_data);
+typedef void (*grpcwrap_destroy_fn)(void* user_data);
+
+typedef struct {
+  /* Must be the first field so that the mutator can be cast back. */
+  struct {
+    const grpcwrap_socket_mutator_vtable* vtable;
+    gpr_refcount refcount;
+  } base;
+  grpcwrap_mutate_fd_fn mutate_fd;
+  grpcwrap_destroy_fn destroy;
+  void* user_data;
+} grpcwrap_socket_mutator;
+
+static bool grpcwrap_socket_mutator_mutate_fd(
+    int fd, struct grpc_socket_mutator* mutator) {
+  grpcwrap_socket_mutator* m = (grpcwrap_socket_mutator*)mutator;
+  return m->mutate_fd(fd, m->user_data) != 0;
+}
+
+static int grpcwrap_socket_mutator_compare(struct grpc_socket_mutator* a,
+                                           struct grpc_socket_mutator* b) {
+  return GPR_ICMP(a, b);
+}
+
+static void grpcwrap_socket_mutator_destroy(
+    struct grpc_socket_mutator* mutator) {
+  grpcwrap_socket_mutator* m = (grpcwrap_socket_mutator*)mutator;
+  m->destroy(m->user_data);
+  gpr_free(m);
+}
+
+static const grpcwrap_socket_mutator_vtable grpcwrap_socket_mutator_vtable_impl =
+    {grpcwrap_socket_mutator_mutate_fd, grpcwrap_socket_mutator_compare,
+     grpcwrap_socket_mutator_destroy};
+
+/*
+ * Create a socket mutator that invokes `mutate_fd` on every new socket,
+ * `destroy` is invoked once the last reference is released.
+ */
+GPR_EXPORT grpcwrap_socket_mutator* GPR_CALLTYPE
+grpcwrap_socket_mutator_create(grpcwrap_mutate_fd_fn mutate_fd,
+                               grpcwrap_destroy_fn destroy, void* user_data) {
+  grpcwrap_socket_mutator* m = gpr_malloc(sizeof(grpcwrap_socket_mutator));
+  memset(m, 0, sizeof(grpcwrap_socket_mutator));
+  m->mutate_fd = mutate_fd;
+  m->destroy = destroy;
+  m->user_data = user_data;
+  grpc_socket_mutator_init((struct grpc_socket_mutator*)m,
+                           &grpcwrap_socket_mutator_vtable_impl);
+  return m;
+}
+
+/*
+ * Takes the ownership of the reference of `mutator`.
+ */
+GPR_EXPORT void GPR_CALLTYPE grpcwrap_channel_args_set_socket_mutator(
+    grpc_channel_args* args, size_t index, grpcwrap_socket_mutator* mutator) {
+  GPR_ASSERT(args);
+  GPR_ASSERT(index < args->num_args);
+  args->args[index] =
+      grpc_socket_mutator_to_arg((struct grpc_socket_mutator*)mutator);
+  args->args[index].key = gpr_strdup(args->args[index].key);
+}
+
 GPR_EXPORT void GPR_CALLTYPE
 grpcwrap_channel_args_destroy(grpc_channel_args* args) {
   size_t i;
   if (args) {
     for (i = 0; i < args->num_args; i++) {
-      gpr_free(args->args[i].key);
       if (args->args[i].type == GRPC_ARG_STRING) {
         gpr_free(args->args[i].value.string);
+      } else if (args->args[i].type == GRPC_ARG_POINTER &&
+                 strcmp(args->args[i].key, GRPC_ARG_SOCKET_MUTATOR) == 0) {
+        grpc_socket_mutator_unref(
+            (struct grpc_socket_mutator*)args->args[i].value.pointer.p);
       }
+      gpr_free(args->args[i].key);
     }
     gpr_free(args->args);
     gpr_free(args);
@@ -525,7 +702,7 @@ GPR_EXPORT grpc_call_error GPR_CALLTYPE grpcwrap_call_start_client_streaming(
     grpc_metadata_array* initial_metadata, uint32_t initial_metadata_flags,
     void* tag) {
   /* TODO: don't use magic number */
-  grpc_op ops[4];
+  grpc_op ops[3];
   memset(ops, 0, sizeof(ops));
   ops[0].op = GRPC_OP_SEND_INITIAL_METADATA;
   grpcwrap_metadata_array_move(&(ctx->send_initial_metadata), initial_metadata);
@@ -535,26 +712,20 @@ GPR_EXPORT grpc_call_error GPR_CALLTYPE grpcwrap_call_start_client_streaming(
   ops[0].flags = initial_metadata_flags;
   ops[0].reserved = NULL;
 
-  ops[1].op = GRPC_OP_RECV_INITIAL_METADATA;
-  ops[1].data.recv_initial_metadata.recv_initial_metadata =
-      &(ctx->recv_initial_metadata);
+  ops[1].op = GRPC_OP_RECV_MESSAGE;
+  ops[1].data.recv_message.recv_message = &(ctx->recv_message);
   ops[1].flags = 0;
   ops[1].reserved = NULL;
 
-  ops[2].op = GRPC_OP_RECV_MESSAGE;
-  ops[2].data.recv_message.recv_message = &(ctx->recv_message);
-  ops[2].flags = 0;
-  ops[2].reserved = NULL;
-
-  ops[3].op = GRPC_OP_RECV_STATUS_ON_CLIENT;
-  ops[3].data.recv_status_on_client.trailing_metadata =
+  ops[2].op = GRPC_OP_RECV_STATUS_ON_CLIENT;
+  ops[2].data.recv_status_on_client.trailing_metadata =
       &(ctx->recv_status_on_client.trailing_metadata);
-  ops[3].data.recv_status_on_client.status =
+  ops[2].data.recv_status_on_client.status =
       &(ctx->recv_status_on_client.status);
-  ops[3].data.recv_status_on_client.status_details =
+  ops[2].data.recv_status_on_client.status_details =
       &(ctx->recv_status_on_client.status_details);
-  ops[3].flags = 0;
-  ops[3].reserved = NULL;
+  ops[2].flags = 0;
+  ops[2].reserved = NULL;
 
   return grpc_call_start_batch(call, ops, sizeof(ops) / sizeof(ops[0]), tag,
                                NULL);
@@ -663,6 +834,47 @@ GPR_EXPORT grpc_call_error GPR_CALLTYPE grpcwrap_call_send_message(
   return grpc_call_start_batch(call, ops, nops, tag, NULL);
 }
 
+/*
+ * Sends a message made of `count` slices. The slices with user data are
+ * referenced by the message until it's sent and then released by `destroy`,
+ * the others are copied.
+ */
+GPR_EXPORT grpc_call_error GPR_CALLTYPE grpcwrap_call_send_message_slices(
+    grpc_call* call, grpcwrap_batch_context* ctx, const char** buffers,
+    const size_t* lengths, void** user_data, size_t count,
+    void (*destroy)(void*), uint32_t write_flags,
+    int32_t send_empty_initial_metadata, void* tag) {
+  grpc_op ops[2];
+  grpc_slice* slices =
+      (grpc_slice*)gpr_malloc(sizeof(grpc_slice) * GPR_MAX(count, 1));
+  size_t i;
+  for (i = 0; i < count; i++) {
+    if (user_data[i]) {
+      slices[i] = grpc_slice_new_with_user_data(
+          (void*)buffers[i], lengths[i], destroy, user_data[i]);
+    } else {
+      slices[i] = grpc_slice_from_copied_buffer(buffers[i], lengths[i]);
+    }
+  }
+  ctx->send_message = grpc_raw_byte_buffer_create(slices, count);
+  for (i = 0; i < count; i++) {
+    grpc_slice_unref(slices[i]);
+  }
+  gpr_free(slices);
+
+  memset(ops, 0, sizeof(ops));
+  size_t nops = send_empty_initial_metadata ? 2 : 1;
+  ops[0].op = GRPC_OP_SEND_MESSAGE;
+  ops[0].data.send_message.send_message = ctx->send_message;
+  ops[0].flags = write_flags;
+  ops[0].reserved = NULL;
+  ops[1].op = GRPC_OP_SEND_INITIAL_METADATA;
+  ops[1].flags = 0;
+  ops[1].reserved = NULL;
+
+  return grpc_call_start_batch(call, ops, nops, tag, NULL);
+}
+
 GPR_EXPORT grpc_call_error GPR_CALLTYPE
 grpcwrap_call_send_close_from_client(grpc_call* call, void* tag) {
   /* TODO: don't use magic number */
@@ -771,10 +983,25 @@ grpcwrap_server_request_call(grpc_server* server, grpc_completion_queue* cq,
                                   &(ctx->request_metadata), cq, cq, tag);
 }
 
+#ifdef GRPC_POSIX_SOCKET
+/* Declared by src/core/lib/iomgr/ev_posix.h, which is not public. */
+const char* grpc_get_poll_strategy_name();
+#endif
+
+GPR_EXPORT const char* GPR_CALLTYPE grpcwrap_poll_strategy_name() {
+#ifdef GRPC_POSIX_SOCKET
+  return grpc_get_poll_strategy_name();
+#else
+  return NULL;
+#endif
+}
+
 #ifdef GRPC_SYS_SECURE
 
 /* Security */
 
+#ifdef GRPC_SYS_TLS_CLIENT
+
 static char* default_pem_root_certs = NULL;
 
 static grpc_ssl_roots_override_result override_ssl_roots_handler(
@@ -815,6 +1042,10 @@ grpcwrap_ssl_credentials_create(const char* pem_root_certs,
   }
 }
 
+#endif
+
+#ifdef GRPC_SYS_TLS_SERVER
+
 GPR_EXPORT grpc_server_credentials* GPR_CALLTYPE
 grpcwrap_ssl_server_credentials_create(
     const char* pem_root_certs, const char** key_cert_pair_cert_chain_array,
@@ -845,3 +1076,20 @@ grpcwrap_ssl_server_credentials_create(
 }
 
 #endif
+
+#ifdef GRPC_SYS_TLS_CLIENT
+
+GPR_EXPORT void GPR_CALLTYPE grpcwrap_metadata_credentials_notify_from_plugin(
+    grpc_credentials_plugin_metadata_cb cb, void* user_data,
+    grpc_metadata_array* metadata, grpc_status_code status,
+    const char* error_details) {
+  if (metadata) {
+    cb(user_data, metadata->metadata, metadata->count, status, error_details);
+  } else {
+    cb(user_data, NULL, 0, status, error_details);
+  }
+}
+
+#endif
+
+#endif
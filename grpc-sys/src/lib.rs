@@ -258,6 +258,7 @@ pub enum GrpcChannelArgs {}
 /// Connectivity state of a channel.
 ///
 /// Based on `grpc_connectivity_state`.
+#[derive(Debug, PartialEq, Clone, Copy)]
 #[repr(C)]
 pub enum GrpcConnectivityState {
     /// Channel has just been initialized.
@@ -287,6 +288,7 @@ pub enum GrpcConnectivityState {
 /// compression algorithms.
 ///
 /// Based on `grpc_compression_level`.
+#[derive(Clone, Copy, Debug, PartialEq)]
 #[repr(C)]
 pub enum GrpcCompressionLevel {
     /// No compression.
@@ -306,6 +308,7 @@ pub enum GrpcCompressionLevel {
 /// Various compression algorithms supported by gRPC.
 ///
 /// Based on `grpc_compression_algorithm`.
+#[derive(Clone, Copy, Debug, PartialEq)]
 #[repr(C)]
 pub enum GrpcCompressionAlgorithms {
     None = 0,
@@ -313,6 +316,15 @@ pub enum GrpcCompressionAlgorithms {
     Gzip,
 }
 
+/// Stream compression algorithms supported by gRPC.
+///
+/// Based on `grpc_stream_compression_algorithm`.
+#[repr(C)]
+pub enum GrpcStreamCompressionAlgorithms {
+    None = 0,
+    Gzip,
+}
+
 /// How to handle payloads for a registered method.
 ///
 /// Based on `grpc_server_register_method_payload_handling`.
@@ -341,6 +353,15 @@ pub struct GprLogFuncArgs {
     pub message: *const c_char,
 }
 
+/// Functions used by gRPC core to manage memory, see `gpr_set_allocation_functions`.
+#[repr(C)]
+pub struct GprAllocationFunctions {
+    pub malloc_fn: Option<unsafe extern "C" fn(size: size_t) -> *mut c_void>,
+    pub zalloc_fn: Option<unsafe extern "C" fn(size: size_t) -> *mut c_void>,
+    pub realloc_fn: Option<unsafe extern "C" fn(ptr: *mut c_void, size: size_t) -> *mut c_void>,
+    pub free_fn: Option<unsafe extern "C" fn(ptr: *mut c_void)>,
+}
+
 #[repr(C)]
 pub struct GrpcMetadataArray {
     pub count: size_t,
@@ -371,6 +392,7 @@ pub enum GrpcBatchContext {}
 pub enum GrpcServer {}
 pub enum GrpcRequestCallContext {}
 pub enum GrpcAlarm {}
+pub enum GrpcSocketMutator {}
 
 pub const GRPC_MAX_COMPLETION_QUEUE_PLUCKERS: usize = 6;
 
@@ -394,6 +416,12 @@ extern "C" {
 
     pub fn gpr_cpu_num_cores() -> c_uint;
 
+    /// Get the name of the polling engine, it's only valid after
+    /// `grpc_init`. It's null on platforms other than posix.
+    pub fn grpcwrap_poll_strategy_name() -> *const c_char;
+
+    pub fn gpr_set_allocation_functions(functions: GprAllocationFunctions);
+
     pub fn grpc_completion_queue_create_for_next(reserved: *mut c_void)
         -> *mut GrpcCompletionQueue;
     pub fn grpc_completion_queue_next(
@@ -423,12 +451,29 @@ extern "C" {
         key: *const c_char,
         value: c_int,
     );
+    pub fn grpcwrap_channel_args_set_socket_mutator(
+        args: *mut GrpcChannelArgs,
+        index: size_t,
+        mutator: *mut GrpcSocketMutator,
+    );
     pub fn grpcwrap_channel_args_destroy(args: *mut GrpcChannelArgs);
+    pub fn grpcwrap_socket_mutator_create(
+        mutate_fd: extern "C" fn(fd: c_int, user_data: *mut c_void) -> c_int,
+        destroy: extern "C" fn(user_data: *mut c_void),
+        user_data: *mut c_void,
+    ) -> *mut GrpcSocketMutator;
 
     pub fn grpc_channel_check_connectivity_state(
         channel: *mut GrpcChannel,
         try_to_connect: c_int,
     ) -> GrpcConnectivityState;
+    pub fn grpc_channel_watch_connectivity_state(
+        channel: *mut GrpcChannel,
+        last_observed_state: GrpcConnectivityState,
+        deadline: GprTimespec,
+        cq: *mut GrpcCompletionQueue,
+        tag: *mut c_void,
+    );
     pub fn grpcwrap_channel_create_call(
         channel: *mut GrpcChannel,
         parent_call: *mut GrpcCall,
@@ -460,6 +505,11 @@ extern "C" {
         buffer: *mut c_char,
         buffer_len: size_t,
     );
+    pub fn grpcwrap_batch_context_recv_message_copy(
+        ctx: *mut GrpcBatchContext,
+        buffer: *mut c_char,
+        buffer_len: size_t,
+    ) -> size_t;
     pub fn grpcwrap_batch_context_recv_status_on_client_status(
         ctx: *mut GrpcBatchContext,
     ) -> GrpcStatusCode;
@@ -522,6 +572,18 @@ extern "C" {
         send_empty_initial_metadata: uint32_t,
         tag: *mut c_void,
     ) -> GrpcCallStatus;
+    pub fn grpcwrap_call_send_message_slices(
+        call: *mut GrpcCall,
+        ctx: *mut GrpcBatchContext,
+        buffers: *const *const c_char,
+        lengths: *const size_t,
+        user_data: *const *mut c_void,
+        count: size_t,
+        destroy: unsafe extern "C" fn(*mut c_void),
+        write_flags: uint32_t,
+        send_empty_initial_metadata: uint32_t,
+        tag: *mut c_void,
+    ) -> GrpcCallStatus;
     pub fn grpcwrap_call_send_close_from_client(
         call: *mut GrpcCall,
         tag: *mut c_void,
@@ -564,6 +626,7 @@ extern "C" {
         description: *const c_char,
         reserved: *mut c_void,
     );
+    pub fn grpc_call_ref(call: *mut GrpcCall);
     pub fn grpc_call_unref(call: *mut GrpcCall);
 
     pub fn grpc_server_register_method(
@@ -643,6 +706,13 @@ extern "C" {
         val: *const c_char,
         val_len: size_t,
     );
+    pub fn grpcwrap_metadata_array_add_batch(
+        array: *mut GrpcMetadataArray,
+        data: *const c_char,
+        data_length: size_t,
+        lengths: *const size_t,
+        count: size_t,
+    );
     pub fn grpcwrap_metadata_array_get_key(
         array: *const GrpcMetadataArray,
         index: size_t,
@@ -659,38 +729,133 @@ extern "C" {
     pub fn gpr_free(p: *mut c_void);
 }
 
-#[cfg(feature = "secure")]
+#[cfg(unix)]
+extern "C" {
+    pub fn grpc_insecure_channel_create_from_fd(
+        target: *const c_char,
+        fd: c_int,
+        args: *const GrpcChannelArgs,
+    ) -> *mut GrpcChannel;
+    pub fn grpc_server_add_insecure_channel_from_fd(
+        server: *mut GrpcServer,
+        reserved: *mut c_void,
+        fd: c_int,
+    );
+}
+
+#[cfg(any(feature = "tls-client", feature = "tls-server"))]
 mod secure_component {
     use libc::{c_char, c_int, c_void, size_t};
 
-    use super::{GrpcChannel, GrpcChannelArgs, GrpcServer};
+    #[cfg(feature = "tls-client")]
+    use super::{GprTimespec, GrpcChannel, GrpcChannelArgs, GrpcMetadataArray};
+    #[cfg(feature = "tls-server")]
+    use super::{GrpcCall, GrpcServer};
+    use super::{GrpcMetadata, GrpcStatusCode};
 
     pub enum GrpcChannelCredentials {}
     pub enum GrpcServerCredentials {}
+    pub enum GrpcCallCredentials {}
+    pub enum GrpcAuthContext {}
+
+    /// Context that can be used by metadata credentials plugin in order to create auth related
+    /// metadata.
+    #[repr(C)]
+    pub struct GrpcAuthMetadataContext {
+        pub service_url: *const c_char,
+        pub method_name: *const c_char,
+        pub channel_auth_context: *const GrpcAuthContext,
+        pub reserved: *mut c_void,
+    }
+
+    #[repr(C)]
+    pub struct GrpcAuthPropertyIterator {
+        pub ctx: *const GrpcAuthContext,
+        pub index: size_t,
+        pub name: *const c_char,
+    }
+
+    #[repr(C)]
+    pub struct GrpcAuthProperty {
+        pub name: *mut c_char,
+        pub value: *mut c_char,
+        pub value_length: size_t,
+    }
+
+    pub type GrpcCredentialsPluginMetadataCb = extern "C" fn(
+        user_data: *mut c_void,
+        creds_md: *const GrpcMetadata,
+        num_creds_md: size_t,
+        status: GrpcStatusCode,
+        error_details: *const c_char,
+    );
 
+    #[repr(C)]
+    pub struct GrpcMetadataCredentialsPlugin {
+        /// Returns 0 if the metadata will be delivered by `cb` asynchronously,
+        /// otherwise the out parameters are filled synchronously.
+        pub get_metadata: extern "C" fn(
+            state: *mut c_void,
+            context: GrpcAuthMetadataContext,
+            cb: GrpcCredentialsPluginMetadataCb,
+            user_data: *mut c_void,
+            creds_md: *mut GrpcMetadata,
+            num_creds_md: *mut size_t,
+            status: *mut GrpcStatusCode,
+            error_details: *mut *const c_char,
+        ) -> c_int,
+        pub destroy: extern "C" fn(state: *mut c_void),
+        pub state: *mut c_void,
+        pub type_: *const c_char,
+    }
+
+    #[cfg(feature = "tls-client")]
     extern "C" {
         pub fn grpcwrap_ssl_credentials_create(
             root_certs: *const c_char,
             cert_chain: *const c_char,
             private_key: *const c_char,
         ) -> *mut GrpcChannelCredentials;
-
         pub fn grpc_secure_channel_create(
             creds: *mut GrpcChannelCredentials,
             target: *const c_char,
             args: *const GrpcChannelArgs,
             reserved: *mut c_void,
         ) -> *mut GrpcChannel;
-
         pub fn grpc_google_default_credentials_create() -> *mut GrpcChannelCredentials;
+        pub fn grpcwrap_override_default_ssl_roots(certs: *const c_char);
+        pub fn grpc_channel_credentials_release(credentials: *mut GrpcChannelCredentials);
+        pub fn grpc_metadata_credentials_create_from_plugin(
+            plugin: GrpcMetadataCredentialsPlugin,
+            reserved: *mut c_void,
+        ) -> *mut GrpcCallCredentials;
+        pub fn grpc_composite_channel_credentials_create(
+            channel_creds: *mut GrpcChannelCredentials,
+            call_creds: *mut GrpcCallCredentials,
+            reserved: *mut c_void,
+        ) -> *mut GrpcChannelCredentials;
+        pub fn grpc_call_credentials_release(credentials: *mut GrpcCallCredentials);
+        pub fn grpc_service_account_jwt_access_credentials_create(
+            json_key: *const c_char,
+            token_lifetime: GprTimespec,
+            reserved: *mut c_void,
+        ) -> *mut GrpcCallCredentials;
+        pub fn grpcwrap_metadata_credentials_notify_from_plugin(
+            cb: GrpcCredentialsPluginMetadataCb,
+            user_data: *mut c_void,
+            metadata: *mut GrpcMetadataArray,
+            status: GrpcStatusCode,
+            error_details: *const c_char,
+        );
+    }
+
+    #[cfg(feature = "tls-server")]
+    extern "C" {
         pub fn grpc_server_add_secure_http2_port(
             server: *mut GrpcServer,
             addr: *const c_char,
             creds: *mut GrpcServerCredentials,
         ) -> c_int;
-
-        pub fn grpcwrap_override_default_ssl_roots(certs: *const c_char);
-        pub fn grpc_channel_credentials_release(credentials: *mut GrpcChannelCredentials);
         pub fn grpcwrap_ssl_server_credentials_create(
             root_certs: *const c_char,
             cert_chain_array: *mut *const c_char,
@@ -699,10 +864,22 @@ mod secure_component {
             force_client_auth: c_int,
         ) -> *mut GrpcServerCredentials;
         pub fn grpc_server_credentials_release(credentials: *mut GrpcServerCredentials);
+        pub fn grpc_call_auth_context(call: *mut GrpcCall) -> *mut GrpcAuthContext;
+        pub fn grpc_auth_context_release(context: *mut GrpcAuthContext);
+        pub fn grpc_auth_context_property_iterator(
+            ctx: *const GrpcAuthContext,
+        ) -> GrpcAuthPropertyIterator;
+        pub fn grpc_auth_property_iterator_next(
+            it: *mut GrpcAuthPropertyIterator,
+        ) -> *const GrpcAuthProperty;
+        pub fn grpc_auth_context_peer_identity_property_name(
+            ctx: *const GrpcAuthContext,
+        ) -> *const c_char;
+        pub fn grpc_auth_context_peer_is_authenticated(ctx: *const GrpcAuthContext) -> c_int;
     }
 }
 
-#[cfg(feature = "secure")]
+#[cfg(any(feature = "tls-client", feature = "tls-server"))]
 pub use secure_component::*;
 
 // TODO: more tests.
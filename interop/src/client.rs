@@ -15,7 +15,7 @@ use std::thread;
 use std::time::Duration;
 
 use futures::{future, stream, Future, Sink, Stream};
-use grpc::{self, CallOption, Channel, RpcStatusCode, WriteFlags};
+use grpc::{self, CallOption, Channel, Metadata, MetadataBuilder, RpcStatusCode, WriteFlags};
 
 use grpc_proto::testing::empty::Empty;
 use grpc_proto::testing::messages::{
@@ -24,6 +24,23 @@ use grpc_proto::testing::messages::{
 use grpc_proto::testing::test_grpc::{TestServiceClient, UnimplementedServiceClient};
 use grpc_proto::util;
 
+use server::{ECHO_INITIAL_KEY, ECHO_TRAILING_KEY};
+
+const ECHO_INITIAL_VALUE: &str = "test_initial_metadata_value";
+const ECHO_TRAILING_VALUE: &[u8] = &[0xab, 0xab, 0xab];
+
+fn find<'a>(meta: &'a Metadata, key: &str) -> Option<&'a [u8]> {
+    meta.iter().find(|&(k, _)| k == key).map(|(_, v)| v)
+}
+
+fn check_echoed(headers: &Metadata, trailers: &Metadata) {
+    assert_eq!(
+        find(headers, ECHO_INITIAL_KEY),
+        Some(ECHO_INITIAL_VALUE.as_bytes())
+    );
+    assert_eq!(find(trailers, ECHO_TRAILING_KEY), Some(ECHO_TRAILING_VALUE));
+}
+
 pub struct Client {
     channel: Channel,
     client: TestServiceClient,
@@ -212,6 +229,68 @@ impl Client {
         println!("pass");
     }
 
+    pub fn special_status_message(&self) {
+        print!("testing special_status_message ... ");
+        let error_msg =
+            "\t\ntest with whitespace\r\nand Unicode BMP \u{263A} and non-BMP \u{1F648}\t\n";
+        let mut status = EchoStatus::new();
+        status.set_code(2);
+        status.set_message(error_msg.to_owned());
+        let mut req = SimpleRequest::new();
+        req.set_response_status(status);
+        match self.client.unary_call(&req).unwrap_err() {
+            grpc::Error::RpcFailure(s) => {
+                assert_eq!(s.status, RpcStatusCode::Unknown);
+                assert_eq!(s.details.as_ref().unwrap(), error_msg);
+            }
+            e => panic!("expected rpc failure: {:?}", e),
+        }
+        println!("pass");
+    }
+
+    pub fn custom_metadata(&self) {
+        print!("testing custom_metadata ... ");
+        let call_opt = || {
+            let mut builder = MetadataBuilder::new();
+            builder
+                .add_str(ECHO_INITIAL_KEY, ECHO_INITIAL_VALUE)
+                .unwrap()
+                .add_bytes(ECHO_TRAILING_KEY, ECHO_TRAILING_VALUE)
+                .unwrap();
+            CallOption::default().headers(builder.build())
+        };
+
+        let mut req = SimpleRequest::new();
+        req.set_response_size(314_159);
+        req.set_payload(util::new_payload(271_828));
+        let mut receiver = self.client.unary_call_async_opt(&req, call_opt()).unwrap();
+        let resp = (&mut receiver).wait().unwrap();
+        assert_eq!(314_159, resp.get_payload().get_body().len());
+        check_echoed(
+            &receiver.take_headers().unwrap(),
+            &receiver.take_trailers().unwrap(),
+        );
+
+        let (sender, mut receiver) = self.client.full_duplex_call_opt(call_opt()).unwrap();
+        let mut req = StreamingOutputCallRequest::new();
+        req.mut_response_parameters()
+            .push(util::new_parameters(314_159));
+        req.set_payload(util::new_payload(271_828));
+        let sender = sender.send((req, WriteFlags::default())).wait().unwrap();
+        let (headers, mut sender) = sender.await_headers().wait().unwrap();
+        match (&mut receiver).into_future().wait() {
+            Ok((resp, _)) => assert_eq!(resp.unwrap().get_payload().get_body().len(), 314_159),
+            Err((e, _)) => panic!("{:?}", e),
+        }
+        future::poll_fn(|| sender.close()).wait().unwrap();
+        match (&mut receiver).into_future().wait() {
+            Ok((resp, _)) => assert!(resp.is_none()),
+            Err((e, _)) => panic!("{:?}", e),
+        }
+        check_echoed(&headers, &receiver.take_trailers().unwrap());
+        println!("pass");
+    }
+
     pub fn unimplemented_method(&self) {
         print!("testing unimplemented_method ... ");
         match self.client.unimplemented_call(&Empty::new()).unwrap_err() {
@@ -242,6 +321,8 @@ impl Client {
         self.cancel_after_first_response();
         self.timeout_on_sleeping_server();
         self.status_code_and_message();
+        self.special_status_message();
+        self.custom_metadata();
         self.unimplemented_method();
         self.unimplemented_service();
     }
@@ -13,8 +13,8 @@
 
 use futures::{future, stream, Async, Future, Poll, Sink, Stream};
 use grpc::{
-    self, ClientStreamingSink, DuplexSink, RequestStream, RpcContext, RpcStatus, RpcStatusCode,
-    ServerStreamingSink, UnarySink, WriteFlags,
+    self, ClientStreamingSink, DuplexSink, Metadata, MetadataBuilder, RequestStream, RpcContext,
+    RpcStatus, RpcStatusCode, ServerStreamingSink, UnarySink, WriteFlags,
 };
 
 use grpc_proto::testing::empty::Empty;
@@ -25,6 +25,35 @@ use grpc_proto::testing::messages::{
 use grpc_proto::testing::test_grpc::TestService;
 use grpc_proto::util;
 
+/// Key of the request header that is echoed back as initial metadata.
+pub const ECHO_INITIAL_KEY: &str = "x-grpc-test-echo-initial";
+/// Key of the request header that is echoed back as trailing metadata.
+pub const ECHO_TRAILING_KEY: &str = "x-grpc-test-echo-trailing-bin";
+
+// Take the headers that the client asks to echo back, as the initial and the
+// trailing metadata of the response.
+fn echo_metadata(ctx: &RpcContext) -> (Option<Metadata>, Option<Metadata>) {
+    let find = |key: &str| {
+        ctx.request_headers()
+            .iter()
+            .find(|&(k, _)| k == key)
+            .map(|(_, v)| v.to_vec())
+    };
+    let initial = find(ECHO_INITIAL_KEY).map(|v| {
+        let mut builder = MetadataBuilder::new();
+        builder
+            .add_str(ECHO_INITIAL_KEY, &String::from_utf8_lossy(&v))
+            .unwrap();
+        builder.build()
+    });
+    let trailing = find(ECHO_TRAILING_KEY).map(|v| {
+        let mut builder = MetadataBuilder::new();
+        builder.add_bytes(ECHO_TRAILING_KEY, &v).unwrap();
+        builder.build()
+    });
+    (initial, trailing)
+}
+
 enum Error {
     Grpc(grpc::Error),
     Abort,
@@ -48,7 +77,17 @@ impl TestService for InteropTestService {
         ctx.spawn(f)
     }
 
-    fn unary_call(&self, ctx: RpcContext, mut req: SimpleRequest, sink: UnarySink<SimpleResponse>) {
+    fn unary_call(
+        &self,
+        ctx: RpcContext,
+        mut req: SimpleRequest,
+        mut sink: UnarySink<SimpleResponse>,
+    ) {
+        let (initial, trailing) = echo_metadata(&ctx);
+        if let Some(initial) = initial {
+            // Failures show up when the response is sent.
+            let _ = sink.send_headers(initial);
+        }
         if req.has_response_status() {
             let code = req.get_response_status().get_code();
             let msg = Some(req.take_response_status().take_message());
@@ -62,9 +101,11 @@ impl TestService for InteropTestService {
         let resp_size = req.get_response_size();
         let mut resp = SimpleResponse::new();
         resp.set_payload(util::new_payload(resp_size as usize));
-        let f = sink
-            .success(resp)
-            .map_err(|e| panic!("failed to send response: {:?}", e));
+        let f = match trailing {
+            Some(trailing) => sink.success_with_trailers(resp, trailing),
+            None => sink.success(resp),
+        };
+        let f = f.map_err(|e| panic!("failed to send response: {:?}", e));
         ctx.spawn(f)
     }
 
@@ -116,8 +157,16 @@ impl TestService for InteropTestService {
         &self,
         ctx: RpcContext,
         stream: RequestStream<StreamingOutputCallRequest>,
-        sink: DuplexSink<StreamingOutputCallResponse>,
+        mut sink: DuplexSink<StreamingOutputCallResponse>,
     ) {
+        let (initial, trailing) = echo_metadata(&ctx);
+        if let Some(initial) = initial {
+            // Failures show up when the first response is sent.
+            let _ = sink.send_headers(initial);
+        }
+        if let Some(trailing) = trailing {
+            sink.set_trailers(trailing);
+        }
         let f = stream
             .map_err(Error::Grpc)
             .fold(sink, |sink, mut req| {
@@ -76,5 +76,7 @@ mk_test!(cancel_after_begin);
 mk_test!(cancel_after_first_response);
 mk_test!(timeout_on_sleeping_server);
 mk_test!(status_code_and_message);
+mk_test!(special_status_message);
+mk_test!(custom_metadata);
 mk_test!(unimplemented_method);
 mk_test!(unimplemented_service);
@@ -0,0 +1,325 @@
+// Copyright 2018 PingCAP, Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Server side access logging.
+//!
+//! When enabled by [`ServerBuilder::access_log`], an entry is produced for
+//! every call once its status is sent, and handed to an [`AccessLogSink`].
+//! Calls that are dropped without a status are not logged.
+//!
+//! [`ServerBuilder::access_log`]: ../struct.ServerBuilder.html#method.access_log
+//! [`AccessLogSink`]: trait.AccessLogSink.html
+
+use std::fmt::Write as FmtWrite;
+use std::io::Write;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use call::server::RpcContext;
+use call::RpcStatusCode;
+
+/// A record of a finished call.
+#[derive(Clone, Debug)]
+pub struct AccessLogEntry {
+    time: SystemTime,
+    method: String,
+    peer: String,
+    status: RpcStatusCode,
+    latency: Duration,
+    request_bytes: usize,
+    response_bytes: usize,
+    headers: Vec<(String, String)>,
+}
+
+impl AccessLogEntry {
+    /// The time when the call was accepted.
+    pub fn time(&self) -> SystemTime {
+        self.time
+    }
+
+    pub fn method(&self) -> &str {
+        &self.method
+    }
+
+    pub fn peer(&self) -> &str {
+        &self.peer
+    }
+
+    pub fn status(&self) -> RpcStatusCode {
+        self.status
+    }
+
+    /// Time between the call being accepted and the status being sent.
+    pub fn latency(&self) -> Duration {
+        self.latency
+    }
+
+    /// Total size of the messages received from the client.
+    pub fn request_bytes(&self) -> usize {
+        self.request_bytes
+    }
+
+    /// Total size of the messages sent to the client.
+    pub fn response_bytes(&self) -> usize {
+        self.response_bytes
+    }
+
+    /// The request headers selected by [`AccessLog::log_header`].
+    ///
+    /// [`AccessLog::log_header`]: struct.AccessLog.html#method.log_header
+    pub fn headers(&self) -> &[(String, String)] {
+        &self.headers
+    }
+}
+
+fn millis(d: Duration) -> f64 {
+    d.as_secs() as f64 * 1e3 + f64::from(d.subsec_nanos()) / 1e6
+}
+
+fn unix_secs(t: SystemTime) -> f64 {
+    let d = t.duration_since(UNIX_EPOCH).unwrap_or_default();
+    d.as_secs() as f64 + f64::from(d.subsec_nanos()) / 1e9
+}
+
+fn push_json_str(buf: &mut String, s: &str) {
+    buf.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => buf.push_str("\\\""),
+            '\\' => buf.push_str("\\\\"),
+            '\n' => buf.push_str("\\n"),
+            '\r' => buf.push_str("\\r"),
+            '\t' => buf.push_str("\\t"),
+            c if (c as u32) < 0x20 => {
+                let _ = write!(buf, "\\u{:04x}", c as u32);
+            }
+            c => buf.push(c),
+        }
+    }
+    buf.push('"');
+}
+
+/// Built-in formats of access log lines.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum AccessLogFormat {
+    /// One JSON object per line, for example
+    /// `{"time":1539500000.123,"method":"/helloworld.Greeter/SayHello",...}`.
+    JsonLines,
+    /// A line similar to the common log format, for example
+    /// `ipv4:127.0.0.1:5000 [1539500000.123] "/helloworld.Greeter/SayHello" 0 12 34 1.234ms`,
+    /// where the numbers are status code, request size, response size and
+    /// latency. Selected headers are appended as `key="value"`.
+    Common,
+}
+
+impl AccessLogFormat {
+    /// Format `entry` as a line, without the trailing newline.
+    pub fn format(self, entry: &AccessLogEntry) -> String {
+        let mut buf = String::new();
+        match self {
+            AccessLogFormat::JsonLines => {
+                let _ = write!(buf, "{{\"time\":{:.3},\"method\":", unix_secs(entry.time));
+                push_json_str(&mut buf, &entry.method);
+                buf.push_str(",\"peer\":");
+                push_json_str(&mut buf, &entry.peer);
+                let _ = write!(
+                    buf,
+                    ",\"status\":{},\"latency_ms\":{:.3},\"request_bytes\":{},\"response_bytes\":{}",
+                    entry.status as i32,
+                    millis(entry.latency),
+                    entry.request_bytes,
+                    entry.response_bytes
+                );
+                if !entry.headers.is_empty() {
+                    buf.push_str(",\"headers\":{");
+                    for (i, &(ref k, ref v)) in entry.headers.iter().enumerate() {
+                        if i > 0 {
+                            buf.push(',');
+                        }
+                        push_json_str(&mut buf, k);
+                        buf.push(':');
+                        push_json_str(&mut buf, v);
+                    }
+                    buf.push('}');
+                }
+                buf.push('}');
+            }
+            AccessLogFormat::Common => {
+                let _ = write!(
+                    buf,
+                    "{} [{:.3}] \"{}\" {} {} {} {:.3}ms",
+                    entry.peer,
+                    unix_secs(entry.time),
+                    entry.method,
+                    entry.status as i32,
+                    entry.request_bytes,
+                    entry.response_bytes,
+                    millis(entry.latency)
+                );
+                for &(ref k, ref v) in &entry.headers {
+                    let _ = write!(buf, " {}={:?}", k, v);
+                }
+            }
+        }
+        buf
+    }
+}
+
+/// Destination of access log entries.
+///
+/// It's invoked on the thread that sends the status, so it should not block
+/// for long.
+pub trait AccessLogSink: Send + Sync {
+    fn log(&self, entry: &AccessLogEntry);
+}
+
+impl<F: Fn(&AccessLogEntry) + Send + Sync> AccessLogSink for F {
+    fn log(&self, entry: &AccessLogEntry) {
+        self(entry)
+    }
+}
+
+/// Logs entries through the `log` crate at info level, with the target
+/// `grpcio::access_log`.
+pub struct LogSink {
+    format: AccessLogFormat,
+}
+
+impl LogSink {
+    pub fn new(format: AccessLogFormat) -> LogSink {
+        LogSink { format }
+    }
+}
+
+impl AccessLogSink for LogSink {
+    fn log(&self, entry: &AccessLogEntry) {
+        info!(target: "grpcio::access_log", "{}", self.format.format(entry));
+    }
+}
+
+/// Writes entries into a writer, one line per entry.
+///
+/// Write errors are ignored.
+pub struct WriterSink<W> {
+    format: AccessLogFormat,
+    writer: Mutex<W>,
+}
+
+impl<W: Write + Send> WriterSink<W> {
+    pub fn new(format: AccessLogFormat, writer: W) -> WriterSink<W> {
+        WriterSink {
+            format,
+            writer: Mutex::new(writer),
+        }
+    }
+
+    /// Take back the writer.
+    pub fn into_inner(self) -> W {
+        self.writer.into_inner().unwrap()
+    }
+}
+
+impl<W: Write + Send> AccessLogSink for WriterSink<W> {
+    fn log(&self, entry: &AccessLogEntry) {
+        let mut line = self.format.format(entry);
+        line.push('\n');
+        let _ = self.writer.lock().unwrap().write_all(line.as_bytes());
+    }
+}
+
+/// Configuration of server access logging.
+pub struct AccessLog {
+    sink: Box<AccessLogSink>,
+    headers: Vec<String>,
+}
+
+impl AccessLog {
+    pub fn new<S: AccessLogSink + 'static>(sink: S) -> AccessLog {
+        AccessLog {
+            sink: Box::new(sink),
+            headers: vec![],
+        }
+    }
+
+    /// Include the request header `key` in entries, if client sends it.
+    ///
+    /// Values of binary headers are not valid UTF-8 in general, so they are
+    /// logged lossily.
+    pub fn log_header(mut self, key: &str) -> AccessLog {
+        self.headers.push(key.to_ascii_lowercase());
+        self
+    }
+}
+
+/// Register a callback on `ctx` that logs the call once it's finished.
+pub(crate) fn attach(log: &Arc<AccessLog>, ctx: &RpcContext) {
+    let log = log.clone();
+    let method = String::from_utf8_lossy(ctx.method()).into_owned();
+    let peer = ctx.peer().to_string();
+    let headers = ctx
+        .request_headers()
+        .iter()
+        .filter(|&(k, _)| log.headers.iter().any(|h| h == k))
+        .map(|(k, v)| (k.to_owned(), String::from_utf8_lossy(v).into_owned()))
+        .collect();
+    ctx.on_complete(move |summary| {
+        let latency = summary.queue_time() + summary.processing_time();
+        let entry = AccessLogEntry {
+            time: SystemTime::now() - latency,
+            method,
+            peer,
+            status: summary.status(),
+            latency,
+            request_bytes: summary.request_bytes(),
+            response_bytes: summary.response_bytes(),
+            headers,
+        };
+        log.sink.log(&entry);
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_format() {
+        let entry = AccessLogEntry {
+            time: UNIX_EPOCH + Duration::from_millis(1_539_500_000_123),
+            method: "/helloworld.Greeter/SayHello".to_owned(),
+            peer: "ipv4:127.0.0.1:5000".to_owned(),
+            status: RpcStatusCode::NotFound,
+            latency: Duration::from_micros(1234),
+            request_bytes: 12,
+            response_bytes: 34,
+            headers: vec![("x-tenant".to_owned(), "a \"b\"".to_owned())],
+        };
+        assert_eq!(
+            AccessLogFormat::JsonLines.format(&entry),
+            "{\"time\":1539500000.123,\"method\":\"/helloworld.Greeter/SayHello\",\
+             \"peer\":\"ipv4:127.0.0.1:5000\",\"status\":5,\"latency_ms\":1.234,\
+             \"request_bytes\":12,\"response_bytes\":34,\"headers\":{\"x-tenant\":\"a \\\"b\\\"\"}}"
+        );
+        assert_eq!(
+            AccessLogFormat::Common.format(&entry),
+            "ipv4:127.0.0.1:5000 [1539500000.123] \"/helloworld.Greeter/SayHello\" 5 12 34 \
+             1.234ms x-tenant=\"a \\\"b\\\"\""
+        );
+
+        let sink = WriterSink::new(AccessLogFormat::Common, vec![]);
+        sink.log(&entry);
+        sink.log(&entry);
+        let out = String::from_utf8(sink.into_inner()).unwrap();
+        assert_eq!(out.lines().count(), 2);
+    }
+}
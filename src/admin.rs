@@ -0,0 +1,457 @@
+// Copyright 2018 PingCAP, Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! The bundle of admin services.
+//!
+//! [`register_admin_services`] adds the services operators expect on every
+//! server: health checking by `grpc.health.v1.Health`, and server
+//! reflection by `grpc.reflection.v1alpha.ServerReflection`. They are
+//! served along with the other services, or on a port of their own that is
+//! kept off the public network:
+//!
+//! ```ignore
+//! let admin = AdminServices::new().bind("127.0.0.1", 50052);
+//! let health = admin.health().clone();
+//! let mut server = admin::register_admin_services(ServerBuilder::new(env), &admin)
+//!     .register_service(greeter)
+//!     .bind("0.0.0.0", 50051)
+//!     .build()?;
+//! server.start();
+//! health.set_status("helloworld.Greeter", ServingStatus::Serving);
+//! ```
+//!
+//! Only `Check` of the health service is served. Every service is reported
+//! `NOT_SERVING` once the server is draining, and a separate admin port
+//! keeps serving until the server is dropped, so load balancers can see it
+//! while the server drains.
+//!
+//! Reflection answers from the descriptors registered to the
+//! [`global`](../descriptor/fn.global.html) pool. Channelz is not included,
+//! gRPC core 1.7 doesn't collect the channel and socket data it reports.
+//!
+//! [`register_admin_services`]: fn.register_admin_services.html
+
+use std::collections::{HashMap, HashSet};
+use std::sync::{Arc, RwLock};
+
+use futures::{Future, Sink, Stream};
+use protobuf::descriptor::FileDescriptorProto;
+use protobuf::wire_format::WireType;
+use protobuf::{CodedInputStream, CodedOutputStream, Message, ProtobufResult};
+
+use call::{Method, MethodType, RpcStatus, RpcStatusCode, WriteFlags};
+use codec::{raw_codec, Marshaller};
+use descriptor::{self, DescriptorPool};
+use error::{Error, Result};
+use server::{ServerBuilder, Service, ServiceBuilder};
+
+/// The status of a service reported by health checking.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ServingStatus {
+    Unknown = 0,
+    Serving = 1,
+    NotServing = 2,
+}
+
+/// The statuses reported by the health service, shared by its clones.
+#[derive(Clone, Default)]
+pub struct HealthRegistry {
+    statuses: Arc<RwLock<HashMap<String, ServingStatus>>>,
+}
+
+impl HealthRegistry {
+    pub fn new() -> HealthRegistry {
+        HealthRegistry::default()
+    }
+
+    /// Set the status of `service`, e.g. `helloworld.Greeter`. The empty
+    /// name stands for the whole server, which is `SERVING` unless set.
+    pub fn set_status(&self, service: &str, status: ServingStatus) {
+        self.statuses
+            .write()
+            .unwrap()
+            .insert(service.to_owned(), status);
+    }
+
+    /// Forget `service`, it's reported as not found afterwards.
+    pub fn clear_status(&self, service: &str) {
+        self.statuses.write().unwrap().remove(service);
+    }
+
+    /// Get the status of `service`, `None` if it's unknown.
+    pub fn status(&self, service: &str) -> Option<ServingStatus> {
+        match self.statuses.read().unwrap().get(service) {
+            Some(s) => Some(*s),
+            None if service.is_empty() => Some(ServingStatus::Serving),
+            None => None,
+        }
+    }
+}
+
+/// The admin services to register, see [`admin`](index.html) for details.
+#[derive(Clone, Default)]
+pub struct AdminServices {
+    health: HealthRegistry,
+    addr: Option<(String, u16)>,
+}
+
+impl AdminServices {
+    pub fn new() -> AdminServices {
+        AdminServices::default()
+    }
+
+    /// Serve the admin services on `host:port` only, by a server of their
+    /// own that is started and dropped with the server.
+    pub fn bind<S: Into<String>>(mut self, host: S, port: u16) -> AdminServices {
+        self.addr = Some((host.into(), port));
+        self
+    }
+
+    /// Get the statuses reported by the health service.
+    pub fn health(&self) -> &HealthRegistry {
+        &self.health
+    }
+
+    fn services(&self) -> Vec<Service> {
+        let health = self.health.clone();
+        let health = ServiceBuilder::new()
+            .add_unary_handler(&METHOD_HEALTH_CHECK, move |ctx, req, sink| {
+                let res = match health.status(&req.service) {
+                    Some(_) if ctx.is_server_draining() => {
+                        sink.success(health_response(ServingStatus::NotServing))
+                    }
+                    Some(s) => sink.success(health_response(s)),
+                    None => sink.fail(RpcStatus::new(
+                        RpcStatusCode::NotFound,
+                        Some(format!("unknown service {}", req.service)),
+                    )),
+                };
+                ctx.spawn(res.map_err(|e| error!("failed to report health: {:?}", e)));
+            })
+            .build();
+        let reflection = ServiceBuilder::new()
+            .add_duplex_streaming_handler(&METHOD_SERVER_REFLECTION_INFO, |ctx, reqs, sink| {
+                let resps = reqs.map(|req| {
+                    let pool = descriptor::global().read().unwrap();
+                    (reflect(&pool, &req), WriteFlags::default())
+                });
+                // The client may go away at any time.
+                ctx.spawn(sink.send_all(resps).map(|_| ()).map_err(|_| ()));
+            })
+            .build();
+        vec![health, reflection]
+    }
+}
+
+/// Register the admin services to `builder`, or to a server of their own if
+/// `admin` is bound to an address, see [`admin`](index.html) for details.
+pub fn register_admin_services(builder: ServerBuilder, admin: &AdminServices) -> ServerBuilder {
+    let services = admin.services();
+    match admin.addr {
+        Some((ref host, port)) => builder.admin_server(services, host.clone(), port),
+        None => services
+            .into_iter()
+            .fold(builder, |b, s| b.register_service(s)),
+    }
+}
+
+/// Get the numbers and contents of the length delimited fields of a
+/// message, the requests here have no other fields.
+fn parse_fields(buf: &[u8]) -> Result<Vec<(u32, Vec<u8>)>> {
+    let mut is = CodedInputStream::from_bytes(buf);
+    let mut fields = vec![];
+    while !is.eof()? {
+        let (number, wire_type) = is.read_tag_unpack()?;
+        match wire_type {
+            WireType::WireTypeLengthDelimited => fields.push((number, is.read_bytes()?)),
+            _ => is.skip_field(wire_type)?,
+        }
+    }
+    Ok(fields)
+}
+
+fn into_string(bytes: Vec<u8>) -> Result<String> {
+    String::from_utf8(bytes).map_err(|e| Error::Codec(Box::new(e)))
+}
+
+fn write_fields<F>(f: F) -> Vec<u8>
+where
+    F: FnOnce(&mut CodedOutputStream) -> ProtobufResult<()>,
+{
+    let mut buf = vec![];
+    {
+        let mut os = CodedOutputStream::vec(&mut buf);
+        // Writing to a vector never fails.
+        f(&mut os).and_then(|_| os.flush()).unwrap();
+    }
+    buf
+}
+
+struct HealthCheckRequest {
+    service: String,
+}
+
+fn ser_health_request(req: &HealthCheckRequest, buf: &mut Vec<u8>) {
+    buf.extend(write_fields(|os| os.write_string(1, &req.service)));
+}
+
+fn de_health_request(buf: &[u8]) -> Result<HealthCheckRequest> {
+    let mut req = HealthCheckRequest {
+        service: String::new(),
+    };
+    for (number, bytes) in parse_fields(buf)? {
+        if number == 1 {
+            req.service = into_string(bytes)?;
+        }
+    }
+    Ok(req)
+}
+
+fn health_response(status: ServingStatus) -> Vec<u8> {
+    write_fields(|os| os.write_enum(1, status as i32))
+}
+
+const METHOD_HEALTH_CHECK: Method<HealthCheckRequest, Vec<u8>> = Method {
+    ty: MethodType::Unary,
+    name: "/grpc.health.v1.Health/Check",
+    req_mar: Marshaller {
+        ser: ser_health_request,
+        de: de_health_request,
+    },
+    resp_mar: Marshaller {
+        ser: raw_codec::ser,
+        de: raw_codec::de,
+    },
+};
+
+enum Query {
+    FileByFilename(String),
+    FileContainingSymbol(String),
+    ListServices,
+    Unsupported,
+}
+
+struct ReflectionRequest {
+    // Echoed in the response.
+    raw: Vec<u8>,
+    host: String,
+    query: Query,
+}
+
+fn ser_reflection_request(req: &ReflectionRequest, buf: &mut Vec<u8>) {
+    buf.extend_from_slice(&req.raw);
+}
+
+fn de_reflection_request(buf: &[u8]) -> Result<ReflectionRequest> {
+    let mut req = ReflectionRequest {
+        raw: buf.to_vec(),
+        host: String::new(),
+        query: Query::Unsupported,
+    };
+    for (number, bytes) in parse_fields(buf)? {
+        match number {
+            1 => req.host = into_string(bytes)?,
+            3 => req.query = Query::FileByFilename(into_string(bytes)?),
+            4 => req.query = Query::FileContainingSymbol(into_string(bytes)?),
+            7 => req.query = Query::ListServices,
+            // Extensions are not tracked by the pool.
+            _ => req.query = Query::Unsupported,
+        }
+    }
+    Ok(req)
+}
+
+const METHOD_SERVER_REFLECTION_INFO: Method<ReflectionRequest, Vec<u8>> = Method {
+    ty: MethodType::Duplex,
+    name: "/grpc.reflection.v1alpha.ServerReflection/ServerReflectionInfo",
+    req_mar: Marshaller {
+        ser: ser_reflection_request,
+        de: de_reflection_request,
+    },
+    resp_mar: Marshaller {
+        ser: raw_codec::ser,
+        de: raw_codec::de,
+    },
+};
+
+/// Find the file defining `symbol`, which may also be a method.
+fn find_symbol(pool: &DescriptorPool, symbol: &str) -> Option<&'static FileDescriptorProto> {
+    if let Some(f) = pool.file_containing_symbol(symbol) {
+        return Some(f);
+    }
+    let service = &symbol[..symbol.rfind('.')?];
+    pool.service(service)?;
+    pool.file_containing_symbol(service)
+}
+
+/// Serialize `name` and the files it depends on, `name` goes first.
+fn file_with_deps(pool: &DescriptorPool, name: &str) -> Vec<Vec<u8>> {
+    let mut files = vec![];
+    let mut seen = HashSet::new();
+    let mut pending = vec![name.to_owned()];
+    while let Some(name) = pending.pop() {
+        if !seen.insert(name.clone()) {
+            continue;
+        }
+        // Dependencies that are not registered are left to the client.
+        if let Some(f) = pool.file(&name) {
+            files.push(f.write_to_bytes().unwrap());
+            pending.extend(f.get_dependency().iter().rev().cloned());
+        }
+    }
+    files
+}
+
+fn reflect(pool: &DescriptorPool, req: &ReflectionRequest) -> Vec<u8> {
+    let file = match req.query {
+        Query::FileByFilename(ref name) => pool.file(name),
+        Query::FileContainingSymbol(ref symbol) => find_symbol(pool, symbol),
+        _ => None,
+    };
+    write_fields(|os| {
+        os.write_string(1, &req.host)?;
+        os.write_bytes(2, &req.raw)?;
+        match (&req.query, file) {
+            (&Query::ListServices, _) => {
+                let list = write_fields(|os| {
+                    for name in pool.service_names() {
+                        os.write_bytes(1, &write_fields(|os| os.write_string(1, name)))?;
+                    }
+                    Ok(())
+                });
+                os.write_bytes(6, &list)
+            }
+            (_, Some(f)) => {
+                let deps = write_fields(|os| {
+                    for bytes in file_with_deps(pool, f.get_name()) {
+                        os.write_bytes(1, &bytes)?;
+                    }
+                    Ok(())
+                });
+                os.write_bytes(4, &deps)
+            }
+            (&Query::Unsupported, None) => os.write_bytes(
+                7,
+                &reflection_error(RpcStatusCode::Unimplemented, "unsupported request"),
+            ),
+            (_, None) => os.write_bytes(7, &reflection_error(RpcStatusCode::NotFound, "not found")),
+        }
+    })
+}
+
+fn reflection_error(code: RpcStatusCode, message: &str) -> Vec<u8> {
+    write_fields(|os| {
+        os.write_int32(1, code as i32)?;
+        os.write_string(2, message)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use protobuf::descriptor::{MethodDescriptorProto, ServiceDescriptorProto};
+    use protobuf::RepeatedField;
+
+    use super::*;
+
+    fn leak_file(name: &str, deps: &[&str], service: Option<&str>) -> &'static FileDescriptorProto {
+        let mut file = FileDescriptorProto::new();
+        file.set_name(name.to_owned());
+        file.set_package("admin".to_owned());
+        file.set_dependency(RepeatedField::from_vec(
+            deps.iter().map(|d| d.to_string()).collect(),
+        ));
+        if let Some(s) = service {
+            let mut method = MethodDescriptorProto::new();
+            method.set_name("Get".to_owned());
+            let mut service = ServiceDescriptorProto::new();
+            service.set_name(s.to_owned());
+            service.set_method(RepeatedField::from_vec(vec![method]));
+            file.set_service(RepeatedField::from_vec(vec![service]));
+        }
+        Box::leak(Box::new(file))
+    }
+
+    /// Get the field number and content of the response to a request with
+    /// `field` set to `value`.
+    fn query(pool: &DescriptorPool, field: u32, value: &str) -> (u32, Vec<u8>) {
+        let raw = write_fields(|os| os.write_string(field, value));
+        let req = de_reflection_request(&raw).unwrap();
+        let mut resp = parse_fields(&reflect(pool, &req)).unwrap();
+        assert_eq!(resp.len(), 3);
+        assert_eq!(resp[1], (2, raw));
+        resp.pop().unwrap()
+    }
+
+    fn error_code(error: &[u8]) -> i32 {
+        let mut is = CodedInputStream::from_bytes(error);
+        assert_eq!(is.read_tag_unpack().unwrap().0, 1);
+        is.read_int32().unwrap()
+    }
+
+    #[test]
+    fn test_reflection() {
+        let mut pool = DescriptorPool::new();
+        pool.register(leak_file("common.proto", &[], None)).unwrap();
+        pool.register(leak_file("kv.proto", &["common.proto"], Some("Kv")))
+            .unwrap();
+
+        let (n, list) = query(&pool, 7, "");
+        assert_eq!(n, 6);
+        let services = parse_fields(&list).unwrap();
+        assert_eq!(services.len(), 1);
+        let name = parse_fields(&services[0].1).unwrap();
+        assert_eq!(name, vec![(1, b"admin.Kv".to_vec())]);
+
+        // A method is looked up by its service, the dependencies follow.
+        let (n, files) = query(&pool, 4, "admin.Kv.Get");
+        assert_eq!(n, 4);
+        let names: Vec<_> = parse_fields(&files)
+            .unwrap()
+            .into_iter()
+            .map(|(_, b)| {
+                let mut file = FileDescriptorProto::new();
+                file.merge_from_bytes(&b).unwrap();
+                file.get_name().to_owned()
+            })
+            .collect();
+        assert_eq!(names, vec!["kv.proto", "common.proto"]);
+
+        let (n, error) = query(&pool, 3, "missing.proto");
+        assert_eq!(n, 7);
+        assert_eq!(error_code(&error), RpcStatusCode::NotFound as i32);
+        let (_, error) = query(&pool, 6, "admin.Kv");
+        assert_eq!(error_code(&error), RpcStatusCode::Unimplemented as i32);
+    }
+
+    #[test]
+    fn test_health_registry() {
+        let health = HealthRegistry::new();
+        assert_eq!(health.status(""), Some(ServingStatus::Serving));
+        assert_eq!(health.status("admin.Kv"), None);
+        health.set_status("admin.Kv", ServingStatus::NotServing);
+        assert_eq!(
+            health.clone().status("admin.Kv"),
+            Some(ServingStatus::NotServing)
+        );
+        health.clear_status("admin.Kv");
+        assert_eq!(health.status("admin.Kv"), None);
+
+        let req = HealthCheckRequest {
+            service: "admin.Kv".to_owned(),
+        };
+        let mut buf = vec![];
+        ser_health_request(&req, &mut buf);
+        assert_eq!(de_health_request(&buf).unwrap().service, "admin.Kv");
+        assert_eq!(health_response(ServingStatus::Serving), vec![8, 1]);
+    }
+}
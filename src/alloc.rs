@@ -0,0 +1,537 @@
+// Copyright 2018 PingCAP, Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Memory allocation of gRPC core.
+//!
+//! By default gRPC core allocates through libc `malloc`. After
+//! [`use_rust_allocator`] it goes through the global allocator of Rust
+//! instead, so a `#[global_allocator]` like jemalloc also serves gRPC core,
+//! and its statistics cover the memory used by calls and buffers.
+//!
+//! For very high throughput streaming, [`use_buffer_pool`] keeps the large
+//! blocks used for messages and read buffers mapped after they are freed,
+//! and hands them out again, so that the pages are not faulted in for every
+//! message. The blocks can be backed by transparent huge pages and mapped up
+//! front. It goes along with the read chunk sizes of `ChannelBuilder`, which
+//! decide how large the read buffers of gRPC core are.
+//!
+//! [`use_rust_allocator`]: fn.use_rust_allocator.html
+//! [`use_buffer_pool`]: fn.use_buffer_pool.html
+
+use std::alloc::{self, Layout};
+use std::ptr;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+#[cfg(unix)]
+use std::sync::Mutex;
+
+use grpc_sys::{self, GprAllocationFunctions};
+use libc::{c_void, size_t};
+
+use error::{Error, Result};
+
+// C `free` doesn't tell the size, so it's stored in front of the block.
+// 16 bytes keeps the alignment `malloc` guarantees.
+const HEADER: usize = 16;
+
+static STARTED: AtomicBool = AtomicBool::new(false);
+// Whether the allocation functions are replaced already.
+static REPLACED: AtomicBool = AtomicBool::new(false);
+
+/// Mark gRPC core as initialized, the allocator can't be changed afterwards.
+pub(crate) fn mark_started() {
+    STARTED.store(true, Ordering::SeqCst);
+}
+
+fn layout(size: usize) -> Option<Layout> {
+    let total = size.checked_add(HEADER)?;
+    Layout::from_size_align(total, HEADER).ok()
+}
+
+unsafe fn finish(base: *mut u8, size: usize) -> *mut c_void {
+    if base.is_null() {
+        return ptr::null_mut();
+    }
+    *(base as *mut usize) = size;
+    base.add(HEADER) as *mut c_void
+}
+
+unsafe fn base_of(p: *mut c_void) -> (*mut u8, usize) {
+    let base = (p as *mut u8).sub(HEADER);
+    (base, *(base as *mut usize))
+}
+
+unsafe extern "C" fn rust_malloc(size: size_t) -> *mut c_void {
+    match layout(size) {
+        Some(l) => finish(alloc::alloc(l), size),
+        None => ptr::null_mut(),
+    }
+}
+
+unsafe extern "C" fn rust_zalloc(size: size_t) -> *mut c_void {
+    match layout(size) {
+        Some(l) => finish(alloc::alloc_zeroed(l), size),
+        None => ptr::null_mut(),
+    }
+}
+
+unsafe extern "C" fn rust_realloc(p: *mut c_void, size: size_t) -> *mut c_void {
+    if p.is_null() {
+        return rust_malloc(size);
+    }
+    let (base, old) = base_of(p);
+    match layout(size) {
+        Some(l) => finish(alloc::realloc(base, layout(old).unwrap(), l.size()), size),
+        None => ptr::null_mut(),
+    }
+}
+
+unsafe extern "C" fn rust_free(p: *mut c_void) {
+    if p.is_null() {
+        return;
+    }
+    let (base, size) = base_of(p);
+    alloc::dealloc(base, layout(size).unwrap());
+}
+
+/// Make gRPC core allocate through the global allocator of Rust.
+///
+/// An error is returned if an [`Environment`] has been built.
+///
+/// # Safety
+///
+/// Memory allocated by gRPC core before the switch would be freed by the
+/// wrong allocator, so it must be called before anything touches gRPC core,
+/// including creating credentials or metadata. Calling it at the beginning
+/// of `main` is recommended.
+///
+/// [`Environment`]: ../struct.Environment.html
+pub unsafe fn use_rust_allocator() -> Result<()> {
+    check_replaceable()?;
+    grpc_sys::gpr_set_allocation_functions(GprAllocationFunctions {
+        malloc_fn: Some(rust_malloc),
+        zalloc_fn: Some(rust_zalloc),
+        realloc_fn: Some(rust_realloc),
+        free_fn: Some(rust_free),
+    });
+    Ok(())
+}
+
+fn check_replaceable() -> Result<()> {
+    if STARTED.load(Ordering::SeqCst) {
+        return Err(Error::InvalidConfig(
+            "allocator must be set before gRPC is initialized".to_owned(),
+        ));
+    }
+    if REPLACED.swap(true, Ordering::SeqCst) {
+        return Err(Error::InvalidConfig("allocator is already set".to_owned()));
+    }
+    Ok(())
+}
+
+/// Configuration of the pool of large blocks, see [`use_buffer_pool`].
+///
+/// [`use_buffer_pool`]: fn.use_buffer_pool.html
+#[cfg(unix)]
+pub struct BufferPool {
+    min_block: usize,
+    max_cached: usize,
+    huge_pages: bool,
+    preallocate: Vec<(usize, usize)>,
+}
+
+#[cfg(unix)]
+impl Default for BufferPool {
+    fn default() -> BufferPool {
+        BufferPool {
+            min_block: 64 * 1024,
+            max_cached: 64 * 1024 * 1024,
+            huge_pages: false,
+            preallocate: vec![],
+        }
+    }
+}
+
+#[cfg(unix)]
+impl BufferPool {
+    pub fn new() -> BufferPool {
+        BufferPool::default()
+    }
+
+    /// Serve the allocations of at least `bytes`, 64 KiB by default, from
+    /// the pool. Smaller ones go to libc `malloc`.
+    ///
+    /// Blocks are sized in powers of two, so up to half of a block may be
+    /// unused.
+    pub fn min_block(mut self, bytes: usize) -> BufferPool {
+        self.min_block = bytes.max(HEADER);
+        self
+    }
+
+    /// Keep at most `bytes` of freed blocks, 64 MiB by default. Blocks
+    /// beyond it are unmapped.
+    pub fn max_cached(mut self, bytes: usize) -> BufferPool {
+        self.max_cached = bytes;
+        self
+    }
+
+    /// Ask for transparent huge pages for the blocks, which only takes
+    /// effect on Linux for the parts of blocks aligned to huge pages, e.g.
+    /// blocks of 4 MiB or larger.
+    pub fn huge_pages(mut self, enabled: bool) -> BufferPool {
+        self.huge_pages = enabled;
+        self
+    }
+
+    /// Map `count` blocks that fit `bytes` and fault them in when the pool
+    /// is installed, on top of the blocks cached afterwards.
+    pub fn preallocate(mut self, bytes: usize, count: usize) -> BufferPool {
+        self.preallocate.push((bytes, count));
+        self
+    }
+}
+
+/// Statistics of the pool installed by [`use_buffer_pool`].
+///
+/// [`use_buffer_pool`]: fn.use_buffer_pool.html
+#[cfg(unix)]
+#[derive(Clone, Copy, Debug, Default)]
+pub struct BufferPoolStats {
+    /// Bytes of the blocks that are mapped, in use or cached.
+    pub mapped_bytes: usize,
+    /// Bytes of the blocks that are freed and kept for reuse.
+    pub cached_bytes: usize,
+    /// Count of allocations served by a cached block.
+    pub hits: usize,
+    /// Count of allocations that map a new block.
+    pub misses: usize,
+}
+
+#[cfg(unix)]
+struct Pool {
+    min_block: usize,
+    max_cached: usize,
+    #[cfg_attr(not(target_os = "linux"), allow(dead_code))]
+    huge_pages: bool,
+    // Freed blocks of `1 << class` bytes, indexed by class.
+    free: Vec<Mutex<Vec<usize>>>,
+    mapped: AtomicUsize,
+    cached: AtomicUsize,
+    hits: AtomicUsize,
+    misses: AtomicUsize,
+}
+
+#[cfg(unix)]
+impl Pool {
+    fn new(config: &BufferPool) -> Pool {
+        Pool {
+            min_block: config.min_block,
+            max_cached: config.max_cached,
+            huge_pages: config.huge_pages,
+            free: (0..usize::max_value().count_ones())
+                .map(|_| Mutex::new(vec![]))
+                .collect(),
+            mapped: AtomicUsize::new(0),
+            cached: AtomicUsize::new(0),
+            hits: AtomicUsize::new(0),
+            misses: AtomicUsize::new(0),
+        }
+    }
+
+    fn class_of(&self, total: usize) -> Option<usize> {
+        if total < self.min_block {
+            return None;
+        }
+        total
+            .checked_next_power_of_two()
+            .map(|n| n.trailing_zeros() as usize)
+    }
+
+    unsafe fn map_block(&self, class: usize) -> *mut u8 {
+        let len = 1 << class;
+        let p = libc::mmap(
+            ptr::null_mut(),
+            len,
+            libc::PROT_READ | libc::PROT_WRITE,
+            libc::MAP_PRIVATE | libc::MAP_ANON,
+            -1,
+            0,
+        );
+        if p == libc::MAP_FAILED {
+            return ptr::null_mut();
+        }
+        #[cfg(target_os = "linux")]
+        {
+            if self.huge_pages {
+                libc::madvise(p, len, libc::MADV_HUGEPAGE);
+            }
+        }
+        self.mapped.fetch_add(len, Ordering::Relaxed);
+        p as *mut u8
+    }
+
+    // Returns the block and whether it's used before.
+    unsafe fn take_block(&self, class: usize) -> (*mut u8, bool) {
+        if let Some(p) = self.free[class].lock().unwrap().pop() {
+            self.cached.fetch_sub(1 << class, Ordering::Relaxed);
+            self.hits.fetch_add(1, Ordering::Relaxed);
+            return (p as *mut u8, true);
+        }
+        self.misses.fetch_add(1, Ordering::Relaxed);
+        (self.map_block(class), false)
+    }
+
+    unsafe fn release_block(&self, class: usize, base: *mut u8) {
+        let len = 1 << class;
+        if self.cached.fetch_add(len, Ordering::Relaxed) + len <= self.max_cached {
+            self.free[class].lock().unwrap().push(base as usize);
+            return;
+        }
+        self.cached.fetch_sub(len, Ordering::Relaxed);
+        libc::munmap(base as *mut c_void, len);
+        self.mapped.fetch_sub(len, Ordering::Relaxed);
+    }
+
+    unsafe fn preallocate(&self, bytes: usize, count: usize) -> Result<()> {
+        let class = match bytes.checked_add(HEADER).and_then(|t| self.class_of(t)) {
+            Some(c) => c,
+            None => return Ok(()),
+        };
+        for _ in 0..count {
+            let base = self.map_block(class);
+            if base.is_null() {
+                return Err(Error::InvalidConfig(format!(
+                    "failed to map a block of {} bytes",
+                    1usize << class
+                )));
+            }
+            // Fault the pages in.
+            ptr::write_bytes(base, 0, 1 << class);
+            self.release_block(class, base);
+        }
+        Ok(())
+    }
+
+    // The header holds the size and the class plus one, which is zero for
+    // the blocks from `malloc`.
+    unsafe fn malloc(&self, size: usize, zeroed: bool) -> *mut c_void {
+        let total = match size.checked_add(HEADER) {
+            Some(t) => t,
+            None => return ptr::null_mut(),
+        };
+        let (base, tag) = match self.class_of(total) {
+            None if zeroed => (libc::calloc(1, total) as *mut u8, 0),
+            None => (libc::malloc(total) as *mut u8, 0),
+            Some(class) => {
+                let (base, reused) = self.take_block(class);
+                if zeroed && reused {
+                    ptr::write_bytes(base.add(HEADER), 0, size);
+                }
+                (base, class + 1)
+            }
+        };
+        if base.is_null() {
+            return ptr::null_mut();
+        }
+        *(base as *mut usize).add(1) = tag;
+        finish(base, size)
+    }
+
+    unsafe fn realloc(&self, p: *mut c_void, size: usize) -> *mut c_void {
+        if p.is_null() {
+            return self.malloc(size, false);
+        }
+        let (base, old) = base_of(p);
+        let tag = *(base as *mut usize).add(1);
+        let total = match size.checked_add(HEADER) {
+            Some(t) => t,
+            None => return ptr::null_mut(),
+        };
+        let class = self.class_of(total);
+        if tag == 0 && class.is_none() {
+            let base = libc::realloc(base as *mut c_void, total) as *mut u8;
+            return finish(base, size);
+        }
+        if tag != 0 && class == Some(tag - 1) {
+            return finish(base, size);
+        }
+        let new = self.malloc(size, false);
+        if !new.is_null() {
+            ptr::copy_nonoverlapping(p as *const u8, new as *mut u8, old.min(size));
+            self.free(p);
+        }
+        new
+    }
+
+    unsafe fn free(&self, p: *mut c_void) {
+        if p.is_null() {
+            return;
+        }
+        let (base, _) = base_of(p);
+        match *(base as *mut usize).add(1) {
+            0 => libc::free(base as *mut c_void),
+            tag => self.release_block(tag - 1, base),
+        }
+    }
+
+    fn stats(&self) -> BufferPoolStats {
+        BufferPoolStats {
+            mapped_bytes: self.mapped.load(Ordering::Relaxed),
+            cached_bytes: self.cached.load(Ordering::Relaxed),
+            hits: self.hits.load(Ordering::Relaxed),
+            misses: self.misses.load(Ordering::Relaxed),
+        }
+    }
+}
+
+#[cfg(unix)]
+static mut POOL: *const Pool = 0 as *const _;
+
+#[cfg(unix)]
+unsafe extern "C" fn pool_malloc(size: size_t) -> *mut c_void {
+    (*POOL).malloc(size, false)
+}
+
+#[cfg(unix)]
+unsafe extern "C" fn pool_zalloc(size: size_t) -> *mut c_void {
+    (*POOL).malloc(size, true)
+}
+
+#[cfg(unix)]
+unsafe extern "C" fn pool_realloc(p: *mut c_void, size: size_t) -> *mut c_void {
+    (*POOL).realloc(p, size)
+}
+
+#[cfg(unix)]
+unsafe extern "C" fn pool_free(p: *mut c_void) {
+    (*POOL).free(p)
+}
+
+/// Make gRPC core allocate large blocks from a pool configured by `config`.
+///
+/// An error is returned if an [`Environment`] has been built, or the
+/// allocator is replaced already, e.g. by [`use_rust_allocator`].
+///
+/// # Safety
+///
+/// The same as [`use_rust_allocator`], it must be called before anything
+/// touches gRPC core.
+///
+/// [`Environment`]: ../struct.Environment.html
+/// [`use_rust_allocator`]: fn.use_rust_allocator.html
+#[cfg(unix)]
+pub unsafe fn use_buffer_pool(config: BufferPool) -> Result<()> {
+    check_replaceable()?;
+    let pool = Pool::new(&config);
+    for &(bytes, count) in &config.preallocate {
+        pool.preallocate(bytes, count)?;
+    }
+    POOL = Box::into_raw(Box::new(pool));
+    grpc_sys::gpr_set_allocation_functions(GprAllocationFunctions {
+        malloc_fn: Some(pool_malloc),
+        zalloc_fn: Some(pool_zalloc),
+        realloc_fn: Some(pool_realloc),
+        free_fn: Some(pool_free),
+    });
+    Ok(())
+}
+
+/// Get the statistics of the pool, `None` if [`use_buffer_pool`] is not
+/// called.
+///
+/// [`use_buffer_pool`]: fn.use_buffer_pool.html
+#[cfg(unix)]
+pub fn buffer_pool_stats() -> Option<BufferPoolStats> {
+    unsafe { POOL.as_ref().map(|p| p.stats()) }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::slice;
+
+    use super::*;
+
+    #[test]
+    fn test_rust_allocator() {
+        unsafe {
+            let p = rust_zalloc(10) as *mut u8;
+            assert_eq!(p as usize % HEADER, 0);
+            assert!(slice::from_raw_parts(p, 10).iter().all(|b| *b == 0));
+            for i in 0..10 {
+                *p.add(i) = i as u8;
+            }
+            let p = rust_realloc(p as _, 1000) as *mut u8;
+            assert_eq!(base_of(p as _).1, 1000);
+            assert_eq!(
+                slice::from_raw_parts(p, 10),
+                &[0, 1, 2, 3, 4, 5, 6, 7, 8, 9]
+            );
+            rust_free(p as _);
+
+            let p = rust_realloc(ptr::null_mut(), 5);
+            assert!(!p.is_null());
+            rust_free(p);
+            rust_free(ptr::null_mut());
+            assert!(rust_malloc(usize::max_value()).is_null());
+        }
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_buffer_pool() {
+        let config = BufferPool::new()
+            .min_block(4096)
+            .max_cached(16 * 1024)
+            .preallocate(5000, 1);
+        let pool = Pool::new(&config);
+        unsafe {
+            pool.preallocate(5000, 1).unwrap();
+            assert_eq!(pool.stats().cached_bytes, 8192);
+
+            // Small allocations are not pooled.
+            let small = pool.malloc(100, true) as *mut u8;
+            assert_eq!(*(base_of(small as _).0 as *mut usize).add(1), 0);
+            assert!(slice::from_raw_parts(small, 100).iter().all(|b| *b == 0));
+
+            let p = pool.malloc(5000, false) as *mut u8;
+            assert_eq!(p as usize % HEADER, 0);
+            assert_eq!(pool.stats().hits, 1);
+            for i in 0..10 {
+                *p.add(i) = i as u8;
+            }
+            // Still fits the block.
+            let p = pool.realloc(p as _, 6000) as *mut u8;
+            assert_eq!(pool.stats().misses, 0);
+            // Grows out of the small allocation into a block.
+            let small = pool.realloc(small as _, 5000) as *mut u8;
+            assert_eq!(pool.stats().misses, 1);
+            pool.free(small as _);
+            let p = pool.realloc(p as _, 20000) as *mut u8;
+            assert_eq!(
+                slice::from_raw_parts(p, 10),
+                &[0, 1, 2, 3, 4, 5, 6, 7, 8, 9]
+            );
+            // Both blocks of 8 KiB are cached now.
+            assert_eq!(pool.stats().cached_bytes, 16384);
+
+            let z = pool.malloc(5000, true) as *mut u8;
+            assert!(slice::from_raw_parts(z, 5000).iter().all(|b| *b == 0));
+            pool.free(z as _);
+            // Beyond the cache limit, the block is unmapped.
+            let mapped = pool.stats().mapped_bytes;
+            pool.free(p as _);
+            assert_eq!(pool.stats().mapped_bytes, mapped - 32768);
+            assert_eq!(pool.stats().cached_bytes, 16384);
+            pool.free(ptr::null_mut());
+        }
+    }
+}
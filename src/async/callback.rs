@@ -32,6 +32,7 @@ impl Request {
 
     pub fn resolve(mut self, cq: &CompletionQueue, success: bool) {
         let rc = self.ctx.take_request_call_context().unwrap();
+        rc.take_slot(cq);
         if !success {
             server::request_call(rc, cq);
             return;
@@ -31,9 +31,9 @@ struct Alarm {
 }
 
 impl Alarm {
-    fn new(cq: &CompletionQueue, tag: Box<CallTag>) -> Result<Alarm> {
+    fn new(cq: &CompletionQueue, tag: CallTag) -> Result<Alarm> {
         let alarm = unsafe {
-            let ptr = Box::into_raw(tag);
+            let ptr = tag.into_raw();
             let timeout = GprTimespec::inf_future();
             let cq_ref = cq.borrow()?;
             let alarm = grpc_sys::grpc_alarm_create(ptr::null_mut());
@@ -72,7 +72,7 @@ impl NotifyContext {
     ///
     /// It only makes sence to call this function from the thread
     /// that cq is not run on.
-    fn notify(&mut self, tag: Box<CallTag>) {
+    fn notify(&mut self, tag: CallTag) {
         self.alarm.take();
         let mut alarm = match Alarm::new(&self.cq, tag) {
             Ok(a) => a,
@@ -132,7 +132,7 @@ impl Notify for SpawnNotify {
             if ctx.alarmed {
                 return;
             }
-            ctx.notify(Box::new(CallTag::Spawn(self.clone())));
+            ctx.notify(CallTag::Spawn(self.clone()));
             ctx.alarmed = true;
         }
     }
@@ -14,30 +14,42 @@
 mod callback;
 mod executor;
 mod lock;
+mod pool;
 mod promise;
+mod timer;
 
 use std::fmt::{self, Debug, Formatter};
 use std::sync::Arc;
 
 use futures::task::{self, Task};
 use futures::{Async, Future, Poll};
+use libc::c_void;
 
 use self::callback::{Abort, Request as RequestCallback, UnaryRequest as UnaryRequestCallback};
 use self::executor::SpawnNotify;
-use self::promise::{Batch as BatchPromise, Shutdown as ShutdownPromise};
+use self::promise::{Action as ActionPromise, Batch as BatchPromise, Shutdown as ShutdownPromise};
 use call::server::RequestContext;
 use call::{BatchContext, Call};
+use channel::StatsRecorder;
 use cq::CompletionQueue;
 use error::{Error, Result};
+use metadata::Metadata;
 use server::RequestCallContext;
 
 pub use self::executor::Executor;
 pub use self::lock::SpinLock;
+pub use self::pool::outstanding as outstanding_tags;
+pub use self::pool::pending as pending_tags;
 pub use self::promise::BatchType;
+pub use self::timer::{Timer, TimerHandle};
 
 /// A handle that is used to notify future that the task finishes.
 pub struct NotifyHandle<T> {
     result: Option<Result<T>>,
+    // Initial metadata received by a `BatchType::Headers` job.
+    headers: Option<Metadata>,
+    // Trailing metadata received along with the status of a call.
+    trailers: Option<Metadata>,
     task: Option<Task>,
     stale: bool,
 }
@@ -46,6 +58,8 @@ impl<T> NotifyHandle<T> {
     fn new() -> NotifyHandle<T> {
         NotifyHandle {
             result: None,
+            headers: None,
+            trailers: None,
             task: None,
             stale: false,
         }
@@ -89,6 +103,20 @@ impl<T> CqFuture<T> {
     fn new(inner: Arc<Inner<T>>) -> CqFuture<T> {
         CqFuture { inner }
     }
+
+    /// Take the initial metadata received by a `BatchType::Headers` job.
+    ///
+    /// It's only available after the future is resolved.
+    pub fn take_headers(&self) -> Option<Metadata> {
+        self.inner.lock().headers.take()
+    }
+
+    /// Take the trailing metadata received along with the status of the call.
+    ///
+    /// It's only available after the future is resolved.
+    pub fn take_trailers(&self) -> Option<Metadata> {
+        self.inner.lock().trailers.take()
+    }
 }
 
 impl<T> Future for CqFuture<T> {
@@ -127,14 +155,17 @@ pub enum CallTag {
     UnaryRequest(UnaryRequestCallback),
     Abort(Abort),
     Shutdown(ShutdownPromise),
+    Action(ActionPromise),
     Spawn(SpawnNotify),
 }
 
 impl CallTag {
     /// Generate a Future/CallTag pair for batch jobs.
-    pub fn batch_pair(ty: BatchType) -> (BatchFuture, CallTag) {
+    ///
+    /// If `stats` is given, the status of the call received by the job will be recorded.
+    pub fn batch_pair(ty: BatchType, stats: Option<StatsRecorder>) -> (BatchFuture, CallTag) {
         let inner = new_inner();
-        let batch = BatchPromise::new(ty, inner.clone());
+        let batch = BatchPromise::new(ty, inner.clone(), stats);
         (CqFuture::new(inner), CallTag::Batch(batch))
     }
 
@@ -151,6 +182,14 @@ impl CallTag {
         (CqFuture::new(inner), CallTag::Shutdown(shutdown))
     }
 
+    /// Generate a Future/CallTag pair for an action whose result is a bool,
+    /// e.g. watching the connectivity state of a channel.
+    pub fn action_pair() -> (CqFuture<bool>, CallTag) {
+        let inner = new_inner();
+        let action = ActionPromise::new(inner.clone());
+        (CqFuture::new(inner), CallTag::Action(action))
+    }
+
     /// Generate a CallTag for abort call before handler is called.
     pub fn abort(call: Call) -> CallTag {
         CallTag::Abort(Abort::new(call))
@@ -162,6 +201,17 @@ impl CallTag {
         CallTag::UnaryRequest(cb)
     }
 
+    /// Store the tag away, the returned pointer is supposed to be passed to
+    /// the completion queue.
+    pub fn into_raw(self) -> *mut c_void {
+        pool::into_raw(self)
+    }
+
+    /// Take back the tag from the pointer returned by `into_raw`.
+    pub unsafe fn from_raw(ptr: *mut c_void) -> CallTag {
+        pool::from_raw(ptr)
+    }
+
     /// Get the batch context from result holder.
     pub fn batch_ctx(&self) -> Option<&BatchContext> {
         match *self {
@@ -189,6 +239,7 @@ impl CallTag {
             CallTag::UnaryRequest(cb) => cb.resolve(cq, success),
             CallTag::Abort(_) => {}
             CallTag::Shutdown(prom) => prom.resolve(success),
+            CallTag::Action(prom) => prom.resolve(success),
             CallTag::Spawn(notify) => notify.resolve(success),
         }
     }
@@ -202,6 +253,7 @@ impl Debug for CallTag {
             CallTag::UnaryRequest(_) => write!(f, "CallTag::UnaryRequest(..)"),
             CallTag::Abort(_) => write!(f, "CallTag::Abort(..)"),
             CallTag::Shutdown(_) => write!(f, "CallTag::Shutdown"),
+            CallTag::Action(_) => write!(f, "CallTag::Action"),
             CallTag::Spawn(_) => write!(f, "CallTag::Spawn"),
         }
     }
@@ -16,7 +16,9 @@ use std::sync::Arc;
 
 use super::{BatchMessage, Inner};
 use call::{BatchContext, RpcStatusCode};
+use channel::StatsRecorder;
 use error::Error;
+use metadata::Metadata;
 
 /// Batch job type.
 #[derive(PartialEq, Debug)]
@@ -27,6 +29,8 @@ pub enum BatchType {
     Read,
     /// Check the rpc code and then extract one message.
     CheckRead,
+    /// Receive the initial metadata of the call.
+    Headers,
 }
 
 /// A promise used to resolve batch jobs.
@@ -34,14 +38,27 @@ pub struct Batch {
     ty: BatchType,
     ctx: BatchContext,
     inner: Arc<Inner<BatchMessage>>,
+    // Counters to update once the status of the call is received.
+    stats: Option<StatsRecorder>,
 }
 
 impl Batch {
-    pub fn new(ty: BatchType, inner: Arc<Inner<BatchMessage>>) -> Batch {
+    pub fn new(
+        ty: BatchType,
+        inner: Arc<Inner<BatchMessage>>,
+        stats: Option<StatsRecorder>,
+    ) -> Batch {
         Batch {
             ty,
             ctx: BatchContext::new(),
             inner,
+            stats,
+        }
+    }
+
+    fn record_status(&self, code: RpcStatusCode) {
+        if let Some(ref stats) = self.stats {
+            stats.on_finish(code);
         }
     }
 
@@ -49,6 +66,15 @@ impl Batch {
         &self.ctx
     }
 
+    fn trailers(&self) -> Option<Metadata> {
+        let trailers = self.ctx.trailing_metadata();
+        if trailers.is_empty() {
+            None
+        } else {
+            Some(trailers)
+        }
+    }
+
     fn read_one_msg(&mut self, success: bool) {
         let task = {
             let mut guard = self.inner.lock();
@@ -63,17 +89,34 @@ impl Batch {
         task.map(|t| t.notify());
     }
 
+    fn recv_headers(&mut self, success: bool) {
+        let task = {
+            let mut guard = self.inner.lock();
+            if success {
+                guard.headers = Some(self.ctx.initial_metadata());
+                guard.set_result(Ok(None))
+            } else {
+                guard.set_result(Err(Error::RemoteStopped))
+            }
+        };
+        task.map(|t| t.notify());
+    }
+
     fn finish_response(&mut self, succeed: bool) {
         let task = {
             let mut guard = self.inner.lock();
             if succeed {
+                guard.trailers = self.trailers();
                 let status = self.ctx.rpc_status();
+                self.record_status(status.status);
                 if status.status == RpcStatusCode::Ok {
                     guard.set_result(Ok(None))
                 } else {
+                    let status = status.with_trailers(guard.trailers.clone());
                     guard.set_result(Err(Error::RpcFailure(status)))
                 }
             } else {
+                self.record_status(RpcStatusCode::Unknown);
                 guard.set_result(Err(Error::RemoteStopped))
             }
         };
@@ -83,10 +126,14 @@ impl Batch {
     fn handle_unary_response(&mut self) {
         let task = {
             let mut guard = self.inner.lock();
+            guard.headers = Some(self.ctx.initial_metadata());
+            guard.trailers = self.trailers();
             let status = self.ctx.rpc_status();
+            self.record_status(status.status);
             if status.status == RpcStatusCode::Ok {
                 guard.set_result(Ok(self.ctx.recv_message()))
             } else {
+                let status = status.with_trailers(guard.trailers.clone());
                 guard.set_result(Err(Error::RpcFailure(status)))
             }
         };
@@ -105,6 +152,9 @@ impl Batch {
             BatchType::Read => {
                 self.read_one_msg(success);
             }
+            BatchType::Headers => {
+                self.recv_headers(success);
+            }
         }
     }
 }
@@ -116,6 +166,27 @@ impl Debug for Batch {
 }
 
 /// A promise used to resolve async shutdown result.
+/// A promise used to resolve whether an action succeeds.
+///
+/// Unlike `Shutdown`, failure is a result rather than an error.
+pub struct Action {
+    inner: Arc<Inner<bool>>,
+}
+
+impl Action {
+    pub fn new(inner: Arc<Inner<bool>>) -> Action {
+        Action { inner }
+    }
+
+    pub fn resolve(self, success: bool) {
+        let task = {
+            let mut guard = self.inner.lock();
+            guard.set_result(Ok(success))
+        };
+        task.map(|t| t.notify());
+    }
+}
+
 pub struct Shutdown {
     inner: Arc<Inner<()>>,
 }
@@ -0,0 +1,87 @@
+// Copyright 2018 PingCAP, Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::ptr;
+use std::sync::Arc;
+
+use futures::{Future, Poll};
+use grpc_sys::{self, GrpcAlarm};
+
+use super::{CallTag, CqFuture};
+use call::Deadline;
+use cq::CompletionQueue;
+use error::{Error, Result};
+
+/// A handle to cancel a `Timer`, it can be sent to other threads.
+pub struct TimerHandle {
+    alarm: *mut GrpcAlarm,
+}
+
+unsafe impl Send for TimerHandle {}
+unsafe impl Sync for TimerHandle {}
+
+impl TimerHandle {
+    /// Resolve the timer with `false` if it's not fired yet.
+    pub fn cancel(&self) {
+        unsafe { grpc_sys::grpc_alarm_cancel(self.alarm) }
+    }
+}
+
+impl Drop for TimerHandle {
+    fn drop(&mut self) {
+        unsafe { grpc_sys::grpc_alarm_destroy(self.alarm) }
+    }
+}
+
+/// A future that resolves to `true` once the deadline is exceeded, or to
+/// `false` if it's canceled before that.
+pub struct Timer {
+    handle: Arc<TimerHandle>,
+    f: CqFuture<bool>,
+}
+
+impl Timer {
+    pub fn new(cq: &CompletionQueue, deadline: &Deadline) -> Result<Timer> {
+        let (f, tag) = CallTag::action_pair();
+        let cq_ref = cq.borrow()?;
+        let alarm = unsafe {
+            let alarm = grpc_sys::grpc_alarm_create(ptr::null_mut());
+            let tag = tag.into_raw();
+            grpc_sys::grpc_alarm_set(
+                alarm,
+                cq_ref.as_ptr(),
+                deadline.spec(),
+                tag,
+                ptr::null_mut(),
+            );
+            alarm
+        };
+        Ok(Timer {
+            handle: Arc::new(TimerHandle { alarm }),
+            f,
+        })
+    }
+
+    pub fn handle(&self) -> Arc<TimerHandle> {
+        self.handle.clone()
+    }
+}
+
+impl Future for Timer {
+    type Item = bool;
+    type Error = Error;
+
+    fn poll(&mut self) -> Poll<bool, Error> {
+        self.f.poll()
+    }
+}
@@ -0,0 +1,92 @@
+// Copyright 2018 PingCAP, Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::ffi::CStr;
+use std::slice;
+
+use grpc_sys::{self, GrpcCall};
+
+/// Name of the property that holds the subject alternative names of the peer
+/// certificate.
+pub const X509_SAN_PROPERTY_NAME: &str = "x509_subject_alternative_name";
+
+/// A snapshot of the auth properties of a peer.
+#[derive(Debug, Clone)]
+pub struct AuthContext {
+    properties: Vec<(String, Vec<u8>)>,
+    peer_identity_property_name: Option<String>,
+    authenticated: bool,
+}
+
+impl AuthContext {
+    /// Take a snapshot of the auth context of `call`.
+    ///
+    /// `None` is returned if the call is not secure.
+    pub(crate) unsafe fn from_call(call: *mut GrpcCall) -> Option<AuthContext> {
+        let ctx = grpc_sys::grpc_call_auth_context(call);
+        if ctx.is_null() {
+            return None;
+        }
+        let mut properties = vec![];
+        let mut it = grpc_sys::grpc_auth_context_property_iterator(ctx);
+        loop {
+            let p = grpc_sys::grpc_auth_property_iterator_next(&mut it);
+            if p.is_null() {
+                break;
+            }
+            let name = CStr::from_ptr((*p).name).to_string_lossy().into_owned();
+            let value = slice::from_raw_parts((*p).value as *const u8, (*p).value_length);
+            properties.push((name, value.to_vec()));
+        }
+        let name = grpc_sys::grpc_auth_context_peer_identity_property_name(ctx);
+        let peer_identity_property_name = if name.is_null() {
+            None
+        } else {
+            Some(CStr::from_ptr(name).to_string_lossy().into_owned())
+        };
+        let authenticated = grpc_sys::grpc_auth_context_peer_is_authenticated(ctx) != 0;
+        grpc_sys::grpc_auth_context_release(ctx);
+        Some(AuthContext {
+            properties,
+            peer_identity_property_name,
+            authenticated,
+        })
+    }
+
+    /// Whether the peer is authenticated.
+    pub fn is_authenticated(&self) -> bool {
+        self.authenticated
+    }
+
+    /// Returns an iterator over all the properties.
+    pub fn properties(&self) -> impl Iterator<Item = (&str, &[u8])> {
+        self.properties
+            .iter()
+            .map(|&(ref k, ref v)| (k.as_str(), v.as_slice()))
+    }
+
+    /// Returns the values of the properties named `name`.
+    pub fn find<'a>(&'a self, name: &'a str) -> impl Iterator<Item = &'a [u8]> + 'a {
+        self.properties()
+            .filter(move |&(k, _)| k == name)
+            .map(|(_, v)| v)
+    }
+
+    /// Returns the values of the properties that identify the peer.
+    pub fn peer_identity(&self) -> Vec<&[u8]> {
+        match self.peer_identity_property_name {
+            Some(ref name) => self.find(name).collect(),
+            None => vec![],
+        }
+    }
+}
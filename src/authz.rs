@@ -0,0 +1,544 @@
+// Copyright 2018 PingCAP, Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Authorization of calls by a declarative policy.
+//!
+//! An [`Authorizer`] set by [`ServerBuilder::authorizer`] checks every call
+//! against its [`Policy`] before the handler is invoked. A call that matches
+//! any deny rule is denied, otherwise it's allowed if it matches any allow
+//! rule. Denied calls fail with `PermissionDenied`:
+//!
+//! ```ignore
+//! let policy = Policy::new("kv")
+//!     .allow(Rule::new("readers").path("/kv.Kv/Get").principal("*.example.org"))
+//!     .deny(Rule::new("no-debug").header("x-debug", "*"));
+//! let authorizer = Authorizer::new(policy);
+//! let server = ServerBuilder::new(env)
+//!     .authorizer(authorizer.clone())
+//!     .register_service(service)
+//!     .build()?;
+//! // The policy can be replaced while the server is running.
+//! authorizer.set_policy(new_policy);
+//! ```
+//!
+//! A rule matches a call if all of its fields do, and a field matches if it's
+//! empty or any of its patterns does. Patterns are [`MethodPattern`]s, which
+//! are compared with the method path, the principals of the peer and the
+//! request headers respectively. The principals are the subject alternative
+//! names of the peer certificate and the peer identity, so rules that have
+//! principals never match calls that are not authenticated. gRPC core 1.7
+//! only reports the DNS names among the subject alternative names, so URI
+//! names like SPIFFE IDs can't be matched.
+//!
+//! With the `authz-json` feature, a policy can also be loaded from JSON in the
+//! format of gRPC authorization policies by [`Policy::from_json`], and kept in
+//! sync with a file by [`Authorizer::watch_file`]:
+//!
+//! ```json
+//! {
+//!     "name": "kv",
+//!     "deny_rules": [
+//!         {"name": "no-debug", "request": {"headers": [{"key": "x-debug", "values": ["*"]}]}}
+//!     ],
+//!     "allow_rules": [
+//!         {
+//!             "name": "readers",
+//!             "source": {"principals": ["*.example.org"]},
+//!             "request": {"paths": ["/kv.Kv/Get"]}
+//!         }
+//!     ]
+//! }
+//! ```
+//!
+//! [`Authorizer`]: struct.Authorizer.html
+//! [`ServerBuilder::authorizer`]: ../struct.ServerBuilder.html#method.authorizer
+//! [`Policy`]: struct.Policy.html
+//! [`MethodPattern`]: ../struct.MethodPattern.html
+//! [`Policy::from_json`]: struct.Policy.html#method.from_json
+//! [`Authorizer::watch_file`]: struct.Authorizer.html#method.watch_file
+
+#[cfg(feature = "authz-json")]
+use std::fs;
+#[cfg(feature = "authz-json")]
+use std::path::{Path, PathBuf};
+use std::result;
+use std::sync::{Arc, RwLock};
+#[cfg(feature = "authz-json")]
+use std::thread;
+#[cfg(feature = "authz-json")]
+use std::time::Duration;
+
+#[cfg(feature = "authz-json")]
+use serde_json::{self, Map, Value};
+
+#[cfg(feature = "tls-server")]
+use auth::X509_SAN_PROPERTY_NAME;
+use call::server::RpcContext;
+use call::RpcStatus;
+#[cfg(feature = "authz-json")]
+use error::{Error, Result};
+use route::MethodPattern;
+
+/// A rule of a [`Policy`](struct.Policy.html).
+#[derive(Clone, Debug)]
+pub struct Rule {
+    name: String,
+    paths: Vec<MethodPattern>,
+    principals: Vec<MethodPattern>,
+    headers: Vec<(String, Vec<MethodPattern>)>,
+}
+
+impl Rule {
+    pub fn new<S: Into<String>>(name: S) -> Rule {
+        Rule {
+            name: name.into(),
+            paths: vec![],
+            principals: vec![],
+            headers: vec![],
+        }
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Match the calls to the methods that match `pattern`.
+    pub fn path<S: Into<String>>(mut self, pattern: S) -> Rule {
+        self.paths.push(MethodPattern::new(pattern));
+        self
+    }
+
+    /// Match the calls from the authenticated peers that have a principal
+    /// matching `pattern`.
+    pub fn principal<S: Into<String>>(mut self, pattern: S) -> Rule {
+        self.principals.push(MethodPattern::new(pattern));
+        self
+    }
+
+    /// Match the calls that have a `key` header matching `pattern`.
+    ///
+    /// The patterns of the same key are alternatives, while the headers of
+    /// different keys all have to match.
+    pub fn header<K: Into<String>, S: Into<String>>(mut self, key: K, pattern: S) -> Rule {
+        let key = key.into().to_lowercase();
+        let pattern = MethodPattern::new(pattern);
+        if let Some(&mut (_, ref mut patterns)) = self.headers.iter_mut().find(|h| h.0 == key) {
+            patterns.push(pattern);
+            return self;
+        }
+        self.headers.push((key, vec![pattern]));
+        self
+    }
+
+    fn matches(&self, call: &CallInfo) -> bool {
+        if !self.paths.is_empty() && !self.paths.iter().any(|p| p.matches(call.path)) {
+            return false;
+        }
+        if !self.principals.is_empty() {
+            let principals = match call.principals {
+                Some(ref p) => p,
+                None => return false,
+            };
+            if !self
+                .principals
+                .iter()
+                .any(|p| principals.iter().any(|v| p.matches(v)))
+            {
+                return false;
+            }
+        }
+        self.headers.iter().all(|&(ref key, ref patterns)| {
+            call.headers
+                .iter()
+                .filter(|h| h.0 == key)
+                .any(|h| patterns.iter().any(|p| p.matches(h.1)))
+        })
+    }
+}
+
+// What rules are matched against.
+struct CallInfo<'a> {
+    path: &'a [u8],
+    // `None` if the peer is not authenticated.
+    principals: Option<Vec<Vec<u8>>>,
+    headers: Vec<(&'a str, &'a [u8])>,
+}
+
+#[cfg(feature = "tls-server")]
+fn principals(ctx: &RpcContext) -> Option<Vec<Vec<u8>>> {
+    let auth = match ctx.auth_context() {
+        Some(ref a) if a.is_authenticated() => a.clone(),
+        _ => return None,
+    };
+    let mut principals: Vec<Vec<u8>> = auth
+        .find(X509_SAN_PROPERTY_NAME)
+        .map(|v| v.to_vec())
+        .collect();
+    for id in auth.peer_identity() {
+        if !principals.iter().any(|p| p.as_slice() == id) {
+            principals.push(id.to_vec());
+        }
+    }
+    Some(principals)
+}
+
+#[cfg(not(feature = "tls-server"))]
+fn principals(_: &RpcContext) -> Option<Vec<Vec<u8>>> {
+    None
+}
+
+/// A set of allow and deny rules.
+#[derive(Clone, Debug)]
+pub struct Policy {
+    name: String,
+    deny_rules: Vec<Rule>,
+    allow_rules: Vec<Rule>,
+}
+
+impl Policy {
+    /// Create a policy that denies every call until rules are added.
+    pub fn new<S: Into<String>>(name: S) -> Policy {
+        Policy {
+            name: name.into(),
+            deny_rules: vec![],
+            allow_rules: vec![],
+        }
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Allow the calls that match `rule`, unless they match a deny rule.
+    pub fn allow(mut self, rule: Rule) -> Policy {
+        self.allow_rules.push(rule);
+        self
+    }
+
+    /// Deny the calls that match `rule`.
+    pub fn deny(mut self, rule: Rule) -> Policy {
+        self.deny_rules.push(rule);
+        self
+    }
+
+    /// Check whether the call is allowed, the name of the allow rule it
+    /// matches is returned if so.
+    ///
+    /// The returned status can be used to fail the call directly.
+    pub fn check(&self, ctx: &RpcContext) -> result::Result<&str, RpcStatus> {
+        let call = CallInfo {
+            path: ctx.method(),
+            principals: principals(ctx),
+            headers: ctx.request_headers().iter().collect(),
+        };
+        self.evaluate(&call)
+    }
+
+    fn evaluate(&self, call: &CallInfo) -> result::Result<&str, RpcStatus> {
+        if let Some(r) = self.deny_rules.iter().find(|r| r.matches(call)) {
+            return Err(RpcStatus::permission_denied(format!(
+                "denied by rule {} of policy {}",
+                r.name, self.name
+            )));
+        }
+        match self.allow_rules.iter().find(|r| r.matches(call)) {
+            Some(r) => Ok(&r.name),
+            None => Err(RpcStatus::permission_denied(format!(
+                "not allowed by policy {}",
+                self.name
+            ))),
+        }
+    }
+
+    /// Parse a policy in the JSON format of gRPC authorization policies.
+    ///
+    /// Unknown fields are rejected, so that a misspelled field can't widen
+    /// what a rule matches.
+    #[cfg(feature = "authz-json")]
+    pub fn from_json(json: &str) -> Result<Policy> {
+        let value: Value = serde_json::from_str(json).map_err(|e| invalid(e.to_string()))?;
+        let obj = object(&value, "policy")?;
+        check_fields(obj, "policy", &["name", "deny_rules", "allow_rules"])?;
+        let mut policy = Policy::new(string(obj.get("name"), "name")?);
+        if obj.get("allow_rules").is_none() {
+            return Err(invalid("allow_rules is missing".to_owned()));
+        }
+        for (field, deny) in &[("deny_rules", true), ("allow_rules", false)] {
+            let rules = match obj.get(*field) {
+                Some(&Value::Array(ref rules)) => rules,
+                None => continue,
+                Some(_) => return Err(invalid(format!("{} should be an array", field))),
+            };
+            for r in rules {
+                let rule = parse_rule(r)?;
+                policy = if *deny {
+                    policy.deny(rule)
+                } else {
+                    policy.allow(rule)
+                };
+            }
+        }
+        Ok(policy)
+    }
+
+    /// Read a policy from the JSON file at `path`, see
+    /// [`from_json`](#method.from_json).
+    #[cfg(feature = "authz-json")]
+    pub fn from_file<P: AsRef<Path>>(path: P) -> Result<Policy> {
+        let path = path.as_ref();
+        let json = fs::read_to_string(path)
+            .map_err(|e| invalid(format!("failed to read {}: {}", path.display(), e)))?;
+        Policy::from_json(&json)
+    }
+}
+
+#[cfg(feature = "authz-json")]
+fn invalid(msg: String) -> Error {
+    Error::InvalidConfig(format!("invalid authorization policy: {}", msg))
+}
+
+#[cfg(feature = "authz-json")]
+fn object<'a>(v: &'a Value, what: &str) -> Result<&'a Map<String, Value>> {
+    v.as_object()
+        .ok_or_else(|| invalid(format!("{} should be an object", what)))
+}
+
+#[cfg(feature = "authz-json")]
+fn check_fields(obj: &Map<String, Value>, what: &str, known: &[&str]) -> Result<()> {
+    match obj.keys().find(|k| !known.contains(&k.as_str())) {
+        Some(k) => Err(invalid(format!("unknown field {} in {}", k, what))),
+        None => Ok(()),
+    }
+}
+
+#[cfg(feature = "authz-json")]
+fn string(v: Option<&Value>, what: &str) -> Result<String> {
+    match v {
+        Some(&Value::String(ref s)) => Ok(s.clone()),
+        Some(_) => Err(invalid(format!("{} should be a string", what))),
+        None => Err(invalid(format!("{} is missing", what))),
+    }
+}
+
+#[cfg(feature = "authz-json")]
+fn strings(v: Option<&Value>, what: &str) -> Result<Vec<String>> {
+    match v {
+        Some(&Value::Array(ref values)) => values.iter().map(|v| string(Some(v), what)).collect(),
+        Some(_) => Err(invalid(format!("{} should be an array", what))),
+        None => Ok(vec![]),
+    }
+}
+
+#[cfg(feature = "authz-json")]
+fn parse_rule(v: &Value) -> Result<Rule> {
+    let obj = object(v, "rule")?;
+    check_fields(obj, "rule", &["name", "source", "request"])?;
+    let mut rule = Rule::new(string(obj.get("name"), "rule name")?);
+    if let Some(source) = obj.get("source") {
+        let source = object(source, "source")?;
+        check_fields(source, "source", &["principals"])?;
+        for p in strings(source.get("principals"), "principals")? {
+            rule = rule.principal(p);
+        }
+    }
+    if let Some(request) = obj.get("request") {
+        let request = object(request, "request")?;
+        check_fields(request, "request", &["paths", "headers"])?;
+        for p in strings(request.get("paths"), "paths")? {
+            rule = rule.path(p);
+        }
+        let headers = match request.get("headers") {
+            Some(&Value::Array(ref headers)) => headers.as_slice(),
+            Some(_) => return Err(invalid("headers should be an array".to_owned())),
+            None => &[],
+        };
+        for h in headers {
+            let h = object(h, "header")?;
+            check_fields(h, "header", &["key", "values"])?;
+            let key = string(h.get("key"), "header key")?;
+            let values = strings(h.get("values"), "header values")?;
+            if values.is_empty() {
+                return Err(invalid(format!("header {} has no values", key)));
+            }
+            for value in values {
+                rule = rule.header(key.as_str(), value);
+            }
+        }
+    }
+    Ok(rule)
+}
+
+/// Checks calls against a policy that can be replaced at any time.
+///
+/// Clones share the same policy.
+#[derive(Clone)]
+pub struct Authorizer {
+    policy: Arc<RwLock<Arc<Policy>>>,
+}
+
+impl Authorizer {
+    pub fn new(policy: Policy) -> Authorizer {
+        Authorizer {
+            policy: Arc::new(RwLock::new(Arc::new(policy))),
+        }
+    }
+
+    /// Get the policy in use.
+    pub fn policy(&self) -> Arc<Policy> {
+        self.policy.read().unwrap().clone()
+    }
+
+    /// Check the following calls against `policy`. The calls in progress are
+    /// not affected.
+    pub fn set_policy(&self, policy: Policy) {
+        *self.policy.write().unwrap() = Arc::new(policy);
+    }
+
+    /// Check `ctx` against the policy in use.
+    pub fn check(&self, ctx: &RpcContext) -> result::Result<(), RpcStatus> {
+        let policy = self.policy();
+        match policy.check(ctx) {
+            Ok(_) => Ok(()),
+            Err(status) => {
+                debug!(
+                    "call to {} from {} is denied: {:?}",
+                    String::from_utf8_lossy(ctx.method()),
+                    ctx.peer(),
+                    status.details
+                );
+                Err(status)
+            }
+        }
+    }
+
+    /// Load the policy from the JSON file at `path`, and reload it whenever
+    /// the file is modified.
+    ///
+    /// The file is checked every `interval` by a thread, which stops once all
+    /// the clones of the returned authorizer are dropped. If the file fails to
+    /// be reloaded, the error is logged and the last policy is kept.
+    #[cfg(feature = "authz-json")]
+    pub fn watch_file<P: Into<PathBuf>>(path: P, interval: Duration) -> Result<Authorizer> {
+        let path = path.into();
+        let mut modified = fs::metadata(&path).and_then(|m| m.modified()).ok();
+        let authorizer = Authorizer::new(Policy::from_file(&path)?);
+        let policy = Arc::downgrade(&authorizer.policy);
+        thread::Builder::new()
+            .name("grpc-authz-watcher".to_owned())
+            .spawn(move || loop {
+                thread::sleep(interval);
+                let policy = match policy.upgrade() {
+                    Some(p) => p,
+                    None => return,
+                };
+                let m = fs::metadata(&path).and_then(|m| m.modified()).ok();
+                if m == modified {
+                    continue;
+                }
+                modified = m;
+                match Policy::from_file(&path) {
+                    Ok(p) => {
+                        info!("authorization policy {} is reloaded", p.name());
+                        *policy.write().unwrap() = Arc::new(p);
+                    }
+                    Err(e) => error!("failed to reload {}: {:?}", path.display(), e),
+                }
+            })
+            .map_err(|e| invalid(format!("failed to spawn watcher: {}", e)))?;
+        Ok(authorizer)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use call::RpcStatusCode;
+
+    #[test]
+    fn test_evaluate() {
+        let policy = Policy::new("kv")
+            .allow(
+                Rule::new("readers")
+                    .path("/kv.Kv/Get")
+                    .principal("spiffe://example.org/*"),
+            )
+            .allow(Rule::new("health").path("/grpc.health.v1.Health/*"))
+            .deny(Rule::new("no-debug").header("X-Debug", "*"));
+        let call = |path: &'static str, principals: Option<&[&str]>, headers| CallInfo {
+            path: path.as_bytes(),
+            principals: principals.map(|p| p.iter().map(|p| p.as_bytes().to_vec()).collect()),
+            headers,
+        };
+        let frontend: &[&str] = &["spiffe://example.org/frontend"];
+
+        let get = call("/kv.Kv/Get", Some(frontend), vec![]);
+        assert_eq!(policy.evaluate(&get).unwrap(), "readers");
+        let health = call("/grpc.health.v1.Health/Check", None, vec![]);
+        assert_eq!(policy.evaluate(&health).unwrap(), "health");
+        for c in &[
+            call("/kv.Kv/Put", Some(frontend), vec![]),
+            call("/kv.Kv/Get", None, vec![]),
+            call("/kv.Kv/Get", Some(&["spiffe://other.org/a"]), vec![]),
+            call("/kv.Kv/Get", Some(frontend), vec![("x-debug", b"1")]),
+        ] {
+            let status = policy.evaluate(c).unwrap_err();
+            assert_eq!(status.status, RpcStatusCode::PermissionDenied);
+        }
+        let status = policy
+            .evaluate(&call("/kv.Kv/Get", Some(frontend), vec![("x-debug", b"1")]))
+            .unwrap_err();
+        assert_eq!(
+            status.details.unwrap(),
+            "denied by rule no-debug of policy kv"
+        );
+    }
+
+    #[cfg(feature = "authz-json")]
+    #[test]
+    fn test_from_json() {
+        let policy = Policy::from_json(
+            r#"{
+                "name": "kv",
+                "deny_rules": [
+                    {"name": "no-debug", "request": {"headers": [{"key": "x-debug", "values": ["*"]}]}}
+                ],
+                "allow_rules": [
+                    {
+                        "name": "readers",
+                        "source": {"principals": ["spiffe://example.org/*"]},
+                        "request": {"paths": ["/kv.Kv/Get", "/kv.Kv/Scan"]}
+                    }
+                ]
+            }"#,
+        )
+        .unwrap();
+        assert_eq!(policy.name(), "kv");
+        assert_eq!(policy.deny_rules[0].headers.len(), 1);
+        assert_eq!(policy.allow_rules[0].paths.len(), 2);
+        assert_eq!(policy.allow_rules[0].principals.len(), 1);
+
+        for json in &[
+            r#"{"name": "kv"}"#,
+            r#"{"allow_rules": []}"#,
+            r#"{"name": "kv", "allow_rules": [{"name": "a", "requests": {}}]}"#,
+            r#"{"name": "kv", "allow_rules": [{"name": "a", "request": {"paths": "/a"}}]}"#,
+            r#"{"name": "kv", "allow_rules": [{"name": "a", "request": {"headers": [{"key": "k"}]}}]}"#,
+        ] {
+            assert!(
+                Policy::from_json(json).is_err(),
+                "{} should be invalid",
+                json
+            );
+        }
+    }
+}
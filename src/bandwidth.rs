@@ -0,0 +1,313 @@
+// Copyright 2018 PingCAP, Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Bandwidth limits of streaming calls.
+//!
+//! A [`BandwidthLimit`] set by [`ServerBuilder::bandwidth_limit`] caps the
+//! bytes per second of the messages streamed by every call, or by all the
+//! calls on the same connection, so one client's bulk export can't take all
+//! the bandwidth of a server:
+//!
+//! ```ignore
+//! let limit = BandwidthLimit::new()
+//!     .send_rate(4 * 1024 * 1024)
+//!     .receive_rate(1024 * 1024)
+//!     .per_connection(true);
+//! let server = ServerBuilder::new(env).bandwidth_limit(limit)...;
+//! ```
+//!
+//! The limits are token buckets. A message is let through as long as the
+//! bucket isn't in debt, so messages larger than the burst aren't stuck,
+//! and the following messages wait until the debt is paid off. Sending is
+//! held up by the sinks, while receiving stops asking gRPC core for the
+//! next message, so the client is slowed down by HTTP/2 flow control.
+//!
+//! Only the messages of streams are limited, neither the request of a
+//! server streaming call nor the response of a client streaming call is.
+//!
+//! [`BandwidthLimit`]: struct.BandwidthLimit.html
+//! [`ServerBuilder::bandwidth_limit`]: ../struct.ServerBuilder.html#method.bandwidth_limit
+
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex, Weak};
+use std::time::{Duration, Instant};
+
+use futures::{Async, Future};
+
+use async::Timer;
+use call::Deadline;
+use cq::CompletionQueue;
+
+/// Limits of the bandwidth of streaming calls, in bytes per second.
+#[derive(Clone, Debug, Default)]
+pub struct BandwidthLimit {
+    receive_rate: Option<u64>,
+    send_rate: Option<u64>,
+    burst: Option<u64>,
+    per_connection: bool,
+}
+
+impl BandwidthLimit {
+    pub fn new() -> BandwidthLimit {
+        BandwidthLimit::default()
+    }
+
+    /// Limit the request messages received.
+    pub fn receive_rate(mut self, bytes_per_sec: u64) -> BandwidthLimit {
+        self.receive_rate = Some(bytes_per_sec);
+        self
+    }
+
+    /// Limit the response messages sent.
+    pub fn send_rate(mut self, bytes_per_sec: u64) -> BandwidthLimit {
+        self.send_rate = Some(bytes_per_sec);
+        self
+    }
+
+    /// Allow bursts of up to `bytes`. It's the bytes of one second by default.
+    pub fn burst(mut self, bytes: u64) -> BandwidthLimit {
+        self.burst = Some(bytes);
+        self
+    }
+
+    /// Share the limits among the calls on the same connection, instead of
+    /// applying them to every call. Connections are told apart by the
+    /// address of the peer, so the ones over unix sockets share the limits.
+    pub fn per_connection(mut self, enabled: bool) -> BandwidthLimit {
+        self.per_connection = enabled;
+        self
+    }
+
+    fn buckets(&self) -> Arc<Buckets> {
+        let bucket = |rate: Option<u64>| rate.map(|r| TokenBucket::new(r, self.burst.unwrap_or(r)));
+        Arc::new(Buckets {
+            receive: bucket(self.receive_rate),
+            send: bucket(self.send_rate),
+        })
+    }
+}
+
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+struct TokenBucket {
+    rate: f64,
+    burst: f64,
+    state: Mutex<Bucket>,
+}
+
+impl TokenBucket {
+    fn new(rate: u64, burst: u64) -> TokenBucket {
+        TokenBucket {
+            // A zero rate would never pay off the debt.
+            rate: rate.max(1) as f64,
+            burst: burst as f64,
+            state: Mutex::new(Bucket {
+                tokens: burst as f64,
+                updated: Instant::now(),
+            }),
+        }
+    }
+
+    /// Get how long it takes to pay off the debt, `None` if there is none.
+    fn debt_at(&self, now: Instant) -> Option<Duration> {
+        let mut b = self.state.lock().unwrap();
+        if now > b.updated {
+            let elapsed = now - b.updated;
+            let secs = elapsed.as_secs() as f64 + f64::from(elapsed.subsec_nanos()) / 1e9;
+            b.tokens = (b.tokens + secs * self.rate).min(self.burst);
+            b.updated = now;
+        }
+        if b.tokens >= 0.0 {
+            return None;
+        }
+        let nanos = (-b.tokens / self.rate * 1e9).ceil() as u64;
+        Some(Duration::new(
+            nanos / 1_000_000_000,
+            (nanos % 1_000_000_000) as u32,
+        ))
+    }
+
+    fn take(&self, bytes: usize) {
+        self.state.lock().unwrap().tokens -= bytes as f64;
+    }
+}
+
+/// The buckets of a call, shared by the calls on the same connection if
+/// [`BandwidthLimit::per_connection`] is enabled.
+///
+/// [`BandwidthLimit::per_connection`]: struct.BandwidthLimit.html#method.per_connection
+pub(crate) struct Buckets {
+    receive: Option<TokenBucket>,
+    send: Option<TokenBucket>,
+}
+
+#[derive(Default)]
+struct Connections {
+    // Buckets are released with the last call on the connection.
+    buckets: HashMap<String, Weak<Buckets>>,
+    // Forget the closed connections once there are this many.
+    cleanup_at: usize,
+}
+
+pub(crate) struct BandwidthLimiter {
+    limit: BandwidthLimit,
+    connections: Mutex<Connections>,
+}
+
+impl BandwidthLimiter {
+    pub fn new(limit: BandwidthLimit) -> BandwidthLimiter {
+        BandwidthLimiter {
+            limit,
+            connections: Mutex::default(),
+        }
+    }
+
+    /// Get the buckets for a call from `peer`.
+    pub fn attach(&self, peer: &str) -> Arc<Buckets> {
+        if !self.limit.per_connection {
+            return self.limit.buckets();
+        }
+        let mut conns = self.connections.lock().unwrap();
+        if let Some(b) = conns.buckets.get(peer).and_then(|w| w.upgrade()) {
+            return b;
+        }
+        let b = self.limit.buckets();
+        if conns.buckets.len() >= conns.cleanup_at {
+            conns.buckets.retain(|_, w| w.upgrade().is_some());
+            conns.cleanup_at = (conns.buckets.len() * 2).max(64);
+        }
+        conns.buckets.insert(peer.to_owned(), Arc::downgrade(&b));
+        b
+    }
+}
+
+/// Check whether `bucket` is out of debt, or arm `timer` to be woken up when
+/// it is.
+fn poll_ready(bucket: &TokenBucket, timer: &mut Option<Timer>, cq: &CompletionQueue) -> bool {
+    loop {
+        let wait = match bucket.debt_at(Instant::now()) {
+            Some(wait) => wait,
+            None => {
+                *timer = None;
+                return true;
+            }
+        };
+        if timer.is_none() {
+            match Timer::new(cq, &Deadline::from(wait)) {
+                Ok(t) => *timer = Some(t),
+                // The queue is shutting down, don't hold up the call.
+                Err(_) => return true,
+            }
+        }
+        match timer.as_mut().unwrap().poll() {
+            Ok(Async::NotReady) => return false,
+            // Another call on the connection may have taken the tokens,
+            // check the bucket again.
+            _ => *timer = None,
+        }
+    }
+}
+
+/// Throttles the streaming operations of a call.
+///
+/// Receiving and sending may be polled by different tasks, so each of them
+/// has its own timer.
+pub(crate) struct Throttle {
+    buckets: Arc<Buckets>,
+    receive_timer: Option<Timer>,
+    send_timer: Option<Timer>,
+}
+
+impl Throttle {
+    pub fn new(buckets: Arc<Buckets>) -> Throttle {
+        Throttle {
+            buckets,
+            receive_timer: None,
+            send_timer: None,
+        }
+    }
+
+    pub fn poll_receive(&mut self, cq: &CompletionQueue) -> bool {
+        match self.buckets.receive {
+            Some(ref b) => poll_ready(b, &mut self.receive_timer, cq),
+            None => true,
+        }
+    }
+
+    pub fn poll_send(&mut self, cq: &CompletionQueue) -> bool {
+        match self.buckets.send {
+            Some(ref b) => poll_ready(b, &mut self.send_timer, cq),
+            None => true,
+        }
+    }
+
+    pub fn on_received(&self, bytes: usize) {
+        if let Some(ref b) = self.buckets.receive {
+            b.take(bytes);
+        }
+    }
+
+    pub fn on_sent(&self, bytes: usize) {
+        if let Some(ref b) = self.buckets.send {
+            b.take(bytes);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_token_bucket() {
+        let bucket = TokenBucket::new(1000, 500);
+        let now = Instant::now();
+        assert_eq!(bucket.debt_at(now), None);
+        // Larger messages than the burst go through, and are paid off later.
+        bucket.take(1500);
+        assert_eq!(bucket.debt_at(now), Some(Duration::from_secs(1)));
+        let later = now + Duration::from_millis(600);
+        assert_eq!(bucket.debt_at(later), Some(Duration::from_millis(400)));
+        // Idle time doesn't save more than the burst.
+        let idle = later + Duration::from_secs(10);
+        assert_eq!(bucket.debt_at(idle), None);
+        bucket.take(501);
+        assert_eq!(bucket.debt_at(idle), Some(Duration::from_millis(1)));
+
+        let limiter = BandwidthLimiter::new(BandwidthLimit::new().send_rate(100));
+        let (a, b) = (
+            limiter.attach("ipv4:1.1.1.1:1"),
+            limiter.attach("ipv4:1.1.1.1:1"),
+        );
+        assert!(a.receive.is_none() && a.send.is_some());
+        assert!(!Arc::ptr_eq(&a, &b));
+
+        let limit = BandwidthLimit::new().send_rate(100).per_connection(true);
+        let limiter = BandwidthLimiter::new(limit);
+        let (a, b) = (
+            limiter.attach("ipv4:1.1.1.1:1"),
+            limiter.attach("ipv4:1.1.1.1:1"),
+        );
+        let c = limiter.attach("ipv4:1.1.1.1:2");
+        assert!(Arc::ptr_eq(&a, &b));
+        assert!(!Arc::ptr_eq(&a, &c));
+        a.send.as_ref().unwrap().take(1000);
+        drop((a, b));
+        // A new connection from the same address starts afresh.
+        let d = limiter.attach("ipv4:1.1.1.1:1");
+        assert_eq!(d.send.as_ref().unwrap().debt_at(Instant::now()), None);
+    }
+}
@@ -0,0 +1,122 @@
+// Copyright 2018 PingCAP, Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Synchronous handlers.
+//!
+//! Handlers are invoked in the gRPC poll threads, so they must not block.
+//! A synchronous function can be registered by
+//! [`ServiceBuilder::add_blocking_unary_handler`] instead, it's run in a
+//! [`BlockingPool`] and its result is sent back by the poll thread:
+//!
+//! ```ignore
+//! let pool = BlockingPool::new(8);
+//! let service = ServiceBuilder::new()
+//!     .add_blocking_unary_handler(&METHOD_GREETER_SAY_HELLO, &pool, |req: HelloRequest| {
+//!         let mut reply = HelloReply::new();
+//!         reply.set_message(db.lookup(req.get_name())?);
+//!         Ok(reply)
+//!     })
+//!     .build();
+//! ```
+//!
+//! A function that panics fails the call with `Internal`, the worker thread
+//! keeps running. The threads exit once the pool and all the services using
+//! it are dropped.
+//!
+//! [`ServiceBuilder::add_blocking_unary_handler`]: ../struct.ServiceBuilder.html#method.add_blocking_unary_handler
+//! [`BlockingPool`]: struct.BlockingPool.html
+
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread::Builder as ThreadBuilder;
+
+type Job = Box<FnOnce() + Send>;
+
+/// A pool of threads that run blocking handlers.
+#[derive(Clone)]
+pub struct BlockingPool {
+    sender: Arc<Mutex<Sender<Job>>>,
+}
+
+impl BlockingPool {
+    /// Create a pool of `threads` threads, at least one.
+    pub fn new(threads: usize) -> BlockingPool {
+        let (tx, rx) = mpsc::channel();
+        let rx = Arc::new(Mutex::new(rx));
+        for i in 0..threads.max(1) {
+            let rx = rx.clone();
+            ThreadBuilder::new()
+                .name(format!("grpc-blocking-{}", i))
+                .spawn(move || worker(&rx))
+                .unwrap();
+        }
+        BlockingPool {
+            sender: Arc::new(Mutex::new(tx)),
+        }
+    }
+
+    /// Run `f` in one of the threads.
+    pub(crate) fn execute<F>(&self, f: F)
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        // The workers only exit after all senders are dropped.
+        self.sender.lock().unwrap().send(Box::new(f)).unwrap();
+    }
+}
+
+fn worker(rx: &Mutex<Receiver<Job>>) {
+    loop {
+        let job = match rx.lock().unwrap().recv() {
+            Ok(job) => job,
+            Err(_) => return,
+        };
+        // The panic is reported by the call, which sees the job dropped.
+        let _ = panic::catch_unwind(AssertUnwindSafe(job));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::mpsc;
+    use std::thread;
+
+    use super::*;
+
+    #[test]
+    fn test_blocking_pool() {
+        let pool = BlockingPool::new(2);
+        let (tx, rx) = mpsc::channel();
+        for i in 0..4 {
+            let tx = tx.clone();
+            pool.execute(move || {
+                if i == 1 {
+                    panic!("job {} panicked", i);
+                }
+                tx.send((i, thread::current().name().unwrap().to_owned()))
+                    .unwrap();
+            });
+        }
+        drop(tx);
+        let mut done: Vec<_> = rx.iter().collect();
+        done.sort();
+        assert_eq!(
+            done.iter().map(|&(i, _)| i).collect::<Vec<_>>(),
+            vec![0, 2, 3]
+        );
+        assert!(done
+            .iter()
+            .all(|&(_, ref n)| n.starts_with("grpc-blocking-")));
+    }
+}
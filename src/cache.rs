@@ -0,0 +1,479 @@
+// Copyright 2018 PingCAP, Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Client side caching of unary responses.
+//!
+//! A [`CachingClient`] answers the unary calls marked by
+//! [`CallOption::cacheable`] from a [`CacheStore`] when it holds a fresh
+//! response for the same call, and only sends the calls otherwise:
+//!
+//! ```ignore
+//! let client = CachingClient::new(Client::new(ch), Arc::new(LruStore::new(1024)));
+//! let opt = CallOption::default().cacheable(true);
+//! let config = client.unary_call(&METHOD_CONFIG_GET, &req, opt)?;
+//! ```
+//!
+//! How long a response stays fresh is decided by the `cache-control`
+//! trailer the server sends along with it, e.g. by
+//! `sink.success_with_trailers`:
+//!
+//! - `max-age=<seconds>` keeps it for the given time;
+//! - `no-store` or `no-cache` doesn't keep it at all;
+//! - without the trailer, the response is kept for the default ttl of the
+//!   client, if one is set by [`CachingClient::default_ttl`].
+//!
+//! A call with a `cache-control: no-cache` header skips the lookup, but its
+//! response is still stored. Failed calls are never cached. `max-age` is
+//! capped at 2^31 seconds, as suggested by RFC 7234.
+//!
+//! Two calls are the same call only if they have the same method, serialized
+//! request, authority and headers, except `cache-control`. So a response
+//! fetched with one caller's `authorization` is never served to another
+//! caller, but headers that change with every call, e.g. a request id, make
+//! every call miss the cache.
+//!
+//! [`CachingClient`]: struct.CachingClient.html
+//! [`CachingClient::default_ttl`]: struct.CachingClient.html#method.default_ttl
+//! [`CacheStore`]: trait.CacheStore.html
+//! [`CallOption::cacheable`]: ../struct.CallOption.html#method.cacheable
+
+use std::collections::{BTreeMap, HashMap};
+use std::mem;
+use std::str;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use futures::{Async, Future, Poll};
+
+use call::client::{CallOption, ClientUnaryReceiver};
+use call::{Method, MethodType};
+use client::Client;
+use codec::{raw_codec, DeserializeFn, Marshaller};
+use error::{Error, Result};
+use metadata::Metadata;
+
+/// The metadata key of caching directives.
+pub const CACHE_CONTROL_KEY: &str = "cache-control";
+
+/// The largest `max-age` that is followed, any larger one is capped to it.
+const MAX_AGE_LIMIT: u64 = 1 << 31;
+
+/// The storage of cached responses.
+///
+/// Keys are opaque bytes built from the method name, the authority and
+/// headers of the call and the serialized request, values are serialized
+/// responses.
+pub trait CacheStore: Send + Sync {
+    /// Get the value of `key` if it's not expired.
+    fn get(&self, key: &[u8]) -> Option<Vec<u8>>;
+
+    /// Store `value` for `key`, it expires after `ttl`.
+    fn put(&self, key: Vec<u8>, value: Vec<u8>, ttl: Duration);
+}
+
+struct Slot {
+    value: Vec<u8>,
+    expires: Instant,
+    tick: u64,
+}
+
+#[derive(Default)]
+struct Lru {
+    slots: HashMap<Vec<u8>, Slot>,
+    // Keys ordered by their last use.
+    order: BTreeMap<u64, Vec<u8>>,
+    tick: u64,
+}
+
+impl Lru {
+    fn remove(&mut self, key: &[u8]) {
+        if let Some(slot) = self.slots.remove(key) {
+            self.order.remove(&slot.tick);
+        }
+    }
+}
+
+/// An in-memory [`CacheStore`] that holds at most a given count of entries,
+/// evicting the least recently used ones.
+///
+/// [`CacheStore`]: trait.CacheStore.html
+pub struct LruStore {
+    capacity: usize,
+    lru: Mutex<Lru>,
+}
+
+impl LruStore {
+    /// Create a store of at most `capacity`, at least one, entries.
+    pub fn new(capacity: usize) -> LruStore {
+        LruStore {
+            capacity: capacity.max(1),
+            lru: Mutex::default(),
+        }
+    }
+
+    /// Get the count of entries, including the expired ones not evicted yet.
+    pub fn len(&self) -> usize {
+        self.lru.lock().unwrap().slots.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Remove all the entries.
+    pub fn clear(&self) {
+        let mut lru = self.lru.lock().unwrap();
+        lru.slots.clear();
+        lru.order.clear();
+    }
+}
+
+impl CacheStore for LruStore {
+    fn get(&self, key: &[u8]) -> Option<Vec<u8>> {
+        let mut lru = self.lru.lock().unwrap();
+        if lru.slots.get(key)?.expires <= Instant::now() {
+            lru.remove(key);
+            return None;
+        }
+        lru.tick += 1;
+        let tick = lru.tick;
+        let (old, value) = {
+            let slot = lru.slots.get_mut(key).unwrap();
+            (mem::replace(&mut slot.tick, tick), slot.value.clone())
+        };
+        let key = lru.order.remove(&old).unwrap();
+        lru.order.insert(tick, key);
+        Some(value)
+    }
+
+    fn put(&self, key: Vec<u8>, value: Vec<u8>, ttl: Duration) {
+        let mut lru = self.lru.lock().unwrap();
+        lru.remove(&key);
+        lru.tick += 1;
+        let tick = lru.tick;
+        lru.order.insert(tick, key.clone());
+        lru.slots.insert(
+            key,
+            Slot {
+                value,
+                expires: Instant::now() + ttl.min(Duration::from_secs(MAX_AGE_LIMIT)),
+                tick,
+            },
+        );
+        while lru.slots.len() > self.capacity {
+            let oldest = *lru.order.keys().next().unwrap();
+            let key = lru.order.remove(&oldest).unwrap();
+            lru.slots.remove(&key);
+        }
+    }
+}
+
+#[derive(Debug, PartialEq)]
+enum Directive {
+    MaxAge(Duration),
+    NoStore,
+    Unspecified,
+}
+
+fn parse_cache_control(value: &[u8]) -> Directive {
+    let value = match str::from_utf8(value) {
+        Ok(v) => v,
+        Err(_) => return Directive::Unspecified,
+    };
+    let mut res = Directive::Unspecified;
+    for d in value.split(',').map(|d| d.trim().to_ascii_lowercase()) {
+        if d == "no-store" || d == "no-cache" {
+            return Directive::NoStore;
+        }
+        if d.starts_with("max-age=") {
+            let secs = d["max-age=".len()..].trim_matches('"');
+            if !secs.is_empty() && secs.bytes().all(|b| b.is_ascii_digit()) {
+                // Too many digits to fit in `u64` is still a valid max-age.
+                let secs = secs.parse().unwrap_or(MAX_AGE_LIMIT).min(MAX_AGE_LIMIT);
+                res = Directive::MaxAge(Duration::from_secs(secs));
+            }
+        }
+    }
+    res
+}
+
+fn push_field(key: &mut Vec<u8>, field: &[u8]) {
+    key.extend_from_slice(&(field.len() as u64).to_le_bytes());
+    key.extend_from_slice(field);
+}
+
+/// Build the key of a call, every field is prefixed by its length so
+/// different calls never share a key.
+fn cache_key(method: &str, opt: &CallOption, req: &[u8]) -> Vec<u8> {
+    let mut key = vec![];
+    push_field(&mut key, method.as_bytes());
+    push_field(&mut key, req);
+    push_field(&mut key, opt.get_authority().unwrap_or("").as_bytes());
+    if let Some(headers) = opt.get_headers() {
+        for (k, v) in headers.iter().filter(|&(k, _)| k != CACHE_CONTROL_KEY) {
+            push_field(&mut key, k.as_bytes());
+            push_field(&mut key, v);
+        }
+    }
+    key
+}
+
+fn find_directive(meta: Option<&Metadata>) -> Directive {
+    meta.and_then(|m| m.iter().find(|&(k, _)| k == CACHE_CONTROL_KEY))
+        .map_or(Directive::Unspecified, |(_, v)| parse_cache_control(v))
+}
+
+/// A client that caches the responses of cacheable unary calls, see
+/// [`cache`](index.html) for details.
+#[derive(Clone)]
+pub struct CachingClient {
+    client: Client,
+    store: Arc<CacheStore>,
+    default_ttl: Option<Duration>,
+}
+
+impl CachingClient {
+    pub fn new(client: Client, store: Arc<CacheStore>) -> CachingClient {
+        CachingClient {
+            client,
+            store,
+            default_ttl: None,
+        }
+    }
+
+    /// Keep the responses that come without a `cache-control` trailer for
+    /// `ttl`. They are not cached by default.
+    pub fn default_ttl(mut self, ttl: Duration) -> CachingClient {
+        self.default_ttl = Some(ttl);
+        self
+    }
+
+    /// Get the client the calls are sent by.
+    pub fn client(&self) -> &Client {
+        &self.client
+    }
+
+    /// Create a synchronized unary RPC call, which may be answered by the
+    /// cache.
+    pub fn unary_call<Req: 'static, Resp: 'static>(
+        &self,
+        method: &Method<Req, Resp>,
+        req: &Req,
+        opt: CallOption,
+    ) -> Result<Resp> {
+        self.unary_call_async(method, req, opt)?.wait()
+    }
+
+    /// Create an asynchronized unary RPC call, which may be answered by the
+    /// cache.
+    ///
+    /// Calls that are not marked cacheable are sent as is.
+    pub fn unary_call_async<Req: 'static, Resp: 'static>(
+        &self,
+        method: &Method<Req, Resp>,
+        req: &Req,
+        opt: CallOption,
+    ) -> Result<CachedUnaryReceiver<Resp>> {
+        if !opt.is_cacheable() {
+            let recv = self.client.unary_call_async(method, req, opt)?;
+            return Ok(CachedUnaryReceiver {
+                state: State::Direct(recv),
+            });
+        }
+        let mut data = vec![];
+        (method.req_ser())(req, &mut data);
+        let key = cache_key(method.name, &opt, &data);
+        let resp_de = method.resp_de();
+        if find_directive(opt.get_headers()) != Directive::NoStore {
+            if let Some(data) = self.store.get(&key) {
+                return Ok(CachedUnaryReceiver {
+                    state: State::Hit(Some(resp_de(&data))),
+                });
+            }
+        }
+
+        // The request is serialized already, send it as is and keep the raw
+        // response for the store.
+        let raw = Method {
+            ty: MethodType::Unary,
+            name: method.name,
+            req_mar: Marshaller {
+                ser: raw_codec::ser,
+                de: raw_codec::de,
+            },
+            resp_mar: Marshaller {
+                ser: raw_codec::ser,
+                de: raw_codec::de,
+            },
+        };
+        let recv = self.client.unary_call_async(&raw, &data, opt)?;
+        Ok(CachedUnaryReceiver {
+            state: State::Miss {
+                recv,
+                key,
+                resp_de,
+                store: self.store.clone(),
+                default_ttl: self.default_ttl,
+            },
+        })
+    }
+}
+
+enum State<Resp> {
+    Direct(ClientUnaryReceiver<Resp>),
+    Hit(Option<Result<Resp>>),
+    Miss {
+        recv: ClientUnaryReceiver<Vec<u8>>,
+        key: Vec<u8>,
+        resp_de: DeserializeFn<Resp>,
+        store: Arc<CacheStore>,
+        default_ttl: Option<Duration>,
+    },
+}
+
+/// The response of a call made by [`CachingClient`].
+///
+/// [`CachingClient`]: struct.CachingClient.html
+pub struct CachedUnaryReceiver<Resp> {
+    state: State<Resp>,
+}
+
+impl<Resp> CachedUnaryReceiver<Resp> {
+    /// Whether the call is answered by the cache.
+    pub fn is_cached(&self) -> bool {
+        match self.state {
+            State::Hit(_) => true,
+            _ => false,
+        }
+    }
+
+    /// Cancel the call, it does nothing if the call is answered by the cache.
+    pub fn cancel(&mut self) {
+        match self.state {
+            State::Direct(ref mut r) => r.cancel(),
+            State::Miss { ref mut recv, .. } => recv.cancel(),
+            State::Hit(_) => {}
+        }
+    }
+}
+
+impl<Resp> Future for CachedUnaryReceiver<Resp> {
+    type Item = Resp;
+    type Error = Error;
+
+    fn poll(&mut self) -> Poll<Resp, Error> {
+        match self.state {
+            State::Direct(ref mut r) => r.poll(),
+            State::Hit(ref mut res) => res.take().expect("cannot poll twice").map(Async::Ready),
+            State::Miss {
+                ref mut recv,
+                ref key,
+                resp_de,
+                ref store,
+                default_ttl,
+            } => {
+                let data = try_ready!(recv.poll());
+                let trailers = recv.take_trailers();
+                let ttl = match find_directive(trailers.as_ref()) {
+                    Directive::MaxAge(ttl) => Some(ttl),
+                    Directive::NoStore => None,
+                    Directive::Unspecified => default_ttl,
+                };
+                let resp = resp_de(&data)?;
+                if let Some(ttl) = ttl {
+                    if ttl > Duration::from_secs(0) {
+                        store.put(key.clone(), data, ttl);
+                    }
+                }
+                Ok(Async::Ready(resp))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::thread;
+
+    use super::*;
+    use metadata::MetadataBuilder;
+
+    #[test]
+    fn test_lru_store() {
+        assert_eq!(
+            parse_cache_control(b"public, max-age=60"),
+            Directive::MaxAge(Duration::from_secs(60))
+        );
+        assert_eq!(
+            parse_cache_control(b"max-age=60, No-Store"),
+            Directive::NoStore
+        );
+        assert_eq!(parse_cache_control(b"private"), Directive::Unspecified);
+        assert_eq!(parse_cache_control(b"max-age=x"), Directive::Unspecified);
+        assert_eq!(parse_cache_control(b"max-age=-1"), Directive::Unspecified);
+        let limit = Directive::MaxAge(Duration::from_secs(MAX_AGE_LIMIT));
+        assert_eq!(parse_cache_control(b"max-age=18446744073709551615"), limit);
+        assert_eq!(parse_cache_control(b"max-age=99999999999999999999"), limit);
+
+        let store = LruStore::new(2);
+        let ttl = Duration::from_secs(60);
+        store.put(b"a".to_vec(), b"1".to_vec(), ttl);
+        store.put(b"b".to_vec(), b"2".to_vec(), ttl);
+        // `a` is used later than `b` now.
+        assert_eq!(store.get(b"a"), Some(b"1".to_vec()));
+        store.put(b"c".to_vec(), b"3".to_vec(), ttl);
+        assert_eq!(store.get(b"b"), None);
+        assert_eq!(store.get(b"a"), Some(b"1".to_vec()));
+        assert_eq!(store.get(b"c"), Some(b"3".to_vec()));
+        store.put(b"c".to_vec(), b"4".to_vec(), ttl);
+        assert_eq!(store.get(b"c"), Some(b"4".to_vec()));
+        assert_eq!(store.len(), 2);
+
+        store.put(b"d".to_vec(), b"5".to_vec(), Duration::from_millis(10));
+        thread::sleep(Duration::from_millis(20));
+        assert_eq!(store.get(b"d"), None);
+        assert_eq!(store.len(), 1);
+        store.clear();
+        assert!(store.is_empty());
+
+        // A ttl that overflows `Instant` is capped.
+        store.put(
+            b"e".to_vec(),
+            b"6".to_vec(),
+            Duration::from_secs(u64::max_value()),
+        );
+        assert_eq!(store.get(b"e"), Some(b"6".to_vec()));
+    }
+
+    #[test]
+    fn test_cache_key() {
+        let with_header = |key: &str, value: &str| {
+            let mut builder = MetadataBuilder::new();
+            builder.add_str(key, value).unwrap();
+            CallOption::default().headers(builder.build())
+        };
+        let plain = cache_key("/a", &CallOption::default(), b"req");
+        assert_eq!(plain, cache_key("/a", &CallOption::default(), b"req"));
+        assert_ne!(plain, cache_key("/a", &CallOption::default(), b"other"));
+        assert_ne!(plain, cache_key("/b", &CallOption::default(), b"req"));
+        let authority = CallOption::default().authority("a.example.org");
+        assert_ne!(plain, cache_key("/a", &authority, b"req"));
+
+        let alice = cache_key("/a", &with_header("authorization", "alice"), b"req");
+        let bob = cache_key("/a", &with_header("authorization", "bob"), b"req");
+        assert_ne!(plain, alice);
+        assert_ne!(alice, bob);
+        let no_cache = with_header(CACHE_CONTROL_KEY, "no-cache");
+        assert_eq!(plain, cache_key("/a", &no_cache, b"req"));
+    }
+}
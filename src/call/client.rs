@@ -11,20 +11,30 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-use std::ptr;
 use std::sync::Arc;
 use std::time::Duration;
+use std::{cmp, ptr};
 
+use futures::stream::FuturesUnordered;
 use futures::{Async, AsyncSink, Future, Poll, Sink, StartSend, Stream};
 use grpc_sys;
 
 use super::{ShareCall, ShareCallHolder, SinkBase, WriteFlags};
 use async::{BatchFuture, BatchMessage, BatchType, CqFuture, SpinLock};
-use call::{check_run, Call, Method};
-use channel::Channel;
-use codec::{DeserializeFn, SerializeFn};
+use call::{check_run, check_run_with_stats, Call, Deadline, Method};
+use channel::{Channel, CompressionLevel, StreamCompressionAlgorithms};
+use checksum::{self, Checksum};
+use codec::{DeserializeFn, DeserializeIntoFn, SerializeFn};
+use context::Context;
 use error::{Error, Result};
-use metadata::Metadata;
+use message_hook::Hook;
+use metadata::{MergePolicy, Metadata, MetadataBuilder};
+use method_config;
+use peer::Peer;
+use request_id::RequestIdConfig;
+
+// Metadata key gRPC core looks for to override the stream compression algorithm.
+const STREAM_COMPRESSION_REQUEST_KEY: &str = "grpc-internal-stream-encoding-request";
 
 /// Update the flag bit in res.
 #[inline]
@@ -40,9 +50,13 @@ pub fn change_flag(res: &mut u32, flag: u32, set: bool) {
 #[derive(Clone, Default)]
 pub struct CallOption {
     timeout: Option<Duration>,
+    deadline: Option<Deadline>,
     write_flags: WriteFlags,
     call_flags: u32,
     headers: Option<Metadata>,
+    checksum: Option<Arc<Checksum>>,
+    authority: Option<String>,
+    stream_idle_timeout: Option<Duration>,
 }
 
 impl CallOption {
@@ -76,13 +90,21 @@ impl CallOption {
         self
     }
 
+    pub(crate) fn is_idempotent(&self) -> bool {
+        self.call_flags & grpc_sys::GRPC_INITIAL_METADATA_IDEMPOTENT_REQUEST != 0
+    }
+
+    pub(crate) fn is_cacheable(&self) -> bool {
+        self.call_flags & grpc_sys::GRPC_INITIAL_METADATA_CACHEABLE_REQUEST != 0
+    }
+
     /// Set write flags.
     pub fn write_flags(mut self, write_flags: WriteFlags) -> CallOption {
         self.write_flags = write_flags;
         self
     }
 
-    /// Set a timeout.
+    /// Set a timeout, which is counted from the time the call is created.
     pub fn timeout(mut self, timeout: Duration) -> CallOption {
         self.timeout = Some(timeout);
         self
@@ -93,29 +115,160 @@ impl CallOption {
         self.timeout
     }
 
+    /// Reset a streaming call with `Unavailable` if it has neither received
+    /// nor sent a message for longer than `timeout`.
+    ///
+    /// A response message counts once it's taken from the receiver, and a
+    /// request message once it's handed to gRPC core. It has no effect on
+    /// unary calls.
+    pub fn stream_idle_timeout(mut self, timeout: Duration) -> CallOption {
+        self.stream_idle_timeout = Some(timeout);
+        self
+    }
+
+    /// Set an absolute deadline, e.g. the one of a server call that is being
+    /// propagated to its downstream calls.
+    ///
+    /// If a timeout is also set, whichever is earlier takes effect.
+    pub fn deadline<D: Into<Deadline>>(mut self, deadline: D) -> CallOption {
+        self.deadline = Some(deadline.into());
+        self
+    }
+
+    /// Get the absolute deadline.
+    pub fn get_deadline(&self) -> Option<Deadline> {
+        self.deadline
+    }
+
+    /// The deadline that applies to a call created now.
+    pub(crate) fn effective_deadline(&self) -> Deadline {
+        let timeout = self
+            .timeout
+            .map_or_else(Deadline::infinite, Deadline::after);
+        self.deadline.map_or(timeout, |d| cmp::min(d, timeout))
+    }
+
     /// Set the headers to be sent with the call.
     pub fn headers(mut self, meta: Metadata) -> CallOption {
         self.headers = Some(meta);
         self
     }
 
+    /// Merge `meta` into the headers to be sent with the call, keys that are
+    /// already set are resolved by `policy`.
+    pub fn merge_headers(mut self, meta: Metadata, policy: MergePolicy) -> CallOption {
+        self.headers = Some(match self.headers.take() {
+            Some(headers) => headers.merge(&meta, policy),
+            None => meta,
+        });
+        self
+    }
+
+    /// Send the baggage in `ctx` along with the headers.
+    ///
+    /// Baggage headers that are already set are kept, server merges them all.
+    pub fn context(self, ctx: &Context) -> CallOption {
+        if ctx.is_empty() {
+            return self;
+        }
+        self.merge_headers(ctx.to_headers(), MergePolicy::Append)
+    }
+
+    /// Override the default compression level of the channel for this call.
+    ///
+    /// The level is mapped to an algorithm as if the server accepts all the
+    /// algorithms, see [`method_config::algorithm_for_level`].
+    ///
+    /// [`method_config::algorithm_for_level`]: method_config/fn.algorithm_for_level.html
+    pub fn compression_level(self, level: CompressionLevel) -> CallOption {
+        let meta = method_config::compression_metadata(method_config::algorithm_for_level(level));
+        self.merge_headers(meta, MergePolicy::Replace)
+    }
+
+    /// Override the stream compression algorithm of the channel for this call.
+    pub fn stream_compression(self, algo: StreamCompressionAlgorithms) -> CallOption {
+        let name = match algo {
+            StreamCompressionAlgorithms::None => "identity",
+            StreamCompressionAlgorithms::Gzip => "gzip",
+        };
+        let mut builder = MetadataBuilder::with_capacity(1);
+        builder
+            .add_str(STREAM_COMPRESSION_REQUEST_KEY, name)
+            .unwrap();
+        self.merge_headers(builder.build(), MergePolicy::Replace)
+    }
+
     /// Get headers to be sent with the call.
     pub fn get_headers(&self) -> Option<&Metadata> {
         self.headers.as_ref()
     }
+
+    /// Override the `:authority` of the call, which is the default authority
+    /// of the channel otherwise.
+    ///
+    /// It's useful when connecting to a proxy address while the server is
+    /// expected to see the logical authority.
+    pub fn authority<S: Into<String>>(mut self, authority: S) -> CallOption {
+        self.authority = Some(authority.into());
+        self
+    }
+
+    /// Get the authority of the call.
+    pub fn get_authority(&self) -> Option<&str> {
+        self.authority.as_ref().map(|a| a.as_str())
+    }
+
+    /// Send the checksum of the request along with headers and verify the
+    /// checksum of the response if server sends one.
+    ///
+    /// Only single messages are covered: both messages of unary calls, the
+    /// request of server streaming calls and the response of client
+    /// streaming calls.
+    pub fn checksum(mut self, checksum: Arc<Checksum>) -> CallOption {
+        self.checksum = Some(checksum);
+        self
+    }
+
+    pub(crate) fn attach_request_id(&mut self, config: &RequestIdConfig) {
+        self.headers = Some(config.attach(self.headers.take()));
+    }
+
+    fn append_checksum(&mut self, payload: &[u8]) {
+        if let Some(ref c) = self.checksum {
+            self.headers = Some(checksum::append(self.headers.take(), c.as_ref(), payload));
+        }
+    }
 }
 
 impl Call {
-    pub fn unary_async<Req, Resp>(
+    // Serialize the request, which is shown to the message hook if any.
+    fn serialize_request<Req: 'static, Resp>(
         channel: &Channel,
         method: &Method<Req, Resp>,
         req: &Req,
-        mut opt: CallOption,
-    ) -> Result<ClientUnaryReceiver<Resp>> {
-        let call = channel.create_call(method, &opt)?;
+    ) -> Result<Vec<u8>> {
         let mut payload = vec![];
         (method.req_ser())(req, &mut payload);
-        let cq_f = check_run(BatchType::CheckRead, |ctx, tag| unsafe {
+        if let Some(h) = channel.hook::<Req>(method.name) {
+            let mut req = (method.req_de())(&payload)?;
+            h.on_send(&mut req);
+            payload.clear();
+            (method.req_ser())(&req, &mut payload);
+        }
+        Ok(payload)
+    }
+
+    pub fn unary_async<Req: 'static, Resp: 'static>(
+        channel: &Channel,
+        method: &Method<Req, Resp>,
+        req: &Req,
+        mut opt: CallOption,
+    ) -> Result<ClientUnaryReceiver<Resp>> {
+        let call = channel.create_call(method.name, &mut opt)?;
+        let payload = Call::serialize_request(channel, method, req)?;
+        opt.append_checksum(&payload);
+        let stats = Some(channel.stats_recorder(&call));
+        let cq_f = check_run_with_stats(BatchType::CheckRead, stats, |ctx, tag| unsafe {
             grpc_sys::grpcwrap_call_start_unary(
                 call.call,
                 ctx,
@@ -129,16 +282,42 @@ impl Call {
                 tag,
             )
         });
-        Ok(ClientUnaryReceiver::new(call, cq_f, method.resp_de()))
+        Ok(ClientUnaryReceiver::new(
+            call,
+            cq_f,
+            method.resp_de(),
+            opt.checksum,
+            channel.hook(method.name),
+        ))
     }
 
-    pub fn client_streaming<Req, Resp>(
+    pub fn batch_unary_async<'a, Req, Resp, I>(
+        channel: &Channel,
+        method: &Method<Req, Resp>,
+        reqs: I,
+        opt: CallOption,
+    ) -> Result<BatchUnaryReceiver<Resp>>
+    where
+        Req: 'static,
+        Resp: 'static,
+        I: IntoIterator<Item = &'a Req>,
+    {
+        let mut batch = BatchUnaryReceiver::new();
+        for (i, req) in reqs.into_iter().enumerate() {
+            let recv = Call::unary_async(channel, method, req, opt.clone())?;
+            batch.push(i, recv);
+        }
+        Ok(batch)
+    }
+
+    pub fn client_streaming<Req: 'static, Resp: 'static>(
         channel: &Channel,
         method: &Method<Req, Resp>,
         mut opt: CallOption,
     ) -> Result<(ClientCStreamSender<Req>, ClientCStreamReceiver<Resp>)> {
-        let call = channel.create_call(method, &opt)?;
-        let cq_f = check_run(BatchType::CheckRead, |ctx, tag| unsafe {
+        let call = channel.create_call(method.name, &mut opt)?;
+        let stats = Some(channel.stats_recorder(&call));
+        let cq_f = check_run_with_stats(BatchType::CheckRead, stats, |ctx, tag| unsafe {
             grpc_sys::grpcwrap_call_start_client_streaming(
                 call.call,
                 ctx,
@@ -149,26 +328,41 @@ impl Call {
                 tag,
             )
         });
+        let headers_f = check_run(BatchType::Headers, |ctx, tag| unsafe {
+            grpc_sys::grpcwrap_call_recv_initial_metadata(call.call, ctx, tag)
+        });
 
-        let share_call = Arc::new(SpinLock::new(ShareCall::new(call, cq_f)));
-        let sink = ClientCStreamSender::new(share_call.clone(), method.req_ser());
+        let mut share_call = ShareCall::new(call, cq_f);
+        share_call.headers_f = Some(headers_f);
+        if let Some(timeout) = opt.stream_idle_timeout {
+            share_call.watch_idle(timeout);
+        }
+        let share_call = Arc::new(SpinLock::new(share_call));
+        let sink = ClientCStreamSender::new(
+            share_call.clone(),
+            method.req_ser(),
+            channel.hook(method.name),
+        );
         let recv = ClientCStreamReceiver {
             call: share_call,
             resp_de: method.resp_de(),
+            checksum: opt.checksum,
+            hook: channel.hook(method.name),
         };
         Ok((sink, recv))
     }
 
-    pub fn server_streaming<Req, Resp>(
+    pub fn server_streaming<Req: 'static, Resp: 'static>(
         channel: &Channel,
         method: &Method<Req, Resp>,
         req: &Req,
         mut opt: CallOption,
     ) -> Result<ClientSStreamReceiver<Resp>> {
-        let call = channel.create_call(method, &opt)?;
-        let mut payload = vec![];
-        (method.req_ser())(req, &mut payload);
-        let cq_f = check_run(BatchType::Finish, |ctx, tag| unsafe {
+        let call = channel.create_call(method.name, &mut opt)?;
+        let payload = Call::serialize_request(channel, method, req)?;
+        opt.append_checksum(&payload);
+        let stats = Some(channel.stats_recorder(&call));
+        let cq_f = check_run_with_stats(BatchType::Finish, stats, |ctx, tag| unsafe {
             grpc_sys::grpcwrap_call_start_server_streaming(
                 call.call,
                 ctx,
@@ -188,16 +382,40 @@ impl Call {
             grpc_sys::grpcwrap_call_recv_initial_metadata(call.call, ctx, tag)
         });
 
-        Ok(ClientSStreamReceiver::new(call, cq_f, method.resp_de()))
+        Ok(ClientSStreamReceiver::new(
+            call,
+            cq_f,
+            method.resp_de(),
+            channel.hook(method.name),
+            opt.stream_idle_timeout,
+        ))
     }
 
-    pub fn duplex_streaming<Req, Resp>(
+    pub fn duplex_streaming<Req: 'static, Resp: 'static>(
         channel: &Channel,
         method: &Method<Req, Resp>,
+        opt: CallOption,
+    ) -> Result<(ClientDuplexSender<Req>, ClientDuplexReceiver<Resp>)> {
+        Call::duplex_streaming_by_name(
+            channel,
+            method.name,
+            method.req_ser(),
+            method.resp_de(),
+            opt,
+        )
+    }
+
+    /// Start a duplex streaming call to the method with the given full qualified name.
+    pub fn duplex_streaming_by_name<Req: 'static, Resp: 'static>(
+        channel: &Channel,
+        method: &str,
+        req_ser: SerializeFn<Req>,
+        resp_de: DeserializeFn<Resp>,
         mut opt: CallOption,
     ) -> Result<(ClientDuplexSender<Req>, ClientDuplexReceiver<Resp>)> {
-        let call = channel.create_call(method, &opt)?;
-        let cq_f = check_run(BatchType::Finish, |ctx, tag| unsafe {
+        let call = channel.create_call(method, &mut opt)?;
+        let stats = Some(channel.stats_recorder(&call));
+        let cq_f = check_run_with_stats(BatchType::Finish, stats, |ctx, tag| unsafe {
             grpc_sys::grpcwrap_call_start_duplex_streaming(
                 call.call,
                 ctx,
@@ -209,14 +427,18 @@ impl Call {
             )
         });
 
-        // TODO: handle header.
-        check_run(BatchType::Finish, |ctx, tag| unsafe {
+        let headers_f = check_run(BatchType::Headers, |ctx, tag| unsafe {
             grpc_sys::grpcwrap_call_recv_initial_metadata(call.call, ctx, tag)
         });
 
-        let share_call = Arc::new(SpinLock::new(ShareCall::new(call, cq_f)));
-        let sink = ClientDuplexSender::new(share_call.clone(), method.req_ser());
-        let recv = ClientDuplexReceiver::new(share_call, method.resp_de());
+        let mut share_call = ShareCall::new(call, cq_f);
+        share_call.headers_f = Some(headers_f);
+        if let Some(timeout) = opt.stream_idle_timeout {
+            share_call.watch_idle(timeout);
+        }
+        let share_call = Arc::new(SpinLock::new(share_call));
+        let sink = ClientDuplexSender::new(share_call.clone(), req_ser, channel.hook(method));
+        let recv = ClientDuplexReceiver::new(share_call, resp_de, channel.hook(method));
         Ok((sink, recv))
     }
 }
@@ -228,6 +450,10 @@ pub struct ClientUnaryReceiver<T> {
     call: Call,
     resp_f: CqFuture<BatchMessage>,
     resp_de: DeserializeFn<T>,
+    checksum: Option<Arc<Checksum>>,
+    headers: Option<Metadata>,
+    trailers: Option<Metadata>,
+    hook: Option<Hook<T>>,
 }
 
 impl<T> ClientUnaryReceiver<T> {
@@ -235,11 +461,17 @@ impl<T> ClientUnaryReceiver<T> {
         call: Call,
         resp_f: CqFuture<BatchMessage>,
         de: DeserializeFn<T>,
+        checksum: Option<Arc<Checksum>>,
+        hook: Option<Hook<T>>,
     ) -> ClientUnaryReceiver<T> {
         ClientUnaryReceiver {
             call,
             resp_f,
             resp_de: de,
+            checksum,
+            headers: None,
+            trailers: None,
+            hook,
         }
     }
 
@@ -247,6 +479,26 @@ impl<T> ClientUnaryReceiver<T> {
     pub fn cancel(&mut self) {
         self.call.cancel()
     }
+
+    /// Get the address of the server, or the target of the channel if the
+    /// call is not sent yet.
+    pub fn peer(&self) -> Peer {
+        self.call.peer()
+    }
+
+    /// Take the initial metadata sent by server.
+    ///
+    /// It's only available after the future is resolved.
+    pub fn take_headers(&mut self) -> Option<Metadata> {
+        self.headers.take()
+    }
+
+    /// Take the trailing metadata sent by server.
+    ///
+    /// It's only available after the future is resolved.
+    pub fn take_trailers(&mut self) -> Option<Metadata> {
+        self.trailers.take()
+    }
 }
 
 impl<T> Future for ClientUnaryReceiver<T> {
@@ -254,16 +506,89 @@ impl<T> Future for ClientUnaryReceiver<T> {
     type Error = Error;
 
     fn poll(&mut self) -> Poll<T, Error> {
-        let data = try_ready!(self.resp_f.poll());
-        let t = (self.resp_de)(&data.unwrap())?;
+        let res = self.resp_f.poll();
+        if let Ok(Async::NotReady) = res {
+            return Ok(Async::NotReady);
+        }
+        self.headers = self.resp_f.take_headers();
+        self.trailers = self.resp_f.take_trailers();
+        let data = try_ready!(res).unwrap();
+        if let Some(ref c) = self.checksum {
+            if let Some(ref trailers) = self.trailers {
+                if !checksum::verify(trailers, c.as_ref(), &data) {
+                    return Err(checksum::mismatch_error());
+                }
+            }
+        }
+        let mut t = (self.resp_de)(&data)?;
+        if let Some(ref h) = self.hook {
+            h.on_receive(&mut t);
+        }
         Ok(Async::Ready(t))
     }
 }
 
+// A unary receiver that remembers its position in the batch.
+struct IndexedUnaryReceiver<T> {
+    index: usize,
+    recv: ClientUnaryReceiver<T>,
+}
+
+impl<T> Future for IndexedUnaryReceiver<T> {
+    type Item = (usize, Result<T>);
+    type Error = Error;
+
+    fn poll(&mut self) -> Poll<(usize, Result<T>), Error> {
+        match self.recv.poll() {
+            Ok(Async::NotReady) => Ok(Async::NotReady),
+            Ok(Async::Ready(t)) => Ok(Async::Ready((self.index, Ok(t)))),
+            Err(e) => Ok(Async::Ready((self.index, Err(e)))),
+        }
+    }
+}
+
+/// A receiver for a batch of unary requests.
+///
+/// Every response is yielded as soon as it's received, along with the index of the
+/// request in the batch.
+pub struct BatchUnaryReceiver<T> {
+    calls: FuturesUnordered<IndexedUnaryReceiver<T>>,
+}
+
+impl<T> BatchUnaryReceiver<T> {
+    fn new() -> BatchUnaryReceiver<T> {
+        BatchUnaryReceiver {
+            calls: FuturesUnordered::new(),
+        }
+    }
+
+    fn push(&mut self, index: usize, recv: ClientUnaryReceiver<T>) {
+        self.calls.push(IndexedUnaryReceiver { index, recv })
+    }
+
+    /// Cancel all the calls that are not finished yet.
+    pub fn cancel(&mut self) {
+        for c in self.calls.iter_mut() {
+            c.recv.cancel();
+        }
+    }
+}
+
+impl<T> Stream for BatchUnaryReceiver<T> {
+    type Item = (usize, Result<T>);
+    type Error = Error;
+
+    fn poll(&mut self) -> Poll<Option<(usize, Result<T>)>, Error> {
+        self.calls.poll()
+    }
+}
+
 /// A receiver for client streaming call.
 pub struct ClientCStreamReceiver<T> {
     call: Arc<SpinLock<ShareCall>>,
     resp_de: DeserializeFn<T>,
+    checksum: Option<Arc<Checksum>>,
+    hook: Option<Hook<T>>,
 }
 
 impl<T> ClientCStreamReceiver<T> {
@@ -272,6 +597,19 @@ impl<T> ClientCStreamReceiver<T> {
         let lock = self.call.lock();
         lock.call.cancel()
     }
+
+    /// Get the address of the server, or the target of the channel if the
+    /// call is not sent yet.
+    pub fn peer(&self) -> Peer {
+        self.call.lock().call.peer()
+    }
+
+    /// Take the trailing metadata sent by server.
+    ///
+    /// It's only available after the response is received.
+    pub fn take_trailers(&mut self) -> Option<Metadata> {
+        self.call.lock().trailers.take()
+    }
 }
 
 impl<T> Future for ClientCStreamReceiver<T> {
@@ -281,9 +619,18 @@ impl<T> Future for ClientCStreamReceiver<T> {
     fn poll(&mut self) -> Poll<T, Error> {
         let data = {
             let mut call = self.call.lock();
-            try_ready!(call.poll_finish())
+            let data = try_ready!(call.poll_finish()).unwrap();
+            if let (&Some(ref c), &Some(ref trailers)) = (&self.checksum, &call.trailers) {
+                if !checksum::verify(trailers, c.as_ref(), &data) {
+                    return Err(checksum::mismatch_error());
+                }
+            }
+            data
         };
-        let t = (self.resp_de)(&data.unwrap())?;
+        let mut t = (self.resp_de)(&data)?;
+        if let Some(ref h) = self.hook {
+            h.on_receive(&mut t);
+        }
         Ok(Async::Ready(t))
     }
 }
@@ -294,15 +641,21 @@ pub struct StreamingCallSink<Req> {
     sink_base: SinkBase,
     close_f: Option<BatchFuture>,
     req_ser: SerializeFn<Req>,
+    hook: Option<Hook<Req>>,
 }
 
 impl<Req> StreamingCallSink<Req> {
-    fn new(call: Arc<SpinLock<ShareCall>>, ser: SerializeFn<Req>) -> StreamingCallSink<Req> {
+    fn new(
+        call: Arc<SpinLock<ShareCall>>,
+        ser: SerializeFn<Req>,
+        hook: Option<Hook<Req>>,
+    ) -> StreamingCallSink<Req> {
         StreamingCallSink {
             call,
             sink_base: SinkBase::new(false),
             close_f: None,
             req_ser: ser,
+            hook,
         }
     }
 
@@ -310,19 +663,57 @@ impl<Req> StreamingCallSink<Req> {
         let call = self.call.lock();
         call.call.cancel()
     }
+
+    /// Poll the initial metadata sent by the server.
+    ///
+    /// Messages can be sent without waiting for it, unless the protocol needs
+    /// to inspect the headers first, e.g. to get a session id assigned by server.
+    pub fn poll_headers(&mut self) -> Poll<Metadata, Error> {
+        self.call.lock().poll_headers()
+    }
+
+    /// Wait for the initial metadata sent by the server. The sink is given back
+    /// along with the metadata once it's received.
+    pub fn await_headers(self) -> AwaitHeaders<Req> {
+        AwaitHeaders { sink: Some(self) }
+    }
+}
+
+/// A future that resolves to the initial metadata sent by the server and the
+/// sink of the call.
+///
+/// It's created by [`StreamingCallSink::await_headers`].
+///
+/// [`StreamingCallSink::await_headers`]: struct.StreamingCallSink.html#method.await_headers
+pub struct AwaitHeaders<Req> {
+    sink: Option<StreamingCallSink<Req>>,
+}
+
+impl<Req> Future for AwaitHeaders<Req> {
+    type Item = (Metadata, StreamingCallSink<Req>);
+    type Error = Error;
+
+    fn poll(&mut self) -> Poll<Self::Item, Error> {
+        let headers = try_ready!(self
+            .sink
+            .as_mut()
+            .expect("polled after resolved")
+            .poll_headers());
+        Ok(Async::Ready((headers, self.sink.take().unwrap())))
+    }
 }
 
 impl<Req> Sink for StreamingCallSink<Req> {
     type SinkItem = (Req, WriteFlags);
     type SinkError = Error;
 
-    fn start_send(&mut self, (msg, flags): Self::SinkItem) -> StartSend<Self::SinkItem, Error> {
+    fn start_send(&mut self, (mut msg, flags): Self::SinkItem) -> StartSend<Self::SinkItem, Error> {
         {
             let mut call = self.call.lock();
             call.check_alive()?;
         }
         self.sink_base
-            .start_send(&mut self.call, &msg, flags, self.req_ser)
+            .start_send(&mut self.call, &mut msg, flags, self.req_ser, &self.hook)
             .map(|s| {
                 if s {
                     AsyncSink::Ready
@@ -366,15 +757,17 @@ struct ResponseStreamImpl<H, T> {
     msg_f: Option<BatchFuture>,
     read_done: bool,
     resp_de: DeserializeFn<T>,
+    hook: Option<Hook<T>>,
 }
 
 impl<H: ShareCallHolder, T> ResponseStreamImpl<H, T> {
-    fn new(call: H, resp_de: DeserializeFn<T>) -> ResponseStreamImpl<H, T> {
+    fn new(call: H, resp_de: DeserializeFn<T>, hook: Option<Hook<T>>) -> ResponseStreamImpl<H, T> {
         ResponseStreamImpl {
             call,
             msg_f: None,
             read_done: false,
             resp_de,
+            hook,
         }
     }
 
@@ -382,7 +775,41 @@ impl<H: ShareCallHolder, T> ResponseStreamImpl<H, T> {
         self.call.call(|c| c.call.cancel())
     }
 
+    fn peer(&mut self) -> Peer {
+        self.call.call(|c| c.call.peer())
+    }
+
+    fn take_trailers(&mut self) -> Option<Metadata> {
+        self.call.call(|c| c.trailers.take())
+    }
+
     fn poll(&mut self) -> Poll<Option<T>, Error> {
+        match try_ready!(self.poll_bytes()) {
+            None => Ok(Async::Ready(None)),
+            Some(data) => {
+                let mut msg = (self.resp_de)(&data)?;
+                if let Some(ref h) = self.hook {
+                    h.on_receive(&mut msg);
+                }
+                Ok(Async::Ready(Some(msg)))
+            }
+        }
+    }
+
+    fn poll_into(&mut self, de: DeserializeIntoFn<T>, msg: &mut T) -> Poll<bool, Error> {
+        match try_ready!(self.poll_bytes()) {
+            None => Ok(Async::Ready(false)),
+            Some(data) => {
+                de(&data, msg)?;
+                if let Some(ref h) = self.hook {
+                    h.on_receive(msg);
+                }
+                Ok(Async::Ready(true))
+            }
+        }
+    }
+
+    fn poll_bytes(&mut self) -> Poll<Option<Vec<u8>>, Error> {
         let mut finished = false;
         self.call.call(|c| {
             if c.finished {
@@ -417,9 +844,9 @@ impl<H: ShareCallHolder, T> ResponseStreamImpl<H, T> {
             self.msg_f.take();
             let msg_f = self.call.call(|c| c.call.start_recv_message())?;
             self.msg_f = Some(msg_f);
-            if let Some(ref data) = bytes {
-                let msg = (self.resp_de)(data)?;
-                return Ok(Async::Ready(Some(msg)));
+            if bytes.is_some() {
+                self.call.call(|c| c.on_active());
+                return Ok(Async::Ready(bytes));
             }
         }
     }
@@ -435,16 +862,42 @@ impl<Resp> ClientSStreamReceiver<Resp> {
         call: Call,
         finish_f: CqFuture<BatchMessage>,
         de: DeserializeFn<Resp>,
+        hook: Option<Hook<Resp>>,
+        idle_timeout: Option<Duration>,
     ) -> ClientSStreamReceiver<Resp> {
-        let share_call = ShareCall::new(call, finish_f);
+        let mut share_call = ShareCall::new(call, finish_f);
+        if let Some(timeout) = idle_timeout {
+            share_call.watch_idle(timeout);
+        }
         ClientSStreamReceiver {
-            imp: ResponseStreamImpl::new(share_call, de),
+            imp: ResponseStreamImpl::new(share_call, de, hook),
         }
     }
 
     pub fn cancel(&mut self) {
         self.imp.cancel()
     }
+
+    /// Get the address of the server, or the target of the channel if the
+    /// call is not sent yet.
+    pub fn peer(&mut self) -> Peer {
+        self.imp.peer()
+    }
+
+    /// Take the trailing metadata sent by server.
+    ///
+    /// It's only available after the stream is finished.
+    pub fn take_trailers(&mut self) -> Option<Metadata> {
+        self.imp.take_trailers()
+    }
+    /// Like `poll`, but decode the next message into `msg` with `de`.
+    ///
+    /// `Ready(false)` means the stream is finished and `msg` is untouched.
+    /// Reusing one message for the whole stream saves allocating a new one
+    /// for every message, e.g. with `pb_de_into`.
+    pub fn poll_into(&mut self, de: DeserializeIntoFn<Resp>, msg: &mut Resp) -> Poll<bool, Error> {
+        self.imp.poll_into(de, msg)
+    }
 }
 
 impl<Resp> Stream for ClientSStreamReceiver<Resp> {
@@ -462,15 +915,40 @@ pub struct ClientDuplexReceiver<Resp> {
 }
 
 impl<Resp> ClientDuplexReceiver<Resp> {
-    fn new(call: Arc<SpinLock<ShareCall>>, de: DeserializeFn<Resp>) -> ClientDuplexReceiver<Resp> {
+    fn new(
+        call: Arc<SpinLock<ShareCall>>,
+        de: DeserializeFn<Resp>,
+        hook: Option<Hook<Resp>>,
+    ) -> ClientDuplexReceiver<Resp> {
         ClientDuplexReceiver {
-            imp: ResponseStreamImpl::new(call, de),
+            imp: ResponseStreamImpl::new(call, de, hook),
         }
     }
 
     pub fn cancel(&mut self) {
         self.imp.cancel()
     }
+
+    /// Get the address of the server, or the target of the channel if the
+    /// call is not sent yet.
+    pub fn peer(&mut self) -> Peer {
+        self.imp.peer()
+    }
+
+    /// Take the trailing metadata sent by server.
+    ///
+    /// It's only available after the stream is finished.
+    pub fn take_trailers(&mut self) -> Option<Metadata> {
+        self.imp.take_trailers()
+    }
+    /// Like `poll`, but decode the next message into `msg` with `de`.
+    ///
+    /// `Ready(false)` means the stream is finished and `msg` is untouched.
+    /// Reusing one message for the whole stream saves allocating a new one
+    /// for every message, e.g. with `pb_de_into`.
+    pub fn poll_into(&mut self, de: DeserializeIntoFn<Resp>, msg: &mut Resp) -> Poll<bool, Error> {
+        self.imp.poll_into(de, msg)
+    }
 }
 
 impl<Resp> Stream for ClientDuplexReceiver<Resp> {
@@ -484,6 +962,8 @@ impl<Resp> Stream for ClientDuplexReceiver<Resp> {
 
 #[cfg(test)]
 mod tests {
+    use super::*;
+
     #[test]
     fn test_change_flag() {
         let mut flag = 2 | 4;
@@ -492,4 +972,16 @@ mod tests {
         super::change_flag(&mut flag, 4, false);
         assert_eq!(flag, 2 | 8);
     }
+
+    #[test]
+    fn test_stream_compression() {
+        let opt = CallOption::default()
+            .stream_compression(StreamCompressionAlgorithms::Gzip)
+            .stream_compression(StreamCompressionAlgorithms::None);
+        let headers: Vec<_> = opt.get_headers().unwrap().iter().collect();
+        assert_eq!(
+            headers,
+            vec![(STREAM_COMPRESSION_REQUEST_KEY, b"identity".as_ref())]
+        );
+    }
 }
@@ -0,0 +1,193 @@
+// Copyright 2018 PingCAP, Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::cmp::Ordering;
+use std::fmt::{self, Debug, Formatter};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use grpc_sys::{self, GprClockType, GprTimespec};
+
+const NANOS_PER_SEC: i64 = 1_000_000_000;
+
+fn now() -> GprTimespec {
+    unsafe { grpc_sys::gpr_now(GprClockType::Monotonic) }
+}
+
+fn add(spec: GprTimespec, dur: Duration) -> GprTimespec {
+    let secs = spec.tv_sec.saturating_add(dur.as_secs() as i64);
+    let nanos = i64::from(spec.tv_nsec) + i64::from(dur.subsec_nanos());
+    GprTimespec {
+        tv_sec: secs.saturating_add(nanos / NANOS_PER_SEC),
+        tv_nsec: (nanos % NANOS_PER_SEC) as i32,
+        clock_type: spec.clock_type,
+    }
+}
+
+/// An absolute point in time by which a call is supposed to finish.
+///
+/// Deadlines are measured against the monotonic clock, so they are not
+/// affected by changes of system time, and can be compared with each other
+/// directly. It can be converted from an `Instant`, a `SystemTime` or a
+/// `Duration`, which is counted from now.
+#[derive(Clone, Copy)]
+pub struct Deadline {
+    spec: GprTimespec,
+}
+
+impl Deadline {
+    pub(crate) fn from_spec(spec: GprTimespec) -> Deadline {
+        let spec = unsafe { grpc_sys::gpr_convert_clock_type(spec, GprClockType::Monotonic) };
+        Deadline { spec }
+    }
+
+    /// A deadline that is never exceeded.
+    pub fn infinite() -> Deadline {
+        let spec = unsafe { grpc_sys::gpr_inf_future(GprClockType::Monotonic) };
+        Deadline { spec }
+    }
+
+    /// A deadline that is `timeout` from now.
+    pub fn after(timeout: Duration) -> Deadline {
+        Deadline {
+            spec: add(now(), timeout),
+        }
+    }
+
+    pub(crate) fn spec(&self) -> GprTimespec {
+        self.spec
+    }
+
+    /// Whether the deadline is infinite.
+    pub fn is_infinite(&self) -> bool {
+        self.spec.tv_sec == Deadline::infinite().spec.tv_sec
+    }
+
+    pub fn exceeded(&self) -> bool {
+        unsafe { grpc_sys::gpr_time_cmp(now(), self.spec) >= 0 }
+    }
+
+    /// Get the time left before the deadline is exceeded.
+    ///
+    /// Returns `None` if the deadline is infinite.
+    pub fn timeout(&self) -> Option<Duration> {
+        if self.is_infinite() {
+            return None;
+        }
+        let now = now();
+        let mut secs = self.spec.tv_sec - now.tv_sec;
+        let mut nanos = self.spec.tv_nsec - now.tv_nsec;
+        if nanos < 0 {
+            secs -= 1;
+            nanos += NANOS_PER_SEC as i32;
+        }
+        if secs < 0 {
+            return Some(Duration::from_secs(0));
+        }
+        Some(Duration::new(secs as u64, nanos as u32))
+    }
+
+    /// Convert the deadline to an `Instant`.
+    ///
+    /// Returns `None` if the deadline is infinite.
+    pub fn to_instant(&self) -> Option<Instant> {
+        self.timeout().map(|t| Instant::now() + t)
+    }
+}
+
+impl From<Duration> for Deadline {
+    fn from(timeout: Duration) -> Deadline {
+        Deadline::after(timeout)
+    }
+}
+
+impl From<Instant> for Deadline {
+    fn from(instant: Instant) -> Deadline {
+        let now = Instant::now();
+        if instant > now {
+            Deadline::after(instant - now)
+        } else {
+            Deadline::after(Duration::from_secs(0))
+        }
+    }
+}
+
+impl From<SystemTime> for Deadline {
+    fn from(time: SystemTime) -> Deadline {
+        // Times before the epoch are long exceeded anyway.
+        let since_epoch = time
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_else(|_| Duration::from_secs(0));
+        let mut spec = GprTimespec::from(since_epoch);
+        spec.clock_type = GprClockType::Realtime;
+        Deadline::from_spec(spec)
+    }
+}
+
+impl PartialEq for Deadline {
+    fn eq(&self, other: &Deadline) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for Deadline {}
+
+impl PartialOrd for Deadline {
+    fn partial_cmp(&self, other: &Deadline) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Deadline {
+    fn cmp(&self, other: &Deadline) -> Ordering {
+        (self.spec.tv_sec, self.spec.tv_nsec).cmp(&(other.spec.tv_sec, other.spec.tv_nsec))
+    }
+}
+
+impl Debug for Deadline {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match self.timeout() {
+            Some(t) => write!(f, "Deadline({:?} from now)", t),
+            None => write!(f, "Deadline(infinite)"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_deadline() {
+        let inf = Deadline::infinite();
+        assert!(inf.is_infinite());
+        assert!(!inf.exceeded());
+        assert_eq!(inf.timeout(), None);
+
+        let d1 = Deadline::from(Duration::from_secs(10));
+        let d2 = Deadline::from(Instant::now() + Duration::from_secs(20));
+        let d3 = Deadline::from(SystemTime::now() + Duration::from_secs(30));
+        assert!(d1 < d2 && d2 < d3 && d3 < inf);
+        for &(d, secs) in &[(d1, 10), (d2, 20), (d3, 30)] {
+            assert!(!d.exceeded());
+            let t = d.timeout().unwrap();
+            assert!(t <= Duration::from_secs(secs), "{:?}", t);
+            assert!(t > Duration::from_secs(secs - 5), "{:?}", t);
+        }
+        assert_eq!(d1, d1.clone());
+        assert_eq!(d1.min(inf), d1);
+
+        let past = Deadline::from(UNIX_EPOCH);
+        assert!(past.exceeded());
+        assert_eq!(past.timeout(), Some(Duration::from_secs(0)));
+    }
+}
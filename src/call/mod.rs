@@ -12,20 +12,39 @@
 // limitations under the License.
 
 pub mod client;
+mod deadline;
 pub mod server;
 
+use std::ffi::{CStr, CString};
+use std::io::{self, ErrorKind};
 use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
 use std::{ptr, slice, usize};
 
 use cq::CompletionQueue;
 use futures::{Async, Future, Poll};
 use grpc_sys::{self, GrpcBatchContext, GrpcCall, GrpcCallStatus};
-use libc::c_void;
-
-use async::{self, BatchFuture, BatchMessage, BatchType, CallTag, CqFuture, SpinLock};
-use codec::{DeserializeFn, Marshaller, SerializeFn};
+use libc::{c_char, c_void};
+#[cfg(feature = "protobuf-codec")]
+use protobuf::{Message, ProtobufError};
+
+use self::server::CallTracker;
+use async::{self, BatchFuture, BatchMessage, BatchType, CallTag, CqFuture, Executor, SpinLock};
+use bandwidth::Throttle;
+use channel::StatsRecorder;
+#[cfg(feature = "protobuf-codec")]
+use codec::pb_codec;
+use codec::{self, DeserializeFn, Marshaller, SerializeFn, SharedParts};
 use error::{Error, Result};
-
+use idle::{self, IdleClock};
+use message_hook::Hook;
+use metadata::Metadata;
+use method_config::MethodConfig;
+use orca::CallMetricRecorder;
+use peer::Peer;
+
+pub use self::deadline::Deadline;
 pub use grpc_sys::GrpcStatusCode as RpcStatusCode;
 
 /// Method types supported by gRPC.
@@ -86,6 +105,9 @@ impl<Req, Resp> Method<Req, Resp> {
     }
 }
 
+/// Metadata key of the serialized `google.rpc.Status` that carries error details.
+pub const STATUS_DETAILS_KEY: &str = "grpc-status-details-bin";
+
 /// RPC result returned from the server.
 #[derive(Debug, Clone)]
 pub struct RpcStatus {
@@ -94,20 +116,182 @@ pub struct RpcStatus {
 
     /// Optional detail string.
     pub details: Option<String>,
+
+    // Trailing metadata received along with the status.
+    trailers: Option<Metadata>,
 }
 
 impl RpcStatus {
     /// Create a new [`RpcStatus`].
     pub fn new(status: RpcStatusCode, details: Option<String>) -> RpcStatus {
-        RpcStatus { status, details }
+        RpcStatus {
+            status,
+            details,
+            trailers: None,
+        }
     }
 
     /// Create a new [`RpcStatus`] that status code is Ok.
     pub fn ok() -> RpcStatus {
         RpcStatus::new(RpcStatusCode::Ok, None)
     }
+
+    pub(crate) fn with_trailers(mut self, trailers: Option<Metadata>) -> RpcStatus {
+        self.trailers = trailers;
+        self
+    }
+
+    /// The trailing metadata received along with the status.
+    ///
+    /// It's only available for statuses received by clients.
+    pub fn trailers(&self) -> Option<&Metadata> {
+        self.trailers.as_ref()
+    }
+
+    /// The serialized `google.rpc.Status` sent by server, if any.
+    pub fn details_bin(&self) -> Option<&[u8]> {
+        self.trailers
+            .as_ref()
+            .and_then(|t| t.iter().find(|&(k, _)| k == STATUS_DETAILS_KEY))
+            .map(|(_, v)| v)
+    }
+
+    /// Parse the `google.rpc.Status` sent by server.
+    ///
+    /// `M` is supposed to be the message generated from `google/rpc/status.proto`.
+    #[cfg(feature = "protobuf-codec")]
+    pub fn parse_details<M: Message>(&self) -> Option<Result<M>> {
+        self.details_bin().map(|d| pb_codec::de(d))
+    }
+
+    /// Whether the failure is transient, which means the request is safe to be
+    /// retried.
+    pub fn is_transient(&self) -> bool {
+        self.status == RpcStatusCode::Unavailable
+    }
+}
+
+macro_rules! status_helpers {
+    ($($name:ident => $code:ident,)+) => {
+        impl RpcStatus {
+            $(
+                #[doc = "Create a new [`RpcStatus`] with code `"]
+                #[doc = stringify!($code)]
+                #[doc = "` and the given message."]
+                pub fn $name<S: Into<String>>(msg: S) -> RpcStatus {
+                    RpcStatus::new(RpcStatusCode::$code, Some(msg.into()))
+                }
+            )+
+        }
+    };
 }
 
+status_helpers! {
+    cancelled => Cancelled,
+    unknown => Unknown,
+    invalid_argument => InvalidArgument,
+    deadline_exceeded => DeadlineExceeded,
+    not_found => NotFound,
+    already_exists => AlreadyExists,
+    permission_denied => PermissionDenied,
+    unauthenticated => Unauthenticated,
+    resource_exhausted => ResourceExhausted,
+    failed_precondition => FailedPrecondition,
+    aborted => Aborted,
+    out_of_range => OutOfRange,
+    unimplemented => Unimplemented,
+    internal => Internal,
+    unavailable => Unavailable,
+    data_loss => DataLoss,
+}
+
+/// A type that can be reported to clients as an [`RpcStatus`].
+///
+/// Implement it for domain errors, so handlers can convert them with
+/// `RpcStatus::from_error` or reply them with `UnarySink::complete`.
+pub trait ToGrpcStatus {
+    /// Convert to the status that is sent to clients.
+    fn to_grpc_status(&self) -> RpcStatus;
+}
+
+impl RpcStatus {
+    /// Create a new [`RpcStatus`] from any [`ToGrpcStatus`].
+    pub fn from_error<E: ToGrpcStatus + ?Sized>(e: &E) -> RpcStatus {
+        e.to_grpc_status()
+    }
+}
+
+impl ToGrpcStatus for RpcStatus {
+    fn to_grpc_status(&self) -> RpcStatus {
+        self.clone()
+    }
+}
+
+impl ToGrpcStatus for io::Error {
+    fn to_grpc_status(&self) -> RpcStatus {
+        let code = match self.kind() {
+            ErrorKind::NotFound => RpcStatusCode::NotFound,
+            ErrorKind::PermissionDenied => RpcStatusCode::PermissionDenied,
+            ErrorKind::AlreadyExists => RpcStatusCode::AlreadyExists,
+            ErrorKind::InvalidInput | ErrorKind::InvalidData => RpcStatusCode::InvalidArgument,
+            ErrorKind::TimedOut => RpcStatusCode::DeadlineExceeded,
+            ErrorKind::Interrupted => RpcStatusCode::Cancelled,
+            ErrorKind::ConnectionRefused
+            | ErrorKind::ConnectionReset
+            | ErrorKind::ConnectionAborted
+            | ErrorKind::NotConnected
+            | ErrorKind::BrokenPipe
+            | ErrorKind::WouldBlock => RpcStatusCode::Unavailable,
+            ErrorKind::UnexpectedEof => RpcStatusCode::OutOfRange,
+            _ => RpcStatusCode::Unknown,
+        };
+        RpcStatus::new(code, Some(self.to_string()))
+    }
+}
+
+#[cfg(feature = "protobuf-codec")]
+impl ToGrpcStatus for ProtobufError {
+    fn to_grpc_status(&self) -> RpcStatus {
+        RpcStatus::invalid_argument(self.to_string())
+    }
+}
+
+impl ToGrpcStatus for Error {
+    fn to_grpc_status(&self) -> RpcStatus {
+        match *self {
+            Error::RpcFailure(ref status) => status.clone(),
+            Error::RpcFinished(Some(ref status)) => status.clone(),
+            Error::Codec(ref e) => RpcStatus::internal(e.to_string()),
+            Error::RemoteStopped => RpcStatus::unavailable("remote is stopped"),
+            Error::QueueShutdown => RpcStatus::unavailable("completion queue is shutdown"),
+            ref e => RpcStatus::unknown(e.to_string()),
+        }
+    }
+}
+
+impl From<io::Error> for RpcStatus {
+    fn from(e: io::Error) -> RpcStatus {
+        e.to_grpc_status()
+    }
+}
+
+#[cfg(feature = "protobuf-codec")]
+impl From<ProtobufError> for RpcStatus {
+    fn from(e: ProtobufError) -> RpcStatus {
+        e.to_grpc_status()
+    }
+}
+
+impl From<Error> for RpcStatus {
+    fn from(e: Error) -> RpcStatus {
+        e.to_grpc_status()
+    }
+}
+
+// Messages up to this length are received into the stack first, which saves
+// walking them twice for the length and the copy.
+const SMALL_MESSAGE_LEN: usize = 1024;
+
 /// Context for batch request.
 pub struct BatchContext {
     ctx: *mut GrpcBatchContext,
@@ -142,20 +326,50 @@ impl BatchContext {
             }
         };
 
-        RpcStatus { status, details }
+        RpcStatus::new(status, details)
+    }
+
+    /// Get the initial metadata sent by the server.
+    pub fn initial_metadata(&self) -> Metadata {
+        unsafe {
+            let ptr = grpc_sys::grpcwrap_batch_context_recv_initial_metadata(self.ctx);
+            // The array will be freed with the context, so make a deep copy.
+            (*(ptr as *const Metadata)).clone()
+        }
+    }
+
+    /// Get the trailing metadata sent along with the status of the rpc call.
+    pub fn trailing_metadata(&self) -> Metadata {
+        unsafe {
+            let ptr =
+                grpc_sys::grpcwrap_batch_context_recv_status_on_client_trailing_metadata(self.ctx);
+            // The array will be freed with the context, so make a deep copy.
+            (*(ptr as *const Metadata)).clone()
+        }
     }
 
     /// Fetch the response bytes of the rpc call.
     // TODO: return Read instead.
     pub fn recv_message(&self) -> Option<Vec<u8>> {
-        // TODO: avoid copy
-        let len = unsafe { grpc_sys::grpcwrap_batch_context_recv_message_length(self.ctx) };
+        // Small messages are copied out in one crossing, larger ones need
+        // another one after the buffer is allocated for them.
+        let mut small = [0u8; SMALL_MESSAGE_LEN];
+        let len = unsafe {
+            grpc_sys::grpcwrap_batch_context_recv_message_copy(
+                self.ctx,
+                small.as_mut_ptr() as *mut _,
+                small.len(),
+            )
+        };
         if len == usize::MAX {
             return None;
         }
+        if len <= small.len() {
+            return Some(small[..len].to_vec());
+        }
         let mut buffer = Vec::with_capacity(len);
         unsafe {
-            grpc_sys::grpcwrap_batch_context_recv_message_to_buffer(
+            grpc_sys::grpcwrap_batch_context_recv_message_copy(
                 self.ctx,
                 buffer.as_mut_ptr() as *mut _,
                 len,
@@ -172,13 +386,14 @@ impl Drop for BatchContext {
     }
 }
 
+unsafe extern "C" fn release_shared(data: *mut c_void) {
+    drop(Arc::from_raw(data as *const Vec<u8>));
+}
+
 #[inline]
 fn box_batch_tag(tag: CallTag) -> (*mut GrpcBatchContext, *mut c_void) {
-    let tag_box = Box::new(tag);
-    (
-        tag_box.batch_ctx().unwrap().as_ptr(),
-        Box::into_raw(tag_box) as _,
-    )
+    let batch_ptr = tag.batch_ctx().unwrap().as_ptr();
+    (batch_ptr, tag.into_raw())
 }
 
 /// A helper function that runs the batch call and checks the result.
@@ -186,18 +401,37 @@ fn check_run<F>(bt: BatchType, f: F) -> BatchFuture
 where
     F: FnOnce(*mut GrpcBatchContext, *mut c_void) -> GrpcCallStatus,
 {
-    let (cq_f, tag) = CallTag::batch_pair(bt);
+    check_run_with_stats(bt, None, f)
+}
+
+/// Same as `check_run`, but records the status of the call to `stats`.
+fn check_run_with_stats<F>(bt: BatchType, stats: Option<StatsRecorder>, f: F) -> BatchFuture
+where
+    F: FnOnce(*mut GrpcBatchContext, *mut c_void) -> GrpcCallStatus,
+{
+    let (cq_f, tag) = CallTag::batch_pair(bt, stats);
     let (batch_ptr, tag_ptr) = box_batch_tag(tag);
     let code = f(batch_ptr, tag_ptr as *mut c_void);
     if code != GrpcCallStatus::Ok {
         unsafe {
-            Box::from_raw(tag_ptr);
+            CallTag::from_raw(tag_ptr);
         }
         panic!("create call fail: {:?}", code);
     }
     cq_f
 }
 
+/// Get the peer address of a raw call.
+pub(crate) unsafe fn call_peer(call: *mut GrpcCall) -> String {
+    let p = grpc_sys::grpc_call_get_peer(call);
+    let peer = CStr::from_ptr(p)
+        .to_str()
+        .expect("valid UTF-8 data")
+        .to_owned();
+    grpc_sys::gpr_free(p as _);
+    peer
+}
+
 /// A Call represents an RPC.
 ///
 /// When created, it is in a configuration state allowing properties to be
@@ -216,21 +450,78 @@ impl Call {
         Call { call, cq }
     }
 
+    pub(crate) fn as_ptr(&self) -> *mut GrpcCall {
+        self.call
+    }
+
+    /// Get another reference to the call.
+    pub(crate) fn share(&self) -> Call {
+        unsafe {
+            grpc_sys::grpc_call_ref(self.call);
+            Call::from_raw(self.call, self.cq.clone())
+        }
+    }
+
+    /// Get the peer address of the call.
+    pub fn peer(&self) -> Peer {
+        Peer::parse(&unsafe { call_peer(self.call) })
+    }
+
     /// Send a message asynchronously.
+    ///
+    /// `shared` are inserted into `msg` as separate slices.
     pub fn start_send_message(
         &mut self,
         msg: &[u8],
+        shared: &[(usize, Arc<Vec<u8>>)],
         write_flags: u32,
         initial_meta: bool,
     ) -> Result<BatchFuture> {
         let _cq_ref = self.cq.borrow()?;
         let i = if initial_meta { 1 } else { 0 };
+        if shared.is_empty() {
+            let f = check_run(BatchType::Finish, |ctx, tag| unsafe {
+                grpc_sys::grpcwrap_call_send_message(
+                    self.call,
+                    ctx,
+                    msg.as_ptr() as _,
+                    msg.len(),
+                    write_flags,
+                    i,
+                    tag,
+                )
+            });
+            return Ok(f);
+        }
+
+        let (mut buffers, mut lengths, mut user_data) = (vec![], vec![], vec![]);
+        let mut offset = 0;
+        for &(at, ref data) in shared {
+            if at > offset {
+                buffers.push(msg[offset..at].as_ptr() as *const c_char);
+                lengths.push(at - offset);
+                user_data.push(ptr::null_mut());
+                offset = at;
+            }
+            buffers.push(data.as_ptr() as *const c_char);
+            lengths.push(data.len());
+            // Released by `release_shared` once the message is sent.
+            user_data.push(Arc::into_raw(data.clone()) as *mut c_void);
+        }
+        if offset < msg.len() {
+            buffers.push(msg[offset..].as_ptr() as *const c_char);
+            lengths.push(msg.len() - offset);
+            user_data.push(ptr::null_mut());
+        }
         let f = check_run(BatchType::Finish, |ctx, tag| unsafe {
-            grpc_sys::grpcwrap_call_send_message(
+            grpc_sys::grpcwrap_call_send_message_slices(
                 self.call,
                 ctx,
-                msg.as_ptr() as _,
-                msg.len(),
+                buffers.as_ptr(),
+                lengths.as_ptr(),
+                user_data.as_ptr(),
+                buffers.len(),
+                release_shared,
                 write_flags,
                 i,
                 tag,
@@ -239,6 +530,15 @@ impl Call {
         Ok(f)
     }
 
+    /// Send the initial metadata asynchronously.
+    pub fn start_send_initial_metadata(&mut self, meta: &mut Metadata) -> Result<BatchFuture> {
+        let _cq_ref = self.cq.borrow()?;
+        let f = check_run(BatchType::Finish, |ctx, tag| unsafe {
+            grpc_sys::grpcwrap_call_send_initial_metadata(self.call, ctx, meta as *mut _ as _, tag)
+        });
+        Ok(f)
+    }
+
     /// Finish the rpc call from client.
     pub fn start_send_close_client(&mut self) -> Result<BatchFuture> {
         let _cq_ref = self.cq.borrow()?;
@@ -272,6 +572,7 @@ impl Call {
     pub fn start_send_status_from_server(
         &mut self,
         status: &RpcStatus,
+        trailers: &mut Option<Metadata>,
         send_empty_metadata: bool,
         payload: &Option<Vec<u8>>,
         write_flags: u32,
@@ -293,7 +594,9 @@ impl Call {
                 status.status,
                 details_ptr,
                 details_len,
-                ptr::null_mut(),
+                trailers
+                    .as_mut()
+                    .map_or_else(ptr::null_mut, |m| m as *mut _ as _),
                 send_empty_metadata,
                 payload_ptr as _,
                 payload_len,
@@ -338,7 +641,7 @@ impl Call {
         };
         if code != GrpcCallStatus::Ok {
             unsafe {
-                Box::from_raw(tag_ptr);
+                CallTag::from_raw(tag_ptr);
             }
             panic!("create call fail: {:?}", code);
         }
@@ -356,6 +659,31 @@ impl Call {
             grpc_sys::grpc_call_cancel(self.call, ptr::null_mut());
         }
     }
+
+    /// Cancel the call with `status`, on server side the status is sent to
+    /// client.
+    pub fn cancel_with_status(&self, status: &RpcStatus) {
+        match self.cq.borrow() {
+            // Queue is shutdown, ignore.
+            Err(Error::QueueShutdown) => return,
+            Err(e) => panic!("unexpected error when canceling call: {:?}", e),
+            _ => {}
+        }
+        // gRPC core requires a description, and copies it.
+        let details = status
+            .details
+            .as_ref()
+            .and_then(|d| CString::new(d.as_str()).ok())
+            .unwrap_or_default();
+        unsafe {
+            grpc_sys::grpc_call_cancel_with_status(
+                self.call,
+                status.status,
+                details.as_ptr(),
+                ptr::null_mut(),
+            );
+        }
+    }
 }
 
 impl Drop for Call {
@@ -374,6 +702,18 @@ struct ShareCall {
     close_f: CqFuture<BatchMessage>,
     finished: bool,
     status: Option<RpcStatus>,
+    // Only set on client side, resolved once the initial metadata is received.
+    headers_f: Option<BatchFuture>,
+    headers: Option<Metadata>,
+    trailers: Option<Metadata>,
+    // Only set on server side.
+    tracker: Option<Arc<CallTracker>>,
+    method_config: Option<Arc<MethodConfig>>,
+    // Whether the initial metadata is sent by `send_initial_metadata`.
+    headers_sent: bool,
+    throttle: Option<Throttle>,
+    idle: Option<Arc<IdleClock>>,
+    metric_recorder: Option<Arc<CallMetricRecorder>>,
 }
 
 impl ShareCall {
@@ -383,6 +723,167 @@ impl ShareCall {
             close_f,
             finished: false,
             status: None,
+            headers_f: None,
+            headers: None,
+            trailers: None,
+            tracker: None,
+            method_config: None,
+            headers_sent: false,
+            throttle: None,
+            idle: None,
+            metric_recorder: None,
+        }
+    }
+
+    /// Reset the call if it's idle for longer than `timeout`. Server calls
+    /// are reset through their trackers instead.
+    fn watch_idle(&mut self, timeout: Duration) {
+        let clock = Arc::new(IdleClock::new(timeout));
+        let call = self.call.share();
+        let watch = idle::watch(clock.clone(), &self.call.cq, move |status| {
+            call.cancel_with_status(&status)
+        });
+        Executor::new(&self.call.cq).spawn(watch);
+        self.idle = Some(clock);
+    }
+
+    /// A message is received or sent.
+    fn on_active(&self) {
+        if let Some(ref c) = self.idle {
+            c.touch();
+        }
+    }
+
+    /// Finish the call with `status`, and get the error for the operation
+    /// that is rejected.
+    fn reject(&mut self, status: RpcStatus) -> Error {
+        match self.tracker {
+            Some(ref t) => t.fail(&self.call, status.clone()),
+            None => self.call.cancel_with_status(&status),
+        }
+        Error::RpcFailure(status)
+    }
+
+    fn on_received(&mut self, len: usize) -> Result<()> {
+        self.on_active();
+        if let Some(ref t) = self.tracker {
+            t.on_received(len);
+        }
+        if let Some(ref t) = self.throttle {
+            t.on_received(len);
+        }
+        let status = self
+            .method_config
+            .as_ref()
+            .and_then(|c| c.check_receive(len));
+        match status {
+            Some(s) => Err(self.reject(s)),
+            None => Ok(()),
+        }
+    }
+
+    /// Check whether the next message can be received within the bandwidth
+    /// limit, the current task is woken up when it can if not.
+    fn poll_receive_quota(&mut self) -> bool {
+        match self.throttle {
+            Some(ref mut t) => t.poll_receive(&self.call.cq),
+            None => true,
+        }
+    }
+
+    /// Like `poll_receive_quota`, but for sending.
+    fn poll_send_quota(&mut self) -> bool {
+        match self.throttle {
+            Some(ref mut t) => t.poll_send(&self.call.cq),
+            None => true,
+        }
+    }
+
+    fn check_send(&mut self, len: usize) -> Result<()> {
+        let status = self.method_config.as_ref().and_then(|c| c.check_send(len));
+        match status {
+            Some(s) => Err(self.reject(s)),
+            None => Ok(()),
+        }
+    }
+
+    /// Send the initial metadata before any message.
+    fn send_initial_metadata(&mut self, mut meta: Metadata) -> Result<()> {
+        self.call.start_send_initial_metadata(&mut meta)?;
+        self.headers_sent = true;
+        Ok(())
+    }
+
+    fn start_send_message(
+        &mut self,
+        msg: &[u8],
+        shared: &[(usize, Arc<Vec<u8>>)],
+        write_flags: u32,
+        initial_meta: bool,
+    ) -> Result<BatchFuture> {
+        let len = msg.len() + shared.iter().map(|p| p.1.len()).sum::<usize>();
+        self.check_send(len)?;
+        let initial_meta = initial_meta && !self.headers_sent;
+        let f = self
+            .call
+            .start_send_message(msg, shared, write_flags, initial_meta)?;
+        self.on_active();
+        if let Some(ref t) = self.tracker {
+            t.on_sent(len);
+            t.on_send_started(len);
+        }
+        if let Some(ref t) = self.throttle {
+            t.on_sent(len);
+        }
+        Ok(f)
+    }
+
+    fn send_status_from_server(
+        &mut self,
+        status: &RpcStatus,
+        trailers: &mut Option<Metadata>,
+        send_empty_metadata: bool,
+        payload: &Option<Vec<u8>>,
+        write_flags: u32,
+    ) -> Result<BatchFuture> {
+        if let Some(ref p) = *payload {
+            self.check_send(p.len())?;
+        }
+        let send_empty_metadata = send_empty_metadata && !self.headers_sent;
+        if let Some(ref r) = self.metric_recorder {
+            *trailers = r.append(trailers.take());
+        }
+        let f = self.call.start_send_status_from_server(
+            status,
+            trailers,
+            send_empty_metadata,
+            payload,
+            write_flags,
+        )?;
+        if let Some(ref t) = self.tracker {
+            if let Some(ref p) = *payload {
+                t.on_sent(p.len());
+            }
+            t.on_complete(status.status);
+        }
+        Ok(f)
+    }
+
+    /// Poll the initial metadata sent by the server.
+    fn poll_headers(&mut self) -> Poll<Metadata, Error> {
+        if let Some(mut f) = self.headers_f.take() {
+            match f.poll() {
+                Ok(Async::NotReady) => {
+                    self.headers_f = Some(f);
+                    return Ok(Async::NotReady);
+                }
+                Ok(Async::Ready(_)) => self.headers = f.take_headers(),
+                Err(e) => return Err(e),
+            }
+        }
+        match self.headers {
+            Some(ref headers) => Ok(Async::Ready(headers.clone())),
+            None => Err(Error::RpcFinished(self.status.clone())),
         }
     }
 
@@ -404,6 +905,10 @@ impl ShareCall {
         };
 
         self.finished = true;
+        self.trailers = self.close_f.take_trailers();
+        if let Some(ref c) = self.idle {
+            c.close();
+        }
         res
     }
 
@@ -418,6 +923,24 @@ impl ShareCall {
     }
 }
 
+impl Drop for ShareCall {
+    fn drop(&mut self) {
+        if let Some(ref c) = self.idle {
+            c.close();
+        }
+        // A server call that is still alive will never send its status once
+        // all the sinks are dropped, detect it in debug builds. Unwinding
+        // is left to the panic policy.
+        if !cfg!(debug_assertions) || thread::panicking() {
+            return;
+        }
+        if self.tracker.is_some() && self.check_alive().is_ok() {
+            let t = self.tracker.as_ref().unwrap();
+            t.on_leaked(&self.call, "sink");
+        }
+    }
+}
+
 /// A helper trait that allows executing function on the inernal `ShareCall` struct.
 trait ShareCallHolder {
     fn call<R, F: FnOnce(&mut ShareCall) -> R>(&mut self, f: F) -> R;
@@ -493,6 +1016,13 @@ impl StreamingBase {
 
         // so msg_f must be either stale or not initialised yet.
         self.msg_f.take();
+        if !call.call(|c| c.poll_receive_quota()) {
+            // The next message is asked for once the quota is available.
+            return Ok(match bytes {
+                Some(_) => Async::Ready(bytes),
+                None => Async::NotReady,
+            });
+        }
         let msg_f = call.call(|c| c.call.start_recv_message())?;
         self.msg_f = Some(msg_f);
         if bytes.is_none() {
@@ -548,7 +1078,11 @@ impl WriteFlags {
 struct SinkBase {
     batch_f: Option<BatchFuture>,
     buf: Vec<u8>,
+    // Parts of the message that are kept out of `buf`.
+    shared: SharedParts,
     send_metadata: bool,
+    // Only set on server side.
+    tracker: Option<Arc<CallTracker>>,
 }
 
 impl SinkBase {
@@ -556,16 +1090,19 @@ impl SinkBase {
         SinkBase {
             batch_f: None,
             buf: Vec::new(),
+            shared: Vec::new(),
             send_metadata,
+            tracker: None,
         }
     }
 
     fn start_send<T, C: ShareCallHolder>(
         &mut self,
         call: &mut C,
-        t: &T,
+        t: &mut T,
         mut flags: WriteFlags,
         ser: SerializeFn<T>,
+        hook: &Option<Hook<T>>,
     ) -> Result<bool> {
         if self.batch_f.is_some() {
             // try its best not to return false.
@@ -575,17 +1112,24 @@ impl SinkBase {
             }
         }
 
+        if let Some(ref h) = *hook {
+            h.on_send(t);
+        }
         self.buf.clear();
-        ser(t, &mut self.buf);
+        self.shared = codec::ser_shared(ser, t, &mut self.buf);
         if flags.get_buffer_hint() && self.send_metadata {
             // temporary fix: buffer hint with send meta will not send out any metadata.
             flags = flags.buffer_hint(false);
         }
-        let write_f = call.call(|c| {
-            c.call
-                .start_send_message(&self.buf, flags.flags, self.send_metadata)
-        })?;
-        self.batch_f = Some(write_f);
+        let (buf, shared, send_metadata) = (&self.buf, &self.shared, self.send_metadata);
+        let (write_f, tracker) = call.call(|c| {
+            let f = c.start_send_message(buf, shared, flags.flags, send_metadata);
+            (f, c.tracker.clone())
+        });
+        // gRPC core holds its own references now.
+        self.shared.clear();
+        self.batch_f = Some(write_f?);
+        self.tracker = tracker;
         self.send_metadata = false;
         Ok(true)
     }
@@ -595,7 +1139,39 @@ impl SinkBase {
             try_ready!(batch_f.poll());
         }
 
-        self.batch_f.take();
+        if self.batch_f.take().is_some() {
+            if let Some(ref t) = self.tracker {
+                t.on_send_done();
+            }
+        }
         Ok(Async::Ready(()))
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_status_conversion() {
+        let status = RpcStatus::not_found("no such key");
+        assert_eq!(status.status, RpcStatusCode::NotFound);
+        assert_eq!(status.details.unwrap(), "no such key");
+
+        let cases = vec![
+            (io::ErrorKind::NotFound, RpcStatusCode::NotFound),
+            (io::ErrorKind::TimedOut, RpcStatusCode::DeadlineExceeded),
+            (io::ErrorKind::ConnectionReset, RpcStatusCode::Unavailable),
+            (io::ErrorKind::Other, RpcStatusCode::Unknown),
+        ];
+        for (kind, code) in cases {
+            let status: RpcStatus = io::Error::new(kind, "oops").into();
+            assert_eq!(status.status, code);
+            assert_eq!(status.details.unwrap(), "oops");
+        }
+
+        let status: RpcStatus = Error::RpcFailure(RpcStatus::aborted("conflict")).into();
+        assert_eq!(status.status, RpcStatusCode::Aborted);
+        assert_eq!(status.details.unwrap(), "conflict");
+    }
+}
@@ -11,48 +11,48 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-use std::ffi::CStr;
-use std::sync::Arc;
-use std::{result, slice};
+use std::any::Any;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use std::{mem, result, slice};
 
 use futures::{Async, AsyncSink, Future, Poll, Sink, StartSend, Stream};
-use grpc_sys::{self, GprClockType, GprTimespec, GrpcCallStatus, GrpcRequestCallContext};
-
-use super::{RpcStatus, ShareCall, ShareCallHolder, WriteFlags};
-use async::{BatchFuture, CallTag, Executor, SpinLock};
-use call::{BatchContext, Call, MethodType, RpcStatusCode, SinkBase, StreamingBase};
-use codec::{DeserializeFn, SerializeFn};
+use grpc_sys::{self, GrpcCallStatus, GrpcRequestCallContext};
+
+use super::{RpcStatus, ShareCall, ShareCallHolder, ToGrpcStatus, WriteFlags};
+use access_log;
+use async::{BatchFuture, BatchMessage, CallTag, CqFuture, Executor, SpinLock, Timer};
+#[cfg(feature = "tls-server")]
+use auth::AuthContext;
+use bandwidth::{Buckets, Throttle};
+use call::{
+    call_peer, BatchContext, Call, Deadline, MethodType, RpcStatusCode, SinkBase, StreamingBase,
+};
+use checksum::{self, Checksum};
+use codec::{DeserializeFn, DeserializeIntoFn, SerializeFn};
+use context::Context;
 use cq::CompletionQueue;
 use error::Error;
+use heartbeat::Heartbeat;
+use idle::{self, IdleClock};
+use message_hook::{Hook, MessageHook};
 use metadata::Metadata;
+use method_config::MethodConfig;
+use orca::CallMetricRecorder;
+use panic_policy::PanicPolicy;
+use peer::Peer;
+use request_id;
+#[cfg(feature = "executor-bridge")]
+use runtime::{BoxFuture, ExternalExecutor};
 use server::{BoxHandler, RequestCallContext};
 
-pub struct Deadline {
-    spec: GprTimespec,
-}
-
-impl Deadline {
-    fn new(spec: GprTimespec) -> Deadline {
-        let realtime_spec =
-            unsafe { grpc_sys::gpr_convert_clock_type(spec, GprClockType::Realtime) };
-
-        Deadline {
-            spec: realtime_spec,
-        }
-    }
-
-    pub fn exceeded(&self) -> bool {
-        unsafe {
-            let now = grpc_sys::gpr_now(GprClockType::Realtime);
-            grpc_sys::gpr_time_cmp(now, self.spec) >= 0
-        }
-    }
-}
-
 /// Context for accepting a request.
 pub struct RequestContext {
     ctx: *mut GrpcRequestCallContext,
     request_call: Option<RequestCallContext>,
+    arrived: Instant,
 }
 
 impl RequestContext {
@@ -62,6 +62,7 @@ impl RequestContext {
         RequestContext {
             ctx,
             request_call: Some(rc),
+            arrived: Instant::now(),
         }
     }
 
@@ -69,16 +70,17 @@ impl RequestContext {
     ///
     /// Return error if the request is a client side unary request.
     pub fn handle_stream_req(
-        self,
+        mut self,
         cq: &CompletionQueue,
         rc: &RequestCallContext,
     ) -> result::Result<(), Self> {
-        let handler = unsafe { rc.get_handler(self.method()) };
+        self.arrived = Instant::now();
+        let handler = unsafe { rc.get_handler(self.host(), self.method()) };
         match handler {
             Some(handler) => match handler.method_type() {
                 MethodType::Unary | MethodType::ServerStreaming => Err(self),
                 _ => {
-                    execute(self, cq, &[], handler);
+                    execute(self, cq, &[], handler, rc);
                     Ok(())
                 }
             },
@@ -96,15 +98,15 @@ impl RequestContext {
     /// request is received.
     pub fn handle_unary_req(self, rc: RequestCallContext, _: &CompletionQueue) {
         // fetch message before calling callback.
-        let tag = Box::new(CallTag::unary_request(self, rc));
+        let tag = CallTag::unary_request(self, rc);
         let batch_ctx = tag.batch_ctx().unwrap().as_ptr();
         let request_ctx = tag.request_ctx().unwrap().as_ptr();
-        let tag_ptr = Box::into_raw(tag);
+        let tag_ptr = tag.into_raw();
         unsafe {
             let call = grpc_sys::grpcwrap_request_call_context_get_call(request_ctx);
             let code = grpc_sys::grpcwrap_call_recv_message(call, batch_ctx, tag_ptr as _);
             if code != GrpcCallStatus::Ok {
-                Box::from_raw(tag_ptr);
+                CallTag::from_raw(tag_ptr);
                 // it should not failed.
                 panic!("try to receive message fail: {:?}", code);
             }
@@ -119,7 +121,7 @@ impl RequestContext {
         self.ctx
     }
 
-    fn call(&mut self, cq: CompletionQueue) -> Call {
+    fn call(&self, cq: CompletionQueue) -> Call {
         unsafe {
             let call = grpc_sys::grpcwrap_request_call_context_ref_call(self.ctx);
             assert!(!call.is_null());
@@ -144,7 +146,7 @@ impl RequestContext {
     fn deadline(&self) -> Deadline {
         let t = unsafe { grpc_sys::grpcwrap_request_call_context_deadline(self.ctx) };
 
-        Deadline::new(t)
+        Deadline::from_spec(t)
     }
 
     fn metadata(&self) -> &Metadata {
@@ -159,13 +161,15 @@ impl RequestContext {
         unsafe {
             // RequestContext always holds a reference of the call.
             let call = grpc_sys::grpcwrap_request_call_context_get_call(self.ctx);
-            let p = grpc_sys::grpc_call_get_peer(call);
-            let peer = CStr::from_ptr(p)
-                .to_str()
-                .expect("valid UTF-8 data")
-                .to_owned();
-            grpc_sys::gpr_free(p as _);
-            peer
+            call_peer(call)
+        }
+    }
+
+    #[cfg(feature = "tls-server")]
+    fn auth_context(&self) -> Option<AuthContext> {
+        unsafe {
+            let call = grpc_sys::grpcwrap_request_call_context_get_call(self.ctx);
+            AuthContext::from_call(call)
         }
     }
 }
@@ -204,10 +208,14 @@ impl UnaryRequestContext {
         self.request_call.take()
     }
 
-    pub fn handle(mut self, rc: &RequestCallContext, cq: &CompletionQueue, data: Option<&[u8]>) {
-        let handler = unsafe { rc.get_handler(self.request.method()).unwrap() };
+    pub fn handle(self, rc: &RequestCallContext, cq: &CompletionQueue, data: Option<&[u8]>) {
+        let handler = match unsafe { rc.get_handler(self.request.host(), self.request.method()) } {
+            Some(handler) => handler,
+            // The method may be removed from a running server before the payload arrives.
+            None => return execute_unimplemented(self.request, cq.clone()),
+        };
         if let Some(data) = data {
-            return execute(self.request, cq, data, handler);
+            return execute(self.request, cq, data, handler, rc);
         }
 
         let status = RpcStatus::new(RpcStatusCode::Internal, Some("No payload".to_owned()));
@@ -219,33 +227,76 @@ pub struct RequestStream<T> {
     call: Arc<SpinLock<ShareCall>>,
     base: StreamingBase,
     de: DeserializeFn<T>,
+    hook: Option<Hook<T>>,
 }
 
 impl<T> RequestStream<T> {
-    fn new(call: Arc<SpinLock<ShareCall>>, de: DeserializeFn<T>) -> RequestStream<T> {
+    fn new(
+        call: Arc<SpinLock<ShareCall>>,
+        de: DeserializeFn<T>,
+        hook: Option<Hook<T>>,
+    ) -> RequestStream<T> {
         RequestStream {
             call,
             base: StreamingBase::new(None),
             de,
+            hook,
         }
     }
-}
-
-impl<T> Stream for RequestStream<T> {
-    type Item = T;
-    type Error = Error;
 
-    fn poll(&mut self) -> Poll<Option<T>, Error> {
+    fn poll_bytes(&mut self) -> Poll<Option<Vec<u8>>, Error> {
         {
             let mut call = self.call.lock();
             call.check_alive()?;
         }
         let data = try_ready!(self.base.poll(&mut self.call, false));
+        if let Some(ref data) = data {
+            self.call.lock().on_received(data.len())?;
+        }
+        Ok(Async::Ready(data))
+    }
+
+    /// Like `poll`, but decode the next message into `msg` with `de`.
+    ///
+    /// `Ready(false)` means the stream is finished and `msg` is untouched.
+    /// Reusing one message for the whole stream saves allocating a new one
+    /// for every message, e.g. with `pb_de_into`:
+    ///
+    /// ```ignore
+    /// let mut point = Point::new();
+    /// future::poll_fn(move || {
+    ///     while try_ready!(points.poll_into(pb_de_into, &mut point)) {
+    ///         summary.add(&point);
+    ///     }
+    ///     Ok(Async::Ready(summary.take()))
+    /// })
+    /// ```
+    pub fn poll_into(&mut self, de: DeserializeIntoFn<T>, msg: &mut T) -> Poll<bool, Error> {
+        match try_ready!(self.poll_bytes()) {
+            None => Ok(Async::Ready(false)),
+            Some(data) => {
+                de(&data, msg)?;
+                if let Some(ref h) = self.hook {
+                    h.on_receive(msg);
+                }
+                Ok(Async::Ready(true))
+            }
+        }
+    }
+}
+
+impl<T> Stream for RequestStream<T> {
+    type Item = T;
+    type Error = Error;
 
-        match data {
+    fn poll(&mut self) -> Poll<Option<T>, Error> {
+        match try_ready!(self.poll_bytes()) {
             None => Ok(Async::Ready(None)),
             Some(data) => {
-                let msg = (self.de)(&data)?;
+                let mut msg = (self.de)(&data)?;
+                if let Some(ref h) = self.hook {
+                    h.on_receive(&mut msg);
+                }
                 Ok(Async::Ready(Some(msg)))
             }
         }
@@ -286,36 +337,82 @@ macro_rules! impl_unary_sink {
             call: $holder,
             write_flags: u32,
             ser: SerializeFn<T>,
+            checksum: Option<Arc<Checksum>>,
+            hook: Option<Hook<T>>,
         }
 
         impl<T> $t<T> {
-            fn new(call: $holder, ser: SerializeFn<T>) -> $t<T> {
+            fn new(
+                call: $holder,
+                ser: SerializeFn<T>,
+                checksum: Option<Arc<Checksum>>,
+                hook: Option<Hook<T>>,
+            ) -> $t<T> {
                 $t {
                     call: call,
                     write_flags: 0,
                     ser: ser,
+                    checksum: checksum,
+                    hook: hook,
                 }
             }
 
+            /// Send `headers` as the initial metadata of the call right away,
+            /// instead of the empty one sent along with the response.
+            ///
+            /// It must be called before the call is finished.
+            pub fn send_headers(&mut self, headers: Metadata) -> result::Result<(), Error> {
+                self.call.call(|c| c.send_initial_metadata(headers))
+            }
+
             pub fn success(self, t: T) -> $rt {
-                self.complete(RpcStatus::ok(), Some(t))
+                self.finish(RpcStatus::ok(), Some(t), None)
+            }
+
+            /// Same as `success`, but also sends `trailers` along with the status.
+            pub fn success_with_trailers(self, t: T, trailers: Metadata) -> $rt {
+                self.finish(RpcStatus::ok(), Some(t), Some(trailers))
             }
 
             pub fn fail(self, status: RpcStatus) -> $rt {
-                self.complete(status, None)
+                self.finish(status, None, None)
+            }
+
+            /// Same as `fail`, but also sends `trailers` along with the status.
+            pub fn fail_with_trailers(self, status: RpcStatus, trailers: Metadata) -> $rt {
+                self.finish(status, None, Some(trailers))
             }
 
-            fn complete(mut self, status: RpcStatus, t: Option<T>) -> $rt {
+            /// Reply with `res`, errors are converted by [`ToGrpcStatus`].
+            pub fn complete<E: ToGrpcStatus>(self, res: result::Result<T, E>) -> $rt {
+                match res {
+                    Ok(t) => self.success(t),
+                    Err(e) => self.fail(e.to_grpc_status()),
+                }
+            }
+
+            fn finish(
+                mut self,
+                status: RpcStatus,
+                mut t: Option<T>,
+                trailers: Option<Metadata>,
+            ) -> $rt {
+                if let (&Some(ref h), &mut Some(ref mut t)) = (&self.hook, &mut t) {
+                    h.on_send(t);
+                }
                 let data = t.as_ref().map(|t| {
                     let mut buf = vec![];
                     (self.ser)(t, &mut buf);
                     buf
                 });
 
+                let mut trailers = match (&self.checksum, &data) {
+                    (&Some(ref c), &Some(ref d)) => Some(checksum::append(trailers, c.as_ref(), d)),
+                    _ => trailers,
+                };
                 let write_flags = self.write_flags;
                 let res = self.call.call(|c| {
-                    c.call
-                        .start_send_status_from_server(&status, true, &data, write_flags)
+                    c.send_status_from_server(&status, &mut trailers, true, &data, write_flags)
                 });
 
                 let (cq_f, err) = match res {
@@ -348,19 +445,23 @@ macro_rules! impl_stream_sink {
             base: SinkBase,
             flush_f: Option<BatchFuture>,
             status: RpcStatus,
+            trailers: Option<Metadata>,
             flushed: bool,
             ser: SerializeFn<T>,
+            hook: Option<Hook<T>>,
         }
 
         impl<T> $t<T> {
-            fn new(call: $holder, ser: SerializeFn<T>) -> $t<T> {
+            fn new(call: $holder, ser: SerializeFn<T>, hook: Option<Hook<T>>) -> $t<T> {
                 $t {
                     call: call,
                     base: SinkBase::new(true),
                     flush_f: None,
                     status: RpcStatus::ok(),
+                    trailers: None,
                     flushed: false,
                     ser: ser,
+                    hook: hook,
                 }
             }
 
@@ -369,12 +470,27 @@ macro_rules! impl_stream_sink {
                 self.status = status;
             }
 
+            /// Send `headers` as the initial metadata of the call right away,
+            /// instead of the empty one sent along with the first message.
+            ///
+            /// It must be called before anything is sent.
+            pub fn send_headers(&mut self, headers: Metadata) -> result::Result<(), Error> {
+                assert!(self.flush_f.is_none());
+                self.call.call(|c| c.send_initial_metadata(headers))
+            }
+
+            /// Set the trailing metadata that will be sent along with the status.
+            pub fn set_trailers(&mut self, trailers: Metadata) {
+                assert!(self.flush_f.is_none());
+                self.trailers = Some(trailers);
+            }
+
             pub fn fail(mut self, status: RpcStatus) -> $ft {
                 assert!(self.flush_f.is_none());
                 let send_metadata = self.base.send_metadata;
+                let trailers = &mut self.trailers;
                 let res = self.call.call(|c| {
-                    c.call
-                        .start_send_status_from_server(&status, send_metadata, &None, 0)
+                    c.send_status_from_server(&status, trailers, send_metadata, &None, 0)
                 });
 
                 let (fail_f, err) = match res {
@@ -394,12 +510,15 @@ macro_rules! impl_stream_sink {
             type SinkItem = (T, WriteFlags);
             type SinkError = Error;
 
-            fn start_send(&mut self, item: Self::SinkItem) -> StartSend<Self::SinkItem, Error> {
+            fn start_send(&mut self, mut item: Self::SinkItem) -> StartSend<Self::SinkItem, Error> {
                 if let Async::Ready(_) = self.call.call(|c| c.poll_finish())? {
                     return Err(Error::RemoteStopped);
                 }
+                if !self.call.call(|c| c.poll_send_quota()) {
+                    return Ok(AsyncSink::NotReady(item));
+                }
                 self.base
-                    .start_send(&mut self.call, &item.0, item.1, self.ser)
+                    .start_send(&mut self.call, &mut item.0, item.1, self.ser, &self.hook)
                     .map(|s| {
                         if s {
                             AsyncSink::Ready
@@ -419,9 +538,9 @@ macro_rules! impl_stream_sink {
 
                     let send_metadata = self.base.send_metadata;
                     let status = &self.status;
+                    let trailers = &mut self.trailers;
                     let flush_f = self.call.call(|c| {
-                        c.call
-                            .start_send_status_from_server(status, send_metadata, &None, 0)
+                        c.send_status_from_server(status, trailers, send_metadata, &None, 0)
                     })?;
                     self.flush_f = Some(flush_f);
                 }
@@ -473,26 +592,425 @@ macro_rules! impl_stream_sink {
 impl_stream_sink!(ServerStreamingSink, ServerStreamingSinkFailure, ShareCall);
 impl_stream_sink!(DuplexSink, DuplexSinkFailure, Arc<SpinLock<ShareCall>>);
 
+/// Statistics of a server call, passed to the callbacks registered by
+/// [`RpcContext::on_complete`] once the status is sent.
+///
+/// [`RpcContext::on_complete`]: struct.RpcContext.html#method.on_complete
+#[derive(Clone, Debug)]
+pub struct CallSummary {
+    status: RpcStatusCode,
+    request_messages: usize,
+    request_bytes: usize,
+    response_messages: usize,
+    response_bytes: usize,
+    queue_time: Duration,
+    processing_time: Duration,
+}
+
+impl CallSummary {
+    /// The status code sent to the client.
+    pub fn status(&self) -> RpcStatusCode {
+        self.status
+    }
+
+    /// Count of the messages received from the client.
+    pub fn request_messages(&self) -> usize {
+        self.request_messages
+    }
+
+    /// Total size of the messages received from the client.
+    pub fn request_bytes(&self) -> usize {
+        self.request_bytes
+    }
+
+    /// Count of the messages sent to the client.
+    pub fn response_messages(&self) -> usize {
+        self.response_messages
+    }
+
+    /// Total size of the messages sent to the client.
+    pub fn response_bytes(&self) -> usize {
+        self.response_bytes
+    }
+
+    /// Time between the call being accepted and the handler being invoked.
+    ///
+    /// For unary and server streaming calls it includes the time spent on
+    /// receiving the request message.
+    pub fn queue_time(&self) -> Duration {
+        self.queue_time
+    }
+
+    /// Time between the handler being invoked and the status being sent.
+    pub fn processing_time(&self) -> Duration {
+        self.processing_time
+    }
+}
+
+/// A guard created by [`RpcContext::completion_guard`].
+///
+/// [`RpcContext::completion_guard`]: struct.RpcContext.html#method.completion_guard
+#[must_use = "the call is failed as soon as the guard is dropped"]
+pub struct CompletionGuard {
+    call: Call,
+    tracker: Arc<CallTracker>,
+}
+
+impl Drop for CompletionGuard {
+    fn drop(&mut self) {
+        self.tracker.on_leaked(&self.call, "completion guard");
+    }
+}
+
+type CompleteCallback = Box<FnOnce(&CallSummary) + Send>;
+
+struct TrackerState {
+    callbacks: Vec<CompleteCallback>,
+    summary: Option<CallSummary>,
+    in_flight: Option<InFlightGuard>,
+}
+
+/// Counts a call in the in flight calls of its server until it's dropped.
+struct InFlightGuard {
+    counter: Arc<AtomicUsize>,
+}
+
+impl InFlightGuard {
+    fn new(counter: Arc<AtomicUsize>) -> InFlightGuard {
+        counter.fetch_add(1, Ordering::SeqCst);
+        InFlightGuard { counter }
+    }
+}
+
+impl Drop for InFlightGuard {
+    fn drop(&mut self) {
+        self.counter.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/// Counters of a server call, shared by the context, the request stream
+/// and the sink.
+pub(crate) struct CallTracker {
+    method: String,
+    arrived: Instant,
+    started: Instant,
+    request_messages: AtomicUsize,
+    request_bytes: AtomicUsize,
+    response_messages: AtomicUsize,
+    response_bytes: AtomicUsize,
+    // Nanoseconds since `started` plus one, zero if unset.
+    send_pending_since: AtomicU64,
+    send_pending_bytes: AtomicUsize,
+    last_received: AtomicU64,
+    state: Mutex<TrackerState>,
+}
+
+impl CallTracker {
+    pub fn new(arrived: Instant, method: String) -> CallTracker {
+        CallTracker {
+            method,
+            arrived,
+            started: Instant::now(),
+            request_messages: AtomicUsize::new(0),
+            request_bytes: AtomicUsize::new(0),
+            response_messages: AtomicUsize::new(0),
+            response_bytes: AtomicUsize::new(0),
+            send_pending_since: AtomicU64::new(0),
+            send_pending_bytes: AtomicUsize::new(0),
+            last_received: AtomicU64::new(0),
+            state: Mutex::new(TrackerState {
+                callbacks: vec![],
+                summary: None,
+                in_flight: None,
+            }),
+        }
+    }
+
+    /// Count the call in `counter` until the status is sent, or until every
+    /// reference of the call is dropped, whichever comes first.
+    fn count_in_flight(&self, counter: Arc<AtomicUsize>) {
+        let mut state = self.state.lock().unwrap();
+        if state.summary.is_none() {
+            state.in_flight = Some(InFlightGuard::new(counter));
+        }
+    }
+
+    pub fn on_received(&self, len: usize) {
+        self.request_messages.fetch_add(1, Ordering::Relaxed);
+        self.request_bytes.fetch_add(len, Ordering::Relaxed);
+        self.last_received.store(self.mark(), Ordering::Relaxed);
+    }
+
+    /// A message of `len` bytes is handed to gRPC core.
+    pub fn on_send_started(&self, len: usize) {
+        self.send_pending_bytes.store(len, Ordering::Relaxed);
+        self.send_pending_since
+            .store(self.mark(), Ordering::Relaxed);
+    }
+
+    /// The message handed to gRPC core is written.
+    pub fn on_send_done(&self) {
+        self.send_pending_since.store(0, Ordering::Relaxed);
+        self.send_pending_bytes.store(0, Ordering::Relaxed);
+    }
+
+    fn mark(&self) -> u64 {
+        let d = self.started.elapsed();
+        d.as_secs() * 1_000_000_000 + u64::from(d.subsec_nanos()) + 1
+    }
+
+    fn since(&self, mark: u64) -> Option<Duration> {
+        if mark == 0 {
+            return None;
+        }
+        let at = self.started + Duration::from_nanos(mark - 1);
+        Some(Instant::now().duration_since(at))
+    }
+
+    /// How long the pending write has waited and its size.
+    pub fn send_pending(&self) -> Option<(Duration, usize)> {
+        let since = self.since(self.send_pending_since.load(Ordering::Relaxed))?;
+        Some((since, self.send_pending_bytes.load(Ordering::Relaxed)))
+    }
+
+    /// Time since the last request message is taken by the handler.
+    pub fn since_received(&self) -> Option<Duration> {
+        self.since(self.last_received.load(Ordering::Relaxed))
+    }
+
+    pub fn on_sent(&self, len: usize) {
+        self.response_messages.fetch_add(1, Ordering::Relaxed);
+        self.response_bytes.fetch_add(len, Ordering::Relaxed);
+    }
+
+    pub fn on_complete(&self, status: RpcStatusCode) {
+        let summary = CallSummary {
+            status,
+            request_messages: self.request_messages.load(Ordering::Relaxed),
+            request_bytes: self.request_bytes.load(Ordering::Relaxed),
+            response_messages: self.response_messages.load(Ordering::Relaxed),
+            response_bytes: self.response_bytes.load(Ordering::Relaxed),
+            queue_time: self.started.duration_since(self.arrived),
+            processing_time: self.started.elapsed(),
+        };
+        let (callbacks, _in_flight) = {
+            let mut state = self.state.lock().unwrap();
+            if state.summary.is_some() {
+                return;
+            }
+            state.summary = Some(summary.clone());
+            (
+                mem::replace(&mut state.callbacks, vec![]),
+                state.in_flight.take(),
+            )
+        };
+        for cb in callbacks {
+            cb(&summary);
+        }
+    }
+
+    /// Time since the call was accepted.
+    pub fn elapsed(&self) -> Duration {
+        self.arrived.elapsed()
+    }
+
+    /// Count of the messages received and sent so far.
+    pub fn progress(&self) -> (usize, usize) {
+        (
+            self.request_messages.load(Ordering::Relaxed),
+            self.response_messages.load(Ordering::Relaxed),
+        )
+    }
+
+    /// Finish `call` with `status`, as if the status is sent.
+    pub fn fail(&self, call: &Call, status: RpcStatus) {
+        call.cancel_with_status(&status);
+        self.on_complete(status.status);
+    }
+
+    /// Fail the call if the status is never going to be sent.
+    pub fn on_leaked(&self, call: &Call, what: &str) {
+        if self.is_complete() {
+            return;
+        }
+        error!(
+            "{} of {} is dropped before the status is sent",
+            what, self.method
+        );
+        let details = format!("{} is dropped before the status is sent", what);
+        self.fail(call, RpcStatus::new(RpcStatusCode::Internal, Some(details)));
+    }
+
+    /// Check whether the status has been sent.
+    pub fn is_complete(&self) -> bool {
+        self.state.lock().unwrap().summary.is_some()
+    }
+
+    fn register(&self, cb: CompleteCallback) {
+        let summary = {
+            let mut state = self.state.lock().unwrap();
+            match state.summary {
+                Some(ref s) => s.clone(),
+                None => return state.callbacks.push(cb),
+            }
+        };
+        cb(&summary)
+    }
+}
+
 /// A context for rpc handling.
 pub struct RpcContext<'a> {
     ctx: RequestContext,
     executor: Executor<'a>,
     deadline: Deadline,
+    checksum: Option<Arc<Checksum>>,
+    tracker: Arc<CallTracker>,
+    request_id: Option<String>,
+    message_hook: Option<Arc<MessageHook>>,
+    method_config: Option<Arc<MethodConfig>>,
+    draining: Option<Arc<AtomicBool>>,
+    bandwidth: Option<Arc<Buckets>>,
+    idle: Option<Arc<IdleClock>>,
+    metric_recorder: Option<Arc<CallMetricRecorder>>,
+    panic_guard: Option<Arc<PanicGuard>>,
+    #[cfg(feature = "executor-bridge")]
+    external_executor: Option<ExternalExecutor>,
 }
 
 impl<'a> RpcContext<'a> {
-    fn new(ctx: RequestContext, cq: &CompletionQueue) -> RpcContext {
+    fn new(
+        ctx: RequestContext,
+        cq: &CompletionQueue,
+        checksum: Option<Arc<Checksum>>,
+    ) -> RpcContext {
         RpcContext {
             deadline: ctx.deadline(),
+            tracker: Arc::new(CallTracker::new(
+                ctx.arrived,
+                String::from_utf8_lossy(ctx.method()).into_owned(),
+            )),
             ctx,
             executor: Executor::new(cq),
+            checksum,
+            request_id: None,
+            message_hook: None,
+            method_config: None,
+            draining: None,
+            bandwidth: None,
+            idle: None,
+            metric_recorder: None,
+            panic_guard: None,
+            #[cfg(feature = "executor-bridge")]
+            external_executor: None,
+        }
+    }
+
+    fn hook<T: 'static>(&self) -> Option<Hook<T>> {
+        let method = String::from_utf8_lossy(self.method());
+        self.message_hook
+            .as_ref()
+            .map(|h| Hook::new(h.clone(), &method))
+    }
+
+    pub(crate) fn tracker(&self) -> &Arc<CallTracker> {
+        &self.tracker
+    }
+
+    fn share_call(&self, call: Call, close_f: CqFuture<BatchMessage>) -> ShareCall {
+        let mut call = ShareCall::new(call, close_f);
+        call.tracker = Some(self.tracker.clone());
+        call.throttle = self.bandwidth.clone().map(Throttle::new);
+        call.idle = self.idle.clone();
+        call.metric_recorder = self.metric_recorder.clone();
+        if let Some(ref config) = self.method_config {
+            if let Some(meta) = config.initial_metadata() {
+                // Failures show up in the following operations.
+                let _ = call.send_initial_metadata(meta);
+            }
+            call.method_config = Some(config.clone());
+        }
+        call
+    }
+
+    /// Reject the payload of unary and server streaming calls that is
+    /// larger than the limit.
+    fn check_payload(&self, payload: &[u8]) -> Option<RpcStatus> {
+        self.method_config
+            .as_ref()
+            .and_then(|c| c.check_receive(payload.len()))
+    }
+
+    /// Finish the call with `DeadlineExceeded` if it runs longer than `timeout`.
+    fn limit_time(&mut self, timeout: Duration) {
+        let deadline = Deadline::after(timeout);
+        if deadline >= self.deadline {
+            // Client's deadline is enforced by gRPC core.
+            return;
+        }
+        self.deadline = deadline;
+        let timer = match Timer::new(self.executor.cq(), &self.deadline) {
+            Ok(t) => t,
+            Err(_) => return,
+        };
+        // Release the call as soon as it finishes.
+        let handle = timer.handle();
+        self.tracker.register(Box::new(move |_| handle.cancel()));
+        let (call, tracker) = (self.call(), self.tracker.clone());
+        self.executor.spawn(timer.then(move |res| {
+            if let Ok(true) = res {
+                if !tracker.is_complete() {
+                    let status = RpcStatus::new(
+                        RpcStatusCode::DeadlineExceeded,
+                        Some("Handler timeout".to_owned()),
+                    );
+                    tracker.fail(&call, status);
+                }
+            }
+            Ok(())
+        }));
+    }
+
+    /// Fail the call with `Unavailable` if its stream is idle for longer
+    /// than `timeout`.
+    fn watch_idle(&mut self, timeout: Duration) {
+        let clock = Arc::new(IdleClock::new(timeout));
+        let c = clock.clone();
+        self.tracker.register(Box::new(move |_| c.close()));
+        let (call, tracker) = (self.call(), self.tracker.clone());
+        let watch = idle::watch(clock.clone(), self.executor.cq(), move |status| {
+            if !tracker.is_complete() {
+                tracker.fail(&call, status);
+            }
+        });
+        self.executor.spawn(watch);
+        self.idle = Some(clock);
+    }
+
+    /// Check the payload against the checksum sent by client.
+    fn verify_checksum(&self, payload: &[u8]) -> bool {
+        match self.checksum {
+            Some(ref c) => checksum::verify(self.request_headers(), c.as_ref(), payload),
+            None => true,
         }
     }
 
-    pub(crate) fn call(&mut self) -> Call {
+    pub(crate) fn call(&self) -> Call {
         self.ctx.call(self.executor.cq().clone())
     }
 
+    /// Create a guard that fails the call with `Internal` if it's dropped
+    /// before the status is sent.
+    ///
+    /// Keep it along with the sink, for example by moving both into the
+    /// future that completes the sink, so a forgotten sink doesn't leave the
+    /// call hanging until the deadline.
+    pub fn completion_guard(&self) -> CompletionGuard {
+        CompletionGuard {
+            call: self.call(),
+            tracker: self.tracker.clone(),
+        }
+    }
+
     pub fn method(&self) -> &[u8] {
         self.ctx.method()
     }
@@ -510,19 +1028,119 @@ impl<'a> RpcContext<'a> {
         self.ctx.metadata()
     }
 
-    pub fn peer(&self) -> String {
-        self.ctx.peer()
+    /// Get the address of the client.
+    pub fn peer(&self) -> Peer {
+        Peer::parse(&self.ctx.peer())
+    }
+
+    /// Get the baggage sent by client.
+    ///
+    /// It can be passed on to downstream calls by [`CallOption::context`].
+    ///
+    /// [`CallOption::context`]: ../struct.CallOption.html#method.context
+    pub fn context(&self) -> Context {
+        Context::from_headers(self.request_headers())
+    }
+
+    /// Get the ID of the request.
+    ///
+    /// `None` is returned if [`ServerBuilder::request_id`] is not enabled.
+    ///
+    /// [`ServerBuilder::request_id`]: ../struct.ServerBuilder.html#method.request_id
+    pub fn request_id(&self) -> Option<&str> {
+        self.request_id.as_ref().map(|s| s.as_str())
+    }
+
+    /// Get the recorder of the load report sent to the client along with the
+    /// status.
+    ///
+    /// `None` is returned if [`ServerBuilder::call_metric_recording`] is not
+    /// enabled.
+    ///
+    /// [`ServerBuilder::call_metric_recording`]: ../struct.ServerBuilder.html#method.call_metric_recording
+    pub fn call_metric_recorder(&self) -> Option<&CallMetricRecorder> {
+        self.metric_recorder.as_ref().map(|r| &**r)
+    }
+
+    /// Get the auth properties of the peer.
+    ///
+    /// `None` is returned if the call is not secure.
+    #[cfg(feature = "tls-server")]
+    pub fn auth_context(&self) -> Option<AuthContext> {
+        self.ctx.auth_context()
+    }
+
+    /// Check whether the server is draining, see [`Server::start_draining`].
+    ///
+    /// [`Server::start_draining`]: ../struct.Server.html#method.start_draining
+    pub fn is_server_draining(&self) -> bool {
+        self.draining
+            .as_ref()
+            .map_or(false, |d| d.load(Ordering::SeqCst))
+    }
+
+    /// Register a callback that is invoked with the statistics of the call
+    /// once its status is sent.
+    ///
+    /// The callback is invoked immediately if the status has already been
+    /// sent, and never if the call is dropped without sending a status.
+    pub fn on_complete<F>(&self, f: F)
+    where
+        F: FnOnce(&CallSummary) + Send + 'static,
+    {
+        self.tracker.register(Box::new(f))
     }
 
     /// Spawn the future into current gRPC poll thread.
     ///
     /// This can reduce a lot of context switching, but please make
     /// sure there is no heavy work in the future.
+    ///
+    /// If the request has an ID, it's the current request ID whenever `f` is
+    /// polled.
+    ///
+    /// If the server has an executor set by `ServerBuilder::executor`, `f` is
+    /// spawned onto it instead.
+    ///
+    /// Unless the panic policy is strict, a panic raised by `f` is caught like
+    /// the ones raised by the handler.
     pub fn spawn<F>(&self, f: F)
     where
         F: Future<Item = (), Error = ()> + Send + 'static,
     {
-        self.executor.spawn(f)
+        let f = CatchPanic {
+            f,
+            guard: self.panic_guard.clone(),
+        };
+        #[cfg(feature = "executor-bridge")]
+        {
+            if let Some(ref executor) = self.external_executor {
+                let f: BoxFuture = match self.request_id {
+                    Some(ref id) => Box::new(request_id::scope(id.clone(), f)),
+                    None => Box::new(f),
+                };
+                if let Err(f) = executor.spawn(f) {
+                    self.executor.spawn(f);
+                }
+                return;
+            }
+        }
+        match self.request_id {
+            Some(ref id) => self.executor.spawn(request_id::scope(id.clone(), f)),
+            None => self.executor.spawn(f),
+        }
+    }
+
+    /// Wrap `stream` so that a message built by `make` is yielded whenever
+    /// it has produced nothing for `idle`.
+    ///
+    /// See [`heartbeat`](heartbeat/index.html) for details.
+    pub fn heartbeat<S, F>(&self, stream: S, idle: Duration, make: F) -> Heartbeat<S, F>
+    where
+        S: Stream,
+        F: FnMut() -> S::Item,
+    {
+        Heartbeat::new(stream, idle, make, self.executor.cq().clone())
     }
 }
 
@@ -540,98 +1158,135 @@ macro_rules! accept_call {
 
 // Helper function to call a unary handler.
 pub fn execute_unary<P, Q, F>(
-    mut ctx: RpcContext,
+    ctx: RpcContext,
     ser: SerializeFn<Q>,
     de: DeserializeFn<P>,
     payload: &[u8],
     f: &F,
 ) where
+    P: 'static,
+    Q: 'static,
     F: Fn(RpcContext, P, UnarySink<Q>),
 {
     let mut call = ctx.call();
     let close_f = accept_call!(call);
-    let request = match de(payload) {
+    let mut request = match de(payload) {
         Ok(f) => f,
         Err(e) => {
             let status = RpcStatus::new(
                 RpcStatusCode::Internal,
                 Some(format!("Failed to deserialize response message: {:?}", e)),
             );
-            call.abort(&status);
+            ctx.tracker.fail(&call, status);
             return;
         }
     };
-    let sink = UnarySink::new(ShareCall::new(call, close_f), ser);
+    if !ctx.verify_checksum(payload) {
+        ctx.tracker.fail(&call, checksum::mismatch_status());
+        return;
+    }
+    if let Some(status) = ctx.check_payload(payload) {
+        ctx.tracker.fail(&call, status);
+        return;
+    }
+    ctx.tracker.on_received(payload.len());
+    if let Some(h) = ctx.hook() {
+        h.on_receive(&mut request);
+    }
+    let sink = UnarySink::new(
+        ctx.share_call(call, close_f),
+        ser,
+        ctx.checksum.clone(),
+        ctx.hook(),
+    );
     f(ctx, request, sink)
 }
 
 // Helper function to call client streaming handler.
 pub fn execute_client_streaming<P, Q, F>(
-    mut ctx: RpcContext,
+    ctx: RpcContext,
     ser: SerializeFn<Q>,
     de: DeserializeFn<P>,
     f: &F,
 ) where
+    P: 'static,
+    Q: 'static,
     F: Fn(RpcContext, RequestStream<P>, ClientStreamingSink<Q>),
 {
     let mut call = ctx.call();
     let close_f = accept_call!(call);
-    let call = Arc::new(SpinLock::new(ShareCall::new(call, close_f)));
+    let call = Arc::new(SpinLock::new(ctx.share_call(call, close_f)));
 
-    let req_s = RequestStream::new(call.clone(), de);
-    let sink = ClientStreamingSink::new(call, ser);
+    let req_s = RequestStream::new(call.clone(), de, ctx.hook());
+    let sink = ClientStreamingSink::new(call, ser, ctx.checksum.clone(), ctx.hook());
     f(ctx, req_s, sink)
 }
 
 // Helper function to call server streaming handler.
 pub fn execute_server_streaming<P, Q, F>(
-    mut ctx: RpcContext,
+    ctx: RpcContext,
     ser: SerializeFn<Q>,
     de: DeserializeFn<P>,
     payload: &[u8],
     f: &F,
 ) where
+    P: 'static,
+    Q: 'static,
     F: Fn(RpcContext, P, ServerStreamingSink<Q>),
 {
     let mut call = ctx.call();
     let close_f = accept_call!(call);
 
-    let request = match de(payload) {
+    let mut request = match de(payload) {
         Ok(t) => t,
         Err(e) => {
             let status = RpcStatus::new(
                 RpcStatusCode::Internal,
                 Some(format!("Failed to deserialize response message: {:?}", e)),
             );
-            call.abort(&status);
+            ctx.tracker.fail(&call, status);
             return;
         }
     };
+    if !ctx.verify_checksum(payload) {
+        ctx.tracker.fail(&call, checksum::mismatch_status());
+        return;
+    }
+    if let Some(status) = ctx.check_payload(payload) {
+        ctx.tracker.fail(&call, status);
+        return;
+    }
 
-    let sink = ServerStreamingSink::new(ShareCall::new(call, close_f), ser);
+    ctx.tracker.on_received(payload.len());
+    if let Some(h) = ctx.hook() {
+        h.on_receive(&mut request);
+    }
+    let sink = ServerStreamingSink::new(ctx.share_call(call, close_f), ser, ctx.hook());
     f(ctx, request, sink)
 }
 
 // Helper function to call duplex streaming handler.
 pub fn execute_duplex_streaming<P, Q, F>(
-    mut ctx: RpcContext,
+    ctx: RpcContext,
     ser: SerializeFn<Q>,
     de: DeserializeFn<P>,
     f: &F,
 ) where
+    P: 'static,
+    Q: 'static,
     F: Fn(RpcContext, RequestStream<P>, DuplexSink<Q>),
 {
     let mut call = ctx.call();
     let close_f = accept_call!(call);
-    let call = Arc::new(SpinLock::new(ShareCall::new(call, close_f)));
+    let call = Arc::new(SpinLock::new(ctx.share_call(call, close_f)));
 
-    let req_s = RequestStream::new(call.clone(), de);
-    let sink = DuplexSink::new(call, ser);
+    let req_s = RequestStream::new(call.clone(), de, ctx.hook());
+    let sink = DuplexSink::new(call, ser, ctx.hook());
     f(ctx, req_s, sink)
 }
 
 // A helper function used to handle all undefined rpc calls.
-pub fn execute_unimplemented(mut ctx: RequestContext, cq: CompletionQueue) {
+pub fn execute_unimplemented(ctx: RequestContext, cq: CompletionQueue) {
     let mut call = ctx.call(cq);
     accept_call!(call);
     call.abort(&RpcStatus::new(RpcStatusCode::Unimplemented, None))
@@ -640,7 +1295,136 @@ pub fn execute_unimplemented(mut ctx: RequestContext, cq: CompletionQueue) {
 // Helper function to call handler.
 //
 // Invoked after a request is ready to be handled.
-fn execute(ctx: RequestContext, cq: &CompletionQueue, payload: &[u8], f: &BoxHandler) {
-    let rpc_ctx = RpcContext::new(ctx, cq);
-    f.handle(rpc_ctx, payload)
+fn execute(
+    ctx: RequestContext,
+    cq: &CompletionQueue,
+    payload: &[u8],
+    f: &BoxHandler,
+    rc: &RequestCallContext,
+) {
+    let mut rpc_ctx = RpcContext::new(ctx, cq, rc.checksum().cloned());
+    rpc_ctx.message_hook = rc.message_hook().cloned();
+    rpc_ctx.draining = Some(rc.draining().clone());
+    rpc_ctx.tracker.count_in_flight(rc.in_flight().clone());
+    #[cfg(feature = "executor-bridge")]
+    {
+        rpc_ctx.external_executor = rc.executor().cloned();
+    }
+    if let Some(log) = rc.access_log() {
+        access_log::attach(log, &rpc_ctx);
+    }
+    if let Some(config) = rc.request_id() {
+        rpc_ctx.request_id = Some(config.extract(rpc_ctx.request_headers()));
+    }
+    if let Some(watchdog) = rc.watchdog() {
+        watchdog.attach(&rpc_ctx);
+    }
+    if let Some(flows) = rc.flows() {
+        flows.attach(&rpc_ctx);
+    }
+    if rc.call_metric_recording() {
+        rpc_ctx.metric_recorder = Some(Arc::default());
+    }
+    if let Some(limiter) = rc.bandwidth() {
+        rpc_ctx.bandwidth = Some(limiter.attach(&rpc_ctx.ctx.peer()));
+    }
+    if let Some(timeout) = rc.stream_idle_timeout() {
+        match f.method_type() {
+            MethodType::Unary => {}
+            _ => rpc_ctx.watch_idle(timeout),
+        }
+    }
+    if f.is_deprecated() {
+        if let Some(calls) = rc.deprecated_calls() {
+            calls.record(&rpc_ctx);
+        }
+    }
+    if let Some(config) = rc.method_config(rpc_ctx.method()) {
+        rpc_ctx.method_config = Some(config.clone());
+        if let Some(timeout) = config.get_timeout() {
+            rpc_ctx.limit_time(timeout);
+        }
+    }
+    if let Some(authorizer) = rc.authorizer() {
+        if let Err(status) = authorizer.check(&rpc_ctx) {
+            let call = rpc_ctx.call();
+            return rpc_ctx.tracker.fail(&call, status);
+        }
+    }
+    let policy = rc.panic_policy();
+    if policy.is_strict() {
+        return dispatch(rpc_ctx, payload, f);
+    }
+
+    let guard = Arc::new(PanicGuard {
+        policy: policy.clone(),
+        call: Mutex::new(rpc_ctx.call()),
+        tracker: rpc_ctx.tracker.clone(),
+        method: String::from_utf8_lossy(rpc_ctx.method()).into_owned(),
+        request_id: rpc_ctx.request_id.clone(),
+    });
+    rpc_ctx.panic_guard = Some(guard.clone());
+    let res = panic::catch_unwind(AssertUnwindSafe(|| dispatch(rpc_ctx, payload, f)));
+    if let Err(e) = res {
+        guard.on_panic(&*e);
+    }
+}
+
+/// Finishes a call with `Internal` when its handler, or a future spawned by
+/// the handler, panics.
+struct PanicGuard {
+    policy: PanicPolicy,
+    // Keep a reference of the call, so it can still be finished after the
+    // sinks are dropped by unwinding.
+    call: Mutex<Call>,
+    tracker: Arc<CallTracker>,
+    method: String,
+    request_id: Option<String>,
+}
+
+impl PanicGuard {
+    fn on_panic(&self, payload: &(Any + Send)) {
+        let id = self.request_id.as_ref().map(|s| s.as_str());
+        let details = self.policy.on_panic(&self.method, id, payload);
+        if !self.tracker.is_complete() {
+            self.tracker.fail(
+                &self.call.lock().unwrap(),
+                RpcStatus::new(RpcStatusCode::Internal, Some(details)),
+            );
+        }
+    }
+}
+
+/// A future spawned by [`RpcContext::spawn`], whose panics are caught by
+/// `guard`.
+struct CatchPanic<F> {
+    f: F,
+    guard: Option<Arc<PanicGuard>>,
+}
+
+impl<F: Future<Item = (), Error = ()>> Future for CatchPanic<F> {
+    type Item = ();
+    type Error = ();
+
+    fn poll(&mut self) -> Poll<(), ()> {
+        let guard = match self.guard {
+            Some(ref guard) => guard,
+            None => return self.f.poll(),
+        };
+        let f = &mut self.f;
+        match panic::catch_unwind(AssertUnwindSafe(|| f.poll())) {
+            Ok(res) => res,
+            Err(e) => {
+                guard.on_panic(&*e);
+                Err(())
+            }
+        }
+    }
+}
+
+fn dispatch(ctx: RpcContext, payload: &[u8], f: &BoxHandler) {
+    match ctx.request_id.clone() {
+        Some(id) => request_id::enter(&id, || f.handle(ctx, payload)),
+        None => f.handle(ctx, payload),
+    }
 }
@@ -15,21 +15,35 @@ use std::borrow::Cow;
 use std::collections::hash_map::Entry;
 use std::collections::HashMap;
 use std::ffi::{CStr, CString};
-use std::sync::Arc;
-use std::time::Duration;
+#[cfg(unix)]
+use std::net::{IpAddr, SocketAddr};
+#[cfg(unix)]
+use std::os::unix::io::RawFd;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
 use std::{cmp, i32, ptr};
+#[cfg(unix)]
+use std::{io, mem};
 
-use grpc_sys::{self, GprTimespec, GrpcChannel, GrpcChannelArgs};
-use libc::{self, c_char, c_int};
+use futures::{Async, Future, Poll, Stream};
+use grpc_sys::{self, GrpcCall, GrpcChannel, GrpcChannelArgs};
+use libc::{self, c_char, c_int, c_void};
 
-use call::{Call, Method};
+use async::{CallTag, CqFuture};
+use call::{call_peer, Call, Deadline, RpcStatusCode};
 use cq::CompletionQueue;
 use env::Environment;
-use error::Result;
+use error::{Error, Result};
+use message_hook::{Hook, MessageHook};
+use probe::{self, ConnectError};
+use request_id::RequestIdConfig;
 use CallOption;
 
 pub use grpc_sys::{
     GrpcCompressionAlgorithms as CompressionAlgorithms, GrpcCompressionLevel as CompressionLevel,
+    GrpcConnectivityState as ConnectivityState,
+    GrpcStreamCompressionAlgorithms as StreamCompressionAlgorithms,
 };
 
 // hack: add a '\0' to be compatible with c string without extra allocation.
@@ -37,6 +51,7 @@ const OPT_DEFAULT_AUTHORITY: &[u8] = b"grpc.default_authority\0";
 const OPT_MAX_CONCURRENT_STREAMS: &[u8] = b"grpc.max_concurrent_streams\0";
 const OPT_MAX_RECEIVE_MESSAGE_LENGTH: &[u8] = b"grpc.max_receive_message_length\0";
 const OPT_MAX_SEND_MESSAGE_LENGTH: &[u8] = b"grpc.max_send_message_length\0";
+const OPT_MAX_METADATA_SIZE: &[u8] = b"grpc.max_metadata_size\0";
 const OPT_MAX_RECONNECT_BACKOFF_MS: &[u8] = b"grpc.max_reconnect_backoff_ms\0";
 const OPT_INITIAL_RECONNECT_BACKOFF_MS: &[u8] = b"grpc.initial_reconnect_backoff_ms\0";
 const OPT_HTTP2_INITIAL_SEQUENCE_NUMBER: &[u8] = b"grpc.http2.initial_sequence_number\0";
@@ -56,11 +71,15 @@ const OPT_HTTP2_MAX_PINGS_WITHOUT_DATA: &[u8] = b"grpc.http2.max_pings_without_d
 const OPT_HTTP2_MAX_PING_STRIKES: &[u8] = b"grpc.http2.max_ping_strikes\0";
 const OPT_DEFALUT_COMPRESSION_ALGORITHM: &[u8] = b"grpc.default_compression_algorithm\0";
 const OPT_DEFAULT_COMPRESSION_LEVEL: &[u8] = b"grpc.default_compression_level\0";
+const OPT_DEFAULT_STREAM_COMPRESSION_ALGORITHM: &[u8] =
+    b"grpc.default_stream_compression_algorithm\0";
+const OPT_DEFAULT_STREAM_COMPRESSION_LEVEL: &[u8] = b"grpc.default_stream_compression_level\0";
 const OPT_KEEPALIVE_TIME_MS: &[u8] = b"grpc.keepalive_time_ms\0";
 const OPT_KEEPALIVE_TIMEOUT_MS: &[u8] = b"grpc.keepalive_timeout_ms\0";
 const OPT_KEEPALIVE_PERMIT_WITHOUT_CALLS: &[u8] = b"grpc.keepalive_permit_without_calls\0";
 const OPT_OPTIMIZATION_TARGET: &[u8] = b"grpc.optimization_target\0";
 const PRIMARY_USER_AGENT_STRING: &[u8] = b"grpc.primary_user_agent\0";
+const SECONDARY_USER_AGENT_STRING: &[u8] = b"grpc.secondary_user_agent\0";
 const OPT_GRPC_ARG_LB_POLICY_NAME: &[u8] = b"grpc.lb_policy_name\0";
 
 /// Ref: http://www.grpc.io/docs/guides/wire.html#user-agents
@@ -75,11 +94,31 @@ fn format_user_agent_string(agent: &str) -> CString {
     CString::new(val).unwrap()
 }
 
+/// Make `addr` resolved by the specified dns server.
+///
+/// Targets using a resolver other than dns are returned untouched.
+fn format_target(addr: &str, dns_server: Option<&str>) -> CString {
+    const DNS_SCHEME: &str = "dns:///";
+    const OTHER_SCHEMES: &[&str] = &["dns:", "ipv4:", "ipv6:", "unix:"];
+
+    let target = match dns_server {
+        Some(server) if addr.starts_with(DNS_SCHEME) => {
+            format!("dns://{}/{}", server, &addr[DNS_SCHEME.len()..])
+        }
+        Some(server) if !OTHER_SCHEMES.iter().any(|s| addr.starts_with(s)) => {
+            format!("dns://{}/{}", server, addr)
+        }
+        _ => addr.to_owned(),
+    };
+    CString::new(target).unwrap()
+}
+
 fn dur_to_ms(dur: Duration) -> i32 {
     let millis = dur.as_secs() * 1000 + dur.subsec_nanos() as u64 / 1_000_000;
     cmp::min(i32::MAX as u64, millis) as i32
 }
 
+#[derive(Clone)]
 enum Options {
     Integer(i32),
     String(CString),
@@ -96,16 +135,121 @@ pub enum OptTarget {
     Throughput,
 }
 
+/// Load balancing policies of gRPC core.
+///
+/// Weighted round robin is not one of them, see [`wrr`](wrr/index.html)
+/// for the one that balances over channels instead.
 #[derive(Clone, Copy)]
 pub enum LbPolicy {
     PickFirst,
     RoundRobin,
+    /// Look-aside load balancing, balancer addresses are discovered by
+    /// querying the `_grpclb._tcp` SRV records of the target.
+    ///
+    /// gRPC core 1.7 always issues the SRV queries when the target is resolved
+    /// by the c-ares resolver, see [`DnsResolver::Ares`]. The native resolver
+    /// can't discover balancers.
+    ///
+    /// [`DnsResolver::Ares`]: enum.DnsResolver.html#variant.Ares
+    Grpclb,
+}
+
+/// A typed channel argument, see the method of [`ChannelBuilder`] with the
+/// same name for the meaning of each argument.
+///
+/// Arguments that are not covered here can still be set by
+/// [`ChannelBuilder::raw_cfg_int`] and [`ChannelBuilder::raw_cfg_string`].
+///
+/// [`ChannelBuilder::raw_cfg_int`]: struct.ChannelBuilder.html#method.raw_cfg_int
+/// [`ChannelBuilder::raw_cfg_string`]: struct.ChannelBuilder.html#method.raw_cfg_string
+pub enum ChannelArg {
+    DefaultAuthority(String),
+    MaxConcurrentStream(i32),
+    MaxReceiveMessageLen(i32),
+    MaxSendMessageLen(i32),
+    MaxMetadataSize(i32),
+    MaxReconnectBackoff(Duration),
+    InitialReconnectBackoff(Duration),
+    HttpsInitialSeqNumber(i32),
+    StreamInitialWindowSize(i32),
+    PrimaryUserAgent(String),
+    SecondaryUserAgent(String),
+    ReusePort(bool),
+    TcpReadChunkSize(i32),
+    TcpMinReadChunkSize(i32),
+    TcpMaxReadChunkSize(i32),
+    Http2WriteBufferSize(i32),
+    Http2MaxFrameSize(i32),
+    Http2BdpProbe(bool),
+    Http2MinSentPingIntervalWithoutData(Duration),
+    Http2MinRecvPingIntervalWithoutData(Duration),
+    Http2MaxPingsWithoutData(i32),
+    Http2MaxPingStrikes(i32),
+    DefaultCompressionAlgorithm(CompressionAlgorithms),
+    DefaultCompressionLevel(CompressionLevel),
+    DefaultStreamCompressionAlgorithm(StreamCompressionAlgorithms),
+    DefaultStreamCompressionLevel(CompressionLevel),
+    KeepaliveTime(Duration),
+    KeepaliveTimeout(Duration),
+    KeepalivePermitWithoutCalls(bool),
+    OptimizeFor(OptTarget),
+    LoadBalancingPolicy(LbPolicy),
+}
+
+// Arguments that take string values, all the others take integer values.
+const STRING_ARGS: &[&[u8]] = &[
+    OPT_DEFAULT_AUTHORITY,
+    PRIMARY_USER_AGENT_STRING,
+    SECONDARY_USER_AGENT_STRING,
+    OPT_OPTIMIZATION_TARGET,
+    OPT_GRPC_ARG_LB_POLICY_NAME,
+];
+
+const INTEGER_ARGS: &[&[u8]] = &[
+    OPT_MAX_CONCURRENT_STREAMS,
+    OPT_MAX_RECEIVE_MESSAGE_LENGTH,
+    OPT_MAX_SEND_MESSAGE_LENGTH,
+    OPT_MAX_METADATA_SIZE,
+    OPT_MAX_RECONNECT_BACKOFF_MS,
+    OPT_INITIAL_RECONNECT_BACKOFF_MS,
+    OPT_HTTP2_INITIAL_SEQUENCE_NUMBER,
+    OPT_SO_REUSE_PORT,
+    OPT_STREAM_INITIAL_WINDOW_SIZE,
+    OPT_TCP_READ_CHUNK_SIZE,
+    OPT_TCP_MIN_READ_CHUNK_SIZE,
+    OPT_TCP_MAX_READ_CHUNK_SIZE,
+    OPT_HTTP2_WRITE_BUFFER_SIZE,
+    OPT_HTTP2_MAX_FRAME_SIZE,
+    OPT_HTTP2_BDP_PROBE,
+    OPT_HTTP2_MIN_SENT_PING_INTERVAL_WITHOUT_DATA_MS,
+    OPT_HTTP2_MIN_RECV_PING_INTERVAL_WITHOUT_DATA_MS,
+    OPT_HTTP2_MAX_PINGS_WITHOUT_DATA,
+    OPT_HTTP2_MAX_PING_STRIKES,
+    OPT_DEFALUT_COMPRESSION_ALGORITHM,
+    OPT_DEFAULT_COMPRESSION_LEVEL,
+    OPT_DEFAULT_STREAM_COMPRESSION_ALGORITHM,
+    OPT_DEFAULT_STREAM_COMPRESSION_LEVEL,
+    OPT_KEEPALIVE_TIME_MS,
+    OPT_KEEPALIVE_TIMEOUT_MS,
+    OPT_KEEPALIVE_PERMIT_WITHOUT_CALLS,
+];
+
+/// The value of a channel argument.
+#[derive(Clone, Debug, PartialEq)]
+pub enum ChannelArgValue {
+    Integer(i32),
+    String(String),
 }
 
 /// [`Channel`] factory in order to configure the properties.
 pub struct ChannelBuilder {
     env: Arc<Environment>,
     options: HashMap<Cow<'static, [u8]>, Options>,
+    dns_server: Option<String>,
+    request_id: Option<RequestIdConfig>,
+    message_hook: Option<Arc<MessageHook>>,
+    socket_mutators: Vec<SocketMutator>,
+    subchannel_stats: bool,
 }
 
 impl ChannelBuilder {
@@ -114,9 +258,22 @@ impl ChannelBuilder {
         ChannelBuilder {
             env,
             options: HashMap::new(),
+            dns_server: None,
+            request_id: None,
+            message_hook: None,
+            socket_mutators: vec![],
+            subchannel_stats: false,
         }
     }
 
+    /// Create a builder that starts with the configuration `args` is built from.
+    pub(crate) fn from_args(env: Arc<Environment>, args: &ChannelArgs) -> ChannelBuilder {
+        let mut builder = ChannelBuilder::new(env);
+        builder.options = args.options.clone();
+        builder.socket_mutators = args.socket_mutators.clone();
+        builder
+    }
+
     /// Set default authority to pass if none specified on call construction.
     pub fn default_authority<S: Into<Vec<u8>>>(mut self, authority: S) -> ChannelBuilder {
         let authority = CString::new(authority).unwrap();
@@ -154,6 +311,17 @@ impl ChannelBuilder {
         self
     }
 
+    /// Set maximum size of the metadata that the channel can receive, which is also
+    /// advertised to the peer as `SETTINGS_MAX_HEADER_LIST_SIZE`. Defaults to 8KiB.
+    ///
+    /// When used to build the args of a server, calls that carry larger request
+    /// headers are failed with `ResourceExhausted`.
+    pub fn max_metadata_size(mut self, size: i32) -> ChannelBuilder {
+        self.options
+            .insert(Cow::Borrowed(OPT_MAX_METADATA_SIZE), Options::Integer(size));
+        self
+    }
+
     /// Set maximum time between subsequent connection attempts.
     pub fn max_reconnect_backoff(mut self, backoff: Duration) -> ChannelBuilder {
         self.options.insert(
@@ -202,6 +370,17 @@ impl ChannelBuilder {
         self
     }
 
+    /// Set secondary user agent, which goes at the end of the user-agent metadata sent on
+    /// each request.
+    pub fn secondary_user_agent(mut self, agent: &str) -> ChannelBuilder {
+        let agent_string = CString::new(agent.trim()).unwrap();
+        self.options.insert(
+            Cow::Borrowed(SECONDARY_USER_AGENT_STRING),
+            Options::String(agent_string),
+        );
+        self
+    }
+
     /// Set whether to allow the use of `SO_REUSEPORT` if available. Defaults to `true`.
     pub fn reuse_port(mut self, reuse: bool) -> ChannelBuilder {
         let opt = if reuse { 1 } else { 0 };
@@ -334,6 +513,31 @@ impl ChannelBuilder {
         self
     }
 
+    /// Set default stream compression algorithm for the channel.
+    ///
+    /// Unlike per-message compression, the whole stream is compressed as one,
+    /// which works better for long streams of small similar messages. Servers
+    /// pick it up when the arguments are passed to `ServerBuilder::channel_args`.
+    pub fn default_stream_compression_algorithm(
+        mut self,
+        algo: StreamCompressionAlgorithms,
+    ) -> ChannelBuilder {
+        self.options.insert(
+            Cow::Borrowed(OPT_DEFAULT_STREAM_COMPRESSION_ALGORITHM),
+            Options::Integer(algo as i32),
+        );
+        self
+    }
+
+    /// Set default stream compression level for the channel.
+    pub fn default_stream_compression_level(mut self, level: CompressionLevel) -> ChannelBuilder {
+        self.options.insert(
+            Cow::Borrowed(OPT_DEFAULT_STREAM_COMPRESSION_LEVEL),
+            Options::Integer(level as i32),
+        );
+        self
+    }
+
     /// After a duration of this time the client/server pings its peer to see
     /// if the transport is still alive.
     pub fn keepalive_time(mut self, timeout: Duration) -> ChannelBuilder {
@@ -385,6 +589,7 @@ impl ChannelBuilder {
         let val = match lb_policy {
             LbPolicy::PickFirst => CString::new("pick_first"),
             LbPolicy::RoundRobin => CString::new("round_robin"),
+            LbPolicy::Grpclb => CString::new("grpclb"),
         };
         self.options.insert(
             Cow::Borrowed(OPT_GRPC_ARG_LB_POLICY_NAME),
@@ -393,33 +598,239 @@ impl ChannelBuilder {
         self
     }
 
+    /// Attach an ID to every call, see [`request_id`](request_id/index.html)
+    /// for details.
+    pub fn request_id(mut self, config: RequestIdConfig) -> ChannelBuilder {
+        self.request_id = Some(config);
+        self
+    }
+
+    /// Invoke `hook` with the messages of every call, see
+    /// [`message_hook`](message_hook/index.html) for details.
+    pub fn message_hook(mut self, hook: Arc<MessageHook>) -> ChannelBuilder {
+        self.message_hook = Some(hook);
+        self
+    }
+
+    /// Count the finished calls by the address they were sent to, see
+    /// [`Channel::subchannel_stats`].
+    ///
+    /// It's disabled by default, as it reads the peer of every call and
+    /// updates a map shared by all the calls of the channel.
+    ///
+    /// [`Channel::subchannel_stats`]: struct.Channel.html#method.subchannel_stats
+    pub fn subchannel_stats(mut self, enable: bool) -> ChannelBuilder {
+        self.subchannel_stats = enable;
+        self
+    }
+
+    /// Invoke `f` on every socket created by the channel before it connects.
+    ///
+    /// The connection attempt fails if `f` returns false. Mutators are invoked
+    /// in the order they are added, on the threads of gRPC core.
+    #[cfg(unix)]
+    pub fn socket_mutator<F>(mut self, f: F) -> ChannelBuilder
+    where
+        F: Fn(RawFd) -> bool + Send + Sync + 'static,
+    {
+        self.socket_mutators.push(Arc::new(f));
+        self
+    }
+
+    /// Bind outgoing connections to a local address, so that the traffic goes
+    /// through the network of the address on multi-homed hosts.
+    ///
+    /// Use port 0 to pick an ephemeral port. Connections to the targets that
+    /// can't be reached from the address fail.
+    #[cfg(unix)]
+    pub fn source_address(self, addr: SocketAddr) -> ChannelBuilder {
+        self.socket_mutator(move |fd| bind_source(fd, &addr))
+    }
+
+    /// Bind outgoing connections to a network interface, e.g. `eth1`.
+    ///
+    /// It requires `CAP_NET_RAW` on kernels older than 5.7.
+    #[cfg(target_os = "linux")]
+    pub fn bind_device(self, name: &str) -> ChannelBuilder {
+        let name = name.as_bytes().to_vec();
+        self.socket_mutator(move |fd| {
+            let res = unsafe {
+                libc::setsockopt(
+                    fd,
+                    libc::SOL_SOCKET,
+                    libc::SO_BINDTODEVICE,
+                    name.as_ptr() as *const libc::c_void,
+                    name.len() as libc::socklen_t,
+                )
+            };
+            if res != 0 {
+                error!(
+                    "failed to bind socket to device {}: {}",
+                    String::from_utf8_lossy(&name),
+                    io::Error::last_os_error()
+                );
+            }
+            res == 0
+        })
+    }
+
+    /// Close the connection if sent data is not acknowledged for `timeout`,
+    /// instead of the many minutes of retransmission by default.
+    ///
+    /// Unlike [`keepalive_time`](#method.keepalive_time), it detects a broken
+    /// network while requests are being sent, without waiting for pings.
+    #[cfg(target_os = "linux")]
+    pub fn tcp_user_timeout(self, timeout: Duration) -> ChannelBuilder {
+        let ms = dur_to_ms(timeout);
+        self.socket_mutator(move |fd| {
+            !is_tcp(fd) || set_sockopt(fd, libc::IPPROTO_TCP, libc::TCP_USER_TIMEOUT, ms)
+        })
+    }
+
+    /// Enable TCP keepalive: probe the peer after the connection is idle for
+    /// `idle`, every `interval`, and close it after `count` probes are
+    /// unanswered.
+    ///
+    /// It's handled by the kernel, and can be used together with HTTP/2 pings
+    /// set by [`keepalive_time`](#method.keepalive_time).
+    #[cfg(target_os = "linux")]
+    pub fn tcp_keepalive(self, idle: Duration, interval: Duration, count: u32) -> ChannelBuilder {
+        let idle = cmp::max(1, idle.as_secs()) as c_int;
+        let interval = cmp::max(1, interval.as_secs()) as c_int;
+        let count = cmp::min(count, i32::MAX as u32) as c_int;
+        self.socket_mutator(move |fd| {
+            !is_tcp(fd)
+                || (set_sockopt(fd, libc::SOL_SOCKET, libc::SO_KEEPALIVE, 1)
+                    && set_sockopt(fd, libc::IPPROTO_TCP, libc::TCP_KEEPIDLE, idle)
+                    && set_sockopt(fd, libc::IPPROTO_TCP, libc::TCP_KEEPINTVL, interval)
+                    && set_sockopt(fd, libc::IPPROTO_TCP, libc::TCP_KEEPCNT, count))
+        })
+    }
+
+    /// Set the dns server used to resolve the target, e.g. `10.96.0.10:53`.
+    ///
+    /// It only takes effect when the target uses the dns resolver, which is the
+    /// default when no scheme is specified. It's not supported by the native resolver.
+    pub fn dns_server<S: Into<String>>(mut self, server: S) -> ChannelBuilder {
+        self.dns_server = Some(server.into());
+        self
+    }
+
+    /// Set a typed channel argument.
+    pub fn arg(self, arg: ChannelArg) -> ChannelBuilder {
+        match arg {
+            ChannelArg::DefaultAuthority(authority) => self.default_authority(authority),
+            ChannelArg::MaxConcurrentStream(num) => self.max_concurrent_stream(num),
+            ChannelArg::MaxReceiveMessageLen(len) => self.max_receive_message_len(len),
+            ChannelArg::MaxSendMessageLen(len) => self.max_send_message_len(len),
+            ChannelArg::MaxMetadataSize(size) => self.max_metadata_size(size),
+            ChannelArg::MaxReconnectBackoff(backoff) => self.max_reconnect_backoff(backoff),
+            ChannelArg::InitialReconnectBackoff(backoff) => self.initial_reconnect_backoff(backoff),
+            ChannelArg::HttpsInitialSeqNumber(number) => self.https_initial_seq_number(number),
+            ChannelArg::StreamInitialWindowSize(size) => self.stream_initial_window_size(size),
+            ChannelArg::PrimaryUserAgent(agent) => self.primary_user_agent(&agent),
+            ChannelArg::SecondaryUserAgent(agent) => self.secondary_user_agent(&agent),
+            ChannelArg::ReusePort(reuse) => self.reuse_port(reuse),
+            ChannelArg::TcpReadChunkSize(bytes) => self.tcp_read_chunk_size(bytes),
+            ChannelArg::TcpMinReadChunkSize(bytes) => self.tcp_min_read_chunk_size(bytes),
+            ChannelArg::TcpMaxReadChunkSize(bytes) => self.tcp_max_read_chunk_size(bytes),
+            ChannelArg::Http2WriteBufferSize(size) => self.http2_write_buffer_size(size),
+            ChannelArg::Http2MaxFrameSize(size) => self.http2_max_frame_size(size),
+            ChannelArg::Http2BdpProbe(enable) => self.http2_bdp_probe(enable),
+            ChannelArg::Http2MinSentPingIntervalWithoutData(interval) => {
+                self.http2_min_sent_ping_interval_without_data(interval)
+            }
+            ChannelArg::Http2MinRecvPingIntervalWithoutData(interval) => {
+                self.http2_min_recv_ping_interval_without_data(interval)
+            }
+            ChannelArg::Http2MaxPingsWithoutData(num) => self.http2_max_pings_without_data(num),
+            ChannelArg::Http2MaxPingStrikes(num) => self.http2_max_ping_strikes(num),
+            ChannelArg::DefaultCompressionAlgorithm(algo) => {
+                self.default_compression_algorithm(algo)
+            }
+            ChannelArg::DefaultCompressionLevel(level) => self.default_compression_level(level),
+            ChannelArg::DefaultStreamCompressionAlgorithm(algo) => {
+                self.default_stream_compression_algorithm(algo)
+            }
+            ChannelArg::DefaultStreamCompressionLevel(level) => {
+                self.default_stream_compression_level(level)
+            }
+            ChannelArg::KeepaliveTime(timeout) => self.keepalive_time(timeout),
+            ChannelArg::KeepaliveTimeout(timeout) => self.keepalive_timeout(timeout),
+            ChannelArg::KeepalivePermitWithoutCalls(allow) => {
+                self.keepalive_permit_without_calls(allow)
+            }
+            ChannelArg::OptimizeFor(target) => self.optimize_for(target),
+            ChannelArg::LoadBalancingPolicy(policy) => self.load_balancing_policy(policy),
+        }
+    }
+
     /// Set a raw integer configuration.
     ///
-    /// This method is only for bench usage, users should use the encapsulated API instead.
-    #[doc(hidden)]
+    /// It's an escape hatch for the arguments of gRPC core that are not covered by
+    /// [`ChannelArg`], prefer the typed API whenever possible.
+    ///
+    /// # Panics
+    ///
+    /// If `key` is a known argument that takes a string value.
     pub fn raw_cfg_int(mut self, key: CString, val: i32) -> ChannelBuilder {
-        self.options
-            .insert(Cow::Owned(key.into_bytes_with_nul()), Options::Integer(val));
+        let key = key.into_bytes_with_nul();
+        assert!(
+            !STRING_ARGS.contains(&key.as_slice()),
+            "{:?} expects a string value",
+            CStr::from_bytes_with_nul(&key).unwrap()
+        );
+        self.options.insert(Cow::Owned(key), Options::Integer(val));
         self
     }
 
     /// Set a raw string configuration.
     ///
-    /// This method is only for bench usage, users should use the encapsulated API instead.
-    #[doc(hidden)]
+    /// It's an escape hatch for the arguments of gRPC core that are not covered by
+    /// [`ChannelArg`], prefer the typed API whenever possible.
+    ///
+    /// # Panics
+    ///
+    /// If `key` is a known argument that takes an integer value.
     pub fn raw_cfg_string(mut self, key: CString, val: CString) -> ChannelBuilder {
-        self.options
-            .insert(Cow::Owned(key.into_bytes_with_nul()), Options::String(val));
+        let key = key.into_bytes_with_nul();
+        assert!(
+            !INTEGER_ARGS.contains(&key.as_slice()),
+            "{:?} expects an integer value",
+            CStr::from_bytes_with_nul(&key).unwrap()
+        );
+        self.options.insert(Cow::Owned(key), Options::String(val));
         self
     }
 
+    // Snapshot of the arguments, sorted by key.
+    fn args_snapshot(&self) -> Vec<(String, ChannelArgValue)> {
+        let mut args: Vec<_> = self
+            .options
+            .iter()
+            .map(|(k, v)| {
+                let key = String::from_utf8_lossy(&k[..k.len() - 1]).into_owned();
+                let val = match *v {
+                    Options::Integer(val) => ChannelArgValue::Integer(val),
+                    Options::String(ref val) => {
+                        ChannelArgValue::String(val.to_string_lossy().into_owned())
+                    }
+                };
+                (key, val)
+            })
+            .collect();
+        args.sort_by(|a, b| a.0.cmp(&b.0));
+        args
+    }
+
     /// Build `ChannelArgs` from the current configuration.
     ///
     /// This method is only for bench usage, users should use the encapsulated API instead.
     #[doc(hidden)]
     #[cfg_attr(feature = "cargo-clippy", allow(identity_conversion))]
     pub fn build_args(&self) -> ChannelArgs {
-        let args = unsafe { grpc_sys::grpcwrap_channel_args_create(self.options.len()) };
+        let count = self.options.len() + !self.socket_mutators.is_empty() as usize;
+        let args = unsafe { grpc_sys::grpcwrap_channel_args_create(count) };
         for (i, (k, v)) in self.options.iter().enumerate() {
             let key = k.as_ptr() as *const c_char;
             match *v {
@@ -439,7 +850,22 @@ impl ChannelBuilder {
                 },
             }
         }
-        ChannelArgs { args }
+        if !self.socket_mutators.is_empty() {
+            let mutators = Box::new(self.socket_mutators.clone());
+            unsafe {
+                let m = grpc_sys::grpcwrap_socket_mutator_create(
+                    mutate_fd,
+                    drop_mutators,
+                    Box::into_raw(mutators) as *mut c_void,
+                );
+                grpc_sys::grpcwrap_channel_args_set_socket_mutator(args, self.options.len(), m);
+            }
+        }
+        ChannelArgs {
+            args,
+            options: self.options.clone(),
+            socket_mutators: self.socket_mutators.clone(),
+        }
     }
 
     fn prepare_connect_args(&mut self) -> ChannelArgs {
@@ -449,19 +875,98 @@ impl ChannelBuilder {
         self.build_args()
     }
 
+    /// Check the configuration against the target, so that mistakes are reported
+    /// instead of being ignored by gRPC core.
+    fn validate(&self, addr: &str) -> Result<()> {
+        if addr.is_empty() {
+            return Err(Error::InvalidConfig("target is empty".to_owned()));
+        }
+        if addr.contains('\0') {
+            return Err(Error::InvalidConfig(format!(
+                "target {:?} contains nul byte",
+                addr
+            )));
+        }
+        if let Some(ref server) = self.dns_server {
+            if format_target(addr, Some(server)).as_bytes() == addr.as_bytes() {
+                return Err(Error::InvalidConfig(format!(
+                    "dns server {} doesn't apply to target {}",
+                    server, addr
+                )));
+            }
+        }
+        if let Some(&Options::Integer(size)) = self.options.get(OPT_HTTP2_MAX_FRAME_SIZE) {
+            if size < 16_384 || size > 16_777_215 {
+                return Err(Error::InvalidConfig(format!(
+                    "http2 max frame size {} is out of range [16384, 16777215]",
+                    size
+                )));
+            }
+        }
+        Ok(())
+    }
+
+    /// Same as [`connect`], but returns an error if the configuration is invalid.
+    ///
+    /// [`connect`]: #method.connect
+    pub fn try_connect(self, addr: &str) -> Result<Channel> {
+        self.validate(addr)?;
+        Ok(self.connect(addr))
+    }
+
     /// Build an insecure [`Channel`] that connects to a specific address.
     pub fn connect(mut self, addr: &str) -> Channel {
+        let target = addr.to_owned();
         let args = self.prepare_connect_args();
-        let addr = CString::new(addr).unwrap();
+        let addr = format_target(addr, self.dns_server.as_ref().map(|s| s.as_str()));
         let addr_ptr = addr.as_ptr();
         let channel =
             unsafe { grpc_sys::grpc_insecure_channel_create(addr_ptr, args.args, ptr::null_mut()) };
 
-        Channel::new(self.env.pick_cq(), self.env, channel)
+        let args = self.args_snapshot();
+        Channel::new(
+            self.env.pick_cq(),
+            self.env,
+            channel,
+            args,
+            self.request_id,
+            self.message_hook,
+            self.subchannel_stats,
+            Some((target, false)),
+        )
+    }
+
+    /// Build an insecure [`Channel`] over a connected socket, e.g. one that
+    /// is set up through a SOCKS proxy or a custom handshake.
+    ///
+    /// `target` is used as the authority of calls. The channel takes the
+    /// ownership of `fd`, and it can't reconnect once the connection is closed.
+    ///
+    /// # Safety
+    ///
+    /// `fd` must be a connected stream socket that is not used elsewhere.
+    #[cfg(unix)]
+    pub unsafe fn connect_from_fd(mut self, target: &str, fd: RawFd) -> Channel {
+        let args = self.prepare_connect_args();
+        let target = CString::new(target).unwrap();
+        let channel =
+            grpc_sys::grpc_insecure_channel_create_from_fd(target.as_ptr(), fd, args.args);
+
+        let args = self.args_snapshot();
+        Channel::new(
+            self.env.pick_cq(),
+            self.env,
+            channel,
+            args,
+            self.request_id,
+            self.message_hook,
+            self.subchannel_stats,
+            None,
+        )
     }
 }
 
-#[cfg(feature = "secure")]
+#[cfg(feature = "tls-client")]
 mod secure_channel {
     use std::borrow::Cow;
     use std::ffi::CString;
@@ -470,8 +975,9 @@ mod secure_channel {
     use grpc_sys;
 
     use credentials::ChannelCredentials;
+    use error::Result;
 
-    use super::{Channel, ChannelBuilder, Options};
+    use super::{format_target, Channel, ChannelBuilder, Options};
 
     const OPT_SSL_TARGET_NAME_OVERRIDE: &[u8] = b"grpc.ssl_target_name_override\0";
 
@@ -490,10 +996,19 @@ mod secure_channel {
             self
         }
 
+        /// Same as [`secure_connect`], but returns an error if the configuration is invalid.
+        ///
+        /// [`secure_connect`]: #method.secure_connect
+        pub fn try_secure_connect(self, addr: &str, creds: ChannelCredentials) -> Result<Channel> {
+            self.validate(addr)?;
+            Ok(self.secure_connect(addr, creds))
+        }
+
         /// Build a secure [`Channel`] that connects to a specific address.
         pub fn secure_connect(mut self, addr: &str, mut creds: ChannelCredentials) -> Channel {
+            let target = addr.to_owned();
             let args = self.prepare_connect_args();
-            let addr = CString::new(addr).unwrap();
+            let addr = format_target(addr, self.dns_server.as_ref().map(|s| s.as_str()));
             let addr_ptr = addr.as_ptr();
             let channel = unsafe {
                 grpc_sys::grpc_secure_channel_create(
@@ -504,13 +1019,127 @@ mod secure_channel {
                 )
             };
 
-            Channel::new(self.env.pick_cq(), self.env, channel)
+            let args = self.args_snapshot();
+            Channel::new(
+                self.env.pick_cq(),
+                self.env,
+                channel,
+                args,
+                self.request_id,
+                self.message_hook,
+                self.subchannel_stats,
+                Some((target, true)),
+            )
         }
     }
 }
 
+type SocketMutator = Arc<Fn(c_int) -> bool + Send + Sync>;
+
+extern "C" fn mutate_fd(fd: c_int, mutators: *mut c_void) -> c_int {
+    let mutators = unsafe { &*(mutators as *const Vec<SocketMutator>) };
+    mutators.iter().all(|m| m(fd)) as c_int
+}
+
+extern "C" fn drop_mutators(mutators: *mut c_void) {
+    unsafe { drop(Box::from_raw(mutators as *mut Vec<SocketMutator>)) }
+}
+
+#[cfg(target_os = "linux")]
+fn is_tcp(fd: RawFd) -> bool {
+    unsafe {
+        let mut storage: libc::sockaddr_storage = mem::zeroed();
+        let mut len = mem::size_of_val(&storage) as libc::socklen_t;
+        let res = libc::getsockname(fd, &mut storage as *mut _ as *mut libc::sockaddr, &mut len);
+        let family = c_int::from(storage.ss_family);
+        res == 0 && (family == libc::AF_INET || family == libc::AF_INET6)
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn set_sockopt(fd: RawFd, level: c_int, name: c_int, val: c_int) -> bool {
+    let res = unsafe {
+        libc::setsockopt(
+            fd,
+            level,
+            name,
+            &val as *const _ as *const c_void,
+            mem::size_of::<c_int>() as libc::socklen_t,
+        )
+    };
+    if res != 0 {
+        error!(
+            "failed to set socket option {} to {}: {}",
+            name,
+            val,
+            io::Error::last_os_error()
+        );
+    }
+    res == 0
+}
+
+#[cfg(unix)]
+fn bind_source(fd: RawFd, addr: &SocketAddr) -> bool {
+    unsafe {
+        // gRPC core connects to IPv4 targets with dual stack sockets when
+        // possible, which only accept IPv4-mapped IPv6 addresses.
+        let mut storage: libc::sockaddr_storage = mem::zeroed();
+        let mut len = mem::size_of_val(&storage) as libc::socklen_t;
+        let res = libc::getsockname(fd, &mut storage as *mut _ as *mut libc::sockaddr, &mut len);
+        let family = if res == 0 {
+            c_int::from(storage.ss_family)
+        } else {
+            libc::AF_UNSPEC
+        };
+        let addr = match *addr {
+            SocketAddr::V4(ref a) if family == libc::AF_INET6 => {
+                SocketAddr::new(IpAddr::V6(a.ip().to_ipv6_mapped()), a.port())
+            }
+            a => a,
+        };
+        let res = match addr {
+            SocketAddr::V4(ref a) => {
+                let mut sin: libc::sockaddr_in = mem::zeroed();
+                sin.sin_family = libc::AF_INET as libc::sa_family_t;
+                sin.sin_port = a.port().to_be();
+                sin.sin_addr.s_addr = u32::from(*a.ip()).to_be();
+                libc::bind(
+                    fd,
+                    &sin as *const _ as *const libc::sockaddr,
+                    mem::size_of_val(&sin) as libc::socklen_t,
+                )
+            }
+            SocketAddr::V6(ref a) => {
+                let mut sin6: libc::sockaddr_in6 = mem::zeroed();
+                sin6.sin6_family = libc::AF_INET6 as libc::sa_family_t;
+                sin6.sin6_port = a.port().to_be();
+                sin6.sin6_addr.s6_addr = a.ip().octets();
+                sin6.sin6_flowinfo = a.flowinfo();
+                sin6.sin6_scope_id = a.scope_id();
+                libc::bind(
+                    fd,
+                    &sin6 as *const _ as *const libc::sockaddr,
+                    mem::size_of_val(&sin6) as libc::socklen_t,
+                )
+            }
+        };
+        if res != 0 {
+            error!(
+                "failed to bind socket to {}: {}",
+                addr,
+                io::Error::last_os_error()
+            );
+        }
+        res == 0
+    }
+}
+
 pub struct ChannelArgs {
     args: *mut GrpcChannelArgs,
+    // The configuration the arguments are built from, so that they can be
+    // extended by `ChannelBuilder::from_args`.
+    options: HashMap<Cow<'static, [u8]>, Options>,
+    socket_mutators: Vec<SocketMutator>,
 }
 
 impl ChannelArgs {
@@ -525,9 +1154,199 @@ impl Drop for ChannelArgs {
     }
 }
 
+// The number of status codes defined by gRPC.
+const STATUS_CODE_COUNT: usize = 17;
+
+/// Counters of the calls made on a channel.
+#[derive(Default)]
+pub(crate) struct CallStats {
+    started: AtomicUsize,
+    finished: AtomicUsize,
+    failed: [AtomicUsize; STATUS_CODE_COUNT],
+    // Only counted if enabled by `ChannelBuilder::subchannel_stats`.
+    by_address: Option<Mutex<HashMap<String, SubchannelStats>>>,
+}
+
+impl CallStats {
+    pub fn on_start(&self) {
+        self.started.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn on_finish(&self, code: RpcStatusCode) {
+        if code != RpcStatusCode::Ok {
+            self.failed[code as usize].fetch_add(1, Ordering::Relaxed);
+        }
+        self.finished.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn on_finish_at(&self, address: String, code: RpcStatusCode, latency: Duration) {
+        let mut by_address = match self.by_address {
+            Some(ref m) => m.lock().unwrap(),
+            None => return,
+        };
+        if let Some(s) = by_address.get_mut(&address) {
+            return s.on_finish(code, latency);
+        }
+        let mut s = SubchannelStats::new(address.clone());
+        s.on_finish(code, latency);
+        by_address.insert(address, s);
+    }
+
+    fn snapshot(&self) -> ChannelStats {
+        let mut failed = [0; STATUS_CODE_COUNT];
+        for (f, c) in failed.iter_mut().zip(&self.failed) {
+            *f = c.load(Ordering::Relaxed);
+        }
+        // Load finished first so that in flight calls never underflow.
+        let finished = self.finished.load(Ordering::Relaxed);
+        let started = self.started.load(Ordering::Relaxed);
+        ChannelStats {
+            started,
+            finished,
+            failed,
+        }
+    }
+}
+
+/// Updates the stats of a channel once the status of a call is received.
+pub(crate) struct StatsRecorder {
+    stats: Arc<CallStats>,
+    // The call and its start time, if subchannel stats are enabled.
+    call: Option<(*mut GrpcCall, Instant)>,
+}
+
+unsafe impl Send for StatsRecorder {}
+
+impl StatsRecorder {
+    fn new(stats: Arc<CallStats>, call: &Call) -> StatsRecorder {
+        let call = if stats.by_address.is_some() {
+            // Hold a reference so the peer can be read after the call is dropped.
+            unsafe { grpc_sys::grpc_call_ref(call.as_ptr()) };
+            Some((call.as_ptr(), Instant::now()))
+        } else {
+            None
+        };
+        StatsRecorder { stats, call }
+    }
+
+    pub fn on_finish(&self, code: RpcStatusCode) {
+        self.stats.on_finish(code);
+        if let Some((call, started)) = self.call {
+            let address = unsafe { call_peer(call) };
+            self.stats.on_finish_at(address, code, started.elapsed());
+        }
+    }
+}
+
+impl Drop for StatsRecorder {
+    fn drop(&mut self) {
+        if let Some((call, _)) = self.call {
+            unsafe { grpc_sys::grpc_call_unref(call) }
+        }
+    }
+}
+
+/// A snapshot of the calls made on a [`Channel`].
+#[derive(Clone, Debug)]
+pub struct ChannelStats {
+    started: usize,
+    finished: usize,
+    failed: [usize; STATUS_CODE_COUNT],
+}
+
+impl ChannelStats {
+    /// The number of calls that have been started.
+    pub fn calls_started(&self) -> usize {
+        self.started
+    }
+
+    /// The number of calls that are not finished yet.
+    pub fn calls_in_flight(&self) -> usize {
+        self.started.saturating_sub(self.finished)
+    }
+
+    /// The number of calls that finished with a status other than `Ok`.
+    pub fn calls_failed(&self) -> usize {
+        self.failed.iter().sum()
+    }
+
+    /// The number of calls that finished with the given status code.
+    ///
+    /// Calls that finished successfully are not counted, so it's always 0 for `Ok`.
+    pub fn calls_failed_with(&self, code: RpcStatusCode) -> usize {
+        self.failed[code as usize]
+    }
+}
+
+/// Counters of the calls a [`Channel`] made to one address.
+///
+/// [`Channel`]: struct.Channel.html
+#[derive(Clone, Debug)]
+pub struct SubchannelStats {
+    address: String,
+    finished: usize,
+    failed: usize,
+    total_latency: Duration,
+    max_latency: Duration,
+}
+
+impl SubchannelStats {
+    fn new(address: String) -> SubchannelStats {
+        SubchannelStats {
+            address,
+            finished: 0,
+            failed: 0,
+            total_latency: Duration::from_secs(0),
+            max_latency: Duration::from_secs(0),
+        }
+    }
+
+    fn on_finish(&mut self, code: RpcStatusCode, latency: Duration) {
+        self.finished += 1;
+        if code != RpcStatusCode::Ok {
+            self.failed += 1;
+        }
+        self.total_latency += latency;
+        self.max_latency = cmp::max(self.max_latency, latency);
+    }
+
+    /// The peer address, e.g. `ipv4:127.0.0.1:50051`.
+    pub fn address(&self) -> &str {
+        &self.address
+    }
+
+    /// The number of calls that have finished.
+    pub fn calls_finished(&self) -> usize {
+        self.finished
+    }
+
+    /// The number of calls that finished with a status other than `Ok`.
+    pub fn calls_failed(&self) -> usize {
+        self.failed
+    }
+
+    /// The mean time from the start of a call to its status.
+    pub fn mean_latency(&self) -> Duration {
+        if self.finished == 0 {
+            return Duration::from_secs(0);
+        }
+        self.total_latency / self.finished as u32
+    }
+
+    pub fn max_latency(&self) -> Duration {
+        self.max_latency
+    }
+}
+
 struct ChannelInner {
     _env: Arc<Environment>,
     channel: *mut GrpcChannel,
+    stats: Arc<CallStats>,
+    args: Vec<(String, ChannelArgValue)>,
+    request_id: Option<RequestIdConfig>,
+    message_hook: Option<Arc<MessageHook>>,
+    // The target and whether it's secure, unless it's over a connected socket.
+    target: Option<(String, bool)>,
 }
 
 impl Drop for ChannelInner {
@@ -554,28 +1373,58 @@ unsafe impl Send for Channel {}
 unsafe impl Sync for Channel {}
 
 impl Channel {
-    fn new(cq: CompletionQueue, env: Arc<Environment>, channel: *mut GrpcChannel) -> Channel {
+    fn new(
+        cq: CompletionQueue,
+        env: Arc<Environment>,
+        channel: *mut GrpcChannel,
+        args: Vec<(String, ChannelArgValue)>,
+        request_id: Option<RequestIdConfig>,
+        message_hook: Option<Arc<MessageHook>>,
+        subchannel_stats: bool,
+        target: Option<(String, bool)>,
+    ) -> Channel {
+        let mut stats = CallStats::default();
+        if subchannel_stats {
+            stats.by_address = Some(Mutex::default());
+        }
         Channel {
-            inner: Arc::new(ChannelInner { _env: env, channel }),
+            inner: Arc::new(ChannelInner {
+                _env: env,
+                channel,
+                stats: Arc::new(stats),
+                args,
+                request_id,
+                message_hook,
+                target,
+            }),
             cq,
         }
     }
 
+    pub(crate) fn hook<T: 'static>(&self, method: &str) -> Option<Hook<T>> {
+        self.inner
+            .message_hook
+            .as_ref()
+            .map(|h| Hook::new(h.clone(), method))
+    }
+
     /// Create a call using the method and option.
-    pub(crate) fn create_call<Req, Resp>(
-        &self,
-        method: &Method<Req, Resp>,
-        opt: &CallOption,
-    ) -> Result<Call> {
+    ///
+    /// The request ID is added to the headers of `opt` if enabled.
+    pub(crate) fn create_call(&self, method: &str, opt: &mut CallOption) -> Result<Call> {
+        if let Some(ref config) = self.inner.request_id {
+            opt.attach_request_id(config);
+        }
         let cq_ref = self.cq.borrow()?;
         let raw_call = unsafe {
             let ch = self.inner.channel;
             let cq = cq_ref.as_ptr();
-            let method_ptr = method.name.as_ptr();
-            let method_len = method.name.len();
-            let timeout = opt
-                .get_timeout()
-                .map_or_else(GprTimespec::inf_future, GprTimespec::from);
+            let method_ptr = method.as_ptr();
+            let method_len = method.len();
+            let deadline = opt.effective_deadline().spec();
+            let (host_ptr, host_len) = opt
+                .get_authority()
+                .map_or((ptr::null(), 0), |a| (a.as_ptr(), a.len()));
             grpc_sys::grpcwrap_channel_create_call(
                 ch,
                 ptr::null_mut(),
@@ -583,17 +1432,267 @@ impl Channel {
                 cq,
                 method_ptr as *const _,
                 method_len,
-                ptr::null(),
-                0,
-                timeout,
+                host_ptr as *const _,
+                host_len,
+                deadline,
                 ptr::null_mut(),
             )
         };
 
+        self.inner.stats.on_start();
         unsafe { Ok(Call::from_raw(raw_call, self.cq.clone())) }
     }
 
+    /// Get the arguments the channel is created with, sorted by key.
+    ///
+    /// It's mainly for debugging, the defaults filled by gRPC core are not included.
+    pub fn args(&self) -> &[(String, ChannelArgValue)] {
+        &self.inner.args
+    }
+
+    /// Get a snapshot of the calls made on the channel.
+    ///
+    /// It can be used to apply backpressure, e.g. stop sending requests when
+    /// there are too many calls in flight.
+    pub fn stats(&self) -> ChannelStats {
+        self.inner.stats.snapshot()
+    }
+
+    /// Get the counters of the finished calls by the address they were sent
+    /// to, sorted by address.
+    ///
+    /// With the `round_robin` policy, it shows whether a single backend is
+    /// failing or slow while still receiving traffic. The connectivity states
+    /// of subchannels are not exposed by gRPC core.
+    ///
+    /// It's always empty unless enabled by [`ChannelBuilder::subchannel_stats`].
+    ///
+    /// [`ChannelBuilder::subchannel_stats`]: struct.ChannelBuilder.html#method.subchannel_stats
+    pub fn subchannel_stats(&self) -> Vec<SubchannelStats> {
+        let by_address = match self.inner.stats.by_address {
+            Some(ref m) => m.lock().unwrap(),
+            None => return vec![],
+        };
+        let mut stats: Vec<_> = by_address.values().cloned().collect();
+        stats.sort_by(|a, b| a.address.cmp(&b.address));
+        stats
+    }
+
+    pub(crate) fn stats_recorder(&self, call: &Call) -> StatsRecorder {
+        StatsRecorder::new(self.inner.stats.clone(), call)
+    }
+
     pub(crate) fn cq(&self) -> &CompletionQueue {
         &self.cq
     }
+
+    /// Get the connectivity state of the channel.
+    ///
+    /// If `try_to_connect` is true, an idle channel starts connecting.
+    pub fn check_connectivity_state(&self, try_to_connect: bool) -> ConnectivityState {
+        unsafe {
+            grpc_sys::grpc_channel_check_connectivity_state(
+                self.inner.channel,
+                try_to_connect as c_int,
+            )
+        }
+    }
+
+    /// Wait for the connectivity state to change from `last_observed`.
+    ///
+    /// The future resolves to true once the state is changed, or to false if
+    /// `deadline` is reached first.
+    pub fn wait_for_state_change<D: Into<Deadline>>(
+        &self,
+        last_observed: ConnectivityState,
+        deadline: D,
+    ) -> StateChange {
+        let cq_ref = match self.cq.borrow() {
+            Ok(r) => r,
+            Err(e) => {
+                return StateChange {
+                    f: None,
+                    err: Some(e),
+                }
+            }
+        };
+        let (cq_f, tag) = CallTag::action_pair();
+        unsafe {
+            grpc_sys::grpc_channel_watch_connectivity_state(
+                self.inner.channel,
+                last_observed,
+                deadline.into().spec(),
+                cq_ref.as_ptr(),
+                tag.into_raw(),
+            )
+        }
+        StateChange {
+            f: Some(cq_f),
+            err: None,
+        }
+    }
+
+    /// Probe the target to guess why the channel fails to connect.
+    ///
+    /// gRPC core doesn't report the errors of connection attempts, so when
+    /// the channel is in `TransientFailure`, its target is resolved with the
+    /// system resolver and connected with a plain TCP socket. The result is
+    /// not the error gRPC core hit, and may differ from it: the DNS server of
+    /// [`ChannelBuilder::dns_server`] or a `dns://authority/` target, and
+    /// proxies, are not used. If the TCP connection is established, the kind
+    /// is `Unknown`.
+    ///
+    /// It blocks for up to `timeout` per address. Returns `None` if the
+    /// channel is not failing, or the target can't be probed, e.g. the
+    /// channel is over a connected socket.
+    ///
+    /// [`ChannelBuilder::dns_server`]: struct.ChannelBuilder.html#method.dns_server
+    pub fn probe_connect_error(&self, timeout: Duration) -> Option<ConnectError> {
+        if self.check_connectivity_state(false) != ConnectivityState::TransientFailure {
+            return None;
+        }
+        let (ref target, secure) = *self.inner.target.as_ref()?;
+        probe::probe(target, secure, timeout)
+    }
+
+    /// Subscribe to the transitions of the connectivity state.
+    ///
+    /// The current state is yielded first, then every state the channel moves
+    /// to. The stream ends once the channel is shut down. It helps to find out
+    /// why connections cycle, e.g. by logging every `TransientFailure`.
+    pub fn state_changes(&self) -> StateChanges {
+        StateChanges {
+            channel: self.clone(),
+            last: None,
+            watch: None,
+        }
+    }
+}
+
+/// A future that resolves when the connectivity state of a channel changes.
+///
+/// It's created by [`Channel::wait_for_state_change`].
+///
+/// [`Channel::wait_for_state_change`]: struct.Channel.html#method.wait_for_state_change
+pub struct StateChange {
+    f: Option<CqFuture<bool>>,
+    err: Option<Error>,
+}
+
+impl Future for StateChange {
+    type Item = bool;
+    type Error = Error;
+
+    fn poll(&mut self) -> Poll<bool, Error> {
+        if let Some(e) = self.err.take() {
+            return Err(e);
+        }
+        self.f.as_mut().expect("polled after error").poll()
+    }
+}
+
+/// A stream of the connectivity states of a channel.
+///
+/// It's created by [`Channel::state_changes`].
+///
+/// [`Channel::state_changes`]: struct.Channel.html#method.state_changes
+pub struct StateChanges {
+    channel: Channel,
+    last: Option<ConnectivityState>,
+    watch: Option<StateChange>,
+}
+
+impl Stream for StateChanges {
+    type Item = ConnectivityState;
+    type Error = Error;
+
+    fn poll(&mut self) -> Poll<Option<ConnectivityState>, Error> {
+        loop {
+            let last = match self.last {
+                None => {
+                    let state = self.channel.check_connectivity_state(false);
+                    self.last = Some(state);
+                    return Ok(Async::Ready(Some(state)));
+                }
+                Some(ConnectivityState::Shutdown) => return Ok(Async::Ready(None)),
+                Some(state) => state,
+            };
+            if self.watch.is_none() {
+                self.watch = Some(
+                    self.channel
+                        .wait_for_state_change(last, Deadline::infinite()),
+                );
+            }
+            let changed = try_ready!(self.watch.as_mut().unwrap().poll());
+            self.watch = None;
+            if !changed {
+                return Ok(Async::Ready(None));
+            }
+            let state = self.channel.check_connectivity_state(false);
+            if state != last {
+                self.last = Some(state);
+                return Ok(Async::Ready(Some(state)));
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_format_target() {
+        let cases = vec![
+            ("localhost:50051", None, "localhost:50051"),
+            (
+                "localhost:50051",
+                Some("8.8.8.8"),
+                "dns://8.8.8.8/localhost:50051",
+            ),
+            (
+                "dns:///localhost:50051",
+                Some("8.8.8.8:53"),
+                "dns://8.8.8.8:53/localhost:50051",
+            ),
+            (
+                "dns://1.1.1.1/localhost:50051",
+                Some("8.8.8.8"),
+                "dns://1.1.1.1/localhost:50051",
+            ),
+            (
+                "ipv4:127.0.0.1:50051",
+                Some("8.8.8.8"),
+                "ipv4:127.0.0.1:50051",
+            ),
+            (
+                "unix:///tmp/grpc.sock",
+                Some("8.8.8.8"),
+                "unix:///tmp/grpc.sock",
+            ),
+        ];
+        for (addr, server, expect) in cases {
+            assert_eq!(format_target(addr, server).to_str().unwrap(), expect);
+        }
+    }
+
+    #[test]
+    fn test_validate() {
+        let env = Arc::new(Environment::new(1));
+        let builder = ChannelBuilder::new(env.clone());
+        assert!(builder.validate("localhost:50051").is_ok());
+        for addr in &["", "local\0host"] {
+            assert!(builder.validate(addr).is_err(), "{:?}", addr);
+        }
+
+        let builder = ChannelBuilder::new(env.clone()).dns_server("8.8.8.8");
+        assert!(builder.validate("localhost:50051").is_ok());
+        assert!(builder.validate("unix:///tmp/grpc.sock").is_err());
+
+        let builder = ChannelBuilder::new(env).http2_max_frame_size(1024);
+        match builder.try_connect("localhost:50051") {
+            Err(Error::InvalidConfig(_)) => {}
+            _ => panic!("invalid frame size should be rejected"),
+        }
+    }
 }
@@ -0,0 +1,289 @@
+// Copyright 2018 PingCAP, Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! A cache of client channels.
+//!
+//! [`ChannelManager`] keeps one channel per target, so clients created for
+//! the same target share a connection:
+//!
+//! - Channels that are not used for the idle timeout and have no call in
+//!   flight are dropped from the cache.
+//! - When the cache is full, the least recently used channel is dropped.
+//! - Channels that stay in `TransientFailure` for longer than
+//!   [`rebuild_after_failure`] are rebuilt on next use.
+//!
+//! A dropped channel stays alive as long as clients still hold it.
+//!
+//! [`ChannelManager`]: struct.ChannelManager.html
+//! [`rebuild_after_failure`]: struct.ChannelManager.html#method.rebuild_after_failure
+
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex, Weak};
+use std::time::{Duration, Instant};
+
+use futures::Future;
+
+use async::Executor;
+use channel::{Channel, ConnectivityState};
+
+/// When the channel started failing, if it's failing.
+type FailingSince = Mutex<Option<Instant>>;
+
+struct Entry {
+    channel: Channel,
+    last_used: Instant,
+    failing_since: Arc<FailingSince>,
+}
+
+impl Entry {
+    fn is_idle(&self, now: Instant, timeout: Duration) -> bool {
+        now.duration_since(self.last_used) >= timeout && self.channel.stats().calls_in_flight() == 0
+    }
+
+    fn is_broken(&self, now: Instant, threshold: Duration) -> bool {
+        self.failing_since
+            .lock()
+            .unwrap()
+            .map_or(false, |since| now.duration_since(since) >= threshold)
+    }
+}
+
+/// Track the connectivity state of `channel` until its entry is dropped.
+///
+/// Reconnection attempts move the channel between `TransientFailure` and
+/// `Connecting`, so it's failing until it's `Ready` or `Idle` again. The
+/// state is checked at least every `period`, which bounds how long the
+/// watcher keeps the channel alive after the entry is dropped.
+fn watch(channel: Channel, failing_since: Weak<FailingSince>, period: Duration) {
+    let state = channel.check_connectivity_state(false);
+    match failing_since.upgrade() {
+        Some(since) => {
+            let mut since = since.lock().unwrap();
+            match state {
+                ConnectivityState::TransientFailure => {
+                    since.get_or_insert_with(Instant::now);
+                }
+                ConnectivityState::Ready | ConnectivityState::Idle => *since = None,
+                _ => {}
+            }
+        }
+        None => return,
+    }
+    if state == ConnectivityState::Shutdown {
+        return;
+    }
+    let change = channel.wait_for_state_change(state, period);
+    let ch = channel.clone();
+    Executor::new(channel.cq()).spawn(change.then(move |res| {
+        if res.is_ok() {
+            watch(ch, failing_since, period);
+        }
+        Ok(())
+    }));
+}
+
+/// Caches channels by target.
+pub struct ChannelManager {
+    connect: Box<Fn(&str) -> Channel + Send + Sync>,
+    idle_timeout: Option<Duration>,
+    max_channels: Option<usize>,
+    rebuild_after_failure: Option<Duration>,
+    channels: Mutex<HashMap<String, Entry>>,
+}
+
+impl ChannelManager {
+    /// Create a manager that builds channels with `connect`, e.g.
+    /// `move |target| ChannelBuilder::new(env.clone()).connect(target)`.
+    pub fn new<F>(connect: F) -> ChannelManager
+    where
+        F: Fn(&str) -> Channel + Send + Sync + 'static,
+    {
+        ChannelManager {
+            connect: Box::new(connect),
+            idle_timeout: None,
+            max_channels: None,
+            rebuild_after_failure: None,
+            channels: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Drop channels that are not used for `timeout`, they are kept forever
+    /// by default.
+    pub fn idle_timeout(mut self, timeout: Duration) -> ChannelManager {
+        self.idle_timeout = Some(timeout);
+        self
+    }
+
+    /// Set the max count of cached channels, unlimited by default.
+    pub fn max_channels(mut self, max: usize) -> ChannelManager {
+        self.max_channels = Some(max);
+        self
+    }
+
+    /// Rebuild the channels that stay in `TransientFailure` for `threshold`,
+    /// e.g. when the addresses behind a name change but the old ones are
+    /// still being retried. They are kept by default.
+    ///
+    /// The connectivity state of every cached channel is watched on its
+    /// completion queue.
+    pub fn rebuild_after_failure(mut self, threshold: Duration) -> ChannelManager {
+        self.rebuild_after_failure = Some(threshold);
+        self
+    }
+
+    /// Get the channel to `target`, building it if it's not cached or it's
+    /// failing for too long.
+    pub fn get(&self, target: &str) -> Channel {
+        let now = Instant::now();
+        let mut channels = self.channels.lock().unwrap();
+        self.evict_idle_locked(&mut channels, now);
+        if let Some(e) = channels.get_mut(target) {
+            let broken = self
+                .rebuild_after_failure
+                .map_or(false, |threshold| e.is_broken(now, threshold));
+            if !broken {
+                e.last_used = now;
+                return e.channel.clone();
+            }
+        }
+        if let Some(max) = self.max_channels {
+            while !channels.contains_key(target) && channels.len() >= max.max(1) {
+                let lru = channels
+                    .iter()
+                    .min_by_key(|&(_, e)| e.last_used)
+                    .map(|(t, _)| t.clone())
+                    .unwrap();
+                channels.remove(&lru);
+            }
+        }
+        let channel = (self.connect)(target);
+        let failing_since = Arc::new(Mutex::new(None));
+        if let Some(threshold) = self.rebuild_after_failure {
+            watch(channel.clone(), Arc::downgrade(&failing_since), threshold);
+        }
+        channels.insert(
+            target.to_owned(),
+            Entry {
+                channel: channel.clone(),
+                last_used: now,
+                failing_since,
+            },
+        );
+        channel
+    }
+
+    /// Drop the channel to `target` from the cache.
+    pub fn remove(&self, target: &str) -> Option<Channel> {
+        self.channels
+            .lock()
+            .unwrap()
+            .remove(target)
+            .map(|e| e.channel)
+    }
+
+    /// Drop the idle channels now, instead of waiting for next `get`.
+    pub fn evict_idle(&self) {
+        let mut channels = self.channels.lock().unwrap();
+        self.evict_idle_locked(&mut channels, Instant::now());
+    }
+
+    /// Get the count of cached channels.
+    pub fn len(&self) -> usize {
+        self.channels.lock().unwrap().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    fn evict_idle_locked(&self, channels: &mut HashMap<String, Entry>, now: Instant) {
+        if let Some(timeout) = self.idle_timeout {
+            channels.retain(|_, e| !e.is_idle(now, timeout));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+    use std::thread;
+
+    use super::*;
+    use channel::ChannelBuilder;
+    use env::Environment;
+
+    #[test]
+    fn test_channel_manager() {
+        let env = Arc::new(Environment::new(1));
+        let built = Arc::new(AtomicUsize::new(0));
+        let built_ = built.clone();
+        let manager = ChannelManager::new(move |target| {
+            built_.fetch_add(1, Ordering::SeqCst);
+            ChannelBuilder::new(env.clone()).connect(target)
+        })
+        .max_channels(2)
+        .idle_timeout(Duration::from_millis(500));
+
+        manager.get("127.0.0.1:1");
+        manager.get("127.0.0.1:1");
+        assert_eq!(built.load(Ordering::SeqCst), 1);
+        manager.get("127.0.0.1:2");
+        thread::sleep(Duration::from_millis(10));
+        manager.get("127.0.0.1:1");
+        // The least recently used one is dropped.
+        manager.get("127.0.0.1:3");
+        assert_eq!(built.load(Ordering::SeqCst), 3);
+        assert_eq!(manager.len(), 2);
+        manager.get("127.0.0.1:1");
+        assert_eq!(built.load(Ordering::SeqCst), 3);
+        manager.get("127.0.0.1:2");
+        assert_eq!(built.load(Ordering::SeqCst), 4);
+
+        thread::sleep(Duration::from_millis(600));
+        manager.evict_idle();
+        assert!(manager.is_empty());
+        assert!(manager.remove("127.0.0.1:1").is_none());
+    }
+
+    #[test]
+    fn test_rebuild_after_failure() {
+        let env = Arc::new(Environment::new(1));
+        let built = Arc::new(AtomicUsize::new(0));
+        let built_ = built.clone();
+        let manager = ChannelManager::new(move |target| {
+            built_.fetch_add(1, Ordering::SeqCst);
+            ChannelBuilder::new(env.clone()).connect(target)
+        })
+        .rebuild_after_failure(Duration::from_millis(200));
+
+        // Nothing listens on the port.
+        let ch = manager.get("127.0.0.1:1");
+        ch.check_connectivity_state(true);
+        let start = Instant::now();
+        while ch.check_connectivity_state(false) != ConnectivityState::TransientFailure {
+            assert!(start.elapsed() < Duration::from_secs(5));
+            thread::sleep(Duration::from_millis(10));
+        }
+        // Failing, but not for long enough.
+        manager.get("127.0.0.1:1");
+        assert_eq!(built.load(Ordering::SeqCst), 1);
+
+        thread::sleep(Duration::from_millis(400));
+        manager.get("127.0.0.1:1");
+        assert_eq!(built.load(Ordering::SeqCst), 2);
+        // The new channel is idle.
+        manager.get("127.0.0.1:1");
+        assert_eq!(built.load(Ordering::SeqCst), 2);
+    }
+}
@@ -0,0 +1,145 @@
+// Copyright 2018 PingCAP, Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Message level checksum.
+//!
+//! When enabled, the checksum of a serialized single message is sent in
+//! metadata alongside the message, requests in headers and responses in
+//! trailers. It covers unary calls, the requests of server streaming calls
+//! and the responses of client streaming calls. The
+//! receiver verifies the message against it and fails the call with
+//! `DataLoss` on mismatch. Messages without a checksum are accepted as is, so
+//! only one side needs to be upgraded at a time.
+
+use call::{RpcStatus, RpcStatusCode};
+use error::Error;
+use metadata::{Metadata, MetadataBuilder};
+
+/// Metadata key used to carry the checksum of a message.
+pub const CHECKSUM_KEY: &str = "x-message-checksum-bin";
+
+/// An algorithm to calculate the checksum of a serialized message.
+pub trait Checksum: Send + Sync {
+    /// Calculate the checksum of `data`.
+    fn checksum(&self, data: &[u8]) -> Vec<u8>;
+}
+
+// Reversed polynomial of CRC-32C (Castagnoli).
+const CASTAGNOLI: u32 = 0x82F6_3B78;
+
+/// CRC-32C checksum, the checksum is encoded in big endian.
+pub struct Crc32c {
+    table: [u32; 256],
+}
+
+impl Crc32c {
+    pub fn new() -> Crc32c {
+        let mut table = [0; 256];
+        for (i, e) in table.iter_mut().enumerate() {
+            let mut crc = i as u32;
+            for _ in 0..8 {
+                crc = if crc & 1 == 1 {
+                    (crc >> 1) ^ CASTAGNOLI
+                } else {
+                    crc >> 1
+                };
+            }
+            *e = crc;
+        }
+        Crc32c { table }
+    }
+
+    /// Calculate the CRC-32C of `data`.
+    pub fn crc32c(&self, data: &[u8]) -> u32 {
+        let mut crc = !0u32;
+        for b in data {
+            crc = self.table[((crc ^ u32::from(*b)) & 0xff) as usize] ^ (crc >> 8);
+        }
+        !crc
+    }
+}
+
+impl Default for Crc32c {
+    fn default() -> Crc32c {
+        Crc32c::new()
+    }
+}
+
+impl Checksum for Crc32c {
+    fn checksum(&self, data: &[u8]) -> Vec<u8> {
+        let crc = self.crc32c(data);
+        vec![
+            (crc >> 24) as u8,
+            (crc >> 16) as u8,
+            (crc >> 8) as u8,
+            crc as u8,
+        ]
+    }
+}
+
+/// Append the checksum of `data` to `meta`.
+pub(crate) fn append(meta: Option<Metadata>, c: &Checksum, data: &[u8]) -> Metadata {
+    let mut builder = match meta {
+        Some(m) => MetadataBuilder::from_metadata(m),
+        None => MetadataBuilder::with_capacity(1),
+    };
+    builder.add_bytes(CHECKSUM_KEY, &c.checksum(data)).unwrap();
+    builder.build()
+}
+
+/// Check `data` against the checksum carried by `meta`.
+///
+/// It passes if there is no checksum in `meta`.
+pub(crate) fn verify(meta: &Metadata, c: &Checksum, data: &[u8]) -> bool {
+    match meta.iter().find(|&(k, _)| k == CHECKSUM_KEY) {
+        Some((_, sum)) => c.checksum(data) == sum,
+        None => true,
+    }
+}
+
+pub(crate) fn mismatch_status() -> RpcStatus {
+    RpcStatus::new(
+        RpcStatusCode::DataLoss,
+        Some("message checksum mismatch".to_owned()),
+    )
+}
+
+pub(crate) fn mismatch_error() -> Error {
+    Error::RpcFailure(mismatch_status())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_crc32c() {
+        let c = Crc32c::new();
+        assert_eq!(c.crc32c(b""), 0);
+        assert_eq!(c.crc32c(b"123456789"), 0xE306_9283);
+        assert_eq!(c.crc32c(&[0; 32]), 0x8A91_36AA);
+        assert_eq!(c.checksum(b"123456789"), vec![0xE3, 0x06, 0x92, 0x83]);
+    }
+
+    #[test]
+    fn test_verify() {
+        let c = Crc32c::new();
+        let mut builder = MetadataBuilder::new();
+        builder.add_str("k", "v").unwrap();
+        let meta = append(Some(builder.build()), &c, b"data");
+        assert_eq!(meta.len(), 2);
+        assert!(verify(&meta, &c, b"data"));
+        assert!(!verify(&meta, &c, b"date"));
+        assert!(verify(&MetadataBuilder::new().build(), &c, b"date"));
+    }
+}
@@ -0,0 +1,220 @@
+// Copyright 2018 PingCAP, Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Helpers to transfer large unary messages in chunks.
+//!
+//! A chunked unary method is served as a duplex streaming method whose messages
+//! are slices of the serialized request and response. Client sends all the
+//! request chunks and half-closes, then server sends all the response chunks and
+//! closes, so no single message exceeds the message size limit.
+//!
+//! The method is described by a [`Method`] with type `Duplex` and the
+//! marshallers of the whole request and response, for example:
+//!
+//! ```ignore
+//! const METHOD_UPLOAD_CHUNKED: Method<UploadRequest, UploadResponse> = Method {
+//!     ty: MethodType::Duplex,
+//!     name: "/blob.Blob/UploadChunked",
+//!     req_mar: Marshaller { ser: pb_ser, de: pb_de },
+//!     resp_mar: Marshaller { ser: pb_ser, de: pb_de },
+//! };
+//! ```
+//!
+//! Use [`Client::chunked_unary_call_async`] to call it and
+//! [`ServiceBuilder::add_chunked_unary_handler`] to serve it. The compiler
+//! can also generate such a companion for every unary method, see
+//! `grpcio_compiler::codegen::gen_chunked`.
+//!
+//! [`Method`]: ../struct.Method.html
+//! [`Client::chunked_unary_call_async`]: ../struct.Client.html#method.chunked_unary_call_async
+//! [`ServiceBuilder::add_chunked_unary_handler`]: ../struct.ServiceBuilder.html#method.add_chunked_unary_handler
+
+use futures::{stream, Async, Future, Poll, Sink, Stream};
+
+use call::client::ClientDuplexReceiver;
+use call::server::{DuplexSink, RequestStream};
+use call::{RpcStatus, WriteFlags};
+use codec::{DeserializeFn, SerializeFn};
+use error::Error;
+
+/// The chunk size of the services generated with chunked companions, well
+/// below the default message size limit.
+pub const DEFAULT_CHUNK_SIZE: usize = 1024 * 1024;
+
+/// Split `data` into chunks that are no larger than `chunk_size`.
+///
+/// An empty `data` still produces one empty chunk, so that there is always a
+/// message on the wire.
+pub(crate) fn split(data: &[u8], chunk_size: usize) -> Vec<(Vec<u8>, WriteFlags)> {
+    assert!(chunk_size > 0, "chunk size should be positive");
+    if data.is_empty() {
+        return vec![(vec![], WriteFlags::default())];
+    }
+    data.chunks(chunk_size)
+        .map(|c| (c.to_vec(), WriteFlags::default()))
+        .collect()
+}
+
+/// A future that reassembles chunks into a message.
+struct Assembler<S> {
+    chunks: S,
+    buf: Vec<u8>,
+}
+
+impl<S: Stream<Item = Vec<u8>, Error = Error>> Assembler<S> {
+    fn new(chunks: S) -> Assembler<S> {
+        Assembler {
+            chunks,
+            buf: vec![],
+        }
+    }
+
+    fn poll<T>(&mut self, de: DeserializeFn<T>) -> Poll<T, Error> {
+        while let Some(chunk) = try_ready!(self.chunks.poll()) {
+            self.buf.extend_from_slice(&chunk);
+        }
+        let t = de(&self.buf)?;
+        self.buf = vec![];
+        Ok(Async::Ready(t))
+    }
+}
+
+/// The request of a chunked unary call.
+///
+/// It's resolved once all the chunks are received.
+pub struct ChunkedRequest<T> {
+    inner: Assembler<RequestStream<Vec<u8>>>,
+    de: DeserializeFn<T>,
+}
+
+impl<T> ChunkedRequest<T> {
+    pub(crate) fn new(reqs: RequestStream<Vec<u8>>, de: DeserializeFn<T>) -> ChunkedRequest<T> {
+        ChunkedRequest {
+            inner: Assembler::new(reqs),
+            de,
+        }
+    }
+}
+
+impl<T> Future for ChunkedRequest<T> {
+    type Item = T;
+    type Error = Error;
+
+    fn poll(&mut self) -> Poll<T, Error> {
+        self.inner.poll(self.de)
+    }
+}
+
+/// A sink for chunked unary call.
+pub struct ChunkedSink<T> {
+    sink: DuplexSink<Vec<u8>>,
+    ser: SerializeFn<T>,
+    chunk_size: usize,
+}
+
+impl<T> ChunkedSink<T> {
+    pub(crate) fn new(
+        sink: DuplexSink<Vec<u8>>,
+        ser: SerializeFn<T>,
+        chunk_size: usize,
+    ) -> ChunkedSink<T> {
+        ChunkedSink {
+            sink,
+            ser,
+            chunk_size,
+        }
+    }
+
+    /// Send the response in chunks.
+    pub fn success(self, t: T) -> ChunkedSinkResult {
+        let mut buf = vec![];
+        (self.ser)(&t, &mut buf);
+        let chunks = stream::iter_ok::<_, Error>(split(&buf, self.chunk_size));
+        let f = self.sink.send_all(chunks).map(|_| ());
+        ChunkedSinkResult { f: Box::new(f) }
+    }
+
+    pub fn fail(self, status: RpcStatus) -> ChunkedSinkResult {
+        ChunkedSinkResult {
+            f: Box::new(self.sink.fail(status)),
+        }
+    }
+}
+
+/// A future that is resolved once the response is sent.
+#[must_use = "if unused the response may not be sent"]
+pub struct ChunkedSinkResult {
+    f: Box<Future<Item = (), Error = Error> + Send>,
+}
+
+impl Future for ChunkedSinkResult {
+    type Item = ();
+    type Error = Error;
+
+    fn poll(&mut self) -> Poll<(), Error> {
+        self.f.poll()
+    }
+}
+
+/// A receiver for chunked unary call.
+///
+/// The future is resolved once all the response chunks are received.
+pub struct ClientChunkedReceiver<T> {
+    inner: Assembler<ClientDuplexReceiver<Vec<u8>>>,
+    de: DeserializeFn<T>,
+}
+
+impl<T> ClientChunkedReceiver<T> {
+    pub(crate) fn new(
+        resps: ClientDuplexReceiver<Vec<u8>>,
+        de: DeserializeFn<T>,
+    ) -> ClientChunkedReceiver<T> {
+        ClientChunkedReceiver {
+            inner: Assembler::new(resps),
+            de,
+        }
+    }
+
+    /// Cancel the call.
+    pub fn cancel(&mut self) {
+        self.inner.chunks.cancel()
+    }
+}
+
+impl<T> Future for ClientChunkedReceiver<T> {
+    type Item = T;
+    type Error = Error;
+
+    fn poll(&mut self) -> Poll<T, Error> {
+        self.inner.poll(self.de)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_split() {
+        let cases: Vec<(&[u8], usize, Vec<&[u8]>)> = vec![
+            (b"", 2, vec![b""]),
+            (b"a", 2, vec![b"a"]),
+            (b"abcd", 2, vec![b"ab", b"cd"]),
+            (b"abcde", 2, vec![b"ab", b"cd", b"e"]),
+        ];
+        for (data, size, expect) in cases {
+            let chunks: Vec<_> = split(data, size).into_iter().map(|(c, _)| c).collect();
+            assert_eq!(chunks, expect);
+        }
+    }
+}
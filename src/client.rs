@@ -11,19 +11,22 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-use futures::Future;
+use futures::{stream, Future, Sink};
 
 use async::Executor;
 use call::client::{
-    CallOption, ClientCStreamReceiver, ClientCStreamSender, ClientDuplexReceiver,
-    ClientDuplexSender, ClientSStreamReceiver, ClientUnaryReceiver,
+    BatchUnaryReceiver, CallOption, ClientCStreamReceiver, ClientCStreamSender,
+    ClientDuplexReceiver, ClientDuplexSender, ClientSStreamReceiver, ClientUnaryReceiver,
 };
 use call::{Call, Method};
 use channel::Channel;
+use chunk::{self, ClientChunkedReceiver};
+use codec::raw_codec;
 
-use error::Result;
+use error::{Error, Result};
 
 /// A generic client for making RPC calls.
+#[derive(Clone)]
 pub struct Client {
     channel: Channel,
 }
@@ -35,7 +38,7 @@ impl Client {
     }
 
     /// Create a synchronized unary RPC call.
-    pub fn unary_call<Req, Resp>(
+    pub fn unary_call<Req: 'static, Resp: 'static>(
         &self,
         method: &Method<Req, Resp>,
         req: &Req,
@@ -46,7 +49,7 @@ impl Client {
     }
 
     /// Create an asynchronized unary RPC call.
-    pub fn unary_call_async<Req, Resp>(
+    pub fn unary_call_async<Req: 'static, Resp: 'static>(
         &self,
         method: &Method<Req, Resp>,
         req: &Req,
@@ -55,10 +58,30 @@ impl Client {
         Call::unary_async(&self.channel, method, req, opt)
     }
 
+    /// Start a batch of asynchronized unary RPC calls.
+    ///
+    /// Responses are yielded in the order they are received, tagged with the index of
+    /// the request. It's a convenience for fan-out workloads: every request is still
+    /// a separate call with its own completion, so it costs the same as starting the
+    /// calls one by one and merging their futures.
+    pub fn batch_unary_call_async<'a, Req, Resp, I>(
+        &self,
+        method: &Method<Req, Resp>,
+        reqs: I,
+        opt: CallOption,
+    ) -> Result<BatchUnaryReceiver<Resp>>
+    where
+        Req: 'static,
+        Resp: 'static,
+        I: IntoIterator<Item = &'a Req>,
+    {
+        Call::batch_unary_async(&self.channel, method, reqs, opt)
+    }
+
     /// Create an asynchronized client streaming call.
     ///
     /// Client can send a stream of requests and server responds with a single response.
-    pub fn client_streaming<Req, Resp>(
+    pub fn client_streaming<Req: 'static, Resp: 'static>(
         &self,
         method: &Method<Req, Resp>,
         opt: CallOption,
@@ -69,7 +92,7 @@ impl Client {
     /// Create an asynchronized server streaming call.
     ///
     /// Client sends on request and server responds with a stream of responses.
-    pub fn server_streaming<Req, Resp>(
+    pub fn server_streaming<Req: 'static, Resp: 'static>(
         &self,
         method: &Method<Req, Resp>,
         req: &Req,
@@ -83,7 +106,7 @@ impl Client {
     /// Client sends a stream of requests and server responds with a stream of responses.
     /// The response stream is completely independent and both side can be sending messages
     /// at the same time.
-    pub fn duplex_streaming<Req, Resp>(
+    pub fn duplex_streaming<Req: 'static, Resp: 'static>(
         &self,
         method: &Method<Req, Resp>,
         opt: CallOption,
@@ -91,6 +114,60 @@ impl Client {
         Call::duplex_streaming(&self.channel, method, opt)
     }
 
+    /// Create an asynchronized duplex streaming call without any serialization.
+    ///
+    /// `method` is the full qualified name of the method, for example the one
+    /// returned by [`RpcContext::method`]. Messages are sent and received as raw
+    /// bytes, which makes it possible to forward calls of unknown methods, as
+    /// [`proxy::forward`] does.
+    ///
+    /// [`RpcContext::method`]: ../struct.RpcContext.html#method.method
+    /// [`proxy::forward`]: proxy/fn.forward.html
+    pub fn raw_duplex_streaming(
+        &self,
+        method: &str,
+        opt: CallOption,
+    ) -> Result<(ClientDuplexSender<Vec<u8>>, ClientDuplexReceiver<Vec<u8>>)> {
+        Call::duplex_streaming_by_name(&self.channel, method, raw_codec::ser, raw_codec::de, opt)
+    }
+
+    /// Create a synchronized chunked unary RPC call.
+    pub fn chunked_unary_call<Req, Resp>(
+        &self,
+        method: &Method<Req, Resp>,
+        req: &Req,
+        chunk_size: usize,
+        opt: CallOption,
+    ) -> Result<Resp> {
+        let f = self.chunked_unary_call_async(method, req, chunk_size, opt)?;
+        f.wait()
+    }
+
+    /// Create an asynchronized chunked unary RPC call.
+    ///
+    /// The request is sent in chunks no larger than `chunk_size`, and the response
+    /// is reassembled before being returned. See [`chunk`] for more details.
+    ///
+    /// [`chunk`]: chunk/index.html
+    pub fn chunked_unary_call_async<Req, Resp>(
+        &self,
+        method: &Method<Req, Resp>,
+        req: &Req,
+        chunk_size: usize,
+        opt: CallOption,
+    ) -> Result<ClientChunkedReceiver<Resp>> {
+        let mut payload = vec![];
+        (method.req_ser())(req, &mut payload);
+        let chunks = stream::iter_ok::<_, Error>(chunk::split(&payload, chunk_size));
+        let (tx, rx) = self.raw_duplex_streaming(method.name, opt)?;
+        self.spawn(
+            tx.send_all(chunks)
+                .map(|_| ())
+                .map_err(|e| error!("failed to send request chunks: {:?}", e)),
+        );
+        Ok(ClientChunkedReceiver::new(rx, method.resp_de()))
+    }
+
     /// Spawn the future into current gRPC poll thread.
     ///
     /// This can reduce a lot of context switching, but please make
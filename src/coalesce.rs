@@ -0,0 +1,212 @@
+// Copyright 2018 PingCAP, Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Coalescing of identical unary calls.
+//!
+//! A [`CoalescingClient`] sends only one of the concurrent unary calls
+//! marked by [`CallOption::idempotent`] that have the same method and
+//! serialized request. The calls made while it's in flight wait for it and
+//! get a copy of its result, so a hot key fetched by many tasks at once
+//! reaches the server only once:
+//!
+//! ```ignore
+//! let client = CoalescingClient::new(Client::new(ch));
+//! let opt = CallOption::default().idempotent(true);
+//! let value = client.unary_call_async(&METHOD_KV_GET, &req, opt)?;
+//! ```
+//!
+//! Only the request is compared, the call options such as headers and
+//! deadline of the call that is sent apply to all the calls sharing it.
+//! Calls that are not marked idempotent are always sent on their own.
+//!
+//! [`CoalescingClient`]: struct.CoalescingClient.html
+//! [`CallOption::idempotent`]: ../struct.CallOption.html#method.idempotent
+
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+use futures::sync::oneshot;
+use futures::{Async, Future, Poll};
+
+use call::client::{CallOption, ClientUnaryReceiver};
+use call::{Method, MethodType, RpcStatus, RpcStatusCode};
+use client::Client;
+use codec::{raw_codec, DeserializeFn, Marshaller};
+use error::{Error, Result};
+
+type Waiters = Vec<oneshot::Sender<Result<Vec<u8>>>>;
+
+/// A client that coalesces concurrent identical idempotent unary calls, see
+/// [`coalesce`](index.html) for details.
+#[derive(Clone)]
+pub struct CoalescingClient {
+    client: Client,
+    in_flight: Arc<Mutex<HashMap<Vec<u8>, Waiters>>>,
+}
+
+impl CoalescingClient {
+    pub fn new(client: Client) -> CoalescingClient {
+        CoalescingClient {
+            client,
+            in_flight: Arc::default(),
+        }
+    }
+
+    /// Get the client the calls are sent by.
+    pub fn client(&self) -> &Client {
+        &self.client
+    }
+
+    /// Get the count of distinct calls in flight.
+    pub fn in_flight(&self) -> usize {
+        self.in_flight.lock().unwrap().len()
+    }
+
+    /// Create a synchronized unary RPC call, which may share the response
+    /// of an identical call in flight.
+    pub fn unary_call<Req: 'static, Resp: 'static>(
+        &self,
+        method: &Method<Req, Resp>,
+        req: &Req,
+        opt: CallOption,
+    ) -> Result<Resp> {
+        self.unary_call_async(method, req, opt)?.wait()
+    }
+
+    /// Create an asynchronized unary RPC call, which may share the response
+    /// of an identical call in flight.
+    pub fn unary_call_async<Req: 'static, Resp: 'static>(
+        &self,
+        method: &Method<Req, Resp>,
+        req: &Req,
+        opt: CallOption,
+    ) -> Result<CoalescedCall<Resp>> {
+        if !opt.is_idempotent() {
+            let recv = self.client.unary_call_async(method, req, opt)?;
+            return Ok(CoalescedCall {
+                state: State::Direct(recv),
+            });
+        }
+        let mut key = method.name.as_bytes().to_vec();
+        key.push(0);
+        (method.req_ser())(req, &mut key);
+        let (tx, rx) = oneshot::channel();
+        let shared = CoalescedCall {
+            state: State::Shared(rx, method.resp_de()),
+        };
+
+        let recv = {
+            let mut in_flight = self.in_flight.lock().unwrap();
+            if let Some(waiters) = in_flight.get_mut(&key) {
+                waiters.push(tx);
+                return Ok(shared);
+            }
+            // The request is serialized already, send it as is.
+            let raw = Method {
+                ty: MethodType::Unary,
+                name: method.name,
+                req_mar: Marshaller {
+                    ser: raw_codec::ser,
+                    de: raw_codec::de,
+                },
+                resp_mar: Marshaller {
+                    ser: raw_codec::ser,
+                    de: raw_codec::de,
+                },
+            };
+            let payload = key[method.name.len() + 1..].to_vec();
+            let recv: ClientUnaryReceiver<Vec<u8>> =
+                self.client.unary_call_async(&raw, &payload, opt)?;
+            in_flight.insert(key.clone(), vec![tx]);
+            recv
+        };
+
+        // Spawned after the lock is released, as it may be polled
+        // immediately.
+        let in_flight = self.in_flight.clone();
+        self.client.spawn(recv.then(move |res| {
+            let waiters = in_flight.lock().unwrap().remove(&key).unwrap_or_default();
+            for tx in waiters {
+                let _ = tx.send(match res {
+                    Ok(ref data) => Ok(data.clone()),
+                    Err(ref e) => Err(share_error(e)),
+                });
+            }
+            Ok(())
+        }));
+        Ok(shared)
+    }
+}
+
+// `Error` can't be cloned, the status is kept if there is one.
+fn share_error(e: &Error) -> Error {
+    match *e {
+        Error::RpcFailure(ref status) => Error::RpcFailure(status.clone()),
+        ref e => Error::RpcFailure(RpcStatus::new(RpcStatusCode::Unknown, Some(e.to_string()))),
+    }
+}
+
+enum State<Resp> {
+    Direct(ClientUnaryReceiver<Resp>),
+    Shared(oneshot::Receiver<Result<Vec<u8>>>, DeserializeFn<Resp>),
+}
+
+/// The response of a call made by [`CoalescingClient`].
+///
+/// Dropping it doesn't cancel the call that is sent, as it may be shared.
+///
+/// [`CoalescingClient`]: struct.CoalescingClient.html
+pub struct CoalescedCall<Resp> {
+    state: State<Resp>,
+}
+
+impl<Resp> Future for CoalescedCall<Resp> {
+    type Item = Resp;
+    type Error = Error;
+
+    fn poll(&mut self) -> Poll<Resp, Error> {
+        match self.state {
+            State::Direct(ref mut r) => r.poll(),
+            State::Shared(ref mut rx, de) => match rx.poll() {
+                Ok(Async::Ready(res)) => de(&res?).map(Async::Ready),
+                Ok(Async::NotReady) => Ok(Async::NotReady),
+                // The environment is shut down before the call is finished.
+                Err(_) => Err(Error::RpcFailure(RpcStatus::new(
+                    RpcStatusCode::Cancelled,
+                    Some("coalesced call is shut down".to_owned()),
+                ))),
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_share_error() {
+        let status = RpcStatus::new(RpcStatusCode::NotFound, Some("no key".to_owned()));
+        match share_error(&Error::RpcFailure(status)) {
+            Error::RpcFailure(s) => {
+                assert_eq!(s.status, RpcStatusCode::NotFound);
+                assert_eq!(s.details.unwrap(), "no key");
+            }
+            e => panic!("unexpected error {:?}", e),
+        }
+        match share_error(&Error::RemoteStopped) {
+            Error::RpcFailure(s) => assert_eq!(s.status, RpcStatusCode::Unknown),
+            e => panic!("unexpected error {:?}", e),
+        }
+    }
+}
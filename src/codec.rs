@@ -11,9 +11,17 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
+use std::cell::RefCell;
+use std::sync::Arc;
+
 use error::Result;
 
 pub type DeserializeFn<T> = fn(&[u8]) -> Result<T>;
+/// Deserializes into an existing value, which lets a streaming loop reuse
+/// one message and its allocations, see e.g. [`RequestStream::poll_into`].
+///
+/// [`RequestStream::poll_into`]: struct.RequestStream.html#method.poll_into
+pub type DeserializeIntoFn<T> = fn(&[u8], &mut T) -> Result<()>;
 pub type SerializeFn<T> = fn(&T, &mut Vec<u8>);
 
 /// Defines how to serialize and deserialize between the specialized type and byte slice.
@@ -49,4 +57,128 @@ pub mod pb_codec {
     pub fn de<T: Message>(buf: &[u8]) -> Result<T> {
         protobuf::parse_from_bytes(buf).map_err(From::from)
     }
+
+    /// Clear `t` and merge `buf` into it. The repeated and string fields keep
+    /// their allocations.
+    #[inline]
+    pub fn de_into<T: Message>(buf: &[u8], t: &mut T) -> Result<()> {
+        t.clear();
+        t.merge_from_bytes(buf)?;
+        t.check_initialized().map_err(From::from)
+    }
+}
+
+/// The codec that passes the payload through without any transformation.
+pub mod raw_codec {
+    use error::Result;
+
+    #[inline]
+    #[cfg_attr(feature = "cargo-clippy", allow(ptr_arg))]
+    pub fn ser(t: &Vec<u8>, buf: &mut Vec<u8>) {
+        buf.extend_from_slice(t)
+    }
+
+    #[inline]
+    pub fn de(buf: &[u8]) -> Result<Vec<u8>> {
+        Ok(buf.to_vec())
+    }
+}
+
+// Parts shorter than this are copied anyway, referencing them costs more.
+const MIN_SHARED_LEN: usize = 1024;
+
+/// Parts of a message that are kept out of its buffer, each with the offset
+/// it's inserted at.
+pub(crate) type SharedParts = Vec<(usize, Arc<Vec<u8>>)>;
+
+struct Collector {
+    buf: *const Vec<u8>,
+    parts: SharedParts,
+}
+
+thread_local! {
+    static COLLECTOR: RefCell<Option<Collector>> = RefCell::new(None);
+}
+
+/// Append `data` to the message that is being serialized into `buf`.
+///
+/// When the message is sent by a streaming call, `data` is not copied into
+/// `buf` but passed to gRPC core as a separate slice of the message, which
+/// saves copying large parts such as the blob after a small header. In other
+/// cases, e.g. unary calls, and for parts shorter than 1 KiB, it's the same as
+/// `buf.extend_from_slice(&data)`.
+pub fn append_shared(buf: &mut Vec<u8>, data: &Arc<Vec<u8>>) {
+    if data.len() >= MIN_SHARED_LEN {
+        let shared = COLLECTOR
+            .try_with(|c| match *c.borrow_mut() {
+                Some(ref mut c) if c.buf == buf as *const _ => {
+                    c.parts.push((buf.len(), data.clone()));
+                    true
+                }
+                _ => false,
+            })
+            .unwrap_or(false);
+        if shared {
+            return;
+        }
+    }
+    buf.extend_from_slice(data)
+}
+
+// Restores the outer collector even if the serializer panics.
+struct Guard(Option<Collector>);
+
+impl Drop for Guard {
+    fn drop(&mut self) {
+        let prev = self.0.take();
+        let _ = COLLECTOR.try_with(|c| *c.borrow_mut() = prev);
+    }
+}
+
+/// Serialize `t` into `buf`, keeping out the parts added by `append_shared`.
+pub(crate) fn ser_shared<T>(ser: SerializeFn<T>, t: &T, buf: &mut Vec<u8>) -> SharedParts {
+    let _guard = Guard(COLLECTOR.with(|c| c.borrow_mut().take()));
+    COLLECTOR.with(|c| {
+        *c.borrow_mut() = Some(Collector {
+            buf: buf as *const _,
+            parts: vec![],
+        })
+    });
+    ser(t, buf);
+    COLLECTOR
+        .with(|c| c.borrow_mut().take())
+        .map_or_else(Vec::new, |c| c.parts)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ser(t: &(Vec<u8>, Arc<Vec<u8>>), buf: &mut Vec<u8>) {
+        buf.extend_from_slice(&t.0);
+        append_shared(buf, &t.1);
+        // Nested buffers are not sent by the call, so they get a copy.
+        let mut nested = vec![];
+        append_shared(&mut nested, &t.1);
+        assert_eq!(nested, *t.1);
+        append_shared(buf, &Arc::new(b"tail".to_vec()));
+    }
+
+    #[test]
+    fn test_ser_shared() {
+        let blob = Arc::new(vec![7; MIN_SHARED_LEN]);
+        let msg = (b"header".to_vec(), blob.clone());
+
+        let mut buf = vec![];
+        let parts = ser_shared(ser, &msg, &mut buf);
+        assert_eq!(buf, b"headertail");
+        assert_eq!(parts.len(), 1);
+        assert_eq!(parts[0].0, 6);
+        assert!(Arc::ptr_eq(&parts[0].1, &blob));
+
+        // Without a call, everything is copied.
+        let mut buf = vec![];
+        ser(&msg, &mut buf);
+        assert_eq!(buf.len(), 6 + MIN_SHARED_LEN + 4);
+    }
 }
@@ -0,0 +1,229 @@
+// Copyright 2018 PingCAP, Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Key/value baggage that is propagated across services.
+//!
+//! A [`Context`] is attached to an outgoing call by [`CallOption::context`]
+//! and sent in the [`BAGGAGE_KEY`] header, using the format of the W3C
+//! baggage header: `key1=value1,key2=value2`, both keys and values being
+//! percent-encoded. Server gets it back by [`RpcContext::context`], and can
+//! pass it on to the calls it makes.
+//!
+//! [`Context`]: struct.Context.html
+//! [`BAGGAGE_KEY`]: constant.BAGGAGE_KEY.html
+//! [`CallOption::context`]: ../struct.CallOption.html#method.context
+//! [`RpcContext::context`]: ../struct.RpcContext.html#method.context
+
+use std::fmt::Write;
+use std::{mem, str};
+
+use metadata::{Metadata, MetadataBuilder};
+
+/// Metadata key used to carry the baggage.
+pub const BAGGAGE_KEY: &str = "baggage";
+
+fn unreserved(b: u8) -> bool {
+    match b {
+        b'a'..=b'z' | b'A'..=b'Z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => true,
+        _ => false,
+    }
+}
+
+fn encode(s: &str, buf: &mut String) {
+    for &b in s.as_bytes() {
+        if unreserved(b) {
+            buf.push(b as char);
+        } else {
+            let _ = write!(buf, "%{:02X}", b);
+        }
+    }
+}
+
+fn hex(b: u8) -> Option<u8> {
+    match b {
+        b'0'..=b'9' => Some(b - b'0'),
+        b'a'..=b'f' => Some(b - b'a' + 10),
+        b'A'..=b'F' => Some(b - b'A' + 10),
+        _ => None,
+    }
+}
+
+fn decode(s: &str) -> Option<String> {
+    let bytes = s.as_bytes();
+    let mut res = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'%' {
+            if i + 2 >= bytes.len() {
+                return None;
+            }
+            res.push(hex(bytes[i + 1])? << 4 | hex(bytes[i + 2])?);
+            i += 3;
+        } else {
+            res.push(bytes[i]);
+            i += 1;
+        }
+    }
+    String::from_utf8(res).ok()
+}
+
+/// A set of key/value pairs propagated along with calls.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Context {
+    entries: Vec<(String, String)>,
+}
+
+impl Context {
+    pub fn new() -> Context {
+        Context::default()
+    }
+
+    /// Set `key` to `value`, the previous value is replaced.
+    pub fn with<K: Into<String>, V: Into<String>>(mut self, key: K, value: V) -> Context {
+        self.insert(key, value);
+        self
+    }
+
+    /// Set `key` to `value`, the previous value is returned.
+    pub fn insert<K: Into<String>, V: Into<String>>(&mut self, key: K, value: V) -> Option<String> {
+        let (key, value) = (key.into(), value.into());
+        match self.entries.iter_mut().find(|e| e.0 == key) {
+            Some(e) => return Some(mem::replace(&mut e.1, value)),
+            None => self.entries.push((key, value)),
+        }
+        None
+    }
+
+    pub fn get(&self, key: &str) -> Option<&str> {
+        self.entries
+            .iter()
+            .find(|e| e.0 == key)
+            .map(|e| e.1.as_str())
+    }
+
+    pub fn remove(&mut self, key: &str) -> Option<String> {
+        let pos = self.entries.iter().position(|e| e.0 == key)?;
+        Some(self.entries.remove(pos).1)
+    }
+
+    /// Returns an iterator over the entries, in the order they are inserted.
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.entries.iter().map(|e| (e.0.as_str(), e.1.as_str()))
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// Keep only the entries whose keys are in `keys`.
+    ///
+    /// It's useful to stop internal hints from leaking to another service.
+    pub fn retain(&mut self, keys: &[&str]) {
+        self.entries.retain(|e| keys.contains(&e.0.as_str()));
+    }
+
+    /// Encode as the value of the baggage header.
+    pub fn to_header_value(&self) -> String {
+        let mut buf = String::new();
+        for (i, &(ref k, ref v)) in self.entries.iter().enumerate() {
+            if i > 0 {
+                buf.push(',');
+            }
+            encode(k, &mut buf);
+            buf.push('=');
+            encode(v, &mut buf);
+        }
+        buf
+    }
+
+    /// Merge the entries found in a baggage header value into this context.
+    ///
+    /// Malformed entries are ignored, as well as the properties of entries.
+    pub fn merge_header_value(&mut self, value: &str) {
+        for member in value.split(',') {
+            let pair = member.split(';').next().unwrap();
+            let mut parts = pair.splitn(2, '=');
+            let (k, v) = match (parts.next(), parts.next()) {
+                (Some(k), Some(v)) => (k.trim(), v.trim()),
+                _ => continue,
+            };
+            if k.is_empty() {
+                continue;
+            }
+            if let (Some(k), Some(v)) = (decode(k), decode(v)) {
+                self.insert(k, v);
+            }
+        }
+    }
+
+    /// Collect the baggage from the headers of a call.
+    pub fn from_headers(headers: &Metadata) -> Context {
+        let mut ctx = Context::new();
+        for (k, v) in headers {
+            if k != BAGGAGE_KEY {
+                continue;
+            }
+            if let Ok(v) = str::from_utf8(v) {
+                ctx.merge_header_value(v);
+            }
+        }
+        ctx
+    }
+
+    pub(crate) fn to_headers(&self) -> Metadata {
+        let mut builder = MetadataBuilder::with_capacity(1);
+        if !self.is_empty() {
+            builder
+                .add_str(BAGGAGE_KEY, &self.to_header_value())
+                .expect("encoded baggage should be a valid ascii value");
+        }
+        builder.build()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_header_value() {
+        let ctx = Context::new()
+            .with("tenant", "acme")
+            .with("route", "canary, eu=1")
+            .with("tenant", "acme corp");
+        assert_eq!(ctx.get("tenant"), Some("acme corp"));
+        let value = ctx.to_header_value();
+        assert_eq!(value, "tenant=acme%20corp,route=canary%2C%20eu%3D1");
+
+        let mut parsed = Context::new();
+        parsed.merge_header_value(&value);
+        assert_eq!(parsed, ctx);
+
+        let mut parsed = Context::new();
+        parsed.merge_header_value(" a = 1 ;prop=x, bad, =2, c=%zz, d=%E4%BD%A0");
+        assert_eq!(
+            parsed.iter().collect::<Vec<_>>(),
+            vec![("a", "1"), ("d", "你")]
+        );
+
+        let mut ctx = ctx;
+        ctx.retain(&["route"]);
+        assert_eq!(ctx.len(), 1);
+        assert_eq!(ctx.remove("route"), Some("canary, eu=1".to_owned()));
+        assert!(ctx.is_empty());
+    }
+}
@@ -0,0 +1,160 @@
+// Copyright 2018 PingCAP, Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Multiplex request/response pairs over one duplex streaming call.
+//!
+//! Every request is sent along with an id, and server is supposed to carry the
+//! same id in the response, which is extracted by a user supplied function.
+//! Responses can arrive in any order.
+
+use std::collections::HashMap;
+use std::hash::Hash;
+use std::sync::{Arc, Mutex};
+
+use futures::sync::{mpsc, oneshot};
+use futures::{Async, Future, Poll, Sink, Stream};
+
+use call::client::{ClientDuplexReceiver, ClientDuplexSender};
+use call::WriteFlags;
+use client::Client;
+use error::{Error, Result};
+
+struct State<K, Resp> {
+    pending: HashMap<K, oneshot::Sender<Result<Resp>>>,
+    closed: bool,
+}
+
+impl<K: Hash + Eq, Resp> State<K, Resp> {
+    fn close(&mut self, res: &Result<()>) {
+        self.closed = true;
+        for (_, tx) in self.pending.drain() {
+            let e = match *res {
+                Ok(()) => Error::RpcFinished(None),
+                Err(Error::RpcFailure(ref status)) => Error::RpcFailure(status.clone()),
+                Err(_) => Error::RemoteStopped,
+            };
+            let _ = tx.send(Err(e));
+        }
+    }
+}
+
+/// A handle to send correlated requests over a duplex streaming call.
+///
+/// The call is half-closed once the correlator is dropped.
+pub struct Correlator<Req, Resp, K> {
+    tx: mpsc::UnboundedSender<(Req, WriteFlags)>,
+    state: Arc<Mutex<State<K, Resp>>>,
+}
+
+impl<Req, Resp, K> Correlator<Req, Resp, K>
+where
+    Req: Send + 'static,
+    Resp: Send + 'static,
+    K: Hash + Eq + Send + 'static,
+{
+    /// Take over the streams of a duplex streaming call.
+    ///
+    /// `response_id` extracts the id from a response. Both streams are driven
+    /// by the completion queue of `client`.
+    pub fn new<F>(
+        client: &Client,
+        sender: ClientDuplexSender<Req>,
+        receiver: ClientDuplexReceiver<Resp>,
+        response_id: F,
+    ) -> Correlator<Req, Resp, K>
+    where
+        F: Fn(&Resp) -> K + Send + 'static,
+    {
+        let (tx, rx) = mpsc::unbounded();
+        let state = Arc::new(Mutex::new(State {
+            pending: HashMap::new(),
+            closed: false,
+        }));
+
+        let send_f = sender
+            .send_all(rx.map_err(|()| Error::RemoteStopped))
+            .map(|_| ())
+            .map_err(|e| warn!("failed to send correlated requests: {:?}", e));
+        client.spawn(send_f);
+
+        let recv_state = state.clone();
+        let close_state = state.clone();
+        let recv_f = receiver
+            .for_each(move |resp| {
+                let id = response_id(&resp);
+                match recv_state.lock().unwrap().pending.remove(&id) {
+                    Some(tx) => {
+                        let _ = tx.send(Ok(resp));
+                    }
+                    None => warn!("drop a response that doesn't match any request"),
+                }
+                Ok(())
+            })
+            .then(move |res| {
+                close_state.lock().unwrap().close(&res);
+                Ok(())
+            });
+        client.spawn(recv_f);
+
+        Correlator { tx, state }
+    }
+
+    /// Send `req` and return a future that resolves to the response with the
+    /// same `id`.
+    ///
+    /// `id` should be unique among the requests in flight, otherwise the
+    /// previous request with the same id fails with `RemoteStopped`.
+    pub fn call(&self, id: K, req: Req) -> CorrelatedResponse<Resp> {
+        self.call_opt(id, req, WriteFlags::default())
+    }
+
+    /// Same as `call`, but with custom write flags.
+    pub fn call_opt(&self, id: K, req: Req, flags: WriteFlags) -> CorrelatedResponse<Resp> {
+        let (tx, rx) = oneshot::channel();
+        {
+            let mut state = self.state.lock().unwrap();
+            if state.closed {
+                let _ = tx.send(Err(Error::RpcFinished(None)));
+                return CorrelatedResponse { rx };
+            }
+            state.pending.insert(id, tx);
+        }
+        // If the call is broken, the pending request will be failed once
+        // the receiving side is closed.
+        let _ = self.tx.unbounded_send((req, flags));
+        CorrelatedResponse { rx }
+    }
+
+    /// Count of requests that are waiting for responses.
+    pub fn in_flight(&self) -> usize {
+        self.state.lock().unwrap().pending.len()
+    }
+}
+
+/// A future that resolves to the response of a correlated request.
+pub struct CorrelatedResponse<Resp> {
+    rx: oneshot::Receiver<Result<Resp>>,
+}
+
+impl<Resp> Future for CorrelatedResponse<Resp> {
+    type Item = Resp;
+    type Error = Error;
+
+    fn poll(&mut self) -> Poll<Resp, Error> {
+        match self.rx.poll() {
+            Ok(Async::Ready(res)) => res.map(Async::Ready),
+            Ok(Async::NotReady) => Ok(Async::NotReady),
+            Err(_) => Err(Error::RemoteStopped),
+        }
+    }
+}
@@ -12,9 +12,10 @@
 // limitations under the License.
 
 use std::ptr;
-use std::sync::atomic::{AtomicIsize, Ordering};
+use std::sync::atomic::{AtomicIsize, AtomicU64, AtomicUsize, Ordering};
 use std::sync::Arc;
 use std::thread::ThreadId;
+use std::time::{Duration, Instant};
 
 use grpc_sys::{self, GprClockType, GrpcCompletionQueue};
 
@@ -31,6 +32,9 @@ pub struct CompletionQueueHandle {
     // be shutdown; When `ref_cnt` > 0, completion queue can accept requests
     // and should not be shutdown.
     ref_cnt: AtomicIsize,
+    created: Instant,
+    events: AtomicUsize,
+    busy_nanos: AtomicU64,
 }
 
 unsafe impl Sync for CompletionQueueHandle {}
@@ -41,6 +45,9 @@ impl CompletionQueueHandle {
         CompletionQueueHandle {
             cq: unsafe { grpc_sys::grpc_completion_queue_create_for_next(ptr::null_mut()) },
             ref_cnt: AtomicIsize::new(1),
+            created: Instant::now(),
+            events: AtomicUsize::new(0),
+            busy_nanos: AtomicU64::new(0),
         }
     }
 
@@ -52,9 +59,10 @@ impl CompletionQueueHandle {
                 return Err(Error::QueueShutdown);
             }
             let new_cnt = cnt + 1;
-            if cnt == self
-                .ref_cnt
-                .compare_and_swap(cnt, new_cnt, Ordering::SeqCst)
+            if cnt
+                == self
+                    .ref_cnt
+                    .compare_and_swap(cnt, new_cnt, Ordering::SeqCst)
             {
                 return Ok(());
             }
@@ -67,9 +75,10 @@ impl CompletionQueueHandle {
             // If `shutdown` is not called, `cnt` > 0, so minus 1 to unref.
             // If `shutdown` is called, `cnt` < 0, so plus 1 to unref.
             let new_cnt = cnt - cnt.signum();
-            if cnt == self
-                .ref_cnt
-                .compare_and_swap(cnt, new_cnt, Ordering::SeqCst)
+            if cnt
+                == self
+                    .ref_cnt
+                    .compare_and_swap(cnt, new_cnt, Ordering::SeqCst)
             {
                 break new_cnt == 0;
             }
@@ -92,9 +101,10 @@ impl CompletionQueueHandle {
             // Because `cnt` is initialised to 1, so minus 1 to make it reach
             // toward 0. That is `new_cnt = -(cnt - 1) = -cnt + 1`.
             let new_cnt = -cnt + 1;
-            if cnt == self
-                .ref_cnt
-                .compare_and_swap(cnt, new_cnt, Ordering::SeqCst)
+            if cnt
+                == self
+                    .ref_cnt
+                    .compare_and_swap(cnt, new_cnt, Ordering::SeqCst)
             {
                 break new_cnt == 0;
             }
@@ -164,4 +174,58 @@ impl CompletionQueue {
     pub fn worker_id(&self) -> ThreadId {
         self.id
     }
+
+    /// Record an event that took `busy` to handle by the poll thread.
+    pub(crate) fn record_event(&self, busy: Duration) {
+        let nanos = busy.as_secs() * 1_000_000_000 + u64::from(busy.subsec_nanos());
+        self.handle.events.fetch_add(1, Ordering::Relaxed);
+        self.handle.busy_nanos.fetch_add(nanos, Ordering::Relaxed);
+    }
+
+    pub fn stats(&self) -> CqStats {
+        CqStats {
+            events: self.handle.events.load(Ordering::Relaxed),
+            busy_time: Duration::from_nanos(self.handle.busy_nanos.load(Ordering::Relaxed)),
+            uptime: self.handle.created.elapsed(),
+        }
+    }
+}
+
+/// Statistics of a completion queue and the thread polling it.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct CqStats {
+    events: usize,
+    busy_time: Duration,
+    uptime: Duration,
+}
+
+impl CqStats {
+    /// Count of events that have been processed.
+    pub fn events(&self) -> usize {
+        self.events
+    }
+
+    /// Total time the poll thread spent on handling events, instead of
+    /// waiting for them.
+    pub fn busy_time(&self) -> Duration {
+        self.busy_time
+    }
+
+    /// Time since the completion queue was created.
+    pub fn uptime(&self) -> Duration {
+        self.uptime
+    }
+
+    /// Ratio of busy time to uptime, in `[0, 1]`.
+    ///
+    /// A value close to 1 means the poll thread is saturated and more
+    /// completion queues may help.
+    pub fn utilization(&self) -> f64 {
+        let secs = |d: Duration| d.as_secs() as f64 + f64::from(d.subsec_nanos()) / 1e9;
+        let uptime = secs(self.uptime);
+        if uptime <= 0.0 {
+            return 0.0;
+        }
+        (secs(self.busy_time) / uptime).min(1.0)
+    }
 }
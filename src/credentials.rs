@@ -14,10 +14,29 @@
 use std::ffi::CString;
 use std::ptr;
 
-use error::{Error, Result};
-use grpc_sys::{self, GrpcChannelCredentials, GrpcServerCredentials};
+use grpc_sys;
+#[cfg(feature = "tls-server")]
+use grpc_sys::GrpcServerCredentials;
 use libc::c_char;
 
+#[cfg(feature = "tls-client")]
+use self::client_imports::*;
+
+#[cfg(feature = "tls-client")]
+mod client_imports {
+    pub use std::ffi::CStr;
+    pub use std::time::Duration;
+
+    pub use call::{RpcStatus, RpcStatusCode};
+    pub use error::{Error, Result};
+    pub use grpc_sys::{
+        GrpcAuthMetadataContext, GrpcCallCredentials, GrpcChannelCredentials,
+        GrpcCredentialsPluginMetadataCb, GrpcMetadata, GrpcMetadataCredentialsPlugin,
+    };
+    pub use libc::{c_int, c_void, size_t};
+    pub use metadata::Metadata;
+}
+
 fn clear_key_securely(key: &mut [u8]) {
     unsafe {
         for b in key {
@@ -27,6 +46,7 @@ fn clear_key_securely(key: &mut [u8]) {
 }
 
 /// [`ServerCredentials`] factory in order to configure the properties.
+#[cfg(feature = "tls-server")]
 pub struct ServerCredentialsBuilder {
     root: Option<CString>,
     cert_chains: Vec<*mut c_char>,
@@ -34,6 +54,7 @@ pub struct ServerCredentialsBuilder {
     force_client_auth: bool,
 }
 
+#[cfg(feature = "tls-server")]
 impl ServerCredentialsBuilder {
     /// Initialize a new [`ServerCredentialsBuilder`].
     pub fn new() -> ServerCredentialsBuilder {
@@ -102,6 +123,7 @@ impl ServerCredentialsBuilder {
     }
 }
 
+#[cfg(feature = "tls-server")]
 impl Drop for ServerCredentialsBuilder {
     fn drop(&mut self) {
         for cert in self.cert_chains.drain(..) {
@@ -119,16 +141,24 @@ impl Drop for ServerCredentialsBuilder {
 /// Server-side SSL credentials.
 ///
 /// Use [`ServerCredentialsBuilder`] to build a [`ServerCredentials`].
+///
+/// gRPC core always requires the client to negotiate `h2` with ALPN, it
+/// can't be relaxed for proxies that strip ALPN. Such connections fail the
+/// handshake, which is only logged by gRPC core; the client sees its calls
+/// fail with `Unavailable`.
+#[cfg(feature = "tls-server")]
 pub struct ServerCredentials {
     creds: *mut GrpcServerCredentials,
 }
 
+#[cfg(feature = "tls-server")]
 impl ServerCredentials {
     pub fn as_mut_ptr(&mut self) -> *mut GrpcServerCredentials {
         self.creds
     }
 }
 
+#[cfg(feature = "tls-server")]
 impl Drop for ServerCredentials {
     fn drop(&mut self) {
         unsafe { grpc_sys::grpc_server_credentials_release(self.creds) }
@@ -136,11 +166,13 @@ impl Drop for ServerCredentials {
 }
 
 /// [`ChannelCredentials`] factory in order to configure the properties.
+#[cfg(feature = "tls-client")]
 pub struct ChannelCredentialsBuilder {
     root: Option<CString>,
     cert_key_pair: Option<(CString, CString)>,
 }
 
+#[cfg(feature = "tls-client")]
 impl ChannelCredentialsBuilder {
     /// Initialize a new [`ChannelCredentialsBuilder`].
     pub fn new() -> ChannelCredentialsBuilder {
@@ -203,6 +235,7 @@ impl ChannelCredentialsBuilder {
     }
 }
 
+#[cfg(feature = "tls-client")]
 impl Drop for ChannelCredentialsBuilder {
     fn drop(&mut self) {
         if let Some((_, key)) = self.cert_key_pair.take() {
@@ -215,10 +248,15 @@ impl Drop for ChannelCredentialsBuilder {
 ///
 /// Use [`ChannelCredentialsBuilder`] or [`ChannelCredentials::google_default_credentials`] to
 /// build a [`ChannelCredentials`].
+///
+/// The server has to select `h2` with ALPN, otherwise the handshake fails and
+/// the calls fail with `Unavailable`.
+#[cfg(feature = "tls-client")]
 pub struct ChannelCredentials {
     creds: *mut GrpcChannelCredentials,
 }
 
+#[cfg(feature = "tls-client")]
 impl ChannelCredentials {
     pub fn as_mut_ptr(&mut self) -> *mut GrpcChannelCredentials {
         self.creds
@@ -239,10 +277,215 @@ impl ChannelCredentials {
             Ok(ChannelCredentials { creds })
         }
     }
+
+    /// Attach `call_creds` to every call made with the credentials.
+    pub fn with_call_credentials(self, call_creds: CallCredentials) -> ChannelCredentials {
+        let creds = unsafe {
+            grpc_sys::grpc_composite_channel_credentials_create(
+                self.creds,
+                call_creds.creds,
+                ptr::null_mut(),
+            )
+        };
+        ChannelCredentials { creds }
+    }
 }
 
+#[cfg(feature = "tls-client")]
 impl Drop for ChannelCredentials {
     fn drop(&mut self) {
         unsafe { grpc_sys::grpc_channel_credentials_release(self.creds) }
     }
 }
+
+/// The context of a call that requests auth metadata.
+#[cfg(feature = "tls-client")]
+#[derive(Debug, Clone)]
+pub struct AuthMetadataContext {
+    /// The url of the service, for example `https://foo.example.com/helloworld.Greeter`.
+    pub service_url: String,
+    /// The name of the method, for example `SayHello`.
+    pub method_name: String,
+}
+
+#[cfg(feature = "tls-client")]
+fn lossy_string(s: *const c_char) -> String {
+    if s.is_null() {
+        return String::new();
+    }
+    unsafe { CStr::from_ptr(s).to_string_lossy().into_owned() }
+}
+
+/// A sink to deliver the metadata requested by gRPC core.
+///
+/// If it's dropped without calling any method, the call fails with `Internal`.
+#[cfg(feature = "tls-client")]
+pub struct AuthMetadataSink {
+    cb: GrpcCredentialsPluginMetadataCb,
+    user_data: *mut c_void,
+    notified: bool,
+}
+
+// The callback can be invoked from any thread.
+#[cfg(feature = "tls-client")]
+unsafe impl Send for AuthMetadataSink {}
+
+#[cfg(feature = "tls-client")]
+impl AuthMetadataSink {
+    fn notify(&mut self, metadata: Option<Metadata>, status: RpcStatus) {
+        self.notified = true;
+        let mut metadata = metadata;
+        let details = status
+            .details
+            .map(|d| CString::new(d.replace('\0', "")).unwrap());
+        unsafe {
+            grpc_sys::grpcwrap_metadata_credentials_notify_from_plugin(
+                self.cb,
+                self.user_data,
+                metadata
+                    .as_mut()
+                    .map_or_else(ptr::null_mut, |m| m as *mut _ as _),
+                status.status,
+                details.as_ref().map_or_else(ptr::null, |d| d.as_ptr()),
+            )
+        }
+    }
+
+    /// Attach `metadata` to the call.
+    pub fn success(mut self, metadata: Metadata) {
+        self.notify(Some(metadata), RpcStatus::ok())
+    }
+
+    /// Fail the call with `status`.
+    pub fn fail(mut self, status: RpcStatus) {
+        assert_ne!(status.status, RpcStatusCode::Ok);
+        self.notify(None, status)
+    }
+}
+
+#[cfg(feature = "tls-client")]
+impl Drop for AuthMetadataSink {
+    fn drop(&mut self) {
+        if !self.notified {
+            self.notify(
+                None,
+                RpcStatus::internal("auth metadata sink is dropped without result"),
+            )
+        }
+    }
+}
+
+/// A provider of auth metadata, tokens for example, for every call.
+///
+/// `get_metadata` is called before sending each call. It should not block,
+/// and can deliver the metadata later via the `sink`, which makes it possible
+/// to fetch or refresh the tokens asynchronously.
+#[cfg(feature = "tls-client")]
+pub trait CallCredentialsProvider: Send + Sync + 'static {
+    fn get_metadata(&self, ctx: AuthMetadataContext, sink: AuthMetadataSink);
+}
+
+#[cfg(feature = "tls-client")]
+extern "C" fn plugin_get_metadata(
+    state: *mut c_void,
+    context: GrpcAuthMetadataContext,
+    cb: GrpcCredentialsPluginMetadataCb,
+    user_data: *mut c_void,
+    _: *mut GrpcMetadata,
+    _: *mut size_t,
+    _: *mut RpcStatusCode,
+    _: *mut *const c_char,
+) -> c_int {
+    let provider = unsafe { &*(state as *const Box<CallCredentialsProvider>) };
+    let ctx = AuthMetadataContext {
+        service_url: lossy_string(context.service_url),
+        method_name: lossy_string(context.method_name),
+    };
+    let sink = AuthMetadataSink {
+        cb,
+        user_data,
+        notified: false,
+    };
+    provider.get_metadata(ctx, sink);
+    // The metadata is always delivered asynchronously.
+    0
+}
+
+#[cfg(feature = "tls-client")]
+extern "C" fn plugin_destroy(state: *mut c_void) {
+    unsafe {
+        Box::from_raw(state as *mut Box<CallCredentialsProvider>);
+    }
+}
+
+#[cfg(feature = "tls-client")]
+const PLUGIN_TYPE: &[u8] = b"grpcio_rust_plugin\0";
+
+/// Credentials that attach auth metadata to calls.
+///
+/// Use [`ChannelCredentials::with_call_credentials`] to apply them to a channel.
+#[cfg(feature = "tls-client")]
+pub struct CallCredentials {
+    creds: *mut GrpcCallCredentials,
+}
+
+#[cfg(feature = "tls-client")]
+impl CallCredentials {
+    /// Create call credentials whose metadata is provided by `provider`.
+    pub fn from_provider<P: CallCredentialsProvider>(provider: P) -> CallCredentials {
+        let state: Box<Box<CallCredentialsProvider>> = Box::new(Box::new(provider));
+        let plugin = GrpcMetadataCredentialsPlugin {
+            get_metadata: plugin_get_metadata,
+            destroy: plugin_destroy,
+            state: Box::into_raw(state) as _,
+            type_: PLUGIN_TYPE.as_ptr() as _,
+        };
+        let creds = unsafe {
+            grpc_sys::grpc_metadata_credentials_create_from_plugin(plugin, ptr::null_mut())
+        };
+        CallCredentials { creds }
+    }
+
+    /// Create call credentials that sign a JWT with a Google service account key
+    /// and use it as the access token.
+    ///
+    /// `json_key` is the content of the JSON key file downloaded from the Google
+    /// Cloud console, and `token_lifetime` is how long a signed token is valid.
+    pub fn service_account_jwt_access(
+        json_key: &str,
+        token_lifetime: Duration,
+    ) -> Result<CallCredentials> {
+        // Like `ChannelCredentials::google_default_credentials`, this can be
+        // called before construction of an `Environment`.
+        unsafe {
+            grpc_sys::grpc_init();
+        }
+        let key = match CString::new(json_key) {
+            Ok(k) => k,
+            Err(_) => return Err(Error::GoogleAuthenticationFailed),
+        };
+        let creds = unsafe {
+            grpc_sys::grpc_service_account_jwt_access_credentials_create(
+                key.as_ptr(),
+                token_lifetime.into(),
+                ptr::null_mut(),
+            )
+        };
+        if creds.is_null() {
+            Err(Error::GoogleAuthenticationFailed)
+        } else {
+            Ok(CallCredentials { creds })
+        }
+    }
+
+    pub fn as_mut_ptr(&mut self) -> *mut GrpcCallCredentials {
+        self.creds
+    }
+}
+
+#[cfg(feature = "tls-client")]
+impl Drop for CallCredentials {
+    fn drop(&mut self) {
+        unsafe { grpc_sys::grpc_call_credentials_release(self.creds) }
+    }
+}
@@ -0,0 +1,281 @@
+// Copyright 2018 PingCAP, Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Runtime lookup of proto descriptors.
+//!
+//! A [`DescriptorPool`] maps the full names of messages, enums and services,
+//! e.g. `helloworld.HelloRequest`, to their descriptors and the files that
+//! define them. Files are added by passing the `file_descriptor_proto` of
+//! the generated modules to [`DescriptorPool::register`], or to [`register`]
+//! for the process wide pool:
+//!
+//! ```ignore
+//! grpcio::descriptor::register(helloworld::file_descriptor_proto()).unwrap();
+//! let pool = grpcio::descriptor::global().read().unwrap();
+//! let (service, method) = pool.method("/helloworld.Greeter/SayHello").unwrap();
+//! ```
+//!
+//! [`DescriptorPool`]: struct.DescriptorPool.html
+//! [`DescriptorPool::register`]: struct.DescriptorPool.html#method.register
+//! [`register`]: fn.register.html
+
+use std::collections::HashMap;
+use std::sync::{Once, RwLock};
+
+use protobuf::descriptor::{
+    DescriptorProto, EnumDescriptorProto, FileDescriptorProto, MethodDescriptorProto,
+    ServiceDescriptorProto,
+};
+
+use error::{Error, Result};
+
+#[derive(Clone, Copy)]
+enum Symbol {
+    Message(&'static DescriptorProto),
+    Enum(&'static EnumDescriptorProto),
+    Service(&'static ServiceDescriptorProto),
+}
+
+struct Entry {
+    file: &'static FileDescriptorProto,
+    symbol: Symbol,
+}
+
+/// A set of file descriptors indexed by the names they define.
+#[derive(Default)]
+pub struct DescriptorPool {
+    files: HashMap<String, &'static FileDescriptorProto>,
+    symbols: HashMap<String, Entry>,
+}
+
+impl DescriptorPool {
+    pub fn new() -> DescriptorPool {
+        DescriptorPool::default()
+    }
+
+    /// Add `file` and everything it defines.
+    ///
+    /// Registering a file again does nothing. It fails without changing the
+    /// pool if any name is already defined by another file.
+    pub fn register(&mut self, file: &'static FileDescriptorProto) -> Result<()> {
+        if self.files.contains_key(file.get_name()) {
+            return Ok(());
+        }
+        let mut symbols = vec![];
+        let package = file.get_package();
+        for m in file.get_message_type() {
+            collect_message(package, m, &mut symbols);
+        }
+        for e in file.get_enum_type() {
+            symbols.push((full_name(package, e.get_name()), Symbol::Enum(e)));
+        }
+        for s in file.get_service() {
+            symbols.push((full_name(package, s.get_name()), Symbol::Service(s)));
+        }
+        for &(ref name, _) in &symbols {
+            if let Some(e) = self.symbols.get(name) {
+                return Err(Error::InvalidConfig(format!(
+                    "{} in {} is already defined in {}",
+                    name,
+                    file.get_name(),
+                    e.file.get_name()
+                )));
+            }
+        }
+        self.files.insert(file.get_name().to_owned(), file);
+        for (name, symbol) in symbols {
+            self.symbols.insert(name, Entry { file, symbol });
+        }
+        Ok(())
+    }
+
+    /// Get the file registered by its name, e.g. `helloworld.proto`.
+    pub fn file(&self, name: &str) -> Option<&'static FileDescriptorProto> {
+        self.files.get(name).cloned()
+    }
+
+    /// Get the file that defines the message, enum or service `name`.
+    pub fn file_containing_symbol(&self, name: &str) -> Option<&'static FileDescriptorProto> {
+        self.symbols.get(name).map(|e| e.file)
+    }
+
+    pub fn message(&self, name: &str) -> Option<&'static DescriptorProto> {
+        match self.symbols.get(name)?.symbol {
+            Symbol::Message(m) => Some(m),
+            _ => None,
+        }
+    }
+
+    pub fn enum_type(&self, name: &str) -> Option<&'static EnumDescriptorProto> {
+        match self.symbols.get(name)?.symbol {
+            Symbol::Enum(e) => Some(e),
+            _ => None,
+        }
+    }
+
+    pub fn service(&self, name: &str) -> Option<&'static ServiceDescriptorProto> {
+        match self.symbols.get(name)?.symbol {
+            Symbol::Service(s) => Some(s),
+            _ => None,
+        }
+    }
+
+    /// Get the method of a call path, e.g. `/helloworld.Greeter/SayHello`,
+    /// with the service defining it.
+    pub fn method(
+        &self,
+        path: &str,
+    ) -> Option<(
+        &'static ServiceDescriptorProto,
+        &'static MethodDescriptorProto,
+    )> {
+        let path = if path.starts_with('/') {
+            &path[1..]
+        } else {
+            path
+        };
+        let mut parts = path.splitn(2, '/');
+        let service = self.service(parts.next()?)?;
+        let method = parts.next()?;
+        service
+            .get_method()
+            .iter()
+            .find(|m| m.get_name() == method)
+            .map(|m| (service, m))
+    }
+
+    /// Get the full names of all the services, sorted.
+    pub fn service_names(&self) -> Vec<&str> {
+        let mut names: Vec<_> = self
+            .symbols
+            .iter()
+            .filter_map(|(name, e)| match e.symbol {
+                Symbol::Service(_) => Some(name.as_str()),
+                _ => None,
+            })
+            .collect();
+        names.sort();
+        names
+    }
+}
+
+fn full_name(scope: &str, name: &str) -> String {
+    if scope.is_empty() {
+        name.to_owned()
+    } else {
+        format!("{}.{}", scope, name)
+    }
+}
+
+fn collect_message(scope: &str, m: &'static DescriptorProto, symbols: &mut Vec<(String, Symbol)>) {
+    let name = full_name(scope, m.get_name());
+    for nested in m.get_nested_type() {
+        collect_message(&name, nested, symbols);
+    }
+    for e in m.get_enum_type() {
+        symbols.push((full_name(&name, e.get_name()), Symbol::Enum(e)));
+    }
+    symbols.push((name, Symbol::Message(m)));
+}
+
+/// Get the process wide pool.
+pub fn global() -> &'static RwLock<DescriptorPool> {
+    static INIT: Once = Once::new();
+    static mut POOL: *const RwLock<DescriptorPool> = 0 as *const _;
+    unsafe {
+        INIT.call_once(|| POOL = Box::into_raw(Box::new(RwLock::new(DescriptorPool::new()))));
+        &*POOL
+    }
+}
+
+/// Add `file` to the process wide pool, see [`DescriptorPool::register`].
+///
+/// [`DescriptorPool::register`]: struct.DescriptorPool.html#method.register
+pub fn register(file: &'static FileDescriptorProto) -> Result<()> {
+    global().write().unwrap().register(file)
+}
+
+#[cfg(test)]
+mod tests {
+    use protobuf::descriptor;
+
+    use super::*;
+
+    fn greeter_file(name: &str) -> &'static FileDescriptorProto {
+        let mut method = MethodDescriptorProto::new();
+        method.set_name("SayHello".to_owned());
+        method.set_input_type(".helloworld.HelloRequest".to_owned());
+        method.set_output_type(".helloworld.HelloReply".to_owned());
+        let mut service = ServiceDescriptorProto::new();
+        service.set_name("Greeter".to_owned());
+        service.mut_method().push(method);
+        let mut file = FileDescriptorProto::new();
+        file.set_name(name.to_owned());
+        file.set_package("helloworld".to_owned());
+        for msg in &["HelloRequest", "HelloReply"] {
+            let mut m = DescriptorProto::new();
+            m.set_name(msg.to_string());
+            file.mut_message_type().push(m);
+        }
+        file.mut_service().push(service);
+        Box::leak(Box::new(file))
+    }
+
+    #[test]
+    fn test_descriptor_pool() {
+        let mut pool = DescriptorPool::new();
+        let desc = descriptor::file_descriptor_proto();
+        pool.register(desc).unwrap();
+        pool.register(desc).unwrap();
+        assert!(pool.message("google.protobuf.DescriptorProto").is_some());
+        let range = pool
+            .message("google.protobuf.DescriptorProto.ExtensionRange")
+            .unwrap();
+        assert_eq!(range.get_name(), "ExtensionRange");
+        assert!(pool
+            .enum_type("google.protobuf.FieldDescriptorProto.Type")
+            .is_some());
+        assert!(pool
+            .message("google.protobuf.FieldDescriptorProto.Type")
+            .is_none());
+        assert_eq!(
+            pool.file_containing_symbol("google.protobuf.FileOptions")
+                .unwrap()
+                .get_name(),
+            desc.get_name()
+        );
+
+        pool.register(greeter_file("helloworld.proto")).unwrap();
+        assert_eq!(pool.service_names(), vec!["helloworld.Greeter"]);
+        let (service, method) = pool.method("/helloworld.Greeter/SayHello").unwrap();
+        assert_eq!(service.get_name(), "Greeter");
+        assert_eq!(method.get_input_type(), ".helloworld.HelloRequest");
+        assert!(pool.method("/helloworld.Greeter/SayBye").is_none());
+        assert!(pool.method("/helloworld.Unknown/SayHello").is_none());
+        assert!(pool.file("helloworld.proto").is_some());
+
+        // Names can't be defined twice.
+        match pool.register(greeter_file("other.proto")) {
+            Err(Error::InvalidConfig(_)) => {}
+            _ => panic!("conflicting names should be rejected"),
+        }
+        assert!(pool.file("other.proto").is_none());
+
+        register(desc).unwrap();
+        assert!(global()
+            .read()
+            .unwrap()
+            .message("google.protobuf.FileDescriptorSet")
+            .is_some());
+    }
+}
@@ -11,14 +11,23 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
+use std::env;
+use std::ffi::CStr;
 use std::sync::atomic::{AtomicUsize, Ordering};
 use std::sync::Arc;
 use std::thread::{self, Builder as ThreadBuilder, JoinHandle};
+use std::time::Instant;
+#[cfg(target_os = "linux")]
+use std::{io, mem};
 
 use grpc_sys;
+#[cfg(target_os = "linux")]
+use libc;
 
-use async::CallTag;
-use cq::{CompletionQueue, CompletionQueueHandle, EventType};
+use alloc;
+use async::{self, CallTag};
+use cq::{CompletionQueue, CompletionQueueHandle, CqStats, EventType};
+use error::{Error, Result};
 
 // event loop
 fn poll_queue(cq: Arc<CompletionQueueHandle>) {
@@ -33,9 +42,64 @@ fn poll_queue(cq: Arc<CompletionQueueHandle>) {
             EventType::OpComplete => {}
         }
 
-        let tag: Box<CallTag> = unsafe { Box::from_raw(e.tag as _) };
+        let start = Instant::now();
+        let tag = unsafe { CallTag::from_raw(e.tag as _) };
 
         tag.resolve(&cq, e.success != 0);
+        cq.record_event(start.elapsed());
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn pin_to_core(core: usize) {
+    unsafe {
+        let mut set: libc::cpu_set_t = mem::zeroed();
+        libc::CPU_SET(core, &mut set);
+        if libc::sched_setaffinity(0, mem::size_of_val(&set), &set) != 0 {
+            warn!(
+                "failed to pin poll thread to core {}: {}",
+                core,
+                io::Error::last_os_error()
+            );
+        }
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn pin_to_core(_: usize) {}
+
+/// The resolver used to resolve dns names.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum DnsResolver {
+    /// Resolve names with `getaddrinfo`.
+    Native,
+    /// Resolve names with c-ares, which supports custom dns servers and SRV records.
+    Ares,
+}
+
+const POLL_STRATEGY_ENV: &str = "GRPC_POLL_STRATEGY";
+
+/// The engine used to poll file descriptors.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum PollStrategy {
+    /// Linux only, use epoll and signals to wake up polling threads.
+    EpollSig,
+    /// Linux only, experimental, use epoll with multiple pollsets.
+    Epollex,
+    /// Linux only, use epoll with a single pollset.
+    Epoll1,
+    /// Use `poll`, it's available on all posix platforms.
+    Poll,
+}
+
+impl PollStrategy {
+    fn name(self) -> &'static str {
+        match self {
+            PollStrategy::EpollSig => "epollsig",
+            PollStrategy::Epollex => "epollex",
+            PollStrategy::Epoll1 => "epoll1",
+            PollStrategy::Poll => "poll",
+        }
     }
 }
 
@@ -43,6 +107,9 @@ fn poll_queue(cq: Arc<CompletionQueueHandle>) {
 pub struct EnvBuilder {
     cq_count: usize,
     name_prefix: Option<String>,
+    dns_resolver: Option<DnsResolver>,
+    poll_strategy: Option<PollStrategy>,
+    cores: Option<Vec<usize>>,
 }
 
 impl EnvBuilder {
@@ -51,17 +118,17 @@ impl EnvBuilder {
         EnvBuilder {
             cq_count: unsafe { grpc_sys::gpr_cpu_num_cores() as usize },
             name_prefix: None,
+            dns_resolver: None,
+            poll_strategy: None,
+            cores: None,
         }
     }
 
     /// Set the number of completion queues and polling threads. Each thread polls
     /// one completion queue.
     ///
-    /// # Panics
-    ///
-    /// This method will panic if `count` is 0.
+    /// `count` should be larger than 0.
     pub fn cq_count(mut self, count: usize) -> EnvBuilder {
-        assert!(count > 0);
         self.cq_count = count;
         self
     }
@@ -72,11 +139,100 @@ impl EnvBuilder {
         self
     }
 
+    /// Set the resolver used to resolve dns names.
+    ///
+    /// gRPC picks the resolver when the library is initialized, so it's a process
+    /// wide setting and only takes effect if no other [`Environment`] is alive.
+    /// It's passed by the `GRPC_DNS_RESOLVER` environment variable, see
+    /// [`poll_strategy`](#method.poll_strategy) for the caveat.
+    pub fn dns_resolver(mut self, resolver: DnsResolver) -> EnvBuilder {
+        self.dns_resolver = Some(resolver);
+        self
+    }
+
+    /// Set the engine used to poll file descriptors.
+    ///
+    /// By default gRPC picks the best one available on the platform, or the ones
+    /// listed by the `GRPC_POLL_STRATEGY` environment variable. Just like
+    /// [`dns_resolver`], it's a process wide setting. If `strategy` is not
+    /// supported by the platform, gRPC falls back to its default, use
+    /// [`Environment::poll_strategy`] to check the one in use.
+    ///
+    /// gRPC core only reads the setting from the environment, so it's passed
+    /// by setting the environment variable, same as [`dns_resolver`]. Setting
+    /// it is not synchronized with other threads that read or write the
+    /// environment, so the environment should be built before such threads
+    /// are started.
+    ///
+    /// [`dns_resolver`]: #method.dns_resolver
+    /// [`Environment::poll_strategy`]: struct.Environment.html#method.poll_strategy
+    pub fn poll_strategy(mut self, strategy: PollStrategy) -> EnvBuilder {
+        self.poll_strategy = Some(strategy);
+        self
+    }
+
+    /// Run one polling thread on each of `cores`, all of them if it's empty,
+    /// and pin the threads to their cores. It overrides [`cq_count`].
+    ///
+    /// Calls accepted by a server are handled in the thread that polls the
+    /// queue they arrive on, and futures spawned by handlers are polled by
+    /// the same thread. So a call never leaves its core, unless handlers
+    /// hand it over to other threads by themselves.
+    ///
+    /// [`cq_count`]: #method.cq_count
+    #[cfg(target_os = "linux")]
+    pub fn thread_per_core(mut self, cores: Vec<usize>) -> EnvBuilder {
+        self.cores = Some(if cores.is_empty() {
+            (0..unsafe { grpc_sys::gpr_cpu_num_cores() as usize }).collect()
+        } else {
+            cores
+        });
+        self
+    }
+
     /// Finalize the [`EnvBuilder`], build the [`Environment`] and initialize the gRPC library.
+    ///
+    /// # Panics
+    ///
+    /// This method will panic if the configuration is invalid, see [`try_build`].
+    ///
+    /// [`try_build`]: #method.try_build
     pub fn build(self) -> Environment {
-        unsafe {
-            grpc_sys::grpc_init();
+        self.try_build().unwrap()
+    }
+
+    /// Same as [`build`], but returns an error if the configuration is invalid.
+    ///
+    /// [`build`]: #method.build
+    pub fn try_build(mut self) -> Result<Environment> {
+        if let Some(ref cores) = self.cores {
+            self.cq_count = cores.len();
+        }
+        if self.cq_count == 0 {
+            return Err(Error::InvalidConfig(
+                "completion queue count must be larger than 0".to_owned(),
+            ));
+        }
+        if let Some(strategy) = self.poll_strategy {
+            env::set_var(POLL_STRATEGY_ENV, strategy.name());
         }
+        if let Some(resolver) = self.dns_resolver {
+            let name = match resolver {
+                DnsResolver::Native => "native",
+                DnsResolver::Ares => "ares",
+            };
+            env::set_var("GRPC_DNS_RESOLVER", name);
+        }
+        alloc::mark_started();
+        let poll_strategy = unsafe {
+            grpc_sys::grpc_init();
+            let name = grpc_sys::grpcwrap_poll_strategy_name();
+            if name.is_null() {
+                None
+            } else {
+                Some(CStr::from_ptr(name).to_string_lossy().into_owned())
+            }
+        };
         let mut cqs = Vec::with_capacity(self.cq_count);
         let mut handles = Vec::with_capacity(self.cq_count);
         for i in 0..self.cq_count {
@@ -86,16 +242,25 @@ impl EnvBuilder {
             if let Some(ref prefix) = self.name_prefix {
                 builder = builder.name(format!("{}-{}", prefix, i));
             }
-            let handle = builder.spawn(move || poll_queue(cq_)).unwrap();
+            let core = self.cores.as_ref().map(|c| c[i]);
+            let handle = builder
+                .spawn(move || {
+                    if let Some(core) = core {
+                        pin_to_core(core);
+                    }
+                    poll_queue(cq_)
+                })
+                .unwrap();
             cqs.push(CompletionQueue::new(cq, handle.thread().id()));
             handles.push(handle);
         }
 
-        Environment {
+        Ok(Environment {
             cqs,
             idx: AtomicUsize::new(0),
             _handles: handles,
-        }
+            poll_strategy,
+        })
     }
 }
 
@@ -104,6 +269,7 @@ pub struct Environment {
     cqs: Vec<CompletionQueue>,
     idx: AtomicUsize,
     _handles: Vec<JoinHandle<()>>,
+    poll_strategy: Option<String>,
 }
 
 impl Environment {
@@ -127,11 +293,70 @@ impl Environment {
         self.cqs.as_slice()
     }
 
+    /// Get the name of the engine gRPC uses to poll file descriptors, e.g.
+    /// `epollsig`, which may differ from the requested one if it's not
+    /// supported by the platform. It's `None` on platforms other than posix.
+    pub fn poll_strategy(&self) -> Option<&str> {
+        self.poll_strategy.as_ref().map(|s| s.as_str())
+    }
+
     /// Pick an arbitrary completion queue.
     pub fn pick_cq(&self) -> CompletionQueue {
         let idx = self.idx.fetch_add(1, Ordering::Relaxed);
         self.cqs[idx % self.cqs.len()].clone()
     }
+
+    /// Get the statistics of the completion queues and their poll threads.
+    pub fn stats(&self) -> EnvStats {
+        EnvStats {
+            cqs: self.cqs.iter().map(CompletionQueue::stats).collect(),
+            pending: async::pending_tags(),
+        }
+    }
+
+    /// Describe the operations waiting for completion in all the environments
+    /// of the process, e.g. to find out what a hanging shutdown waits for.
+    ///
+    /// Operations are only tracked when the `tag-slab` feature is enabled,
+    /// `None` is returned otherwise.
+    pub fn pending_operations(&self) -> Option<Vec<String>> {
+        async::outstanding_tags()
+    }
+}
+
+/// Statistics of an [`Environment`].
+///
+/// [`Environment`]: struct.Environment.html
+#[derive(Clone, Debug)]
+pub struct EnvStats {
+    cqs: Vec<CqStats>,
+    pending: usize,
+}
+
+impl EnvStats {
+    /// Statistics of each completion queue, in the same order as
+    /// [`Environment::completion_queues`].
+    ///
+    /// [`Environment::completion_queues`]: struct.Environment.html#method.completion_queues
+    pub fn completion_queues(&self) -> &[CqStats] {
+        &self.cqs
+    }
+
+    /// Count of operations waiting for completion.
+    ///
+    /// gRPC core doesn't tell the queue depth of each completion queue, so
+    /// it's counted across all the environments of the process.
+    pub fn pending_operations(&self) -> usize {
+        self.pending
+    }
+
+    /// The highest utilization among the poll threads.
+    pub fn max_utilization(&self) -> f64 {
+        self.cqs
+            .iter()
+            .map(CqStats::utilization)
+            .fold(0.0, f64::max)
+    }
 }
 
 impl Drop for Environment {
@@ -174,4 +399,62 @@ mod tests {
             handle.join().unwrap();
         }
     }
+
+    #[test]
+    fn test_poll_strategy() {
+        let env = EnvBuilder::new()
+            .cq_count(1)
+            .poll_strategy(PollStrategy::Poll)
+            .build();
+        // Other tests may have initialized gRPC with another engine.
+        if cfg!(unix) {
+            assert!(env.poll_strategy().is_some());
+        }
+    }
+
+    #[test]
+    fn test_stats() {
+        let env = Environment::new(2);
+        let stats = env.stats();
+        assert_eq!(stats.completion_queues().len(), 2);
+        for s in stats.completion_queues() {
+            assert!(s.busy_time() <= s.uptime());
+            assert!(s.utilization() >= 0.0 && s.utilization() <= 1.0);
+        }
+        assert!(stats.max_utilization() <= 1.0);
+    }
+
+    #[test]
+    #[cfg(target_os = "linux")]
+    fn test_thread_per_core() {
+        use std::sync::mpsc;
+
+        use async::Executor;
+        use futures::future;
+
+        let env = EnvBuilder::new()
+            .cq_count(3)
+            .thread_per_core(vec![0])
+            .build();
+        assert_eq!(env.completion_queues().len(), 1);
+        let (tx, rx) = mpsc::channel();
+        Executor::new(&env.pick_cq()).spawn(future::lazy(move || {
+            let mut set: libc::cpu_set_t = unsafe { mem::zeroed() };
+            unsafe { libc::sched_getaffinity(0, mem::size_of_val(&set), &mut set) };
+            let cores: Vec<_> = (0..libc::CPU_SETSIZE as usize)
+                .filter(|&c| unsafe { libc::CPU_ISSET(c, &set) })
+                .collect();
+            tx.send(cores).unwrap();
+            Ok(())
+        }));
+        assert_eq!(rx.recv().unwrap(), vec![0]);
+    }
+
+    #[test]
+    fn test_invalid_cq_count() {
+        match EnvBuilder::new().cq_count(0).try_build() {
+            Err(Error::InvalidConfig(_)) => {}
+            _ => panic!("zero completion queue should be rejected"),
+        }
+    }
 }
@@ -19,6 +19,7 @@ use grpc_sys::GrpcCallStatus;
 use protobuf::ProtobufError;
 
 use call::RpcStatus;
+use metadata::Metadata;
 
 /// Errors generated from this library.
 #[derive(Debug)]
@@ -39,10 +40,12 @@ pub enum Error {
     BindFail(String, u16),
     /// gRPC completion queue is shutdown.
     QueueShutdown,
-    /// Failed to create Google default credentials.
+    /// Failed to create Google default credentials or service account credentials.
     GoogleAuthenticationFailed,
     /// Invalid format of metadata.
     InvalidMetadata(String),
+    /// Invalid configuration of a builder.
+    InvalidConfig(String),
 }
 
 impl Display for Error {
@@ -64,6 +67,7 @@ impl error::Error for Error {
             Error::QueueShutdown => "gRPC completion queue shutdown",
             Error::GoogleAuthenticationFailed => "Could not create google default credentials.",
             Error::InvalidMetadata(_) => "invalid format of metadata",
+            Error::InvalidConfig(_) => "invalid configuration",
         }
     }
 
@@ -73,6 +77,34 @@ impl error::Error for Error {
             _ => None,
         }
     }
+
+    fn source(&self) -> Option<&(error::Error + 'static)> {
+        match *self {
+            Error::Codec(ref e) => Some(e.as_ref()),
+            _ => None,
+        }
+    }
+}
+
+impl Error {
+    /// The status of the failed rpc call, if any.
+    pub fn status(&self) -> Option<&RpcStatus> {
+        match *self {
+            Error::RpcFailure(ref s) | Error::RpcFinished(Some(ref s)) => Some(s),
+            _ => None,
+        }
+    }
+
+    /// The trailing metadata received along with the failed status.
+    pub fn trailers(&self) -> Option<&Metadata> {
+        self.status().and_then(|s| s.trailers())
+    }
+
+    /// Whether the error is transient, which means the request is safe to be
+    /// retried.
+    pub fn is_transient(&self) -> bool {
+        self.status().map_or(false, |s| s.is_transient())
+    }
 }
 
 #[cfg(feature = "protobuf-codec")]
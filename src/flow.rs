@@ -0,0 +1,195 @@
+// Copyright 2018 PingCAP, Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Backpressure of the calls in progress.
+//!
+//! gRPC core doesn't expose the HTTP/2 flow control windows of its streams.
+//! Their effect can be observed from the messages though: a message handed
+//! to gRPC core is only written once the stream and the connection have
+//! enough window, so a write that stays pending means the client doesn't
+//! read fast enough. The other way around, a handler that doesn't take the
+//! request messages stops gRPC core from reading the stream, and the client
+//! runs out of window.
+//!
+//! When enabled by [`ServerBuilder::flow_stats`], [`Server::stream_flows`]
+//! reports both for every call in progress:
+//!
+//! ```ignore
+//! for f in server.stream_flows() {
+//!     if let Some((waited, bytes)) = f.send_pending() {
+//!         if waited > Duration::from_secs(10) {
+//!             warn!("{} from {} is stuck writing {} bytes", f.method(), f.peer(), bytes);
+//!         }
+//!     }
+//! }
+//! ```
+//!
+//! [`ServerBuilder::flow_stats`]: ../struct.ServerBuilder.html#method.flow_stats
+//! [`Server::stream_flows`]: ../struct.Server.html#method.stream_flows
+
+use std::sync::{Arc, Mutex, Weak};
+use std::time::Duration;
+
+use call::server::{CallTracker, RpcContext};
+
+/// The backpressure of a call in progress.
+#[derive(Clone, Debug)]
+pub struct StreamFlow {
+    method: String,
+    peer: String,
+    elapsed: Duration,
+    request_messages: usize,
+    response_messages: usize,
+    send_pending: Option<(Duration, usize)>,
+    since_received: Option<Duration>,
+}
+
+impl StreamFlow {
+    pub fn method(&self) -> &str {
+        &self.method
+    }
+
+    pub fn peer(&self) -> &str {
+        &self.peer
+    }
+
+    /// Time since the call was accepted.
+    pub fn elapsed(&self) -> Duration {
+        self.elapsed
+    }
+
+    /// Count of the messages received from the client so far.
+    pub fn request_messages(&self) -> usize {
+        self.request_messages
+    }
+
+    /// Count of the messages sent to the client so far.
+    pub fn response_messages(&self) -> usize {
+        self.response_messages
+    }
+
+    /// How long the last response message has waited to be written, and its
+    /// size, if it's not written yet.
+    ///
+    /// The write is considered pending until the sink is flushed, e.g. by
+    /// `send` or `send_all`.
+    pub fn send_pending(&self) -> Option<(Duration, usize)> {
+        self.send_pending
+    }
+
+    /// Time since the handler took the last request message, if any.
+    ///
+    /// A streaming handler that stops polling its request stream keeps
+    /// growing it, while the client is blocked once its window is used up.
+    pub fn since_received(&self) -> Option<Duration> {
+        self.since_received
+    }
+}
+
+struct Entry {
+    method: String,
+    peer: String,
+    tracker: Weak<CallTracker>,
+}
+
+#[derive(Default)]
+pub(crate) struct FlowRegistry {
+    calls: Mutex<Vec<Entry>>,
+}
+
+impl FlowRegistry {
+    /// Report the call until its status is sent or it's dropped.
+    pub fn attach(&self, ctx: &RpcContext) {
+        self.register(
+            String::from_utf8_lossy(ctx.method()).into_owned(),
+            ctx.peer().to_string(),
+            ctx.tracker(),
+        )
+    }
+
+    fn register(&self, method: String, peer: String, tracker: &Arc<CallTracker>) {
+        self.calls.lock().unwrap().push(Entry {
+            method,
+            peer,
+            tracker: Arc::downgrade(tracker),
+        });
+    }
+
+    /// Get the flows of the calls in progress, and forget the finished ones.
+    pub fn snapshot(&self) -> Vec<StreamFlow> {
+        let mut flows = vec![];
+        let mut calls = self.calls.lock().unwrap();
+        calls.retain(|e| {
+            let tracker = match e.tracker.upgrade() {
+                Some(ref t) if !t.is_complete() => t.clone(),
+                _ => return false,
+            };
+            let (request_messages, response_messages) = tracker.progress();
+            flows.push(StreamFlow {
+                method: e.method.clone(),
+                peer: e.peer.clone(),
+                elapsed: tracker.elapsed(),
+                request_messages,
+                response_messages,
+                send_pending: tracker.send_pending(),
+                since_received: tracker.since_received(),
+            });
+            true
+        });
+        flows
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Instant;
+
+    use super::*;
+    use call::RpcStatusCode;
+
+    #[test]
+    fn test_snapshot() {
+        let registry = FlowRegistry::default();
+        let stuck = Arc::new(CallTracker::new(Instant::now(), String::new()));
+        stuck.on_received(3);
+        stuck.on_send_started(10);
+        let flowing = Arc::new(CallTracker::new(Instant::now(), String::new()));
+        flowing.on_send_started(10);
+        flowing.on_send_done();
+        let finished = Arc::new(CallTracker::new(Instant::now(), String::new()));
+        finished.on_complete(RpcStatusCode::Ok);
+        for (m, t) in &[
+            ("/a/Stuck", &stuck),
+            ("/a/Flowing", &flowing),
+            ("/a/Finished", &finished),
+        ] {
+            registry.register(m.to_string(), "peer".to_owned(), t);
+        }
+        registry.register(
+            "/a/Dropped".to_owned(),
+            "peer".to_owned(),
+            &Arc::new(CallTracker::new(Instant::now(), String::new())),
+        );
+
+        let flows = registry.snapshot();
+        assert_eq!(flows.len(), 2);
+        assert_eq!(flows[0].method(), "/a/Stuck");
+        assert_eq!(flows[0].send_pending().unwrap().1, 10);
+        assert_eq!(flows[0].request_messages(), 1);
+        assert!(flows[0].since_received().is_some());
+        assert_eq!(flows[1].method(), "/a/Flowing");
+        assert!(flows[1].send_pending().is_none());
+        assert!(flows[1].since_received().is_none());
+        assert_eq!(registry.calls.lock().unwrap().len(), 2);
+    }
+}
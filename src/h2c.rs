@@ -0,0 +1,252 @@
+// Copyright 2018 PingCAP, Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! The HTTP/1.1 Upgrade to h2c, see RFC 7540 section 3.2.
+//!
+//! gRPC core only speaks HTTP/2 with prior knowledge on cleartext
+//! connections. For the connections accepted by the server itself, the
+//! upgrade is done here before the connection is handed over to gRPC core.
+//!
+//! Only probes are upgraded: an `OPTIONS` request without a body, which
+//! doesn't need to be answered on stream 1. The settings in `HTTP2-Settings`
+//! are validated but not applied, the client sends them again in its
+//! connection preface.
+
+use std::io::{self, Read, Write};
+use std::net::TcpStream;
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::str;
+use std::time::{Duration, Instant};
+
+const PREFACE: &[u8] = b"PRI * HTTP/2.0\r\n\r\nSM\r\n\r\n";
+const MAX_HEAD_LEN: usize = 8 * 1024;
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+const SWITCHING_PROTOCOLS: &[u8] =
+    b"HTTP/1.1 101 Switching Protocols\r\nConnection: Upgrade\r\nUpgrade: h2c\r\n\r\n";
+const BAD_REQUEST: &[u8] =
+    b"HTTP/1.1 400 Bad Request\r\nConnection: close\r\nContent-Length: 0\r\n\r\n";
+const METHOD_NOT_ALLOWED: &[u8] = b"HTTP/1.1 405 Method Not Allowed\r\nAllow: OPTIONS\r\n\
+Connection: close\r\nContent-Length: 0\r\n\r\n";
+const UPGRADE_REQUIRED: &[u8] = b"HTTP/1.1 426 Upgrade Required\r\nConnection: Upgrade, close\r\n\
+Upgrade: h2c\r\nContent-Length: 0\r\n\r\n";
+
+#[derive(Debug, PartialEq)]
+enum Head {
+    /// The head is not complete yet.
+    Partial,
+    /// An upgrade probe with its head length.
+    Probe(usize),
+    /// A request that can't be upgraded, with the response to it.
+    Refused(&'static [u8]),
+}
+
+fn has_token(value: &str, token: &str) -> bool {
+    value
+        .split(',')
+        .any(|t| t.trim().eq_ignore_ascii_case(token))
+}
+
+/// Whether `value` is a base64url encoded SETTINGS payload.
+fn is_valid_settings(value: &str) -> bool {
+    let value = value.trim_end_matches('=').as_bytes();
+    let valid = value
+        .iter()
+        .all(|&b| b.is_ascii_alphanumeric() || b == b'-' || b == b'_');
+    // Each setting takes 6 bytes, which are encoded as 8 characters.
+    valid && value.chunks(8).all(|c| c.len() == 8)
+}
+
+fn parse_head(buf: &[u8]) -> Head {
+    let end = match buf.windows(4).position(|w| w == b"\r\n\r\n") {
+        Some(pos) => pos + 4,
+        None if buf.len() >= MAX_HEAD_LEN => return Head::Refused(BAD_REQUEST),
+        None => return Head::Partial,
+    };
+    let head = match str::from_utf8(&buf[..end]) {
+        Ok(h) => h,
+        Err(_) => return Head::Refused(BAD_REQUEST),
+    };
+    let mut lines = head.split("\r\n");
+    let method = match lines.next() {
+        Some(l) if l.ends_with(" HTTP/1.1") => l.split(' ').next().unwrap(),
+        _ => return Head::Refused(BAD_REQUEST),
+    };
+    let (mut upgrade, mut connection, mut body) = (false, false, false);
+    let mut settings = vec![];
+    for line in lines {
+        let mut kv = line.splitn(2, ':');
+        let (key, value) = match (kv.next(), kv.next()) {
+            (Some(k), Some(v)) => (k.trim(), v.trim()),
+            _ => continue,
+        };
+        if key.eq_ignore_ascii_case("upgrade") {
+            upgrade = has_token(value, "h2c");
+        } else if key.eq_ignore_ascii_case("connection") {
+            connection = has_token(value, "upgrade") && has_token(value, "http2-settings");
+        } else if key.eq_ignore_ascii_case("http2-settings") {
+            settings.push(value);
+        } else if key.eq_ignore_ascii_case("content-length") {
+            body |= value != "0";
+        } else if key.eq_ignore_ascii_case("transfer-encoding") {
+            body = true;
+        }
+    }
+    if !upgrade || !connection || settings.is_empty() {
+        return Head::Refused(UPGRADE_REQUIRED);
+    }
+    if method != "OPTIONS" {
+        return Head::Refused(METHOD_NOT_ALLOWED);
+    }
+    if body || settings.len() > 1 || !is_valid_settings(settings[0]) {
+        return Head::Refused(BAD_REQUEST);
+    }
+    Head::Probe(end)
+}
+
+/// The state of a handshake after it's advanced.
+pub enum Progress {
+    /// More bytes are needed.
+    Pending(Handshake),
+    /// The connection speaks HTTP/2 now.
+    Ready(TcpStream),
+    /// The connection is refused or closed by the client.
+    Closed,
+}
+
+/// A non-blocking h2c handshake on an accepted connection.
+///
+/// It's advanced whenever the connection is readable, so that a single
+/// thread can drive all the pending handshakes.
+pub struct Handshake {
+    stream: TcpStream,
+    buf: Vec<u8>,
+    deadline: Instant,
+}
+
+impl Handshake {
+    pub fn new(stream: TcpStream) -> io::Result<Handshake> {
+        stream.set_nonblocking(true)?;
+        Ok(Handshake {
+            stream,
+            buf: vec![],
+            deadline: Instant::now() + HANDSHAKE_TIMEOUT,
+        })
+    }
+
+    pub fn is_expired(&self, now: Instant) -> bool {
+        now >= self.deadline
+    }
+
+    /// Consume the bytes that have arrived.
+    ///
+    /// A connection that doesn't start with an HTTP/1.1 request is left
+    /// untouched for gRPC core. Only the first byte is checked, so requests
+    /// whose method starts with `P`, like `POST`, are handed over as well and
+    /// closed by gRPC core.
+    pub fn advance(mut self) -> io::Result<Progress> {
+        if self.buf.is_empty() {
+            let mut first = [0];
+            match self.stream.peek(&mut first) {
+                Ok(0) => return Ok(Progress::Closed),
+                Ok(_) if first[0] == PREFACE[0] => return Ok(Progress::Ready(self.stream)),
+                Ok(_) => {}
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {
+                    return Ok(Progress::Pending(self))
+                }
+                Err(e) => return Err(e),
+            }
+        }
+        let mut chunk = [0; 1024];
+        while self.buf.len() < MAX_HEAD_LEN {
+            match self.stream.read(&mut chunk) {
+                Ok(0) => return Ok(Progress::Closed),
+                Ok(n) => self.buf.extend_from_slice(&chunk[..n]),
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => return Err(e),
+            }
+        }
+        let resp = match parse_head(&self.buf) {
+            Head::Partial => return Ok(Progress::Pending(self)),
+            Head::Probe(len) if len == self.buf.len() => SWITCHING_PROTOCOLS,
+            // The preface must not be sent before 101 is received.
+            Head::Probe(_) => BAD_REQUEST,
+            Head::Refused(resp) => resp,
+        };
+        // The response fits in the empty send buffer of a new connection.
+        self.stream.write_all(resp)?;
+        if resp == SWITCHING_PROTOCOLS {
+            Ok(Progress::Ready(self.stream))
+        } else {
+            Ok(Progress::Closed)
+        }
+    }
+}
+
+impl AsRawFd for Handshake {
+    fn as_raw_fd(&self) -> RawFd {
+        self.stream.as_raw_fd()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_head() {
+        let req = b"OPTIONS * HTTP/1.1\r\nHost: a\r\nConnection: Upgrade, HTTP2-Settings\r\n\
+Upgrade: h2c\r\nHTTP2-Settings: AAMAAABkAAQAAP__\r\n\r\n";
+        assert_eq!(parse_head(req), Head::Probe(req.len()));
+        assert_eq!(parse_head(&req[..20]), Head::Partial);
+
+        let req = b"OPTIONS * HTTP/1.1\r\nconnection: upgrade,http2-settings\r\n\
+upgrade: H2C\r\nhttp2-settings:\r\ncontent-length: 0\r\n\r\n";
+        assert_eq!(parse_head(req), Head::Probe(req.len()));
+
+        // Not a probe.
+        let req = b"POST / HTTP/1.1\r\nconnection: upgrade,http2-settings\r\n\
+upgrade: h2c\r\nhttp2-settings:\r\n\r\n";
+        assert_eq!(parse_head(req), Head::Refused(METHOD_NOT_ALLOWED));
+        let req = b"OPTIONS * HTTP/1.1\r\nconnection: upgrade,http2-settings\r\n\
+upgrade: h2c\r\nhttp2-settings:\r\ncontent-length: 3\r\n\r\nabc";
+        assert_eq!(parse_head(req), Head::Refused(BAD_REQUEST));
+        let req = b"OPTIONS * HTTP/1.1\r\nconnection: upgrade,http2-settings\r\n\
+upgrade: h2c\r\nhttp2-settings:\r\ntransfer-encoding: chunked\r\n\r\n";
+        assert_eq!(parse_head(req), Head::Refused(BAD_REQUEST));
+        // Malformed settings.
+        let req = b"OPTIONS * HTTP/1.1\r\nconnection: upgrade,http2-settings\r\n\
+upgrade: h2c\r\nhttp2-settings: AAMA\r\n\r\n";
+        assert_eq!(parse_head(req), Head::Refused(BAD_REQUEST));
+
+        // Missing settings.
+        let req = b"GET / HTTP/1.1\r\nConnection: Upgrade\r\nUpgrade: h2c\r\n\r\n";
+        assert_eq!(parse_head(req), Head::Refused(UPGRADE_REQUIRED));
+        let req = b"GET / HTTP/1.1\r\nHost: a\r\n\r\n";
+        assert_eq!(parse_head(req), Head::Refused(UPGRADE_REQUIRED));
+        assert_eq!(
+            parse_head(&vec![b'a'; MAX_HEAD_LEN]),
+            Head::Refused(BAD_REQUEST)
+        );
+    }
+
+    #[test]
+    fn test_is_valid_settings() {
+        assert!(is_valid_settings(""));
+        assert!(is_valid_settings("AAMAAABkAAQAAP__"));
+        assert!(is_valid_settings("AAMAAABk"));
+        assert!(!is_valid_settings("AAMAAAB"));
+        assert!(!is_valid_settings("AAMA+ABk"));
+    }
+}
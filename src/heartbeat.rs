@@ -0,0 +1,158 @@
+// Copyright 2018 PingCAP, Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Application level keep-alive of server streams.
+//!
+//! Proxies and load balancers tend to close streams that stay silent for a
+//! while, even if the connection is kept alive by HTTP/2 pings. A stream
+//! wrapped by [`RpcContext::heartbeat`] yields a message built by the given
+//! constructor whenever the inner stream has produced nothing for the idle
+//! threshold, so the response stream keeps moving:
+//!
+//! ```ignore
+//! let updates = ctx.heartbeat(updates, Duration::from_secs(30), || {
+//!     (Update::new(), WriteFlags::default())
+//! });
+//! let handle = updates.handle();
+//! ctx.spawn(sink.send_all(updates).map(|_| ()).map_err(|_| ()));
+//! ```
+//!
+//! [`RpcContext::heartbeat`]: ../struct.RpcContext.html#method.heartbeat
+
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use futures::{Async, Future, Poll, Stream};
+
+use async::Timer;
+use call::Deadline;
+use cq::CompletionQueue;
+
+/// A handle to turn the heartbeats of a [`Heartbeat`] stream on and off,
+/// after the stream is moved into a sink.
+///
+/// [`Heartbeat`]: struct.Heartbeat.html
+#[derive(Clone)]
+pub struct HeartbeatHandle {
+    enabled: Arc<AtomicBool>,
+}
+
+impl HeartbeatHandle {
+    /// Enable or disable the heartbeats, it takes effect the next time the
+    /// stream is polled.
+    pub fn set_enabled(&self, enabled: bool) {
+        self.enabled.store(enabled, Ordering::Relaxed);
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.enabled.load(Ordering::Relaxed)
+    }
+}
+
+/// A stream created by [`RpcContext::heartbeat`].
+///
+/// [`RpcContext::heartbeat`]: ../struct.RpcContext.html#method.heartbeat
+pub struct Heartbeat<S, F> {
+    stream: S,
+    make: F,
+    idle: Duration,
+    cq: CompletionQueue,
+    // Time the last item is yielded.
+    last: Instant,
+    timer: Option<Timer>,
+    handle: HeartbeatHandle,
+    finished: bool,
+}
+
+impl<S, F> Heartbeat<S, F> {
+    pub(crate) fn new(stream: S, idle: Duration, make: F, cq: CompletionQueue) -> Heartbeat<S, F> {
+        Heartbeat {
+            stream,
+            make,
+            idle,
+            cq,
+            last: Instant::now(),
+            timer: None,
+            handle: HeartbeatHandle {
+                enabled: Arc::new(AtomicBool::new(true)),
+            },
+            finished: false,
+        }
+    }
+
+    pub fn handle(&self) -> HeartbeatHandle {
+        self.handle.clone()
+    }
+
+    /// Check whether a heartbeat is due, or arm the timer to be woken up
+    /// when it is.
+    fn poll_idle(&mut self) -> bool {
+        loop {
+            let due = self.last + self.idle;
+            if Instant::now() >= due {
+                self.timer = None;
+                return true;
+            }
+            if self.timer.is_none() {
+                match Timer::new(&self.cq, &Deadline::from(due)) {
+                    Ok(t) => self.timer = Some(t),
+                    // The queue is shutting down, the stream is going to
+                    // be dropped anyway.
+                    Err(_) => return false,
+                }
+            }
+            match self.timer.as_mut().unwrap().poll() {
+                Ok(Async::NotReady) => return false,
+                // Fired early or canceled, check the time again.
+                _ => self.timer = None,
+            }
+        }
+    }
+}
+
+impl<S, F> Stream for Heartbeat<S, F>
+where
+    S: Stream,
+    F: FnMut() -> S::Item,
+{
+    type Item = S::Item;
+    type Error = S::Error;
+
+    fn poll(&mut self) -> Poll<Option<S::Item>, S::Error> {
+        if self.finished {
+            return Ok(Async::Ready(None));
+        }
+        match self.stream.poll()? {
+            Async::Ready(None) => {
+                self.finished = true;
+                self.timer = None;
+                return Ok(Async::Ready(None));
+            }
+            Async::Ready(Some(item)) => {
+                self.last = Instant::now();
+                return Ok(Async::Ready(Some(item)));
+            }
+            Async::NotReady => {}
+        }
+        if !self.handle.is_enabled() {
+            self.timer = None;
+            return Ok(Async::NotReady);
+        }
+        if self.poll_idle() {
+            self.last = Instant::now();
+            return Ok(Async::Ready(Some((self.make)())));
+        }
+        Ok(Async::NotReady)
+    }
+}
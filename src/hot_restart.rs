@@ -0,0 +1,251 @@
+// Copyright 2018 PingCAP, Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Handover of listening sockets to a successor process.
+//!
+//! For a hot restart, the running process sends the sockets it serves by
+//! [`ServerBuilder::bind_fd`] to the new process over a unix socket. The new
+//! process starts serving them right away, and the old one drains, so no
+//! connection is refused and the streams in flight are served to the end:
+//!
+//! ```ignore
+//! // In the old process, once the new one connects.
+//! let (stream, _) = listener.accept()?;
+//! hot_restart::send(&stream, server.listener_fds(), server.in_flight_calls())?;
+//! server.start_draining().wait()?;
+//!
+//! // In the new process.
+//! let stream = UnixStream::connect(path)?;
+//! let handover = hot_restart::receive(&stream)?;
+//! let mut builder = ServerBuilder::new(env).register_service(service);
+//! for &fd in handover.listeners() {
+//!     builder = builder.bind_fd(fd);
+//! }
+//! ```
+//!
+//! Both processes accept on the sockets until the old one is shut down.
+//! The ports bound by gRPC core itself can't be handed over.
+//!
+//! [`ServerBuilder::bind_fd`]: ../struct.ServerBuilder.html#method.bind_fd
+
+use std::io::{self, ErrorKind};
+use std::mem;
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::os::unix::net::UnixStream;
+use std::ptr;
+
+use libc::{self, c_uint, c_void, cmsghdr, iovec, msghdr};
+
+/// The most sockets sent in one handover.
+pub const MAX_LISTENERS: usize = 64;
+
+const MAGIC: &[u8; 8] = b"GRPCHOT1";
+const HEADER_LEN: usize = 8 + 8 + 4;
+
+// The received sockets are not inherited by child processes.
+#[cfg(any(target_os = "linux", target_os = "android"))]
+const RECV_FLAGS: libc::c_int = libc::MSG_CMSG_CLOEXEC;
+#[cfg(not(any(target_os = "linux", target_os = "android")))]
+const RECV_FLAGS: libc::c_int = 0;
+
+/// What the successor receives.
+#[derive(Debug)]
+pub struct Handover {
+    listeners: Vec<RawFd>,
+    in_flight: u64,
+}
+
+impl Handover {
+    /// The listening sockets, owned by the receiving process now.
+    pub fn listeners(&self) -> &[RawFd] {
+        &self.listeners
+    }
+
+    /// The count of calls the sender was serving when it sent the sockets.
+    pub fn in_flight(&self) -> u64 {
+        self.in_flight
+    }
+}
+
+fn encode(listeners: usize, in_flight: u64) -> [u8; HEADER_LEN] {
+    let mut buf = [0; HEADER_LEN];
+    buf[..8].copy_from_slice(MAGIC);
+    buf[8..16].copy_from_slice(&in_flight.to_le_bytes());
+    buf[16..].copy_from_slice(&(listeners as u32).to_le_bytes());
+    buf
+}
+
+fn decode(buf: &[u8]) -> io::Result<(usize, u64)> {
+    if buf.len() != HEADER_LEN || &buf[..8] != MAGIC {
+        return Err(io::Error::new(
+            ErrorKind::InvalidData,
+            "not a listener handover",
+        ));
+    }
+    let mut in_flight = [0; 8];
+    in_flight.copy_from_slice(&buf[8..16]);
+    let mut count = [0; 4];
+    count.copy_from_slice(&buf[16..]);
+    Ok((
+        u32::from_le_bytes(count) as usize,
+        u64::from_le_bytes(in_flight),
+    ))
+}
+
+fn cmsg_space(fds: usize) -> usize {
+    unsafe { libc::CMSG_SPACE((fds * mem::size_of::<RawFd>()) as c_uint) as usize }
+}
+
+/// Send `listeners` and the count of calls in flight over `stream`.
+///
+/// The sockets stay open in the sending process, which should stop
+/// accepting on them by shutting down its server.
+pub fn send(stream: &UnixStream, listeners: &[RawFd], in_flight: usize) -> io::Result<()> {
+    if listeners.len() > MAX_LISTENERS {
+        return Err(io::Error::new(
+            ErrorKind::InvalidInput,
+            format!("at most {} listeners can be sent", MAX_LISTENERS),
+        ));
+    }
+    let mut header = encode(listeners.len(), in_flight as u64);
+    let mut iov = iovec {
+        iov_base: header.as_mut_ptr() as *mut c_void,
+        iov_len: header.len(),
+    };
+    // u64 keeps the control buffer aligned for `cmsghdr`.
+    let mut control = vec![0u64; (cmsg_space(MAX_LISTENERS) + 7) / 8];
+    let mut msg: msghdr = unsafe { mem::zeroed() };
+    msg.msg_iov = &mut iov;
+    msg.msg_iovlen = 1;
+    if !listeners.is_empty() {
+        msg.msg_control = control.as_mut_ptr() as *mut c_void;
+        msg.msg_controllen = cmsg_space(listeners.len()) as _;
+        unsafe {
+            let cmsg: *mut cmsghdr = libc::CMSG_FIRSTHDR(&msg);
+            (*cmsg).cmsg_level = libc::SOL_SOCKET;
+            (*cmsg).cmsg_type = libc::SCM_RIGHTS;
+            (*cmsg).cmsg_len =
+                libc::CMSG_LEN((listeners.len() * mem::size_of::<RawFd>()) as c_uint) as _;
+            ptr::copy_nonoverlapping(
+                listeners.as_ptr(),
+                libc::CMSG_DATA(cmsg) as *mut RawFd,
+                listeners.len(),
+            );
+        }
+    }
+    let n = unsafe { libc::sendmsg(stream.as_raw_fd(), &msg, 0) };
+    if n < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    if n as usize != header.len() {
+        return Err(io::Error::new(
+            ErrorKind::WriteZero,
+            "handover is sent partially",
+        ));
+    }
+    Ok(())
+}
+
+/// Receive the sockets sent by [`send`](fn.send.html) from `stream`.
+pub fn receive(stream: &UnixStream) -> io::Result<Handover> {
+    let mut header = [0; HEADER_LEN];
+    let mut iov = iovec {
+        iov_base: header.as_mut_ptr() as *mut c_void,
+        iov_len: header.len(),
+    };
+    let mut control = vec![0u64; (cmsg_space(MAX_LISTENERS) + 7) / 8];
+    let mut msg: msghdr = unsafe { mem::zeroed() };
+    msg.msg_iov = &mut iov;
+    msg.msg_iovlen = 1;
+    msg.msg_control = control.as_mut_ptr() as *mut c_void;
+    msg.msg_controllen = cmsg_space(MAX_LISTENERS) as _;
+    let n = unsafe { libc::recvmsg(stream.as_raw_fd(), &mut msg, RECV_FLAGS) };
+    if n < 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    let mut listeners = vec![];
+    unsafe {
+        let mut cmsg = libc::CMSG_FIRSTHDR(&msg);
+        while !cmsg.is_null() {
+            if (*cmsg).cmsg_level == libc::SOL_SOCKET && (*cmsg).cmsg_type == libc::SCM_RIGHTS {
+                let data = libc::CMSG_DATA(cmsg) as *const RawFd;
+                let len = (*cmsg).cmsg_len as usize - libc::CMSG_LEN(0) as usize;
+                for i in 0..len / mem::size_of::<RawFd>() {
+                    listeners.push(ptr::read_unaligned(data.add(i)));
+                }
+            }
+            cmsg = libc::CMSG_NXTHDR(&msg, cmsg);
+        }
+    }
+    let checked = if msg.msg_flags & libc::MSG_CTRUNC != 0 {
+        Err(io::Error::new(
+            ErrorKind::InvalidData,
+            "too many listeners are sent",
+        ))
+    } else {
+        decode(&header[..n as usize]).and_then(|(count, in_flight)| {
+            if count == listeners.len() {
+                Ok(in_flight)
+            } else {
+                Err(io::Error::new(
+                    ErrorKind::InvalidData,
+                    format!("expect {} listeners, got {}", count, listeners.len()),
+                ))
+            }
+        })
+    };
+    match checked {
+        Ok(in_flight) => Ok(Handover {
+            listeners,
+            in_flight,
+        }),
+        Err(e) => {
+            for fd in listeners {
+                unsafe { libc::close(fd) };
+            }
+            Err(e)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::{TcpListener, TcpStream};
+    use std::os::unix::io::FromRawFd;
+
+    use super::*;
+
+    #[test]
+    fn test_handover() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (old, new) = UnixStream::pair().unwrap();
+        send(&old, &[listener.as_raw_fd()], 3).unwrap();
+        let handover = receive(&new).unwrap();
+        assert_eq!(handover.in_flight(), 3);
+        assert_eq!(handover.listeners().len(), 1);
+        assert_ne!(handover.listeners()[0], listener.as_raw_fd());
+        drop(listener);
+
+        // The received socket still listens on the same address.
+        let received = unsafe { TcpListener::from_raw_fd(handover.listeners()[0]) };
+        let _conn = TcpStream::connect(addr).unwrap();
+        received.accept().unwrap();
+
+        send(&old, &[], 0).unwrap();
+        assert!(receive(&new).unwrap().listeners().is_empty());
+        drop(old);
+        assert!(receive(&new).is_err());
+    }
+}
@@ -0,0 +1,171 @@
+// Copyright 2018 PingCAP, Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Resetting of idle streams.
+//!
+//! A stream whose peer is gone without closing the connection, or that is
+//! simply forgotten, holds its call until the connection dies. With an idle
+//! timeout, a streaming call that has neither received nor sent a message
+//! for that long is reset with `Unavailable`.
+//!
+//! A message counts once it's taken from the receiving stream or handed to
+//! gRPC core by the sink, so a stream that is not polled is idle too, and so
+//! is one whose writes are stuck.
+
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use futures::{Async, Future, Poll};
+
+use async::{Timer, TimerHandle};
+use call::{Deadline, RpcStatus, RpcStatusCode};
+use cq::CompletionQueue;
+
+/// The last activity of a stream.
+pub(crate) struct IdleClock {
+    timeout: Duration,
+    started: Instant,
+    // Nanoseconds since `started`.
+    last_active: AtomicU64,
+    closed: AtomicBool,
+    timer: Mutex<Option<Arc<TimerHandle>>>,
+}
+
+impl IdleClock {
+    pub fn new(timeout: Duration) -> IdleClock {
+        IdleClock {
+            timeout,
+            started: Instant::now(),
+            last_active: AtomicU64::new(0),
+            closed: AtomicBool::new(false),
+            timer: Mutex::new(None),
+        }
+    }
+
+    /// A message is received or sent.
+    pub fn touch(&self) {
+        let d = self.started.elapsed();
+        let nanos = d.as_secs() * 1_000_000_000 + u64::from(d.subsec_nanos());
+        self.last_active.fetch_max(nanos, Ordering::Relaxed);
+    }
+
+    fn idle_at(&self, now: Instant) -> Duration {
+        let last = self.started + Duration::from_nanos(self.last_active.load(Ordering::Relaxed));
+        if now > last {
+            now - last
+        } else {
+            Duration::from_secs(0)
+        }
+    }
+
+    /// Stop watching the stream, the pending timer is canceled so the call
+    /// is released right away.
+    pub fn close(&self) {
+        self.closed.store(true, Ordering::SeqCst);
+        if let Some(handle) = self.timer.lock().unwrap().take() {
+            handle.cancel();
+        }
+    }
+
+    fn is_closed(&self) -> bool {
+        self.closed.load(Ordering::SeqCst)
+    }
+
+    fn arm(&self, cq: &CompletionQueue, wait: Duration) -> Option<Timer> {
+        let timer = Timer::new(cq, &Deadline::from(wait)).ok()?;
+        *self.timer.lock().unwrap() = Some(timer.handle());
+        // `close` may miss the timer if it's called in between.
+        if self.is_closed() {
+            timer.handle().cancel();
+        }
+        Some(timer)
+    }
+}
+
+/// A future that calls `reset` once the stream is idle for longer than the
+/// timeout of `clock`, or resolves without calling it once the clock is
+/// closed.
+pub(crate) struct IdleWatch<F> {
+    clock: Arc<IdleClock>,
+    cq: CompletionQueue,
+    timer: Option<Timer>,
+    reset: Option<F>,
+}
+
+pub(crate) fn watch<F>(clock: Arc<IdleClock>, cq: &CompletionQueue, reset: F) -> IdleWatch<F>
+where
+    F: FnOnce(RpcStatus),
+{
+    IdleWatch {
+        clock,
+        cq: cq.clone(),
+        timer: None,
+        reset: Some(reset),
+    }
+}
+
+impl<F: FnOnce(RpcStatus)> Future for IdleWatch<F> {
+    type Item = ();
+    type Error = ();
+
+    fn poll(&mut self) -> Poll<(), ()> {
+        loop {
+            if self.clock.is_closed() {
+                return Ok(Async::Ready(()));
+            }
+            let idle = self.clock.idle_at(Instant::now());
+            if idle >= self.clock.timeout {
+                let details = format!("stream is idle for more than {:?}", self.clock.timeout);
+                let reset = self.reset.take().unwrap();
+                reset(RpcStatus::new(RpcStatusCode::Unavailable, Some(details)));
+                return Ok(Async::Ready(()));
+            }
+            if self.timer.is_none() {
+                match self.clock.arm(&self.cq, self.clock.timeout - idle) {
+                    Some(t) => self.timer = Some(t),
+                    // The queue is shutting down, so is the call.
+                    None => return Ok(Async::Ready(())),
+                }
+            }
+            match self.timer.as_mut().unwrap().poll() {
+                Ok(Async::NotReady) => return Ok(Async::NotReady),
+                // Messages may have been seen in the meantime, check again.
+                Ok(Async::Ready(true)) => self.timer = None,
+                _ => return Ok(Async::Ready(())),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::thread;
+
+    use super::*;
+
+    #[test]
+    fn test_idle_clock() {
+        let clock = IdleClock::new(Duration::from_secs(1));
+        let later = clock.started + Duration::from_millis(300);
+        assert_eq!(clock.idle_at(later), Duration::from_millis(300));
+        thread::sleep(Duration::from_millis(1));
+        clock.touch();
+        assert!(clock.idle_at(later) < Duration::from_millis(300));
+        // Activity after `now` doesn't make it go negative.
+        assert_eq!(clock.idle_at(clock.started), Duration::from_secs(0));
+        assert!(!clock.is_closed());
+        clock.close();
+        assert!(clock.is_closed());
+    }
+}
@@ -41,44 +41,109 @@ extern crate libc;
 extern crate log;
 #[cfg(feature = "protobuf-codec")]
 extern crate protobuf;
+#[cfg(feature = "authz-json")]
+extern crate serde_json;
 
+pub mod access_log;
+#[cfg(feature = "protobuf-codec")]
+pub mod admin;
+pub mod alloc;
 mod async;
+#[cfg(feature = "tls-server")]
+mod auth;
+pub mod authz;
+pub mod bandwidth;
+pub mod blocking;
+pub mod cache;
 mod call;
 mod channel;
+pub mod channel_manager;
+pub mod checksum;
+pub mod chunk;
 mod client;
+pub mod coalesce;
 mod codec;
+pub mod context;
+pub mod correlate;
 mod cq;
-#[cfg(feature = "secure")]
+#[cfg(any(feature = "tls-client", feature = "tls-server"))]
 mod credentials;
+#[cfg(feature = "protobuf-codec")]
+pub mod descriptor;
 mod env;
 mod error;
+pub mod flow;
+#[cfg(unix)]
+mod h2c;
+pub mod heartbeat;
+#[cfg(unix)]
+pub mod hot_restart;
+mod idle;
 mod log_util;
+pub mod message_hook;
 mod metadata;
+pub mod method_config;
+pub mod orca;
+pub mod outlier;
+pub mod panic_policy;
+mod peer;
+pub mod pipeline;
+mod probe;
+pub mod proxy;
+pub mod request_id;
+mod route;
+#[cfg(feature = "executor-bridge")]
+pub mod runtime;
 mod server;
+#[cfg(unix)]
+pub mod socket_activation;
+#[cfg(unix)]
+pub mod transport;
+pub mod watchdog;
+#[cfg(feature = "protobuf-codec")]
+pub mod wkt;
+pub mod wrr;
 
+#[cfg(feature = "tls-server")]
+pub use auth::{AuthContext, X509_SAN_PROPERTY_NAME};
 pub use call::client::{
-    CallOption, ClientCStreamReceiver, ClientCStreamSender, ClientDuplexReceiver,
-    ClientDuplexSender, ClientSStreamReceiver, ClientUnaryReceiver, StreamingCallSink,
+    AwaitHeaders, BatchUnaryReceiver, CallOption, ClientCStreamReceiver, ClientCStreamSender,
+    ClientDuplexReceiver, ClientDuplexSender, ClientSStreamReceiver, ClientUnaryReceiver,
+    StreamingCallSink,
 };
 pub use call::server::{
-    ClientStreamingSink, ClientStreamingSinkResult, Deadline, DuplexSink, DuplexSinkFailure,
-    RequestStream, RpcContext, ServerStreamingSink, ServerStreamingSinkFailure, UnarySink,
-    UnarySinkResult,
+    CallSummary, ClientStreamingSink, ClientStreamingSinkResult, CompletionGuard, DuplexSink,
+    DuplexSinkFailure, RequestStream, RpcContext, ServerStreamingSink, ServerStreamingSinkFailure,
+    UnarySink, UnarySinkResult,
+};
+pub use call::{
+    Deadline, Method, MethodType, RpcStatus, RpcStatusCode, ToGrpcStatus, WriteFlags,
+    STATUS_DETAILS_KEY,
 };
-pub use call::{Method, MethodType, RpcStatus, RpcStatusCode, WriteFlags};
 pub use channel::{
-    Channel, ChannelBuilder, CompressionAlgorithms, CompressionLevel, LbPolicy, OptTarget,
+    Channel, ChannelArg, ChannelArgValue, ChannelBuilder, ChannelStats, CompressionAlgorithms,
+    CompressionLevel, ConnectivityState, LbPolicy, OptTarget, StateChange, StateChanges,
+    StreamCompressionAlgorithms, SubchannelStats,
 };
 pub use client::Client;
 #[cfg(feature = "protobuf-codec")]
-pub use codec::pb_codec::{de as pb_de, ser as pb_ser};
-pub use codec::Marshaller;
-#[cfg(feature = "secure")]
+pub use codec::pb_codec::{de as pb_de, de_into as pb_de_into, ser as pb_ser};
+pub use codec::{append_shared, DeserializeIntoFn, Marshaller};
+pub use cq::CqStats;
+#[cfg(feature = "tls-client")]
 pub use credentials::{
-    ChannelCredentials, ChannelCredentialsBuilder, ServerCredentials, ServerCredentialsBuilder,
+    AuthMetadataContext, AuthMetadataSink, CallCredentials, CallCredentialsProvider,
+    ChannelCredentials, ChannelCredentialsBuilder,
 };
-pub use env::{EnvBuilder, Environment};
+#[cfg(feature = "tls-server")]
+pub use credentials::{ServerCredentials, ServerCredentialsBuilder};
+pub use env::{DnsResolver, EnvBuilder, EnvStats, Environment, PollStrategy};
 pub use error::{Error, Result};
 pub use log_util::redirect_log;
-pub use metadata::{Metadata, MetadataBuilder, MetadataIter};
-pub use server::{Server, ServerBuilder, Service, ServiceBuilder, ShutdownFuture};
+pub use metadata::{MergePolicy, Metadata, MetadataBuilder, MetadataIter};
+pub use peer::Peer;
+pub use probe::{ConnectError, ConnectErrorKind};
+pub use route::MethodPattern;
+pub use server::{
+    RequestSlotStats, Server, ServerBuilder, Service, ServiceBuilder, ShutdownFuture, SocketFamily,
+};
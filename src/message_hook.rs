@@ -0,0 +1,93 @@
+// Copyright 2018 PingCAP, Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Hooks that see the decoded messages of calls.
+//!
+//! A [`MessageHook`] installed by [`ServerBuilder::message_hook`] or
+//! [`ChannelBuilder::message_hook`] is invoked with every message of every
+//! call, right after it's decoded and right before it's encoded. Messages are
+//! passed as `Any`, hooks downcast them to the types they are interested in and
+//! may modify them in place, for example to scrub sensitive fields:
+//!
+//! ```
+//! # extern crate grpcio;
+//! use std::any::Any;
+//!
+//! use grpcio::message_hook::MessageHook;
+//!
+//! struct Redact;
+//!
+//! impl MessageHook for Redact {
+//!     fn on_send(&self, _: &str, msg: &mut Any) {
+//!         if let Some(s) = msg.downcast_mut::<String>() {
+//!             s.clear();
+//!         }
+//!     }
+//! }
+//! # fn main() {}
+//! ```
+//!
+//! Requests of client side unary and server streaming calls are passed by
+//! reference, so they are decoded from the serialized request to be shown to
+//! the hook, and encoded again afterwards. Raw handlers, like fallback handlers
+//! and chunked calls, see `Vec<u8>`.
+//!
+//! [`MessageHook`]: trait.MessageHook.html
+//! [`ServerBuilder::message_hook`]: ../struct.ServerBuilder.html#method.message_hook
+//! [`ChannelBuilder::message_hook`]: ../struct.ChannelBuilder.html#method.message_hook
+
+use std::any::Any;
+use std::sync::Arc;
+
+/// A hook invoked with the messages of calls.
+///
+/// `method` is the full qualified name of the method. Hooks are invoked on the
+/// threads that poll the calls, so they should not block.
+pub trait MessageHook: Send + Sync {
+    /// Invoked before a message is encoded and sent to the peer.
+    fn on_send(&self, _method: &str, _msg: &mut Any) {}
+
+    /// Invoked after a message is received from the peer and decoded.
+    fn on_receive(&self, _method: &str, _msg: &mut Any) {}
+}
+
+fn as_any<T: Any>(t: &mut T) -> &mut Any {
+    t
+}
+
+/// A hook bound to a method and a message type.
+pub(crate) struct Hook<T> {
+    hook: Arc<MessageHook>,
+    method: Arc<str>,
+    as_any: fn(&mut T) -> &mut Any,
+}
+
+impl<T: 'static> Hook<T> {
+    pub fn new(hook: Arc<MessageHook>, method: &str) -> Hook<T> {
+        Hook {
+            hook,
+            method: Arc::from(method),
+            as_any: as_any::<T>,
+        }
+    }
+}
+
+impl<T> Hook<T> {
+    pub fn on_send(&self, t: &mut T) {
+        self.hook.on_send(&self.method, (self.as_any)(t))
+    }
+
+    pub fn on_receive(&self, t: &mut T) {
+        self.hook.on_receive(&self.method, (self.as_any)(t))
+    }
+}
@@ -13,6 +13,7 @@
 
 use grpc_sys::{self, GrpcMetadataArray};
 use std::borrow::Cow;
+use std::fmt::{self, Debug, Formatter};
 use std::{mem, slice, str};
 
 use libc;
@@ -56,8 +57,15 @@ fn normalize_key(key: &str, binary: bool) -> Result<Cow<str>> {
 }
 
 /// Builder for immutable Metadata.
+///
+/// The entries are buffered until `build`, which hands all of them to
+/// gRPC core at once.
 pub struct MetadataBuilder {
     arr: Metadata,
+    // Keys and values back to back.
+    data: Vec<u8>,
+    // Lengths of the keys and values in `data`, in pairs.
+    lens: Vec<usize>,
 }
 
 impl MetadataBuilder {
@@ -70,6 +78,17 @@ impl MetadataBuilder {
     pub fn with_capacity(cap: usize) -> MetadataBuilder {
         MetadataBuilder {
             arr: Metadata::with_capacity(cap),
+            data: vec![],
+            lens: Vec::with_capacity(cap * 2),
+        }
+    }
+
+    /// Create a builder that appends entries to `meta`.
+    pub(crate) fn from_metadata(meta: Metadata) -> MetadataBuilder {
+        MetadataBuilder {
+            arr: meta,
+            data: vec![],
+            lens: vec![],
         }
     }
 
@@ -94,15 +113,10 @@ impl MetadataBuilder {
     }
 
     fn add_metadata(&mut self, key: &str, value: &[u8]) -> Result<&mut MetadataBuilder> {
-        unsafe {
-            grpc_sys::grpcwrap_metadata_array_add(
-                &mut self.arr.0,
-                key.as_ptr() as _,
-                key.len(),
-                value.as_ptr() as _,
-                value.len(),
-            )
-        }
+        self.data.extend_from_slice(key.as_bytes());
+        self.data.extend_from_slice(value);
+        self.lens.push(key.len());
+        self.lens.push(value.len());
         Ok(self)
     }
 
@@ -117,12 +131,32 @@ impl MetadataBuilder {
     /// Create `Metadata` with configured entries.
     pub fn build(mut self) -> Metadata {
         unsafe {
+            grpc_sys::grpcwrap_metadata_array_add_batch(
+                &mut self.arr.0,
+                self.data.as_ptr() as _,
+                self.data.len(),
+                self.lens.as_ptr(),
+                self.lens.len() / 2,
+            );
             grpc_sys::grpcwrap_metadata_array_shrink_to_fit(&mut self.arr.0);
         }
         self.arr
     }
 }
 
+/// How to resolve a key that appears in both sides of [`Metadata::merge`].
+///
+/// [`Metadata::merge`]: struct.Metadata.html#method.merge
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum MergePolicy {
+    /// Keep the existing values and drop the new ones.
+    KeepExisting,
+    /// Drop the existing values in favor of the new ones.
+    Replace,
+    /// Keep both, the existing values come first.
+    Append,
+}
+
 /// A collection of metadata entries that can be exchanged during a call.
 ///
 /// gRPC supports these types of metadata:
@@ -192,6 +226,46 @@ impl Metadata {
             index: 0,
         }
     }
+
+    /// Merge the entries of `other` into a copy of `self`, keys that appear in
+    /// both are resolved by `policy`.
+    pub fn merge(&self, other: &Metadata, policy: MergePolicy) -> Metadata {
+        let conflicts = |key: &str, meta: &Metadata| meta.iter().any(|(k, _)| k == key);
+        let mut builder = MetadataBuilder::with_capacity(self.len() + other.len());
+        for (k, v) in self.iter() {
+            if policy == MergePolicy::Replace && conflicts(k, other) {
+                continue;
+            }
+            builder.add_metadata(k, v).unwrap();
+        }
+        for (k, v) in other.iter() {
+            if policy == MergePolicy::KeepExisting && conflicts(k, self) {
+                continue;
+            }
+            builder.add_metadata(k, v).unwrap();
+        }
+        builder.build()
+    }
+}
+
+// Metadata owns all its entries, which are reference counted by gRPC core
+// in a thread safe way.
+unsafe impl Send for Metadata {}
+// Metadata is immutable once built.
+unsafe impl Sync for Metadata {}
+
+impl Debug for Metadata {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        let mut map = f.debug_map();
+        for (k, v) in self.iter() {
+            if k.ends_with("-bin") {
+                map.entry(&k, &v);
+            } else {
+                map.entry(&k, &String::from_utf8_lossy(v));
+            }
+        }
+        map.finish()
+    }
 }
 
 impl Clone for Metadata {
@@ -323,5 +397,63 @@ mod tests {
         let empty_metadata = MetadataBuilder::new().build();
         assert!(empty_metadata.is_empty());
         assert_eq!(empty_metadata.len(), 0);
+
+        // Values longer than the inlined slices share the copy of the batch.
+        let token = "t".repeat(1024);
+        let mut builder = MetadataBuilder::from_metadata(metadata1);
+        builder.add_str("authorization", &token).unwrap();
+        builder.add_bytes("empty-bin", b"").unwrap();
+        let metadata = builder.build();
+        assert_eq!(metadata.len(), 12);
+        assert_eq!(metadata.get(0).unwrap(), ("k0", &b"v0"[..]));
+        assert_eq!(
+            metadata.get(10).unwrap(),
+            ("authorization", token.as_bytes())
+        );
+        assert_eq!(metadata.get(11).unwrap(), ("empty-bin", &b""[..]));
+        let metadata1 = metadata.clone();
+        drop(metadata);
+        assert_eq!(metadata1.get(10).unwrap().1, token.as_bytes());
+    }
+
+    #[test]
+    fn test_merge() {
+        let mut builder = MetadataBuilder::new();
+        builder
+            .add_str("a", "1")
+            .unwrap()
+            .add_str("b", "1")
+            .unwrap();
+        let base = builder.build();
+        let mut builder = MetadataBuilder::new();
+        builder
+            .add_str("b", "2")
+            .unwrap()
+            .add_str("c", "2")
+            .unwrap();
+        let other = builder.build();
+
+        let cases = vec![
+            (
+                MergePolicy::KeepExisting,
+                vec![("a", "1"), ("b", "1"), ("c", "2")],
+            ),
+            (
+                MergePolicy::Replace,
+                vec![("a", "1"), ("b", "2"), ("c", "2")],
+            ),
+            (
+                MergePolicy::Append,
+                vec![("a", "1"), ("b", "1"), ("b", "2"), ("c", "2")],
+            ),
+        ];
+        for (policy, expect) in cases {
+            let merged = base.merge(&other, policy);
+            let kvs: Vec<_> = merged
+                .iter()
+                .map(|(k, v)| (k, str::from_utf8(v).unwrap()))
+                .collect();
+            assert_eq!(kvs, expect, "{:?}", policy);
+        }
     }
 }
@@ -0,0 +1,204 @@
+// Copyright 2018 PingCAP, Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Options of server methods.
+//!
+//! A [`MethodConfig`] is registered for a method path by
+//! [`ServerBuilder::method_config`], and applied to every call of the method
+//! before its handler is invoked:
+//!
+//! - Messages larger than the limits fail the call with `ResourceExhausted`.
+//! - Responses are compressed with the given algorithm or level.
+//! - Calls still running after the timeout are finished with
+//!   `DeadlineExceeded`, and [`RpcContext::deadline`] reports the shorter one
+//!   of the timeout and the deadline of client.
+//!
+//! [`MethodConfig`]: struct.MethodConfig.html
+//! [`ServerBuilder::method_config`]: ../struct.ServerBuilder.html#method.method_config
+//! [`RpcContext::deadline`]: ../struct.RpcContext.html#method.deadline
+
+use std::time::Duration;
+
+use grpc_sys::{
+    GrpcCompressionAlgorithms as CompressionAlgorithms, GrpcCompressionLevel as CompressionLevel,
+};
+
+use call::{RpcStatus, RpcStatusCode};
+use metadata::{Metadata, MetadataBuilder};
+
+// Metadata key gRPC core looks for to override the compression algorithm.
+const COMPRESSION_REQUEST_KEY: &str = "grpc-internal-encoding-request";
+
+#[derive(Clone, Copy, Debug)]
+enum Compression {
+    Algorithm(CompressionAlgorithms),
+    Level(CompressionLevel),
+}
+
+/// Options of a server method.
+#[derive(Clone, Debug, Default)]
+pub struct MethodConfig {
+    max_receive_message_len: Option<usize>,
+    max_send_message_len: Option<usize>,
+    compression: Option<Compression>,
+    timeout: Option<Duration>,
+}
+
+impl MethodConfig {
+    pub fn new() -> MethodConfig {
+        MethodConfig::default()
+    }
+
+    /// Set the max size of the messages received from client.
+    pub fn max_receive_message_len(mut self, len: usize) -> MethodConfig {
+        self.max_receive_message_len = Some(len);
+        self
+    }
+
+    /// Set the max size of the messages sent to client.
+    pub fn max_send_message_len(mut self, len: usize) -> MethodConfig {
+        self.max_send_message_len = Some(len);
+        self
+    }
+
+    /// Compress the responses with `algo`, instead of the default algorithm
+    /// of the server.
+    pub fn compression(mut self, algo: CompressionAlgorithms) -> MethodConfig {
+        self.compression = Some(Compression::Algorithm(algo));
+        self
+    }
+
+    /// Compress the responses at `level`, instead of the default level of
+    /// the server, see [`algorithm_for_level`](fn.algorithm_for_level.html).
+    pub fn compression_level(mut self, level: CompressionLevel) -> MethodConfig {
+        self.compression = Some(Compression::Level(level));
+        self
+    }
+
+    /// Finish the calls that run longer than `timeout`.
+    pub fn timeout(mut self, timeout: Duration) -> MethodConfig {
+        self.timeout = Some(timeout);
+        self
+    }
+
+    pub fn get_timeout(&self) -> Option<Duration> {
+        self.timeout
+    }
+
+    /// Get the status a call is failed with if it receives a message of `len`.
+    pub(crate) fn check_receive(&self, len: usize) -> Option<RpcStatus> {
+        check(self.max_receive_message_len, len, "Received")
+    }
+
+    /// Get the status a call is failed with if it sends a message of `len`.
+    pub(crate) fn check_send(&self, len: usize) -> Option<RpcStatus> {
+        check(self.max_send_message_len, len, "Sent")
+    }
+
+    /// Get the initial metadata that has to be sent before any response.
+    pub(crate) fn initial_metadata(&self) -> Option<Metadata> {
+        let algo = match self.compression? {
+            Compression::Algorithm(algo) => algo,
+            Compression::Level(level) => algorithm_for_level(level),
+        };
+        Some(compression_metadata(algo))
+    }
+}
+
+/// Get the algorithm gRPC core maps `level` to for a peer that accepts all
+/// the algorithms, which is what gRPC clients do by default.
+///
+/// Levels trade ratio for speed only by choosing the algorithm, gRPC core
+/// always compresses with the default zlib level. Use `None` to save the
+/// CPU time of compressing altogether.
+pub fn algorithm_for_level(level: CompressionLevel) -> CompressionAlgorithms {
+    match level {
+        CompressionLevel::None => CompressionAlgorithms::None,
+        CompressionLevel::Low => CompressionAlgorithms::Gzip,
+        CompressionLevel::Med | CompressionLevel::High => CompressionAlgorithms::Deflate,
+    }
+}
+
+/// Get the metadata that overrides the compression algorithm of a call.
+pub(crate) fn compression_metadata(algo: CompressionAlgorithms) -> Metadata {
+    let name = match algo {
+        CompressionAlgorithms::None => "identity",
+        CompressionAlgorithms::Deflate => "deflate",
+        CompressionAlgorithms::Gzip => "gzip",
+    };
+    let mut builder = MetadataBuilder::with_capacity(1);
+    builder.add_str(COMPRESSION_REQUEST_KEY, name).unwrap();
+    builder.build()
+}
+
+fn check(limit: Option<usize>, len: usize, action: &str) -> Option<RpcStatus> {
+    match limit {
+        Some(max) if len > max => Some(RpcStatus::new(
+            RpcStatusCode::ResourceExhausted,
+            Some(format!(
+                "{} message larger than max ({} vs. {})",
+                action, len, max
+            )),
+        )),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_method_config() {
+        let config = MethodConfig::new()
+            .max_receive_message_len(10)
+            .max_send_message_len(20);
+        assert!(config.check_receive(10).is_none());
+        let status = config.check_receive(11).unwrap();
+        assert_eq!(status.status, RpcStatusCode::ResourceExhausted);
+        assert_eq!(
+            status.details.unwrap(),
+            "Received message larger than max (11 vs. 10)"
+        );
+        assert!(config.check_send(20).is_none());
+        assert!(config.check_send(21).is_some());
+        assert!(config.initial_metadata().is_none());
+        assert!(MethodConfig::new()
+            .check_receive(usize::max_value())
+            .is_none());
+
+        let meta = config
+            .compression(CompressionAlgorithms::Gzip)
+            .initial_metadata()
+            .unwrap();
+        assert_eq!(
+            meta.iter().collect::<Vec<_>>(),
+            vec![(COMPRESSION_REQUEST_KEY, b"gzip".as_ref())]
+        );
+
+        // The last one of the algorithm and the level takes effect.
+        let meta = MethodConfig::new()
+            .compression(CompressionAlgorithms::Gzip)
+            .compression_level(CompressionLevel::None)
+            .initial_metadata()
+            .unwrap();
+        assert_eq!(
+            meta.iter().collect::<Vec<_>>(),
+            vec![(COMPRESSION_REQUEST_KEY, b"identity".as_ref())]
+        );
+        assert_eq!(
+            algorithm_for_level(CompressionLevel::High),
+            CompressionAlgorithms::Deflate
+        );
+    }
+}
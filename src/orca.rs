@@ -0,0 +1,457 @@
+// Copyright 2018 PingCAP, Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Backend metrics of calls, as defined by ORCA (Open Request Cost
+//! Aggregation).
+//!
+//! When enabled by [`ServerBuilder::call_metric_recording`], handlers record
+//! the load of the server in [`RpcContext::call_metric_recorder`], and the
+//! [`LoadReport`] is sent to the client in the `endpoint-load-metrics-bin`
+//! trailer, encoded as `xds.data.orca.v3.OrcaLoadReport`:
+//!
+//! ```ignore
+//! if let Some(r) = ctx.call_metric_recorder() {
+//!     r.record_cpu_utilization(cpu.usage());
+//!     r.record_request_cost("db_rows", rows as f64);
+//! }
+//! ```
+//!
+//! Clients get the reports from the trailers of the calls, which is what
+//! weighted load balancing needs:
+//!
+//! ```ignore
+//! if let Some(report) = receiver.take_trailers().and_then(|t| LoadReport::from_trailers(&t)) {
+//!     weights.update(addr, report.qps() / report.cpu_utilization());
+//! }
+//! ```
+//!
+//! Besides the binary trailer, reports in the `TEXT` format of the
+//! `endpoint-load-metrics` trailer are parsed too, e.g.
+//! `TEXT cpu_utilization=0.3, named_metrics.queue=12`.
+//!
+//! [`ServerBuilder::call_metric_recording`]: ../struct.ServerBuilder.html#method.call_metric_recording
+//! [`RpcContext::call_metric_recorder`]: ../struct.RpcContext.html#method.call_metric_recorder
+//! [`LoadReport`]: struct.LoadReport.html
+
+use std::collections::HashMap;
+use std::str;
+use std::sync::Mutex;
+
+use metadata::{Metadata, MetadataBuilder};
+
+/// The trailer that carries the binary load report.
+pub const LOAD_REPORT_KEY: &str = "endpoint-load-metrics-bin";
+/// The trailer that carries the load report in text.
+pub const TEXT_LOAD_REPORT_KEY: &str = "endpoint-load-metrics";
+
+/// The load of a server reported along with a call.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct LoadReport {
+    cpu_utilization: f64,
+    mem_utilization: f64,
+    application_utilization: f64,
+    qps: f64,
+    eps: f64,
+    request_cost: HashMap<String, f64>,
+    utilization: HashMap<String, f64>,
+    named_metrics: HashMap<String, f64>,
+}
+
+impl LoadReport {
+    pub fn new() -> LoadReport {
+        LoadReport::default()
+    }
+
+    /// Get the report carried by `trailers`, `None` if there is none or it's
+    /// malformed.
+    pub fn from_trailers(trailers: &Metadata) -> Option<LoadReport> {
+        if let Some((_, v)) = trailers.iter().find(|&(k, _)| k == LOAD_REPORT_KEY) {
+            return LoadReport::decode(v);
+        }
+        let (_, v) = trailers.iter().find(|&(k, _)| k == TEXT_LOAD_REPORT_KEY)?;
+        LoadReport::parse_text(str::from_utf8(v).ok()?)
+    }
+
+    /// CPU utilization, usually in `[0, 1]`, but it may exceed 1 when the
+    /// server uses more CPU than it's entitled to.
+    pub fn cpu_utilization(&self) -> f64 {
+        self.cpu_utilization
+    }
+
+    /// Memory utilization in `[0, 1]`.
+    pub fn mem_utilization(&self) -> f64 {
+        self.mem_utilization
+    }
+
+    /// Utilization defined by the application, used instead of the CPU
+    /// utilization by weighted round robin when it's set.
+    pub fn application_utilization(&self) -> f64 {
+        self.application_utilization
+    }
+
+    /// Queries per second served.
+    pub fn qps(&self) -> f64 {
+        self.qps
+    }
+
+    /// Errors per second.
+    pub fn eps(&self) -> f64 {
+        self.eps
+    }
+
+    /// Costs of the call, e.g. the rows it scanned.
+    pub fn request_cost(&self) -> &HashMap<String, f64> {
+        &self.request_cost
+    }
+
+    /// Utilization of resources, each in `[0, 1]`.
+    pub fn utilization(&self) -> &HashMap<String, f64> {
+        &self.utilization
+    }
+
+    /// Metrics defined by the application.
+    pub fn named_metrics(&self) -> &HashMap<String, f64> {
+        &self.named_metrics
+    }
+
+    pub fn set_cpu_utilization(&mut self, v: f64) {
+        self.cpu_utilization = v;
+    }
+
+    pub fn set_mem_utilization(&mut self, v: f64) {
+        self.mem_utilization = v;
+    }
+
+    pub fn set_application_utilization(&mut self, v: f64) {
+        self.application_utilization = v;
+    }
+
+    pub fn set_qps(&mut self, v: f64) {
+        self.qps = v;
+    }
+
+    pub fn set_eps(&mut self, v: f64) {
+        self.eps = v;
+    }
+
+    pub fn mut_request_cost(&mut self) -> &mut HashMap<String, f64> {
+        &mut self.request_cost
+    }
+
+    pub fn mut_utilization(&mut self) -> &mut HashMap<String, f64> {
+        &mut self.utilization
+    }
+
+    pub fn mut_named_metrics(&mut self) -> &mut HashMap<String, f64> {
+        &mut self.named_metrics
+    }
+
+    pub fn is_empty(&self) -> bool {
+        *self == LoadReport::default()
+    }
+
+    /// Encode the report as `OrcaLoadReport`.
+    pub fn encode(&self) -> Vec<u8> {
+        let mut buf = vec![];
+        put_double(&mut buf, 1, self.cpu_utilization);
+        put_double(&mut buf, 2, self.mem_utilization);
+        put_map(&mut buf, 4, &self.request_cost);
+        put_map(&mut buf, 5, &self.utilization);
+        put_double(&mut buf, 6, self.qps);
+        put_double(&mut buf, 7, self.eps);
+        put_map(&mut buf, 8, &self.named_metrics);
+        put_double(&mut buf, 9, self.application_utilization);
+        buf
+    }
+
+    /// Decode an `OrcaLoadReport`, `None` if it's malformed.
+    pub fn decode(mut buf: &[u8]) -> Option<LoadReport> {
+        let mut report = LoadReport::default();
+        // The deprecated integral `rps` is used if `rps_fractional` is absent.
+        let mut rps = None;
+        while !buf.is_empty() {
+            let key = get_varint(&mut buf)?;
+            let (field, wire_type) = (key >> 3, key & 7);
+            match (field, wire_type) {
+                (1, 1) => report.cpu_utilization = get_double(&mut buf)?,
+                (2, 1) => report.mem_utilization = get_double(&mut buf)?,
+                (3, 0) => rps = Some(get_varint(&mut buf)? as f64),
+                (4, 2) => get_entry(&mut buf, &mut report.request_cost)?,
+                (5, 2) => get_entry(&mut buf, &mut report.utilization)?,
+                (6, 1) => report.qps = get_double(&mut buf)?,
+                (7, 1) => report.eps = get_double(&mut buf)?,
+                (8, 2) => get_entry(&mut buf, &mut report.named_metrics)?,
+                (9, 1) => report.application_utilization = get_double(&mut buf)?,
+                (_, 0) => {
+                    get_varint(&mut buf)?;
+                }
+                (_, 1) => {
+                    get_bytes(&mut buf, 8)?;
+                }
+                (_, 2) => {
+                    get_len_delimited(&mut buf)?;
+                }
+                (_, 5) => {
+                    get_bytes(&mut buf, 4)?;
+                }
+                _ => return None,
+            }
+        }
+        if report.qps == 0.0 {
+            report.qps = rps.unwrap_or(0.0);
+        }
+        Some(report)
+    }
+
+    /// Parse a report in the `TEXT` format, e.g.
+    /// `TEXT cpu_utilization=0.3, request_cost.rows=100`.
+    pub fn parse_text(s: &str) -> Option<LoadReport> {
+        let s = s.trim();
+        if !s.starts_with("TEXT ") {
+            return None;
+        }
+        let mut report = LoadReport::default();
+        for pair in s["TEXT ".len()..].split(',') {
+            let mut kv = pair.splitn(2, '=');
+            let (key, value) = (kv.next()?.trim(), kv.next()?.trim());
+            let value: f64 = value.parse().ok()?;
+            match key {
+                "cpu_utilization" => report.cpu_utilization = value,
+                "mem_utilization" => report.mem_utilization = value,
+                "application_utilization" => report.application_utilization = value,
+                "rps_fractional" => report.qps = value,
+                "eps" => report.eps = value,
+                _ => {
+                    let mut name = key.splitn(2, '.');
+                    let map = match name.next()? {
+                        "request_cost" => &mut report.request_cost,
+                        "utilization" => &mut report.utilization,
+                        "named_metrics" => &mut report.named_metrics,
+                        _ => return None,
+                    };
+                    map.insert(name.next()?.to_owned(), value);
+                }
+            }
+        }
+        Some(report)
+    }
+}
+
+fn put_varint(buf: &mut Vec<u8>, mut v: u64) {
+    while v >= 0x80 {
+        buf.push(v as u8 | 0x80);
+        v >>= 7;
+    }
+    buf.push(v as u8);
+}
+
+fn put_double(buf: &mut Vec<u8>, field: u64, v: f64) {
+    // Default values are omitted like proto3 does.
+    if v == 0.0 {
+        return;
+    }
+    put_varint(buf, field << 3 | 1);
+    buf.extend_from_slice(&v.to_bits().to_le_bytes());
+}
+
+fn put_map(buf: &mut Vec<u8>, field: u64, map: &HashMap<String, f64>) {
+    for (k, v) in map {
+        let mut entry = vec![];
+        put_varint(&mut entry, 1 << 3 | 2);
+        put_varint(&mut entry, k.len() as u64);
+        entry.extend_from_slice(k.as_bytes());
+        put_varint(&mut entry, 2 << 3 | 1);
+        entry.extend_from_slice(&v.to_bits().to_le_bytes());
+        put_varint(buf, field << 3 | 2);
+        put_varint(buf, entry.len() as u64);
+        buf.extend_from_slice(&entry);
+    }
+}
+
+fn get_varint(buf: &mut &[u8]) -> Option<u64> {
+    let mut v = 0;
+    for i in 0..10 {
+        let (&b, rest) = buf.split_first()?;
+        *buf = rest;
+        v |= u64::from(b & 0x7f) << (7 * i);
+        if b < 0x80 {
+            return Some(v);
+        }
+    }
+    None
+}
+
+fn get_bytes<'a>(buf: &mut &'a [u8], len: usize) -> Option<&'a [u8]> {
+    if buf.len() < len {
+        return None;
+    }
+    let (bytes, rest) = buf.split_at(len);
+    *buf = rest;
+    Some(bytes)
+}
+
+fn get_len_delimited<'a>(buf: &mut &'a [u8]) -> Option<&'a [u8]> {
+    let len = get_varint(buf)?;
+    if len > buf.len() as u64 {
+        return None;
+    }
+    get_bytes(buf, len as usize)
+}
+
+fn get_double(buf: &mut &[u8]) -> Option<f64> {
+    let mut bits = [0; 8];
+    bits.copy_from_slice(get_bytes(buf, 8)?);
+    Some(f64::from_bits(u64::from_le_bytes(bits)))
+}
+
+fn get_entry(buf: &mut &[u8], map: &mut HashMap<String, f64>) -> Option<()> {
+    let mut entry = get_len_delimited(buf)?;
+    let (mut key, mut value) = (String::new(), 0.0);
+    while !entry.is_empty() {
+        match get_varint(&mut entry)? {
+            k if k == 1 << 3 | 2 => {
+                key = str::from_utf8(get_len_delimited(&mut entry)?)
+                    .ok()?
+                    .to_owned()
+            }
+            k if k == 2 << 3 | 1 => value = get_double(&mut entry)?,
+            _ => return None,
+        }
+    }
+    map.insert(key, value);
+    Some(())
+}
+
+fn is_valid(v: f64) -> bool {
+    v >= 0.0
+}
+
+/// Records the load report of a call, which is sent to the client in the
+/// trailers once the call is finished.
+///
+/// Negative and NaN values are ignored.
+#[derive(Debug, Default)]
+pub struct CallMetricRecorder {
+    report: Mutex<LoadReport>,
+}
+
+impl CallMetricRecorder {
+    pub fn record_cpu_utilization(&self, v: f64) {
+        if is_valid(v) {
+            self.report.lock().unwrap().cpu_utilization = v;
+        }
+    }
+
+    pub fn record_mem_utilization(&self, v: f64) {
+        if is_valid(v) {
+            self.report.lock().unwrap().mem_utilization = v;
+        }
+    }
+
+    pub fn record_application_utilization(&self, v: f64) {
+        if is_valid(v) {
+            self.report.lock().unwrap().application_utilization = v;
+        }
+    }
+
+    pub fn record_qps(&self, v: f64) {
+        if is_valid(v) {
+            self.report.lock().unwrap().qps = v;
+        }
+    }
+
+    pub fn record_eps(&self, v: f64) {
+        if is_valid(v) {
+            self.report.lock().unwrap().eps = v;
+        }
+    }
+
+    pub fn record_request_cost(&self, name: &str, v: f64) {
+        if is_valid(v) {
+            let mut r = self.report.lock().unwrap();
+            r.request_cost.insert(name.to_owned(), v);
+        }
+    }
+
+    pub fn record_utilization(&self, name: &str, v: f64) {
+        if is_valid(v) {
+            let mut r = self.report.lock().unwrap();
+            r.utilization.insert(name.to_owned(), v);
+        }
+    }
+
+    pub fn record_named_metric(&self, name: &str, v: f64) {
+        if is_valid(v) {
+            let mut r = self.report.lock().unwrap();
+            r.named_metrics.insert(name.to_owned(), v);
+        }
+    }
+
+    /// Get a copy of what is recorded so far.
+    pub fn report(&self) -> LoadReport {
+        self.report.lock().unwrap().clone()
+    }
+
+    /// Add the report to `trailers`, unless nothing is recorded.
+    pub(crate) fn append(&self, trailers: Option<Metadata>) -> Option<Metadata> {
+        let report = self.report.lock().unwrap();
+        if report.is_empty() {
+            return trailers;
+        }
+        let mut builder = match trailers {
+            Some(m) => MetadataBuilder::from_metadata(m),
+            None => MetadataBuilder::with_capacity(1),
+        };
+        builder
+            .add_bytes(LOAD_REPORT_KEY, &report.encode())
+            .unwrap();
+        Some(builder.build())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_load_report() {
+        let recorder = CallMetricRecorder::default();
+        assert!(recorder.append(None).is_none());
+        recorder.record_cpu_utilization(0.5);
+        recorder.record_mem_utilization(-1.0);
+        recorder.record_qps(120.0);
+        recorder.record_request_cost("rows", 1000.0);
+        recorder.record_utilization("disk", 0.25);
+        recorder.record_named_metric("queue", 3.0);
+        let report = recorder.report();
+        assert_eq!(report.mem_utilization(), 0.0);
+
+        let decoded = LoadReport::decode(&report.encode()).unwrap();
+        assert_eq!(decoded, report);
+        let trailers = recorder.append(None).unwrap();
+        assert_eq!(LoadReport::from_trailers(&trailers), Some(report));
+        assert_eq!(LoadReport::decode(&[0x09, 0, 0]), None);
+
+        // Integral `rps` (3) and unknown fields (10).
+        let decoded = LoadReport::decode(&[0x18, 0x05, 0x50, 0x01]).unwrap();
+        assert_eq!(decoded.qps(), 5.0);
+
+        let text = "TEXT cpu_utilization=0.3, rps_fractional=7, named_metrics.queue=12";
+        let parsed = LoadReport::parse_text(text).unwrap();
+        assert_eq!(parsed.cpu_utilization(), 0.3);
+        assert_eq!(parsed.qps(), 7.0);
+        assert_eq!(parsed.named_metrics()["queue"], 12.0);
+        assert_eq!(LoadReport::parse_text("JSON {}"), None);
+        assert_eq!(LoadReport::parse_text("TEXT foo=1"), None);
+    }
+}
@@ -0,0 +1,507 @@
+// Copyright 2018 PingCAP, Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Ejection of failing backends.
+//!
+//! gRPC core 1.7 doesn't have outlier detection, it's applied by
+//! [`WeightedRoundRobin`] when it's set by [`WrrConfig::outlier_detection`]:
+//!
+//! ```ignore
+//! let detection = OutlierDetection::new()
+//!     .consecutive_failures(5)
+//!     .failure_percentage(FailurePercentageEjection::new().threshold(50));
+//! let lb = WeightedRoundRobin::new(backends, WrrConfig::new().outlier_detection(detection));
+//! ```
+//!
+//! It works the same as the xDS outlier detection of gRPC: a call counts as
+//! a failed one unless its status is `Ok`, and every interval the counts of
+//! the last interval are checked against the enabled ejections:
+//!
+//! - success rate, which ejects the backends whose success rate is more than
+//!   `stdev_factor / 1000` standard deviations below the mean;
+//! - failure percentage, which ejects the backends whose failures are more
+//!   than `threshold` percent of their calls.
+//!
+//! Besides, a backend is ejected right away once it fails the configured
+//! number of consecutive calls.
+//!
+//! An ejected backend gets no calls for the base ejection time, multiplied by
+//! the times it's ejected in a row, up to the max ejection time. No more
+//! than the max ejection percentage of the backends are ejected at a time,
+//! but at least one can be.
+//!
+//! [`WeightedRoundRobin`]: ../wrr/struct.WeightedRoundRobin.html
+//! [`WrrConfig::outlier_detection`]: ../wrr/struct.WrrConfig.html#method.outlier_detection
+
+use std::cmp;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+/// Ejection of the backends whose success rate is far below the others.
+#[derive(Clone, Debug)]
+pub struct SuccessRateEjection {
+    stdev_factor: u32,
+    enforcement_percentage: u32,
+    minimum_hosts: usize,
+    request_volume: u64,
+}
+
+impl Default for SuccessRateEjection {
+    fn default() -> SuccessRateEjection {
+        SuccessRateEjection {
+            stdev_factor: 1900,
+            enforcement_percentage: 100,
+            minimum_hosts: 5,
+            request_volume: 100,
+        }
+    }
+}
+
+impl SuccessRateEjection {
+    pub fn new() -> SuccessRateEjection {
+        SuccessRateEjection::default()
+    }
+
+    /// Eject the backends whose success rate is below the mean by more than
+    /// `factor / 1000` standard deviations, 1900 by default.
+    pub fn stdev_factor(mut self, factor: u32) -> SuccessRateEjection {
+        self.stdev_factor = factor;
+        self
+    }
+
+    /// Set the chance in percent that an outlier is ejected, 100 by default.
+    pub fn enforcement_percentage(mut self, percentage: u32) -> SuccessRateEjection {
+        self.enforcement_percentage = cmp::min(percentage, 100);
+        self
+    }
+
+    /// Only check when at least `hosts` backends have enough calls, 5 by
+    /// default.
+    pub fn minimum_hosts(mut self, hosts: usize) -> SuccessRateEjection {
+        self.minimum_hosts = hosts;
+        self
+    }
+
+    /// Only check the backends with at least `calls` calls in the interval,
+    /// 100 by default.
+    pub fn request_volume(mut self, calls: u64) -> SuccessRateEjection {
+        self.request_volume = calls;
+        self
+    }
+}
+
+/// Ejection of the backends that fail too many calls.
+#[derive(Clone, Debug)]
+pub struct FailurePercentageEjection {
+    threshold: u32,
+    enforcement_percentage: u32,
+    minimum_hosts: usize,
+    request_volume: u64,
+}
+
+impl Default for FailurePercentageEjection {
+    fn default() -> FailurePercentageEjection {
+        FailurePercentageEjection {
+            threshold: 85,
+            enforcement_percentage: 100,
+            minimum_hosts: 5,
+            request_volume: 50,
+        }
+    }
+}
+
+impl FailurePercentageEjection {
+    pub fn new() -> FailurePercentageEjection {
+        FailurePercentageEjection::default()
+    }
+
+    /// Eject the backends that fail more than `percentage` of their calls,
+    /// 85 by default.
+    pub fn threshold(mut self, percentage: u32) -> FailurePercentageEjection {
+        self.threshold = cmp::min(percentage, 100);
+        self
+    }
+
+    /// Set the chance in percent that an outlier is ejected, 100 by default.
+    pub fn enforcement_percentage(mut self, percentage: u32) -> FailurePercentageEjection {
+        self.enforcement_percentage = cmp::min(percentage, 100);
+        self
+    }
+
+    /// Only check when at least `hosts` backends have enough calls, 5 by
+    /// default.
+    pub fn minimum_hosts(mut self, hosts: usize) -> FailurePercentageEjection {
+        self.minimum_hosts = hosts;
+        self
+    }
+
+    /// Only check the backends with at least `calls` calls in the interval,
+    /// 50 by default.
+    pub fn request_volume(mut self, calls: u64) -> FailurePercentageEjection {
+        self.request_volume = calls;
+        self
+    }
+}
+
+/// Configuration of outlier detection, see [`outlier`](index.html) for
+/// details. Nothing is ejected unless one of the ejections is enabled.
+#[derive(Clone, Debug)]
+pub struct OutlierDetection {
+    interval: Duration,
+    base_ejection_time: Duration,
+    max_ejection_time: Duration,
+    max_ejection_percent: u32,
+    consecutive_failures: Option<u32>,
+    success_rate: Option<SuccessRateEjection>,
+    failure_percentage: Option<FailurePercentageEjection>,
+}
+
+impl Default for OutlierDetection {
+    fn default() -> OutlierDetection {
+        OutlierDetection {
+            interval: Duration::from_secs(10),
+            base_ejection_time: Duration::from_secs(30),
+            max_ejection_time: Duration::from_secs(300),
+            max_ejection_percent: 10,
+            consecutive_failures: None,
+            success_rate: None,
+            failure_percentage: None,
+        }
+    }
+}
+
+impl OutlierDetection {
+    pub fn new() -> OutlierDetection {
+        OutlierDetection::default()
+    }
+
+    /// Set how often the backends are checked, 10 seconds by default.
+    pub fn interval(mut self, interval: Duration) -> OutlierDetection {
+        self.interval = interval;
+        self
+    }
+
+    /// Set how long a backend is ejected the first time, 30 seconds by
+    /// default.
+    pub fn base_ejection_time(mut self, time: Duration) -> OutlierDetection {
+        self.base_ejection_time = time;
+        self
+    }
+
+    /// Set how long a backend is ejected at most, 300 seconds by default, or
+    /// the base ejection time if it's longer.
+    pub fn max_ejection_time(mut self, time: Duration) -> OutlierDetection {
+        self.max_ejection_time = time;
+        self
+    }
+
+    /// Set how many of the backends in percent can be ejected at a time, 10
+    /// by default.
+    pub fn max_ejection_percent(mut self, percent: u32) -> OutlierDetection {
+        self.max_ejection_percent = cmp::min(percent, 100);
+        self
+    }
+
+    /// Eject a backend once it fails `failures` calls in a row.
+    pub fn consecutive_failures(mut self, failures: u32) -> OutlierDetection {
+        self.consecutive_failures = Some(failures);
+        self
+    }
+
+    pub fn success_rate(mut self, ejection: SuccessRateEjection) -> OutlierDetection {
+        self.success_rate = Some(ejection);
+        self
+    }
+
+    pub fn failure_percentage(mut self, ejection: FailurePercentageEjection) -> OutlierDetection {
+        self.failure_percentage = Some(ejection);
+        self
+    }
+}
+
+#[derive(Default)]
+struct Host {
+    successes: u64,
+    failures: u64,
+    // The counts of the last interval.
+    last_successes: u64,
+    last_failures: u64,
+    consecutive_failures: u32,
+    ejected_at: Option<Instant>,
+    multiplier: u32,
+}
+
+impl Host {
+    fn volume(&self) -> u64 {
+        self.last_successes + self.last_failures
+    }
+
+    fn success_rate(&self) -> f64 {
+        self.last_successes as f64 / self.volume() as f64
+    }
+}
+
+/// Tracks the calls of the backends of a balancer.
+pub(crate) struct Detector {
+    config: OutlierDetection,
+    hosts: Vec<Host>,
+    last_sweep: Instant,
+    rng: u64,
+}
+
+impl Detector {
+    pub fn new(config: OutlierDetection, hosts: usize, now: Instant) -> Detector {
+        let seed = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| u64::from(d.subsec_nanos()))
+            .unwrap_or(0);
+        Detector {
+            config,
+            hosts: (0..hosts).map(|_| Host::default()).collect(),
+            last_sweep: now,
+            // Xorshift gets stuck at 0.
+            rng: seed | 1,
+        }
+    }
+
+    pub fn is_ejected(&self, host: usize) -> bool {
+        self.hosts[host].ejected_at.is_some()
+    }
+
+    fn roll(&mut self, percentage: u32) -> bool {
+        if percentage >= 100 {
+            return true;
+        }
+        self.rng ^= self.rng << 13;
+        self.rng ^= self.rng >> 7;
+        self.rng ^= self.rng << 17;
+        self.rng % 100 < u64::from(percentage)
+    }
+
+    fn can_eject(&self) -> bool {
+        let ejected = self.hosts.iter().filter(|h| h.ejected_at.is_some()).count();
+        // At least one host can be ejected, however small the percentage is.
+        (ejected == 0 && self.config.max_ejection_percent > 0)
+            || ejected * 100 < self.hosts.len() * self.config.max_ejection_percent as usize
+    }
+
+    fn eject(&mut self, host: usize, now: Instant) {
+        let h = &mut self.hosts[host];
+        h.ejected_at = Some(now);
+        h.multiplier += 1;
+    }
+
+    /// Record the result of a call, returns whether the host is ejected by
+    /// it.
+    pub fn record(&mut self, host: usize, success: bool, now: Instant) -> bool {
+        let limit = self.config.consecutive_failures;
+        let h = &mut self.hosts[host];
+        if success {
+            h.successes += 1;
+            h.consecutive_failures = 0;
+            return false;
+        }
+        h.failures += 1;
+        h.consecutive_failures += 1;
+        let exceeded = match limit {
+            Some(n) => h.ejected_at.is_none() && h.consecutive_failures >= n,
+            None => false,
+        };
+        if exceeded && self.can_eject() {
+            self.hosts[host].consecutive_failures = 0;
+            self.eject(host, now);
+            return true;
+        }
+        false
+    }
+
+    /// Check the hosts if the interval is up, returns whether any host is
+    /// ejected or returned.
+    pub fn sweep(&mut self, now: Instant) -> bool {
+        if now < self.last_sweep + self.config.interval {
+            return false;
+        }
+        self.last_sweep = now;
+        for h in &mut self.hosts {
+            h.last_successes = h.successes;
+            h.last_failures = h.failures;
+            h.successes = 0;
+            h.failures = 0;
+        }
+        let mut changed = false;
+        if let Some(ejection) = self.config.success_rate.clone() {
+            changed |= self.eject_by_success_rate(&ejection, now);
+        }
+        if let Some(ejection) = self.config.failure_percentage.clone() {
+            changed |= self.eject_by_failure_percentage(&ejection, now);
+        }
+
+        let (base, max) = (
+            self.config.base_ejection_time,
+            self.config.max_ejection_time,
+        );
+        for h in &mut self.hosts {
+            match h.ejected_at {
+                Some(at) => {
+                    let time = cmp::min(base * h.multiplier, cmp::max(base, max));
+                    if now >= at + time {
+                        h.ejected_at = None;
+                        changed = true;
+                    }
+                }
+                None => h.multiplier = h.multiplier.saturating_sub(1),
+            }
+        }
+        changed
+    }
+
+    fn candidates(&self, request_volume: u64) -> Vec<usize> {
+        (0..self.hosts.len())
+            .filter(|i| self.hosts[*i].ejected_at.is_none())
+            .filter(|i| self.hosts[*i].volume() >= cmp::max(request_volume, 1))
+            .collect()
+    }
+
+    fn eject_by_success_rate(&mut self, ejection: &SuccessRateEjection, now: Instant) -> bool {
+        let candidates = self.candidates(ejection.request_volume);
+        if candidates.is_empty() || candidates.len() < ejection.minimum_hosts {
+            return false;
+        }
+        let rates: Vec<f64> = candidates
+            .iter()
+            .map(|i| self.hosts[*i].success_rate())
+            .collect();
+        let mean = rates.iter().sum::<f64>() / rates.len() as f64;
+        let variance =
+            rates.iter().map(|r| (r - mean) * (r - mean)).sum::<f64>() / rates.len() as f64;
+        let threshold = mean - variance.sqrt() * (f64::from(ejection.stdev_factor) / 1000.0);
+        let mut changed = false;
+        for (i, rate) in candidates.into_iter().zip(rates) {
+            if rate < threshold && self.can_eject() && self.roll(ejection.enforcement_percentage) {
+                self.eject(i, now);
+                changed = true;
+            }
+        }
+        changed
+    }
+
+    fn eject_by_failure_percentage(
+        &mut self,
+        ejection: &FailurePercentageEjection,
+        now: Instant,
+    ) -> bool {
+        let candidates = self.candidates(ejection.request_volume);
+        if candidates.is_empty() || candidates.len() < ejection.minimum_hosts {
+            return false;
+        }
+        let mut changed = false;
+        for i in candidates {
+            let h = &self.hosts[i];
+            let failed = h.last_failures * 100 > u64::from(ejection.threshold) * h.volume();
+            if failed && self.can_eject() && self.roll(ejection.enforcement_percentage) {
+                self.eject(i, now);
+                changed = true;
+            }
+        }
+        changed
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn calls(d: &mut Detector, host: usize, successes: u64, failures: u64, now: Instant) {
+        for _ in 0..successes {
+            d.record(host, true, now);
+        }
+        for _ in 0..failures {
+            d.record(host, false, now);
+        }
+    }
+
+    #[test]
+    fn test_consecutive_failures() {
+        let config = OutlierDetection::new()
+            .consecutive_failures(3)
+            .max_ejection_percent(50)
+            .base_ejection_time(Duration::from_secs(30))
+            .max_ejection_time(Duration::from_secs(50))
+            .interval(Duration::from_secs(10));
+        let start = Instant::now();
+        let at = |secs| start + Duration::from_secs(secs);
+        let mut d = Detector::new(config, 4, start);
+        assert!(!d.record(0, false, start));
+        d.record(0, false, start);
+        d.record(0, true, start);
+        assert!(!d.record(0, false, start));
+        d.record(0, false, start);
+        assert!(d.record(0, false, start));
+        assert!(d.is_ejected(0));
+        // Up to half of the hosts.
+        calls(&mut d, 1, 0, 3, start);
+        calls(&mut d, 2, 0, 3, start);
+        assert!(d.is_ejected(1));
+        assert!(!d.is_ejected(2));
+
+        assert!(!d.sweep(at(5)));
+        assert!(d.sweep(at(30)));
+        assert!(!d.is_ejected(0) && !d.is_ejected(1));
+        // Ejected for longer every time in a row, up to the max.
+        calls(&mut d, 0, 0, 3, at(30));
+        assert!(!d.sweep(at(70)));
+        assert!(d.sweep(at(80)));
+        assert!(!d.is_ejected(0));
+    }
+
+    #[test]
+    fn test_ejections() {
+        let start = Instant::now();
+        let at = |secs| start + Duration::from_secs(secs);
+        let detection = OutlierDetection::new().max_ejection_percent(100);
+
+        let config = detection.clone().failure_percentage(
+            FailurePercentageEjection::new()
+                .threshold(50)
+                .minimum_hosts(3)
+                .request_volume(10),
+        );
+        let mut d = Detector::new(config, 4, start);
+        calls(&mut d, 0, 10, 0, start);
+        calls(&mut d, 1, 10, 0, start);
+        calls(&mut d, 2, 4, 6, start);
+        // Too few calls to be checked.
+        calls(&mut d, 3, 0, 9, start);
+        assert!(d.sweep(at(10)));
+        assert_eq!(
+            (0..4).map(|i| d.is_ejected(i)).collect::<Vec<_>>(),
+            vec![false, false, true, false]
+        );
+
+        let config = detection.success_rate(
+            SuccessRateEjection::new()
+                .stdev_factor(1000)
+                .minimum_hosts(4)
+                .request_volume(10),
+        );
+        let mut d = Detector::new(config, 4, start);
+        for i in 0..3 {
+            calls(&mut d, i, 10, 0, start);
+        }
+        calls(&mut d, 3, 7, 3, start);
+        assert!(d.sweep(at(10)));
+        assert!(d.is_ejected(3));
+        assert!((0..3).all(|i| !d.is_ejected(i)));
+        // Counts of the interval are checked, which is empty now.
+        assert!(!d.sweep(at(20)));
+    }
+}
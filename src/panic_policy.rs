@@ -0,0 +1,182 @@
+// Copyright 2018 PingCAP, Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Handling of panics raised by server handlers.
+//!
+//! By default a panicking handler is caught, its call is finished with
+//! `Internal` if no status has been sent, and the completion queue thread
+//! keeps serving other calls. [`PanicPolicy::strict`] lets panics propagate
+//! instead, which takes down the poll thread as before.
+//!
+//! Both the handler invocation and the futures it spawns by
+//! [`RpcContext::spawn`] are guarded. Panics raised by futures spawned by
+//! other means, e.g. onto a runtime directly, are not caught.
+//!
+//! [`PanicPolicy::strict`]: struct.PanicPolicy.html#method.strict
+//! [`RpcContext::spawn`]: ../struct.RpcContext.html#method.spawn
+
+use std::any::Any;
+use std::sync::Arc;
+
+/// Message sent to client when the panic message is not exposed.
+pub const REDACTED_MESSAGE: &str = "handler panicked";
+
+/// A panic raised by a handler.
+#[derive(Debug)]
+pub struct HandlerPanic<'a> {
+    method: &'a str,
+    request_id: Option<&'a str>,
+    message: Option<&'a str>,
+}
+
+impl<'a> HandlerPanic<'a> {
+    /// The method being handled.
+    pub fn method(&self) -> &str {
+        self.method
+    }
+
+    /// The ID of the request, if [`ServerBuilder::request_id`] is enabled.
+    ///
+    /// [`ServerBuilder::request_id`]: ../struct.ServerBuilder.html#method.request_id
+    pub fn request_id(&self) -> Option<&str> {
+        self.request_id
+    }
+
+    /// The panic message, `None` if the payload is not a string.
+    pub fn message(&self) -> Option<&str> {
+        self.message
+    }
+}
+
+/// How panics raised by handlers are handled.
+#[derive(Clone)]
+pub struct PanicPolicy {
+    strict: bool,
+    expose_message: bool,
+    hook: Option<Arc<Fn(&HandlerPanic) + Send + Sync>>,
+}
+
+impl PanicPolicy {
+    /// Catch panics and finish the calls with [`REDACTED_MESSAGE`].
+    ///
+    /// [`REDACTED_MESSAGE`]: constant.REDACTED_MESSAGE.html
+    pub fn new() -> PanicPolicy {
+        PanicPolicy {
+            strict: false,
+            expose_message: false,
+            hook: None,
+        }
+    }
+
+    /// Don't catch panics.
+    pub fn strict() -> PanicPolicy {
+        PanicPolicy {
+            strict: true,
+            ..PanicPolicy::new()
+        }
+    }
+
+    /// Send the panic message to client as the status details.
+    ///
+    /// The message may contain internal details, so it's disabled by default.
+    pub fn expose_message(mut self, expose: bool) -> PanicPolicy {
+        self.expose_message = expose;
+        self
+    }
+
+    /// Invoke `f` on every caught panic.
+    pub fn hook<F>(mut self, f: F) -> PanicPolicy
+    where
+        F: Fn(&HandlerPanic) + Send + Sync + 'static,
+    {
+        self.hook = Some(Arc::new(f));
+        self
+    }
+
+    pub fn is_strict(&self) -> bool {
+        self.strict
+    }
+
+    /// Get the status details for a panic, and report it to the hook.
+    pub(crate) fn on_panic(
+        &self,
+        method: &str,
+        request_id: Option<&str>,
+        payload: &(Any + Send),
+    ) -> String {
+        let message = payload_message(payload);
+        let p = HandlerPanic {
+            method,
+            request_id,
+            message,
+        };
+        match p.message {
+            Some(msg) => error!("handler of {} panicked: {}", method, msg),
+            None => error!("handler of {} panicked", method),
+        }
+        let details = match (self.expose_message, p.message) {
+            (true, Some(msg)) => msg.to_owned(),
+            _ => REDACTED_MESSAGE.to_owned(),
+        };
+        if let Some(ref hook) = self.hook {
+            hook(&p);
+        }
+        details
+    }
+}
+
+impl Default for PanicPolicy {
+    fn default() -> PanicPolicy {
+        PanicPolicy::new()
+    }
+}
+
+fn payload_message(payload: &(Any + Send)) -> Option<&str> {
+    if let Some(s) = payload.downcast_ref::<&'static str>() {
+        return Some(s);
+    }
+    payload.downcast_ref::<String>().map(|s| s.as_str())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::panic;
+    use std::sync::Mutex;
+
+    use super::*;
+
+    #[test]
+    fn test_on_panic() {
+        let seen = Arc::new(Mutex::new(vec![]));
+        let seen_ = seen.clone();
+        let policy = PanicPolicy::new().hook(move |p| {
+            let msg = p.message().map(|s| s.to_owned());
+            seen_.lock().unwrap().push((p.method().to_owned(), msg));
+        });
+        let payload = panic::catch_unwind(|| panic!("boom {}", 1)).unwrap_err();
+        assert_eq!(policy.on_panic("/a/B", None, &*payload), REDACTED_MESSAGE);
+        let policy = policy.expose_message(true);
+        assert_eq!(policy.on_panic("/a/B", Some("id"), &*payload), "boom 1");
+        let payload: Box<Any + Send> = Box::new(3);
+        assert_eq!(policy.on_panic("/a/C", None, &*payload), REDACTED_MESSAGE);
+        assert_eq!(
+            *seen.lock().unwrap(),
+            vec![
+                ("/a/B".to_owned(), Some("boom 1".to_owned())),
+                ("/a/B".to_owned(), Some("boom 1".to_owned())),
+                ("/a/C".to_owned(), None),
+            ]
+        );
+        assert!(PanicPolicy::strict().is_strict());
+    }
+}
@@ -0,0 +1,93 @@
+// Copyright 2018 PingCAP, Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::fmt::{self, Display, Formatter};
+use std::net::SocketAddr;
+
+/// The address of the other side of a call, as reported by gRPC core.
+///
+/// Its `Display` gives the URI gRPC core uses, e.g. `ipv4:127.0.0.1:50051`.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Peer {
+    /// A TCP connection, `ipv4:` or `ipv6:`.
+    Tcp(SocketAddr),
+    /// A unix domain socket, `unix:` followed by the path.
+    Unix(String),
+    /// Any other form of address, e.g. the target of a client call that is
+    /// not connected yet.
+    Other(String),
+}
+
+impl Peer {
+    /// Parse the URI reported by gRPC core.
+    pub fn parse(uri: &str) -> Peer {
+        for scheme in &["ipv4:", "ipv6:"] {
+            if uri.starts_with(scheme) {
+                if let Ok(addr) = uri[scheme.len()..].parse() {
+                    return Peer::Tcp(addr);
+                }
+            }
+        }
+        if uri.starts_with("unix:") {
+            return Peer::Unix(uri["unix:".len()..].to_owned());
+        }
+        Peer::Other(uri.to_owned())
+    }
+
+    /// Get the socket address if it's a TCP connection.
+    pub fn socket_addr(&self) -> Option<SocketAddr> {
+        match *self {
+            Peer::Tcp(addr) => Some(addr),
+            _ => None,
+        }
+    }
+}
+
+impl Display for Peer {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match *self {
+            Peer::Tcp(addr @ SocketAddr::V4(_)) => write!(f, "ipv4:{}", addr),
+            Peer::Tcp(addr @ SocketAddr::V6(_)) => write!(f, "ipv6:{}", addr),
+            Peer::Unix(ref path) => write!(f, "unix:{}", path),
+            Peer::Other(ref uri) => write!(f, "{}", uri),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        for uri in &[
+            "ipv4:127.0.0.1:50051",
+            "ipv6:[::1]:443",
+            "unix:/tmp/grpc.sock",
+            "fd:3",
+            "localhost:50051",
+        ] {
+            assert_eq!(Peer::parse(uri).to_string(), *uri);
+        }
+        assert_eq!(
+            Peer::parse("ipv4:10.0.0.1:80").socket_addr(),
+            Some("10.0.0.1:80".parse().unwrap())
+        );
+        assert_eq!(
+            Peer::parse("unix:/tmp/grpc.sock"),
+            Peer::Unix("/tmp/grpc.sock".to_owned())
+        );
+        assert_eq!(Peer::parse("ipv4:bad"), Peer::Other("ipv4:bad".to_owned()));
+        assert!(Peer::parse("localhost:50051").socket_addr().is_none());
+    }
+}
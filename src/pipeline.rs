@@ -0,0 +1,193 @@
+// Copyright 2018 PingCAP, Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Ordered unary calls.
+//!
+//! A [`Pipeline`] starts the unary calls passed to [`Pipeline::call`] in
+//! the same order on its client, with at most a given count of them in
+//! flight. Calls beyond the limit are queued and started as the earlier
+//! ones finish, so a sequence of writes can be sent without waiting for each
+//! response or building a client streaming method for it:
+//!
+//! ```ignore
+//! let pipeline = Pipeline::new(client, &METHOD_PUT, 16);
+//! let results: Vec<_> = puts.into_iter()
+//!     .map(|put| pipeline.call(put, CallOption::default()))
+//!     .collect();
+//! ```
+//!
+//! Only the order in which the calls are started is kept. Calls in flight
+//! at the same time are separate streams, which the server may receive and
+//! run in any order, e.g. when it polls several completion queues. Use a
+//! `max_in_flight` of 1 if the server must see them in order, at the cost of
+//! a round trip per call.
+//!
+//! Each call has its own future, a failed call doesn't affect the others.
+//! The calls share the connection of the channel as long as the channel
+//! uses only one, e.g. with the default `pick_first` policy.
+//!
+//! [`Pipeline`]: struct.Pipeline.html
+//! [`Pipeline::call`]: struct.Pipeline.html#method.call
+
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+
+use futures::sync::oneshot;
+use futures::{Async, Future, Poll};
+
+use call::client::{CallOption, ClientUnaryReceiver};
+use call::{Method, RpcStatus, RpcStatusCode};
+use client::Client;
+use error::{Error, Result};
+
+struct Pending<Req, Resp> {
+    req: Req,
+    opt: CallOption,
+    tx: oneshot::Sender<Result<Resp>>,
+}
+
+struct State<Req, Resp> {
+    in_flight: usize,
+    queue: VecDeque<Pending<Req, Resp>>,
+}
+
+struct Inner<Req: 'static, Resp: 'static> {
+    client: Client,
+    method: &'static Method<Req, Resp>,
+    max_in_flight: usize,
+    state: Mutex<State<Req, Resp>>,
+}
+
+type Driver = Box<Future<Item = (), Error = ()> + Send>;
+
+/// Starts the unary calls of a method in order, see
+/// [`pipeline`](index.html) for details.
+pub struct Pipeline<Req: 'static, Resp: 'static> {
+    inner: Arc<Inner<Req, Resp>>,
+}
+
+impl<Req: Send + 'static, Resp: Send + 'static> Pipeline<Req, Resp> {
+    /// Create a pipeline of `method` calls with at most `max_in_flight`,
+    /// at least one, of them in flight.
+    ///
+    /// The server is only guaranteed to receive the calls in order when
+    /// `max_in_flight` is 1.
+    pub fn new(
+        client: Client,
+        method: &'static Method<Req, Resp>,
+        max_in_flight: usize,
+    ) -> Pipeline<Req, Resp> {
+        Pipeline {
+            inner: Arc::new(Inner {
+                client,
+                method,
+                max_in_flight: max_in_flight.max(1),
+                state: Mutex::new(State {
+                    in_flight: 0,
+                    queue: VecDeque::new(),
+                }),
+            }),
+        }
+    }
+
+    /// Queue a call of `req`, it's started after all the calls queued
+    /// before it are started.
+    pub fn call(&self, req: Req, opt: CallOption) -> PipelinedCall<Resp> {
+        let (tx, rx) = oneshot::channel();
+        let drivers = {
+            let mut state = self.inner.state.lock().unwrap();
+            state.queue.push_back(Pending { req, opt, tx });
+            pump(&self.inner, &mut state)
+        };
+        spawn(&self.inner, drivers);
+        PipelinedCall { rx }
+    }
+
+    /// Get the count of calls that are started but not finished.
+    pub fn in_flight(&self) -> usize {
+        self.inner.state.lock().unwrap().in_flight
+    }
+
+    /// Get the count of calls that are not started yet.
+    pub fn queued(&self) -> usize {
+        self.inner.state.lock().unwrap().queue.len()
+    }
+}
+
+// Start the queued calls as long as there are free slots. The calls are
+// started with the lock held to keep them in order, but their drivers are
+// spawned after it's released, as they may be polled immediately.
+fn pump<Req, Resp>(inner: &Arc<Inner<Req, Resp>>, state: &mut State<Req, Resp>) -> Vec<Driver>
+where
+    Req: Send + 'static,
+    Resp: Send + 'static,
+{
+    let mut drivers = vec![];
+    while state.in_flight < inner.max_in_flight {
+        let p = match state.queue.pop_front() {
+            Some(p) => p,
+            None => break,
+        };
+        let receiver: ClientUnaryReceiver<Resp> =
+            match inner.client.unary_call_async(inner.method, &p.req, p.opt) {
+                Ok(r) => r,
+                Err(e) => {
+                    let _ = p.tx.send(Err(e));
+                    continue;
+                }
+            };
+        state.in_flight += 1;
+        let (inner, tx) = (inner.clone(), p.tx);
+        drivers.push(Box::new(receiver.then(move |res| {
+            let _ = tx.send(res);
+            let drivers = {
+                let mut state = inner.state.lock().unwrap();
+                state.in_flight -= 1;
+                pump(&inner, &mut state)
+            };
+            spawn(&inner, drivers);
+            Ok(())
+        })) as Driver);
+    }
+    drivers
+}
+
+fn spawn<Req, Resp>(inner: &Inner<Req, Resp>, drivers: Vec<Driver>) {
+    for d in drivers {
+        inner.client.spawn(d);
+    }
+}
+
+/// The response of a call queued by [`Pipeline::call`].
+///
+/// [`Pipeline::call`]: struct.Pipeline.html#method.call
+pub struct PipelinedCall<Resp> {
+    rx: oneshot::Receiver<Result<Resp>>,
+}
+
+impl<Resp> Future for PipelinedCall<Resp> {
+    type Item = Resp;
+    type Error = Error;
+
+    fn poll(&mut self) -> Poll<Resp, Error> {
+        match self.rx.poll() {
+            Ok(Async::Ready(res)) => res.map(Async::Ready),
+            Ok(Async::NotReady) => Ok(Async::NotReady),
+            // The environment is shut down before the call is finished.
+            Err(_) => Err(Error::RpcFailure(RpcStatus::new(
+                RpcStatusCode::Cancelled,
+                Some("pipeline is shut down".to_owned()),
+            ))),
+        }
+    }
+}
@@ -0,0 +1,244 @@
+// Copyright 2018 PingCAP, Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+// gRPC core doesn't report why a connection attempt fails, so a new attempt
+// is made here with std to guess the cause. It's not the error core hit.
+
+use std::fmt::{self, Display, Formatter};
+use std::io;
+use std::net::{SocketAddr, TcpStream, ToSocketAddrs};
+#[cfg(unix)]
+use std::os::unix::net::UnixStream;
+use std::time::Duration;
+
+/// The cause of a failed connection attempt.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ConnectErrorKind {
+    /// The name of the target can't be resolved.
+    Dns,
+    /// The connection is refused, nothing listens on the address.
+    Refused,
+    /// The connection is not established in time.
+    Timeout,
+    /// The TCP connection is established, so the cause is unknown. It may be
+    /// a failing TLS or HTTP/2 handshake, or the failure may be gone already.
+    Unknown,
+    /// Other errors, e.g. the network is unreachable.
+    Other,
+}
+
+/// An error of probing the target of a channel, see
+/// [`Channel::probe_connect_error`].
+///
+/// [`Channel::probe_connect_error`]: struct.Channel.html#method.probe_connect_error
+#[derive(Clone, Debug)]
+pub struct ConnectError {
+    kind: ConnectErrorKind,
+    address: Option<String>,
+    message: String,
+}
+
+impl ConnectError {
+    fn new(kind: ConnectErrorKind, address: Option<String>, message: String) -> ConnectError {
+        ConnectError {
+            kind,
+            address,
+            message,
+        }
+    }
+
+    pub fn kind(&self) -> ConnectErrorKind {
+        self.kind
+    }
+
+    /// The resolved address that fails, unless the name can't be resolved.
+    pub fn address(&self) -> Option<&str> {
+        self.address.as_ref().map(|s| s.as_str())
+    }
+
+    pub fn message(&self) -> &str {
+        &self.message
+    }
+}
+
+impl Display for ConnectError {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match self.address {
+            Some(ref addr) => write!(f, "{:?} error on {}: {}", self.kind, addr, self.message),
+            None => write!(f, "{:?} error: {}", self.kind, self.message),
+        }
+    }
+}
+
+#[derive(Debug, PartialEq)]
+enum Addresses {
+    Resolve(String, u16),
+    Resolved(Vec<String>),
+    #[cfg_attr(not(unix), allow(dead_code))]
+    Unix(String),
+}
+
+// Only the name resolvers that come with gRPC core are understood. The
+// authority of `dns:` targets is ignored.
+fn parse_target(target: &str) -> Option<Addresses> {
+    // The dns resolver of gRPC core defaults to 443, even for insecure channels.
+    let default_port = 443;
+    if target.starts_with("unix:") {
+        return Some(Addresses::Unix(target["unix:".len()..].to_owned()));
+    }
+    for scheme in &["ipv4:", "ipv6:"] {
+        if target.starts_with(scheme) {
+            let addrs = target[scheme.len()..]
+                .split(',')
+                .map(|s| s.to_owned())
+                .collect();
+            return Some(Addresses::Resolved(addrs));
+        }
+    }
+    let name = if target.starts_with("dns:") {
+        // `dns:[//authority/]host[:port]`
+        let rest = &target["dns:".len()..];
+        if rest.starts_with("//") {
+            &rest[2..][rest[2..].find('/')? + 1..]
+        } else {
+            rest
+        }
+    } else if target.contains("://") {
+        return None;
+    } else {
+        target
+    };
+    let (host, port) = match name.rfind(':') {
+        Some(pos) if !name[pos..].contains(']') => (&name[..pos], name[pos + 1..].parse().ok()?),
+        _ => (name, default_port),
+    };
+    let host = host.trim_matches(|c| c == '[' || c == ']');
+    Some(Addresses::Resolve(host.to_owned(), port))
+}
+
+fn classify(e: &io::Error) -> ConnectErrorKind {
+    match e.kind() {
+        io::ErrorKind::ConnectionRefused => ConnectErrorKind::Refused,
+        io::ErrorKind::TimedOut | io::ErrorKind::WouldBlock => ConnectErrorKind::Timeout,
+        _ => ConnectErrorKind::Other,
+    }
+}
+
+fn unknown_error(address: String, secure: bool) -> ConnectError {
+    let message = if secure {
+        "connected, the TLS or HTTP/2 handshake may fail"
+    } else {
+        "connected, the HTTP/2 handshake may fail"
+    };
+    ConnectError::new(ConnectErrorKind::Unknown, Some(address), message.to_owned())
+}
+
+/// Connect to `target` with the system resolver and a plain socket, to guess
+/// why gRPC core fails to.
+///
+/// Returns `None` if the target is of an unknown scheme.
+pub fn probe(target: &str, secure: bool, timeout: Duration) -> Option<ConnectError> {
+    let addrs = match parse_target(target)? {
+        #[cfg(unix)]
+        Addresses::Unix(path) => {
+            return Some(match UnixStream::connect(&path) {
+                Ok(_) => unknown_error(path, secure),
+                Err(e) => ConnectError::new(classify(&e), Some(path), e.to_string()),
+            });
+        }
+        #[cfg(not(unix))]
+        Addresses::Unix(_) => return None,
+        Addresses::Resolved(addrs) => {
+            let mut resolved = vec![];
+            for a in addrs {
+                match a.parse::<SocketAddr>() {
+                    Ok(addr) => resolved.push(addr),
+                    Err(e) => {
+                        return Some(ConnectError::new(
+                            ConnectErrorKind::Dns,
+                            None,
+                            format!("invalid address {}: {}", a, e),
+                        ))
+                    }
+                }
+            }
+            resolved
+        }
+        Addresses::Resolve(host, port) => match (host.as_str(), port).to_socket_addrs() {
+            Ok(addrs) => addrs.collect(),
+            Err(e) => {
+                return Some(ConnectError::new(
+                    ConnectErrorKind::Dns,
+                    None,
+                    format!("failed to resolve {}: {}", host, e),
+                ))
+            }
+        },
+    };
+    // gRPC core tries the addresses in order too, report the last error if
+    // none is reachable.
+    let mut last = None;
+    for addr in addrs {
+        match TcpStream::connect_timeout(&addr, timeout) {
+            Ok(_) => return Some(unknown_error(addr.to_string(), secure)),
+            Err(e) => {
+                last = Some(ConnectError::new(
+                    classify(&e),
+                    Some(addr.to_string()),
+                    e.to_string(),
+                ))
+            }
+        }
+    }
+    Some(last.unwrap_or_else(|| {
+        ConnectError::new(
+            ConnectErrorKind::Dns,
+            None,
+            format!("{} resolves to no address", target),
+        )
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_target() {
+        let resolve = |h: &str, p| Some(Addresses::Resolve(h.to_owned(), p));
+        assert_eq!(parse_target("localhost:50051"), resolve("localhost", 50051));
+        assert_eq!(parse_target("example.com"), resolve("example.com", 443));
+        assert_eq!(parse_target("[::1]:80"), resolve("::1", 80));
+        assert_eq!(parse_target("dns:///a.com:81"), resolve("a.com", 81));
+        assert_eq!(
+            parse_target("dns://8.8.8.8:53/a.com:81"),
+            resolve("a.com", 81)
+        );
+        assert_eq!(
+            parse_target("ipv4:127.0.0.1:1,127.0.0.2:2"),
+            Some(Addresses::Resolved(vec![
+                "127.0.0.1:1".to_owned(),
+                "127.0.0.2:2".to_owned()
+            ]))
+        );
+        assert_eq!(
+            parse_target("unix:/tmp/sock"),
+            Some(Addresses::Unix("/tmp/sock".to_owned()))
+        );
+        assert_eq!(parse_target("xds://a.com"), None);
+        assert_eq!(parse_target("a.com:port"), None);
+
+        let e = probe("ipv4:a.b", false, Duration::from_secs(1)).unwrap();
+        assert_eq!(e.kind(), ConnectErrorKind::Dns);
+    }
+}
@@ -0,0 +1,264 @@
+// Copyright 2018 PingCAP, Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Forward calls of any method to another server as they are.
+//!
+//! [`forward`] pairs a call accepted by [`ServerBuilder::fallback_handler`] with
+//! a call started by [`Client::raw_duplex_streaming`]. Request headers and the
+//! deadline are copied to the upstream call, messages are relayed in both
+//! directions, and the response headers, status and trailers of the upstream
+//! call are sent back to the downstream client. Half-close is propagated
+//! separately in each direction, so all the four method types are supported.
+//!
+//! ```ignore
+//! let client = Client::new(ChannelBuilder::new(env.clone()).connect("backend:50051"));
+//! let server = ServerBuilder::new(env)
+//!     .fallback_handler(move |ctx, reqs, sink| proxy::forward(&client, &ctx, reqs, sink))
+//!     .bind("0.0.0.0", 50051)
+//!     .build()?;
+//! ```
+//!
+//! [`forward`]: fn.forward.html
+//! [`ServerBuilder::fallback_handler`]: ../struct.ServerBuilder.html#method.fallback_handler
+//! [`Client::raw_duplex_streaming`]: ../struct.Client.html#method.raw_duplex_streaming
+
+use std::str;
+
+use futures::future::{self, Loop};
+use futures::sync::oneshot;
+use futures::{Async, AsyncSink, Future, Poll, Sink, Stream};
+
+use call::client::{CallOption, ClientDuplexReceiver, ClientDuplexSender};
+use call::server::{DuplexSink, RequestStream, RpcContext};
+use call::{RpcStatus, WriteFlags};
+use client::Client;
+use error::Error;
+use metadata::{Metadata, MetadataBuilder};
+
+/// Headers that are set by gRPC core for every call, and are not forwarded.
+const SKIPPED_HEADERS: &[&str] = &["user-agent"];
+
+/// Copy the request headers that should be sent to the upstream server.
+pub fn forward_headers(headers: &Metadata) -> Metadata {
+    let mut builder = MetadataBuilder::with_capacity(headers.len());
+    for (key, value) in headers.iter() {
+        if SKIPPED_HEADERS.contains(&key) {
+            continue;
+        }
+        let res = if key.ends_with("-bin") {
+            builder.add_bytes(key, value).map(|_| ())
+        } else {
+            match str::from_utf8(value) {
+                Ok(v) => builder.add_str(key, v).map(|_| ()),
+                Err(_) => {
+                    warn!("drop header {} whose value is not utf8", key);
+                    continue;
+                }
+            }
+        };
+        if let Err(e) = res {
+            warn!("drop header {}: {:?}", key, e);
+        }
+    }
+    builder.build()
+}
+
+/// Forward the call in `ctx` to the same method of the server that `client`
+/// connects to.
+///
+/// The relay is driven by the completion queues of `ctx` and `client`. If the
+/// downstream client cancels the call, the upstream call is cancelled too.
+pub fn forward(
+    client: &Client,
+    ctx: &RpcContext,
+    reqs: RequestStream<Vec<u8>>,
+    sink: DuplexSink<Vec<u8>>,
+) {
+    let method = String::from_utf8_lossy(ctx.method()).into_owned();
+    let opt = CallOption::default()
+        .headers(forward_headers(ctx.request_headers()))
+        .deadline(*ctx.deadline());
+    let (tx, rx) = match client.raw_duplex_streaming(&method, opt) {
+        Ok(pair) => pair,
+        Err(e) => {
+            let f = sink
+                .fail(RpcStatus::from_error(&e))
+                .map_err(|e| warn!("failed to report proxy error: {:?}", e));
+            ctx.spawn(f);
+            return;
+        }
+    };
+
+    let (headers_tx, headers_rx) = oneshot::channel();
+    client.spawn(ForwardRequests {
+        reqs,
+        tx,
+        buffered: None,
+        closing: false,
+        reqs_done: false,
+        headers: Some(headers_tx),
+    });
+
+    let recv_f = headers_rx
+        .then(move |res| {
+            let mut sink = sink;
+            if let Ok(headers) = res {
+                sink.send_headers(headers)?;
+            }
+            Ok(sink)
+        })
+        .and_then(move |sink| future::loop_fn((rx, sink), forward_response))
+        .map_err(|e| warn!("failed to forward responses: {:?}", e));
+    ctx.spawn(recv_f);
+}
+
+type ResponseLoop = Loop<(), (ClientDuplexReceiver<Vec<u8>>, DuplexSink<Vec<u8>>)>;
+
+fn forward_response(
+    (rx, mut sink): (ClientDuplexReceiver<Vec<u8>>, DuplexSink<Vec<u8>>),
+) -> Box<Future<Item = ResponseLoop, Error = Error> + Send> {
+    Box::new(rx.into_future().then(
+        move |res| -> Box<Future<Item = ResponseLoop, Error = Error> + Send> {
+            match res {
+                Ok((Some(msg), mut rx)) => Box::new(sink.send((msg, WriteFlags::default())).then(
+                    move |res| match res {
+                        Ok(sink) => Ok(Loop::Continue((rx, sink))),
+                        Err(e) => {
+                            rx.cancel();
+                            Err(e)
+                        }
+                    },
+                )),
+                Ok((None, mut rx)) => {
+                    if let Some(trailers) = rx.take_trailers() {
+                        sink.set_trailers(trailers);
+                    }
+                    Box::new(future::poll_fn(move || sink.close()).map(Loop::Break))
+                }
+                Err((e, mut rx)) => {
+                    if let Some(trailers) = rx.take_trailers() {
+                        sink.set_trailers(trailers);
+                    }
+                    let status = match e {
+                        Error::RpcFailure(status) => status,
+                        e => RpcStatus::from_error(&e),
+                    };
+                    Box::new(sink.fail(status).map(Loop::Break))
+                }
+            }
+        },
+    ))
+}
+
+/// Relays the requests to the upstream call, and hands over the response
+/// headers once they arrive.
+struct ForwardRequests {
+    reqs: RequestStream<Vec<u8>>,
+    tx: ClientDuplexSender<Vec<u8>>,
+    buffered: Option<Vec<u8>>,
+    closing: bool,
+    reqs_done: bool,
+    headers: Option<oneshot::Sender<Metadata>>,
+}
+
+impl ForwardRequests {
+    fn poll_headers(&mut self) {
+        let res = match self.tx.poll_headers() {
+            Ok(Async::NotReady) => return,
+            Ok(Async::Ready(headers)) => Some(headers),
+            // The call is finished without headers, the status is reported
+            // by the response stream.
+            Err(_) => None,
+        };
+        let headers_tx = self.headers.take().unwrap();
+        if let Some(headers) = res {
+            let _ = headers_tx.send(headers);
+        }
+    }
+
+    fn poll_requests(&mut self) -> Poll<(), Error> {
+        loop {
+            if self.closing {
+                return self.tx.close();
+            }
+            if let Some(msg) = self.buffered.take() {
+                if let AsyncSink::NotReady((msg, _)) =
+                    self.tx.start_send((msg, WriteFlags::default()))?
+                {
+                    self.buffered = Some(msg);
+                    return Ok(Async::NotReady);
+                }
+            }
+            match self.reqs.poll() {
+                Ok(Async::Ready(Some(msg))) => self.buffered = Some(msg),
+                Ok(Async::Ready(None)) => self.closing = true,
+                Ok(Async::NotReady) => {
+                    try_ready!(self.tx.poll_complete());
+                    return Ok(Async::NotReady);
+                }
+                Err(e) => {
+                    self.tx.cancel();
+                    return Err(e);
+                }
+            }
+        }
+    }
+}
+
+impl Future for ForwardRequests {
+    type Item = ();
+    type Error = ();
+
+    fn poll(&mut self) -> Poll<(), ()> {
+        if self.headers.is_some() {
+            self.poll_headers();
+        }
+        if !self.reqs_done {
+            match self.poll_requests() {
+                Ok(Async::NotReady) => {}
+                Ok(Async::Ready(())) => self.reqs_done = true,
+                Err(e) => {
+                    warn!("failed to forward requests: {:?}", e);
+                    return Ok(Async::Ready(()));
+                }
+            }
+        }
+        if self.reqs_done && self.headers.is_none() {
+            return Ok(Async::Ready(()));
+        }
+        Ok(Async::NotReady)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_forward_headers() {
+        let mut builder = MetadataBuilder::new();
+        builder
+            .add_str("x-tag", "a")
+            .unwrap()
+            .add_str("user-agent", "grpc-rust")
+            .unwrap()
+            .add_bytes("x-data-bin", b"\x00\xff")
+            .unwrap();
+        let headers = forward_headers(&builder.build());
+        let headers: Vec<_> = headers.iter().collect();
+        assert_eq!(
+            headers,
+            vec![("x-tag", b"a" as &[u8]), ("x-data-bin", b"\x00\xff")]
+        );
+    }
+}
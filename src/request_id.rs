@@ -0,0 +1,243 @@
+// Copyright 2018 PingCAP, Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Request ID generation and propagation.
+//!
+//! When enabled by [`ServerBuilder::request_id`], the ID sent by client is
+//! picked from the request headers, or a new one is generated if there is
+//! none. It can be retrieved by [`RpcContext::request_id`], and by [`current`]
+//! while the handler or the futures spawned by [`RpcContext::spawn`] run.
+//!
+//! When enabled by [`ChannelBuilder::request_id`], every call made on the
+//! channel carries an ID: the one set in the headers by caller, the current
+//! one if the call is made when handling a request, or a new one otherwise.
+//!
+//! [`ServerBuilder::request_id`]: ../struct.ServerBuilder.html#method.request_id
+//! [`ChannelBuilder::request_id`]: ../struct.ChannelBuilder.html#method.request_id
+//! [`RpcContext::request_id`]: ../struct.RpcContext.html#method.request_id
+//! [`RpcContext::spawn`]: ../struct.RpcContext.html#method.spawn
+//! [`current`]: fn.current.html
+
+use std::cell::RefCell;
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hasher};
+use std::mem;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+
+use futures::{Future, Poll};
+
+use metadata::{MergePolicy, Metadata, MetadataBuilder};
+
+/// Default metadata key used to carry the request ID.
+pub const REQUEST_ID_KEY: &str = "x-request-id";
+
+thread_local! {
+    static CURRENT: RefCell<Option<String>> = RefCell::new(None);
+}
+
+/// Get the ID of the request being handled by current thread.
+pub fn current() -> Option<String> {
+    CURRENT.with(|c| c.borrow().clone())
+}
+
+// Restores the previous ID on drop, so a panicking handler doesn't leak its
+// ID to the next one.
+struct Reset(Option<String>);
+
+impl Drop for Reset {
+    fn drop(&mut self) {
+        let prev = self.0.take();
+        let _ = CURRENT.try_with(|c| *c.borrow_mut() = prev);
+    }
+}
+
+/// Run `f` with `id` as the current request ID.
+pub fn enter<R, F: FnOnce() -> R>(id: &str, f: F) -> R {
+    let prev = CURRENT.with(|c| mem::replace(&mut *c.borrow_mut(), Some(id.to_owned())));
+    let _reset = Reset(prev);
+    f()
+}
+
+/// Make `f` run with `id` as the current request ID whenever it's polled.
+pub fn scope<F: Future>(id: String, f: F) -> Scoped<F> {
+    Scoped { id, f }
+}
+
+/// A future created by [`scope`].
+///
+/// [`scope`]: fn.scope.html
+pub struct Scoped<F> {
+    id: String,
+    f: F,
+}
+
+impl<F: Future> Future for Scoped<F> {
+    type Item = F::Item;
+    type Error = F::Error;
+
+    fn poll(&mut self) -> Poll<F::Item, F::Error> {
+        let f = &mut self.f;
+        enter(&self.id, || f.poll())
+    }
+}
+
+/// Generate a random (version 4) UUID.
+pub fn new_uuid() -> String {
+    // `RandomState` is seeded randomly, and the counter makes sure two IDs
+    // never share the same seed even if the seeds are cached by std.
+    static COUNTER: AtomicUsize = AtomicUsize::new(0);
+    let mut bytes = [0u8; 16];
+    for half in bytes.chunks_mut(8) {
+        let mut hasher = RandomState::new().build_hasher();
+        hasher.write_usize(COUNTER.fetch_add(1, Ordering::Relaxed));
+        let v = hasher.finish();
+        for (i, b) in half.iter_mut().enumerate() {
+            *b = (v >> (i * 8)) as u8;
+        }
+    }
+    bytes[6] = (bytes[6] & 0x0f) | 0x40;
+    bytes[8] = (bytes[8] & 0x3f) | 0x80;
+    let mut s = String::with_capacity(36);
+    for (i, b) in bytes.iter().enumerate() {
+        if i == 4 || i == 6 || i == 8 || i == 10 {
+            s.push('-');
+        }
+        s.push_str(&format!("{:02x}", b));
+    }
+    s
+}
+
+/// Configuration of request ID propagation.
+#[derive(Clone)]
+pub struct RequestIdConfig {
+    key: String,
+    generator: Arc<Fn() -> String + Send + Sync>,
+}
+
+impl RequestIdConfig {
+    /// Carry the ID in [`REQUEST_ID_KEY`] and generate UUIDs.
+    ///
+    /// [`REQUEST_ID_KEY`]: constant.REQUEST_ID_KEY.html
+    pub fn new() -> RequestIdConfig {
+        RequestIdConfig {
+            key: REQUEST_ID_KEY.to_owned(),
+            generator: Arc::new(new_uuid),
+        }
+    }
+
+    /// Set the metadata key used to carry the ID, it should not be a binary key.
+    pub fn key(mut self, key: &str) -> RequestIdConfig {
+        self.key = key.to_ascii_lowercase();
+        self
+    }
+
+    /// Set the function used to generate new IDs.
+    pub fn generator<F>(mut self, f: F) -> RequestIdConfig
+    where
+        F: Fn() -> String + Send + Sync + 'static,
+    {
+        self.generator = Arc::new(f);
+        self
+    }
+
+    pub fn get_key(&self) -> &str {
+        &self.key
+    }
+
+    fn find(&self, headers: &Metadata) -> Option<String> {
+        headers
+            .iter()
+            .find(|&(k, _)| k == self.key)
+            .and_then(|(_, v)| String::from_utf8(v.to_vec()).ok())
+    }
+
+    /// Get the ID sent by client, or generate a new one.
+    pub(crate) fn extract(&self, headers: &Metadata) -> String {
+        self.find(headers).unwrap_or_else(|| (self.generator)())
+    }
+
+    /// Add an ID to `headers` if there is none.
+    pub(crate) fn attach(&self, headers: Option<Metadata>) -> Metadata {
+        if let Some(h) = headers {
+            if self.find(&h).is_some() {
+                return h;
+            }
+            return h.merge(&self.build(), MergePolicy::KeepExisting);
+        }
+        self.build()
+    }
+
+    fn build(&self) -> Metadata {
+        let id = current().unwrap_or_else(|| (self.generator)());
+        let mut builder = MetadataBuilder::new();
+        builder
+            .add_str(&self.key, &id)
+            .expect("request id should be a valid ascii value");
+        builder.build()
+    }
+}
+
+impl Default for RequestIdConfig {
+    fn default() -> RequestIdConfig {
+        RequestIdConfig::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::future;
+
+    use super::*;
+
+    #[test]
+    fn test_new_uuid() {
+        let a = new_uuid();
+        let b = new_uuid();
+        assert_ne!(a, b);
+        assert_eq!(a.len(), 36);
+        assert_eq!(&a[14..15], "4");
+        assert!("89ab".contains(&a[19..20]), "{}", a);
+    }
+
+    #[test]
+    fn test_current() {
+        assert_eq!(current(), None);
+        enter("a", || {
+            assert_eq!(current(), Some("a".to_owned()));
+            enter("b", || assert_eq!(current(), Some("b".to_owned())));
+            assert_eq!(current(), Some("a".to_owned()));
+        });
+        assert_eq!(current(), None);
+
+        let f = scope("c".to_owned(), future::lazy(|| Ok::<_, ()>(current())));
+        assert_eq!(f.wait(), Ok(Some("c".to_owned())));
+        assert_eq!(current(), None);
+    }
+
+    #[test]
+    fn test_attach() {
+        let config = RequestIdConfig::new().generator(|| "generated".to_owned());
+        let headers = config.attach(None);
+        assert_eq!(config.extract(&headers), "generated");
+
+        let mut builder = MetadataBuilder::new();
+        builder.add_str("x-request-id", "given").unwrap();
+        let headers = config.attach(Some(builder.build()));
+        assert_eq!(headers.len(), 1);
+        assert_eq!(config.extract(&headers), "given");
+
+        let headers = enter("current", || config.attach(None));
+        assert_eq!(config.extract(&headers), "current");
+    }
+}
@@ -0,0 +1,99 @@
+// Copyright 2018 PingCAP, Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+/// A glob-style pattern of method paths, e.g. `/helloworld.Greeter/*`.
+///
+/// `*` matches any sequence of characters, including `/`, and `?` matches
+/// exactly one character. Other characters match themselves.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct MethodPattern {
+    pattern: Vec<u8>,
+}
+
+impl MethodPattern {
+    pub fn new<S: Into<String>>(pattern: S) -> MethodPattern {
+        MethodPattern {
+            pattern: pattern.into().into_bytes(),
+        }
+    }
+
+    /// A pattern that matches all the methods of `service`, which is the full
+    /// name of the service like `helloworld.Greeter`.
+    pub fn service(service: &str) -> MethodPattern {
+        let mut pattern = Vec::with_capacity(service.len() + 3);
+        pattern.push(b'/');
+        pattern.extend_from_slice(service.as_bytes());
+        pattern.extend_from_slice(b"/*");
+        MethodPattern { pattern }
+    }
+
+    /// Check whether `path` matches the pattern.
+    ///
+    /// `path` is usually the one returned by [`RpcContext::method`].
+    ///
+    /// [`RpcContext::method`]: struct.RpcContext.html#method.method
+    pub fn matches<P: AsRef<[u8]>>(&self, path: P) -> bool {
+        let (p, s) = (&self.pattern, path.as_ref());
+        let (mut pi, mut si) = (0, 0);
+        // Position of the last `*` and the position in `s` it's tried at.
+        let mut backtrack = None;
+        while si < s.len() {
+            if pi < p.len() && (p[pi] == b'?' || p[pi] == s[si]) {
+                pi += 1;
+                si += 1;
+            } else if pi < p.len() && p[pi] == b'*' {
+                backtrack = Some((pi, si));
+                pi += 1;
+            } else if let Some((star, pos)) = backtrack {
+                // Let the `*` consume one more character.
+                backtrack = Some((star, pos + 1));
+                pi = star + 1;
+                si = pos + 1;
+            } else {
+                return false;
+            }
+        }
+        p[pi..].iter().all(|&c| c == b'*')
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_method_pattern() {
+        let path = "/helloworld.Greeter/SayHello";
+        for pattern in &[
+            path,
+            "*",
+            "/helloworld.Greeter/*",
+            "/*.Greeter/Say*",
+            "/helloworld.*/*Hello",
+            "/helloworld.Greeter/SayHell?",
+        ] {
+            assert!(MethodPattern::new(*pattern).matches(path), "{}", pattern);
+        }
+        assert!(MethodPattern::service("helloworld.Greeter").matches(path));
+        for pattern in &[
+            "",
+            "/helloworld.Greeter",
+            "/helloworld.Greeter/Say",
+            "/routeguide.*/*",
+            "/helloworld.Greeter/SayHello?",
+        ] {
+            assert!(!MethodPattern::new(*pattern).matches(path), "{}", pattern);
+        }
+        assert!(MethodPattern::new("").matches(""));
+    }
+}
@@ -0,0 +1,135 @@
+// Copyright 2018 PingCAP, Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Running gRPC futures on other runtimes.
+//!
+//! The futures and streams returned by clients are `Send + 'static`, and
+//! they are woken up by the completion queues of the environment, not by the
+//! runtime polling them. So they can be spawned onto any futures executor,
+//! e.g. a tokio runtime, as they are.
+//!
+//! Server handlers spawn futures into the gRPC poll thread by default. With
+//! an executor set by [`ServerBuilder::executor`], [`RpcContext::spawn`]
+//! hands them to it instead, so handlers can share the runtime of the rest
+//! of the application:
+//!
+//! ```ignore
+//! let runtime = tokio::runtime::Runtime::new().unwrap();
+//! let server = ServerBuilder::new(env)
+//!     .register_service(service)
+//!     .executor(runtime.executor())
+//!     .bind("127.0.0.1", 0)
+//!     .build()
+//!     .unwrap();
+//! ```
+//!
+//! Futures rejected by the executor, e.g. because it's shut down, are run in
+//! the gRPC poll thread.
+//!
+//! [`ServerBuilder::executor`]: ../struct.ServerBuilder.html#method.executor
+//! [`RpcContext::spawn`]: ../struct.RpcContext.html#method.spawn
+
+use std::sync::Arc;
+
+use futures::future::Executor;
+use futures::Future;
+
+/// A future spawned by server handlers.
+pub type BoxFuture = Box<Future<Item = (), Error = ()> + Send>;
+
+/// An executor set by [`ServerBuilder::executor`].
+///
+/// [`ServerBuilder::executor`]: ../struct.ServerBuilder.html#method.executor
+#[derive(Clone)]
+pub struct ExternalExecutor {
+    inner: Arc<Executor<BoxFuture> + Send + Sync>,
+}
+
+impl ExternalExecutor {
+    pub fn new<E>(executor: E) -> ExternalExecutor
+    where
+        E: Executor<BoxFuture> + Send + Sync + 'static,
+    {
+        ExternalExecutor {
+            inner: Arc::new(executor),
+        }
+    }
+
+    /// Spawn `f`, or give it back if it's rejected.
+    pub(crate) fn spawn(&self, f: BoxFuture) -> Result<(), BoxFuture> {
+        self.inner.execute(f).map_err(|e| e.into_future())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+    use std::thread;
+
+    use futures::future::{self, ExecuteError, ExecuteErrorKind};
+
+    use super::*;
+    use call::client::{
+        ClientCStreamReceiver, ClientCStreamSender, ClientDuplexReceiver, ClientDuplexSender,
+        ClientSStreamReceiver, ClientUnaryReceiver,
+    };
+
+    fn assert_send<T: Send + 'static>() {}
+
+    struct ThreadExecutor {
+        closed: AtomicBool,
+        spawned: Arc<AtomicUsize>,
+    }
+
+    impl Executor<BoxFuture> for ThreadExecutor {
+        fn execute(&self, f: BoxFuture) -> Result<(), ExecuteError<BoxFuture>> {
+            if self.closed.load(Ordering::SeqCst) {
+                return Err(ExecuteError::new(ExecuteErrorKind::Shutdown, f));
+            }
+            let spawned = self.spawned.clone();
+            thread::spawn(move || {
+                f.wait().unwrap();
+                spawned.fetch_add(1, Ordering::SeqCst);
+            })
+            .join()
+            .unwrap();
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_external_executor() {
+        assert_send::<ClientUnaryReceiver<Vec<u8>>>();
+        assert_send::<ClientCStreamSender<Vec<u8>>>();
+        assert_send::<ClientCStreamReceiver<Vec<u8>>>();
+        assert_send::<ClientSStreamReceiver<Vec<u8>>>();
+        assert_send::<ClientDuplexSender<Vec<u8>>>();
+        assert_send::<ClientDuplexReceiver<Vec<u8>>>();
+
+        let spawned = Arc::new(AtomicUsize::new(0));
+        let executor = ExternalExecutor::new(ThreadExecutor {
+            closed: AtomicBool::new(false),
+            spawned: spawned.clone(),
+        });
+        assert!(executor.spawn(Box::new(future::ok(()))).is_ok());
+        assert_eq!(spawned.load(Ordering::SeqCst), 1);
+
+        let executor = ExternalExecutor::new(ThreadExecutor {
+            closed: AtomicBool::new(true),
+            spawned: spawned.clone(),
+        });
+        let f = executor.spawn(Box::new(future::ok(()))).unwrap_err();
+        assert_eq!(f.wait(), Ok(()));
+        assert_eq!(spawned.load(Ordering::SeqCst), 1);
+    }
+}
@@ -11,27 +11,71 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
+use std::cell::UnsafeCell;
 use std::collections::HashMap;
 use std::fmt;
 use std::fmt::{Debug, Formatter};
-use std::ptr;
-use std::sync::atomic::{AtomicBool, Ordering};
-use std::sync::Arc;
+#[cfg(unix)]
+use std::net::TcpStream;
+#[cfg(unix)]
+use std::os::unix::io::{AsRawFd, FromRawFd, IntoRawFd, RawFd};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+#[cfg(unix)]
+use std::sync::Weak;
+use std::sync::{Arc, Mutex};
+#[cfg(unix)]
+use std::thread::{self, Builder as ThreadBuilder};
+#[cfg(unix)]
+use std::time::{Duration, Instant};
+use std::{cmp, i32, ptr, result, str};
+#[cfg(unix)]
+use std::{io, mem};
 
+use futures::sync::oneshot;
 use futures::{Async, Future, Poll};
 use grpc_sys::{self, GrpcCallStatus, GrpcServer};
+#[cfg(unix)]
+use libc::{self, c_int, c_void};
 
+use access_log::AccessLog;
 use async::{CallTag, CqFuture};
+use authz::Authorizer;
+use bandwidth::{BandwidthLimit, BandwidthLimiter};
+use blocking::BlockingPool;
 use call::server::*;
-use call::{Method, MethodType};
-use channel::ChannelArgs;
+use call::{Method, MethodType, RpcStatus, RpcStatusCode};
+use channel::{ChannelArgs, ChannelBuilder, CompressionAlgorithms, CompressionLevel};
+use checksum::Checksum;
+use chunk::{ChunkedRequest, ChunkedSink};
+use codec::raw_codec;
 use cq::CompletionQueue;
 use env::Environment;
 use error::{Error, Result};
+use flow::{FlowRegistry, StreamFlow};
+#[cfg(unix)]
+use h2c;
+use message_hook::MessageHook;
+use method_config::MethodConfig;
+use panic_policy::PanicPolicy;
+use request_id::RequestIdConfig;
+#[cfg(feature = "executor-bridge")]
+use runtime::{BoxFuture, ExternalExecutor};
+use watchdog::{self, Watchdog, WatchdogCore};
 use RpcContext;
 
 const DEFAULT_REQUEST_SLOTS_PER_CQ: usize = 1024;
 
+/// The kind of a socket a [`Server`] listens on.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SocketFamily {
+    Ipv4,
+    /// An IPv6 socket that doesn't accept IPv4 connections.
+    Ipv6,
+    /// An IPv6 socket that also accepts IPv4 connections.
+    DualStack,
+    Unix,
+}
+
 /// An RPC call holder.
 #[derive(Clone)]
 pub struct Handler<F> {
@@ -49,6 +93,13 @@ pub trait CloneableHandler: Send {
     fn handle(&self, ctx: RpcContext, reqs: &[u8]);
     fn box_clone(&self) -> Box<CloneableHandler>;
     fn method_type(&self) -> MethodType;
+
+    /// Whether the method is marked by [`ServiceBuilder::deprecate_method`].
+    ///
+    /// [`ServiceBuilder::deprecate_method`]: struct.ServiceBuilder.html#method.deprecate_method
+    fn is_deprecated(&self) -> bool {
+        false
+    }
 }
 
 impl<F: 'static> CloneableHandler for Handler<F>
@@ -71,10 +122,77 @@ where
     }
 }
 
-#[cfg(feature = "secure")]
+struct DeprecatedHandler(BoxHandler);
+
+impl CloneableHandler for DeprecatedHandler {
+    #[inline]
+    fn handle(&self, ctx: RpcContext, reqs: &[u8]) {
+        self.0.handle(ctx, reqs)
+    }
+
+    #[inline]
+    fn box_clone(&self) -> Box<CloneableHandler> {
+        Box::new(DeprecatedHandler(self.0.box_clone()))
+    }
+
+    #[inline]
+    fn method_type(&self) -> MethodType {
+        self.0.method_type()
+    }
+
+    #[inline]
+    fn is_deprecated(&self) -> bool {
+        true
+    }
+}
+
+/// Counts of the calls to deprecated methods.
+#[derive(Default)]
+pub struct DeprecatedCalls {
+    calls: Mutex<HashMap<String, usize>>,
+}
+
+impl DeprecatedCalls {
+    pub fn record(&self, ctx: &RpcContext) {
+        let method = String::from_utf8_lossy(ctx.method());
+        let mut calls = self.calls.lock().unwrap();
+        if let Some(count) = calls.get_mut(&*method) {
+            *count += 1;
+            return;
+        }
+        warn!("deprecated method {} is called by {}", method, ctx.peer());
+        calls.insert(method.into_owned(), 1);
+    }
+
+    fn snapshot(&self) -> Vec<(String, usize)> {
+        let mut calls: Vec<_> = self
+            .calls
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(m, c)| (m.clone(), *c))
+            .collect();
+        calls.sort();
+        calls
+    }
+}
+
+/// Join host and port into an address that can be recognized by gRPC core.
+///
+/// IPv6 literals need to be wrapped in brackets, e.g. `[::1]:50051`.
+fn join_host_port(host: &str, port: u16) -> String {
+    if host.contains(':') && !host.starts_with('[') {
+        format!("[{}]:{}\0", host, port)
+    } else {
+        format!("{}:{}\0", host, port)
+    }
+}
+
+#[cfg(feature = "tls-server")]
 mod imp {
     use grpc_sys::{self, GrpcServer};
 
+    use super::join_host_port;
     use credentials::ServerCredentials;
 
     pub struct Binder {
@@ -95,7 +213,7 @@ mod imp {
         }
 
         pub unsafe fn bind(&mut self, server: *mut GrpcServer) -> u16 {
-            let addr = format!("{}:{}\0", self.host, self.port);
+            let addr = join_host_port(&self.host, self.port);
             let port = match self.cred.take() {
                 None => grpc_sys::grpc_server_add_insecure_http2_port(server, addr.as_ptr() as _),
                 Some(mut cert) => grpc_sys::grpc_server_add_secure_http2_port(
@@ -109,10 +227,12 @@ mod imp {
     }
 }
 
-#[cfg(not(feature = "secure"))]
+#[cfg(not(feature = "tls-server"))]
 mod imp {
     use grpc_sys::{self, GrpcServer};
 
+    use super::join_host_port;
+
     pub struct Binder {
         pub host: String,
         pub port: u16,
@@ -124,7 +244,7 @@ mod imp {
         }
 
         pub unsafe fn bind(&mut self, server: *mut GrpcServer) -> u16 {
-            let addr = format!("{}:{}\0", self.host, self.port);
+            let addr = join_host_port(&self.host, self.port);
             grpc_sys::grpc_server_add_insecure_http2_port(server, addr.as_ptr() as _) as u16
         }
     }
@@ -166,6 +286,38 @@ impl ServiceBuilder {
         self
     }
 
+    /// Add a unary RPC call handler that runs `f` in `pool`, see
+    /// [`blocking`](blocking/index.html) for details.
+    pub fn add_blocking_unary_handler<Req, Resp, F>(
+        self,
+        method: &Method<Req, Resp>,
+        pool: &BlockingPool,
+        f: F,
+    ) -> ServiceBuilder
+    where
+        Req: Send + 'static,
+        Resp: Send + 'static,
+        F: Fn(Req) -> result::Result<Resp, RpcStatus> + Send + Sync + 'static,
+    {
+        let (pool, f) = (pool.clone(), Arc::new(f));
+        self.add_unary_handler(method, move |ctx, req, sink: UnarySink<Resp>| {
+            let (tx, rx) = oneshot::channel();
+            let f = f.clone();
+            pool.execute(move || {
+                let _ = tx.send(f(req));
+            });
+            let f = rx.then(|res| match res {
+                Ok(Ok(resp)) => sink.success(resp),
+                Ok(Err(status)) => sink.fail(status),
+                Err(_) => sink.fail(RpcStatus::new(
+                    RpcStatusCode::Internal,
+                    Some("blocking handler panicked".to_owned()),
+                )),
+            });
+            ctx.spawn(f.map_err(|e| error!("failed to send response: {:?}", e)));
+        })
+    }
+
     /// Add a client streaming RPC call handler.
     pub fn add_client_streaming_handler<Req, Resp, F>(
         mut self,
@@ -222,6 +374,57 @@ impl ServiceBuilder {
         self
     }
 
+    /// Add a chunked unary RPC call handler.
+    ///
+    /// `method` should be a duplex method whose marshallers handle the whole request
+    /// and response. The response is sent in chunks no larger than `chunk_size`.
+    /// See [`chunk`] for more details.
+    ///
+    /// [`chunk`]: chunk/index.html
+    pub fn add_chunked_unary_handler<Req, Resp, F>(
+        mut self,
+        method: &Method<Req, Resp>,
+        chunk_size: usize,
+        handler: F,
+    ) -> ServiceBuilder
+    where
+        Req: 'static,
+        Resp: 'static,
+        F: Fn(RpcContext, ChunkedRequest<Req>, ChunkedSink<Resp>) + Send + Clone + 'static,
+    {
+        let (ser, de) = (method.resp_ser(), method.req_de());
+        let h = move |ctx: RpcContext, _: &[u8]| {
+            execute_duplex_streaming(ctx, raw_codec::ser, raw_codec::de, &|ctx, reqs, sink| {
+                let req = ChunkedRequest::new(reqs, de);
+                let sink = ChunkedSink::new(sink, ser, chunk_size);
+                handler(ctx, req, sink)
+            })
+        };
+        let ch = Box::new(Handler::new(MethodType::Duplex, h));
+        self.handlers.insert(method.name.as_bytes(), ch);
+        self
+    }
+
+    /// Mark `method` as deprecated, the calls to it are counted by
+    /// [`ServerBuilder::deprecation_stats`].
+    ///
+    /// It should be called after the handler of `method` is added, the code
+    /// generated for a method with `option deprecated = true` does so.
+    ///
+    /// [`ServerBuilder::deprecation_stats`]: struct.ServerBuilder.html#method.deprecation_stats
+    pub fn deprecate_method<Req, Resp>(mut self, method: &Method<Req, Resp>) -> ServiceBuilder {
+        let name = method.name.as_bytes();
+        if let Some(h) = self.handlers.remove(name) {
+            let h = if h.is_deprecated() {
+                h
+            } else {
+                Box::new(DeprecatedHandler(h))
+            };
+            self.handlers.insert(name, h);
+        }
+        self
+    }
+
     /// Finalize the [`ServiceBuilder`] and build the [`Service`].
     pub fn build(self) -> Service {
         Service {
@@ -237,13 +440,44 @@ pub struct Service {
     handlers: HashMap<&'static [u8], BoxHandler>,
 }
 
+/// A hook run with the addresses the server is bound to.
+type ServerHook = Box<FnOnce(&[(String, u16)]) + Send>;
+
 /// [`Server`] factory in order to configure the properties.
 pub struct ServerBuilder {
     env: Arc<Environment>,
     binders: Vec<Binder>,
     args: Option<ChannelArgs>,
     slots_per_cq: usize,
+    max_slots_per_cq: Option<usize>,
     handlers: HashMap<&'static [u8], BoxHandler>,
+    virtual_hosts: HashMap<String, HashMap<&'static [u8], BoxHandler>>,
+    fallback: Option<BoxHandler>,
+    checksum: Option<Arc<Checksum>>,
+    access_log: Option<Arc<AccessLog>>,
+    request_id: Option<RequestIdConfig>,
+    message_hook: Option<Arc<MessageHook>>,
+    panic_policy: PanicPolicy,
+    watchdog: Option<Watchdog>,
+    flow_stats: bool,
+    deprecation_stats: bool,
+    authorizer: Option<Authorizer>,
+    bandwidth_limit: Option<BandwidthLimit>,
+    stream_idle_timeout: Option<Duration>,
+    call_metric_recording: bool,
+    start_hooks: Vec<ServerHook>,
+    admin: Option<Box<ServerBuilder>>,
+    method_configs: HashMap<Vec<u8>, Arc<MethodConfig>>,
+    v6_only: Option<bool>,
+    max_concurrent_streams: Option<u32>,
+    compression_algorithm: Option<CompressionAlgorithms>,
+    compression_level: Option<CompressionLevel>,
+    #[cfg(unix)]
+    listeners: Vec<RawFd>,
+    #[cfg(unix)]
+    h2c_upgrade: bool,
+    #[cfg(feature = "executor-bridge")]
+    executor: Option<ExternalExecutor>,
 }
 
 impl ServerBuilder {
@@ -254,45 +488,402 @@ impl ServerBuilder {
             binders: Vec::new(),
             args: None,
             slots_per_cq: DEFAULT_REQUEST_SLOTS_PER_CQ,
+            max_slots_per_cq: None,
             handlers: HashMap::new(),
+            virtual_hosts: HashMap::new(),
+            fallback: None,
+            checksum: None,
+            access_log: None,
+            request_id: None,
+            message_hook: None,
+            panic_policy: PanicPolicy::new(),
+            watchdog: None,
+            flow_stats: false,
+            deprecation_stats: false,
+            authorizer: None,
+            bandwidth_limit: None,
+            stream_idle_timeout: None,
+            call_metric_recording: false,
+            start_hooks: vec![],
+            admin: None,
+            method_configs: HashMap::new(),
+            v6_only: None,
+            max_concurrent_streams: None,
+            compression_algorithm: None,
+            compression_level: None,
+            #[cfg(unix)]
+            listeners: vec![],
+            #[cfg(unix)]
+            h2c_upgrade: false,
+            #[cfg(feature = "executor-bridge")]
+            executor: None,
         }
     }
 
     /// Bind to an address.
     ///
     /// This function can be called multiple times to bind to multiple ports.
+    /// `host` can be a hostname, an IPv4 address or an IPv6 address. If `port`
+    /// is 0, an unused port will be picked, which can be queried by
+    /// [`Server::bind_addrs`] after the server is built.
+    ///
+    /// The port speaks HTTP/2 over cleartext with prior knowledge only, see
+    /// [`h2c_upgrade`](#method.h2c_upgrade) for clients that start with
+    /// HTTP/1.1.
+    ///
+    /// [`Server::bind_addrs`]: struct.Server.html#method.bind_addrs
     pub fn bind<S: Into<String>>(mut self, host: S, port: u16) -> ServerBuilder {
         self.binders.push(Binder::new(host.into(), port));
         self
     }
 
+    /// Serve the connections accepted from a listening socket, e.g. one passed
+    /// by systemd socket activation, see
+    /// [`socket_activation`](socket_activation/index.html).
+    ///
+    /// gRPC core can't adopt listening sockets, so the connections are
+    /// accepted by a thread once the server is started, until it's shut down.
+    /// The socket is not closed by the server, so that it can be handed over
+    /// to another process.
+    #[cfg(unix)]
+    pub fn bind_fd(mut self, fd: RawFd) -> ServerBuilder {
+        self.listeners.push(fd);
+        self
+    }
+
+    /// Accept the HTTP/1.1 Upgrade to h2c on the sockets passed by
+    /// [`bind_fd`](#method.bind_fd), besides HTTP/2 with prior knowledge.
+    /// It's disabled by default, and not available on the ports bound by
+    /// gRPC core.
+    ///
+    /// Some load balancers only reach HTTP/2 backends this way. Only probes
+    /// are upgraded: an `OPTIONS` request without a body is answered with
+    /// `101 Switching Protocols` and is not served on stream 1. Upgrade
+    /// requests with other methods get `405 Method Not Allowed`, those with a
+    /// body or malformed `HTTP2-Settings` get `400 Bad Request`, and other
+    /// HTTP/1.1 requests get `426 Upgrade Required`. The settings in
+    /// `HTTP2-Settings` are not applied, the client sends them again in its
+    /// connection preface.
+    ///
+    /// Requests whose method starts with `P`, like `POST`, can't be told apart
+    /// from the preface by their first byte, they are handed over and closed by
+    /// gRPC core.
+    #[cfg(unix)]
+    pub fn h2c_upgrade(mut self, enabled: bool) -> ServerBuilder {
+        self.h2c_upgrade = enabled;
+        self
+    }
+
+    /// Bind to `[::]`, which accepts both IPv4 and IPv6 connections on the
+    /// platforms that support dual stack sockets.
+    ///
+    /// Check [`Server::bound_families`] for the families it actually listens on.
+    ///
+    /// [`Server::bound_families`]: struct.Server.html#method.bound_families
+    pub fn bind_dual_stack(self, port: u16) -> ServerBuilder {
+        self.bind("::", port)
+    }
+
+    /// Set whether IPv6 sockets only accept IPv6 connections.
+    ///
+    /// By default gRPC core tries to make IPv6 sockets dual stack. Note that
+    /// gRPC core binds `0.0.0.0` as `[::]` too, so it's rejected when IPv6 only
+    /// is enabled.
+    #[cfg(unix)]
+    pub fn bind_v6_only(mut self, v6_only: bool) -> ServerBuilder {
+        self.v6_only = Some(v6_only);
+        self
+    }
+
+    /// Set the max number of calls a client can make concurrently on one
+    /// connection.
+    ///
+    /// Calls beyond the limit are queued by the client instead of failing. It
+    /// doesn't bound the calls from all the connections, which is done by
+    /// [`requests_slot_per_cq`](#method.requests_slot_per_cq), nor the work
+    /// spawned by handlers.
+    pub fn max_concurrent_streams(mut self, num: u32) -> ServerBuilder {
+        self.max_concurrent_streams = Some(num);
+        self
+    }
+
+    /// Set the default algorithm the responses are compressed with.
+    pub fn default_compression_algorithm(mut self, algo: CompressionAlgorithms) -> ServerBuilder {
+        self.compression_algorithm = Some(algo);
+        self
+    }
+
+    /// Set the default level the responses are compressed at, which takes
+    /// precedence over the default algorithm.
+    ///
+    /// gRPC core maps the level to an algorithm the client accepts. It always
+    /// compresses with the default zlib level, so `CompressionLevel::None` is
+    /// the way to save CPU time on compression. It can be overridden by
+    /// [`MethodConfig::compression_level`] for a method.
+    ///
+    /// [`MethodConfig::compression_level`]: method_config/struct.MethodConfig.html#method.compression_level
+    pub fn default_compression_level(mut self, level: CompressionLevel) -> ServerBuilder {
+        self.compression_level = Some(level);
+        self
+    }
+
     /// Add additional configuration for each incoming channel.
+    ///
+    /// The options of the builder that are applied by channel arguments, e.g.
+    /// [`max_concurrent_streams`](#method.max_concurrent_streams), are merged
+    /// into `args` and take precedence over the same arguments in it.
     #[doc(hidden)]
     pub fn channel_args(mut self, args: ChannelArgs) -> ServerBuilder {
         self.args = Some(args);
         self
     }
 
-    /// Set how many requests a completion queue can handle.
+    /// Set how many requests a completion queue can handle, it should be larger than 0.
     pub fn requests_slot_per_cq(mut self, slots: usize) -> ServerBuilder {
         self.slots_per_cq = slots;
         self
     }
 
+    /// Let a completion queue post more request slots when it runs out of
+    /// them, up to `max` in total. By default it stays at
+    /// [`requests_slot_per_cq`](#method.requests_slot_per_cq).
+    ///
+    /// New calls wait in gRPC core while there is no slot, which adds latency
+    /// to bursts. The slots are never taken back once posted, see
+    /// [`Server::request_slot_stats`] to tune both.
+    ///
+    /// [`Server::request_slot_stats`]: struct.Server.html#method.request_slot_stats
+    pub fn max_requests_slot_per_cq(mut self, max: usize) -> ServerBuilder {
+        self.max_slots_per_cq = Some(max);
+        self
+    }
+
+    /// Verify the checksum of unary requests if client sends one, and send the
+    /// checksum of unary responses in trailers.
+    ///
+    /// Requests that fail the verification are rejected with `DataLoss`.
+    pub fn checksum(mut self, checksum: Arc<Checksum>) -> ServerBuilder {
+        self.checksum = Some(checksum);
+        self
+    }
+
+    /// Log every call that is finished with a status.
+    pub fn access_log(mut self, log: AccessLog) -> ServerBuilder {
+        self.access_log = Some(Arc::new(log));
+        self
+    }
+
+    /// Assign an ID to every call, see [`request_id`](request_id/index.html)
+    /// for details.
+    pub fn request_id(mut self, config: RequestIdConfig) -> ServerBuilder {
+        self.request_id = Some(config);
+        self
+    }
+
+    /// Invoke `hook` with the messages of every call, see
+    /// [`message_hook`](message_hook/index.html) for details.
+    pub fn message_hook(mut self, hook: Arc<MessageHook>) -> ServerBuilder {
+        self.message_hook = Some(hook);
+        self
+    }
+
+    /// Set how panics raised by handlers are handled, see
+    /// [`panic_policy`](panic_policy/index.html) for details.
+    pub fn panic_policy(mut self, policy: PanicPolicy) -> ServerBuilder {
+        self.panic_policy = policy;
+        self
+    }
+
+    /// Report calls that run too long, see [`watchdog`](watchdog/index.html)
+    /// for details.
+    pub fn watchdog(mut self, watchdog: Watchdog) -> ServerBuilder {
+        self.watchdog = Some(watchdog);
+        self
+    }
+
+    /// Track the backpressure of the calls in progress, so that it can be
+    /// reported by [`Server::stream_flows`], see [`flow`](flow/index.html)
+    /// for details. It's disabled by default.
+    ///
+    /// [`Server::stream_flows`]: struct.Server.html#method.stream_flows
+    pub fn flow_stats(mut self, enabled: bool) -> ServerBuilder {
+        self.flow_stats = enabled;
+        self
+    }
+
+    /// Count the calls to the methods marked by
+    /// [`ServiceBuilder::deprecate_method`], so that they can be reported by
+    /// [`Server::deprecated_calls`]. A warning is logged the first time each
+    /// of them is called. It's disabled by default.
+    ///
+    /// [`ServiceBuilder::deprecate_method`]: struct.ServiceBuilder.html#method.deprecate_method
+    /// [`Server::deprecated_calls`]: struct.Server.html#method.deprecated_calls
+    pub fn deprecation_stats(mut self, enabled: bool) -> ServerBuilder {
+        self.deprecation_stats = enabled;
+        self
+    }
+
+    /// Check every call by `authorizer` before its handler is invoked, see
+    /// [`authz`](authz/index.html) for details.
+    pub fn authorizer(mut self, authorizer: Authorizer) -> ServerBuilder {
+        self.authorizer = Some(authorizer);
+        self
+    }
+
+    /// Limit the bandwidth of streaming calls, see
+    /// [`bandwidth`](bandwidth/index.html) for details.
+    pub fn bandwidth_limit(mut self, limit: BandwidthLimit) -> ServerBuilder {
+        self.bandwidth_limit = Some(limit);
+        self
+    }
+
+    /// Reset the streaming calls that have neither received nor sent a
+    /// message for longer than `timeout` with `Unavailable`.
+    ///
+    /// A request message counts once the handler takes it, and a response
+    /// message once it's handed to gRPC core, so a call whose writes are
+    /// stuck on a peer that stopped reading is idle too. Unary calls are
+    /// never reset.
+    pub fn stream_idle_timeout(mut self, timeout: Duration) -> ServerBuilder {
+        self.stream_idle_timeout = Some(timeout);
+        self
+    }
+
+    /// Let handlers record the load of the server with
+    /// [`RpcContext::call_metric_recorder`], which is reported to clients in
+    /// the trailers, see [`orca`](orca/index.html). It's disabled by default.
+    ///
+    /// [`RpcContext::call_metric_recorder`]: struct.RpcContext.html#method.call_metric_recorder
+    pub fn call_metric_recording(mut self, enabled: bool) -> ServerBuilder {
+        self.call_metric_recording = enabled;
+        self
+    }
+
+    /// Run `hook` with the bound addresses once [`Server::start`] returns,
+    /// when the server is accepting calls, e.g. to register it with service
+    /// discovery. Hooks are run in the order they are added.
+    ///
+    /// [`Server::start`]: struct.Server.html#method.start
+    pub fn on_start<F>(mut self, hook: F) -> ServerBuilder
+    where
+        F: FnOnce(&[(String, u16)]) + Send + 'static,
+    {
+        self.start_hooks.push(Box::new(hook));
+        self
+    }
+
+    /// Serve `services` by a server of their own bound to `host:port`, which
+    /// is started with the server and keeps serving until it's dropped.
+    #[cfg(feature = "protobuf-codec")]
+    pub(crate) fn admin_server(
+        mut self,
+        services: Vec<Service>,
+        host: String,
+        port: u16,
+    ) -> ServerBuilder {
+        let builder = ServerBuilder::new(self.env.clone()).bind(host, port);
+        let builder = services
+            .into_iter()
+            .fold(builder, |b, s| b.register_service(s));
+        self.admin = Some(Box::new(builder));
+        self
+    }
+
+    /// Spawn the futures of handlers onto `executor` instead of the gRPC
+    /// poll threads, see [`runtime`](runtime/index.html) for details.
+    #[cfg(feature = "executor-bridge")]
+    pub fn executor<E>(mut self, executor: E) -> ServerBuilder
+    where
+        E: ::futures::future::Executor<BoxFuture> + Send + Sync + 'static,
+    {
+        self.executor = Some(ExternalExecutor::new(executor));
+        self
+    }
+
+    /// Apply `config` to the calls of the method at `path`, e.g.
+    /// `/helloworld.Greeter/SayHello`. See [`method_config`](method_config/index.html)
+    /// for details.
+    pub fn method_config<S: Into<String>>(
+        mut self,
+        path: S,
+        config: MethodConfig,
+    ) -> ServerBuilder {
+        self.method_configs
+            .insert(path.into().into_bytes(), Arc::new(config));
+        self
+    }
+
     /// Register a service.
     pub fn register_service(mut self, service: Service) -> ServerBuilder {
         self.handlers.extend(service.handlers);
         self
     }
 
+    /// Register a service that only serves the calls to `host`.
+    ///
+    /// A call is dispatched by its `:authority`, which is compared with `host` as is
+    /// first, and then with its port stripped. Calls to other hosts, or to methods that
+    /// are not in `service`, are handled by the services registered by
+    /// [`register_service`] as usual, so the same service can be registered once
+    /// per tenant behind one port.
+    ///
+    /// [`register_service`]: #method.register_service
+    pub fn register_service_for_host<S: Into<String>>(
+        mut self,
+        host: S,
+        service: Service,
+    ) -> ServerBuilder {
+        self.virtual_hosts
+            .entry(host.into())
+            .or_insert_with(HashMap::new)
+            .extend(service.handlers);
+        self
+    }
+
+    /// Set a handler for all the methods that are not registered.
+    ///
+    /// Because the method type is unknown, every call is handled as a duplex streaming call
+    /// with raw payloads. The method name and request headers can be retrieved from the
+    /// [`RpcContext`]. If no fallback handler is set, `Unimplemented` is returned.
+    pub fn fallback_handler<F>(mut self, handler: F) -> ServerBuilder
+    where
+        F: Fn(RpcContext, RequestStream<Vec<u8>>, DuplexSink<Vec<u8>>) + Send + Clone + 'static,
+    {
+        let h = move |ctx: RpcContext, _: &[u8]| {
+            execute_duplex_streaming(ctx, raw_codec::ser, raw_codec::de, &handler)
+        };
+        self.fallback = Some(Box::new(Handler::new(MethodType::Duplex, h)));
+        self
+    }
+
     /// Finalize the [`ServerBuilder`] and build the [`Server`].
     pub fn build(mut self) -> Result<Server> {
-        let args = self
-            .args
-            .as_ref()
-            .map_or_else(ptr::null, |args| args.as_ptr());
+        if self.slots_per_cq == 0 {
+            return Err(Error::InvalidConfig(
+                "request slots per completion queue must be larger than 0".to_owned(),
+            ));
+        }
+        let max_slots_per_cq = self.max_slots_per_cq.unwrap_or(self.slots_per_cq);
+        if max_slots_per_cq < self.slots_per_cq {
+            return Err(Error::InvalidConfig(format!(
+                "max request slots per completion queue {} is less than {}",
+                max_slots_per_cq, self.slots_per_cq
+            )));
+        }
+        let admin = match self.admin.take() {
+            Some(b) => Some(Box::new(b.build()?)),
+            None => None,
+        };
+        if self.v6_only == Some(true) && self.binders.iter().any(|b| b.host == "0.0.0.0") {
+            return Err(Error::InvalidConfig(
+                "0.0.0.0 is bound as [::], which can't accept IPv4 when IPv6 only is enabled"
+                    .to_owned(),
+            ));
+        }
+        let families = Arc::new(Mutex::new(vec![]));
+        let args = self.server_args(&families).build_args();
         unsafe {
-            let server = grpc_sys::grpc_server_create(args, ptr::null_mut());
+            let server = grpc_sys::grpc_server_create(args.as_ptr(), ptr::null_mut());
             let mut bind_addrs = Vec::with_capacity(self.binders.len());
             for mut binder in self.binders.drain(..) {
                 let bind_port = binder.bind(server);
@@ -313,21 +904,152 @@ impl ServerBuilder {
                 );
             }
 
+            let families = families.lock().unwrap().clone();
+            let slots = self
+                .env
+                .completion_queues()
+                .iter()
+                .map(|_| Arc::default())
+                .collect();
             Ok(Server {
                 env: self.env,
                 core: Arc::new(ServerCore {
                     server,
                     shutdown: AtomicBool::new(false),
+                    draining: Arc::default(),
+                    in_flight: Arc::default(),
                     bind_addrs,
                     slots_per_cq: self.slots_per_cq,
+                    max_slots_per_cq,
+                    slots,
+                    handlers: Mutex::new(self.handlers),
+                    virtual_hosts: Mutex::new(self.virtual_hosts),
+                    fallback: Mutex::new(self.fallback),
+                    generation: AtomicUsize::new(0),
+                    checksum: self.checksum,
+                    access_log: self.access_log,
+                    request_id: self.request_id,
+                    message_hook: self.message_hook,
+                    panic_policy: self.panic_policy,
+                    watchdog: self.watchdog.map(watchdog::start),
+                    flows: if self.flow_stats {
+                        Some(FlowRegistry::default())
+                    } else {
+                        None
+                    },
+                    deprecated_calls: if self.deprecation_stats {
+                        Some(DeprecatedCalls::default())
+                    } else {
+                        None
+                    },
+                    authorizer: self.authorizer,
+                    bandwidth: self.bandwidth_limit.map(BandwidthLimiter::new),
+                    stream_idle_timeout: self.stream_idle_timeout,
+                    call_metric_recording: self.call_metric_recording,
+                    method_configs: self.method_configs,
+                    families,
+                    #[cfg(unix)]
+                    listeners: self.listeners,
+                    #[cfg(unix)]
+                    h2c_upgrade: self.h2c_upgrade,
+                    #[cfg(feature = "executor-bridge")]
+                    executor: self.executor,
                 }),
-                handlers: self.handlers,
+                start_hooks: self.start_hooks,
+                shutdown_hooks: vec![],
+                admin,
             })
         }
     }
+
+    /// Get the channel arguments for the options of the builder.
+    fn server_args(&self, families: &Arc<Mutex<Vec<SocketFamily>>>) -> ChannelBuilder {
+        let mut builder = match self.args {
+            Some(ref args) => ChannelBuilder::from_args(self.env.clone(), args),
+            None => ChannelBuilder::new(self.env.clone()),
+        };
+        if let Some(num) = self.max_concurrent_streams {
+            builder = builder.max_concurrent_stream(cmp::min(num, i32::MAX as u32) as i32);
+        }
+        if let Some(algo) = self.compression_algorithm {
+            builder = builder.default_compression_algorithm(algo);
+        }
+        if let Some(level) = self.compression_level {
+            builder = builder.default_compression_level(level);
+        }
+        #[cfg(unix)]
+        let builder = {
+            let v6_only = self.v6_only;
+            let families = families.clone();
+            builder.socket_mutator(move |fd| prepare_listener(fd, v6_only, &families))
+        };
+        #[cfg(not(unix))]
+        let _ = families;
+        builder
+    }
+}
+
+/// Apply the options to a listening socket and record its family.
+#[cfg(unix)]
+fn prepare_listener(fd: RawFd, v6_only: Option<bool>, families: &Mutex<Vec<SocketFamily>>) -> bool {
+    unsafe {
+        let mut storage: libc::sockaddr_storage = mem::zeroed();
+        let mut len = mem::size_of_val(&storage) as libc::socklen_t;
+        let addr = &mut storage as *mut _ as *mut libc::sockaddr;
+        // Accepted sockets are connected, only listeners are handled.
+        if libc::getpeername(fd, addr, &mut len) == 0 {
+            return true;
+        }
+        len = mem::size_of_val(&storage) as libc::socklen_t;
+        if libc::getsockname(fd, addr, &mut len) != 0 {
+            return true;
+        }
+        let opt_len = mem::size_of::<c_int>() as libc::socklen_t;
+        let family = match c_int::from(storage.ss_family) {
+            libc::AF_INET => SocketFamily::Ipv4,
+            libc::AF_INET6 => {
+                if let Some(v6_only) = v6_only {
+                    let val = v6_only as c_int;
+                    let res = libc::setsockopt(
+                        fd,
+                        libc::IPPROTO_IPV6,
+                        libc::IPV6_V6ONLY,
+                        &val as *const _ as *const c_void,
+                        opt_len,
+                    );
+                    if res != 0 {
+                        error!(
+                            "failed to set IPV6_V6ONLY to {}: {}",
+                            v6_only,
+                            io::Error::last_os_error()
+                        );
+                        return false;
+                    }
+                }
+                let mut val: c_int = 1;
+                let mut len = opt_len;
+                let res = libc::getsockopt(
+                    fd,
+                    libc::IPPROTO_IPV6,
+                    libc::IPV6_V6ONLY,
+                    &mut val as *mut _ as *mut c_void,
+                    &mut len,
+                );
+                if res == 0 && val == 0 {
+                    SocketFamily::DualStack
+                } else {
+                    SocketFamily::Ipv6
+                }
+            }
+            libc::AF_UNIX => SocketFamily::Unix,
+            _ => return true,
+        };
+        families.lock().unwrap().push(family);
+        true
+    }
 }
 
-#[cfg(feature = "secure")]
+#[cfg(feature = "tls-server")]
 mod secure_server {
     use credentials::ServerCredentials;
 
@@ -346,6 +1068,27 @@ mod secure_server {
             self.binders.push(Binder::with_cred(host.into(), port, c));
             self
         }
+
+        /// Bind to an address with the given credentials.
+        ///
+        /// Credentials are applied to this address only, so one server can listen
+        /// on a secure port and an insecure port at the same time. If `cred` is
+        /// `None`, the address accepts insecure connections, just like [`bind`].
+        ///
+        /// [`bind`]: #method.bind
+        pub fn bind_with_cred<S: Into<String>>(
+            mut self,
+            host: S,
+            port: u16,
+            cred: Option<ServerCredentials>,
+        ) -> ServerBuilder {
+            let binder = match cred {
+                Some(c) => Binder::with_cred(host.into(), port, c),
+                None => Binder::new(host.into(), port),
+            };
+            self.binders.push(binder);
+            self
+        }
     }
 }
 
@@ -353,7 +1096,165 @@ struct ServerCore {
     server: *mut GrpcServer,
     bind_addrs: Vec<(String, u16)>,
     slots_per_cq: usize,
+    max_slots_per_cq: usize,
+    // One for each completion queue.
+    slots: Vec<Arc<RequestSlots>>,
     shutdown: AtomicBool,
+    draining: Arc<AtomicBool>,
+    // Calls that are handled but not finished.
+    in_flight: Arc<AtomicUsize>,
+    handlers: Mutex<HashMap<&'static [u8], BoxHandler>>,
+    virtual_hosts: Mutex<HashMap<String, HashMap<&'static [u8], BoxHandler>>>,
+    fallback: Mutex<Option<BoxHandler>>,
+    // Bumped every time `handlers` is changed, so that the replica held by
+    // each completion queue knows when to be refreshed.
+    generation: AtomicUsize,
+    checksum: Option<Arc<Checksum>>,
+    access_log: Option<Arc<AccessLog>>,
+    request_id: Option<RequestIdConfig>,
+    message_hook: Option<Arc<MessageHook>>,
+    panic_policy: PanicPolicy,
+    watchdog: Option<Arc<WatchdogCore>>,
+    flows: Option<FlowRegistry>,
+    deprecated_calls: Option<DeprecatedCalls>,
+    authorizer: Option<Authorizer>,
+    bandwidth: Option<BandwidthLimiter>,
+    stream_idle_timeout: Option<Duration>,
+    call_metric_recording: bool,
+    method_configs: HashMap<Vec<u8>, Arc<MethodConfig>>,
+    families: Vec<SocketFamily>,
+    #[cfg(unix)]
+    listeners: Vec<RawFd>,
+    #[cfg(unix)]
+    h2c_upgrade: bool,
+    #[cfg(feature = "executor-bridge")]
+    executor: Option<ExternalExecutor>,
+}
+
+impl ServerCore {
+    fn replicate_handlers(&self) -> HandlerRegistry {
+        // Load generation before copying, so a concurrent update will
+        // trigger another refresh instead of being lost.
+        let generation = self.generation.load(Ordering::SeqCst);
+        let handlers = self.handlers.lock().unwrap();
+        // Handlers are Send and Clone, but not Sync. So we need to
+        // provide a replica for each completion queue.
+        let handlers = clone_handlers(&handlers);
+        let virtual_hosts = self
+            .virtual_hosts
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(host, handlers)| (host.clone(), clone_handlers(handlers)))
+            .collect();
+        let fallback = self
+            .fallback
+            .lock()
+            .unwrap()
+            .as_ref()
+            .map(|h| h.box_clone());
+        HandlerRegistry {
+            generation,
+            handlers,
+            virtual_hosts,
+            fallback,
+        }
+    }
+}
+
+fn clone_handlers(
+    handlers: &HashMap<&'static [u8], BoxHandler>,
+) -> HashMap<&'static [u8], BoxHandler> {
+    handlers
+        .iter()
+        .map(|(k, v)| (k.to_owned(), v.box_clone()))
+        .collect()
+}
+
+/// Strip the port from an authority, e.g. `example.com:443` or `[::1]:443`.
+fn strip_port(authority: &str) -> &str {
+    match authority.rfind(':') {
+        Some(pos) if !authority[pos..].contains(']') => &authority[..pos],
+        _ => authority,
+    }
+}
+
+#[cfg(unix)]
+unsafe fn add_channel_from_fd(core: &ServerCore, fd: RawFd) {
+    // gRPC core expects the socket to be non-blocking.
+    let flags = libc::fcntl(fd, libc::F_GETFL);
+    libc::fcntl(fd, libc::F_SETFL, flags | libc::O_NONBLOCK);
+    grpc_sys::grpc_server_add_insecure_channel_from_fd(core.server, ptr::null_mut(), fd)
+}
+
+/// Accept connections from `fd` until the server is shut down.
+#[cfg(unix)]
+fn accept_loop(core: &Weak<ServerCore>, fd: RawFd) {
+    let mut handshakes: Vec<h2c::Handshake> = vec![];
+    loop {
+        let pollfd = |fd| libc::pollfd {
+            fd,
+            events: libc::POLLIN,
+            revents: 0,
+        };
+        let mut pfds: Vec<_> = Some(fd)
+            .into_iter()
+            .chain(handshakes.iter().map(|h| h.as_raw_fd()))
+            .map(pollfd)
+            .collect();
+        // Wake up periodically to check whether the server is shut down.
+        let res = unsafe { libc::poll(pfds.as_mut_ptr(), pfds.len() as libc::nfds_t, 100) };
+        let core = match core.upgrade() {
+            Some(core) => core,
+            None => return,
+        };
+        if core.shutdown.load(Ordering::SeqCst) {
+            return;
+        }
+        if res < 0 {
+            continue;
+        }
+        let pending = mem::replace(&mut handshakes, vec![]);
+        for (h, pfd) in pending.into_iter().zip(&pfds[1..]) {
+            if pfd.revents == 0 {
+                handshakes.push(h);
+                continue;
+            }
+            match h.advance() {
+                Ok(h2c::Progress::Pending(h)) => handshakes.push(h),
+                Ok(h2c::Progress::Ready(stream)) => unsafe {
+                    add_channel_from_fd(&core, stream.into_raw_fd())
+                },
+                Ok(h2c::Progress::Closed) => {}
+                Err(e) => debug!("failed to upgrade connection to h2c: {}", e),
+            }
+        }
+        let now = Instant::now();
+        handshakes.retain(|h| !h.is_expired(now));
+        if pfds[0].revents == 0 {
+            continue;
+        }
+        let conn = unsafe { libc::accept(fd, ptr::null_mut(), ptr::null_mut()) };
+        if conn < 0 {
+            let e = io::Error::last_os_error();
+            if e.kind() != io::ErrorKind::WouldBlock && e.kind() != io::ErrorKind::Interrupted {
+                error!("failed to accept connection from fd {}: {}", fd, e);
+                // Avoid spinning on persistent errors like EMFILE.
+                thread::sleep(Duration::from_millis(100));
+            }
+            continue;
+        }
+        if !core.h2c_upgrade {
+            unsafe { add_channel_from_fd(&core, conn) }
+            continue;
+        }
+        // The handshakes are driven by this thread, without blocking the
+        // others.
+        match h2c::Handshake::new(unsafe { TcpStream::from_raw_fd(conn) }) {
+            Ok(h) => handshakes.push(h),
+            Err(e) => debug!("failed to upgrade connection to h2c: {}", e),
+        }
+    }
 }
 
 impl Drop for ServerCore {
@@ -367,18 +1268,154 @@ unsafe impl Sync for ServerCore {}
 
 pub type BoxHandler = Box<CloneableHandler>;
 
+/// A replica of the server's handlers owned by a completion queue.
+struct HandlerRegistry {
+    generation: usize,
+    handlers: HashMap<&'static [u8], BoxHandler>,
+    virtual_hosts: HashMap<String, HashMap<&'static [u8], BoxHandler>>,
+    fallback: Option<BoxHandler>,
+}
+
 #[derive(Clone)]
 pub struct RequestCallContext {
     server: Arc<ServerCore>,
-    registry: Arc<HashMap<&'static [u8], BoxHandler>>,
+    registry: Arc<UnsafeCell<HandlerRegistry>>,
+    slots: Arc<RequestSlots>,
 }
 
 impl RequestCallContext {
     /// Users should guarantee the method is always called from the same thread.
     /// TODO: Is there a better way?
     #[inline]
-    pub unsafe fn get_handler(&self, path: &[u8]) -> Option<&BoxHandler> {
-        self.registry.get(path)
+    pub unsafe fn get_handler(&self, host: &[u8], path: &[u8]) -> Option<&BoxHandler> {
+        let registry = &mut *self.registry.get();
+        if registry.generation != self.server.generation.load(Ordering::SeqCst) {
+            *registry = self.server.replicate_handlers();
+        }
+        let registry = &*registry;
+        if !registry.virtual_hosts.is_empty() {
+            let vhosts = &registry.virtual_hosts;
+            let vhost = str::from_utf8(host)
+                .ok()
+                .and_then(|h| vhosts.get(h).or_else(|| vhosts.get(strip_port(h))));
+            if let Some(h) = vhost.and_then(|handlers| handlers.get(path)) {
+                return Some(h);
+            }
+        }
+        match registry.handlers.get(path) {
+            None => registry.fallback.as_ref(),
+            h => h,
+        }
+    }
+
+    #[inline]
+    pub fn checksum(&self) -> Option<&Arc<Checksum>> {
+        self.server.checksum.as_ref()
+    }
+
+    #[inline]
+    pub fn access_log(&self) -> Option<&Arc<AccessLog>> {
+        self.server.access_log.as_ref()
+    }
+
+    #[inline]
+    pub fn request_id(&self) -> Option<&RequestIdConfig> {
+        self.server.request_id.as_ref()
+    }
+
+    #[inline]
+    pub fn message_hook(&self) -> Option<&Arc<MessageHook>> {
+        self.server.message_hook.as_ref()
+    }
+
+    #[inline]
+    pub fn panic_policy(&self) -> &PanicPolicy {
+        &self.server.panic_policy
+    }
+
+    #[inline]
+    pub fn watchdog(&self) -> Option<&Arc<WatchdogCore>> {
+        self.server.watchdog.as_ref()
+    }
+
+    #[inline]
+    pub fn flows(&self) -> Option<&FlowRegistry> {
+        self.server.flows.as_ref()
+    }
+
+    #[inline]
+    pub fn deprecated_calls(&self) -> Option<&DeprecatedCalls> {
+        self.server.deprecated_calls.as_ref()
+    }
+
+    #[inline]
+    pub fn authorizer(&self) -> Option<&Authorizer> {
+        self.server.authorizer.as_ref()
+    }
+
+    #[inline]
+    pub fn bandwidth(&self) -> Option<&BandwidthLimiter> {
+        self.server.bandwidth.as_ref()
+    }
+
+    #[inline]
+    pub fn stream_idle_timeout(&self) -> Option<Duration> {
+        self.server.stream_idle_timeout
+    }
+
+    #[inline]
+    pub fn call_metric_recording(&self) -> bool {
+        self.server.call_metric_recording
+    }
+
+    #[inline]
+    pub fn method_config(&self, path: &[u8]) -> Option<&Arc<MethodConfig>> {
+        if self.server.method_configs.is_empty() {
+            return None;
+        }
+        self.server.method_configs.get(path)
+    }
+
+    #[inline]
+    pub fn draining(&self) -> &Arc<AtomicBool> {
+        &self.server.draining
+    }
+
+    #[inline]
+    pub fn in_flight(&self) -> &Arc<AtomicUsize> {
+        &self.server.in_flight
+    }
+
+    /// Account for the slot taken by a new call, and post another one if
+    /// none is left and the limit allows.
+    pub fn take_slot(&self, cq: &CompletionQueue) {
+        let slots = &self.slots;
+        if slots.available.fetch_sub(1, Ordering::SeqCst) != 1 {
+            return;
+        }
+        slots.exhausted.fetch_add(1, Ordering::Relaxed);
+        let max = self.server.max_slots_per_cq;
+        let mut posted = slots.posted.load(Ordering::SeqCst);
+        while posted < max {
+            match slots.posted.compare_exchange(
+                posted,
+                posted + 1,
+                Ordering::SeqCst,
+                Ordering::SeqCst,
+            ) {
+                Ok(_) => {
+                    request_call(self.clone(), cq);
+                    return;
+                }
+                Err(p) => posted = p,
+            }
+        }
+    }
+
+    #[cfg(feature = "executor-bridge")]
+    #[inline]
+    pub fn executor(&self) -> Option<&ExternalExecutor> {
+        self.server.executor.as_ref()
     }
 }
 
@@ -386,6 +1423,45 @@ impl RequestCallContext {
 // to other thread. However it's not `Sync`, as `BoxHandler` is neccessary not `Sync`.
 unsafe impl Send for RequestCallContext {}
 
+#[derive(Default)]
+struct RequestSlots {
+    posted: AtomicUsize,
+    // Slots that wait for a new call.
+    available: AtomicUsize,
+    exhausted: AtomicUsize,
+}
+
+/// Statistics of the request slots of a completion queue, see
+/// [`ServerBuilder::requests_slot_per_cq`].
+///
+/// [`ServerBuilder::requests_slot_per_cq`]: struct.ServerBuilder.html#method.requests_slot_per_cq
+#[derive(Clone, Debug)]
+pub struct RequestSlotStats {
+    posted: usize,
+    available: usize,
+    exhausted: usize,
+}
+
+impl RequestSlotStats {
+    /// Count of slots posted to the completion queue, including the ones
+    /// added by [`ServerBuilder::max_requests_slot_per_cq`].
+    ///
+    /// [`ServerBuilder::max_requests_slot_per_cq`]: struct.ServerBuilder.html#method.max_requests_slot_per_cq
+    pub fn posted(&self) -> usize {
+        self.posted
+    }
+
+    /// Count of slots waiting for new calls.
+    pub fn available(&self) -> usize {
+        self.available
+    }
+
+    /// Times that all the slots were taken, so the next calls had to wait.
+    pub fn exhausted(&self) -> usize {
+        self.exhausted
+    }
+}
+
 /// Request notification of a new call.
 pub fn request_call(ctx: RequestCallContext, cq: &CompletionQueue) {
     if ctx.server.shutdown.load(Ordering::Relaxed) {
@@ -396,11 +1472,12 @@ pub fn request_call(ctx: RequestCallContext, cq: &CompletionQueue) {
         Err(_) => return,
         Ok(c) => c,
     };
+    // Counted before the slot is posted, as it may be taken right away.
+    ctx.slots.available.fetch_add(1, Ordering::SeqCst);
     let server_ptr = ctx.server.server;
     let prom = CallTag::request(ctx);
     let request_ptr = prom.request_ctx().unwrap().as_ptr();
-    let prom_box = Box::new(prom);
-    let tag = Box::into_raw(prom_box);
+    let tag = prom.into_raw();
     let code = unsafe {
         grpc_sys::grpcwrap_server_request_call(
             server_ptr,
@@ -410,7 +1487,9 @@ pub fn request_call(ctx: RequestCallContext, cq: &CompletionQueue) {
         )
     };
     if code != GrpcCallStatus::Ok {
-        Box::from(tag);
+        unsafe {
+            CallTag::from_raw(tag);
+        }
         panic!("failed to request call: {:?}", code);
     }
 }
@@ -438,15 +1517,28 @@ impl Future for ShutdownFuture {
 pub struct Server {
     env: Arc<Environment>,
     core: Arc<ServerCore>,
-    handlers: HashMap<&'static [u8], BoxHandler>,
+    start_hooks: Vec<ServerHook>,
+    shutdown_hooks: Vec<ServerHook>,
+    admin: Option<Box<Server>>,
 }
 
 impl Server {
     /// Shutdown the server asynchronously.
+    ///
+    /// The server stops listening and sends GOAWAY on every connection, the
+    /// calls in flight keep being served and the future resolves once they
+    /// are all finished. It can be called more than once, e.g. after
+    /// [`start_draining`](#method.start_draining), all the futures resolve
+    /// together.
+    ///
+    /// The hooks added by [`on_shutdown`](#method.on_shutdown) are run
+    /// before the server stops listening.
     pub fn shutdown(&mut self) -> ShutdownFuture {
+        while let Some(hook) = self.shutdown_hooks.pop() {
+            hook(&self.core.bind_addrs);
+        }
         let (cq_f, prom) = CallTag::shutdown_pair();
-        let prom_box = Box::new(prom);
-        let tag = Box::into_raw(prom_box);
+        let tag = prom.into_raw();
         unsafe {
             // Since env still exists, no way can cq been shutdown.
             let cq_ref = self.env.completion_queues()[0].borrow().unwrap();
@@ -460,6 +1552,101 @@ impl Server {
         ShutdownFuture { cq_f }
     }
 
+    /// Put the server in lame duck mode for a rolling restart.
+    ///
+    /// The server is marked draining, which a health service should report
+    /// as `NOT_SERVING` by checking [`RpcContext::is_server_draining`], and
+    /// is shut down, see [`shutdown`](#method.shutdown). Clients move their
+    /// new calls to other servers on GOAWAY, while the calls in flight,
+    /// including long-lived streams, are served to the end. The streams that
+    /// outlive the drain deadline can be ended by
+    /// [`cancel_all_calls`](#method.cancel_all_calls):
+    ///
+    /// ```ignore
+    /// let drained = server.start_draining();
+    /// thread::sleep(Duration::from_secs(30));
+    /// // The calls that are not finished yet are cancelled.
+    /// server.cancel_all_calls();
+    /// drained.wait().unwrap();
+    /// ```
+    ///
+    /// [`RpcContext::is_server_draining`]: struct.RpcContext.html#method.is_server_draining
+    pub fn start_draining(&mut self) -> ShutdownFuture {
+        self.core.draining.store(true, Ordering::SeqCst);
+        if let Some(ref admin) = self.admin {
+            admin.core.draining.store(true, Ordering::SeqCst);
+        }
+        self.shutdown()
+    }
+
+    /// Run `hook` with the bound addresses the first time the server is shut
+    /// down, before it stops listening, e.g. to deregister it from service
+    /// discovery. Hooks are run in the reverse order they are added, and a
+    /// server that is dropped without being shut down runs them too.
+    pub fn on_shutdown<F>(&mut self, hook: F)
+    where
+        F: FnOnce(&[(String, u16)]) + Send + 'static,
+    {
+        self.shutdown_hooks.push(Box::new(hook));
+    }
+
+    /// Check whether [`start_draining`](#method.start_draining) is called.
+    pub fn is_draining(&self) -> bool {
+        self.core.draining.load(Ordering::SeqCst)
+    }
+
+    /// Get the backpressure of the calls in progress, empty unless
+    /// [`ServerBuilder::flow_stats`] is enabled.
+    ///
+    /// [`ServerBuilder::flow_stats`]: struct.ServerBuilder.html#method.flow_stats
+    pub fn stream_flows(&self) -> Vec<StreamFlow> {
+        self.core
+            .flows
+            .as_ref()
+            .map_or_else(Vec::new, |f| f.snapshot())
+    }
+
+    /// Get the count of calls to each deprecated method that is called,
+    /// sorted by method name. It's empty unless
+    /// [`ServerBuilder::deprecation_stats`] is enabled.
+    ///
+    /// [`ServerBuilder::deprecation_stats`]: struct.ServerBuilder.html#method.deprecation_stats
+    pub fn deprecated_calls(&self) -> Vec<(String, usize)> {
+        self.core
+            .deprecated_calls
+            .as_ref()
+            .map_or_else(Vec::new, |d| d.snapshot())
+    }
+
+    /// Get the statistics of the request slots, in the same order as
+    /// [`Environment::completion_queues`].
+    ///
+    /// [`Environment::completion_queues`]: struct.Environment.html#method.completion_queues
+    pub fn request_slot_stats(&self) -> Vec<RequestSlotStats> {
+        self.core
+            .slots
+            .iter()
+            .map(|s| RequestSlotStats {
+                posted: s.posted.load(Ordering::SeqCst),
+                available: s.available.load(Ordering::SeqCst),
+                exhausted: s.exhausted.load(Ordering::Relaxed),
+            })
+            .collect()
+    }
+
+    /// Get the count of calls that are being handled.
+    pub fn in_flight_calls(&self) -> usize {
+        self.core.in_flight.load(Ordering::SeqCst)
+    }
+
+    /// Get the listening sockets passed by
+    /// [`ServerBuilder::bind_fd`](struct.ServerBuilder.html#method.bind_fd),
+    /// e.g. to hand them over by [`hot_restart`](hot_restart/index.html).
+    #[cfg(unix)]
+    pub fn listener_fds(&self) -> &[RawFd] {
+        &self.core.listeners
+    }
+
     /// Cancel all in-progress calls.
     ///
     /// Only usable after shutdown.
@@ -471,29 +1658,110 @@ impl Server {
     pub fn start(&mut self) {
         unsafe {
             grpc_sys::grpc_server_start(self.core.server);
-            for cq in self.env.completion_queues() {
-                // Handlers are Send and Clone, but not Sync. So we need to
-                // provide a replica for each completion queue.
-                let registry = self
-                    .handlers
-                    .iter()
-                    .map(|(k, v)| (k.to_owned(), v.box_clone()))
-                    .collect();
+            for (cq, slots) in self.env.completion_queues().iter().zip(&self.core.slots) {
                 let rc = RequestCallContext {
                     server: self.core.clone(),
-                    registry: Arc::new(registry),
+                    registry: Arc::new(UnsafeCell::new(self.core.replicate_handlers())),
+                    slots: slots.clone(),
                 };
+                slots.posted.store(self.core.slots_per_cq, Ordering::SeqCst);
                 for _ in 0..self.core.slots_per_cq {
                     request_call(rc.clone(), cq);
                 }
             }
         }
+        #[cfg(unix)]
+        for &fd in &self.core.listeners {
+            let core = Arc::downgrade(&self.core);
+            ThreadBuilder::new()
+                .name("grpc-accept".to_owned())
+                .spawn(move || accept_loop(&core, fd))
+                .unwrap();
+        }
+        if let Some(ref mut admin) = self.admin {
+            admin.start();
+        }
+        for hook in mem::replace(&mut self.start_hooks, vec![]) {
+            hook(&self.core.bind_addrs);
+        }
+    }
+
+    /// Register a service to the server.
+    ///
+    /// Unlike [`ServerBuilder::register_service`], this can be called after the server
+    /// is started; the handlers will be picked up by the following calls. A method that
+    /// is already registered will be replaced.
+    pub fn add_service(&self, service: Service) {
+        let mut handlers = self.core.handlers.lock().unwrap();
+        handlers.extend(service.handlers);
+        self.core.generation.fetch_add(1, Ordering::SeqCst);
+    }
+
+    /// Stop serving the method with the given full qualified name, e.g.
+    /// `/helloworld.Greeter/SayHello`.
+    ///
+    /// Following calls to the method are handled like those of any other unknown
+    /// method: they're passed to the [`ServerBuilder::fallback_handler`] if one is
+    /// set, and answered with `Unimplemented` otherwise. Returns `false` if the
+    /// method is not registered.
+    ///
+    /// [`ServerBuilder::fallback_handler`]: struct.ServerBuilder.html#method.fallback_handler
+    pub fn remove_method(&self, name: &str) -> bool {
+        let mut handlers = self.core.handlers.lock().unwrap();
+        if handlers.remove(name.as_bytes()).is_none() {
+            return false;
+        }
+        self.core.generation.fetch_add(1, Ordering::SeqCst);
+        true
+    }
+
+    /// Stop serving all the methods of the given service, see [`remove_method`].
+    ///
+    /// [`remove_method`]: #method.remove_method
+    pub fn remove_service(&self, service: &Service) {
+        let mut handlers = self.core.handlers.lock().unwrap();
+        for name in service.handlers.keys() {
+            handlers.remove(name);
+        }
+        self.core.generation.fetch_add(1, Ordering::SeqCst);
     }
 
     /// Get binded addresses.
+    ///
+    /// Every address passed to the builder is returned in the same order it was
+    /// bound, paired with the actual port it listens on.
     pub fn bind_addrs(&self) -> &[(String, u16)] {
         &self.core.bind_addrs
     }
+
+    /// Get the addresses of the admin services if they are served on a port
+    /// of their own, see [`admin`](admin/index.html).
+    pub fn admin_bind_addrs(&self) -> &[(String, u16)] {
+        self.admin.as_ref().map_or(&[], |a| a.bind_addrs())
+    }
+
+    /// Get the families of the sockets the server listens on, in the order
+    /// they are created.
+    ///
+    /// An address may be served by more than one socket, e.g. `[::]` is served
+    /// by an IPv6 and an IPv4 socket if dual stack is not available. It's
+    /// always empty on Windows, where gRPC core makes IPv6 sockets dual stack.
+    pub fn bound_families(&self) -> &[SocketFamily] {
+        &self.core.families
+    }
+
+    /// Serve the calls on a connected socket, e.g. one that is accepted by
+    /// the application itself.
+    ///
+    /// The server must be started, and it takes the ownership of `fd`.
+    ///
+    /// # Safety
+    ///
+    /// `fd` must be a connected stream socket that is not used elsewhere.
+    #[cfg(unix)]
+    pub unsafe fn add_insecure_channel_from_fd(&self, fd: RawFd) {
+        add_channel_from_fd(&self.core, fd)
+    }
 }
 
 impl Drop for Server {
@@ -0,0 +1,78 @@
+// Copyright 2018 PingCAP, Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Listening sockets passed by the service manager.
+//!
+//! With systemd socket activation, the sockets are opened by systemd and
+//! inherited by the service, which serves them with [`ServerBuilder::bind_fd`]:
+//!
+//! ```ignore
+//! let mut builder = ServerBuilder::new(env).register_service(service);
+//! for fd in socket_activation::listen_fds() {
+//!     builder = builder.bind_fd(fd);
+//! }
+//! ```
+//!
+//! Sockets kept open across `exec` can be handed over to a new binary the
+//! same way, without refusing any connection.
+//!
+//! [`ServerBuilder::bind_fd`]: ../struct.ServerBuilder.html#method.bind_fd
+
+use std::env;
+use std::os::unix::io::RawFd;
+use std::process;
+
+// The first fd passed by systemd, see sd_listen_fds(3).
+const LISTEN_FDS_START: RawFd = 3;
+
+/// Get the listening sockets passed by systemd, empty if there is none or
+/// they are passed to another process.
+///
+/// The environment variables are removed, so that child processes don't
+/// take them.
+pub fn listen_fds() -> Vec<RawFd> {
+    let pid = env::var("LISTEN_PID").ok();
+    let fds = env::var("LISTEN_FDS").ok();
+    env::remove_var("LISTEN_PID");
+    env::remove_var("LISTEN_FDS");
+    env::remove_var("LISTEN_FDNAMES");
+    parse(
+        pid.as_ref().map(|s| s.as_str()),
+        fds.as_ref().map(|s| s.as_str()),
+        process::id(),
+    )
+}
+
+fn parse(pid: Option<&str>, fds: Option<&str>, my_pid: u32) -> Vec<RawFd> {
+    match pid.and_then(|p| p.parse::<u32>().ok()) {
+        Some(pid) if pid == my_pid => {}
+        _ => return vec![],
+    }
+    let count = fds.and_then(|n| n.parse::<RawFd>().ok()).unwrap_or(0);
+    (LISTEN_FDS_START..LISTEN_FDS_START + count.max(0)).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        assert_eq!(parse(Some("7"), Some("2"), 7), vec![3, 4]);
+        assert!(parse(Some("8"), Some("2"), 7).is_empty());
+        assert!(parse(None, Some("2"), 7).is_empty());
+        assert!(parse(Some("7"), None, 7).is_empty());
+        assert!(parse(Some("7"), Some("-1"), 7).is_empty());
+        assert!(parse(Some("x"), Some("1"), 7).is_empty());
+    }
+}
@@ -0,0 +1,172 @@
+// Copyright 2018 PingCAP, Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Channels and servers over transports other than TCP and unix sockets.
+//!
+//! gRPC core only speaks to sockets, so a custom transport is bridged to one
+//! end of a socket pair whose other end is handed to gRPC core. Any byte
+//! stream that can be read and written at the same time will do, e.g. a QUIC
+//! stream, an SSH channel or a serial link:
+//!
+//! ```ignore
+//! // On the server side, once the stream is accepted.
+//! transport::serve_stream(&server, port.try_clone()?, port)?;
+//!
+//! // On the client side.
+//! let ch = transport::connect_stream(ChannelBuilder::new(env), "device", port.try_clone()?, port)?;
+//! ```
+//!
+//! The bytes are copied by two threads for every stream, which exit once
+//! either side is closed. [`in_memory`] connects a channel to a server in
+//! the same process without any copy.
+//!
+//! The channels can't reconnect, and the server must be started.
+//!
+//! [`in_memory`]: fn.in_memory.html
+
+use std::io::{self, Read, Write};
+use std::net::Shutdown;
+use std::os::unix::io::IntoRawFd;
+use std::os::unix::net::UnixStream;
+use std::thread::Builder as ThreadBuilder;
+
+use channel::{Channel, ChannelBuilder};
+use server::Server;
+
+const BUF_LEN: usize = 16 * 1024;
+
+/// Connect a channel built by `builder` to `server` with an in-memory pipe.
+///
+/// `target` is used as the authority of calls.
+pub fn in_memory(server: &Server, builder: ChannelBuilder, target: &str) -> io::Result<Channel> {
+    let (server_end, client_end) = UnixStream::pair()?;
+    unsafe {
+        server.add_insecure_channel_from_fd(server_end.into_raw_fd());
+        Ok(builder.connect_from_fd(target, client_end.into_raw_fd()))
+    }
+}
+
+/// Build a channel by `builder` over a byte stream, which is read from
+/// `reader` and written to `writer`.
+///
+/// `target` is used as the authority of calls.
+pub fn connect_stream<R, W>(
+    builder: ChannelBuilder,
+    target: &str,
+    reader: R,
+    writer: W,
+) -> io::Result<Channel>
+where
+    R: Read + Send + 'static,
+    W: Write + Send + 'static,
+{
+    let core_end = bridge(reader, writer)?;
+    unsafe { Ok(builder.connect_from_fd(target, core_end.into_raw_fd())) }
+}
+
+/// Serve the calls of `server` over a byte stream, which is read from
+/// `reader` and written to `writer`.
+pub fn serve_stream<R, W>(server: &Server, reader: R, writer: W) -> io::Result<()>
+where
+    R: Read + Send + 'static,
+    W: Write + Send + 'static,
+{
+    let core_end = bridge(reader, writer)?;
+    unsafe { server.add_insecure_channel_from_fd(core_end.into_raw_fd()) };
+    Ok(())
+}
+
+// Copy the bytes until `r` is closed. Every write is flushed, as gRPC core
+// waits for the response of what it has written.
+fn pump<R: Read, W: Write>(r: &mut R, w: &mut W) -> io::Result<()> {
+    let mut buf = vec![0; BUF_LEN];
+    loop {
+        let n = match r.read(&mut buf) {
+            Ok(0) => return Ok(()),
+            Ok(n) => n,
+            Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        };
+        w.write_all(&buf[..n])?;
+        w.flush()?;
+    }
+}
+
+// Returns the end of the socket pair that should be handed to gRPC core.
+fn bridge<R, W>(mut reader: R, mut writer: W) -> io::Result<UnixStream>
+where
+    R: Read + Send + 'static,
+    W: Write + Send + 'static,
+{
+    let (core_end, mut inbound) = UnixStream::pair()?;
+    let mut outbound = inbound.try_clone()?;
+    ThreadBuilder::new()
+        .name("grpc-transport-in".to_owned())
+        .spawn(move || {
+            if let Err(e) = pump(&mut reader, &mut inbound) {
+                debug!("failed to read from transport: {}", e);
+            }
+            // Let gRPC core see the end of the stream.
+            let _ = inbound.shutdown(Shutdown::Write);
+        })?;
+    ThreadBuilder::new()
+        .name("grpc-transport-out".to_owned())
+        .spawn(move || {
+            if let Err(e) = pump(&mut outbound, &mut writer) {
+                debug!("failed to write to transport: {}", e);
+            }
+            // `writer` is dropped here, which should close the stream.
+            let _ = outbound.shutdown(Shutdown::Read);
+        })?;
+    Ok(core_end)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use super::*;
+
+    struct Flushes(Vec<u8>, usize);
+
+    impl Write for Flushes {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            self.1 += 1;
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_pump() {
+        let data: Vec<u8> = (0..BUF_LEN * 2 + 1).map(|i| i as u8).collect();
+        let mut w = Flushes(vec![], 0);
+        pump(&mut Cursor::new(data.clone()), &mut w).unwrap();
+        assert_eq!(w.0, data);
+        assert_eq!(w.1, 3);
+
+        // Bytes written to the bridge come back out on the other side.
+        let (ours, theirs) = UnixStream::pair().unwrap();
+        let core_end = bridge(theirs.try_clone().unwrap(), theirs).unwrap();
+        (&core_end).write_all(b"ping").unwrap();
+        let mut buf = [0; 4];
+        (&ours).read_exact(&mut buf).unwrap();
+        assert_eq!(&buf, b"ping");
+        (&ours).write_all(b"pong").unwrap();
+        (&core_end).read_exact(&mut buf).unwrap();
+        assert_eq!(&buf, b"pong");
+    }
+}
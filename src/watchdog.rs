@@ -0,0 +1,261 @@
+// Copyright 2018 PingCAP, Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Detection of slow calls.
+//!
+//! When enabled by [`ServerBuilder::watchdog`], a background thread checks
+//! the calls in progress periodically, and reports every call that has not
+//! sent its status after the threshold, once. It helps to find handlers that
+//! are stuck or never complete their sinks.
+//!
+//! Futures don't keep a trace of where they are waiting, so the progress of
+//! a call is described by the count of messages received and sent instead.
+//!
+//! [`ServerBuilder::watchdog`]: ../struct.ServerBuilder.html#method.watchdog
+
+use std::sync::{Arc, Mutex, Weak};
+use std::thread::{self, Builder as ThreadBuilder};
+use std::time::Duration;
+
+use call::server::{CallTracker, RpcContext};
+
+/// A call that runs longer than the threshold.
+#[derive(Clone, Debug)]
+pub struct SlowCall {
+    method: String,
+    peer: String,
+    request_id: Option<String>,
+    elapsed: Duration,
+    request_messages: usize,
+    response_messages: usize,
+}
+
+impl SlowCall {
+    pub fn method(&self) -> &str {
+        &self.method
+    }
+
+    pub fn peer(&self) -> &str {
+        &self.peer
+    }
+
+    /// The ID of the request, if [`ServerBuilder::request_id`] is enabled.
+    ///
+    /// [`ServerBuilder::request_id`]: ../struct.ServerBuilder.html#method.request_id
+    pub fn request_id(&self) -> Option<&str> {
+        self.request_id.as_ref().map(|s| s.as_str())
+    }
+
+    /// Time since the call was accepted.
+    pub fn elapsed(&self) -> Duration {
+        self.elapsed
+    }
+
+    /// Count of the messages received from the client so far.
+    pub fn request_messages(&self) -> usize {
+        self.request_messages
+    }
+
+    /// Count of the messages sent to the client so far.
+    pub fn response_messages(&self) -> usize {
+        self.response_messages
+    }
+}
+
+/// Configuration of the slow call watchdog.
+pub struct Watchdog {
+    threshold: Duration,
+    interval: Duration,
+    sink: Box<Fn(&SlowCall) + Send + Sync>,
+}
+
+impl Watchdog {
+    /// Report calls running longer than `threshold` through the `log` crate
+    /// at warn level.
+    pub fn new(threshold: Duration) -> Watchdog {
+        Watchdog {
+            threshold,
+            interval: threshold / 2,
+            sink: Box::new(|call: &SlowCall| {
+                warn!(
+                    "slow call {} from {} has run for {:?}, {} messages received, {} sent",
+                    call.method,
+                    call.peer,
+                    call.elapsed,
+                    call.request_messages,
+                    call.response_messages
+                )
+            }),
+        }
+    }
+
+    /// Set how often the calls are checked, half the threshold by default.
+    pub fn interval(mut self, interval: Duration) -> Watchdog {
+        self.interval = interval;
+        self
+    }
+
+    /// Report slow calls to `f` instead of logging them.
+    ///
+    /// It's invoked on the watchdog thread.
+    pub fn on_slow<F>(mut self, f: F) -> Watchdog
+    where
+        F: Fn(&SlowCall) + Send + Sync + 'static,
+    {
+        self.sink = Box::new(f);
+        self
+    }
+}
+
+struct Entry {
+    method: String,
+    peer: String,
+    request_id: Option<String>,
+    tracker: Weak<CallTracker>,
+}
+
+pub(crate) struct WatchdogCore {
+    config: Watchdog,
+    calls: Mutex<Vec<Entry>>,
+}
+
+impl WatchdogCore {
+    /// Watch the call until its status is sent or it's dropped.
+    pub fn attach(&self, ctx: &RpcContext) {
+        self.register(
+            String::from_utf8_lossy(ctx.method()).into_owned(),
+            ctx.peer().to_string(),
+            ctx.request_id().map(|s| s.to_owned()),
+            ctx.tracker(),
+        )
+    }
+
+    fn register(
+        &self,
+        method: String,
+        peer: String,
+        request_id: Option<String>,
+        tracker: &Arc<CallTracker>,
+    ) {
+        self.calls.lock().unwrap().push(Entry {
+            method,
+            peer,
+            request_id,
+            tracker: Arc::downgrade(tracker),
+        });
+    }
+
+    /// Report the slow calls, and stop watching them as well as the
+    /// finished ones.
+    fn check(&self) {
+        let mut slow = vec![];
+        {
+            let mut calls = self.calls.lock().unwrap();
+            let mut i = 0;
+            while i < calls.len() {
+                let tracker = match calls[i].tracker.upgrade() {
+                    Some(ref t) if !t.is_complete() => t.clone(),
+                    _ => {
+                        calls.swap_remove(i);
+                        continue;
+                    }
+                };
+                let elapsed = tracker.elapsed();
+                if elapsed < self.config.threshold {
+                    i += 1;
+                    continue;
+                }
+                let e = calls.swap_remove(i);
+                let (request_messages, response_messages) = tracker.progress();
+                slow.push(SlowCall {
+                    method: e.method,
+                    peer: e.peer,
+                    request_id: e.request_id,
+                    elapsed,
+                    request_messages,
+                    response_messages,
+                });
+            }
+        }
+        for call in &slow {
+            (self.config.sink)(call);
+        }
+    }
+}
+
+/// Start a thread that checks the calls until the returned core is dropped.
+pub(crate) fn start(config: Watchdog) -> Arc<WatchdogCore> {
+    let interval = config.interval;
+    let core = Arc::new(WatchdogCore {
+        config,
+        calls: Mutex::new(vec![]),
+    });
+    let weak = Arc::downgrade(&core);
+    ThreadBuilder::new()
+        .name("grpc-watchdog".to_owned())
+        .spawn(move || loop {
+            thread::sleep(interval);
+            match weak.upgrade() {
+                Some(core) => core.check(),
+                None => return,
+            }
+        })
+        .unwrap();
+    core
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Instant;
+
+    use super::*;
+    use call::RpcStatusCode;
+
+    #[test]
+    fn test_check() {
+        let reported = Arc::new(Mutex::new(vec![]));
+        let reported_ = reported.clone();
+        let config = Watchdog::new(Duration::from_secs(1))
+            .on_slow(move |c| reported_.lock().unwrap().push(c.method().to_owned()));
+        let core = WatchdogCore {
+            config,
+            calls: Mutex::new(vec![]),
+        };
+        let long_ago = Instant::now() - Duration::from_secs(10);
+        let slow = Arc::new(CallTracker::new(long_ago, String::new()));
+        slow.on_received(3);
+        let finished = Arc::new(CallTracker::new(long_ago, String::new()));
+        finished.on_complete(RpcStatusCode::Ok);
+        let fast = Arc::new(CallTracker::new(Instant::now(), String::new()));
+        for (m, t) in &[
+            ("/a/Slow", &slow),
+            ("/a/Finished", &finished),
+            ("/a/Fast", &fast),
+        ] {
+            core.register(m.to_string(), "peer".to_owned(), None, t);
+        }
+        core.register(
+            "/a/Dropped".to_owned(),
+            "peer".to_owned(),
+            None,
+            &Arc::new(CallTracker::new(long_ago, String::new())),
+        );
+
+        core.check();
+        assert_eq!(*reported.lock().unwrap(), vec!["/a/Slow".to_owned()]);
+        assert_eq!(core.calls.lock().unwrap().len(), 1);
+        // Slow calls are only reported once.
+        core.check();
+        assert_eq!(reported.lock().unwrap().len(), 1);
+    }
+}
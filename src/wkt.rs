@@ -0,0 +1,226 @@
+// Copyright 2018 PingCAP, Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Conversions between the well known types of protobuf and std types.
+//!
+//! Both sides are foreign to the generated code, so `From` can't be
+//! implemented there. [`IntoProto`] and [`FromProto`] are provided instead:
+//!
+//! ```
+//! # extern crate grpcio;
+//! # extern crate protobuf;
+//! use std::time::SystemTime;
+//!
+//! use grpcio::wkt::{FromProto, IntoProto};
+//! use protobuf::well_known_types::Timestamp;
+//!
+//! # fn main() {
+//! let now = SystemTime::now();
+//! let ts: Timestamp = now.into_proto();
+//! assert_eq!(SystemTime::from_proto(&ts).unwrap(), now);
+//! # }
+//! ```
+//!
+//! [`IntoProto`]: trait.IntoProto.html
+//! [`FromProto`]: trait.FromProto.html
+
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use protobuf::well_known_types::{self as wkt, Empty, Timestamp};
+
+use error::{Error, Result};
+
+const NANOS_PER_SEC: i64 = 1_000_000_000;
+
+/// Convert a std type into a well known type.
+pub trait IntoProto<T> {
+    fn into_proto(self) -> T;
+}
+
+/// Convert a well known type into a std type.
+///
+/// Values that can't be represented, e.g. a negative duration, are reported
+/// as `Error::Codec`.
+pub trait FromProto<T>: Sized {
+    fn from_proto(t: &T) -> Result<Self>;
+}
+
+fn invalid(msg: String) -> Error {
+    Error::Codec(msg.into())
+}
+
+impl IntoProto<Timestamp> for SystemTime {
+    fn into_proto(self) -> Timestamp {
+        let (seconds, nanos) = match self.duration_since(UNIX_EPOCH) {
+            Ok(d) => (d.as_secs() as i64, i64::from(d.subsec_nanos())),
+            Err(e) => {
+                // Nanos are never negative in a timestamp.
+                let d = e.duration();
+                let nanos = i64::from(d.subsec_nanos());
+                if nanos == 0 {
+                    (-(d.as_secs() as i64), 0)
+                } else {
+                    (-(d.as_secs() as i64) - 1, NANOS_PER_SEC - nanos)
+                }
+            }
+        };
+        let mut ts = Timestamp::new();
+        ts.set_seconds(seconds);
+        ts.set_nanos(nanos as i32);
+        ts
+    }
+}
+
+impl FromProto<Timestamp> for SystemTime {
+    fn from_proto(ts: &Timestamp) -> Result<SystemTime> {
+        let (seconds, nanos) = (ts.get_seconds(), ts.get_nanos());
+        if nanos < 0 || i64::from(nanos) >= NANOS_PER_SEC {
+            return Err(invalid(format!("invalid nanos of timestamp: {}", nanos)));
+        }
+        let t = if seconds >= 0 {
+            UNIX_EPOCH.checked_add(Duration::new(seconds as u64, nanos as u32))
+        } else {
+            UNIX_EPOCH
+                .checked_sub(Duration::from_secs(seconds.wrapping_neg() as u64))
+                .and_then(|t| t.checked_add(Duration::new(0, nanos as u32)))
+        };
+        t.ok_or_else(|| invalid(format!("timestamp out of range: {:?}", ts)))
+    }
+}
+
+impl IntoProto<wkt::Duration> for Duration {
+    /// Durations longer than `i64::max_value()` seconds are saturated.
+    fn into_proto(self) -> wkt::Duration {
+        let mut d = wkt::Duration::new();
+        if self.as_secs() > i64::max_value() as u64 {
+            d.set_seconds(i64::max_value());
+            d.set_nanos((NANOS_PER_SEC - 1) as i32);
+        } else {
+            d.set_seconds(self.as_secs() as i64);
+            d.set_nanos(self.subsec_nanos() as i32);
+        }
+        d
+    }
+}
+
+impl FromProto<wkt::Duration> for Duration {
+    fn from_proto(d: &wkt::Duration) -> Result<Duration> {
+        let (seconds, nanos) = (d.get_seconds(), d.get_nanos());
+        if seconds < 0 || nanos < 0 {
+            return Err(invalid(format!("negative duration: {:?}", d)));
+        }
+        if i64::from(nanos) >= NANOS_PER_SEC {
+            return Err(invalid(format!("invalid nanos of duration: {}", nanos)));
+        }
+        Ok(Duration::new(seconds as u64, nanos as u32))
+    }
+}
+
+impl IntoProto<Empty> for () {
+    fn into_proto(self) -> Empty {
+        Empty::new()
+    }
+}
+
+impl FromProto<Empty> for () {
+    fn from_proto(_: &Empty) -> Result<()> {
+        Ok(())
+    }
+}
+
+macro_rules! impl_wrapper {
+    ($wrapper:ident, $t:ty, $get:ident) => {
+        impl IntoProto<wkt::$wrapper> for $t {
+            fn into_proto(self) -> wkt::$wrapper {
+                let mut w = wkt::$wrapper::new();
+                w.set_value(self);
+                w
+            }
+        }
+
+        impl FromProto<wkt::$wrapper> for $t {
+            fn from_proto(w: &wkt::$wrapper) -> Result<$t> {
+                Ok(w.$get().to_owned())
+            }
+        }
+
+        impl IntoProto<wkt::$wrapper> for Option<$t> {
+            /// `None` is converted to the default value.
+            fn into_proto(self) -> wkt::$wrapper {
+                self.map_or_else(wkt::$wrapper::new, IntoProto::into_proto)
+            }
+        }
+    };
+}
+
+impl_wrapper!(BoolValue, bool, get_value);
+impl_wrapper!(Int32Value, i32, get_value);
+impl_wrapper!(Int64Value, i64, get_value);
+impl_wrapper!(UInt32Value, u32, get_value);
+impl_wrapper!(UInt64Value, u64, get_value);
+impl_wrapper!(FloatValue, f32, get_value);
+impl_wrapper!(DoubleValue, f64, get_value);
+impl_wrapper!(StringValue, String, get_value);
+impl_wrapper!(BytesValue, Vec<u8>, get_value);
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_timestamp() {
+        for t in &[
+            UNIX_EPOCH,
+            UNIX_EPOCH + Duration::new(1_539_500_000, 123),
+            UNIX_EPOCH - Duration::new(10, 0),
+            UNIX_EPOCH - Duration::new(10, 250_000_000),
+        ] {
+            let ts: Timestamp = (*t).into_proto();
+            assert!(ts.get_nanos() >= 0);
+            assert_eq!(SystemTime::from_proto(&ts).unwrap(), *t);
+        }
+        let ts: Timestamp = (UNIX_EPOCH - Duration::new(10, 250_000_000)).into_proto();
+        assert_eq!((ts.get_seconds(), ts.get_nanos()), (-11, 750_000_000));
+
+        let mut ts = Timestamp::new();
+        ts.set_nanos(NANOS_PER_SEC as i32);
+        assert!(SystemTime::from_proto(&ts).is_err());
+    }
+
+    #[test]
+    fn test_duration() {
+        let d = Duration::new(3, 500);
+        let pd: wkt::Duration = d.into_proto();
+        assert_eq!((pd.get_seconds(), pd.get_nanos()), (3, 500));
+        assert_eq!(Duration::from_proto(&pd).unwrap(), d);
+
+        let mut pd = wkt::Duration::new();
+        pd.set_seconds(-1);
+        assert!(Duration::from_proto(&pd).is_err());
+
+        let pd: wkt::Duration = Duration::new(u64::max_value(), 0).into_proto();
+        assert_eq!(pd.get_seconds(), i64::max_value());
+    }
+
+    #[test]
+    fn test_wrappers() {
+        let w: wkt::StringValue = "hello".to_owned().into_proto();
+        assert_eq!(String::from_proto(&w).unwrap(), "hello");
+        let w: wkt::Int64Value = Some(-3i64).into_proto();
+        assert_eq!(i64::from_proto(&w).unwrap(), -3);
+        let w: wkt::BoolValue = None.into_proto();
+        assert!(!bool::from_proto(&w).unwrap());
+        let e: Empty = ().into_proto();
+        <()>::from_proto(&e).unwrap();
+    }
+}
@@ -0,0 +1,590 @@
+// Copyright 2018 PingCAP, Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Weighted round robin by the load of backends.
+//!
+//! gRPC core 1.7 has no `weighted_round_robin` policy, and its policies
+//! can't be extended, so there is no [`LbPolicy`] for it. Instead,
+//! [`WeightedRoundRobin`] spreads the calls over one channel per backend,
+//! weighted by the [`orca`] load reports the backends send in the trailers:
+//!
+//! ```ignore
+//! let backends = addrs
+//!     .iter()
+//!     .map(|a| (a.clone(), ChannelBuilder::new(env.clone()).connect(a)))
+//!     .collect();
+//! let lb = WeightedRoundRobin::new(backends, WrrConfig::new());
+//! let reply = lb.unary_call(&METHOD_SAY_HELLO, &req, CallOption::default())?;
+//! ```
+//!
+//! The weight of a backend is
+//! `qps / (utilization + eps / qps * error_utilization_penalty)`, where the
+//! utilization is the application utilization if it's reported, otherwise
+//! the CPU utilization. So hot backends get less traffic, and so do the ones
+//! that fail a lot. Reports without qps or utilization are ignored.
+//!
+//! A backend's weight is only used once it has been reported for the
+//! blackout period, so a backend that just started doesn't get flooded
+//! before its load settles, and it's forgotten if it's not reported for the
+//! expiration period. Backends without a weight get the mean weight of the
+//! others, and calls are spread evenly until at least two backends have
+//! weights.
+//!
+//! Unary calls made by [`WeightedRoundRobin::unary_call_async`] feed their
+//! trailers back automatically. Other calls can be sent through
+//! [`WeightedRoundRobin::pick`], and their trailers are fed back by
+//! [`Pick::report`].
+//!
+//! Failing backends can be ejected by [`WrrConfig::outlier_detection`], so
+//! it also serves clients of static addresses without load reports, which
+//! are balanced evenly.
+//!
+//! [`LbPolicy`]: ../enum.LbPolicy.html
+//! [`orca`]: ../orca/index.html
+//! [`WeightedRoundRobin`]: struct.WeightedRoundRobin.html
+//! [`WeightedRoundRobin::unary_call_async`]: struct.WeightedRoundRobin.html#method.unary_call_async
+//! [`WeightedRoundRobin::pick`]: struct.WeightedRoundRobin.html#method.pick
+//! [`Pick::report`]: struct.Pick.html#method.report
+//! [`WrrConfig::outlier_detection`]: struct.WrrConfig.html#method.outlier_detection
+
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use futures::{Async, Future, Poll};
+
+use call::client::{CallOption, ClientUnaryReceiver};
+use call::Method;
+use channel::Channel;
+use client::Client;
+use error::{Error, Result};
+use metadata::Metadata;
+use orca::LoadReport;
+use outlier::{Detector, OutlierDetection};
+
+/// Configuration of [`WeightedRoundRobin`].
+///
+/// [`WeightedRoundRobin`]: struct.WeightedRoundRobin.html
+#[derive(Clone, Debug)]
+pub struct WrrConfig {
+    blackout_period: Duration,
+    weight_expiration_period: Duration,
+    weight_update_period: Duration,
+    error_utilization_penalty: f64,
+    outlier_detection: Option<OutlierDetection>,
+}
+
+impl Default for WrrConfig {
+    fn default() -> WrrConfig {
+        WrrConfig {
+            blackout_period: Duration::from_secs(10),
+            weight_expiration_period: Duration::from_secs(180),
+            weight_update_period: Duration::from_secs(1),
+            error_utilization_penalty: 1.0,
+            outlier_detection: None,
+        }
+    }
+}
+
+impl WrrConfig {
+    pub fn new() -> WrrConfig {
+        WrrConfig::default()
+    }
+
+    /// Set how long a backend needs to be reported before its weight is
+    /// used, 10 seconds by default.
+    pub fn blackout_period(mut self, period: Duration) -> WrrConfig {
+        self.blackout_period = period;
+        self
+    }
+
+    /// Set how long the weight of a backend is kept without a new report,
+    /// 3 minutes by default.
+    pub fn weight_expiration_period(mut self, period: Duration) -> WrrConfig {
+        self.weight_expiration_period = period;
+        self
+    }
+
+    /// Set how often the weights are applied to the picks, 1 second by
+    /// default.
+    pub fn weight_update_period(mut self, period: Duration) -> WrrConfig {
+        self.weight_update_period = period;
+        self
+    }
+
+    /// Set how much the errors per second weigh against the utilization, 1
+    /// by default. 0 ignores the errors.
+    pub fn error_utilization_penalty(mut self, penalty: f64) -> WrrConfig {
+        self.error_utilization_penalty = penalty;
+        self
+    }
+
+    /// Eject the failing backends, see [`outlier`](../outlier/index.html)
+    /// for details.
+    pub fn outlier_detection(mut self, detection: OutlierDetection) -> WrrConfig {
+        self.outlier_detection = Some(detection);
+        self
+    }
+}
+
+fn since(now: Instant, t: Instant) -> Duration {
+    if now > t {
+        now - t
+    } else {
+        Duration::from_secs(0)
+    }
+}
+
+#[derive(Default)]
+struct Weight {
+    value: f64,
+    non_empty_since: Option<Instant>,
+    last_update: Option<Instant>,
+}
+
+impl Weight {
+    fn update(&mut self, report: &LoadReport, config: &WrrConfig, now: Instant) {
+        let utilization = if report.application_utilization() > 0.0 {
+            report.application_utilization()
+        } else {
+            report.cpu_utilization()
+        };
+        let qps = report.qps();
+        if qps <= 0.0 || utilization <= 0.0 {
+            return;
+        }
+        let penalty = report.eps() / qps * config.error_utilization_penalty;
+        self.value = qps / (utilization + penalty);
+        if self.non_empty_since.is_none() {
+            self.non_empty_since = Some(now);
+        }
+        self.last_update = Some(now);
+    }
+
+    /// Get the weight, 0 if it's not known.
+    fn get(&mut self, config: &WrrConfig, now: Instant) -> f64 {
+        let (since_start, since_update) = match (self.non_empty_since, self.last_update) {
+            (Some(s), Some(u)) => (since(now, s), since(now, u)),
+            _ => return 0.0,
+        };
+        if since_update >= config.weight_expiration_period {
+            // The blackout starts over once it's reported again.
+            self.non_empty_since = None;
+            return 0.0;
+        }
+        if since_start < config.blackout_period {
+            return 0.0;
+        }
+        self.value
+    }
+}
+
+/// Smooth weighted round robin, which interleaves the picks of the backends
+/// instead of sending bursts to the heavy ones.
+#[derive(Default)]
+struct Scheduler {
+    weights: Vec<f64>,
+    current: Vec<f64>,
+    updated: Option<Instant>,
+}
+
+impl Scheduler {
+    /// Apply `weights` to the picks, backends with `None` are ejected.
+    fn set_weights(&mut self, weights: Vec<Option<f64>>, now: Instant) {
+        let ejected: Vec<bool> = weights.iter().map(|w| w.is_none()).collect();
+        // Ejection is ignored if all the backends are ejected.
+        let ejected = if ejected.iter().all(|e| *e) {
+            vec![false; weights.len()]
+        } else {
+            ejected
+        };
+        let mut weights =
+            Scheduler::balance(weights.into_iter().map(|w| w.unwrap_or(0.0)).collect());
+        for (w, e) in weights.iter_mut().zip(&ejected) {
+            if *e {
+                *w = 0.0;
+            }
+        }
+        if self.current.len() != weights.len() {
+            self.current = vec![0.0; weights.len()];
+        }
+        for (c, w) in self.current.iter_mut().zip(&weights) {
+            if *w == 0.0 {
+                *c = 0.0;
+            }
+        }
+        self.weights = weights;
+        self.updated = Some(now);
+    }
+
+    /// Fill in the unknown weights.
+    fn balance(mut weights: Vec<f64>) -> Vec<f64> {
+        let known: Vec<f64> = weights.iter().cloned().filter(|w| *w > 0.0).collect();
+        if known.len() < 2 {
+            weights.iter_mut().for_each(|w| *w = 1.0);
+        } else {
+            let mean = known.iter().sum::<f64>() / known.len() as f64;
+            weights
+                .iter_mut()
+                .filter(|w| **w <= 0.0)
+                .for_each(|w| *w = mean);
+        }
+        weights
+    }
+
+    fn is_stale(&self, config: &WrrConfig, now: Instant) -> bool {
+        match self.updated {
+            Some(t) => since(now, t) >= config.weight_update_period,
+            None => true,
+        }
+    }
+
+    fn pick(&mut self) -> usize {
+        let total: f64 = self.weights.iter().sum();
+        for (c, w) in self.current.iter_mut().zip(&self.weights) {
+            *c += *w;
+        }
+        let mut best = None;
+        for (i, (c, w)) in self.current.iter().zip(&self.weights).enumerate() {
+            let better = match best {
+                Some(b) => *c > self.current[b],
+                None => true,
+            };
+            // Ejected backends are skipped.
+            if *w > 0.0 && better {
+                best = Some(i);
+            }
+        }
+        let best = best.unwrap();
+        self.current[best] -= total;
+        best
+    }
+}
+
+struct Backend {
+    addr: String,
+    client: Client,
+    weight: Mutex<Weight>,
+}
+
+struct Inner {
+    config: WrrConfig,
+    backends: Vec<Backend>,
+    scheduler: Mutex<Scheduler>,
+    detector: Option<Mutex<Detector>>,
+}
+
+impl Inner {
+    fn weights_at(&self, now: Instant) -> Vec<f64> {
+        self.backends
+            .iter()
+            .map(|b| b.weight.lock().unwrap().get(&self.config, now))
+            .collect()
+    }
+
+    /// Get the weights of the backends, `None` for the ejected ones.
+    fn pick_weights_at(&self, now: Instant) -> Vec<Option<f64>> {
+        let weights = self.weights_at(now);
+        let detector = match self.detector {
+            Some(ref d) => d.lock().unwrap(),
+            None => return weights.into_iter().map(Some).collect(),
+        };
+        weights
+            .into_iter()
+            .enumerate()
+            .map(|(i, w)| {
+                if detector.is_ejected(i) {
+                    None
+                } else {
+                    Some(w)
+                }
+            })
+            .collect()
+    }
+
+    /// Check the outliers, returns whether the picks need to be updated.
+    fn sweep(&self, now: Instant) -> bool {
+        match self.detector {
+            Some(ref d) => d.lock().unwrap().sweep(now),
+            None => false,
+        }
+    }
+}
+
+/// Balances the calls over backends by their load, see
+/// [`wrr`](index.html) for details.
+#[derive(Clone)]
+pub struct WeightedRoundRobin {
+    inner: Arc<Inner>,
+}
+
+impl WeightedRoundRobin {
+    /// Balance over `backends`, which are addresses and the channels
+    /// connected to them.
+    ///
+    /// # Panics
+    ///
+    /// If `backends` is empty.
+    pub fn new(backends: Vec<(String, Channel)>, config: WrrConfig) -> WeightedRoundRobin {
+        assert!(!backends.is_empty(), "no backend to balance over");
+        let detector = config
+            .outlier_detection
+            .clone()
+            .map(|d| Mutex::new(Detector::new(d, backends.len(), Instant::now())));
+        let backends = backends
+            .into_iter()
+            .map(|(addr, ch)| Backend {
+                addr,
+                client: Client::new(ch),
+                weight: Mutex::default(),
+            })
+            .collect();
+        WeightedRoundRobin {
+            inner: Arc::new(Inner {
+                config,
+                backends,
+                scheduler: Mutex::default(),
+                detector,
+            }),
+        }
+    }
+
+    /// Get the weights of the backends, 0 for the ones without a weight.
+    pub fn weights(&self) -> Vec<(String, f64)> {
+        let weights = self.inner.weights_at(Instant::now());
+        self.inner
+            .backends
+            .iter()
+            .zip(weights)
+            .map(|(b, w)| (b.addr.clone(), w))
+            .collect()
+    }
+
+    /// Get the addresses of the backends ejected by outlier detection.
+    pub fn ejected(&self) -> Vec<String> {
+        let detector = match self.inner.detector {
+            Some(ref d) => d.lock().unwrap(),
+            None => return vec![],
+        };
+        (0..self.inner.backends.len())
+            .filter(|i| detector.is_ejected(*i))
+            .map(|i| self.inner.backends[i].addr.clone())
+            .collect()
+    }
+
+    /// Pick the backend for the next call.
+    pub fn pick(&self) -> Pick {
+        let now = Instant::now();
+        let swept = self.inner.sweep(now);
+        let mut scheduler = self.inner.scheduler.lock().unwrap();
+        if swept || scheduler.is_stale(&self.inner.config, now) {
+            scheduler.set_weights(self.inner.pick_weights_at(now), now);
+        }
+        Pick {
+            inner: self.inner.clone(),
+            index: scheduler.pick(),
+        }
+    }
+
+    /// Create a synchronized unary RPC call on the picked backend.
+    pub fn unary_call<Req: 'static, Resp: 'static>(
+        &self,
+        method: &Method<Req, Resp>,
+        req: &Req,
+        opt: CallOption,
+    ) -> Result<Resp> {
+        self.unary_call_async(method, req, opt)?.wait()
+    }
+
+    /// Create an asynchronized unary RPC call on the picked backend, whose
+    /// load report is taken from the trailers and whose result is recorded
+    /// once it's finished.
+    pub fn unary_call_async<Req: 'static, Resp: 'static>(
+        &self,
+        method: &Method<Req, Resp>,
+        req: &Req,
+        opt: CallOption,
+    ) -> Result<WrrUnaryReceiver<Resp>> {
+        let pick = self.pick();
+        let recv = pick.client().unary_call_async(method, req, opt)?;
+        Ok(WrrUnaryReceiver { recv, pick })
+    }
+}
+
+/// A backend picked by [`WeightedRoundRobin::pick`].
+///
+/// [`WeightedRoundRobin::pick`]: struct.WeightedRoundRobin.html#method.pick
+pub struct Pick {
+    inner: Arc<Inner>,
+    index: usize,
+}
+
+impl Pick {
+    pub fn addr(&self) -> &str {
+        &self.inner.backends[self.index].addr
+    }
+
+    /// Get the client to send the call by.
+    pub fn client(&self) -> &Client {
+        &self.inner.backends[self.index].client
+    }
+
+    /// Update the weight of the backend by the load report in `trailers`,
+    /// it does nothing if there is none.
+    pub fn report(&self, trailers: &Metadata) {
+        if let Some(report) = LoadReport::from_trailers(trailers) {
+            let backend = &self.inner.backends[self.index];
+            let mut weight = backend.weight.lock().unwrap();
+            weight.update(&report, &self.inner.config, Instant::now());
+        }
+    }
+
+    /// Record whether the call succeeded for outlier detection, it does
+    /// nothing if outlier detection is not enabled.
+    pub fn record(&self, success: bool) {
+        let detector = match self.inner.detector {
+            Some(ref d) => d,
+            None => return,
+        };
+        let ejected = detector
+            .lock()
+            .unwrap()
+            .record(self.index, success, Instant::now());
+        if ejected {
+            // Stop sending calls to it right away.
+            self.inner.scheduler.lock().unwrap().updated = None;
+        }
+    }
+}
+
+/// The response of a call made by [`WeightedRoundRobin`].
+///
+/// [`WeightedRoundRobin`]: struct.WeightedRoundRobin.html
+pub struct WrrUnaryReceiver<Resp> {
+    recv: ClientUnaryReceiver<Resp>,
+    pick: Pick,
+}
+
+impl<Resp> WrrUnaryReceiver<Resp> {
+    /// Get the backend the call is sent to.
+    pub fn pick(&self) -> &Pick {
+        &self.pick
+    }
+
+    pub fn cancel(&mut self) {
+        self.recv.cancel()
+    }
+}
+
+impl<Resp> Future for WrrUnaryReceiver<Resp> {
+    type Item = Resp;
+    type Error = Error;
+
+    fn poll(&mut self) -> Poll<Resp, Error> {
+        let res = match self.recv.poll() {
+            Ok(Async::NotReady) => return Ok(Async::NotReady),
+            res => res,
+        };
+        // Failed calls carry load reports too.
+        if let Some(trailers) = self.recv.take_trailers() {
+            self.pick.report(&trailers);
+        }
+        self.pick.record(res.is_ok());
+        res
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn report(cpu: f64, qps: f64, eps: f64) -> LoadReport {
+        let mut r = LoadReport::new();
+        r.set_cpu_utilization(cpu);
+        r.set_qps(qps);
+        r.set_eps(eps);
+        r
+    }
+
+    #[test]
+    fn test_weight() {
+        let config = WrrConfig::new()
+            .blackout_period(Duration::from_secs(10))
+            .weight_expiration_period(Duration::from_secs(60));
+        let start = Instant::now();
+        let at = |secs| start + Duration::from_secs(secs);
+        let mut w = Weight::default();
+        assert_eq!(w.get(&config, start), 0.0);
+        // Reports without load are ignored.
+        w.update(&report(0.0, 100.0, 0.0), &config, start);
+        assert_eq!(w.get(&config, at(20)), 0.0);
+
+        w.update(&report(0.5, 100.0, 0.0), &config, start);
+        assert_eq!(w.get(&config, at(5)), 0.0);
+        assert_eq!(w.get(&config, at(10)), 200.0);
+        // Errors make it lighter.
+        w.update(&report(0.5, 100.0, 50.0), &config, at(10));
+        assert_eq!(w.get(&config, at(11)), 100.0);
+        let mut r = report(0.5, 100.0, 0.0);
+        r.set_application_utilization(0.25);
+        w.update(&r, &config, at(12));
+        assert_eq!(w.get(&config, at(13)), 400.0);
+
+        // Expired weights go through the blackout again.
+        assert_eq!(w.get(&config, at(72)), 0.0);
+        w.update(&report(0.5, 100.0, 0.0), &config, at(80));
+        assert_eq!(w.get(&config, at(85)), 0.0);
+        assert_eq!(w.get(&config, at(90)), 200.0);
+    }
+
+    #[test]
+    fn test_scheduler() {
+        let now = Instant::now();
+        let picks = |s: &mut Scheduler, n| {
+            let mut counts = vec![0; s.weights.len()];
+            for _ in 0..n {
+                counts[s.pick()] += 1;
+            }
+            counts
+        };
+        let mut s = Scheduler::default();
+        // Evenly until two backends have weights.
+        s.set_weights(vec![Some(0.0), Some(30.0), Some(0.0)], now);
+        assert_eq!(picks(&mut s, 30), vec![10, 10, 10]);
+
+        // A simulated reporter: the hot backend handles the same qps at
+        // three times the utilization of the others.
+        s.set_weights(
+            vec![Some(100.0 / 0.9), Some(100.0 / 0.3), Some(100.0 / 0.3)],
+            now,
+        );
+        assert_eq!(picks(&mut s, 70), vec![10, 30, 30]);
+        // Backends without weights get the mean.
+        s.set_weights(vec![Some(10.0), Some(0.0), Some(30.0)], now);
+        assert_eq!(picks(&mut s, 60), vec![10, 20, 30]);
+        // Picks are interleaved.
+        let mut s = Scheduler::default();
+        s.set_weights(vec![Some(1.0), Some(1.0), Some(2.0)], now);
+        let order: Vec<_> = (0..4).map(|_| s.pick()).collect();
+        assert_eq!(order, vec![2, 0, 1, 2]);
+
+        // Ejected backends get no picks, unless all of them are ejected.
+        s.set_weights(vec![Some(1.0), None, Some(1.0)], now);
+        assert_eq!(picks(&mut s, 4), vec![2, 0, 2]);
+        s.set_weights(vec![None, None], now);
+        assert_eq!(picks(&mut s, 4), vec![2, 2]);
+
+        let config = WrrConfig::new().weight_update_period(Duration::from_secs(1));
+        assert!(!s.is_stale(&config, now));
+        assert!(s.is_stale(&config, now + Duration::from_secs(1)));
+    }
+}
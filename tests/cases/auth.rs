@@ -0,0 +1,272 @@
+// Copyright 2018 PingCAP, Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use futures::*;
+use grpcio::*;
+use grpcio_proto::example::helloworld::*;
+use grpcio_proto::example::helloworld_grpc::*;
+use grpcio_proto::util::*;
+use std::sync::atomic::*;
+use std::sync::*;
+use std::thread;
+use std::time::*;
+
+#[test]
+fn test_bind_with_cred() {
+    #[derive(Clone)]
+    struct HelloService;
+
+    impl Greeter for HelloService {
+        fn say_hello(&self, ctx: RpcContext, req: HelloRequest, sink: UnarySink<HelloReply>) {
+            let mut resp = HelloReply::new();
+            resp.set_message(format!("hello {}", req.get_name()));
+            ctx.spawn(
+                sink.success(resp)
+                    .map_err(|e| panic!("failed to reply {:?}", e)),
+            );
+        }
+    }
+
+    let env = Arc::new(EnvBuilder::new().build());
+    let mut server = ServerBuilder::new(env.clone())
+        .register_service(create_greeter(HelloService))
+        .bind_with_cred("127.0.0.1", 0, Some(create_test_server_credentials()))
+        .bind_with_cred("127.0.0.1", 0, None)
+        .build()
+        .unwrap();
+    server.start();
+    let secure_port = server.bind_addrs()[0].1;
+    let insecure_port = server.bind_addrs()[1].1;
+
+    let mut req = HelloRequest::new();
+    req.set_name("world".to_owned());
+
+    let ch = ChannelBuilder::new(env.clone())
+        .override_ssl_target("foo.test.google.fr")
+        .secure_connect(
+            &format!("127.0.0.1:{}", secure_port),
+            create_test_channel_credentials(),
+        );
+    let client = GreeterClient::new(ch);
+    let resp = client.say_hello(&req).unwrap();
+    assert_eq!(resp.get_message(), "hello world");
+
+    let ch = ChannelBuilder::new(env.clone()).connect(&format!("127.0.0.1:{}", insecure_port));
+    let client = GreeterClient::new(ch);
+    let resp = client.say_hello(&req).unwrap();
+    assert_eq!(resp.get_message(), "hello world");
+
+    // Plain text connections are rejected by the secure port.
+    let ch = ChannelBuilder::new(env).connect(&format!("127.0.0.1:{}", secure_port));
+    let client = GreeterClient::new(ch);
+    let opt = CallOption::default().timeout(Duration::from_secs(1));
+    assert!(client.say_hello_opt(&req, opt).is_err());
+}
+
+#[test]
+fn test_call_credentials() {
+    #[derive(Clone)]
+    struct TokenService;
+
+    impl Greeter for TokenService {
+        fn say_hello(&self, ctx: RpcContext, req: HelloRequest, sink: UnarySink<HelloReply>) {
+            let token = ctx
+                .request_headers()
+                .iter()
+                .find(|&(k, _)| k == "x-token")
+                .map(|(_, v)| String::from_utf8(v.to_vec()).unwrap());
+            let mut resp = HelloReply::new();
+            resp.set_message(format!("{} {:?}", req.get_name(), token));
+            ctx.spawn(
+                sink.success(resp)
+                    .map_err(|e| panic!("failed to reply {:?}", e)),
+            );
+        }
+    }
+
+    struct TokenProvider {
+        counter: AtomicUsize,
+    }
+
+    impl CallCredentialsProvider for TokenProvider {
+        fn get_metadata(&self, ctx: AuthMetadataContext, sink: AuthMetadataSink) {
+            assert_eq!(ctx.method_name, "SayHello");
+            let n = self.counter.fetch_add(1, Ordering::SeqCst);
+            if n == 1 {
+                sink.fail(RpcStatus::unauthenticated("no token"));
+                return;
+            }
+            let mut builder = MetadataBuilder::new();
+            builder.add_str("x-token", &format!("t{}", n)).unwrap();
+            // Deliver the metadata asynchronously.
+            thread::spawn(move || sink.success(builder.build()));
+        }
+    }
+
+    let env = Arc::new(EnvBuilder::new().build());
+    let mut server = ServerBuilder::new(env.clone())
+        .register_service(create_greeter(TokenService))
+        .bind_secure("127.0.0.1", 0, create_test_server_credentials())
+        .build()
+        .unwrap();
+    server.start();
+    let port = server.bind_addrs()[0].1;
+
+    let call_creds = CallCredentials::from_provider(TokenProvider {
+        counter: AtomicUsize::new(0),
+    });
+    let creds = create_test_channel_credentials().with_call_credentials(call_creds);
+    let ch = ChannelBuilder::new(env)
+        .override_ssl_target("foo.test.google.fr")
+        .secure_connect(&format!("127.0.0.1:{}", port), creds);
+    let client = GreeterClient::new(ch);
+
+    let mut req = HelloRequest::new();
+    req.set_name("world".to_owned());
+    let resp = client.say_hello(&req).unwrap();
+    assert_eq!(resp.get_message(), "world Some(\"t0\")");
+    client.say_hello(&req).unwrap_err();
+    let resp = client.say_hello(&req).unwrap();
+    assert_eq!(resp.get_message(), "world Some(\"t2\")");
+}
+
+#[test]
+fn test_invalid_jwt_credentials() {
+    let res = CallCredentials::service_account_jwt_access("not a key", Duration::from_secs(3600));
+    match res {
+        Err(Error::GoogleAuthenticationFailed) => {}
+        Err(e) => panic!("unexpected error: {:?}", e),
+        Ok(_) => panic!("invalid key should be rejected"),
+    }
+}
+
+#[test]
+fn test_auth_context() {
+    #[derive(Clone)]
+    struct AuthService;
+
+    impl Greeter for AuthService {
+        fn say_hello(&self, ctx: RpcContext, _: HelloRequest, sink: UnarySink<HelloReply>) {
+            let msg = match ctx.auth_context() {
+                Some(auth) => {
+                    let ty: Vec<_> = auth.find("transport_security_type").collect();
+                    format!("{} {:?}", auth.is_authenticated(), ty)
+                }
+                None => "insecure".to_owned(),
+            };
+            let mut resp = HelloReply::new();
+            resp.set_message(msg);
+            ctx.spawn(
+                sink.success(resp)
+                    .map_err(|e| panic!("failed to reply {:?}", e)),
+            );
+        }
+    }
+
+    let env = Arc::new(EnvBuilder::new().build());
+    let mut server = ServerBuilder::new(env.clone())
+        .register_service(create_greeter(AuthService))
+        .bind_secure("127.0.0.1", 0, create_test_server_credentials())
+        .bind("127.0.0.1", 0)
+        .build()
+        .unwrap();
+    server.start();
+    let secure_port = server.bind_addrs()[0].1;
+    let insecure_port = server.bind_addrs()[1].1;
+
+    let ch = ChannelBuilder::new(env.clone())
+        .override_ssl_target("foo.test.google.fr")
+        .secure_connect(
+            &format!("127.0.0.1:{}", secure_port),
+            create_test_channel_credentials(),
+        );
+    let resp = GreeterClient::new(ch)
+        .say_hello(&HelloRequest::new())
+        .unwrap();
+    assert_eq!(resp.get_message(), "false [[115, 115, 108]]");
+
+    let ch = ChannelBuilder::new(env).connect(&format!("127.0.0.1:{}", insecure_port));
+    let resp = GreeterClient::new(ch)
+        .say_hello(&HelloRequest::new())
+        .unwrap();
+    assert_eq!(resp.get_message(), "insecure");
+}
+
+#[test]
+fn test_authorizer() {
+    use grpcio::authz::{Authorizer, Policy, Rule};
+
+    #[derive(Clone)]
+    struct HelloService;
+
+    impl Greeter for HelloService {
+        fn say_hello(&self, ctx: RpcContext, req: HelloRequest, sink: UnarySink<HelloReply>) {
+            let mut resp = HelloReply::new();
+            resp.set_message(format!("hello {}", req.get_name()));
+            ctx.spawn(
+                sink.success(resp)
+                    .map_err(|e| panic!("failed to reply: {:?}", e)),
+            );
+        }
+    }
+
+    let policy = Policy::new("greeter").allow(
+        Rule::new("admins")
+            .path("/helloworld.Greeter/*")
+            .header("x-role", "admin"),
+    );
+    let authorizer = Authorizer::new(policy);
+    let env = Arc::new(EnvBuilder::new().build());
+    let mut server = ServerBuilder::new(env.clone())
+        .register_service(create_greeter(HelloService))
+        .bind("127.0.0.1", 0)
+        .authorizer(authorizer.clone())
+        .build()
+        .unwrap();
+    server.start();
+    let port = server.bind_addrs()[0].1;
+    let ch = ChannelBuilder::new(env).connect(&format!("127.0.0.1:{}", port));
+    let client = GreeterClient::new(ch);
+    let mut req = HelloRequest::new();
+    req.set_name("authz".to_owned());
+    let call = |role: &str| {
+        let mut headers = MetadataBuilder::new();
+        headers.add_str("x-role", role).unwrap();
+        let opt = CallOption::default().headers(headers.build());
+        client.say_hello_opt(&req, opt)
+    };
+
+    assert_eq!(call("admin").unwrap().get_message(), "hello authz");
+    match call("guest") {
+        Err(Error::RpcFailure(s)) => assert_eq!(s.status, RpcStatusCode::PermissionDenied),
+        r => panic!("unexpected result {:?}", r),
+    }
+
+    // A new policy takes effect for the following calls.
+    authorizer.set_policy(
+        Policy::new("greeter")
+            .allow(Rule::new("all"))
+            .deny(Rule::new("no-admins").header("x-role", "admin")),
+    );
+    assert_eq!(call("guest").unwrap().get_message(), "hello authz");
+    match call("admin") {
+        Err(Error::RpcFailure(s)) => {
+            assert_eq!(s.status, RpcStatusCode::PermissionDenied);
+            assert_eq!(
+                s.details.unwrap(),
+                "denied by rule no-admins of policy greeter"
+            );
+        }
+        r => panic!("unexpected result {:?}", r),
+    }
+}
@@ -0,0 +1,409 @@
+// Copyright 2018 PingCAP, Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use futures::*;
+use grpcio::*;
+use grpcio_proto::example::helloworld::*;
+use grpcio_proto::example::helloworld_grpc::*;
+use std::ffi::CString;
+use std::mem;
+use std::sync::*;
+use std::thread;
+use std::time::*;
+
+use super::start_greeter;
+
+#[test]
+fn test_channel_stats() {
+    let env = Arc::new(EnvBuilder::new().build());
+    let mut server = ServerBuilder::new(env.clone())
+        .bind("127.0.0.1", 0)
+        .build()
+        .unwrap();
+    server.start();
+    let port = server.bind_addrs()[0].1;
+    let addr = format!("127.0.0.1:{}", port);
+    let ch = ChannelBuilder::new(env.clone())
+        .subchannel_stats(true)
+        .connect(&addr);
+    let stats = ch.stats();
+    assert_eq!(stats.calls_started(), 0);
+    assert_eq!(stats.calls_in_flight(), 0);
+    assert!(ch.subchannel_stats().is_empty());
+
+    let client = GreeterClient::new(ch.clone());
+    for _ in 0..2 {
+        assert!(client.say_hello(&HelloRequest::new()).is_err());
+    }
+    let stats = ch.stats();
+    assert_eq!(stats.calls_started(), 2);
+    assert_eq!(stats.calls_in_flight(), 0);
+    assert_eq!(stats.calls_failed(), 2);
+    assert_eq!(stats.calls_failed_with(RpcStatusCode::Unimplemented), 2);
+    assert_eq!(stats.calls_failed_with(RpcStatusCode::Ok), 0);
+
+    let subchannels = ch.subchannel_stats();
+    assert_eq!(subchannels.len(), 1);
+    let s = &subchannels[0];
+    assert_eq!(s.address(), format!("ipv4:127.0.0.1:{}", port));
+    assert_eq!(s.calls_finished(), 2);
+    assert_eq!(s.calls_failed(), 2);
+    assert!(s.max_latency() >= s.mean_latency());
+
+    // Subchannel stats are not counted by default.
+    let ch = ChannelBuilder::new(env).connect(&addr);
+    let client = GreeterClient::new(ch.clone());
+    assert!(client.say_hello(&HelloRequest::new()).is_err());
+    assert_eq!(ch.stats().calls_failed(), 1);
+    assert!(ch.subchannel_stats().is_empty());
+}
+
+#[test]
+fn test_authority() {
+    #[derive(Clone)]
+    struct HostService;
+
+    impl Greeter for HostService {
+        fn say_hello(&self, ctx: RpcContext, _: HelloRequest, sink: UnarySink<HelloReply>) {
+            let mut resp = HelloReply::new();
+            resp.set_message(String::from_utf8(ctx.host().to_vec()).unwrap());
+            ctx.spawn(
+                sink.success(resp)
+                    .map_err(|e| panic!("failed to reply {:?}", e)),
+            );
+        }
+    }
+
+    let env = Arc::new(EnvBuilder::new().build());
+    let mut server = ServerBuilder::new(env.clone())
+        .register_service(create_greeter(HostService))
+        .bind("127.0.0.1", 0)
+        .build()
+        .unwrap();
+    server.start();
+    let port = server.bind_addrs()[0].1;
+    let ch = ChannelBuilder::new(env)
+        .default_authority("default.example.com")
+        .connect(&format!("127.0.0.1:{}", port));
+    let client = GreeterClient::new(ch);
+
+    let resp = client.say_hello(&HelloRequest::new()).unwrap();
+    assert_eq!(resp.get_message(), "default.example.com");
+    let opt = CallOption::default().authority("logical.example.com");
+    let resp = client.say_hello_opt(&HelloRequest::new(), opt).unwrap();
+    assert_eq!(resp.get_message(), "logical.example.com");
+}
+
+#[test]
+fn test_user_agent() {
+    #[derive(Clone)]
+    struct AgentService;
+
+    impl Greeter for AgentService {
+        fn say_hello(&self, ctx: RpcContext, _: HelloRequest, sink: UnarySink<HelloReply>) {
+            let agent = ctx
+                .request_headers()
+                .iter()
+                .find(|&(k, _)| k == "user-agent")
+                .map(|(_, v)| String::from_utf8(v.to_vec()).unwrap())
+                .unwrap();
+            let mut resp = HelloReply::new();
+            resp.set_message(agent);
+            ctx.spawn(
+                sink.success(resp)
+                    .map_err(|e| panic!("failed to reply {:?}", e)),
+            );
+        }
+    }
+
+    let env = Arc::new(EnvBuilder::new().build());
+    let mut server = ServerBuilder::new(env.clone())
+        .register_service(create_greeter(AgentService))
+        .bind("127.0.0.1", 0)
+        .build()
+        .unwrap();
+    server.start();
+    let port = server.bind_addrs()[0].1;
+    let ch = ChannelBuilder::new(env)
+        .primary_user_agent("fleet-a/1.0")
+        .secondary_user_agent("canary")
+        .connect(&format!("127.0.0.1:{}", port));
+    let client = GreeterClient::new(ch);
+
+    let agent = client
+        .say_hello(&HelloRequest::new())
+        .unwrap()
+        .take_message();
+    assert!(agent.starts_with("fleet-a/1.0 grpc-rust/"), "{}", agent);
+    assert!(agent.ends_with(" canary"), "{}", agent);
+}
+
+#[test]
+fn test_channel_args() {
+    let env = Arc::new(EnvBuilder::new().build());
+    let ch = ChannelBuilder::new(env)
+        .arg(ChannelArg::MaxSendMessageLen(1024))
+        .arg(ChannelArg::KeepaliveTime(Duration::from_secs(10)))
+        .arg(ChannelArg::LoadBalancingPolicy(LbPolicy::RoundRobin))
+        .raw_cfg_int(CString::new("grpc.max_connection_idle_ms").unwrap(), 5000)
+        .connect("127.0.0.1:1");
+    let args: Vec<_> = ch
+        .args()
+        .iter()
+        .filter(|&&(ref k, _)| k != "grpc.primary_user_agent")
+        .cloned()
+        .collect();
+    assert_eq!(
+        args,
+        vec![
+            (
+                "grpc.keepalive_time_ms".to_owned(),
+                ChannelArgValue::Integer(10000),
+            ),
+            (
+                "grpc.lb_policy_name".to_owned(),
+                ChannelArgValue::String("round_robin".to_owned()),
+            ),
+            (
+                "grpc.max_connection_idle_ms".to_owned(),
+                ChannelArgValue::Integer(5000),
+            ),
+            (
+                "grpc.max_send_message_length".to_owned(),
+                ChannelArgValue::Integer(1024),
+            ),
+        ]
+    );
+}
+
+#[test]
+#[should_panic(expected = "expects a string value")]
+fn test_raw_channel_arg_type_mismatch() {
+    let env = Arc::new(EnvBuilder::new().build());
+    ChannelBuilder::new(env).raw_cfg_int(CString::new("grpc.lb_policy_name").unwrap(), 1);
+}
+
+#[test]
+fn test_connectivity_state() {
+    #[derive(Clone)]
+    struct GreeterService;
+
+    impl Greeter for GreeterService {
+        fn say_hello(&self, ctx: RpcContext, _: HelloRequest, sink: UnarySink<HelloReply>) {
+            ctx.spawn(
+                sink.success(HelloReply::new())
+                    .map_err(|e| panic!("failed to reply {:?}", e)),
+            );
+        }
+    }
+
+    let (_server, ch) = start_greeter(GreeterService);
+
+    assert_eq!(ch.check_connectivity_state(false), ConnectivityState::Idle);
+    let changed = ch
+        .wait_for_state_change(ConnectivityState::Idle, Duration::from_millis(100))
+        .wait()
+        .unwrap();
+    assert!(!changed);
+
+    let mut states = ch.state_changes().wait();
+    assert_eq!(states.next().unwrap().unwrap(), ConnectivityState::Idle);
+    ch.check_connectivity_state(true);
+    for state in states {
+        match state.unwrap() {
+            ConnectivityState::Ready => break,
+            ConnectivityState::Connecting => continue,
+            s => panic!("unexpected state {:?}", s),
+        }
+    }
+}
+
+#[test]
+#[cfg(target_os = "linux")]
+fn test_source_address() {
+    #[derive(Clone)]
+    struct PeerService;
+
+    impl Greeter for PeerService {
+        fn say_hello(&self, ctx: RpcContext, _: HelloRequest, sink: UnarySink<HelloReply>) {
+            let mut resp = HelloReply::new();
+            resp.set_message(ctx.peer().to_string());
+            ctx.spawn(sink.success(resp).map_err(|_| ()));
+        }
+    }
+
+    let env = Arc::new(EnvBuilder::new().build());
+    let mut server = ServerBuilder::new(env.clone())
+        .register_service(create_greeter(PeerService))
+        .bind("127.0.0.1", 0)
+        .build()
+        .unwrap();
+    server.start();
+    let port = server.bind_addrs()[0].1;
+    // The whole 127.0.0.0/8 is routed to loopback on Linux.
+    let ch = ChannelBuilder::new(env.clone())
+        .source_address("127.0.0.2:0".parse().unwrap())
+        .connect(&format!("127.0.0.1:{}", port));
+    let client = GreeterClient::new(ch);
+    let resp = client.say_hello(&HelloRequest::new()).unwrap();
+    assert!(
+        resp.get_message().starts_with("ipv4:127.0.0.2:"),
+        "{}",
+        resp.get_message()
+    );
+
+    // The address is not reachable from an IPv6 socket.
+    let ch = ChannelBuilder::new(env)
+        .source_address("[::1]:0".parse().unwrap())
+        .connect(&format!("127.0.0.1:{}", port));
+    let client = GreeterClient::new(ch);
+    let opt = CallOption::default().timeout(Duration::from_millis(500));
+    assert!(client.say_hello_opt(&HelloRequest::new(), opt).is_err());
+}
+
+#[test]
+#[cfg(target_os = "linux")]
+fn test_tcp_user_timeout() {
+    extern crate libc;
+
+    fn get_sockopt(fd: i32, level: i32, name: i32) -> i32 {
+        let mut val: libc::c_int = 0;
+        let mut len = mem::size_of::<libc::c_int>() as libc::socklen_t;
+        unsafe {
+            assert_eq!(
+                libc::getsockopt(fd, level, name, &mut val as *mut _ as *mut _, &mut len),
+                0
+            );
+        }
+        val
+    }
+
+    let env = Arc::new(EnvBuilder::new().build());
+    let mut server = ServerBuilder::new(env.clone())
+        .bind("127.0.0.1", 0)
+        .build()
+        .unwrap();
+    server.start();
+    let port = server.bind_addrs()[0].1;
+
+    let opts = Arc::new(Mutex::new(vec![]));
+    let opts_ = opts.clone();
+    let ch = ChannelBuilder::new(env)
+        .tcp_user_timeout(Duration::from_millis(1500))
+        .tcp_keepalive(Duration::from_secs(10), Duration::from_secs(2), 3)
+        // Mutators are invoked in order, so the options are set already.
+        .socket_mutator(move |fd| {
+            opts_.lock().unwrap().push(vec![
+                get_sockopt(fd, libc::IPPROTO_TCP, libc::TCP_USER_TIMEOUT),
+                get_sockopt(fd, libc::SOL_SOCKET, libc::SO_KEEPALIVE),
+                get_sockopt(fd, libc::IPPROTO_TCP, libc::TCP_KEEPIDLE),
+                get_sockopt(fd, libc::IPPROTO_TCP, libc::TCP_KEEPINTVL),
+                get_sockopt(fd, libc::IPPROTO_TCP, libc::TCP_KEEPCNT),
+            ]);
+            true
+        })
+        .connect(&format!("127.0.0.1:{}", port));
+    let client = GreeterClient::new(ch);
+    // The server has no service, it's only for connecting.
+    assert!(client.say_hello(&HelloRequest::new()).is_err());
+    let opts = opts.lock().unwrap();
+    assert!(!opts.is_empty());
+    assert_eq!(opts[0], vec![1500, 1, 10, 2, 3]);
+}
+
+#[test]
+fn test_probe_connect_error() {
+    use std::net::TcpListener;
+
+    fn wait_for_failure(ch: &Channel) {
+        let mut state = ch.check_connectivity_state(true);
+        while state != ConnectivityState::TransientFailure {
+            assert!(ch
+                .wait_for_state_change(state, Duration::from_secs(10))
+                .wait()
+                .unwrap());
+            state = ch.check_connectivity_state(true);
+        }
+    }
+
+    let env = Arc::new(EnvBuilder::new().cq_count(1).build());
+    let ch = ChannelBuilder::new(env.clone()).connect("127.0.0.1:1");
+    assert!(ch.probe_connect_error(Duration::from_secs(1)).is_none());
+    wait_for_failure(&ch);
+    let e = ch.probe_connect_error(Duration::from_secs(1)).unwrap();
+    assert_eq!(e.kind(), ConnectErrorKind::Refused);
+    assert_eq!(e.address(), Some("127.0.0.1:1"));
+
+    // Accepts connections but doesn't speak HTTP/2.
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    thread::spawn(move || {
+        for s in listener.incoming() {
+            drop(s);
+        }
+    });
+    let ch = ChannelBuilder::new(env).connect(&addr.to_string());
+    wait_for_failure(&ch);
+    let e = ch.probe_connect_error(Duration::from_secs(1)).unwrap();
+    assert_eq!(e.kind(), ConnectErrorKind::Unknown);
+}
+
+#[cfg(unix)]
+#[test]
+fn test_custom_transport() {
+    use grpcio::transport;
+    use std::os::unix::net::UnixStream;
+
+    #[derive(Clone)]
+    struct HelloService;
+
+    impl Greeter for HelloService {
+        fn say_hello(&self, ctx: RpcContext, req: HelloRequest, sink: UnarySink<HelloReply>) {
+            let mut resp = HelloReply::new();
+            resp.set_message(format!("hello {}", req.get_name()));
+            ctx.spawn(
+                sink.success(resp)
+                    .map_err(|e| panic!("failed to reply: {:?}", e)),
+            );
+        }
+    }
+
+    let env = Arc::new(EnvBuilder::new().build());
+    let mut server = ServerBuilder::new(env.clone())
+        .register_service(create_greeter(HelloService))
+        .build()
+        .unwrap();
+    server.start();
+    let mut req = HelloRequest::new();
+    req.set_name("pipe".to_owned());
+
+    let ch = transport::in_memory(&server, ChannelBuilder::new(env.clone()), "memory").unwrap();
+    let resp = GreeterClient::new(ch).say_hello(&req).unwrap();
+    assert_eq!(resp.get_message(), "hello pipe");
+
+    // Any byte stream works, a socket pair stands in for e.g. a serial link.
+    let (server_side, client_side) = UnixStream::pair().unwrap();
+    transport::serve_stream(&server, server_side.try_clone().unwrap(), server_side).unwrap();
+    let ch = transport::connect_stream(
+        ChannelBuilder::new(env),
+        "link",
+        client_side.try_clone().unwrap(),
+        client_side,
+    )
+    .unwrap();
+    let client = GreeterClient::new(ch);
+    for _ in 0..3 {
+        let resp = client.say_hello(&req).unwrap();
+        assert_eq!(resp.get_message(), "hello pipe");
+    }
+}
@@ -0,0 +1,426 @@
+// Copyright 2018 PingCAP, Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use futures::*;
+use grpcio::*;
+use grpcio_proto::example::helloworld::*;
+use grpcio_proto::example::helloworld_grpc::*;
+use std::sync::atomic::*;
+use std::sync::*;
+use std::thread;
+use std::time::*;
+
+use super::start_greeter;
+
+#[test]
+fn test_checksum() {
+    #[derive(Clone)]
+    struct HelloService;
+
+    impl Greeter for HelloService {
+        fn say_hello(&self, ctx: RpcContext, req: HelloRequest, sink: UnarySink<HelloReply>) {
+            let mut resp = HelloReply::new();
+            resp.set_message(format!("hello {}", req.get_name()));
+            ctx.spawn(
+                sink.success(resp)
+                    .map_err(|e| panic!("failed to reply {:?}", e)),
+            );
+        }
+    }
+
+    // A checksum that never matches the expected one.
+    struct Broken;
+
+    impl checksum::Checksum for Broken {
+        fn checksum(&self, data: &[u8]) -> Vec<u8> {
+            vec![data.len() as u8]
+        }
+    }
+
+    const METHOD_COUNT_HELLO: Method<HelloRequest, HelloReply> = Method {
+        ty: MethodType::ClientStreaming,
+        name: "/helloworld.Counter/CountHello",
+        req_mar: Marshaller {
+            ser: pb_ser,
+            de: pb_de,
+        },
+        resp_mar: Marshaller {
+            ser: pb_ser,
+            de: pb_de,
+        },
+    };
+
+    let env = Arc::new(EnvBuilder::new().build());
+    let counter = ServiceBuilder::new()
+        .add_client_streaming_handler(&METHOD_COUNT_HELLO, |ctx, reqs, sink| {
+            let f = reqs
+                .fold(0, |n, _| Ok::<_, Error>(n + 1))
+                .and_then(|n| {
+                    let mut resp = HelloReply::new();
+                    resp.set_message(format!("{} hellos", n));
+                    sink.success(resp)
+                })
+                .map_err(|e| panic!("failed to reply {:?}", e));
+            ctx.spawn(f)
+        })
+        .build();
+    let mut server = ServerBuilder::new(env.clone())
+        .register_service(create_greeter(HelloService))
+        .register_service(counter)
+        .checksum(Arc::new(checksum::Crc32c::new()))
+        .bind("127.0.0.1", 0)
+        .build()
+        .unwrap();
+    server.start();
+    let port = server.bind_addrs()[0].1;
+    let ch = ChannelBuilder::new(env).connect(&format!("127.0.0.1:{}", port));
+    let raw_client = Client::new(ch.clone());
+    let client = GreeterClient::new(ch);
+
+    let mut req = HelloRequest::new();
+    req.set_name("world".to_owned());
+    let opt = CallOption::default().checksum(Arc::new(checksum::Crc32c::new()));
+    let resp = client.say_hello_opt(&req, opt).unwrap();
+    assert_eq!(resp.get_message(), "hello world");
+
+    // Checksum is optional.
+    let resp = client.say_hello(&req).unwrap();
+    assert_eq!(resp.get_message(), "hello world");
+
+    let opt = CallOption::default().checksum(Arc::new(Broken));
+    match client.say_hello_opt(&req, opt) {
+        Err(Error::RpcFailure(status)) => assert_eq!(status.status, RpcStatusCode::DataLoss),
+        res => panic!("expected data loss, got {:?}", res),
+    }
+
+    // The response of client streaming calls is verified too.
+    let count_hello = |c: Arc<checksum::Checksum>| {
+        let opt = CallOption::default().checksum(c);
+        let (tx, rx) = raw_client
+            .client_streaming(&METHOD_COUNT_HELLO, opt)
+            .unwrap();
+        let reqs = vec![(req.clone(), WriteFlags::default()); 2];
+        let _ = tx.send_all(stream::iter_ok::<_, Error>(reqs)).wait().unwrap();
+        rx.wait()
+    };
+    let resp = count_hello(Arc::new(checksum::Crc32c::new())).unwrap();
+    assert_eq!(resp.get_message(), "2 hellos");
+    match count_hello(Arc::new(Broken)) {
+        Err(Error::RpcFailure(status)) => assert_eq!(status.status, RpcStatusCode::DataLoss),
+        res => panic!("expected data loss, got {:?}", res),
+    }
+}
+
+#[test]
+fn test_chunked_unary() {
+    const METHOD_SAY_HELLO_CHUNKED: Method<HelloRequest, HelloReply> = Method {
+        ty: MethodType::Duplex,
+        name: "/helloworld.Greeter/SayHelloChunked",
+        req_mar: Marshaller {
+            ser: pb_ser,
+            de: pb_de,
+        },
+        resp_mar: Marshaller {
+            ser: pb_ser,
+            de: pb_de,
+        },
+    };
+
+    let env = Arc::new(EnvBuilder::new().build());
+    let service = ServiceBuilder::new()
+        .add_chunked_unary_handler(&METHOD_SAY_HELLO_CHUNKED, 100, |ctx, req, sink| {
+            let f = req
+                .and_then(|req| {
+                    let mut resp = HelloReply::new();
+                    resp.set_message(format!("hello {}", req.get_name()));
+                    sink.success(resp)
+                })
+                .map_err(|e| panic!("failed to reply {:?}", e));
+            ctx.spawn(f)
+        })
+        .build();
+    let args = ChannelBuilder::new(env.clone())
+        .max_receive_message_len(1024)
+        .max_send_message_len(1024)
+        .build_args();
+    let mut server = ServerBuilder::new(env.clone())
+        .register_service(service)
+        .channel_args(args)
+        .bind("127.0.0.1", 0)
+        .build()
+        .unwrap();
+    server.start();
+    let port = server.bind_addrs()[0].1;
+    let ch = ChannelBuilder::new(env)
+        .max_receive_message_len(1024)
+        .max_send_message_len(1024)
+        .connect(&format!("127.0.0.1:{}", port));
+    let client = Client::new(ch);
+
+    let name = "x".repeat(10 * 1024);
+    let mut req = HelloRequest::new();
+    req.set_name(name.clone());
+    let resp = client
+        .chunked_unary_call(&METHOD_SAY_HELLO_CHUNKED, &req, 1000, CallOption::default())
+        .unwrap();
+    assert_eq!(resp.get_message(), format!("hello {}", name));
+
+    // Empty messages still work.
+    let resp = client
+        .chunked_unary_call(
+            &METHOD_SAY_HELLO_CHUNKED,
+            &HelloRequest::new(),
+            1000,
+            CallOption::default(),
+        )
+        .unwrap();
+    assert_eq!(resp.get_message(), "hello ");
+}
+
+#[test]
+fn test_batch_unary() {
+    const METHOD_SAY_HELLO: Method<HelloRequest, HelloReply> = Method {
+        ty: MethodType::Unary,
+        name: "/helloworld.Greeter/SayHello",
+        req_mar: Marshaller {
+            ser: pb_ser,
+            de: pb_de,
+        },
+        resp_mar: Marshaller {
+            ser: pb_ser,
+            de: pb_de,
+        },
+    };
+
+    #[derive(Clone)]
+    struct HelloService;
+
+    impl Greeter for HelloService {
+        fn say_hello(&self, ctx: RpcContext, req: HelloRequest, sink: UnarySink<HelloReply>) {
+            let f = if req.get_name().is_empty() {
+                let status = RpcStatus::new(RpcStatusCode::InvalidArgument, None);
+                sink.fail(status)
+            } else {
+                let mut resp = HelloReply::new();
+                resp.set_message(format!("hello {}", req.get_name()));
+                sink.success(resp)
+            };
+            ctx.spawn(f.map_err(|e| panic!("failed to reply {:?}", e)));
+        }
+    }
+
+    let (_server, ch) = start_greeter(HelloService);
+    let client = Client::new(ch);
+
+    let reqs: Vec<_> = (0..10)
+        .map(|i| {
+            let mut req = HelloRequest::new();
+            // Request 5 is invalid.
+            if i != 5 {
+                req.set_name(format!("{}", i));
+            }
+            req
+        })
+        .collect();
+    let resps = client
+        .batch_unary_call_async(&METHOD_SAY_HELLO, &reqs, CallOption::default())
+        .unwrap();
+    let mut resps: Vec<_> = resps.wait().map(|r| r.unwrap()).collect();
+    resps.sort_by_key(|&(i, _)| i);
+    assert_eq!(resps.len(), 10);
+    for (i, resp) in resps {
+        match resp {
+            Ok(resp) => assert_eq!(resp.get_message(), format!("hello {}", i)),
+            Err(Error::RpcFailure(status)) => {
+                assert_eq!(i, 5);
+                assert_eq!(status.status, RpcStatusCode::InvalidArgument);
+            }
+            Err(e) => panic!("unexpected error: {:?}", e),
+        }
+    }
+}
+
+#[test]
+fn test_await_headers() {
+    let env = Arc::new(EnvBuilder::new().build());
+    // Greet before reading any request, then echo.
+    let mut server = ServerBuilder::new(env.clone())
+        .fallback_handler(|ctx, reqs, sink| {
+            let resps = stream::once(Ok(b"welcome".to_vec()))
+                .chain(reqs)
+                .map(|msg| (msg, WriteFlags::default()));
+            let f = sink
+                .send_all(resps)
+                .map(|_| ())
+                .map_err(|e| panic!("failed to reply: {:?}", e));
+            ctx.spawn(f)
+        })
+        .bind("127.0.0.1", 0)
+        .build()
+        .unwrap();
+    server.start();
+    let port = server.bind_addrs()[0].1;
+    let ch = ChannelBuilder::new(env).connect(&format!("127.0.0.1:{}", port));
+    let client = Client::new(ch);
+
+    let (tx, rx) = client
+        .raw_duplex_streaming("/test.Chat/Talk", CallOption::default())
+        .unwrap();
+    let (_, tx) = tx.await_headers().wait().unwrap();
+    let reqs = stream::once::<_, Error>(Ok((b"hello".to_vec(), WriteFlags::default())));
+    // The call is half-closed once all the requests are sent.
+    let _ = tx.send_all(reqs).wait().unwrap();
+    let resps: Vec<_> = rx.collect().wait().unwrap();
+    assert_eq!(resps, vec![b"welcome".to_vec(), b"hello".to_vec()]);
+}
+
+#[test]
+fn test_caching_client() {
+    use grpcio::cache::{CachingClient, LruStore};
+
+    const METHOD_SAY_HELLO: Method<HelloRequest, HelloReply> = Method {
+        ty: MethodType::Unary,
+        name: "/helloworld.Greeter/SayHello",
+        req_mar: Marshaller {
+            ser: pb_ser,
+            de: pb_de,
+        },
+        resp_mar: Marshaller {
+            ser: pb_ser,
+            de: pb_de,
+        },
+    };
+
+    #[derive(Clone)]
+    struct ConfigService {
+        calls: Arc<AtomicUsize>,
+    }
+
+    impl Greeter for ConfigService {
+        fn say_hello(&self, ctx: RpcContext, req: HelloRequest, sink: UnarySink<HelloReply>) {
+            let n = self.calls.fetch_add(1, Ordering::SeqCst);
+            let mut resp = HelloReply::new();
+            resp.set_message(format!("{}-{}", req.get_name(), n));
+            let directive = if req.get_name() == "hot" {
+                "max-age=60"
+            } else {
+                "no-store"
+            };
+            let mut builder = MetadataBuilder::new();
+            builder.add_str("cache-control", directive).unwrap();
+            ctx.spawn(
+                sink.success_with_trailers(resp, builder.build())
+                    .map_err(|_| ()),
+            );
+        }
+    }
+
+    let service = ConfigService {
+        calls: Arc::default(),
+    };
+    let (_server, ch) = start_greeter(service.clone());
+    let client = CachingClient::new(Client::new(ch), Arc::new(LruStore::new(16)));
+    let call = |name: &str, opt: CallOption| {
+        let mut req = HelloRequest::new();
+        req.set_name(name.to_owned());
+        client
+            .unary_call(&METHOD_SAY_HELLO, &req, opt)
+            .unwrap()
+            .get_message()
+            .to_owned()
+    };
+    let cacheable = || CallOption::default().cacheable(true);
+
+    assert_eq!(call("hot", cacheable()), "hot-0");
+    assert_eq!(call("hot", cacheable()), "hot-0");
+    // Calls not marked cacheable always reach the server.
+    assert_eq!(call("hot", CallOption::default()), "hot-1");
+    assert_eq!(call("cold", cacheable()), "cold-2");
+    assert_eq!(call("cold", cacheable()), "cold-3");
+
+    let mut builder = MetadataBuilder::new();
+    builder.add_str("cache-control", "no-cache").unwrap();
+    let opt = cacheable().headers(builder.build());
+    assert_eq!(call("hot", opt), "hot-4");
+    // The refreshed response replaces the cached one.
+    assert_eq!(call("hot", cacheable()), "hot-4");
+    assert_eq!(service.calls.load(Ordering::SeqCst), 5);
+}
+
+#[test]
+fn test_coalescing_client() {
+    use grpcio::coalesce::CoalescingClient;
+
+    const METHOD_SAY_HELLO: Method<HelloRequest, HelloReply> = Method {
+        ty: MethodType::Unary,
+        name: "/helloworld.Greeter/SayHello",
+        req_mar: Marshaller {
+            ser: pb_ser,
+            de: pb_de,
+        },
+        resp_mar: Marshaller {
+            ser: pb_ser,
+            de: pb_de,
+        },
+    };
+
+    #[derive(Clone)]
+    struct SlowService {
+        calls: Arc<AtomicUsize>,
+    }
+
+    impl Greeter for SlowService {
+        fn say_hello(&self, _: RpcContext, req: HelloRequest, sink: UnarySink<HelloReply>) {
+            let n = self.calls.fetch_add(1, Ordering::SeqCst);
+            thread::spawn(move || {
+                thread::sleep(Duration::from_millis(200));
+                let mut resp = HelloReply::new();
+                resp.set_message(format!("{}-{}", req.get_name(), n));
+                sink.success(resp).wait().unwrap();
+            });
+        }
+    }
+
+    let service = SlowService {
+        calls: Arc::default(),
+    };
+    let (_server, ch) = start_greeter(service.clone());
+    let client = CoalescingClient::new(Client::new(ch));
+    let call = |name: &str, opt: CallOption| {
+        let mut req = HelloRequest::new();
+        req.set_name(name.to_owned());
+        client
+            .unary_call_async(&METHOD_SAY_HELLO, &req, opt)
+            .unwrap()
+    };
+    let idempotent = || CallOption::default().idempotent(true);
+
+    let hot: Vec<_> = (0..4).map(|_| call("hot", idempotent())).collect();
+    let other = call("other", idempotent());
+    assert_eq!(client.in_flight(), 2);
+    let replies: Vec<_> = hot
+        .into_iter()
+        .map(|c| c.wait().unwrap().get_message().to_owned())
+        .collect();
+    assert!(replies.iter().all(|r| *r == replies[0]));
+    assert!(other.wait().unwrap().get_message().starts_with("other-"));
+    assert_eq!(service.calls.load(Ordering::SeqCst), 2);
+    assert_eq!(client.in_flight(), 0);
+
+    // Calls not marked idempotent are sent on their own.
+    let calls: Vec<_> = (0..2).map(|_| call("hot", CallOption::default())).collect();
+    for c in calls {
+        c.wait().unwrap();
+    }
+    assert_eq!(service.calls.load(Ordering::SeqCst), 4);
+}
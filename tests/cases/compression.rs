@@ -0,0 +1,241 @@
+// Copyright 2018 PingCAP, Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use futures::*;
+use grpcio::method_config;
+use grpcio::*;
+use grpcio_proto::example::helloworld::*;
+use grpcio_proto::example::helloworld_grpc::*;
+use std::io::{Read, Write};
+use std::net::{Shutdown, TcpListener, TcpStream};
+use std::sync::*;
+use std::thread;
+
+#[test]
+fn test_stream_compression() {
+    #[derive(Clone)]
+    struct EchoService;
+
+    impl Greeter for EchoService {
+        fn say_hello(&self, ctx: RpcContext, req: HelloRequest, sink: UnarySink<HelloReply>) {
+            let mut resp = HelloReply::new();
+            resp.set_message(req.get_name().to_owned());
+            ctx.spawn(
+                sink.success(resp)
+                    .map_err(|e| panic!("failed to reply {:?}", e)),
+            );
+        }
+    }
+
+    let env = Arc::new(EnvBuilder::new().build());
+    let args = ChannelBuilder::new(env.clone())
+        .default_stream_compression_algorithm(StreamCompressionAlgorithms::Gzip)
+        .build_args();
+    let mut server = ServerBuilder::new(env.clone())
+        .register_service(create_greeter(EchoService))
+        .channel_args(args)
+        .bind("127.0.0.1", 0)
+        .build()
+        .unwrap();
+    server.start();
+    let port = server.bind_addrs()[0].1;
+    let ch = ChannelBuilder::new(env)
+        .default_stream_compression_algorithm(StreamCompressionAlgorithms::Gzip)
+        .default_stream_compression_level(CompressionLevel::High)
+        .connect(&format!("127.0.0.1:{}", port));
+    let client = GreeterClient::new(ch);
+
+    let mut req = HelloRequest::new();
+    req.set_name("a".repeat(4096));
+    let resp = client.say_hello(&req).unwrap();
+    assert_eq!(resp.get_message(), req.get_name());
+    let opt = CallOption::default().stream_compression(StreamCompressionAlgorithms::None);
+    let resp = client.say_hello_opt(&req, opt).unwrap();
+    assert_eq!(resp.get_message(), req.get_name());
+}
+
+// Forward the connections accepted by `listener` to `port`, recording the
+// bytes sent by the client and by the server respectively.
+fn start_capture(listener: TcpListener, port: u16) -> (Arc<Mutex<Vec<u8>>>, Arc<Mutex<Vec<u8>>>) {
+    fn pipe(mut from: TcpStream, mut to: TcpStream, captured: Arc<Mutex<Vec<u8>>>) {
+        let mut buf = [0; 4096];
+        loop {
+            let n = match from.read(&mut buf) {
+                Ok(0) | Err(_) => break,
+                Ok(n) => n,
+            };
+            captured.lock().unwrap().extend_from_slice(&buf[..n]);
+            if to.write_all(&buf[..n]).is_err() {
+                break;
+            }
+        }
+        let _ = to.shutdown(Shutdown::Write);
+    }
+
+    let sent: Arc<Mutex<Vec<u8>>> = Arc::default();
+    let received: Arc<Mutex<Vec<u8>>> = Arc::default();
+    let (s, r) = (Arc::clone(&sent), Arc::clone(&received));
+    thread::spawn(move || {
+        for client in listener.incoming() {
+            let client = client.unwrap();
+            let server = TcpStream::connect(("127.0.0.1", port)).unwrap();
+            let (c, s2) = (client.try_clone().unwrap(), server.try_clone().unwrap());
+            let s = s.clone();
+            thread::spawn(move || pipe(c, s2, s));
+            let r = r.clone();
+            thread::spawn(move || pipe(server, client, r));
+        }
+    });
+    (sent, received)
+}
+
+// Find the first gRPC message of `len` bytes in the captured bytes, and tell
+// how it's encoded on the wire.
+fn message_encoding(bytes: &[u8], len: usize) -> Option<&'static str> {
+    bytes
+        .windows(7)
+        .filter_map(|w| {
+            let msg_len = (w[1] as usize) << 24
+                | (w[2] as usize) << 16
+                | (w[3] as usize) << 8
+                | w[4] as usize;
+            match w[0] {
+                0 if msg_len == len => Some("identity"),
+                1 if msg_len < len && w[5] == 0x1f && w[6] == 0x8b => Some("gzip"),
+                // A zlib header, whose check bits make it a multiple of 31.
+                1 if msg_len < len
+                    && w[5] == 0x78
+                    && (u16::from(w[5]) << 8 | u16::from(w[6])) % 31 == 0 =>
+                {
+                    Some("deflate")
+                }
+                _ => None,
+            }
+        })
+        .next()
+}
+
+#[test]
+fn test_compression_level() {
+    #[derive(Clone)]
+    struct EchoService;
+
+    impl Greeter for EchoService {
+        fn say_hello(&self, ctx: RpcContext, mut req: HelloRequest, sink: UnarySink<HelloReply>) {
+            let mut resp = HelloReply::new();
+            resp.set_message(req.take_name());
+            ctx.spawn(
+                sink.success(resp)
+                    .map_err(|e| panic!("failed to reply {:?}", e)),
+            );
+        }
+    }
+
+    let mut req = HelloRequest::new();
+    req.set_name("a".repeat(16 * 1024));
+    let mut resp = HelloReply::new();
+    resp.set_message(req.get_name().to_owned());
+    let (mut req_buf, mut resp_buf) = (vec![], vec![]);
+    pb_ser(&req, &mut req_buf);
+    pb_ser(&resp, &mut resp_buf);
+
+    let env = Arc::new(EnvBuilder::new().build());
+    let method = "/helloworld.Greeter/SayHello";
+    let cases: Vec<(
+        Box<Fn(ServerBuilder) -> ServerBuilder>,
+        CallOption,
+        &str,
+        &str,
+    )> = vec![
+        (
+            Box::new(|b| b),
+            CallOption::default(),
+            "identity",
+            "identity",
+        ),
+        (
+            Box::new(|b| b.default_compression_level(CompressionLevel::Low)),
+            CallOption::default(),
+            "identity",
+            "gzip",
+        ),
+        (
+            Box::new(|b| b.default_compression_level(CompressionLevel::High)),
+            CallOption::default(),
+            "identity",
+            "deflate",
+        ),
+        (
+            Box::new(move |b| {
+                let config =
+                    method_config::MethodConfig::new().compression_level(CompressionLevel::None);
+                b.default_compression_level(CompressionLevel::High)
+                    .method_config(method, config)
+            }),
+            CallOption::default(),
+            "identity",
+            "identity",
+        ),
+        (
+            Box::new(move |b| {
+                let config =
+                    method_config::MethodConfig::new().compression_level(CompressionLevel::Low);
+                b.method_config(method, config)
+            }),
+            CallOption::default(),
+            "identity",
+            "gzip",
+        ),
+        (
+            Box::new(|b| b),
+            CallOption::default().compression_level(CompressionLevel::Low),
+            "gzip",
+            "identity",
+        ),
+        (
+            Box::new(|b| b),
+            CallOption::default().compression_level(CompressionLevel::High),
+            "deflate",
+            "identity",
+        ),
+    ];
+    for (i, (server_opt, call_opt, req_encoding, resp_encoding)) in cases.into_iter().enumerate() {
+        let builder = ServerBuilder::new(env.clone())
+            .register_service(create_greeter(EchoService))
+            .bind("127.0.0.1", 0);
+        let mut server = server_opt(builder).build().unwrap();
+        server.start();
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let proxy_port = listener.local_addr().unwrap().port();
+        let (sent, received) = start_capture(listener, server.bind_addrs()[0].1);
+        let ch = ChannelBuilder::new(env.clone()).connect(&format!("127.0.0.1:{}", proxy_port));
+        let client = GreeterClient::new(ch);
+
+        let reply = client.say_hello_opt(&req, call_opt).unwrap();
+        assert_eq!(reply.get_message(), req.get_name(), "case {}", i);
+        let sent = sent.lock().unwrap();
+        assert_eq!(
+            message_encoding(&sent, req_buf.len()),
+            Some(req_encoding),
+            "case {}",
+            i
+        );
+        let received = received.lock().unwrap();
+        assert_eq!(
+            message_encoding(&received, resp_buf.len()),
+            Some(resp_encoding),
+            "case {}",
+            i
+        );
+    }
+}
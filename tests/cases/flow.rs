@@ -0,0 +1,521 @@
+// Copyright 2018 PingCAP, Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use futures::*;
+use grpcio::*;
+use grpcio_proto::example::helloworld::*;
+use grpcio_proto::example::helloworld_grpc::*;
+use std::sync::atomic::*;
+use std::sync::*;
+use std::thread;
+use std::time::*;
+
+use super::start_greeter;
+
+#[test]
+fn test_correlated_duplex() {
+    let env = Arc::new(EnvBuilder::new().build());
+    // Echo requests in pairs with the order swapped.
+    let mut server = ServerBuilder::new(env.clone())
+        .fallback_handler(|ctx, reqs, sink| {
+            let resps = reqs
+                .chunks(2)
+                .map(|mut pair| {
+                    pair.reverse();
+                    stream::iter_ok::<_, Error>(
+                        pair.into_iter().map(|msg| (msg, WriteFlags::default())),
+                    )
+                })
+                .flatten();
+            let f = sink
+                .send_all(resps)
+                .map(|_| ())
+                .map_err(|e| panic!("failed to reply: {:?}", e));
+            ctx.spawn(f)
+        })
+        .bind("127.0.0.1", 0)
+        .build()
+        .unwrap();
+    server.start();
+    let port = server.bind_addrs()[0].1;
+    let ch = ChannelBuilder::new(env).connect(&format!("127.0.0.1:{}", port));
+    let client = Client::new(ch);
+
+    let (tx, rx) = client
+        .raw_duplex_streaming("/test.Chat/Talk", CallOption::default())
+        .unwrap();
+    let correlator = correlate::Correlator::new(&client, tx, rx, |resp: &Vec<u8>| resp[0]);
+    let f1 = correlator.call(1, b"\x01first".to_vec());
+    let f2 = correlator.call(2, b"\x02second".to_vec());
+    assert_eq!(f1.wait().unwrap(), b"\x01first".to_vec());
+    assert_eq!(f2.wait().unwrap(), b"\x02second".to_vec());
+    assert_eq!(correlator.in_flight(), 0);
+
+    // The unpaired request is only answered after the call is half-closed.
+    let f3 = correlator.call(3, b"\x03third".to_vec());
+    drop(correlator);
+    assert_eq!(f3.wait().unwrap(), b"\x03third".to_vec());
+}
+
+#[test]
+fn test_pipeline() {
+    use grpcio::pipeline::Pipeline;
+
+    const METHOD_SAY_HELLO: Method<HelloRequest, HelloReply> = Method {
+        ty: MethodType::Unary,
+        name: "/helloworld.Greeter/SayHello",
+        req_mar: Marshaller {
+            ser: pb_ser,
+            de: pb_de,
+        },
+        resp_mar: Marshaller {
+            ser: pb_ser,
+            de: pb_de,
+        },
+    };
+
+    #[derive(Clone)]
+    struct OrderService {
+        arrived: Arc<Mutex<Vec<String>>>,
+        running: Arc<AtomicUsize>,
+        max_running: Arc<AtomicUsize>,
+    }
+
+    impl Greeter for OrderService {
+        fn say_hello(&self, _: RpcContext, req: HelloRequest, sink: UnarySink<HelloReply>) {
+            self.arrived.lock().unwrap().push(req.get_name().to_owned());
+            let running = self.running.fetch_add(1, Ordering::SeqCst) + 1;
+            self.max_running.fetch_max(running, Ordering::SeqCst);
+            let s = self.clone();
+            thread::spawn(move || {
+                thread::sleep(Duration::from_millis(20));
+                s.running.fetch_sub(1, Ordering::SeqCst);
+                let mut resp = HelloReply::new();
+                resp.set_message(req.get_name().to_owned());
+                sink.success(resp).wait().unwrap();
+            });
+        }
+    }
+
+    let service = OrderService {
+        arrived: Arc::default(),
+        running: Arc::default(),
+        max_running: Arc::default(),
+    };
+    let (_server, ch) = start_greeter(service.clone());
+    let names: Vec<_> = (0..8).map(|i| format!("{}", i)).collect();
+
+    for &max_in_flight in &[1, 2] {
+        service.arrived.lock().unwrap().clear();
+        service.max_running.store(0, Ordering::SeqCst);
+        let pipeline = Pipeline::new(Client::new(ch.clone()), &METHOD_SAY_HELLO, max_in_flight);
+        let calls: Vec<_> = names
+            .iter()
+            .map(|name| {
+                let mut req = HelloRequest::new();
+                req.set_name(name.clone());
+                pipeline.call(req, CallOption::default())
+            })
+            .collect();
+        assert!(pipeline.in_flight() <= max_in_flight);
+        assert!(pipeline.queued() >= names.len() - max_in_flight);
+        for (name, call) in names.iter().zip(calls) {
+            assert_eq!(call.wait().unwrap().get_message(), name.as_str());
+        }
+        let mut arrived = service.arrived.lock().unwrap().clone();
+        if max_in_flight > 1 {
+            // Concurrent calls may arrive in any order.
+            arrived.sort();
+        }
+        assert_eq!(arrived, names);
+        assert!(service.max_running.load(Ordering::SeqCst) <= max_in_flight);
+        assert_eq!(pipeline.in_flight(), 0);
+        assert_eq!(pipeline.queued(), 0);
+    }
+}
+
+#[test]
+fn test_stream_flows() {
+    use grpcio_proto::example::route_guide::*;
+    use grpcio_proto::example::route_guide_grpc::*;
+
+    #[derive(Clone)]
+    struct BulkService;
+
+    impl RouteGuide for BulkService {
+        fn get_feature(&self, _: RpcContext, _: Point, _: UnarySink<Feature>) {
+            unimplemented!()
+        }
+
+        fn list_features(&self, ctx: RpcContext, _: Rectangle, sink: ServerStreamingSink<Feature>) {
+            let features = (0..16).map(|_| {
+                let mut f = Feature::new();
+                f.set_name("x".repeat(256 * 1024));
+                (f, WriteFlags::default())
+            });
+            ctx.spawn(
+                sink.send_all(stream::iter_ok::<_, Error>(features))
+                    .map(|_| ())
+                    .map_err(|_| ()),
+            );
+        }
+
+        fn record_route(
+            &self,
+            _: RpcContext,
+            _: RequestStream<Point>,
+            _: ClientStreamingSink<RouteSummary>,
+        ) {
+            unimplemented!()
+        }
+
+        fn route_chat(&self, _: RpcContext, _: RequestStream<RouteNote>, _: DuplexSink<RouteNote>) {
+            unimplemented!()
+        }
+    }
+
+    let env = Arc::new(EnvBuilder::new().build());
+    let mut server = ServerBuilder::new(env.clone())
+        .register_service(create_route_guide(BulkService))
+        .bind("127.0.0.1", 0)
+        .flow_stats(true)
+        .build()
+        .unwrap();
+    server.start();
+    let port = server.bind_addrs()[0].1;
+    let ch = ChannelBuilder::new(env).connect(&format!("127.0.0.1:{}", port));
+    let client = RouteGuideClient::new(ch);
+
+    // The response is not read, so the writes are blocked by flow control.
+    let features = client.list_features(&Rectangle::new()).unwrap();
+    let mut pending = None;
+    for _ in 0..100 {
+        let flows = server.stream_flows();
+        pending = flows.first().and_then(|f| f.send_pending());
+        if pending.map_or(false, |(waited, _)| waited >= Duration::from_millis(100)) {
+            assert_eq!(flows[0].method(), "/routeguide.RouteGuide/ListFeatures");
+            break;
+        }
+        thread::sleep(Duration::from_millis(20));
+    }
+    let (_, bytes) = pending.unwrap();
+    assert!(bytes > 256 * 1024, "{}", bytes);
+
+    assert_eq!(features.collect().wait().unwrap().len(), 16);
+    for _ in 0..100 {
+        if server.stream_flows().is_empty() {
+            break;
+        }
+        thread::sleep(Duration::from_millis(10));
+    }
+    assert!(server.stream_flows().is_empty());
+}
+
+#[test]
+fn test_append_shared() {
+    type Blob = (Vec<u8>, Arc<Vec<u8>>);
+
+    fn ser(b: &Blob, buf: &mut Vec<u8>) {
+        buf.extend_from_slice(&b.0);
+        append_shared(buf, &b.1);
+        buf.push(b'$');
+    }
+
+    fn de(buf: &[u8]) -> Result<Blob> {
+        Ok((buf[..4].to_vec(), Arc::new(buf[4..].to_vec())))
+    }
+
+    const METHOD_LIST_BLOBS: Method<Blob, Blob> = Method {
+        ty: MethodType::ServerStreaming,
+        name: "/test.Blobs/List",
+        req_mar: Marshaller { ser, de },
+        resp_mar: Marshaller { ser, de },
+    };
+
+    let env = Arc::new(EnvBuilder::new().build());
+    let blob = Arc::new(vec![b'x'; 64 * 1024]);
+    let body = blob.clone();
+    let service = ServiceBuilder::new()
+        .add_server_streaming_handler(&METHOD_LIST_BLOBS, move |ctx, _, sink| {
+            let body = body.clone();
+            let blobs = (0..4u8).map(move |i| ((vec![i; 4], body.clone()), WriteFlags::default()));
+            ctx.spawn(
+                sink.send_all(stream::iter_ok::<_, Error>(blobs))
+                    .map(|_| ())
+                    .map_err(|e| panic!("failed to reply: {:?}", e)),
+            );
+        })
+        .build();
+    let mut server = ServerBuilder::new(env.clone())
+        .register_service(service)
+        .bind("127.0.0.1", 0)
+        .build()
+        .unwrap();
+    server.start();
+    let port = server.bind_addrs()[0].1;
+    let ch = ChannelBuilder::new(env).connect(&format!("127.0.0.1:{}", port));
+    let client = Client::new(ch);
+
+    let req = (b"list".to_vec(), Arc::new(vec![]));
+    let blobs = client
+        .server_streaming(&METHOD_LIST_BLOBS, &req, CallOption::default())
+        .unwrap()
+        .collect()
+        .wait()
+        .unwrap();
+    assert_eq!(blobs.len(), 4);
+    for (i, (header, body)) in blobs.into_iter().enumerate() {
+        assert_eq!(header, vec![i as u8; 4]);
+        assert_eq!(body.len(), blob.len() + 1);
+        assert_eq!(&body[..blob.len()], &blob[..]);
+        assert_eq!(body[blob.len()], b'$');
+    }
+    // The references held by gRPC core are released once the messages are
+    // sent.
+    server.shutdown().wait().unwrap();
+    drop(server);
+    for _ in 0..100 {
+        if Arc::strong_count(&blob) == 1 {
+            break;
+        }
+        thread::sleep(Duration::from_millis(10));
+    }
+    assert_eq!(Arc::strong_count(&blob), 1);
+}
+
+#[test]
+fn test_poll_into() {
+    use grpcio_proto::example::route_guide::*;
+    use grpcio_proto::example::route_guide_grpc::*;
+
+    #[derive(Clone)]
+    struct ReuseService;
+
+    impl RouteGuide for ReuseService {
+        fn get_feature(&self, _: RpcContext, _: Point, _: UnarySink<Feature>) {
+            unimplemented!()
+        }
+
+        fn list_features(&self, ctx: RpcContext, _: Rectangle, sink: ServerStreamingSink<Feature>) {
+            let features = ["a", "bb", "ccc"].iter().map(|name| {
+                let mut f = Feature::new();
+                f.set_name(name.to_string());
+                (f, WriteFlags::default())
+            });
+            ctx.spawn(
+                sink.send_all(stream::iter_ok::<_, Error>(features))
+                    .map(|_| ())
+                    .map_err(|_| ()),
+            );
+        }
+
+        fn record_route(
+            &self,
+            ctx: RpcContext,
+            mut points: RequestStream<Point>,
+            sink: ClientStreamingSink<RouteSummary>,
+        ) {
+            let mut point = Point::new();
+            let mut summary = RouteSummary::new();
+            let f = future::poll_fn(move || loop {
+                match points.poll_into(pb_de_into, &mut point)? {
+                    Async::Ready(true) => {
+                        summary.set_point_count(summary.get_point_count() + 1);
+                        summary.set_distance(summary.get_distance() + point.get_latitude());
+                    }
+                    Async::Ready(false) => return Ok(Async::Ready(summary.clone())),
+                    Async::NotReady => return Ok(Async::NotReady),
+                }
+            });
+            ctx.spawn(
+                f.and_then(|summary| sink.success(summary))
+                    .map_err(|e: Error| panic!("failed to reply: {:?}", e)),
+            );
+        }
+
+        fn route_chat(&self, _: RpcContext, _: RequestStream<RouteNote>, _: DuplexSink<RouteNote>) {
+            unimplemented!()
+        }
+    }
+
+    let env = Arc::new(EnvBuilder::new().build());
+    let mut server = ServerBuilder::new(env.clone())
+        .register_service(create_route_guide(ReuseService))
+        .bind("127.0.0.1", 0)
+        .build()
+        .unwrap();
+    server.start();
+    let port = server.bind_addrs()[0].1;
+    let ch = ChannelBuilder::new(env).connect(&format!("127.0.0.1:{}", port));
+    let client = RouteGuideClient::new(ch);
+
+    let mut features = client.list_features(&Rectangle::new()).unwrap();
+    let mut feature = Feature::new();
+    let mut names = vec![];
+    future::poll_fn(|| loop {
+        match features.poll_into(pb_de_into, &mut feature)? {
+            Async::Ready(true) => names.push(feature.get_name().to_owned()),
+            Async::Ready(false) => return Ok(Async::Ready(())),
+            Async::NotReady => return Ok(Async::NotReady),
+        }
+    })
+    .wait()
+    .map_err(|e: Error| e)
+    .unwrap();
+    assert_eq!(names, vec!["a", "bb", "ccc"]);
+
+    let (tx, rx) = client.record_route().unwrap();
+    let points = (1..4).map(|i| {
+        let mut p = Point::new();
+        p.set_latitude(i);
+        (p, WriteFlags::default())
+    });
+    // The sink is closed once all the points are sent.
+    let _ = tx
+        .send_all(stream::iter_ok::<_, Error>(points))
+        .wait()
+        .unwrap();
+    let summary = rx.wait().unwrap();
+    assert_eq!(summary.get_point_count(), 3);
+    assert_eq!(summary.get_distance(), 6);
+}
+
+#[test]
+fn test_bandwidth_limit() {
+    use grpcio::bandwidth::BandwidthLimit;
+
+    fn ser(b: &Vec<u8>, buf: &mut Vec<u8>) {
+        buf.extend_from_slice(b);
+    }
+
+    fn de(buf: &[u8]) -> Result<Vec<u8>> {
+        Ok(buf.to_vec())
+    }
+
+    const METHOD_ECHO: Method<Vec<u8>, Vec<u8>> = Method {
+        ty: MethodType::Duplex,
+        name: "/test.Bytes/Echo",
+        req_mar: Marshaller { ser, de },
+        resp_mar: Marshaller { ser, de },
+    };
+
+    let env = Arc::new(EnvBuilder::new().build());
+    let service = ServiceBuilder::new()
+        .add_duplex_streaming_handler(&METHOD_ECHO, |ctx, reqs, sink| {
+            let resps = reqs.map(|r| (r, WriteFlags::default()));
+            ctx.spawn(
+                sink.send_all(resps)
+                    .map(|_| ())
+                    .map_err(|e| panic!("failed to reply: {:?}", e)),
+            );
+        })
+        .build();
+    // 8 messages of 1 KiB take about 0.9s at 8 KiB/s once the burst is used up.
+    let limit = BandwidthLimit::new().send_rate(8 * 1024).burst(1024);
+    let mut server = ServerBuilder::new(env.clone())
+        .register_service(service)
+        .bind("127.0.0.1", 0)
+        .bandwidth_limit(limit)
+        .build()
+        .unwrap();
+    server.start();
+    let port = server.bind_addrs()[0].1;
+    let ch = ChannelBuilder::new(env).connect(&format!("127.0.0.1:{}", port));
+    let client = Client::new(ch);
+
+    let start = Instant::now();
+    let (tx, rx) = client
+        .duplex_streaming(&METHOD_ECHO, CallOption::default())
+        .unwrap();
+    let reqs = (0..8).map(|_| (vec![b'x'; 1024], WriteFlags::default()));
+    let _ = tx
+        .send_all(stream::iter_ok::<_, Error>(reqs))
+        .wait()
+        .unwrap();
+    let resps = rx.collect().wait().unwrap();
+    assert_eq!(resps.len(), 8);
+    assert!(
+        start.elapsed() >= Duration::from_millis(700),
+        "{:?}",
+        start.elapsed()
+    );
+}
+
+#[test]
+fn test_stream_idle_timeout() {
+    fn ser(b: &Vec<u8>, buf: &mut Vec<u8>) {
+        buf.extend_from_slice(b);
+    }
+
+    fn de(buf: &[u8]) -> Result<Vec<u8>> {
+        Ok(buf.to_vec())
+    }
+
+    const METHOD_ECHO: Method<Vec<u8>, Vec<u8>> = Method {
+        ty: MethodType::Duplex,
+        name: "/test.Bytes/Echo",
+        req_mar: Marshaller { ser, de },
+        resp_mar: Marshaller { ser, de },
+    };
+
+    let env = Arc::new(EnvBuilder::new().build());
+    let service = ServiceBuilder::new()
+        .add_duplex_streaming_handler(&METHOD_ECHO, |ctx, reqs, sink| {
+            let resps = reqs.map(|r| (r, WriteFlags::default()));
+            // The stream is reset once it's idle.
+            ctx.spawn(sink.send_all(resps).map(|_| ()).map_err(|_| ()));
+        })
+        .build();
+    let mut server = ServerBuilder::new(env.clone())
+        .register_service(service)
+        .bind("127.0.0.1", 0)
+        .stream_idle_timeout(Duration::from_millis(300))
+        .build()
+        .unwrap();
+    server.start();
+    let port = server.bind_addrs()[0].1;
+    let ch = ChannelBuilder::new(env).connect(&format!("127.0.0.1:{}", port));
+    let client = Client::new(ch);
+
+    // Messages keep the stream alive, the server resets it once they stop.
+    let (tx, rx) = client
+        .duplex_streaming(&METHOD_ECHO, CallOption::default())
+        .unwrap();
+    let tx = tx
+        .send((b"ping".to_vec(), WriteFlags::default()))
+        .wait()
+        .unwrap();
+    let (resp, rx) = rx.into_future().wait().map_err(|(e, _)| e).unwrap();
+    assert_eq!(resp.unwrap(), b"ping".to_vec());
+    let start = Instant::now();
+    match rx.into_future().map_err(|(e, _)| e).wait() {
+        Err(Error::RpcFailure(s)) => {
+            assert_eq!(s.status, RpcStatusCode::Unavailable);
+            assert!(s.details.unwrap().contains("300ms"));
+        }
+        r => panic!("unexpected result {:?}", r.map(|(m, _)| m)),
+    }
+    assert!(start.elapsed() >= Duration::from_millis(250));
+    drop(tx);
+
+    // The client resets it before the server does.
+    let opt = CallOption::default().stream_idle_timeout(Duration::from_millis(100));
+    let (_tx, rx) = client.duplex_streaming(&METHOD_ECHO, opt).unwrap();
+    match rx.into_future().map_err(|(e, _)| e).wait() {
+        Err(Error::RpcFailure(s)) => {
+            assert_eq!(s.status, RpcStatusCode::Unavailable);
+            assert!(s.details.unwrap().contains("100ms"));
+        }
+        r => panic!("unexpected result {:?}", r.map(|(m, _)| m)),
+    }
+}
@@ -0,0 +1,360 @@
+// Copyright 2018 PingCAP, Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use futures::*;
+use grpcio::panic_policy::{self, PanicPolicy};
+use grpcio::*;
+use grpcio_proto::example::helloworld::*;
+use grpcio_proto::example::helloworld_grpc::*;
+use std::mem;
+use std::sync::atomic::*;
+use std::sync::*;
+use std::thread;
+use std::time::*;
+
+use super::start_greeter;
+
+#[test]
+fn test_method_enum() {
+    assert_eq!(GreeterMethod::ALL, &[GreeterMethod::SayHello]);
+    let method = GreeterMethod::from_path("/helloworld.Greeter/SayHello").unwrap();
+    assert_eq!(method, GreeterMethod::SayHello);
+    assert_eq!(method.path(), "/helloworld.Greeter/SayHello");
+    assert!(method.matches(&MethodPattern::service("helloworld.Greeter")));
+    assert!(method.matches(&MethodPattern::new("/*/Say*")));
+    assert!(!method.matches(&MethodPattern::new("/*/Get*")));
+    assert_eq!(GreeterMethod::from_path("/helloworld.Greeter/SayBye"), None);
+}
+
+#[test]
+fn test_shared_handler() {
+    // Not `Clone`, shared by `Arc` instead.
+    struct CountingService {
+        count: AtomicUsize,
+    }
+
+    impl Greeter for CountingService {
+        fn say_hello(&self, ctx: RpcContext, _: HelloRequest, sink: UnarySink<HelloReply>) {
+            let count = self.count.fetch_add(1, Ordering::SeqCst) + 1;
+            let mut resp = HelloReply::new();
+            resp.set_message(count.to_string());
+            ctx.spawn(
+                sink.success(resp)
+                    .map_err(|e| panic!("failed to reply {:?}", e)),
+            );
+        }
+    }
+
+    let handler = Arc::new(CountingService {
+        count: AtomicUsize::new(0),
+    });
+    let env = Arc::new(EnvBuilder::new().build());
+    let mut server = ServerBuilder::new(env.clone())
+        .register_service(create_greeter(handler.clone()))
+        .register_service_for_host("a.example.com", create_greeter(handler.clone()))
+        .bind("127.0.0.1", 0)
+        .build()
+        .unwrap();
+    server.start();
+    let port = server.bind_addrs()[0].1;
+    let ch = ChannelBuilder::new(env).connect(&format!("127.0.0.1:{}", port));
+    let client = GreeterClient::new(ch);
+
+    let resp = client.say_hello(&HelloRequest::new()).unwrap();
+    assert_eq!(resp.get_message(), "1");
+    let opt = CallOption::default().authority("a.example.com");
+    let resp = client.say_hello_opt(&HelloRequest::new(), opt).unwrap();
+    assert_eq!(resp.get_message(), "2");
+    assert_eq!(handler.count.load(Ordering::SeqCst), 2);
+}
+
+#[test]
+fn test_handler_panic() {
+    #[derive(Clone)]
+    struct PanicService;
+
+    impl Greeter for PanicService {
+        fn say_hello(&self, ctx: RpcContext, req: HelloRequest, sink: UnarySink<HelloReply>) {
+            if req.get_name() == "panic" {
+                panic!("secret state");
+            }
+            let mut resp = HelloReply::new();
+            resp.set_message(format!("hello {}", req.get_name()));
+            let spawned_panic = req.get_name() == "spawned panic";
+            ctx.spawn(future::lazy(move || {
+                if spawned_panic {
+                    panic!("secret spawned state");
+                }
+                sink.success(resp)
+                    .map_err(|e| panic!("failed to reply {:?}", e))
+            }));
+        }
+    }
+
+    let env = Arc::new(EnvBuilder::new().cq_count(1).build());
+    let panics = Arc::new(Mutex::new(vec![]));
+    let panics_ = panics.clone();
+    let policy = PanicPolicy::new().hook(move |p| {
+        let msg = p.message().unwrap_or_default().to_owned();
+        panics_.lock().unwrap().push((p.method().to_owned(), msg));
+    });
+    let mut server = ServerBuilder::new(env.clone())
+        .register_service(create_greeter(PanicService))
+        .panic_policy(policy)
+        .bind("127.0.0.1", 0)
+        .build()
+        .unwrap();
+    server.start();
+    let port = server.bind_addrs()[0].1;
+    let ch = ChannelBuilder::new(env).connect(&format!("127.0.0.1:{}", port));
+    let client = GreeterClient::new(ch);
+
+    let mut req = HelloRequest::new();
+    for _ in 0..2 {
+        req.set_name("panic".to_owned());
+        match client.say_hello(&req) {
+            Err(Error::RpcFailure(s)) => {
+                assert_eq!(s.status, RpcStatusCode::Internal);
+                assert_eq!(s.details.as_ref().unwrap(), panic_policy::REDACTED_MESSAGE);
+            }
+            res => panic!("expect internal error, but got {:?}", res),
+        }
+        // The only poll thread should survive the panic.
+        req.set_name("world".to_owned());
+        assert_eq!(client.say_hello(&req).unwrap().get_message(), "hello world");
+    }
+    // Panics of the spawned futures are caught too.
+    req.set_name("spawned panic".to_owned());
+    match client.say_hello(&req) {
+        Err(Error::RpcFailure(s)) => {
+            assert_eq!(s.status, RpcStatusCode::Internal);
+            assert_eq!(s.details.as_ref().unwrap(), panic_policy::REDACTED_MESSAGE);
+        }
+        res => panic!("expect internal error, but got {:?}", res),
+    }
+    req.set_name("world".to_owned());
+    assert_eq!(client.say_hello(&req).unwrap().get_message(), "hello world");
+
+    let method = "/helloworld.Greeter/SayHello".to_owned();
+    assert_eq!(
+        *panics.lock().unwrap(),
+        vec![
+            (method.clone(), "secret state".to_owned()),
+            (method.clone(), "secret state".to_owned()),
+            (method, "secret spawned state".to_owned()),
+        ]
+    );
+}
+
+#[test]
+fn test_leaked_sink() {
+    #[derive(Clone)]
+    struct LeakService;
+
+    impl Greeter for LeakService {
+        fn say_hello(&self, ctx: RpcContext, req: HelloRequest, sink: UnarySink<HelloReply>) {
+            match req.get_name() {
+                "guard" => {
+                    // The sink is kept alive, only the guard is dropped.
+                    let _ = ctx.completion_guard();
+                    mem::forget(sink);
+                }
+                "leak" if cfg!(debug_assertions) => drop(sink),
+                _ => {
+                    let guard = ctx.completion_guard();
+                    let mut resp = HelloReply::new();
+                    resp.set_message(format!("hello {}", req.get_name()));
+                    ctx.spawn(sink.success(resp).then(move |_| {
+                        drop(guard);
+                        Ok(())
+                    }));
+                }
+            }
+        }
+    }
+
+    let (_server, ch) = start_greeter(LeakService);
+    let client = GreeterClient::new(ch);
+
+    let mut req = HelloRequest::new();
+    let mut cases = vec![(
+        "guard",
+        "completion guard is dropped before the status is sent",
+    )];
+    if cfg!(debug_assertions) {
+        cases.push(("leak", "sink is dropped before the status is sent"));
+    }
+    for (name, details) in cases {
+        req.set_name(name.to_owned());
+        match client.say_hello(&req) {
+            Err(Error::RpcFailure(s)) => {
+                assert_eq!(s.status, RpcStatusCode::Internal);
+                assert_eq!(s.details.as_ref().unwrap(), details);
+            }
+            res => panic!("expect internal error, but got {:?}", res),
+        }
+    }
+    req.set_name("world".to_owned());
+    assert_eq!(client.say_hello(&req).unwrap().get_message(), "hello world");
+}
+
+#[test]
+fn test_blocking_unary_handler() {
+    use grpcio::blocking::BlockingPool;
+
+    const METHOD_SAY_HELLO: Method<HelloRequest, HelloReply> = Method {
+        ty: MethodType::Unary,
+        name: "/helloworld.Greeter/SayHello",
+        req_mar: Marshaller {
+            ser: pb_ser,
+            de: pb_de,
+        },
+        resp_mar: Marshaller {
+            ser: pb_ser,
+            de: pb_de,
+        },
+    };
+
+    let env = Arc::new(EnvBuilder::new().build());
+    let pool = BlockingPool::new(2);
+    let service = ServiceBuilder::new()
+        .add_blocking_unary_handler(&METHOD_SAY_HELLO, &pool, |req: HelloRequest| {
+            match req.get_name() {
+                "fail" => Err(RpcStatus::new(RpcStatusCode::InvalidArgument, None)),
+                "panic" => panic!("blocking handler panicked"),
+                name => {
+                    // Blocking is fine here.
+                    thread::sleep(Duration::from_millis(10));
+                    let mut resp = HelloReply::new();
+                    resp.set_message(format!("hello {}", name));
+                    Ok(resp)
+                }
+            }
+        })
+        .build();
+    let mut server = ServerBuilder::new(env.clone())
+        .register_service(service)
+        .bind("127.0.0.1", 0)
+        .build()
+        .unwrap();
+    server.start();
+    let port = server.bind_addrs()[0].1;
+    let ch = ChannelBuilder::new(env).connect(&format!("127.0.0.1:{}", port));
+    let client = GreeterClient::new(ch);
+
+    let receivers: Vec<_> = (0..4)
+        .map(|i| {
+            let mut req = HelloRequest::new();
+            req.set_name(format!("{}", i));
+            client.say_hello_async(&req).unwrap()
+        })
+        .collect();
+    for (i, r) in receivers.into_iter().enumerate() {
+        assert_eq!(r.wait().unwrap().get_message(), format!("hello {}", i));
+    }
+    for &(name, code) in &[
+        ("fail", RpcStatusCode::InvalidArgument),
+        ("panic", RpcStatusCode::Internal),
+    ] {
+        let mut req = HelloRequest::new();
+        req.set_name(name.to_owned());
+        match client.say_hello(&req) {
+            Err(Error::RpcFailure(s)) => assert_eq!(s.status, code),
+            r => panic!("unexpected result {:?}", r),
+        }
+    }
+}
+
+#[test]
+fn test_register_service_at_runtime() {
+    #[derive(Clone)]
+    struct GreeterService;
+
+    impl Greeter for GreeterService {
+        fn say_hello(&self, ctx: RpcContext, mut req: HelloRequest, sink: UnarySink<HelloReply>) {
+            let mut resp = HelloReply::new();
+            resp.set_message(format!("hello {}", req.take_name()));
+            ctx.spawn(
+                sink.success(resp)
+                    .map_err(|e| panic!("failed to reply {:?}", e)),
+            );
+        }
+    }
+
+    let env = Arc::new(EnvBuilder::new().build());
+    let mut server = ServerBuilder::new(env.clone())
+        .bind("127.0.0.1", 0)
+        .build()
+        .unwrap();
+    server.start();
+    let port = server.bind_addrs()[0].1;
+    let ch = ChannelBuilder::new(env).connect(&format!("127.0.0.1:{}", port));
+    let client = GreeterClient::new(ch);
+
+    let mut req = HelloRequest::new();
+    req.set_name("world".to_owned());
+    match client.say_hello(&req) {
+        Err(Error::RpcFailure(s)) => assert_eq!(s.status, RpcStatusCode::Unimplemented),
+        res => panic!("expect unimplemented, but got {:?}", res),
+    }
+
+    server.add_service(create_greeter(GreeterService));
+    let resp = client.say_hello(&req).unwrap();
+    assert_eq!(resp.get_message(), "hello world");
+
+    assert!(server.remove_method("/helloworld.Greeter/SayHello"));
+    assert!(!server.remove_method("/helloworld.Greeter/SayHello"));
+    match client.say_hello(&req) {
+        Err(Error::RpcFailure(s)) => assert_eq!(s.status, RpcStatusCode::Unimplemented),
+        res => panic!("expect unimplemented, but got {:?}", res),
+    }
+}
+
+#[test]
+fn test_fallback_handler() {
+    let env = Arc::new(EnvBuilder::new().build());
+    let (tx, rx) = mpsc::channel();
+    let tx = Arc::new(Mutex::new(tx));
+    let mut server = ServerBuilder::new(env.clone())
+        .fallback_handler(move |ctx, reqs, sink| {
+            let method = String::from_utf8(ctx.method().to_vec()).unwrap();
+            tx.lock().unwrap().send(method).unwrap();
+            let f = reqs
+                .map(|payload| {
+                    let mut req: HelloRequest = pb_de(&payload).unwrap();
+                    let mut resp = HelloReply::new();
+                    resp.set_message(format!("hello {}", req.take_name()));
+                    let mut buf = vec![];
+                    pb_ser(&resp, &mut buf);
+                    (buf, WriteFlags::default())
+                })
+                .forward(sink)
+                .map(|_| ())
+                .map_err(|e| panic!("failed to proxy: {:?}", e));
+            ctx.spawn(f)
+        })
+        .bind("127.0.0.1", 0)
+        .build()
+        .unwrap();
+    server.start();
+    let port = server.bind_addrs()[0].1;
+    let ch = ChannelBuilder::new(env).connect(&format!("127.0.0.1:{}", port));
+    let client = GreeterClient::new(ch);
+
+    let mut req = HelloRequest::new();
+    req.set_name("world".to_owned());
+    let resp = client.say_hello(&req).unwrap();
+    assert_eq!(resp.get_message(), "hello world");
+    let method = rx.recv_timeout(Duration::from_secs(1)).unwrap();
+    assert_eq!(method, "/helloworld.Greeter/SayHello");
+}
@@ -103,3 +103,45 @@ fn test_health_check() {
         e => panic!("unexpected error: {:?}", e),
     }
 }
+
+#[test]
+fn test_admin_services() {
+    use grpcio::admin::{self, AdminServices, ServingStatus};
+
+    let env = Arc::new(Environment::new(1));
+    let admin = AdminServices::new().bind("127.0.0.1", 0);
+    let health = admin.health().clone();
+    let mut server = admin::register_admin_services(ServerBuilder::new(env.clone()), &admin)
+        .bind("127.0.0.1", 0)
+        .build()
+        .unwrap();
+    server.start();
+    let (_, port) = server.admin_bind_addrs()[0];
+    let ch = ChannelBuilder::new(env.clone()).connect(&format!("127.0.0.1:{}", port));
+    let client = HealthClient::new(ch);
+
+    let mut req = HealthCheckRequest::new();
+    let status = client.check(&req).unwrap().get_status();
+    assert_eq!(status, HealthCheckResponse_ServingStatus::SERVING);
+    req.set_service("helloworld.Greeter".to_owned());
+    match client.check(&req) {
+        Err(Error::RpcFailure(s)) => assert_eq!(s.status, RpcStatusCode::NotFound),
+        r => panic!("unexpected result {:?}", r),
+    }
+    health.set_status("helloworld.Greeter", ServingStatus::Serving);
+    let status = client.check(&req).unwrap().get_status();
+    assert_eq!(status, HealthCheckResponse_ServingStatus::SERVING);
+
+    // The admin services are kept off the port of the other services.
+    let (_, port) = server.bind_addrs()[0];
+    let ch = ChannelBuilder::new(env).connect(&format!("127.0.0.1:{}", port));
+    match HealthClient::new(ch).check(&req) {
+        Err(Error::RpcFailure(s)) => assert_eq!(s.status, RpcStatusCode::Unimplemented),
+        r => panic!("unexpected result {:?}", r),
+    }
+
+    // The admin port keeps reporting while the server drains.
+    let _drained = server.start_draining();
+    let status = client.check(&req).unwrap().get_status();
+    assert_eq!(status, HealthCheckResponse_ServingStatus::NOT_SERVING);
+}
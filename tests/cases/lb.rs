@@ -0,0 +1,206 @@
+// Copyright 2018 PingCAP, Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use futures::*;
+use grpcio::orca::LoadReport;
+use grpcio::outlier;
+use grpcio::wrr;
+use grpcio::*;
+use grpcio_proto::example::helloworld::*;
+use grpcio_proto::example::helloworld_grpc::*;
+use std::sync::*;
+use std::time::*;
+
+#[test]
+fn test_call_metric_recording() {
+    #[derive(Clone)]
+    struct LoadService;
+
+    impl Greeter for LoadService {
+        fn say_hello(&self, ctx: RpcContext, req: HelloRequest, sink: UnarySink<HelloReply>) {
+            if let Some(r) = ctx.call_metric_recorder() {
+                r.record_cpu_utilization(0.5);
+                r.record_request_cost("rows", req.get_name().len() as f64);
+            }
+            let mut builder = MetadataBuilder::new();
+            builder.add_str("x-name", req.get_name()).unwrap();
+            ctx.spawn(
+                sink.success_with_trailers(HelloReply::new(), builder.build())
+                    .map_err(|_| ()),
+            );
+        }
+    }
+
+    let env = Arc::new(EnvBuilder::new().build());
+    let mut server = ServerBuilder::new(env.clone())
+        .register_service(create_greeter(LoadService))
+        .bind("127.0.0.1", 0)
+        .call_metric_recording(true)
+        .build()
+        .unwrap();
+    server.start();
+    let port = server.bind_addrs()[0].1;
+    let ch = ChannelBuilder::new(env).connect(&format!("127.0.0.1:{}", port));
+    let client = GreeterClient::new(ch);
+
+    let mut req = HelloRequest::new();
+    req.set_name("world".to_owned());
+    let mut receiver = client.say_hello_async(&req).unwrap();
+    (&mut receiver).wait().unwrap();
+    let trailers = receiver.take_trailers().unwrap();
+    // The report is added to the trailers set by the handler.
+    assert!(trailers.iter().any(|(k, v)| k == "x-name" && v == b"world"));
+    let report = LoadReport::from_trailers(&trailers).unwrap();
+    assert_eq!(report.cpu_utilization(), 0.5);
+    assert_eq!(report.request_cost()["rows"], 5.0);
+}
+
+#[test]
+fn test_weighted_round_robin() {
+    const METHOD_SAY_HELLO: Method<HelloRequest, HelloReply> = Method {
+        ty: MethodType::Unary,
+        name: "/helloworld.Greeter/SayHello",
+        req_mar: Marshaller {
+            ser: pb_ser,
+            de: pb_de,
+        },
+        resp_mar: Marshaller {
+            ser: pb_ser,
+            de: pb_de,
+        },
+    };
+
+    // Simulates backends under different load.
+    #[derive(Clone)]
+    struct LoadService {
+        cpu: f64,
+    }
+
+    impl Greeter for LoadService {
+        fn say_hello(&self, ctx: RpcContext, _: HelloRequest, sink: UnarySink<HelloReply>) {
+            let r = ctx.call_metric_recorder().unwrap();
+            r.record_cpu_utilization(self.cpu);
+            r.record_qps(100.0);
+            ctx.spawn(sink.success(HelloReply::new()).map_err(|_| ()));
+        }
+    }
+
+    let env = Arc::new(EnvBuilder::new().build());
+    let mut servers = vec![];
+    let mut backends = vec![];
+    for cpu in &[0.9, 0.1] {
+        let mut server = ServerBuilder::new(env.clone())
+            .register_service(create_greeter(LoadService { cpu: *cpu }))
+            .bind("127.0.0.1", 0)
+            .call_metric_recording(true)
+            .build()
+            .unwrap();
+        server.start();
+        let addr = format!("127.0.0.1:{}", server.bind_addrs()[0].1);
+        let ch = ChannelBuilder::new(env.clone()).connect(&addr);
+        backends.push((addr, ch));
+        servers.push(server);
+    }
+    let hot = backends[0].0.clone();
+    let config = wrr::WrrConfig::new()
+        .blackout_period(Duration::from_secs(0))
+        .weight_update_period(Duration::from_secs(0));
+    let lb = wrr::WeightedRoundRobin::new(backends, config);
+
+    let call = || {
+        let mut recv = lb
+            .unary_call_async(
+                &METHOD_SAY_HELLO,
+                &HelloRequest::new(),
+                CallOption::default(),
+            )
+            .unwrap();
+        (&mut recv).wait().unwrap();
+        recv.pick().addr() == hot
+    };
+    // Evenly before the backends are reported.
+    assert_ne!(call(), call());
+    let weights = lb.weights();
+    assert!(weights[0].1 > 0.0 && weights[0].1 < weights[1].1);
+    let hot_calls = (0..20).filter(|_| call()).count();
+    assert!(hot_calls <= 4, "{}", hot_calls);
+}
+
+#[test]
+fn test_outlier_detection() {
+    const METHOD_SAY_HELLO: Method<HelloRequest, HelloReply> = Method {
+        ty: MethodType::Unary,
+        name: "/helloworld.Greeter/SayHello",
+        req_mar: Marshaller {
+            ser: pb_ser,
+            de: pb_de,
+        },
+        resp_mar: Marshaller {
+            ser: pb_ser,
+            de: pb_de,
+        },
+    };
+
+    #[derive(Clone)]
+    struct FlakyService {
+        healthy: bool,
+    }
+
+    impl Greeter for FlakyService {
+        fn say_hello(&self, ctx: RpcContext, _: HelloRequest, sink: UnarySink<HelloReply>) {
+            let f = if self.healthy {
+                sink.success(HelloReply::new())
+            } else {
+                sink.fail(RpcStatus::new(RpcStatusCode::Unavailable, None))
+            };
+            ctx.spawn(f.map_err(|_| ()));
+        }
+    }
+
+    let env = Arc::new(EnvBuilder::new().build());
+    let mut servers = vec![];
+    let mut backends = vec![];
+    for healthy in &[false, true] {
+        let mut server = ServerBuilder::new(env.clone())
+            .register_service(create_greeter(FlakyService { healthy: *healthy }))
+            .bind("127.0.0.1", 0)
+            .build()
+            .unwrap();
+        server.start();
+        let addr = format!("127.0.0.1:{}", server.bind_addrs()[0].1);
+        let ch = ChannelBuilder::new(env.clone()).connect(&addr);
+        backends.push((addr, ch));
+        servers.push(server);
+    }
+    let bad = backends[0].0.clone();
+    let detection = outlier::OutlierDetection::new()
+        .consecutive_failures(2)
+        .max_ejection_percent(50);
+    let lb =
+        wrr::WeightedRoundRobin::new(backends, wrr::WrrConfig::new().outlier_detection(detection));
+
+    let call = || {
+        lb.unary_call(
+            &METHOD_SAY_HELLO,
+            &HelloRequest::new(),
+            CallOption::default(),
+        )
+    };
+    let failures = (0..4).filter(|_| call().is_err()).count();
+    assert_eq!(failures, 2);
+    assert_eq!(lb.ejected(), vec![bad]);
+    // Only the healthy backend gets calls while the other is ejected.
+    for _ in 0..10 {
+        call().unwrap();
+    }
+}
@@ -19,6 +19,8 @@ use std::sync::mpsc::{self, Sender};
 use std::sync::*;
 use std::time::*;
 
+use super::start_greeter;
+
 #[derive(Clone)]
 struct GreeterService {
     tx: Sender<(String, Vec<u8>)>,
@@ -74,3 +76,135 @@ fn test_metadata() {
     let metadata = rx.recv_timeout(Duration::from_secs(1)).unwrap();
     assert_eq!(metadata, ("k1-bin".to_owned(), vec![0x00, 0x01, 0x02]));
 }
+
+#[test]
+fn test_max_metadata_size() {
+    let env = Arc::new(EnvBuilder::new().build());
+    let (tx, _rx) = mpsc::channel();
+    let service = create_greeter(GreeterService { tx: tx });
+    let args = ChannelBuilder::new(env.clone())
+        .max_metadata_size(64 * 1024)
+        .build_args();
+    let mut server = ServerBuilder::new(env.clone())
+        .register_service(service)
+        .channel_args(args)
+        .bind("127.0.0.1", 0)
+        .build()
+        .unwrap();
+    server.start();
+    let port = server.bind_addrs()[0].1;
+    let ch = ChannelBuilder::new(env).connect(&format!("127.0.0.1:{}", port));
+    let client = GreeterClient::new(ch);
+
+    let call = |token_len| {
+        let mut builder = MetadataBuilder::new();
+        builder
+            .add_str("authorization", &"x".repeat(token_len))
+            .unwrap();
+        let call_opt = CallOption::default().headers(builder.build());
+        client.say_hello_opt(&HelloRequest::new(), call_opt)
+    };
+    // Larger than the default limit.
+    call(32 * 1024).unwrap();
+    match call(128 * 1024) {
+        Err(Error::RpcFailure(_)) => {}
+        r => panic!("expected failure, got {:?}", r),
+    }
+}
+
+#[derive(Clone)]
+struct TrailerService;
+
+impl Greeter for TrailerService {
+    fn say_hello(&self, ctx: RpcContext, mut req: HelloRequest, mut sink: UnarySink<HelloReply>) {
+        let mut builder = MetadataBuilder::new();
+        builder.add_str("x-greeting", "hello").unwrap();
+        sink.send_headers(builder.build()).unwrap();
+        let mut builder = MetadataBuilder::new();
+        builder.add_str("x-name", req.get_name()).unwrap();
+        let trailers = builder.build();
+        let f = if req.get_name().is_empty() {
+            let status = RpcStatus::new(RpcStatusCode::InvalidArgument, None);
+            sink.fail_with_trailers(status, trailers)
+        } else {
+            let mut resp = HelloReply::new();
+            resp.set_message(format!("hello {}", req.take_name()));
+            sink.success_with_trailers(resp, trailers)
+        };
+        ctx.spawn(f.map_err(|e| panic!("failed to reply {:?}", e)));
+    }
+}
+
+#[test]
+fn test_unary_trailers() {
+    let (_server, ch) = start_greeter(TrailerService);
+    let client = GreeterClient::new(ch);
+
+    let mut req = HelloRequest::new();
+    req.set_name("world".to_owned());
+    let mut receiver = client.say_hello_async(&req).unwrap();
+    let resp = (&mut receiver).wait().unwrap();
+    assert_eq!(resp.get_message(), "hello world");
+    let headers = receiver.take_headers().unwrap();
+    let greetings: Vec<_> = headers.iter().filter(|&(k, _)| k == "x-greeting").collect();
+    assert_eq!(greetings, vec![("x-greeting", b"hello" as &[u8])]);
+    let trailers = receiver.take_trailers().unwrap();
+    let names: Vec<_> = trailers.iter().filter(|&(k, _)| k == "x-name").collect();
+    assert_eq!(names, vec![("x-name", b"world" as &[u8])]);
+
+    let mut receiver = client.say_hello_async(&HelloRequest::new()).unwrap();
+    match (&mut receiver).wait() {
+        Err(e @ Error::RpcFailure(_)) => {
+            let s = e.status().unwrap();
+            assert_eq!(s.status, RpcStatusCode::InvalidArgument);
+            assert!(!e.is_transient());
+            assert!(s.details_bin().is_none());
+            let names: Vec<_> = e
+                .trailers()
+                .unwrap()
+                .iter()
+                .filter(|&(k, _)| k == "x-name")
+                .collect();
+            assert_eq!(names, vec![("x-name", b"" as &[u8])]);
+        }
+        r => panic!("expected rpc failure, but got {:?}", r),
+    }
+    let trailers = receiver.take_trailers().unwrap();
+    let names: Vec<_> = trailers.iter().filter(|&(k, _)| k == "x-name").collect();
+    assert_eq!(names, vec![("x-name", b"" as &[u8])]);
+}
+
+#[test]
+fn test_context_propagation() {
+    #[derive(Clone)]
+    struct ContextService;
+
+    impl Greeter for ContextService {
+        fn say_hello(&self, ctx: RpcContext, _: HelloRequest, sink: UnarySink<HelloReply>) {
+            let baggage = ctx.context();
+            let mut resp = HelloReply::new();
+            resp.set_message(format!(
+                "{} {}",
+                baggage.get("tenant").unwrap_or(""),
+                baggage.get("route").unwrap_or("")
+            ));
+            ctx.spawn(
+                sink.success(resp)
+                    .map_err(|e| panic!("failed to reply {:?}", e)),
+            );
+        }
+    }
+
+    let (_server, ch) = start_greeter(ContextService);
+    let client = GreeterClient::new(ch);
+
+    let baggage = context::Context::new()
+        .with("tenant", "acme corp")
+        .with("route", "eu,canary");
+    let opt = CallOption::default().context(&baggage);
+    let resp = client.say_hello_opt(&HelloRequest::new(), opt).unwrap();
+    assert_eq!(resp.get_message(), "acme corp eu,canary");
+
+    let resp = client.say_hello(&HelloRequest::new()).unwrap();
+    assert_eq!(resp.get_message(), " ");
+}
@@ -21,6 +21,8 @@ use std::sync::*;
 use std::thread::{self, JoinHandle};
 use std::time::*;
 
+use super::start_greeter;
+
 #[test]
 fn test_peer() {
     #[derive(Clone)]
@@ -29,8 +31,9 @@ fn test_peer() {
     impl Greeter for PeerService {
         fn say_hello(&self, ctx: RpcContext, _: HelloRequest, sink: UnarySink<HelloReply>) {
             let peer = ctx.peer();
+            assert!(peer.socket_addr().unwrap().ip().is_loopback(), "{}", peer);
             let mut resp = HelloReply::new();
-            resp.set_message(peer);
+            resp.set_message(peer.to_string());
             ctx.spawn(
                 sink.success(resp)
                     .map_err(|e| panic!("failed to reply {:?}", e)),
@@ -38,22 +41,19 @@ fn test_peer() {
         }
     }
 
-    let env = Arc::new(EnvBuilder::new().build());
-    let service = create_greeter(PeerService);
-    let mut server = ServerBuilder::new(env.clone())
-        .register_service(service)
-        .bind("127.0.0.1", 0)
-        .build()
-        .unwrap();
-    server.start();
+    let (server, ch) = start_greeter(PeerService);
     let port = server.bind_addrs()[0].1;
-    let ch = ChannelBuilder::new(env).connect(&format!("127.0.0.1:{}", port));
     let client = GreeterClient::new(ch);
 
     let req = HelloRequest::new();
-    let resp = client.say_hello(&req).unwrap();
+    let mut recv = client.say_hello_async(&req).unwrap();
+    let resp = (&mut recv).wait().unwrap();
 
     assert!(resp.get_message().contains("127.0.0.1"), "{:?}", resp);
+    assert_eq!(
+        recv.peer().socket_addr(),
+        Some(format!("127.0.0.1:{}", port).parse().unwrap())
+    );
 }
 
 struct Counter {
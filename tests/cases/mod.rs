@@ -11,7 +11,36 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
+use std::sync::Arc;
+
+use grpcio::{Channel, ChannelBuilder, EnvBuilder, Server, ServerBuilder};
+use grpcio_proto::example::helloworld_grpc::{create_greeter, Greeter};
+
 mod alarm;
+mod auth;
+mod channel;
+mod client;
+mod compression;
+mod flow;
+mod handler;
 mod health_check;
+mod lb;
 mod metadata;
 mod misc;
+mod observability;
+mod proxy;
+mod server_options;
+
+/// Start a server for `service` on a local port, and connect a channel to it.
+fn start_greeter<G: Greeter + Send + Clone + 'static>(service: G) -> (Server, Channel) {
+    let env = Arc::new(EnvBuilder::new().build());
+    let mut server = ServerBuilder::new(env.clone())
+        .register_service(create_greeter(service))
+        .bind("127.0.0.1", 0)
+        .build()
+        .unwrap();
+    server.start();
+    let port = server.bind_addrs()[0].1;
+    let ch = ChannelBuilder::new(env).connect(&format!("127.0.0.1:{}", port));
+    (server, ch)
+}
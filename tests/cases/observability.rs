@@ -0,0 +1,383 @@
+// Copyright 2018 PingCAP, Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use futures::*;
+use grpcio::message_hook::MessageHook;
+use grpcio::*;
+use grpcio_proto::example::helloworld::*;
+use grpcio_proto::example::helloworld_grpc::*;
+use std::any::Any;
+use std::sync::*;
+use std::time::*;
+
+#[test]
+fn test_call_summary() {
+    let env = Arc::new(EnvBuilder::new().build());
+    let (summary_tx, summary_rx) = mpsc::channel();
+    let summary_tx = Arc::new(Mutex::new(summary_tx));
+    let mut server = ServerBuilder::new(env.clone())
+        .fallback_handler(move |ctx, reqs, sink| {
+            let tx = summary_tx.lock().unwrap().clone();
+            ctx.on_complete(move |summary| tx.send(summary.clone()).unwrap());
+            let f = sink
+                .send_all(reqs.map(|msg| (msg, WriteFlags::default())))
+                .map(|_| ())
+                .map_err(|e| panic!("failed to reply: {:?}", e));
+            ctx.spawn(f)
+        })
+        .bind("127.0.0.1", 0)
+        .build()
+        .unwrap();
+    server.start();
+    let port = server.bind_addrs()[0].1;
+    let ch = ChannelBuilder::new(env).connect(&format!("127.0.0.1:{}", port));
+    let client = Client::new(ch);
+
+    let (tx, rx) = client
+        .raw_duplex_streaming("/test.Chat/Talk", CallOption::default())
+        .unwrap();
+    let reqs = stream::iter_ok::<_, Error>(vec![
+        (b"hello".to_vec(), WriteFlags::default()),
+        (b"bye".to_vec(), WriteFlags::default()),
+    ]);
+    let _ = tx.send_all(reqs).wait().unwrap();
+    let resps: Vec<_> = rx.collect().wait().unwrap();
+    assert_eq!(resps.len(), 2);
+
+    let summary = summary_rx.recv_timeout(Duration::from_secs(5)).unwrap();
+    assert_eq!(summary.status(), RpcStatusCode::Ok);
+    assert_eq!(summary.request_messages(), 2);
+    assert_eq!(summary.request_bytes(), 8);
+    assert_eq!(summary.response_messages(), 2);
+    assert_eq!(summary.response_bytes(), 8);
+}
+
+#[test]
+fn test_access_log() {
+    #[derive(Clone)]
+    struct EchoService;
+
+    impl Greeter for EchoService {
+        fn say_hello(&self, ctx: RpcContext, req: HelloRequest, sink: UnarySink<HelloReply>) {
+            let mut resp = HelloReply::new();
+            resp.set_message(req.get_name().to_owned());
+            ctx.spawn(
+                sink.success(resp)
+                    .map_err(|e| panic!("failed to reply {:?}", e)),
+            );
+        }
+    }
+
+    let env = Arc::new(EnvBuilder::new().build());
+    let (entry_tx, entry_rx) = mpsc::channel();
+    let entry_tx = Mutex::new(entry_tx);
+    let log = access_log::AccessLog::new(move |e: &access_log::AccessLogEntry| {
+        entry_tx.lock().unwrap().send(e.clone()).unwrap()
+    })
+    .log_header("x-tenant");
+    let mut server = ServerBuilder::new(env.clone())
+        .register_service(create_greeter(EchoService))
+        .access_log(log)
+        .bind("127.0.0.1", 0)
+        .build()
+        .unwrap();
+    server.start();
+    let port = server.bind_addrs()[0].1;
+    let ch = ChannelBuilder::new(env).connect(&format!("127.0.0.1:{}", port));
+    let client = GreeterClient::new(ch);
+
+    let mut req = HelloRequest::new();
+    req.set_name("world".to_owned());
+    let mut headers = MetadataBuilder::new();
+    headers.add_str("x-tenant", "a").unwrap();
+    headers.add_str("x-other", "b").unwrap();
+    let opt = CallOption::default().headers(headers.build());
+    client.say_hello_opt(&req, opt).unwrap();
+
+    let entry = entry_rx.recv_timeout(Duration::from_secs(5)).unwrap();
+    assert_eq!(entry.method(), "/helloworld.Greeter/SayHello");
+    assert!(entry.peer().contains("127.0.0.1"), "{}", entry.peer());
+    assert_eq!(entry.status(), RpcStatusCode::Ok);
+    assert_eq!(entry.request_bytes(), 7);
+    assert_eq!(entry.response_bytes(), 7);
+    assert_eq!(entry.headers(), &[("x-tenant".to_owned(), "a".to_owned())]);
+    let line = access_log::AccessLogFormat::JsonLines.format(&entry);
+    assert!(line.contains("\"status\":0"), "{}", line);
+}
+
+#[test]
+fn test_access_log_of_rejected_calls() {
+    #[derive(Clone)]
+    struct UnreachableService;
+
+    impl Greeter for UnreachableService {
+        fn say_hello(&self, _: RpcContext, _: HelloRequest, _: UnarySink<HelloReply>) {
+            panic!("a request that can't be decoded should be rejected");
+        }
+    }
+
+    let env = Arc::new(EnvBuilder::new().build());
+    let (entry_tx, entry_rx) = mpsc::channel();
+    let entry_tx = Mutex::new(entry_tx);
+    let log = access_log::AccessLog::new(move |e: &access_log::AccessLogEntry| {
+        entry_tx.lock().unwrap().send(e.clone()).unwrap()
+    });
+    let mut server = ServerBuilder::new(env.clone())
+        .register_service(create_greeter(UnreachableService))
+        .access_log(log)
+        .bind("127.0.0.1", 0)
+        .build()
+        .unwrap();
+    server.start();
+    let port = server.bind_addrs()[0].1;
+    let ch = ChannelBuilder::new(env).connect(&format!("127.0.0.1:{}", port));
+    let client = Client::new(ch);
+
+    let (tx, rx) = client
+        .raw_duplex_streaming("/helloworld.Greeter/SayHello", CallOption::default())
+        .unwrap();
+    // A truncated varint, which can't be decoded as `HelloRequest`.
+    let _tx = tx.send((vec![0xff], WriteFlags::default())).wait().unwrap();
+    assert!(rx.collect().wait().is_err());
+
+    let entry = entry_rx.recv_timeout(Duration::from_secs(5)).unwrap();
+    assert_eq!(entry.method(), "/helloworld.Greeter/SayHello");
+    assert_eq!(entry.status(), RpcStatusCode::Internal);
+    assert!(entry_rx.recv_timeout(Duration::from_millis(100)).is_err());
+}
+
+#[test]
+fn test_request_id() {
+    #[derive(Clone)]
+    struct IdService;
+
+    impl Greeter for IdService {
+        fn say_hello(&self, ctx: RpcContext, _: HelloRequest, sink: UnarySink<HelloReply>) {
+            assert_eq!(
+                request_id::current().as_ref().map(|s| s.as_str()),
+                ctx.request_id()
+            );
+            let mut resp = HelloReply::new();
+            resp.set_message(ctx.request_id().unwrap().to_owned());
+            ctx.spawn(future::lazy(move || {
+                assert_eq!(request_id::current(), Some(resp.get_message().to_owned()));
+                sink.success(resp)
+                    .map_err(|e| panic!("failed to reply {:?}", e))
+            }));
+        }
+    }
+
+    let env = Arc::new(EnvBuilder::new().build());
+    let config = request_id::RequestIdConfig::new().key("x-trace-id");
+    let mut server = ServerBuilder::new(env.clone())
+        .register_service(create_greeter(IdService))
+        .request_id(config.clone())
+        .bind("127.0.0.1", 0)
+        .build()
+        .unwrap();
+    server.start();
+    let port = server.bind_addrs()[0].1;
+    let addr = format!("127.0.0.1:{}", port);
+
+    // Generated by server.
+    let client = GreeterClient::new(ChannelBuilder::new(env.clone()).connect(&addr));
+    let id = client
+        .say_hello(&HelloRequest::new())
+        .unwrap()
+        .take_message();
+    assert_eq!(id.len(), 36);
+
+    let config = config.generator(|| "generated".to_owned());
+    let client = GreeterClient::new(ChannelBuilder::new(env).request_id(config).connect(&addr));
+    let id = client
+        .say_hello(&HelloRequest::new())
+        .unwrap()
+        .take_message();
+    assert_eq!(id, "generated");
+    let id = request_id::enter("current", || client.say_hello(&HelloRequest::new()))
+        .unwrap()
+        .take_message();
+    assert_eq!(id, "current");
+    let mut headers = MetadataBuilder::new();
+    headers.add_str("x-trace-id", "given").unwrap();
+    let opt = CallOption::default().headers(headers.build());
+    let id = client
+        .say_hello_opt(&HelloRequest::new(), opt)
+        .unwrap()
+        .take_message();
+    assert_eq!(id, "given");
+}
+
+#[test]
+fn test_message_hook() {
+    #[derive(Clone)]
+    struct EchoService;
+
+    impl Greeter for EchoService {
+        fn say_hello(&self, ctx: RpcContext, req: HelloRequest, sink: UnarySink<HelloReply>) {
+            let mut resp = HelloReply::new();
+            resp.set_message(format!("hello {}", req.get_name()));
+            ctx.spawn(
+                sink.success(resp)
+                    .map_err(|e| panic!("failed to reply {:?}", e)),
+            );
+        }
+    }
+
+    struct Scrub(Mutex<Vec<String>>);
+
+    impl MessageHook for Scrub {
+        fn on_send(&self, method: &str, msg: &mut Any) {
+            if let Some(resp) = msg.downcast_mut::<HelloReply>() {
+                self.0.lock().unwrap().push(format!("send {}", method));
+                let scrubbed = resp.get_message().replace("secret", "******");
+                resp.set_message(scrubbed);
+            }
+        }
+
+        fn on_receive(&self, method: &str, msg: &mut Any) {
+            if let Some(req) = msg.downcast_mut::<HelloRequest>() {
+                self.0.lock().unwrap().push(format!("receive {}", method));
+                let name = format!("{} secret", req.get_name());
+                req.set_name(name);
+            }
+        }
+    }
+
+    struct Canary;
+
+    impl MessageHook for Canary {
+        fn on_send(&self, _: &str, msg: &mut Any) {
+            if let Some(req) = msg.downcast_mut::<HelloRequest>() {
+                req.set_name("canary".to_owned());
+            }
+        }
+
+        fn on_receive(&self, _: &str, msg: &mut Any) {
+            if let Some(resp) = msg.downcast_mut::<HelloReply>() {
+                let message = resp.get_message().to_uppercase();
+                resp.set_message(message);
+            }
+        }
+    }
+
+    let env = Arc::new(EnvBuilder::new().build());
+    let scrub = Arc::new(Scrub(Mutex::new(vec![])));
+    let mut server = ServerBuilder::new(env.clone())
+        .register_service(create_greeter(EchoService))
+        .message_hook(scrub.clone())
+        .bind("127.0.0.1", 0)
+        .build()
+        .unwrap();
+    server.start();
+    let port = server.bind_addrs()[0].1;
+    let ch = ChannelBuilder::new(env)
+        .message_hook(Arc::new(Canary))
+        .connect(&format!("127.0.0.1:{}", port));
+    let client = GreeterClient::new(ch);
+
+    let mut req = HelloRequest::new();
+    req.set_name("world".to_owned());
+    let resp = client.say_hello(&req).unwrap();
+    assert_eq!(resp.get_message(), "HELLO CANARY ******");
+    assert_eq!(req.get_name(), "world");
+    assert_eq!(
+        *scrub.0.lock().unwrap(),
+        vec![
+            "receive /helloworld.Greeter/SayHello".to_owned(),
+            "send /helloworld.Greeter/SayHello".to_owned(),
+        ]
+    );
+}
+
+#[test]
+fn test_deprecated_calls() {
+    const METHOD_SAY_HELLO: Method<HelloRequest, HelloReply> = Method {
+        ty: MethodType::Unary,
+        name: "/helloworld.Greeter/SayHello",
+        req_mar: Marshaller {
+            ser: pb_ser,
+            de: pb_de,
+        },
+        resp_mar: Marshaller {
+            ser: pb_ser,
+            de: pb_de,
+        },
+    };
+
+    let env = Arc::new(EnvBuilder::new().build());
+    let service = ServiceBuilder::new()
+        .add_unary_handler(&METHOD_SAY_HELLO, |ctx, _: HelloRequest, sink| {
+            ctx.spawn(
+                sink.success(HelloReply::new())
+                    .map_err(|e| panic!("failed to reply: {:?}", e)),
+            );
+        })
+        .deprecate_method(&METHOD_SAY_HELLO)
+        .build();
+    let mut server = ServerBuilder::new(env.clone())
+        .register_service(service)
+        .bind("127.0.0.1", 0)
+        .deprecation_stats(true)
+        .build()
+        .unwrap();
+    server.start();
+    assert!(server.deprecated_calls().is_empty());
+
+    let port = server.bind_addrs()[0].1;
+    let ch = ChannelBuilder::new(env).connect(&format!("127.0.0.1:{}", port));
+    let client = GreeterClient::new(ch);
+    for _ in 0..3 {
+        client.say_hello(&HelloRequest::new()).unwrap();
+    }
+    assert_eq!(
+        server.deprecated_calls(),
+        vec![("/helloworld.Greeter/SayHello".to_owned(), 3)]
+    );
+}
+
+#[test]
+fn test_server_hooks() {
+    let env = Arc::new(EnvBuilder::new().build());
+    let events = Arc::new(Mutex::new(vec![]));
+    let (e1, e2) = (events.clone(), events.clone());
+    let mut server = ServerBuilder::new(env)
+        .bind("127.0.0.1", 0)
+        .on_start(move |addrs| e1.lock().unwrap().push(format!("start {}", addrs[0].1)))
+        .on_start(move |_| e2.lock().unwrap().push("registered".to_owned()))
+        .build()
+        .unwrap();
+    let port = server.bind_addrs()[0].1;
+    assert!(events.lock().unwrap().is_empty());
+    server.start();
+    assert_eq!(
+        *events.lock().unwrap(),
+        vec![format!("start {}", port), "registered".to_owned()]
+    );
+
+    events.lock().unwrap().clear();
+    for name in &["first", "second"] {
+        let events = events.clone();
+        server.on_shutdown(move |addrs| {
+            events
+                .lock()
+                .unwrap()
+                .push(format!("{} {}", name, addrs.len()))
+        });
+    }
+    // Hooks are run in reverse order, and only once.
+    let _ = server.shutdown().wait();
+    let _ = server.shutdown().wait();
+    drop(server);
+    assert_eq!(*events.lock().unwrap(), vec!["second 1", "first 1"]);
+}
@@ -0,0 +1,149 @@
+// Copyright 2018 PingCAP, Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use futures::*;
+use grpcio::*;
+use grpcio_proto::example::helloworld::*;
+use grpcio_proto::example::helloworld_grpc::*;
+use std::str;
+use std::sync::*;
+use std::time::*;
+
+fn reply_bytes(msg: &str) -> Vec<u8> {
+    let mut resp = HelloReply::new();
+    resp.set_message(msg.to_owned());
+    let mut buf = vec![];
+    pb_ser(&resp, &mut buf);
+    buf
+}
+
+// A backend that greets every request it receives on any method.
+fn start_backend(env: Arc<Environment>) -> Server {
+    let mut server = ServerBuilder::new(env)
+        .fallback_handler(|ctx, reqs, mut sink| {
+            let tag = ctx
+                .request_headers()
+                .iter()
+                .find(|&(k, _)| k == "x-tag")
+                .map(|(_, v)| str::from_utf8(v).unwrap().to_owned())
+                .unwrap_or_default();
+            let has_deadline = ctx.deadline().timeout().is_some();
+            let mut headers = MetadataBuilder::new();
+            headers.add_str("x-backend", "greeter").unwrap();
+            sink.send_headers(headers.build()).unwrap();
+            let mut trailers = MetadataBuilder::new();
+            trailers.add_str("x-tag", &tag).unwrap();
+            sink.set_trailers(trailers.build());
+            let f = reqs
+                .map(move |payload| {
+                    let req: HelloRequest = pb_de(&payload).unwrap();
+                    let msg = format!("{} {} {}", tag, req.get_name(), has_deadline);
+                    (reply_bytes(&msg), WriteFlags::default())
+                })
+                .forward(sink)
+                .map(|_| ())
+                .map_err(|e| panic!("failed to reply: {:?}", e));
+            ctx.spawn(f)
+        })
+        .bind("127.0.0.1", 0)
+        .build()
+        .unwrap();
+    server.start();
+    server
+}
+
+// A transparent proxy that forwards every call to the backend.
+fn start_proxy(env: Arc<Environment>, backend_port: u16) -> Server {
+    let ch = ChannelBuilder::new(env.clone()).connect(&format!("127.0.0.1:{}", backend_port));
+    let client = Client::new(ch);
+    let mut server = ServerBuilder::new(env)
+        .fallback_handler(move |ctx, reqs, sink| proxy::forward(&client, &ctx, reqs, sink))
+        .bind("127.0.0.1", 0)
+        .build()
+        .unwrap();
+    server.start();
+    server
+}
+
+#[test]
+fn test_proxy_unary() {
+    let env = Arc::new(EnvBuilder::new().build());
+    let backend = start_backend(env.clone());
+    let proxy = start_proxy(env.clone(), backend.bind_addrs()[0].1);
+    let ch = ChannelBuilder::new(env).connect(&format!("127.0.0.1:{}", proxy.bind_addrs()[0].1));
+    let client = GreeterClient::new(ch);
+
+    let mut headers = MetadataBuilder::new();
+    headers.add_str("x-tag", "proxied").unwrap();
+    let opt = CallOption::default()
+        .headers(headers.build())
+        .timeout(Duration::from_secs(5));
+    let mut req = HelloRequest::new();
+    req.set_name("world".to_owned());
+    let mut receiver = client.say_hello_async_opt(&req, opt).unwrap();
+    let resp = (&mut receiver).wait().unwrap();
+    assert_eq!(resp.get_message(), "proxied world true");
+    let headers = receiver.take_headers().unwrap();
+    let backend: Vec<_> = headers.iter().filter(|&(k, _)| k == "x-backend").collect();
+    assert_eq!(backend, vec![("x-backend", b"greeter" as &[u8])]);
+    let trailers = receiver.take_trailers().unwrap();
+    let tags: Vec<_> = trailers.iter().filter(|&(k, _)| k == "x-tag").collect();
+    assert_eq!(tags, vec![("x-tag", b"proxied" as &[u8])]);
+
+    let resp = client.say_hello(&req).unwrap();
+    assert_eq!(resp.get_message(), " world false");
+}
+
+#[test]
+fn test_proxy_streaming() {
+    let env = Arc::new(EnvBuilder::new().build());
+    let backend = start_backend(env.clone());
+    let proxy = start_proxy(env.clone(), backend.bind_addrs()[0].1);
+    let ch = ChannelBuilder::new(env).connect(&format!("127.0.0.1:{}", proxy.bind_addrs()[0].1));
+    let client = Client::new(ch);
+
+    let mut headers = MetadataBuilder::new();
+    headers.add_str("x-tag", "stream").unwrap();
+    let opt = CallOption::default().headers(headers.build());
+    let (tx, mut rx) = client
+        .raw_duplex_streaming("/test.Proxy/Echo", opt)
+        .unwrap();
+    let reqs: Vec<_> = ["a", "b", "c"]
+        .iter()
+        .map(|name| {
+            let mut req = HelloRequest::new();
+            req.set_name(name.to_string());
+            let mut buf = vec![];
+            pb_ser(&req, &mut buf);
+            (buf, WriteFlags::default())
+        })
+        .collect();
+    let (_tx, _) = tx
+        .send_all(stream::iter_ok::<_, Error>(reqs))
+        .wait()
+        .unwrap();
+
+    let mut msgs = vec![];
+    for payload in rx.by_ref().wait() {
+        let resp: HelloReply = pb_de(&payload.unwrap()).unwrap();
+        msgs.push(resp.get_message().to_owned());
+    }
+    assert_eq!(
+        msgs,
+        vec!["stream a false", "stream b false", "stream c false"]
+    );
+
+    let trailers = rx.take_trailers().unwrap();
+    let tags: Vec<_> = trailers.iter().filter(|&(k, _)| k == "x-tag").collect();
+    assert_eq!(tags, vec![("x-tag", b"stream" as &[u8])]);
+}
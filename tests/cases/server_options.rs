@@ -0,0 +1,706 @@
+// Copyright 2018 PingCAP, Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use futures::*;
+use grpcio::method_config;
+use grpcio::*;
+use grpcio_proto::example::helloworld::*;
+use grpcio_proto::example::helloworld_grpc::*;
+use std::sync::atomic::*;
+use std::sync::*;
+use std::thread;
+use std::time::*;
+
+use super::start_greeter;
+
+#[test]
+fn test_bind_multiple_addrs() {
+    #[derive(Clone)]
+    struct HelloService;
+
+    impl Greeter for HelloService {
+        fn say_hello(&self, ctx: RpcContext, req: HelloRequest, sink: UnarySink<HelloReply>) {
+            let mut resp = HelloReply::new();
+            resp.set_message(format!("hello {}", req.get_name()));
+            ctx.spawn(
+                sink.success(resp)
+                    .map_err(|e| panic!("failed to reply {:?}", e)),
+            );
+        }
+    }
+
+    let env = Arc::new(EnvBuilder::new().build());
+    let mut server = ServerBuilder::new(env.clone())
+        .register_service(create_greeter(HelloService))
+        .bind("127.0.0.1", 0)
+        .bind("::1", 0)
+        .bind("0.0.0.0", 0)
+        .build()
+        .unwrap();
+    server.start();
+
+    let addrs = server.bind_addrs().to_vec();
+    let hosts: Vec<_> = addrs.iter().map(|&(ref h, _)| h.as_str()).collect();
+    assert_eq!(hosts, vec!["127.0.0.1", "::1", "0.0.0.0"]);
+    for &(_, port) in &addrs {
+        assert_ne!(port, 0);
+    }
+    assert_ne!(addrs[0].1, addrs[1].1);
+    assert_ne!(addrs[0].1, addrs[2].1);
+
+    for target in &[
+        format!("127.0.0.1:{}", addrs[0].1),
+        format!("[::1]:{}", addrs[1].1),
+        format!("127.0.0.1:{}", addrs[2].1),
+    ] {
+        let ch = ChannelBuilder::new(env.clone()).connect(target);
+        let client = GreeterClient::new(ch);
+        let mut req = HelloRequest::new();
+        req.set_name("world".to_owned());
+        let resp = client.say_hello(&req).unwrap();
+        assert_eq!(resp.get_message(), "hello world");
+    }
+}
+
+#[test]
+fn test_virtual_hosts() {
+    #[derive(Clone)]
+    struct TenantService(&'static str);
+
+    impl Greeter for TenantService {
+        fn say_hello(&self, ctx: RpcContext, req: HelloRequest, sink: UnarySink<HelloReply>) {
+            let mut resp = HelloReply::new();
+            resp.set_message(format!("{} {}", self.0, req.get_name()));
+            ctx.spawn(
+                sink.success(resp)
+                    .map_err(|e| panic!("failed to reply {:?}", e)),
+            );
+        }
+    }
+
+    let env = Arc::new(EnvBuilder::new().build());
+    let mut server = ServerBuilder::new(env.clone())
+        .register_service(create_greeter(TenantService("default")))
+        .register_service_for_host("a.example.com", create_greeter(TenantService("a")))
+        .register_service_for_host("b.example.com:8080", create_greeter(TenantService("b")))
+        .bind("127.0.0.1", 0)
+        .build()
+        .unwrap();
+    server.start();
+    let port = server.bind_addrs()[0].1;
+    let ch = ChannelBuilder::new(env).connect(&format!("127.0.0.1:{}", port));
+    let client = GreeterClient::new(ch);
+
+    let mut req = HelloRequest::new();
+    req.set_name("world".to_owned());
+    for &(authority, expect) in &[
+        ("a.example.com", "a world"),
+        ("a.example.com:443", "a world"),
+        ("b.example.com:8080", "b world"),
+        ("b.example.com", "default world"),
+        ("c.example.com", "default world"),
+    ] {
+        let opt = CallOption::default().authority(authority);
+        let resp = client.say_hello_opt(&req, opt).unwrap();
+        assert_eq!(resp.get_message(), expect, "{}", authority);
+    }
+}
+
+#[test]
+fn test_method_config() {
+    #[derive(Clone)]
+    struct SlowService;
+
+    impl Greeter for SlowService {
+        fn say_hello(&self, ctx: RpcContext, req: HelloRequest, sink: UnarySink<HelloReply>) {
+            let mut resp = HelloReply::new();
+            resp.set_message(format!("hello {}", req.get_name()));
+            if req.get_name() == "slow" {
+                assert!(ctx.deadline().timeout().unwrap() <= Duration::from_millis(200));
+                thread::spawn(move || {
+                    thread::sleep(Duration::from_secs(1));
+                    let _ = sink.success(resp).wait();
+                });
+                return;
+            }
+            ctx.spawn(
+                sink.success(resp)
+                    .map_err(|e| panic!("failed to reply {:?}", e)),
+            );
+        }
+    }
+
+    let env = Arc::new(EnvBuilder::new().build());
+    let config = method_config::MethodConfig::new()
+        .max_receive_message_len(32)
+        .max_send_message_len(64)
+        .compression(CompressionAlgorithms::Gzip)
+        .timeout(Duration::from_millis(200));
+    let mut server = ServerBuilder::new(env.clone())
+        .register_service(create_greeter(SlowService))
+        .method_config("/helloworld.Greeter/SayHello", config)
+        .bind("127.0.0.1", 0)
+        .build()
+        .unwrap();
+    server.start();
+    let port = server.bind_addrs()[0].1;
+    let ch = ChannelBuilder::new(env).connect(&format!("127.0.0.1:{}", port));
+    let client = GreeterClient::new(ch);
+
+    let mut req = HelloRequest::new();
+    req.set_name("world".to_owned());
+    assert_eq!(client.say_hello(&req).unwrap().get_message(), "hello world");
+
+    for (name, code) in vec![
+        ("x".repeat(40), RpcStatusCode::ResourceExhausted),
+        ("slow".to_owned(), RpcStatusCode::DeadlineExceeded),
+    ] {
+        req.set_name(name);
+        let start = Instant::now();
+        match client.say_hello(&req) {
+            Err(Error::RpcFailure(s)) => assert_eq!(s.status, code),
+            res => panic!("expect {:?}, but got {:?}", code, res),
+        }
+        assert!(start.elapsed() < Duration::from_secs(1));
+    }
+}
+
+#[test]
+fn test_heartbeat() {
+    use futures::sync::oneshot;
+    use grpcio_proto::example::route_guide::*;
+    use grpcio_proto::example::route_guide_grpc::*;
+
+    fn feature(name: &str) -> (Feature, WriteFlags) {
+        let mut f = Feature::new();
+        f.set_name(name.to_owned());
+        (f, WriteFlags::default())
+    }
+
+    #[derive(Clone)]
+    struct HeartbeatService;
+
+    impl RouteGuide for HeartbeatService {
+        fn get_feature(&self, _: RpcContext, _: Point, _: UnarySink<Feature>) {
+            unimplemented!()
+        }
+
+        fn list_features(
+            &self,
+            ctx: RpcContext,
+            rect: Rectangle,
+            sink: ServerStreamingSink<Feature>,
+        ) {
+            let (tx, rx) = oneshot::channel();
+            thread::spawn(move || {
+                thread::sleep(Duration::from_millis(350));
+                let _ = tx.send(feature("last"));
+            });
+            let features = stream::iter_ok(vec![feature("first")])
+                .chain(rx.into_stream().map_err(|_| Error::RemoteStopped));
+            let features = ctx.heartbeat(features, Duration::from_millis(100), || {
+                feature("heartbeat")
+            });
+            // Disable the heartbeats if asked to.
+            features
+                .handle()
+                .set_enabled(rect.get_lo().get_latitude() == 0);
+            ctx.spawn(sink.send_all(features).map(|_| ()).map_err(|_| ()));
+        }
+
+        fn record_route(
+            &self,
+            _: RpcContext,
+            _: RequestStream<Point>,
+            _: ClientStreamingSink<RouteSummary>,
+        ) {
+            unimplemented!()
+        }
+
+        fn route_chat(&self, _: RpcContext, _: RequestStream<RouteNote>, _: DuplexSink<RouteNote>) {
+            unimplemented!()
+        }
+    }
+
+    let env = Arc::new(EnvBuilder::new().build());
+    let mut server = ServerBuilder::new(env.clone())
+        .register_service(create_route_guide(HeartbeatService))
+        .bind("127.0.0.1", 0)
+        .build()
+        .unwrap();
+    server.start();
+    let port = server.bind_addrs()[0].1;
+    let ch = ChannelBuilder::new(env).connect(&format!("127.0.0.1:{}", port));
+    let client = RouteGuideClient::new(ch);
+
+    let mut rect = Rectangle::new();
+    let names: Vec<String> = client
+        .list_features(&rect)
+        .unwrap()
+        .map(|f| f.get_name().to_owned())
+        .collect()
+        .wait()
+        .unwrap();
+    assert_eq!(names.first().unwrap(), "first");
+    assert_eq!(names.last().unwrap(), "last");
+    assert!(names.len() > 2, "{:?}", names);
+    assert!(names[1..names.len() - 1].iter().all(|n| n == "heartbeat"));
+
+    rect.mut_lo().set_latitude(1);
+    let names: Vec<String> = client
+        .list_features(&rect)
+        .unwrap()
+        .map(|f| f.get_name().to_owned())
+        .collect()
+        .wait()
+        .unwrap();
+    assert_eq!(names, vec!["first".to_owned(), "last".to_owned()]);
+}
+
+#[test]
+fn test_build_validation() {
+    let env = Arc::new(EnvBuilder::new().build());
+    let res = ServerBuilder::new(env.clone())
+        .bind("127.0.0.1", 0)
+        .requests_slot_per_cq(0)
+        .build();
+    match res {
+        Err(Error::InvalidConfig(_)) => {}
+        Err(e) => panic!("unexpected error {:?}", e),
+        Ok(_) => panic!("zero request slots should be rejected"),
+    }
+
+    // An address of TEST-NET-1, which is never assigned to the host.
+    let res = ServerBuilder::new(env.clone()).bind("192.0.2.1", 0).build();
+    match res {
+        Err(Error::BindFail(ref host, 0)) if host == "192.0.2.1" => {}
+        Err(e) => panic!("unexpected error {:?}", e),
+        Ok(_) => panic!("an address that isn't on the host should not be bound"),
+    }
+}
+
+#[test]
+#[cfg(unix)]
+fn test_bind_v6_only() {
+    let env = Arc::new(EnvBuilder::new().build());
+    let server = ServerBuilder::new(env.clone())
+        .bind("127.0.0.1", 0)
+        .build()
+        .unwrap();
+    assert_eq!(server.bound_families(), &[SocketFamily::Ipv4]);
+
+    let res = ServerBuilder::new(env.clone())
+        .bind("0.0.0.0", 0)
+        .bind_v6_only(true)
+        .build();
+    match res {
+        Err(Error::InvalidConfig(_)) => {}
+        _ => panic!("0.0.0.0 should be rejected when IPv6 only is enabled"),
+    }
+
+    if std::net::TcpListener::bind("[::1]:0").is_err() {
+        // IPv6 is not available.
+        return;
+    }
+    let server = ServerBuilder::new(env.clone())
+        .bind("::1", 0)
+        .bind_v6_only(true)
+        .build()
+        .unwrap();
+    assert_eq!(server.bound_families(), &[SocketFamily::Ipv6]);
+    let server = ServerBuilder::new(env)
+        .bind_dual_stack(0)
+        .bind_v6_only(false)
+        .build()
+        .unwrap();
+    assert_eq!(server.bound_families(), &[SocketFamily::DualStack]);
+}
+
+#[test]
+fn test_max_concurrent_streams() {
+    #[derive(Clone)]
+    struct SlowService {
+        running: Arc<AtomicUsize>,
+        max_running: Arc<AtomicUsize>,
+    }
+
+    impl Greeter for SlowService {
+        fn say_hello(&self, ctx: RpcContext, _: HelloRequest, sink: UnarySink<HelloReply>) {
+            let running = self.running.fetch_add(1, Ordering::SeqCst) + 1;
+            self.max_running.fetch_max(running, Ordering::SeqCst);
+            let (tx, rx) = futures::sync::oneshot::channel();
+            let running = self.running.clone();
+            thread::spawn(move || {
+                thread::sleep(Duration::from_millis(200));
+                running.fetch_sub(1, Ordering::SeqCst);
+                tx.send(()).unwrap();
+            });
+            let f = rx
+                .map_err(|_| Error::RemoteStopped)
+                .and_then(|_| sink.success(HelloReply::new()));
+            ctx.spawn(f.map_err(|_| ()));
+        }
+    }
+
+    let env = Arc::new(EnvBuilder::new().build());
+    for &with_args in &[false, true] {
+        let max_running = Arc::new(AtomicUsize::new(0));
+        let service = SlowService {
+            running: Arc::default(),
+            max_running: max_running.clone(),
+        };
+        let mut builder = ServerBuilder::new(env.clone())
+            .register_service(create_greeter(service))
+            .max_concurrent_streams(1)
+            .bind("127.0.0.1", 0);
+        if with_args {
+            // The option is merged into the arguments.
+            let args = ChannelBuilder::new(env.clone())
+                .max_receive_message_len(1024)
+                .build_args();
+            builder = builder.channel_args(args);
+        }
+        let mut server = builder.build().unwrap();
+        #[cfg(unix)]
+        assert_eq!(server.bound_families(), &[SocketFamily::Ipv4]);
+        server.start();
+        let port = server.bind_addrs()[0].1;
+        let ch = ChannelBuilder::new(env.clone()).connect(&format!("127.0.0.1:{}", port));
+        let client = GreeterClient::new(ch);
+        // Make sure the settings of the server are received.
+        client.say_hello(&HelloRequest::new()).unwrap();
+        let calls: Vec<_> = (0..3)
+            .map(|_| client.say_hello_async(&HelloRequest::new()).unwrap())
+            .collect();
+        for c in calls {
+            c.wait().unwrap();
+        }
+        assert_eq!(max_running.load(Ordering::SeqCst), 1);
+
+        let mut req = HelloRequest::new();
+        req.set_name("x".repeat(2048));
+        assert_eq!(client.say_hello(&req).is_err(), with_args);
+    }
+}
+
+#[test]
+#[cfg(unix)]
+fn test_channel_from_fd() {
+    extern crate libc;
+
+    #[derive(Clone)]
+    struct HelloService;
+
+    impl Greeter for HelloService {
+        fn say_hello(&self, ctx: RpcContext, req: HelloRequest, sink: UnarySink<HelloReply>) {
+            let mut resp = HelloReply::new();
+            resp.set_message(format!("hello {}", req.get_name()));
+            ctx.spawn(sink.success(resp).map_err(|_| ()));
+        }
+    }
+
+    let mut fds = [0; 2];
+    unsafe {
+        assert_eq!(
+            libc::socketpair(libc::AF_UNIX, libc::SOCK_STREAM, 0, fds.as_mut_ptr()),
+            0
+        );
+    }
+    let env = Arc::new(EnvBuilder::new().build());
+    let mut server = ServerBuilder::new(env.clone())
+        .register_service(create_greeter(HelloService))
+        .build()
+        .unwrap();
+    server.start();
+    let ch = unsafe {
+        server.add_insecure_channel_from_fd(fds[1]);
+        ChannelBuilder::new(env).connect_from_fd("localhost", fds[0])
+    };
+    let client = GreeterClient::new(ch);
+    let mut req = HelloRequest::new();
+    req.set_name("fd".to_owned());
+    let resp = client.say_hello(&req).unwrap();
+    assert_eq!(resp.get_message(), "hello fd");
+}
+
+#[test]
+#[cfg(unix)]
+fn test_bind_fd() {
+    use std::net::TcpListener;
+    use std::os::unix::io::AsRawFd;
+
+    #[derive(Clone)]
+    struct HelloService;
+
+    impl Greeter for HelloService {
+        fn say_hello(&self, ctx: RpcContext, req: HelloRequest, sink: UnarySink<HelloReply>) {
+            let mut resp = HelloReply::new();
+            resp.set_message(format!("hello {}", req.get_name()));
+            ctx.spawn(sink.success(resp).map_err(|_| ()));
+        }
+    }
+
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let port = listener.local_addr().unwrap().port();
+    let env = Arc::new(EnvBuilder::new().build());
+    let mut server = ServerBuilder::new(env.clone())
+        .register_service(create_greeter(HelloService))
+        .bind_fd(listener.as_raw_fd())
+        .build()
+        .unwrap();
+    server.start();
+    let ch = ChannelBuilder::new(env).connect(&format!("127.0.0.1:{}", port));
+    let client = GreeterClient::new(ch);
+    let mut req = HelloRequest::new();
+    req.set_name("fd".to_owned());
+    for _ in 0..2 {
+        assert_eq!(client.say_hello(&req).unwrap().get_message(), "hello fd");
+    }
+    server.shutdown().wait().unwrap();
+    // The listener is still usable after the server is shut down.
+    listener.set_nonblocking(true).unwrap();
+    assert!(listener.local_addr().is_ok());
+}
+
+#[test]
+#[cfg(unix)]
+fn test_h2c_upgrade() {
+    use std::io::{Read, Write};
+    use std::net::{TcpListener, TcpStream};
+    use std::os::unix::io::AsRawFd;
+
+    #[derive(Clone)]
+    struct HelloService;
+
+    impl Greeter for HelloService {
+        fn say_hello(&self, ctx: RpcContext, req: HelloRequest, sink: UnarySink<HelloReply>) {
+            let mut resp = HelloReply::new();
+            resp.set_message(format!("hello {}", req.get_name()));
+            ctx.spawn(sink.success(resp).map_err(|_| ()));
+        }
+    }
+
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    let env = Arc::new(EnvBuilder::new().build());
+    let mut server = ServerBuilder::new(env.clone())
+        .register_service(create_greeter(HelloService))
+        .bind_fd(listener.as_raw_fd())
+        .h2c_upgrade(true)
+        .build()
+        .unwrap();
+    server.start();
+
+    // Prior knowledge still works.
+    let ch = ChannelBuilder::new(env).connect(&addr.to_string());
+    let client = GreeterClient::new(ch);
+    let mut req = HelloRequest::new();
+    req.set_name("h2c".to_owned());
+    assert_eq!(client.say_hello(&req).unwrap().get_message(), "hello h2c");
+
+    // Upgrade, then the server speaks HTTP/2 after the client preface.
+    let mut stream = TcpStream::connect(addr).unwrap();
+    stream
+        .set_read_timeout(Some(Duration::from_secs(5)))
+        .unwrap();
+    stream
+        .write_all(
+            b"OPTIONS * HTTP/1.1\r\nHost: localhost\r\nConnection: Upgrade, HTTP2-Settings\r\n\
+Upgrade: h2c\r\nHTTP2-Settings: \r\n\r\n",
+        )
+        .unwrap();
+    let expect = b"HTTP/1.1 101 Switching Protocols\r\nConnection: Upgrade\r\nUpgrade: h2c\r\n\r\n";
+    let mut resp = vec![0; expect.len()];
+    stream.read_exact(&mut resp).unwrap();
+    assert_eq!(resp, expect.to_vec());
+    stream
+        .write_all(b"PRI * HTTP/2.0\r\n\r\nSM\r\n\r\n")
+        .unwrap();
+    // Empty SETTINGS frame of client.
+    stream.write_all(&[0, 0, 0, 4, 0, 0, 0, 0, 0]).unwrap();
+    let mut frame = [0; 9];
+    stream.read_exact(&mut frame).unwrap();
+    // The first frame of server is SETTINGS.
+    assert_eq!(frame[3], 4);
+
+    // Plain HTTP/1.1 is refused.
+    let mut stream = TcpStream::connect(addr).unwrap();
+    stream
+        .set_read_timeout(Some(Duration::from_secs(5)))
+        .unwrap();
+    stream
+        .write_all(b"GET / HTTP/1.1\r\nHost: localhost\r\n\r\n")
+        .unwrap();
+    let mut resp = String::new();
+    stream.read_to_string(&mut resp).unwrap();
+    assert!(resp.starts_with("HTTP/1.1 426"), "{}", resp);
+
+    // Only probes are upgraded.
+    for (req, code) in &[
+        (
+            &b"GET / HTTP/1.1\r\nHost: localhost\r\nConnection: Upgrade, HTTP2-Settings\r\n\
+Upgrade: h2c\r\nHTTP2-Settings: \r\n\r\n"[..],
+            "405",
+        ),
+        (
+            &b"OPTIONS * HTTP/1.1\r\nHost: localhost\r\nConnection: Upgrade, HTTP2-Settings\r\n\
+Upgrade: h2c\r\nHTTP2-Settings: \r\nContent-Length: 1\r\n\r\na"[..],
+            "400",
+        ),
+    ] {
+        let mut stream = TcpStream::connect(addr).unwrap();
+        stream
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        stream.write_all(req).unwrap();
+        let mut resp = String::new();
+        stream.read_to_string(&mut resp).unwrap();
+        assert!(resp.starts_with(&format!("HTTP/1.1 {}", code)), "{}", resp);
+    }
+
+    server.shutdown().wait().unwrap();
+}
+
+#[test]
+fn test_start_draining() {
+    #[derive(Clone)]
+    struct SlowService {
+        draining: Arc<Mutex<Vec<bool>>>,
+    }
+
+    impl Greeter for SlowService {
+        fn say_hello(&self, ctx: RpcContext, req: HelloRequest, sink: UnarySink<HelloReply>) {
+            self.draining.lock().unwrap().push(ctx.is_server_draining());
+            thread::spawn(move || {
+                thread::sleep(Duration::from_millis(300));
+                let mut resp = HelloReply::new();
+                resp.set_message(req.get_name().to_owned());
+                sink.success(resp).wait().unwrap();
+            });
+        }
+    }
+
+    let service = SlowService {
+        draining: Arc::default(),
+    };
+    let (mut server, ch) = start_greeter(service.clone());
+    let client = GreeterClient::new(ch);
+    let mut req = HelloRequest::new();
+    req.set_name("in flight".to_owned());
+    let in_flight = client.say_hello_async(&req).unwrap();
+    // Wait for the call to reach the server.
+    while service.draining.lock().unwrap().is_empty() {
+        thread::sleep(Duration::from_millis(10));
+    }
+    assert!(!server.is_draining());
+
+    let drained = server.start_draining();
+    assert!(server.is_draining());
+    // The call in flight is still served.
+    assert_eq!(in_flight.wait().unwrap().get_message(), "in flight");
+    drained.wait().unwrap();
+    // Shutting down again resolves immediately.
+    server.shutdown().wait().unwrap();
+    assert_eq!(*service.draining.lock().unwrap(), vec![false]);
+
+    let opt = CallOption::default().timeout(Duration::from_millis(500));
+    assert!(client.say_hello_opt(&req, opt).is_err());
+}
+
+#[test]
+fn test_in_flight_calls_of_rejected_calls() {
+    #[derive(Clone)]
+    struct UnreachableService;
+
+    impl Greeter for UnreachableService {
+        fn say_hello(&self, _: RpcContext, _: HelloRequest, _: UnarySink<HelloReply>) {
+            panic!("a request that can't be decoded should be rejected");
+        }
+    }
+
+    let (mut server, ch) = start_greeter(UnreachableService);
+    let client = Client::new(ch);
+    let (tx, rx) = client
+        .raw_duplex_streaming("/helloworld.Greeter/SayHello", CallOption::default())
+        .unwrap();
+    // A truncated varint, which can't be decoded as `HelloRequest`.
+    let _tx = tx.send((vec![0xff], WriteFlags::default())).wait().unwrap();
+    match rx.collect().wait() {
+        Err(Error::RpcFailure(ref s)) if s.status == RpcStatusCode::Internal => {}
+        res => panic!("expect internal error, but got {:?}", res),
+    }
+
+    // The call is rejected before it's handed to the handler, which should
+    // not leave it in flight.
+    let start = Instant::now();
+    while server.in_flight_calls() != 0 {
+        assert!(start.elapsed() < Duration::from_secs(5));
+        thread::sleep(Duration::from_millis(10));
+    }
+    server.start_draining().wait().unwrap();
+}
+
+#[test]
+fn test_request_slots() {
+    #[derive(Clone)]
+    struct HelloService;
+
+    impl Greeter for HelloService {
+        fn say_hello(&self, ctx: RpcContext, req: HelloRequest, sink: UnarySink<HelloReply>) {
+            let mut resp = HelloReply::new();
+            resp.set_message(format!("hello {}", req.get_name()));
+            ctx.spawn(
+                sink.success(resp)
+                    .map_err(|e| panic!("failed to reply: {:?}", e)),
+            );
+        }
+    }
+
+    let env = Arc::new(EnvBuilder::new().cq_count(1).build());
+    let res = ServerBuilder::new(env.clone())
+        .requests_slot_per_cq(2)
+        .max_requests_slot_per_cq(1)
+        .build();
+    match res {
+        Err(Error::InvalidConfig(_)) => {}
+        Err(e) => panic!("unexpected error {:?}", e),
+        Ok(_) => panic!("max slots less than slots should be rejected"),
+    }
+
+    let mut server = ServerBuilder::new(env.clone())
+        .register_service(create_greeter(HelloService))
+        .bind("127.0.0.1", 0)
+        .requests_slot_per_cq(1)
+        .max_requests_slot_per_cq(2)
+        .build()
+        .unwrap();
+    server.start();
+    let stats = server.request_slot_stats();
+    assert_eq!(stats.len(), 1);
+    assert_eq!(stats[0].posted(), 1);
+    assert_eq!(stats[0].exhausted(), 0);
+
+    let port = server.bind_addrs()[0].1;
+    let ch = ChannelBuilder::new(env).connect(&format!("127.0.0.1:{}", port));
+    let client = GreeterClient::new(ch);
+    let mut req = HelloRequest::new();
+    req.set_name("slot".to_owned());
+    for _ in 0..3 {
+        let resp = client.say_hello(&req).unwrap();
+        assert_eq!(resp.get_message(), "hello slot");
+    }
+    // Only the first call takes the last slot, a second one is posted then.
+    let stats = server.request_slot_stats();
+    assert_eq!(stats[0].posted(), 2);
+    assert_eq!(stats[0].exhausted(), 1);
+}
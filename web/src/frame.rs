@@ -0,0 +1,165 @@
+// Copyright 2018 PingCAP, Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Framing of gRPC-Web bodies.
+//!
+//! Every frame starts with a flag byte and the length of its payload in big
+//! endian. A message frame has the flag 0, and the trailers are sent in the
+//! last frame with the flag 0x80, formatted as HTTP/1 headers.
+
+const TRAILER_FLAG: u8 = 0x80;
+const HEADER_LEN: usize = 5;
+
+/// Status of a finished call.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Status {
+    pub code: i32,
+    pub message: String,
+}
+
+impl Status {
+    /// Find the status in headers or trailers.
+    pub fn from_headers(headers: &[(String, String)]) -> Option<Status> {
+        let get = |key| headers.iter().find(|h| h.0 == key).map(|h| h.1.trim());
+        let code = get("grpc-status")?.parse().ok()?;
+        let message = get("grpc-message").map_or_else(String::new, percent_decode);
+        Some(Status { code, message })
+    }
+}
+
+// grpc-message is percent-encoded, malformed escapes are kept as is.
+fn percent_decode(s: &str) -> String {
+    let bytes = s.as_bytes();
+    let mut res = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'%' && i + 2 < bytes.len() {
+            if let Ok(b) = u8::from_str_radix(&s[i + 1..i + 3], 16) {
+                res.push(b);
+                i += 3;
+                continue;
+            }
+        }
+        res.push(bytes[i]);
+        i += 1;
+    }
+    String::from_utf8_lossy(&res).into_owned()
+}
+
+/// Append a message frame to `buf`.
+pub fn encode_message(msg: &[u8], buf: &mut Vec<u8>) {
+    let len = msg.len() as u32;
+    buf.reserve(HEADER_LEN + msg.len());
+    buf.push(0);
+    buf.extend_from_slice(&[
+        (len >> 24) as u8,
+        (len >> 16) as u8,
+        (len >> 8) as u8,
+        len as u8,
+    ]);
+    buf.extend_from_slice(msg);
+}
+
+/// A decoded response body.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Frames {
+    pub messages: Vec<Vec<u8>>,
+    /// Trailer names are in lower case.
+    pub trailers: Vec<(String, String)>,
+}
+
+impl Frames {
+    /// Get the status in trailers.
+    pub fn status(&self) -> Option<Status> {
+        Status::from_headers(&self.trailers)
+    }
+}
+
+fn parse_trailers(data: &[u8], trailers: &mut Vec<(String, String)>) -> Result<(), String> {
+    let data = String::from_utf8_lossy(data);
+    for line in data.split("\r\n") {
+        if line.is_empty() {
+            continue;
+        }
+        let pos = line
+            .find(':')
+            .ok_or_else(|| format!("invalid trailer {:?}", line))?;
+        let (k, v) = line.split_at(pos);
+        trailers.push((k.trim().to_ascii_lowercase(), v[1..].trim().to_owned()));
+    }
+    Ok(())
+}
+
+/// Decode a response body.
+pub fn decode(mut body: &[u8]) -> Result<Frames, String> {
+    let mut frames = Frames::default();
+    while !body.is_empty() {
+        if body.len() < HEADER_LEN {
+            return Err(format!("truncated frame header: {} bytes", body.len()));
+        }
+        let flag = body[0];
+        let len = body[1..HEADER_LEN]
+            .iter()
+            .fold(0usize, |l, b| l << 8 | *b as usize);
+        let data = &body[HEADER_LEN..];
+        if data.len() < len {
+            return Err(format!("truncated frame: {} of {} bytes", data.len(), len));
+        }
+        if flag & TRAILER_FLAG != 0 {
+            parse_trailers(&data[..len], &mut frames.trailers)?;
+        } else if flag != 0 {
+            // Compressed messages are not accepted by the requests.
+            return Err(format!("unexpected frame flag {:#x}", flag));
+        } else {
+            frames.messages.push(data[..len].to_vec());
+        }
+        body = &data[len..];
+    }
+    Ok(frames)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_decode() {
+        let mut body = vec![];
+        encode_message(b"hello", &mut body);
+        encode_message(b"", &mut body);
+        let trailers = b"grpc-status: 3\r\nGrpc-Message: bad%20name\r\n";
+        body.push(TRAILER_FLAG);
+        body.extend_from_slice(&[0, 0, 0, trailers.len() as u8]);
+        body.extend_from_slice(trailers);
+
+        let frames = decode(&body).unwrap();
+        assert_eq!(frames.messages, vec![b"hello".to_vec(), vec![]]);
+        assert_eq!(
+            frames.status(),
+            Some(Status {
+                code: 3,
+                message: "bad name".to_owned(),
+            })
+        );
+
+        assert!(decode(&body[..3]).is_err());
+        assert!(decode(&body[..8]).is_err());
+        assert!(decode(&[1, 0, 0, 0, 0]).is_err());
+        assert_eq!(decode(&[]).unwrap(), Frames::default());
+    }
+
+    #[test]
+    fn test_percent_decode() {
+        assert_eq!(percent_decode("a%2Cb%zz%4"), "a,b%zz%4");
+    }
+}
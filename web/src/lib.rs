@@ -0,0 +1,225 @@
+// Copyright 2018 PingCAP, Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! A gRPC-Web client that doesn't depend on gRPC core.
+//!
+//! It's meant to be used with the clients generated by
+//! `grpcio_compiler::codegen::gen_transport`, so the same descriptors and
+//! message types serve both native and browser clients. The HTTP request is
+//! made by an [`HttpClient`], which can be implemented on top of `fetch` or
+//! `XMLHttpRequest` when targeting `wasm32-unknown-unknown`:
+//!
+//! ```ignore
+//! impl Transport for Web {
+//!     type Error = grpcio_web::Error<JsValue>;
+//!
+//!     fn unary<Req, Resp>(&self, method: &MethodDescriptor, req: &Req) -> Result<Resp, Self::Error>
+//!     where
+//!         Req: Message,
+//!         Resp: Message,
+//!     {
+//!         self.client.unary(method.path, req)
+//!     }
+//! }
+//! ```
+//!
+//! [`HttpClient`]: trait.HttpClient.html
+
+extern crate protobuf;
+
+pub mod frame;
+
+use std::fmt::{self, Display, Formatter};
+use std::{error, result};
+
+use protobuf::{Message, ProtobufError};
+
+use frame::Status;
+
+/// Content type of requests, messages are encoded by protobuf.
+pub const CONTENT_TYPE: &str = "application/grpc-web+proto";
+
+/// Errors of gRPC-Web calls.
+#[derive(Debug)]
+pub enum Error<E> {
+    /// The HTTP request failed.
+    Http(E),
+    /// Server responded with an HTTP status other than 200.
+    HttpStatus(u16),
+    /// The call finished with a non-OK status.
+    RpcFailure(Status),
+    /// The response body is not valid gRPC-Web.
+    Malformed(String),
+    /// Failed to encode or decode a message.
+    Codec(ProtobufError),
+}
+
+impl<E: fmt::Debug> Display for Error<E> {
+    fn fmt(&self, fmt: &mut Formatter) -> fmt::Result {
+        write!(fmt, "{:?}", self)
+    }
+}
+
+impl<E: fmt::Debug> error::Error for Error<E> {
+    fn description(&self) -> &str {
+        match *self {
+            Error::Http(_) => "HTTP request failed",
+            Error::HttpStatus(_) => "unexpected HTTP status",
+            Error::RpcFailure(_) => "RPC failed",
+            Error::Malformed(_) => "malformed gRPC-Web response",
+            Error::Codec(_) => "gRPC-Web codec error",
+        }
+    }
+}
+
+impl<E> From<ProtobufError> for Error<E> {
+    fn from(e: ProtobufError) -> Error<E> {
+        Error::Codec(e)
+    }
+}
+
+pub type Result<T, E> = result::Result<T, Error<E>>;
+
+/// An HTTP POST request of a call.
+#[derive(Clone, Debug)]
+pub struct HttpRequest {
+    pub url: String,
+    pub headers: Vec<(&'static str, String)>,
+    pub body: Vec<u8>,
+}
+
+/// The HTTP response of a call.
+#[derive(Clone, Debug, Default)]
+pub struct HttpResponse {
+    pub status: u16,
+    /// Header names are expected to be in lower case.
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+}
+
+/// The way requests are sent, e.g. `fetch` in browsers.
+pub trait HttpClient {
+    type Error;
+
+    fn post(&self, req: HttpRequest) -> result::Result<HttpResponse, Self::Error>;
+}
+
+/// A client that makes gRPC-Web calls to a base URL, e.g. `https://example.com`.
+pub struct WebClient<H> {
+    base_url: String,
+    http: H,
+}
+
+impl<H: HttpClient> WebClient<H> {
+    pub fn new<S: Into<String>>(base_url: S, http: H) -> WebClient<H> {
+        let mut base_url = base_url.into();
+        while base_url.ends_with('/') {
+            base_url.pop();
+        }
+        WebClient { base_url, http }
+    }
+
+    /// Build the HTTP request of a call to `path`, e.g. `/helloworld.Greeter/SayHello`.
+    pub fn request<Req: Message>(&self, path: &str, req: &Req) -> Result<HttpRequest, H::Error> {
+        let mut body = vec![];
+        frame::encode_message(&req.write_to_bytes()?, &mut body);
+        Ok(HttpRequest {
+            url: format!("{}{}", self.base_url, path),
+            headers: vec![
+                ("content-type", CONTENT_TYPE.to_owned()),
+                ("x-grpc-web", "1".to_owned()),
+            ],
+            body,
+        })
+    }
+
+    /// Make a unary call to `path`.
+    pub fn unary<Req, Resp>(&self, path: &str, req: &Req) -> Result<Resp, H::Error>
+    where
+        Req: Message,
+        Resp: Message,
+    {
+        let req = self.request(path, req)?;
+        let resp = self.http.post(req).map_err(Error::Http)?;
+        parse_unary(&resp)
+    }
+}
+
+/// Parse the response of a unary call.
+pub fn parse_unary<Resp: Message, E>(resp: &HttpResponse) -> Result<Resp, E> {
+    if resp.status != 200 {
+        return Err(Error::HttpStatus(resp.status));
+    }
+    let frames = frame::decode(&resp.body).map_err(Error::Malformed)?;
+    // Servers send the status in headers if there is no message.
+    let status = match frames.status() {
+        Some(s) => s,
+        None => Status::from_headers(&resp.headers)
+            .ok_or_else(|| Error::Malformed("missing grpc-status".to_owned()))?,
+    };
+    if status.code != 0 {
+        return Err(Error::RpcFailure(status));
+    }
+    match frames.messages.len() {
+        1 => Ok(protobuf::parse_from_bytes(&frames.messages[0])?),
+        n => Err(Error::Malformed(format!("expect 1 message, got {}", n))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use protobuf::well_known_types::StringValue;
+
+    use super::*;
+
+    struct Echo;
+
+    impl HttpClient for Echo {
+        type Error = ();
+
+        fn post(&self, req: HttpRequest) -> result::Result<HttpResponse, ()> {
+            assert_eq!(req.url, "http://localhost/test.Echo/Echo");
+            let mut body = req.body;
+            let trailers = b"grpc-status:0\r\n";
+            body.extend_from_slice(&[0x80, 0, 0, 0, trailers.len() as u8]);
+            body.extend_from_slice(trailers);
+            Ok(HttpResponse {
+                status: 200,
+                headers: vec![],
+                body,
+            })
+        }
+    }
+
+    #[test]
+    fn test_unary() {
+        let client = WebClient::new("http://localhost/", Echo);
+        let mut req = StringValue::new();
+        req.set_value("hello".to_owned());
+        let resp: StringValue = client.unary("/test.Echo/Echo", &req).unwrap();
+        assert_eq!(resp, req);
+
+        let resp = HttpResponse {
+            status: 200,
+            headers: vec![
+                ("grpc-status".to_owned(), "5".to_owned()),
+                ("grpc-message".to_owned(), "not%20found".to_owned()),
+            ],
+            body: vec![],
+        };
+        match parse_unary::<StringValue, ()>(&resp) {
+            Err(Error::RpcFailure(s)) => assert_eq!((s.code, s.message.as_str()), (5, "not found")),
+            r => panic!("unexpected {:?}", r),
+        }
+    }
+}